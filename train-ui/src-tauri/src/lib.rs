@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::Cursor;
 use std::sync::Mutex;
@@ -63,6 +64,8 @@ struct DateRange {
 struct LoadedGtfs {
     gtfs: Gtfs,
     date_range: DateRange,
+    // Routes without a route_color, which are drawn with a palette colour.
+    uncoloured_route_ids: HashSet<String>,
 }
 
 #[derive(Default)]
@@ -72,8 +75,11 @@ struct AppStateData {
     sim_steps: Option<Vec<simulation::SimulationStep>>,
     trip_capacities: TripCapacities,
     sim_result: Option<simulation::SimulationResult>,
+    // Crowding cost of each segment of the simulation result, for the exports.
+    segment_costs: Vec<CrowdingCost>,
     path_data: Vec<u8>,
     trip_data: Vec<u8>,
+    stop_data: Vec<u8>,
 }
 
 impl AppStateData {
@@ -105,10 +111,14 @@ async fn load_gtfs(request: ipc::Request<'_>, state: State<'_, AppState>) -> Cmd
     // Load GTFS data.
     // TODO: Refactor this into a separate function (and put in a separate frontend crate?).
     match GtfsReader::default().raw().read_from_reader(Cursor::new(gtfs_zip)).and_then(Gtfs::try_from) {
-        Ok(gtfs) => {
+        Ok(mut gtfs) => {
             if gtfs.shapes.is_empty() {
                 return Err(CmdError::PrerequisiteUnsatisfied("GTFS data must contain shapes."));
             }
+            let frequency_expansion = data_import::expand_frequencies(&mut gtfs);
+            if frequency_expansion.num_frequency_trips > 0 {
+                log::info!("Expanded {} frequency-based trips into {} trips.", frequency_expansion.num_frequency_trips, frequency_expansion.num_generated_trips);
+            }
 
             log::info!("Successfully loaded GTFS data in {:?}.", gtfs.read_duration);
             let mut app_data = state.data.lock()?;
@@ -117,7 +127,11 @@ async fn load_gtfs(request: ipc::Request<'_>, state: State<'_, AppState>) -> Cmd
                 min: gtfs.calendar.values().map(|c| c.start_date).min().unwrap(),
                 max: gtfs.calendar.values().map(|c| c.end_date).max().unwrap(),
             };
-            app_data.loaded_gtfs = Some(LoadedGtfs { gtfs, date_range: date_range.clone() });
+            let uncoloured_route_ids = data_import::import_zip_routes_without_colour(Cursor::new(gtfs_zip)).unwrap_or_else(|err| {
+                log::warn!("Couldn't read the route colours: {err}");
+                HashSet::new()
+            });
+            app_data.loaded_gtfs = Some(LoadedGtfs { gtfs, date_range: date_range.clone(), uncoloured_route_ids });
             Ok(date_range)
         }
         Err(e) => {
@@ -175,12 +189,13 @@ async fn gen_network(model_date: NaiveDate, mode_filter: Option<ModeType>, state
     let default_transfer_time = 3 * 60;
 
     let mut network = Network::new(&loaded_gtfs.gtfs, mode_filter.map(|r| r.get_gtfs_route_type()), model_date, default_transfer_time);
+    data_export::assign_route_styles(&mut network, &loaded_gtfs.gtfs, &loaded_gtfs.uncoloured_route_ids, &data_export::ShapeColouring::default());
     network.build_connections();
 
     // Line shapes are constant for the network, so calculate here.
     app_data.path_data = Vec::new();
     // TODO rename data export functions (as they are now used in-process).
-    data_export::export_shape_file(&network, &mut app_data.path_data)?;
+    data_export::export_shape_file(&network, data_export::ShapeColours::Route, &mut app_data.path_data)?;
 
     app_data.network = Some(network);
 
@@ -196,7 +211,7 @@ async fn patronage_data_import(app: AppHandle, state: State<'_, AppState>) -> Cm
 
     // Dummy data:
     // let num_agents = 72000;
-    // app_data.sim_steps = Some(simulation::gen_simulation_steps(&network, Some(num_agents), Some(0)));
+    // app_data.sim_steps = Some(simulation::gen_simulation_steps(&network, Some(num_agents), Some(0), None));
 
     let Some(filepath) = app.dialog()
                             .file()
@@ -282,7 +297,7 @@ async fn run_simulation(num_rounds: u16,
     let network = app_data.get_network()?;
 
     let random_steps_vec = if gen_random_steps {
-        simulation::gen_simulation_steps(network, None, None)
+        simulation::gen_simulation_steps(network, None, None, None)
     } else {
         Vec::new()
     };
@@ -298,12 +313,13 @@ async fn run_simulation(num_rounds: u16,
 
     let journey_preferences = JourneyPreferences {
         utility_function: Box::new(move |label, start_time| {
-            (label.arrival_time - start_time) as PathfindingCost + cost_utility * label.cost
+            (label.arrival_time - start_time) as PathfindingCost + cost_utility as PathfindingCost * label.cost
         })
     };
 
     let params = simulation::DefaultSimulationParams {
         crowding_function: crowding_func,
+        overcapacity: simulation::Overcapacity::default(),
         progress_callback: if should_report_progress {
             Some(Box::new(|| {
                 on_simulation_event.send(SimulationEvent::StepCompleted).unwrap_or_else(|e| {
@@ -315,16 +331,38 @@ async fn run_simulation(num_rounds: u16,
         num_rounds,
         bag_size,
         trip_capacities: app_data.trip_capacities.clone(),
+        strict_capacity: false,
+        step_size: simulation::StepSize::Full,
+        convergence_tolerance: None,
+        convergence_rounds: 1,
+        replanning: simulation::Replanning::default(),
+        dwell_model: None,
+        route_choice: None,
+        segments: Vec::new(),
+        cancellation: None,
+        checkpointing: None,
+        elasticity: None,
+        warm_start: None,
+        chunk_size: None,
+        plan_cache: None,
+        wheelchair_access: None,
+        departure_choice: None,
+        transfer_times: None,
     };
 
     let sim_result = Some(simulation::run_simulation(network, &simulation_steps, &params));
+    let segment_costs = data_export::segment_crowding_costs(network, &params, &sim_result.as_ref().unwrap().population_count);
 
     // Export the trip data.
     let mut trip_data = Vec::new();
-    data_export::export_network_trips(&network, &sim_result.as_ref().unwrap(), &mut trip_data)?;
+    data_export::export_network_trips(&network, &sim_result.as_ref().unwrap(), &segment_costs, &mut trip_data)?;
+    let mut stop_data = Vec::new();
+    data_export::export_stops(&network, sim_result.as_ref(), &mut stop_data)?;
 
     app_data.sim_result = sim_result;
+    app_data.segment_costs = segment_costs;
     app_data.trip_data = trip_data;
+    app_data.stop_data = stop_data;
 
     Ok(())
 }
@@ -346,7 +384,7 @@ async fn export_counts(app: AppHandle, state: State<'_, AppState>) -> CmdResult<
     };
 
     let filepath = filepath.as_path().ok_or(CmdError::PathConversion(filepath.clone()))?;
-    data_export::export_agent_counts(filepath, network, sim_result, &app_data.trip_capacities)?;
+    data_export::export_agent_counts(filepath, network, sim_result, &app_data.trip_capacities, &app_data.segment_costs)?;
 
     Ok(())
 }
@@ -414,6 +452,12 @@ fn get_trip_data(state: State<'_, AppState>) -> CmdResult<ipc::Response> {
     Ok(ipc::Response::new(app_data.trip_data.clone()))
 }
 
+#[tauri::command]
+fn get_stop_data(state: State<'_, AppState>) -> CmdResult<ipc::Response> {
+    let app_data = state.data.lock()?;
+    Ok(ipc::Response::new(app_data.stop_data.clone()))
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     // Set up logging.
@@ -436,6 +480,7 @@ pub fn run() {
             export_journeys,
             export_transfers,
             get_trip_data, 
+            get_stop_data,
             get_path_data
         ])
         .manage(AppState::default())
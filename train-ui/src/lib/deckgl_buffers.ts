@@ -1,16 +1,44 @@
+// Size of the fixed header written by write_bin in train-ute (magic, version, chunk count, checksum).
+const BIN_FIXED_HEADER_SIZE = 16;
+const BIN_VERSION = 4;
+const BIN_MIN_VERSION = 1;
+
+// Checks the fixed header, returning the chunk offset/length table.
+// Later versions append chunks, so there can be more than numChunks.
+function readBinHeader(buffer: ArrayBuffer, numChunks: number): Uint32Array {
+  const fixedHeader = new DataView(buffer, 0, BIN_FIXED_HEADER_SIZE);
+  const magic = String.fromCharCode(...new Uint8Array(buffer, 0, 4));
+  if (magic !== "WOBB") {
+    throw new Error(
+      "Binary export is missing its header (exported by an older version?).",
+    );
+  }
+  const version = fixedHeader.getUint16(4, true);
+  if (version < BIN_MIN_VERSION || version > BIN_VERSION) {
+    throw new Error(`Unsupported binary export version ${version}.`);
+  }
+  const chunkCount = fixedHeader.getUint32(8, true);
+  if (chunkCount < numChunks) {
+    throw new Error(`Expected ${numChunks} chunks in binary export.`);
+  }
+  return new Uint32Array(buffer, BIN_FIXED_HEADER_SIZE, chunkCount * 2);
+}
+
 export type TripData = {
   length: number;
   startIndices: Uint32Array;
   attributes: {
     getPath: { value: Float32Array; size: number };
     getColor: { value: Uint8ClampedArray; size: number };
-    getTimestamps: { value: Float32Array; size: number };
+    getTimestamps: { value: Float64Array; size: number };
   };
+  // Crowding cost at each point, for colouring by perceived crowding (null before version 4).
+  crowdingCosts: Float32Array | null;
 };
 
 // Loads positions, indices, timestamps, and colours from a buffer for use in a deck.gl TripLayer.
 export function createTripData(buffer: ArrayBuffer): TripData {
-  const headerView = new Uint32Array(buffer, 0, 8);
+  const headerView = readBinHeader(buffer, 4);
 
   const positionsOffset = headerView[0];
   const positionsLength = headerView[1] / Float32Array.BYTES_PER_ELEMENT;
@@ -18,21 +46,36 @@ export function createTripData(buffer: ArrayBuffer): TripData {
   const indicesOffset = headerView[2];
   const indicesLength = headerView[3] / Uint32Array.BYTES_PER_ELEMENT;
 
-  const timestampsOffset = headerView[4];
-  const timestampsLength = headerView[5] / Float32Array.BYTES_PER_ELEMENT;
+  // Version 3 exports have f64 times in chunk 4, which are passed to deck.gl as they are.
+  // Older f32 times are widened, which is exact.
+  const hasF64Times = headerView.length > 9;
+  const timestampsOffset = hasF64Times ? headerView[8] : headerView[4];
+  const timestampsLength = hasF64Times
+    ? headerView[9] / Float64Array.BYTES_PER_ELEMENT
+    : headerView[5] / Float32Array.BYTES_PER_ELEMENT;
 
   const coloursOffset = headerView[6];
   const coloursLength = headerView[7] / Uint8ClampedArray.BYTES_PER_ELEMENT;
 
   const positions = new Float32Array(buffer, positionsOffset, positionsLength);
   const indices = new Uint32Array(buffer, indicesOffset, indicesLength);
-  const timestamps = new Float32Array(
-    buffer,
-    timestampsOffset,
-    timestampsLength,
-  );
+  const timestamps = hasF64Times
+    ? new Float64Array(buffer, timestampsOffset, timestampsLength)
+    : Float64Array.from(
+        new Float32Array(buffer, timestampsOffset, timestampsLength),
+      );
   const colours = new Uint8ClampedArray(buffer, coloursOffset, coloursLength);
 
+  // Version 4 exports have the crowding costs in chunk 5.
+  const crowdingCosts =
+    headerView.length > 11
+      ? new Float32Array(
+          buffer,
+          headerView[10],
+          headerView[11] / Float32Array.BYTES_PER_ELEMENT,
+        )
+      : null;
+
   return {
     length: indices.length,
     startIndices: indices,
@@ -41,6 +84,7 @@ export function createTripData(buffer: ArrayBuffer): TripData {
       getColor: { value: colours, size: 4 },
       getTimestamps: { value: timestamps, size: 1 },
     },
+    crowdingCosts,
   };
 }
 
@@ -56,7 +100,7 @@ export type PathData = {
 // Loads positions, indices, and colours from a buffer for use in a deck.gl PathLayer.
 export function createPathData(buffer: ArrayBuffer): PathData {
   // Get the locations of the data from the header.
-  const headerView = new Uint32Array(buffer, 0, 6);
+  const headerView = readBinHeader(buffer, 3);
 
   const positionsOffset = headerView[0];
   const positionsLength = headerView[1] / Float32Array.BYTES_PER_ELEMENT;
@@ -81,3 +125,70 @@ export function createPathData(buffer: ArrayBuffer): PathData {
     },
   };
 }
+
+export type StopData = {
+  length: number;
+  // Stop names, in the same order as the positions.
+  names: string[];
+  attributes: {
+    getPosition: { value: Float32Array; size: number };
+    getRadius: { value: Float32Array; size: number };
+  };
+};
+
+// Radius (in metres) of a stop without boardings, and of the busiest stop.
+const STOP_MIN_RADIUS = 20;
+const STOP_MAX_RADIUS = 400;
+
+// Loads stop positions, and daily boardings and names (version 2), from a buffer for use in a deck.gl ScatterplotLayer.
+// Circle areas are proportional to boardings, and every stop is drawn at the minimum size without boardings.
+export function createStopData(buffer: ArrayBuffer): StopData {
+  const headerView = readBinHeader(buffer, 1);
+
+  const positions = new Float32Array(
+    buffer,
+    headerView[0],
+    headerView[1] / Float32Array.BYTES_PER_ELEMENT,
+  );
+  const numStops = positions.length / 3;
+
+  const radii = new Float32Array(numStops).fill(STOP_MIN_RADIUS);
+  const names: string[] = new Array(numStops).fill("");
+  if (headerView.length >= 8) {
+    const boardings = new Float32Array(
+      buffer,
+      headerView[2],
+      headerView[3] / Float32Array.BYTES_PER_ELEMENT,
+    );
+    const maxBoardings = boardings.reduce((max, b) => Math.max(max, b), 0);
+    if (maxBoardings > 0) {
+      boardings.forEach((b, i) => {
+        radii[i] =
+          STOP_MIN_RADIUS +
+          (STOP_MAX_RADIUS - STOP_MIN_RADIUS) * Math.sqrt(b / maxBoardings);
+      });
+    }
+
+    const nameBytes = new Uint8Array(buffer, headerView[4], headerView[5]);
+    const nameOffsets = new Uint32Array(
+      buffer,
+      headerView[6],
+      headerView[7] / Uint32Array.BYTES_PER_ELEMENT,
+    );
+    const decoder = new TextDecoder();
+    for (let i = 0; i < numStops && i + 1 < nameOffsets.length; i++) {
+      names[i] = decoder.decode(
+        nameBytes.subarray(nameOffsets[i], nameOffsets[i + 1]),
+      );
+    }
+  }
+
+  return {
+    length: numStops,
+    names,
+    attributes: {
+      getPosition: { value: positions, size: 3 },
+      getRadius: { value: radii, size: 1 },
+    },
+  };
+}
@@ -1,9 +1,7 @@
-use raptor::journey::JourneyPreferences;
 use std::fs;
 use std::fs::File;
-use std::path::Path;
-use train_ute::simulation::{CrowdingFunc, CrowdingModel, DefaultSimulationParams};
-use train_ute::{data_export, data_import, simulation};
+use std::path::{Path, PathBuf};
+use train_ute::{data_export, data_import, run_simulation, RunConfig};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Set up network.
@@ -12,34 +10,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let network = dev_utils::build_example_network(&gtfs);
     network.print_stats();
 
-    // Set up simulation.
-    let params: DefaultSimulationParams = DefaultSimulationParams {
-        // From VicSig: X'Trapolis 3-car has 264 seated, 133 standing. A 6-car has 794 in total.
-        // Crush capacity is 1394, but that's a bit mean.
-        // https://vicsig.net/suburban/train/X'Trapolis
-        crowding_function: CrowdingModel {
-            func: CrowdingFunc::Linear,
-            default_seated: 400,
-            default_standing: 500,
-        },
-        progress_callback: None,
-        journey_preferences: JourneyPreferences::default(),
-        num_rounds: 4,
-        bag_size: 5,
-        should_report_progress: false,
-    };
+    // Set up simulation. The network is already built, so the config is only used for the simulation parameters.
+    let mut config = RunConfig::new(PathBuf::new(), network.date);
+    config.seed = Some(0);
+    let params = config.simulation_params();
 
     let simulation_steps = data_import::build_simulation_steps_from_patronage_data(dev_utils::find_example_patronage_data()?, &network)?;
-    //let simulation_steps = simulation::gen_simulation_steps(&network, Some(1000000), Some(0));
+    //config.num_agents = Some(1000000);
+    //let simulation_steps = config.simulation_steps(&network, &Default::default())?;
 
-    let simulation_result= simulation::run_simulation(&network, &simulation_steps, &params);
+    let simulation_result = run_simulation(&network, &simulation_steps, &params);
+    simulation_result.print_stats();
 
     let data_export_folder = Path::new("../train_ute_export");
-    println!("Exporting simulation data to {:?}", data_export_folder.canonicalize()?);
     fs::create_dir_all(data_export_folder)?;
+    println!("Exporting simulation data to {:?}", data_export_folder.canonicalize()?);
 
-    data_export::export_agent_counts(&data_export_folder.join("agent_counts"), &network, &simulation_result)?;
-    data_export::export_agent_journeys(File::create(&data_export_folder.join("agent_journeys.parquet"))?, &network, &simulation_result)?;
+    let segment_costs = data_export::segment_crowding_costs(&network, &params, &simulation_result.population_count);
+    data_export::export_agent_counts(&data_export_folder.join("agent_counts"), &network, &simulation_result, &params.trip_capacities, &segment_costs)?;
+    data_export::export_agent_journeys(File::create(data_export_folder.join("agent_journeys.parquet"))?, &network, &simulation_result, false)?;
 
     Ok(())
 }
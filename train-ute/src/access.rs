@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::Path;
+
+use itertools::Itertools;
+use rand::prelude::*;
+use raptor::network::{StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::data_import::{parse_time, DataImportError};
+use crate::demand::{travel_time_graph, travel_times_from};
+use crate::simulation::{AgentCount, SimulationStep};
+
+fn default_walk_radius() -> f64 { 800. }
+fn default_detour_factor() -> f64 { 1.3 }
+fn default_walk_speed() -> f64 { 1.33 }
+fn default_num_candidates() -> usize { 3 }
+
+// Walking between an address and the stops near it, for OD matrices of coordinates rather than stops.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct AccessModel {
+    // Straight-line distance (in metres) a stop can be from the address.
+    #[cfg_attr(feature = "serde", serde(default = "default_walk_radius"))]
+    pub walk_radius: f64,
+    // Walking distance over straight-line distance.
+    #[cfg_attr(feature = "serde", serde(default = "default_detour_factor"))]
+    pub detour_factor: f64,
+    // In metres per second.
+    #[cfg_attr(feature = "serde", serde(default = "default_walk_speed"))]
+    pub walk_speed: f64,
+    // Number of the nearest stops within the radius considered at each end.
+    #[cfg_attr(feature = "serde", serde(default = "default_num_candidates"))]
+    pub num_candidates: usize,
+}
+
+impl Default for AccessModel {
+    fn default() -> Self {
+        Self {
+            walk_radius: default_walk_radius(),
+            detour_factor: default_detour_factor(),
+            walk_speed: default_walk_speed(),
+            num_candidates: default_num_candidates(),
+        }
+    }
+}
+
+// Great circle distance between two coordinates in metres.
+fn distance(lat_a: f64, lon_a: f64, lat_b: f64, lon_b: f64) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.;
+    let (lat_a, lon_a, lat_b, lon_b) = (lat_a.to_radians(), lon_a.to_radians(), lat_b.to_radians(), lon_b.to_radians());
+    let h = ((lat_b - lat_a) / 2.).sin().powi(2) + lat_a.cos() * lat_b.cos() * ((lon_b - lon_a) / 2.).sin().powi(2);
+    2. * EARTH_RADIUS * h.sqrt().asin()
+}
+
+impl AccessModel {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.walk_radius.is_finite() && self.walk_radius > 0.) {
+            return Err(format!("walk_radius ({}) must be greater than zero", self.walk_radius));
+        }
+        if !(self.detour_factor.is_finite() && self.detour_factor >= 1.) {
+            return Err(format!("detour_factor ({}) must be at least 1", self.detour_factor));
+        }
+        if !(self.walk_speed.is_finite() && self.walk_speed > 0.) {
+            return Err(format!("walk_speed ({}) must be greater than zero", self.walk_speed));
+        }
+        if self.num_candidates == 0 {
+            return Err("num_candidates must be greater than zero".to_owned());
+        }
+        Ok(())
+    }
+
+    // Time to walk a straight-line distance, allowing for the detour.
+    pub fn walk_time(&self, distance: f64) -> Timestamp {
+        (distance * self.detour_factor / self.walk_speed).round() as Timestamp
+    }
+
+    // The nearest stops within the walk radius of a point and the time to walk to each, nearest first.
+    pub fn candidate_stops(&self, network: &Network, latitude: f64, longitude: f64) -> Vec<(StopIndex, Timestamp)> {
+        network.stop_points.iter().enumerate().filter_map(|(stop, point)| {
+            let distance = distance(latitude, longitude, point.latitude as f64, point.longitude as f64);
+            (distance <= self.walk_radius).then_some((stop as StopIndex, distance))
+        }).sorted_unstable_by(|(_, a), (_, b)| a.total_cmp(b))
+          .take(self.num_candidates)
+          .map(|(stop, distance)| (stop, self.walk_time(distance)))
+          .collect()
+    }
+}
+
+// Why an OD row couldn't be assigned to stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unservable {
+    NoOriginStop,
+    NoDestinationStop,
+    // There are stops near both ends, but none of them are connected.
+    NoConnection,
+}
+
+impl Unservable {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Unservable::NoOriginStop => "no_origin_stop",
+            Unservable::NoDestinationStop => "no_destination_stop",
+            Unservable::NoConnection => "no_connection",
+        }
+    }
+}
+
+// The stops an OD row walks to and from.
+#[derive(Clone, Copy, Debug)]
+pub struct AccessAssignment {
+    pub line: u64,
+    pub departure_time: Timestamp,
+    pub access_stop: StopIndex,
+    pub access_time: Timestamp,
+    pub egress_stop: StopIndex,
+    pub egress_time: Timestamp,
+    pub count: AgentCount,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct UnservablePoint {
+    pub line: u64,
+    pub origin: (f64, f64),
+    pub destination: (f64, f64),
+    pub reason: Unservable,
+    pub count: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessReport {
+    pub assignments: Vec<AccessAssignment>,
+    pub unservable: Vec<UnservablePoint>,
+}
+
+impl AccessReport {
+    pub fn log(&self) {
+        log::info!("Assigned {} OD rows to access and egress stops.", self.assignments.len());
+        if !self.unservable.is_empty() {
+            log::warn!("{:.0} agents in {} OD rows have no connected stop within the walk radius, so can't be served.",
+                       self.unservable.iter().map(|point| point.count).sum::<f64>(),
+                       self.unservable.len());
+        }
+    }
+}
+
+// Reads an OD matrix CSV of origin_lat,origin_lon,destination_lat,destination_lon,departure_time,count, where departure_time
+// is when the agents leave the origin (HH:MM:SS or a HH:MM:SS-HH:MM:SS window as in `load_od_matrix`).
+// Each end is walked to or from one of the nearest stops within the walk radius. The pair with the lowest walk time plus
+// in-vehicle time estimate (see `demand::travel_time_graph`) is chosen, and the agents leave the access stop once they've
+// walked to it. Fractional counts are rounded at random like `load_od_matrix`. Rows with no connected stops are reported as unservable.
+pub fn load_point_od_matrix(reader: impl Read, network: &Network, access: &AccessModel, seed: Option<u64>, demand_scale: f64) -> Result<(Vec<SimulationStep>, AccessReport), DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+    for (i, column) in ["origin_lat", "origin_lon", "destination_lat", "destination_lon", "departure_time", "count"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    };
+
+    let graph = travel_time_graph(network);
+    // Travel times from each candidate access stop, calculated when first needed.
+    let mut travel_times: HashMap<StopIndex, Vec<Option<Timestamp>>> = HashMap::new();
+
+    let mut simulation_steps = HashMap::new();
+    let mut report = AccessReport::default();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+        let coordinate = |i: usize, max: f64| {
+            field(i).parse::<f64>()
+                    .ok()
+                    .filter(|value| value.abs() <= max)
+                    .ok_or_else(|| DataImportError::InvalidCoordinate(line, field(i).to_string()))
+        };
+        let origin = (coordinate(0, 90.)?, coordinate(1, 180.)?);
+        let destination = (coordinate(2, 90.)?, coordinate(3, 180.)?);
+
+        let departure_time = field(4);
+        let (window_start, window_end) = match departure_time.split_once('-') {
+            Some((start, end)) => (parse_time(start), parse_time(end)),
+            None => (parse_time(departure_time), parse_time(departure_time)),
+        };
+        let (Some(window_start), Some(window_end)) = (window_start, window_end) else {
+            return Err(DataImportError::InvalidTime(line, departure_time.to_string()));
+        };
+        if window_end < window_start {
+            return Err(DataImportError::InvalidTime(line, departure_time.to_string()));
+        }
+
+        let count_str = field(5);
+        let count = count_str.parse::<f64>()
+                             .ok()
+                             .filter(|count| count.is_finite() && *count >= 0.)
+                             .ok_or_else(|| DataImportError::InvalidCount(line, count_str.to_string()))?
+            * demand_scale;
+
+        let access_stops = access.candidate_stops(network, origin.0, origin.1);
+        let egress_stops = access.candidate_stops(network, destination.0, destination.1);
+        let mut unservable = |reason| report.unservable.push(UnservablePoint { line, origin, destination, reason, count });
+        if access_stops.is_empty() {
+            unservable(Unservable::NoOriginStop);
+            continue;
+        }
+        if egress_stops.is_empty() {
+            unservable(Unservable::NoDestinationStop);
+            continue;
+        }
+
+        let best = access_stops.iter().cartesian_product(egress_stops.iter()).filter_map(|(&(access_stop, access_time), &(egress_stop, egress_time))| {
+            if access_stop == egress_stop {
+                return None;
+            }
+            let travel_time = travel_times.entry(access_stop).or_insert_with(|| travel_times_from(&graph, access_stop))[egress_stop as usize]?;
+            Some((access_time + travel_time + egress_time, access_stop, access_time, egress_stop, egress_time))
+        }).min_by_key(|&(cost, ..)| cost);
+        let Some((_, access_stop, access_time, egress_stop, egress_time)) = best else {
+            unservable(Unservable::NoConnection);
+            continue;
+        };
+
+        let count = count.floor() as AgentCount + rng.gen_bool(count.fract()) as AgentCount;
+        report.assignments.push(AccessAssignment { line, departure_time: window_start, access_stop, access_time, egress_stop, egress_time, count });
+        if count == 0 {
+            continue;
+        }
+
+        // The agents leave the access stop once they've walked to it.
+        let mut add_agents = |departure_time: Timestamp, count: AgentCount| {
+            let departure_time = departure_time + access_time;
+            simulation_steps.entry((departure_time, access_stop))
+                            .or_insert_with(|| SimulationStep::new(departure_time, access_stop))
+                            .push(egress_stop, count);
+        };
+        if window_start == window_end {
+            add_agents(window_start, count);
+            continue;
+        }
+        // Spread the agents evenly over the window like load_od_matrix.
+        let window_length = (window_end - window_start) as f64;
+        for agent in 0..count {
+            add_agents(window_start + (window_length * (agent as f64 + 0.5) / count as f64) as Timestamp, 1);
+        }
+    }
+
+    if simulation_steps.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        // Sort so the simulation (and replanning selection) is deterministic.
+        Ok((simulation_steps.into_iter().sorted_unstable_by_key(|(key, _)| *key).map(|(_, step)| step).collect_vec(), report))
+    }
+}
+
+// Writes the access and egress stop of each OD row to <path>.csv, the use of each stop's catchment to <path>_catchments.csv,
+// and the rows that couldn't be served to <path>_unservable.csv.
+pub fn export_access(path: &Path, network: &Network, report: &AccessReport) -> Result<(), DataExportError> {
+    let stop_id = |stop: StopIndex| -> &str { network.stops[stop as usize].id.as_ref() };
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["line", "departure_time", "access_stop_id", "access_walk_seconds", "egress_stop_id", "egress_walk_seconds", "count"])?;
+    for assignment in report.assignments.iter() {
+        csv_writer.write_record(&[
+            assignment.line.to_string().as_str(),
+            &get_time_str(assignment.departure_time),
+            stop_id(assignment.access_stop),
+            &assignment.access_time.to_string(),
+            stop_id(assignment.egress_stop),
+            &assignment.egress_time.to_string(),
+            &assignment.count.to_string(),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    // Agents and total walk time for access then egress at each stop.
+    let mut catchments = vec![[(0u64, 0u64); 2]; network.num_stops()];
+    for assignment in report.assignments.iter() {
+        let count = assignment.count as u64;
+        let access = &mut catchments[assignment.access_stop as usize][0];
+        access.0 += count;
+        access.1 += count * assignment.access_time as u64;
+        let egress = &mut catchments[assignment.egress_stop as usize][1];
+        egress.0 += count;
+        egress.1 += count * assignment.egress_time as u64;
+    }
+    let mean_walk = |(agents, walk_time): (u64, u64)| if agents > 0 { format!("{:.0}", walk_time as f64 / agents as f64) } else { String::new() };
+    let catchments_path = path.with_file_name(format!("{}_catchments.csv", path.file_stem().unwrap_or_default().to_string_lossy()));
+    let mut csv_writer = csv::Writer::from_path(catchments_path)?;
+    csv_writer.write_record(&["stop_id", "stop_name", "access_agents", "mean_access_walk_seconds", "egress_agents", "mean_egress_walk_seconds"])?;
+    for (stop, &[access, egress]) in catchments.iter().enumerate() {
+        if access.0 == 0 && egress.0 == 0 {
+            continue;
+        }
+        csv_writer.write_record(&[
+            stop_id(stop as StopIndex),
+            network.stops[stop].name.as_ref(),
+            &access.0.to_string(),
+            &mean_walk(access),
+            &egress.0.to_string(),
+            &mean_walk(egress),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    let unservable_path = path.with_file_name(format!("{}_unservable.csv", path.file_stem().unwrap_or_default().to_string_lossy()));
+    let mut csv_writer = csv::Writer::from_path(unservable_path)?;
+    csv_writer.write_record(&["line", "origin_lat", "origin_lon", "destination_lat", "destination_lon", "reason", "count"])?;
+    for point in report.unservable.iter() {
+        csv_writer.write_record(&[
+            point.line.to_string(),
+            point.origin.0.to_string(),
+            point.origin.1.to_string(),
+            point.destination.0.to_string(),
+            point.destination.1.to_string(),
+            point.reason.get_name().to_owned(),
+            format!("{:.2}", point.count),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::io::Write;
+use std::path::Path;
+
+use crate::data_export::DataExportError;
+
+fn default_max_evaluations() -> usize { 12 }
+
+// Bounded search for the exponential crowding function's beta (and optionally a capacity scale)
+// that minimises the RMSE of the simulated loads against observed loads.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct Calibration {
+    pub beta_min: f64,
+    pub beta_max: f64,
+    // When both are set, the capacity scale is searched too, using a grid over both parameters instead of a golden section search over beta.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capacity_scale_min: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capacity_scale_max: Option<f64>,
+    // Maximum number of simulation runs.
+    #[cfg_attr(feature = "serde", serde(default = "default_max_evaluations"))]
+    pub max_evaluations: usize,
+}
+
+// One simulation run of the calibration.
+#[derive(Clone, Copy, Debug)]
+pub struct CalibrationPoint {
+    pub beta: f64,
+    pub capacity_scale: f64,
+    pub rmse: f64,
+}
+
+impl Calibration {
+    pub fn validate(&self) -> Result<(), String> {
+        let check_bounds = |name: &str, min: f64, max: f64| {
+            if !min.is_finite() || !max.is_finite() || min < 0. || min > max {
+                Err(format!("{name} bounds ({min}, {max}) must be non-negative with min <= max"))
+            } else {
+                Ok(())
+            }
+        };
+        check_bounds("beta", self.beta_min, self.beta_max)?;
+        match (self.capacity_scale_min, self.capacity_scale_max) {
+            (Some(min), Some(max)) => {
+                check_bounds("capacity_scale", min, max)?;
+                if min == 0. {
+                    return Err("capacity_scale_min must be greater than zero".to_owned());
+                }
+            }
+            (None, None) => {}
+            _ => return Err("capacity_scale_min and capacity_scale_max must be set together".to_owned()),
+        }
+        if self.max_evaluations < 2 {
+            return Err(format!("max_evaluations ({}) must be at least 2", self.max_evaluations));
+        }
+        Ok(())
+    }
+
+    // Runs the search, calling `evaluate(beta, capacity_scale)` for the RMSE of each simulation run.
+    // `evaluate` returns None to stop early (e.g. when cancelled). Returns every evaluation in the order they were run.
+    pub fn run(&self, mut evaluate: impl FnMut(f64, f64) -> Option<f64>) -> Vec<CalibrationPoint> {
+        let mut points = Vec::new();
+        let mut evaluate_point = |beta: f64, capacity_scale: f64| -> Option<f64> {
+            let rmse = evaluate(beta, capacity_scale)?;
+            log::info!("Calibration run {}: beta {beta:.4}, capacity scale {capacity_scale:.3}, RMSE {rmse:.3}.", points.len() + 1);
+            points.push(CalibrationPoint { beta, capacity_scale, rmse });
+            Some(rmse)
+        };
+
+        if let (Some(scale_min), Some(scale_max)) = (self.capacity_scale_min, self.capacity_scale_max) {
+            let steps = ((self.max_evaluations as f64).sqrt() as usize).max(2);
+            let lerp = |min: f64, max: f64, i: usize| min + (max - min) * i as f64 / (steps - 1) as f64;
+            'grid: for i in 0..steps {
+                for j in 0..steps {
+                    if evaluate_point(lerp(self.beta_min, self.beta_max, i), lerp(scale_min, scale_max, j)).is_none() {
+                        break 'grid;
+                    }
+                }
+            }
+        } else {
+            golden_section(self.beta_min, self.beta_max, self.max_evaluations, |beta| evaluate_point(beta, 1.));
+        }
+        points
+    }
+}
+
+// Golden section search for the minimum of a unimodal function, using at most `max_evaluations` evaluations.
+fn golden_section(mut a: f64, mut b: f64, max_evaluations: usize, mut f: impl FnMut(f64) -> Option<f64>) -> Option<()> {
+    let inv_phi = (5f64.sqrt() - 1.) / 2.;
+    let mut c = b - inv_phi * (b - a);
+    let mut d = a + inv_phi * (b - a);
+    let mut fc = f(c)?;
+    let mut fd = f(d)?;
+    for _ in 2..max_evaluations {
+        if fc < fd {
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - inv_phi * (b - a);
+            fc = f(c)?;
+        } else {
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + inv_phi * (b - a);
+            fd = f(d)?;
+        }
+    }
+    Some(())
+}
+
+pub fn best_point(points: &[CalibrationPoint]) -> Option<&CalibrationPoint> {
+    points.iter().filter(|point| point.rmse.is_finite()).min_by(|a, b| a.rmse.total_cmp(&b.rmse))
+}
+
+// Writes every calibration run to <path>.csv.
+pub fn export_calibration_runs(path: &Path, points: &[CalibrationPoint]) -> Result<(), DataExportError> {
+    if points.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["run", "beta", "capacity_scale", "rmse"])?;
+    for (run, point) in points.iter().enumerate() {
+        csv_writer.write_record(&[(run + 1).to_string(), point.beta.to_string(), point.capacity_scale.to_string(), point.rmse.to_string()])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the best parameters as run configuration values (to copy into a config file) to <path>.toml.
+pub fn write_calibrated_config(path: &Path, best: &CalibrationPoint, num_runs: usize) -> Result<(), DataExportError> {
+    let mut file = std::fs::File::create(path.with_extension("toml"))?;
+    writeln!(file, "# Best of {num_runs} calibration runs, with an RMSE of {:.3} against the observed loads.", best.rmse)?;
+    writeln!(file, "capacity_scale = {:?}", best.capacity_scale)?;
+    writeln!(file)?;
+    writeln!(file, "[crowding_function]")?;
+    writeln!(file, "func = \"exponential\"")?;
+    writeln!(file, "params = {{ beta = {:?} }}", best.beta)?;
+    Ok(())
+}
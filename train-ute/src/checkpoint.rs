@@ -0,0 +1,555 @@
+use std::fmt::Debug;
+use std::fs;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use raptor::journey::JourneyError;
+use raptor::network::{GlobalTripIndex, PathfindingCost};
+use raptor::{Leg, Network};
+use thiserror::Error;
+
+use crate::data_export::{read_bin, write_bin, DataExportError};
+use crate::simulation::{AgentJourney, AgentJourneyResult, CrowdingCost, IterationStats, SimulationStep};
+
+// File name prefix of the checkpoints written into the checkpoint folder, followed by the number of rounds run.
+const CHECKPOINT_FILE_PREFIX: &str = "checkpoint_";
+const CHECKPOINT_FILE_EXTENSION: &str = "bin";
+
+// Version of the checkpoint layout, the first word of the header. It changes whenever a chunk's layout does (e.g. a field
+// is added to the iteration history), so a checkpoint is never read with the wrong layout.
+const CHECKPOINT_VERSION: u32 = 1;
+// Number of u32s in the header, including the version.
+const HEADER_WORDS: usize = 8;
+// Number of u32s written per agent, journey and leg.
+const AGENT_WORDS: usize = 8;
+const JOURNEY_WORDS: usize = 11;
+const LEG_WORDS: usize = 9;
+// Number of f64s written per round of the iteration history.
+const STATS_WORDS: usize = 11;
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Checkpoint error: {0}")]
+    Bin(#[from] DataExportError),
+    #[error("Invalid checkpoint: {0}.")]
+    Invalid(String),
+    #[error("The checkpoint was written with a different {0}, so the simulation can't be resumed from it.")]
+    Mismatch(&'static str),
+}
+
+// Where to write checkpoints during the simulation, and optionally a checkpoint to resume from.
+#[derive(Clone, Debug)]
+pub struct Checkpointing {
+    pub dir: PathBuf,
+    // Number of checkpoints to keep, the oldest are deleted once there are more. No checkpoints are written if zero.
+    pub keep: usize,
+    // Hash of the simulation parameters (see `params_hash`), written into each checkpoint.
+    pub params_hash: u32,
+    pub resume: Option<Arc<SimulationCheckpoint>>,
+}
+
+// The state of the simulation at the end of a round, from which the remaining rounds can be run.
+// Only the final round's journeys are kept, so a resumed simulation doesn't have the journeys of the rounds before the checkpoint.
+pub struct SimulationCheckpoint {
+    pub network_hash: u32,
+    pub demand_hash: u32,
+    pub params_hash: u32,
+    // Number of rounds run so far, which is the round number to resume from.
+    pub num_rounds_run: u16,
+    pub num_converged_rounds: u16,
+    // Loads averaged over the rounds so far.
+    pub averaged_population: Vec<CrowdingCost>,
+    pub iteration_history: Vec<IterationStats>,
+    // The agents' plans from the last round run.
+    pub agent_journeys: Vec<AgentJourneyResult>,
+}
+
+// Loads from a previous run that the assignment starts from instead of free-flow, from a checkpoint or an exported loads.csv.
+#[derive(Clone, Debug)]
+pub struct WarmStart {
+    // Averaged load per stop time, zero for segments the previous run didn't have.
+    pub averaged_population: Vec<CrowdingCost>,
+    pub num_matched: usize,
+    pub num_unmatched: usize,
+    // Rounds the previous run took from free-flow, if known, to compare the warm-started run against.
+    pub cold_start_rounds: Option<usize>,
+}
+
+impl WarmStart {
+    // A checkpoint's loads are by stop time, so it must be of the same network.
+    pub fn from_checkpoint(checkpoint: &SimulationCheckpoint, network: &Network) -> Result<Self, CheckpointError> {
+        if checkpoint.network_hash != network_hash(network) {
+            return Err(CheckpointError::Mismatch("network"));
+        }
+        if checkpoint.averaged_population.len() != network.stop_times.len() {
+            return Err(CheckpointError::Invalid(format!("{} segment loads for {} stop times", checkpoint.averaged_population.len(), network.stop_times.len())));
+        }
+        Ok(Self {
+            averaged_population: checkpoint.averaged_population.clone(),
+            num_matched: network.routes.iter().enumerate().map(|(route_idx, route)| route.num_trips as usize * network.num_stops_in_route(route_idx).saturating_sub(1)).sum(),
+            num_unmatched: 0,
+            cold_start_rounds: Some(checkpoint.iteration_history.len()),
+        })
+    }
+}
+
+// Hashes the parts of the network the simulation depends on: the stops of each route and the timetable.
+pub fn network_hash(network: &Network) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(network.num_stops() as u32).to_le_bytes());
+    for route in network.routes.iter() {
+        hasher.update(&(route.num_trips as u32).to_le_bytes());
+        for &stop in route.get_stops(&network.route_stops) {
+            hasher.update(&(stop as u32).to_le_bytes());
+        }
+    }
+    for stop_time in network.stop_times.iter() {
+        hasher.update(&(stop_time.arrival_time as u32).to_le_bytes());
+        hasher.update(&(stop_time.departure_time as u32).to_le_bytes());
+    }
+    hasher.finalize()
+}
+
+// Hashes the origin, departure time and destinations of every simulation step.
+pub fn demand_hash(simulation_steps: &[SimulationStep]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for step in simulation_steps {
+        hasher.update(&(step.departure_time as u32).to_le_bytes());
+        hasher.update(&(step.origin_stop as u32).to_le_bytes());
+        hasher.update(&[step.segment]);
+        // Like parties, only hashed when set.
+        if step.requires_accessible {
+            hasher.update(b"accessible");
+        }
+        for ((dest_stop, count), &party_size) in step.destinations().zip(step.party_sizes()) {
+            hasher.update(&(dest_stop as u32).to_le_bytes());
+            hasher.update(&count.to_le_bytes());
+            // Only hashed for parties, so demand without them hashes the same as before parties were added.
+            if party_size > 1 {
+                hasher.update(&party_size.to_le_bytes());
+            }
+        }
+    }
+    hasher.finalize()
+}
+
+// Hashes the debug representation of the simulation parameters, which includes everything that changes the result.
+pub fn params_hash(params: &impl Debug) -> u32 {
+    crc32fast::hash(format!("{params:?}").as_bytes())
+}
+
+fn journey_error_code(result: &Result<AgentJourney, JourneyError>) -> u32 {
+    match result {
+        Ok(_) => 0,
+        Err(JourneyError::ZeroAgents) => 1,
+        Err(JourneyError::NoJourneyFound) => 2,
+        Err(JourneyError::InfiniteLoop) => 3,
+    }
+}
+
+// Decodes a chunk of little-endian values. The chunks from read_bin aren't aligned, so they can't be cast.
+fn decode_u32s(chunk: &[u8]) -> Vec<u32> {
+    chunk.chunks_exact(4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())).collect()
+}
+
+fn decode_crowding_costs(chunk: &[u8]) -> Vec<CrowdingCost> {
+    chunk.chunks_exact(size_of::<CrowdingCost>()).map(|bytes| CrowdingCost::from_le_bytes(bytes.try_into().unwrap())).collect()
+}
+
+fn decode_f64s(chunk: &[u8]) -> Vec<f64> {
+    chunk.chunks_exact(8).map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap())).collect()
+}
+
+// None is written as NaN.
+fn encode_option(value: Option<f64>) -> f64 {
+    value.unwrap_or(f64::NAN)
+}
+
+fn decode_option(value: f64) -> Option<f64> {
+    if value.is_nan() { None } else { Some(value) }
+}
+
+impl Checkpointing {
+    fn checkpoint_path(&self, num_rounds_run: u16) -> PathBuf {
+        self.dir.join(format!("{CHECKPOINT_FILE_PREFIX}{num_rounds_run:05}.{CHECKPOINT_FILE_EXTENSION}"))
+    }
+
+    // Writes a checkpoint atomically (to a temporary file which is then renamed), then deletes the oldest checkpoints beyond `keep`.
+    pub fn write(&self, network_hash: u32, demand_hash: u32, num_rounds_run: u16, num_converged_rounds: u16, averaged_population: &[CrowdingCost], iteration_history: &[IterationStats], agent_journeys: &[AgentJourneyResult]) -> Result<PathBuf, CheckpointError> {
+        // The averaged loads are written at the crowding cost precision, so it's recorded in bytes at the end of the header.
+        let header: [u32; HEADER_WORDS] = [CHECKPOINT_VERSION, network_hash, demand_hash, self.params_hash, num_rounds_run as u32, num_converged_rounds as u32, agent_journeys.len() as u32, size_of::<CrowdingCost>() as u32];
+        debug_assert_eq!(iteration_history.len(), num_rounds_run as usize);
+
+        let stats = iteration_history.iter().flat_map(|stats| [
+            stats.round_number as f64,
+            stats.step_size as f64,
+            stats.total_crowding_cost,
+            encode_option(stats.relative_change),
+            encode_option(stats.relative_load_gap),
+            stats.total_passenger_hours,
+            stats.max_segment_load as f64,
+            stats.num_replanned as f64,
+            stats.num_plan_cache_hits as f64,
+            encode_option(stats.num_changed_route.map(|num| num as f64)),
+            encode_option(stats.switching_cost_improvement),
+        ]).collect::<Vec<f64>>();
+
+        let mut agents = Vec::with_capacity(agent_journeys.len() * AGENT_WORDS);
+        let mut journeys = Vec::new();
+        let mut legs = Vec::new();
+        for agent_journey in agent_journeys {
+            agents.extend([
+                agent_journey.sim_step_idx,
+                agent_journey.journey_idx,
+                agent_journey.origin_stop as u32,
+                agent_journey.dest_stop as u32,
+                agent_journey.start_time as u32,
+                agent_journey.count,
+                agent_journey.segment as u32,
+                journey_error_code(&agent_journey.result),
+            ]);
+            if let Ok(journey) = &agent_journey.result {
+                journeys.extend([
+                    journey.origin_trip.route_idx as u32,
+                    journey.origin_trip.trip_order as u32,
+                    journey.dest_trip.route_idx as u32,
+                    journey.dest_trip.trip_order as u32,
+                    journey.duration as u32,
+                    // Journey costs are stored at the journey planner's precision.
+                    (journey.crowding_cost as PathfindingCost).to_bits(),
+                    (journey.experienced_crowding_cost as PathfindingCost).to_bits(),
+                    journey.in_vehicle_time as u32,
+                    journey.wait_time as u32,
+                    journey.num_transfers as u32,
+                    journey.legs.len() as u32,
+                ]);
+                for leg in journey.legs.iter() {
+                    legs.extend([
+                        leg.trip.route_idx as u32,
+                        leg.trip.trip_order as u32,
+                        leg.boarded_stop as u32,
+                        leg.boarded_stop_order as u32,
+                        leg.boarded_time as u32,
+                        leg.arrival_stop as u32,
+                        leg.arrival_stop_order as u32,
+                        leg.arrival_time as u32,
+                        leg.transfer_time.map_or(u32::MAX, |time| time as u32),
+                    ]);
+                }
+            }
+        }
+
+        fs::create_dir_all(&self.dir).map_err(DataExportError::from)?;
+        let path = self.checkpoint_path(num_rounds_run);
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut writer = std::io::BufWriter::new(fs::File::create(&temp_path).map_err(DataExportError::from)?);
+            write_bin(&[bytemuck::must_cast_slice(&header),
+                        bytemuck::must_cast_slice(averaged_population),
+                        bytemuck::must_cast_slice(&stats),
+                        bytemuck::must_cast_slice(&agents),
+                        bytemuck::must_cast_slice(&journeys),
+                        bytemuck::must_cast_slice(&legs)], &mut writer)?;
+            std::io::Write::flush(&mut writer).map_err(DataExportError::from)?;
+        }
+        fs::rename(&temp_path, &path).map_err(DataExportError::from)?;
+
+        self.rotate().map_err(DataExportError::from)?;
+        Ok(path)
+    }
+
+    // Deletes all but the newest `keep` checkpoints.
+    fn rotate(&self) -> std::io::Result<()> {
+        let mut checkpoints = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let is_checkpoint = path.extension().is_some_and(|extension| extension == CHECKPOINT_FILE_EXTENSION)
+                && path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(CHECKPOINT_FILE_PREFIX));
+            if is_checkpoint {
+                checkpoints.push(path);
+            }
+        }
+        // The round number is zero-padded, so the names sort in the order they were written.
+        checkpoints.sort();
+        for path in checkpoints.iter().rev().skip(self.keep) {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl SimulationCheckpoint {
+    pub fn read(path: &Path) -> Result<Self, CheckpointError> {
+        let chunks = read_bin(path)?;
+        let [header, averaged_population, stats, agents, journeys, legs] = chunks.as_slice() else {
+            return Err(CheckpointError::Invalid(format!("expected 6 chunks, found {}", chunks.len())));
+        };
+        let header = decode_u32s(header);
+        // Checkpoints from before the layout was versioned start with the network hash instead.
+        if header.first() != Some(&CHECKPOINT_VERSION) {
+            return Err(CheckpointError::Mismatch("checkpoint format version"));
+        }
+        let &[_, network_hash, demand_hash, params_hash, num_rounds_run, num_converged_rounds, num_agents, cost_size] = header.as_slice() else {
+            return Err(CheckpointError::Invalid(format!("the header has {} words instead of {HEADER_WORDS}", header.len())));
+        };
+        if cost_size as usize != size_of::<CrowdingCost>() {
+            return Err(CheckpointError::Mismatch("crowding cost precision"));
+        }
+        let averaged_population = decode_crowding_costs(averaged_population);
+
+        let stats = decode_f64s(stats);
+        // The history has a row for every round run.
+        if stats.len() != num_rounds_run as usize * STATS_WORDS {
+            return Err(CheckpointError::Invalid(format!("{} iteration history values for {num_rounds_run} rounds of {STATS_WORDS}", stats.len())));
+        }
+        let iteration_history = stats.chunks_exact(STATS_WORDS).map(|stats| IterationStats {
+            round_number: stats[0] as u16,
+            step_size: stats[1] as CrowdingCost,
+            total_crowding_cost: stats[2],
+            relative_change: decode_option(stats[3]),
+            relative_load_gap: decode_option(stats[4]),
+            total_passenger_hours: stats[5],
+            max_segment_load: stats[6] as _,
+            num_replanned: stats[7] as usize,
+            num_plan_cache_hits: stats[8] as usize,
+            num_changed_route: decode_option(stats[9]).map(|num| num as usize),
+            switching_cost_improvement: decode_option(stats[10]),
+        }).collect();
+
+        let agents = decode_u32s(agents);
+        let journeys = decode_u32s(journeys);
+        let legs = decode_u32s(legs);
+        if agents.len() != num_agents as usize * AGENT_WORDS || journeys.len() % JOURNEY_WORDS != 0 || legs.len() % LEG_WORDS != 0 {
+            return Err(CheckpointError::Invalid("the agent journeys have the wrong length".to_owned()));
+        }
+
+        let mut journeys = journeys.chunks_exact(JOURNEY_WORDS);
+        let mut legs = legs.chunks_exact(LEG_WORDS);
+        let trip = |route_idx: u32, trip_order: u32| GlobalTripIndex { route_idx: route_idx as _, trip_order: trip_order as _ };
+        let agent_journeys = agents.chunks_exact(AGENT_WORDS).map(|agent| {
+            let result = match agent[7] {
+                0 => {
+                    let journey = journeys.next().ok_or_else(|| CheckpointError::Invalid("missing journeys".to_owned()))?;
+                    let journey_legs = (0..journey[10]).map(|_| {
+                        let leg = legs.next().ok_or_else(|| CheckpointError::Invalid("missing legs".to_owned()))?;
+                        Ok(Leg {
+                            trip: trip(leg[0], leg[1]),
+                            boarded_stop: leg[2] as _,
+                            boarded_stop_order: leg[3] as _,
+                            boarded_time: leg[4] as _,
+                            arrival_stop: leg[5] as _,
+                            arrival_stop_order: leg[6] as _,
+                            arrival_time: leg[7] as _,
+                            transfer_time: if leg[8] == u32::MAX { None } else { Some(leg[8] as _) },
+                        })
+                    }).collect::<Result<Vec<_>, CheckpointError>>()?;
+                    Ok(AgentJourney {
+                        origin_trip: trip(journey[0], journey[1]),
+                        dest_trip: trip(journey[2], journey[3]),
+                        duration: journey[4] as _,
+                        crowding_cost: PathfindingCost::from_bits(journey[5]) as CrowdingCost,
+                        experienced_crowding_cost: PathfindingCost::from_bits(journey[6]) as CrowdingCost,
+                        in_vehicle_time: journey[7] as _,
+                        wait_time: journey[8] as _,
+                        num_transfers: journey[9] as u8,
+                        legs: journey_legs,
+                    })
+                }
+                1 => Err(JourneyError::ZeroAgents),
+                2 => Err(JourneyError::NoJourneyFound),
+                3 => Err(JourneyError::InfiniteLoop),
+                code => return Err(CheckpointError::Invalid(format!("unknown journey result {code}"))),
+            };
+            Ok(AgentJourneyResult {
+                sim_step_idx: agent[0],
+                journey_idx: agent[1],
+                origin_stop: agent[2] as _,
+                dest_stop: agent[3] as _,
+                start_time: agent[4] as _,
+                count: agent[5],
+                segment: agent[6] as _,
+                result,
+            })
+        }).collect::<Result<Vec<_>, CheckpointError>>()?;
+
+        Ok(Self {
+            network_hash,
+            demand_hash,
+            params_hash,
+            num_rounds_run: num_rounds_run as u16,
+            num_converged_rounds: num_converged_rounds as u16,
+            averaged_population,
+            iteration_history,
+            agent_journeys,
+        })
+    }
+
+    // Checks the checkpoint was written by a simulation of the same network, demand and parameters.
+    pub fn verify(&self, network: &Network, simulation_steps: &[SimulationStep], params: &impl Debug) -> Result<(), CheckpointError> {
+        if self.network_hash != network_hash(network) {
+            return Err(CheckpointError::Mismatch("network"));
+        }
+        if self.demand_hash != demand_hash(simulation_steps) {
+            return Err(CheckpointError::Mismatch("demand"));
+        }
+        if self.params_hash != params_hash(params) {
+            return Err(CheckpointError::Mismatch("set of simulation parameters"));
+        }
+        if self.averaged_population.len() != network.stop_times.len() {
+            return Err(CheckpointError::Invalid(format!("{} segment loads for {} stop times", self.averaged_population.len(), network.stop_times.len())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{run_simulation, SimulationResult, StepSize};
+    use crate::test_utils::*;
+    use itertools::Itertools;
+
+    fn round_stats(round_number: u16) -> IterationStats {
+        IterationStats {
+            round_number,
+            step_size: 1. / (round_number as CrowdingCost + 1.),
+            total_crowding_cost: 120.5,
+            relative_change: (round_number > 0).then_some(0.25),
+            relative_load_gap: None,
+            total_passenger_hours: 3.5,
+            max_segment_load: 40,
+            num_replanned: 7,
+            num_plan_cache_hits: 2,
+            num_changed_route: Some(1),
+            switching_cost_improvement: Some(-0.5),
+        }
+    }
+
+    fn agent_journeys() -> Vec<AgentJourneyResult> {
+        let leg = Leg {
+            trip: GlobalTripIndex { route_idx: 1, trip_order: 2 },
+            boarded_stop: 3,
+            boarded_stop_order: 0,
+            boarded_time: 8 * 3600,
+            arrival_stop: 4,
+            arrival_stop_order: 2,
+            arrival_time: 8 * 3600 + 600,
+            transfer_time: None,
+        };
+        let journey = AgentJourney {
+            origin_trip: leg.trip,
+            dest_trip: leg.trip,
+            duration: 900,
+            crowding_cost: 1.5,
+            experienced_crowding_cost: 2.,
+            in_vehicle_time: 600,
+            wait_time: 300,
+            num_transfers: 0,
+            legs: vec![leg],
+        };
+        let agent_journey = |sim_step_idx: u32, result| AgentJourneyResult { sim_step_idx, journey_idx: 0, origin_stop: 3, dest_stop: 4, start_time: 8 * 3600 - 300, count: 5, segment: 0, result };
+        vec![agent_journey(0, Ok(journey)), agent_journey(1, Err(JourneyError::NoJourneyFound))]
+    }
+
+    // Writes a checkpoint of two rounds into its own folder, returning the folder and the checkpoint.
+    fn write_checkpoint(name: &str) -> (PathBuf, PathBuf) {
+        let checkpointing = Checkpointing { dir: temp_path(name), keep: 1, params_hash: 0x3333, resume: None };
+        let path = checkpointing.write(0x1111, 0x2222, 2, 1, &[0., 2.5, 5.], &[round_stats(0), round_stats(1)], &agent_journeys()).unwrap();
+        (checkpointing.dir, path)
+    }
+
+    // Rewrites a checkpoint's chunks with `edit`, returning the error reading it back.
+    fn read_edited(path: &Path, edit: impl FnOnce(&mut Vec<Vec<u8>>)) -> CheckpointError {
+        let mut chunks = read_bin(path).unwrap();
+        edit(&mut chunks);
+        let mut bytes = Vec::new();
+        write_bin(&chunks.iter().map(Vec::as_slice).collect::<Vec<_>>(), &mut bytes).unwrap();
+        fs::write(path, bytes).unwrap();
+        SimulationCheckpoint::read(path).err().expect("the edited checkpoint should be rejected")
+    }
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let (dir, path) = write_checkpoint("checkpoint_round_trip");
+        let checkpoint = SimulationCheckpoint::read(&path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!((checkpoint.network_hash, checkpoint.demand_hash, checkpoint.params_hash), (0x1111, 0x2222, 0x3333));
+        assert_eq!((checkpoint.num_rounds_run, checkpoint.num_converged_rounds), (2, 1));
+        assert_eq!(checkpoint.averaged_population, [0., 2.5, 5.]);
+        assert_eq!(checkpoint.iteration_history.len(), 2);
+        let stats = checkpoint.iteration_history[1];
+        assert_eq!((stats.round_number, stats.step_size, stats.relative_change, stats.relative_load_gap), (1, 0.5, Some(0.25), None));
+        assert_eq!((stats.max_segment_load, stats.num_replanned, stats.num_changed_route, stats.switching_cost_improvement), (40, 7, Some(1), Some(-0.5)));
+
+        assert_eq!(checkpoint.agent_journeys.len(), 2);
+        let journey = checkpoint.agent_journeys[0].result.as_ref().unwrap();
+        assert_eq!((journey.duration, journey.crowding_cost, journey.wait_time), (900, 1.5, 300));
+        let leg = &journey.legs[0];
+        assert_eq!((leg.trip.route_idx, leg.trip.trip_order, leg.arrival_stop_order, leg.arrival_time, leg.transfer_time), (1, 2, 2, 8 * 3600 + 600, None));
+        assert!(matches!(checkpoint.agent_journeys[1].result, Err(JourneyError::NoJourneyFound)));
+    }
+
+    #[test]
+    fn checkpoint_of_another_format_version_is_rejected() {
+        let (dir, path) = write_checkpoint("checkpoint_version");
+        let newer = read_edited(&path, |chunks| chunks[0][0..4].copy_from_slice(&(CHECKPOINT_VERSION + 1).to_le_bytes()));
+        // Before the version was added the header started with the network hash.
+        let unversioned = read_edited(&path, |chunks| {
+            chunks[0].drain(0..4);
+        });
+        fs::remove_dir_all(dir).unwrap();
+        assert!(matches!(newer, CheckpointError::Mismatch("checkpoint format version")), "{newer}");
+        assert!(matches!(unversioned, CheckpointError::Mismatch("checkpoint format version")), "{unversioned}");
+    }
+
+    #[test]
+    fn checkpoint_history_must_cover_every_round() {
+        let (dir, path) = write_checkpoint("checkpoint_history");
+        // One round's stats short of the two rounds run.
+        let short = read_edited(&path, |chunks| chunks[2].truncate(STATS_WORDS * 8));
+        fs::remove_dir_all(dir).unwrap();
+        assert!(matches!(short, CheckpointError::Invalid(_)), "{short}");
+    }
+
+    #[test]
+    fn resumed_simulation_matches_an_uninterrupted_one() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 2);
+        let mut params = fixture_params(6);
+        params.step_size = StepSize::SuccessiveAverages;
+        params.convergence_tolerance = None;
+        let uninterrupted = run_simulation(&network, &simulation_steps, &params);
+        assert_eq!(uninterrupted.iteration_history.len(), 6);
+
+        // Stopped by the round limit half way through, checkpointing every round.
+        let checkpointing = Checkpointing { dir: temp_path("resumed_simulation"), keep: 1, params_hash: params_hash(&params), resume: None };
+        params.num_rounds = 3;
+        params.checkpointing = Some(checkpointing.clone());
+        let interrupted = run_simulation(&network, &simulation_steps, &params);
+        assert_eq!(interrupted.iteration_history.len(), 3);
+        let checkpoint = SimulationCheckpoint::read(&checkpointing.checkpoint_path(3)).unwrap();
+        fs::remove_dir_all(&checkpointing.dir).unwrap();
+        checkpoint.verify(&network, &simulation_steps, &params).unwrap();
+        assert_eq!(checkpoint.num_rounds_run, 3);
+
+        params.num_rounds = 6;
+        params.checkpointing = Some(Checkpointing { keep: 0, resume: Some(Arc::new(checkpoint)), ..checkpointing });
+        let resumed = run_simulation(&network, &simulation_steps, &params);
+
+        assert_eq!(resumed.population_count, uninterrupted.population_count);
+        // Each agent's journey in the final round, as its duration and the trip segments ridden.
+        let final_journeys = |result: &SimulationResult| {
+            let agent_journeys = result.round_agent_journeys.last().unwrap();
+            (0..agent_journeys.len()).map(|agent_idx| agent_journeys.get(agent_idx).result.ok().map(|journey| {
+                (journey.duration, journey.legs.iter().map(|leg| (leg.trip.route_idx, leg.trip.trip_order, leg.boarded_stop_order, leg.arrival_stop_order)).collect_vec())
+            })).collect_vec()
+        };
+        assert_eq!(final_journeys(&resumed), final_journeys(&uninterrupted));
+        // The rounds before the checkpoint are restored into the history.
+        let history = |result: &SimulationResult| result.iteration_history.iter().map(|stats| (stats.round_number, stats.total_crowding_cost, stats.max_segment_load, stats.num_replanned)).collect_vec();
+        assert_eq!(history(&resumed), history(&uninterrupted));
+    }
+}
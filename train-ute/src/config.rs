@@ -0,0 +1,1014 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use chrono::NaiveDate;
+use gtfs_structures::Gtfs;
+use raptor::journey::JourneyPreferences;
+use raptor::network::{PathfindingCost, Timestamp};
+use raptor::Network;
+
+use crate::access::{self, AccessModel, AccessReport};
+use crate::calibration::Calibration;
+use crate::data_export::{assign_route_styles, HeatGridConfig, OccupancyThresholds, ReportingPeriod, ReportingPeriods, ShapeColouring, TimeWindow};
+use crate::data_import::{self, DataImportError, Disruption, ObservedLoad, RouteFilter};
+use crate::demand::{self, DepartureProfile, DepartureSampler, GravityModel};
+use crate::exporter;
+use crate::replacement::{self, BusReplacement, BusReplacementReport};
+use crate::simulation::{self, CrowdingCost, CrowdingFunc, CrowdingFuncError, DefaultSimulationParams, DemandElasticity, DepartureChoice, DwellModel, Overcapacity, PartySizes, PlanCache, PopulationCount, PopulationSegment, Replanning, RouteChoice, SegmentIndex, SegmentPreferences, SimulationStep, StepSize, TransferPenalties, TripCapacities, TripCapacity};
+use crate::sweep::Sweep;
+use crate::transfer_times::StopTransferTimes;
+use crate::wheelchair::{self, WheelchairModel};
+
+// Commented template written by `train-ute --write-default-config`.
+pub const DEFAULT_CONFIG_TEMPLATE: &str = include_str!("../default_config.toml");
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("IO error reading {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Config parse error: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid value for `{0}`: {1}")]
+    InvalidValue(&'static str, String),
+    #[error("{0}")]
+    CrowdingFunc(#[from] CrowdingFuncError),
+    #[error("Error importing {0}: {1}")]
+    Import(PathBuf, DataImportError),
+}
+
+fn default_trip_capacity() -> TripCapacity {
+    // From VicSig: X'Trapolis 3-car has 264 seated, 133 standing. A 6-car has 794 in total.
+    // Crush capacity is 1394, but that's a bit mean.
+    // https://vicsig.net/suburban/train/X'Trapolis
+    TripCapacity { seated: 528, standing: 266 }
+}
+
+fn default_crowding_function() -> CrowdingFunc {
+    // Matches the defaults in the UI.
+    CrowdingFunc::TwoStep { a0: 0.25, a1: 0.5, a: 5., b: 0.5, c: 0.02 }
+}
+
+fn default_transfer_time() -> Timestamp { 3 * 60 }
+
+fn default_capacity_scale() -> f64 { 1. }
+
+fn default_max_parallel_runs() -> usize { 1 }
+
+fn default_replications() -> usize { 1 }
+
+fn default_demand_scale() -> f64 { 1. }
+
+fn default_cost_utility() -> CrowdingCost { 0.5 }
+
+fn default_schedule_delay_early() -> CrowdingCost { 0.5 }
+
+fn default_schedule_delay_late() -> CrowdingCost { 1. }
+
+fn default_num_rounds() -> u16 { 4 }
+
+fn default_step_size() -> StepSize { StepSize::Full }
+
+fn default_convergence_rounds() -> u16 { 1 }
+
+fn default_replan_fraction() -> CrowdingCost { 1. }
+
+fn default_replan_decay() -> CrowdingCost { 1. }
+
+fn default_bag_size() -> usize { 5 }
+
+fn default_progress_interval() -> usize { 10000 }
+
+fn default_stop_activity_bin() -> Timestamp { 15 * 60 }
+
+// Where downloaded GTFS feeds are cached if the config doesn't say.
+pub const DEFAULT_GTFS_CACHE_DIR: &str = "../gtfs/cache";
+
+// Stops in different feeds with the same name and within this many metres are merged.
+pub const DEFAULT_STOP_MERGE_DISTANCE: f64 = 100.;
+
+fn default_stop_merge_distance() -> f64 { DEFAULT_STOP_MERGE_DISTANCE }
+
+fn default_gtfs_cache_dir() -> PathBuf { PathBuf::from(DEFAULT_GTFS_CACHE_DIR) }
+
+fn default_export_dir() -> PathBuf { PathBuf::from("../train_ute_export") }
+
+fn default_exporters() -> Vec<String> { exporter::DEFAULT_EXPORTERS.iter().map(|&name| name.to_owned()).collect() }
+
+fn default_num_crowded_trips() -> usize { 10 }
+
+fn default_shape_dist_km() -> f64 { 0.001 }
+
+fn default_periods() -> Vec<ReportingPeriod> {
+    vec![
+        ReportingPeriod { name: "am_peak".to_owned(), window: TimeWindow { start: 7 * 60 * 60, end: 9 * 60 * 60 } },
+        ReportingPeriod { name: "pm_peak".to_owned(), window: TimeWindow { start: 16 * 60 * 60, end: 18 * 60 * 60 + 30 * 60 } },
+    ]
+}
+
+fn open(path: &Path) -> Result<File, ConfigError> {
+    File::open(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))
+}
+
+// Parses a crowding function from an inline TOML table, e.g. `{ func = "exponential", params = { beta = 5.0 } }`.
+pub fn parse_crowding_function(inline_table: &str) -> Result<CrowdingFunc, ConfigError> {
+    #[derive(serde::Deserialize)]
+    struct Wrapper {
+        crowding_function: CrowdingFunc,
+    }
+
+    let wrapper: Wrapper = toml::from_str(&format!("crowding_function = {inline_table}"))?;
+    wrapper.crowding_function.validate()?;
+    Ok(wrapper.crowding_function)
+}
+
+// Where the departure times of generated agents and OD rows without a time come from.
+#[derive(Clone, Debug)]
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "camelCase", tag = "kind")]
+pub enum DepartureProfileConfig {
+    // Normally distributed peaks, which default to the AM and PM peaks.
+    Peaks(DepartureProfile),
+    // CSV of time_bin,weight (see `data_import::import_departure_profile`).
+    Bins { file: PathBuf },
+}
+
+// All the parameters needed for a simulation run, usually loaded from a TOML file.
+// Paths are relative to the working directory, not the config file.
+#[derive(Debug)]
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunConfig {
+    // A local file, or an http(s) URL to download the feed from.
+    pub gtfs_path: PathBuf,
+    // Further feeds (e.g. trams as well as trains) merged with the first one into a single network.
+    #[serde(default)]
+    pub additional_gtfs_paths: Vec<PathBuf>,
+    // Stops in different feeds with the same name and within this many metres are treated as the same stop.
+    #[serde(default = "default_stop_merge_distance")]
+    pub stop_merge_distance: f64,
+    // Where downloaded GTFS feeds are cached.
+    #[serde(default = "default_gtfs_cache_dir")]
+    pub gtfs_cache_dir: PathBuf,
+    pub date: NaiveDate,
+    // Only build the network from routes with these GTFS route_type codes (e.g. 2 for rail). Empty keeps every route type.
+    #[serde(default)]
+    pub route_types: Vec<i16>,
+    // Only build the network from these GTFS route ids. Empty keeps every route.
+    #[serde(default)]
+    pub route_ids: Vec<String>,
+    // Replace stops with their GTFS parent station, so each station's platforms are one stop in the network and the exports.
+    #[serde(default)]
+    pub parent_stations: bool,
+    // Number of randomly generated agents. If not set, one agent is generated every second of the day.
+    // Uniformly random agents travel in groups of one to ten, while the gravity model generates exactly this many agents.
+    #[serde(default)]
+    pub num_agents: Option<usize>,
+    // Weights of randomly generated parties of 1, 2, 3, ... agents, which travel together and are never split.
+    // If empty, uniformly random agents travel in groups of one to ten that can be split.
+    #[serde(default)]
+    pub party_size_weights: Vec<f64>,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    // Optional CSV of origin_stop_id,destination_stop_id,departure_time,count demand. Replaces random agent generation.
+    #[serde(default)]
+    pub od_matrix: Option<PathBuf>,
+    // Optional CSV of origin_lat,origin_lon,destination_lat,destination_lon,departure_time,count demand between addresses,
+    // which walk to and from nearby stops using `access`. Can't be used with `od_matrix`.
+    #[serde(default)]
+    pub point_od_matrix: Option<PathBuf>,
+    #[serde(default)]
+    pub access: AccessModel,
+    // Agents needing wheelchair-accessible journeys, on top of those marked in the OD matrix's requires_accessible column.
+    #[serde(default)]
+    pub wheelchair: WheelchairModel,
+    // Multiplier on every OD matrix count, e.g. 10 when the OD matrix is a 10% sample.
+    #[serde(default = "default_demand_scale")]
+    pub demand_scale: f64,
+    // Fail instead of leaving out the OD matrix pairs with no journey on the date.
+    #[serde(default)]
+    pub fail_on_unreachable: bool,
+    // Optional CSV of stop_id,weight (e.g. catchment population). Without an OD matrix, `num_agents` agents are then
+    // generated with the gravity model instead of uniformly at random.
+    #[serde(default)]
+    pub stop_weights: Option<PathBuf>,
+    #[serde(default)]
+    pub gravity: GravityModel,
+    // Departure profile for random agents, the gravity model (instead of `gravity.profile`) and OD rows with an empty
+    // departure_time. Without one, random agents are spread evenly through the day and OD rows need a time.
+    #[serde(default)]
+    pub departure_profile: Option<DepartureProfileConfig>,
+    // Optional CSV of trip_id,from_stop_id,to_stop_id,observed_load survey data to validate the simulated loads against.
+    #[serde(default)]
+    pub observed_loads: Option<PathBuf>,
+    // Optional stop_times-like CSV of trip_id,route_id,stop_id,arrival_time,departure_time(,shape_id) trips to add to the timetable.
+    #[serde(default)]
+    pub supplementary_trips: Option<PathBuf>,
+    // Optional CSV of trip_id,action,stop_id trips to cancel or truncate before simulating.
+    #[serde(default)]
+    pub disruption: Option<PathBuf>,
+    // Sections of line where buses replace the trains on some dates.
+    #[serde(default)]
+    pub bus_replacements: Vec<BusReplacement>,
+    // Optional GTFS-realtime TripUpdates feed (or a directory of .pb feeds) of what actually ran, to shift the timetable by.
+    // Needs the gtfs_rt feature.
+    #[serde(default)]
+    pub trip_updates: Option<PathBuf>,
+    // Optional CSV of stop_id,capacity giving how many people each station can hold, for the stop occupancy load factors.
+    #[serde(default)]
+    pub stop_capacities: Option<PathBuf>,
+    // Search for the crowding function and capacity scale that best match the observed loads before the final run.
+    #[serde(default)]
+    pub calibration: Option<Calibration>,
+    // Grids of parameters to run every combination of instead of a single run.
+    #[serde(default)]
+    pub sweep: Option<Sweep>,
+    // Minimum time (in seconds) to change between trips at a stop.
+    #[serde(default = "default_transfer_time")]
+    pub default_transfer_time: Timestamp,
+    // Use the min_transfer_time of the feeds' transfers.txt for the transfers they list.
+    #[serde(default)]
+    pub gtfs_transfer_times: bool,
+    // Optional CSV of from_stop_id,to_stop_id,seconds minimum transfer times, which win over the feeds' and the default.
+    #[serde(default)]
+    pub transfer_times: Option<PathBuf>,
+    #[serde(default = "default_trip_capacity")]
+    pub trip_capacity: TripCapacity,
+    // Optional CSV of trip_id,seated,standing or trip_id,consist (using the consists table) overriding
+    // the route and default capacities for individual trips.
+    #[serde(default)]
+    pub trip_capacities: Option<PathBuf>,
+    // Capacity of each consist code used in a trip_id,consist file.
+    #[serde(default)]
+    pub consists: HashMap<String, TripCapacity>,
+    // Optional CSV of route_id,seated,standing overriding the default capacity for every trip of a route.
+    #[serde(default)]
+    pub route_capacities: Option<PathBuf>,
+    // Every capacity (default, route and trip) is multiplied by this, e.g. to calibrate against observed loads.
+    #[serde(default = "default_capacity_scale")]
+    pub capacity_scale: f64,
+    // Deny boarding to agents once a trip reaches its total capacity.
+    #[serde(default)]
+    pub strict_capacity: bool,
+    #[serde(default = "default_crowding_function")]
+    pub crowding_function: CrowdingFunc,
+    // How the crowding cost continues past a trip's total capacity.
+    #[serde(default)]
+    pub overcapacity: Overcapacity,
+    // Weighting of crowding cost against journey time in the journey utility function.
+    #[serde(default = "default_cost_utility")]
+    pub cost_utility: CrowdingCost,
+    // Equivalent seconds of journey time per transfer when choosing journeys (route_choice has its own coefficient).
+    #[serde(default)]
+    pub transfer_penalty: CrowdingCost,
+    // Weight of each second waiting at the origin and between legs, on top of it counting as journey time.
+    #[serde(default)]
+    pub wait_time_weight: CrowdingCost,
+    // Cost of each second departing before or after the preferred departure, in seconds of journey time, for departure_choice.
+    #[serde(default = "default_schedule_delay_early")]
+    pub schedule_delay_early: CrowdingCost,
+    #[serde(default = "default_schedule_delay_late")]
+    pub schedule_delay_late: CrowdingCost,
+    // Maximum number of simulation rounds.
+    #[serde(default = "default_num_rounds")]
+    pub num_rounds: u16,
+    // How loads are averaged between rounds.
+    #[serde(default = "default_step_size")]
+    pub step_size: StepSize,
+    // Stop early once the relative change in total crowding cost between rounds falls below this.
+    #[serde(default)]
+    pub convergence_tolerance: Option<f64>,
+    // Number of consecutive rounds the change must stay below the tolerance before stopping.
+    #[serde(default = "default_convergence_rounds")]
+    pub convergence_rounds: u16,
+    // Proportion of agents that recompute their journey each round after the first. The rest keep their previous plan.
+    #[serde(default = "default_replan_fraction")]
+    pub replan_fraction: CrowdingCost,
+    // The replan fraction is multiplied by this each round.
+    #[serde(default = "default_replan_decay")]
+    pub replan_decay: CrowdingCost,
+    // Optional crowding-dependent dwell times, which delay heavily loaded trips in the following rounds.
+    #[serde(default)]
+    pub dwell: Option<DwellModel>,
+    // Optional logit route choice over the Pareto bag of journeys, instead of always taking the lowest cost journey.
+    #[serde(default)]
+    pub route_choice: Option<RouteChoice>,
+    // Optional elastic demand, where agents facing much worse journeys than on an uncrowded network may not travel.
+    #[serde(default)]
+    pub elasticity: Option<DemandElasticity>,
+    // Optional plan cache, so agents due to replan keep their previous journey while crowding hasn't made it much worse.
+    #[serde(default)]
+    pub plan_cache: Option<PlanCache>,
+    // Optional departure time choice, where agents may shift their departure within a window to avoid crowding.
+    #[serde(default)]
+    pub departure_choice: Option<DepartureChoice>,
+    // Optional population segments, each weighting crowding against journey time in their own way (instead of `cost_utility`).
+    // Agents are split between them at random using the seed, in proportion to their shares.
+    #[serde(default)]
+    pub segments: Vec<PopulationSegment>,
+    #[serde(default = "default_bag_size")]
+    pub bag_size: usize,
+    // Number of threads to simulate with. If not set, all the available processors are used.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    // Number of sweep runs or replications simulated at the same time, which each hold their own loads and journeys.
+    #[serde(default = "default_max_parallel_runs")]
+    pub max_parallel_runs: usize,
+    // Number of times the run is repeated with the seeds seed, seed + 1, ..., aggregating the loads and statistics.
+    #[serde(default = "default_replications")]
+    pub replications: usize,
+    // Number of simulation steps each thread assigns at a time. If not set, it's sized from the network and number of threads.
+    #[serde(default)]
+    pub chunk_size: Option<usize>,
+    // Print progress every this many simulation steps (0 disables progress reporting).
+    #[serde(default = "default_progress_interval")]
+    pub progress_interval: usize,
+    // Write a checkpoint to the export folder at the end of each round, keeping this many of the newest (0 disables checkpoints).
+    #[serde(default)]
+    pub keep_checkpoints: usize,
+    #[serde(default = "default_export_dir")]
+    pub export_dir: PathBuf,
+    // Names of the exporters (see `exporter::ExporterRegistry`) run into the export folder, alongside the other exports.
+    #[serde(default = "default_exporters")]
+    pub exporters: Vec<String>,
+    // Also write the agent_journeys table with the sqlite exporter, which is most of the database's size.
+    #[serde(default)]
+    pub sqlite_journeys: bool,
+    // Also export a loads.csv with one row per trip segment.
+    #[serde(default)]
+    pub export_loads: bool,
+    // Also export a transfer_times.csv with the transfer time used between each pair of stops, when there are per-stop times.
+    #[serde(default)]
+    pub export_transfer_times: bool,
+    // Also export a loads.geojson with a line for every trip segment, for use in GIS software.
+    #[serde(default)]
+    pub export_geojson: bool,
+    // Also export a stop_activity.csv with the boardings, alightings and transfers at each stop per time bin.
+    #[serde(default)]
+    pub export_stop_activity: bool,
+    // Also export a stop_occupancy.csv (and stop_occupancy.bin.zip for the visualiser) with the average number of agents
+    // waiting or transferring at each stop per time bin.
+    #[serde(default)]
+    pub export_stop_occupancy: bool,
+    // Width (in seconds) of the stop activity and occupancy time bins, and of the departures histogram bins.
+    #[serde(default = "default_stop_activity_bin")]
+    pub stop_activity_bin: Timestamp,
+    // Also export an occupancy.csv with the GTFS-realtime occupancy status departing each stop of every trip.
+    #[serde(default)]
+    pub export_occupancy: bool,
+    // Also export the occupancy as a GTFS-realtime occupancy.pb feed. Needs the gtfs_rt feature.
+    #[serde(default)]
+    pub export_occupancy_feed: bool,
+    // Also export an od_matrix.csv with the agents assigned between each pair of first boarding and last alighting stops.
+    #[serde(default)]
+    pub export_od_matrix: bool,
+    // Combine stops into their GTFS parent stations in the OD matrix.
+    #[serde(default)]
+    pub od_matrix_parent_stations: bool,
+    // Also export an events.ndjson with every boarding, alighting and transfer of the final round in time order.
+    #[serde(default)]
+    pub export_events: bool,
+    // Also export the events as a MATSim events.xml, gzipped if matsim_events_gzip is set.
+    #[serde(default)]
+    pub export_matsim_events: bool,
+    #[serde(default)]
+    pub matsim_events_gzip: bool,
+    // Load factors at which the occupancy status changes, which depend on the rolling stock's seated and standing split.
+    #[serde(default)]
+    pub occupancy_thresholds: OccupancyThresholds,
+    // Also export a heat_grid.csv (and heat_grid.bin.zip for the visualiser) with the load factor of each route per time bin.
+    #[serde(default)]
+    pub export_heat_grid: bool,
+    #[serde(default)]
+    pub heat_grid: HeatGridConfig,
+    // Kilometres per unit of the GTFS shape_dist_traveled, used for the segment lengths in stats.json.
+    #[serde(default = "default_shape_dist_km")]
+    pub shape_dist_km: f64,
+    // Print this many of the most crowded trips (by max load factor) at the end of the run.
+    #[serde(default = "default_num_crowded_trips")]
+    pub num_crowded_trips: usize,
+    // Parts of the day the route summary, stop boardings and max load points are also broken down by.
+    #[serde(default = "default_periods")]
+    pub periods: Vec<ReportingPeriod>,
+    // What the exported shapes are coloured by.
+    #[serde(default)]
+    pub shape_colouring: ShapeColouring,
+}
+
+impl RunConfig {
+    // Creates a config with the required values set and everything else defaulted.
+    pub fn new(gtfs_path: PathBuf, date: NaiveDate) -> Self {
+        Self {
+            gtfs_path,
+            additional_gtfs_paths: Vec::new(),
+            stop_merge_distance: default_stop_merge_distance(),
+            gtfs_cache_dir: default_gtfs_cache_dir(),
+            date,
+            route_types: Vec::new(),
+            route_ids: Vec::new(),
+            parent_stations: false,
+            num_agents: None,
+            party_size_weights: Vec::new(),
+            seed: None,
+            od_matrix: None,
+            point_od_matrix: None,
+            access: AccessModel::default(),
+            wheelchair: WheelchairModel::default(),
+            demand_scale: default_demand_scale(),
+            fail_on_unreachable: false,
+            stop_weights: None,
+            gravity: GravityModel::default(),
+            departure_profile: None,
+            observed_loads: None,
+            supplementary_trips: None,
+            disruption: None,
+            bus_replacements: Vec::new(),
+            trip_updates: None,
+            stop_capacities: None,
+            calibration: None,
+            sweep: None,
+            default_transfer_time: default_transfer_time(),
+            gtfs_transfer_times: false,
+            transfer_times: None,
+            trip_capacity: default_trip_capacity(),
+            trip_capacities: None,
+            consists: HashMap::new(),
+            route_capacities: None,
+            capacity_scale: default_capacity_scale(),
+            strict_capacity: false,
+            crowding_function: default_crowding_function(),
+            overcapacity: Overcapacity::default(),
+            cost_utility: default_cost_utility(),
+            transfer_penalty: 0.,
+            wait_time_weight: 0.,
+            schedule_delay_early: default_schedule_delay_early(),
+            schedule_delay_late: default_schedule_delay_late(),
+            num_rounds: default_num_rounds(),
+            step_size: default_step_size(),
+            convergence_tolerance: None,
+            convergence_rounds: default_convergence_rounds(),
+            replan_fraction: default_replan_fraction(),
+            replan_decay: default_replan_decay(),
+            dwell: None,
+            route_choice: None,
+            elasticity: None,
+            plan_cache: None,
+            departure_choice: None,
+            segments: Vec::new(),
+            bag_size: default_bag_size(),
+            threads: None,
+            max_parallel_runs: default_max_parallel_runs(),
+            replications: default_replications(),
+            chunk_size: None,
+            progress_interval: default_progress_interval(),
+            keep_checkpoints: 0,
+            export_dir: default_export_dir(),
+            exporters: default_exporters(),
+            sqlite_journeys: false,
+            export_loads: false,
+            export_transfer_times: false,
+            export_geojson: false,
+            export_stop_activity: false,
+            export_stop_occupancy: false,
+            stop_activity_bin: default_stop_activity_bin(),
+            export_occupancy: false,
+            export_occupancy_feed: false,
+            export_od_matrix: false,
+            od_matrix_parent_stations: false,
+            export_events: false,
+            export_matsim_events: false,
+            matsim_events_gzip: false,
+            occupancy_thresholds: OccupancyThresholds::default(),
+            export_heat_grid: false,
+            heat_grid: HeatGridConfig::default(),
+            shape_dist_km: default_shape_dist_km(),
+            num_crowded_trips: default_num_crowded_trips(),
+            periods: default_periods(),
+            shape_colouring: ShapeColouring::default(),
+        }
+    }
+
+    pub fn from_toml(toml_str: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(toml_str)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let toml_str = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Self::from_toml(&toml_str)
+    }
+
+    // Checks values that deserialise fine but don't make sense to simulate with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.stop_merge_distance.is_nan() || self.stop_merge_distance < 0. {
+            return Err(ConfigError::InvalidValue("stop_merge_distance", format!("{} must not be negative", self.stop_merge_distance)));
+        }
+        self.party_sizes().map_err(|e| ConfigError::InvalidValue("party_size_weights", e))?;
+        if self.num_agents == Some(0) {
+            return Err(ConfigError::InvalidValue("num_agents", "must be greater than zero".to_owned()));
+        }
+        self.gravity.deterrence.validate().map_err(|e| ConfigError::InvalidValue("gravity.deterrence", e))?;
+        self.gravity.profile.validate().map_err(|e| ConfigError::InvalidValue("gravity.profile", e))?;
+        if let Some(DepartureProfileConfig::Peaks(profile)) = &self.departure_profile {
+            profile.validate().map_err(|e| ConfigError::InvalidValue("departure_profile", e))?;
+        }
+        if self.trip_capacity.seated == 0 {
+            return Err(ConfigError::InvalidValue("trip_capacity", format!("{:?} must have at least one seat", self.trip_capacity)));
+        }
+        if !self.demand_scale.is_finite() || self.demand_scale <= 0. {
+            return Err(ConfigError::InvalidValue("demand_scale", format!("{} must be greater than zero", self.demand_scale)));
+        }
+        if !self.shape_dist_km.is_finite() || self.shape_dist_km <= 0. {
+            return Err(ConfigError::InvalidValue("shape_dist_km", format!("{} must be greater than zero", self.shape_dist_km)));
+        }
+        if !self.capacity_scale.is_finite() || self.capacity_scale <= 0. {
+            return Err(ConfigError::InvalidValue("capacity_scale", format!("{} must be greater than zero", self.capacity_scale)));
+        }
+        self.crowding_function.validate()?;
+        self.overcapacity.validate()?;
+        if let Some(calibration) = &self.calibration {
+            calibration.validate().map_err(|e| ConfigError::InvalidValue("calibration", e))?;
+            if self.observed_loads.is_none() {
+                return Err(ConfigError::InvalidValue("calibration", "needs observed_loads to calibrate against".to_owned()));
+            }
+        }
+        if let Some(sweep) = &self.sweep {
+            sweep.validate().map_err(|e| ConfigError::InvalidValue("sweep", e))?;
+            if self.calibration.is_some() {
+                return Err(ConfigError::InvalidValue("sweep", "can't be combined with calibration".to_owned()));
+            }
+        }
+        for (consist, capacity) in self.consists.iter() {
+            if capacity.seated == 0 {
+                return Err(ConfigError::InvalidValue("consists", format!("{consist} has capacity {capacity:?}, which must have at least one seat")));
+            }
+        }
+        if !self.cost_utility.is_finite() || self.cost_utility < 0. {
+            return Err(ConfigError::InvalidValue("cost_utility", format!("{} must be a non-negative number", self.cost_utility)));
+        }
+        if !self.transfer_penalty.is_finite() || self.transfer_penalty < 0. {
+            return Err(ConfigError::InvalidValue("transfer_penalty", format!("{} must be a non-negative number", self.transfer_penalty)));
+        }
+        if !self.wait_time_weight.is_finite() || self.wait_time_weight < 0. {
+            return Err(ConfigError::InvalidValue("wait_time_weight", format!("{} must be a non-negative number", self.wait_time_weight)));
+        }
+        if !self.schedule_delay_early.is_finite() || self.schedule_delay_early < 0. {
+            return Err(ConfigError::InvalidValue("schedule_delay_early", format!("{} must be a non-negative number", self.schedule_delay_early)));
+        }
+        if !self.schedule_delay_late.is_finite() || self.schedule_delay_late < 0. {
+            return Err(ConfigError::InvalidValue("schedule_delay_late", format!("{} must be a non-negative number", self.schedule_delay_late)));
+        }
+        if self.num_rounds == 0 {
+            return Err(ConfigError::InvalidValue("num_rounds", "must be greater than zero".to_owned()));
+        }
+        if let StepSize::Constant(step) = self.step_size {
+            if step.is_nan() || step <= 0. || step > 1. {
+                return Err(ConfigError::InvalidValue("step_size", format!("{step} must be in (0, 1]")));
+            }
+        }
+        if let Some(tolerance) = self.convergence_tolerance {
+            if tolerance.is_nan() || tolerance < 0. {
+                return Err(ConfigError::InvalidValue("convergence_tolerance", format!("{tolerance} must not be negative")));
+            }
+        }
+        if self.convergence_rounds == 0 {
+            return Err(ConfigError::InvalidValue("convergence_rounds", "must be greater than zero".to_owned()));
+        }
+        if self.replan_fraction.is_nan() || self.replan_fraction <= 0. || self.replan_fraction > 1. {
+            return Err(ConfigError::InvalidValue("replan_fraction", format!("{} must be in (0, 1]", self.replan_fraction)));
+        }
+        if self.replan_decay.is_nan() || self.replan_decay <= 0. || self.replan_decay > 1. {
+            return Err(ConfigError::InvalidValue("replan_decay", format!("{} must be in (0, 1]", self.replan_decay)));
+        }
+        if let Some(dwell) = &self.dwell {
+            if !(dwell.base.is_finite() && dwell.base >= 0. && dwell.alpha.is_finite() && dwell.alpha >= 0. && dwell.beta.is_finite() && dwell.beta >= 0.) {
+                return Err(ConfigError::InvalidValue("dwell", format!("{dwell:?} must have non-negative base, alpha and beta")));
+            }
+        }
+        if let Some(elasticity) = &self.elasticity {
+            elasticity.validate().map_err(|e| ConfigError::InvalidValue("elasticity", e))?;
+        }
+        if let Some(plan_cache) = &self.plan_cache {
+            plan_cache.validate().map_err(|e| ConfigError::InvalidValue("plan_cache", e))?;
+        }
+        if let Some(departure_choice) = &self.departure_choice {
+            departure_choice.validate().map_err(|e| ConfigError::InvalidValue("departure_choice", e))?;
+        }
+        if let Some(route_choice) = &self.route_choice {
+            let coefficients = [route_choice.time_coefficient, route_choice.crowding_coefficient, route_choice.scale, route_choice.transfer_coefficient, route_choice.wait_coefficient];
+            if !coefficients.iter().all(|coefficient| coefficient.is_finite() && *coefficient >= 0.) {
+                return Err(ConfigError::InvalidValue("route_choice", format!("{route_choice:?} must have non-negative coefficients and scale")));
+            }
+        }
+        if self.segments.len() > SegmentIndex::MAX as usize + 1 {
+            return Err(ConfigError::InvalidValue("segments", format!("{} segments is too many", self.segments.len())));
+        }
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.name.is_empty() || self.segments[..i].iter().any(|other| other.name == segment.name) {
+                return Err(ConfigError::InvalidValue("segments", format!("segment names must be unique and not empty, but {:?} isn't", segment.name)));
+            }
+            if !(segment.share.is_finite() && segment.share >= 0. && segment.crowding_weight.is_finite() && segment.crowding_weight >= 0.) {
+                return Err(ConfigError::InvalidValue("segments", format!("{segment:?} must have a non-negative share and crowding weight")));
+            }
+        }
+        if !self.segments.is_empty() && self.segments.iter().all(|segment| segment.share == 0.) {
+            return Err(ConfigError::InvalidValue("segments", "shares must not all be zero".to_owned()));
+        }
+        if self.od_matrix.is_some() && self.point_od_matrix.is_some() {
+            return Err(ConfigError::InvalidValue("point_od_matrix", "can't be used with od_matrix".to_owned()));
+        }
+        self.access.validate().map_err(|e| ConfigError::InvalidValue("access", e))?;
+        self.wheelchair.validate().map_err(|e| ConfigError::InvalidValue("wheelchair", e))?;
+        for replacement in self.bus_replacements.iter() {
+            replacement.validate().map_err(|e| ConfigError::InvalidValue("bus_replacements", e))?;
+        }
+        let mut replacement_names = HashSet::new();
+        if !self.bus_replacements.iter().all(|replacement| replacement_names.insert(&replacement.name)) {
+            return Err(ConfigError::InvalidValue("bus_replacements", "names must be unique".to_owned()));
+        }
+        if !(1..=5).contains(&self.bag_size) {
+            return Err(ConfigError::InvalidValue("bag_size", format!("{} must be between 1 and 5", self.bag_size)));
+        }
+        self.occupancy_thresholds.validate().map_err(|e| ConfigError::InvalidValue("occupancy_thresholds", e))?;
+        self.shape_colouring.validate().map_err(|e| ConfigError::InvalidValue("shape_colouring", e))?;
+        self.heat_grid.validate().map_err(|e| ConfigError::InvalidValue("heat_grid", e))?;
+        self.reporting_periods().map_err(|e| ConfigError::InvalidValue("periods", e))?;
+        if self.stop_activity_bin == 0 {
+            return Err(ConfigError::InvalidValue("stop_activity_bin", "must be greater than zero".to_owned()));
+        }
+        if self.threads == Some(0) {
+            return Err(ConfigError::InvalidValue("threads", "must be greater than zero".to_owned()));
+        }
+        if self.chunk_size == Some(0) {
+            return Err(ConfigError::InvalidValue("chunk_size", "must be greater than zero".to_owned()));
+        }
+        if self.max_parallel_runs == 0 {
+            return Err(ConfigError::InvalidValue("max_parallel_runs", "must be greater than zero".to_owned()));
+        }
+        if self.replications == 0 {
+            return Err(ConfigError::InvalidValue("replications", "must be greater than zero".to_owned()));
+        }
+        if self.replications > 1 && (self.sweep.is_some() || self.calibration.is_some()) {
+            return Err(ConfigError::InvalidValue("replications", "can't be combined with a sweep or calibration".to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn reporting_periods(&self) -> Result<ReportingPeriods, String> {
+        ReportingPeriods::new(&self.periods)
+    }
+
+    pub fn journey_preferences(&self) -> JourneyPreferences {
+        Self::weighted_journey_preferences(self.cost_utility)
+    }
+
+    fn weighted_journey_preferences(cost_utility: CrowdingCost) -> JourneyPreferences {
+        JourneyPreferences {
+            utility_function: Box::new(move |label, start_time| {
+                (label.arrival_time - start_time) as PathfindingCost + cost_utility as PathfindingCost * label.cost
+            })
+        }
+    }
+
+    pub fn route_filter(&self) -> RouteFilter {
+        RouteFilter { route_types: self.route_types.clone(), route_ids: self.route_ids.clone() }
+    }
+
+    // The configured per-stop transfer times (see `transfer_times`), or None if there are none. `gtfs_paths` are the feeds
+    // as they were read (e.g. the downloaded copies).
+    pub fn load_transfer_times(&self, gtfs_paths: &[impl AsRef<Path>]) -> Result<Option<StopTransferTimes>, ConfigError> {
+        if !self.gtfs_transfer_times && self.transfer_times.is_none() {
+            return Ok(None);
+        }
+        let mut stop_transfer_times = StopTransferTimes::default();
+        if self.gtfs_transfer_times {
+            stop_transfer_times.gtfs = data_import::gtfs_transfer_times(gtfs_paths);
+        }
+        if let Some(path) = &self.transfer_times {
+            stop_transfer_times.overrides = data_import::import_transfer_times(open(path)?).map_err(|e| ConfigError::Import(path.clone(), e))?;
+        }
+        Ok(Some(stop_transfer_times))
+    }
+
+    // Builds the network for the configured date and transfer time, ready for simulation.
+    // Apply the route filter to the GTFS first to leave routes out.
+    pub fn build_network(&self, gtfs: &Gtfs) -> Network {
+        self.build_network_with_transfer_times(gtfs, None)
+    }
+
+    // As build_network, with a transfer time short enough for the per-stop transfer times from `load_transfer_times`.
+    pub fn build_network_with_transfer_times(&self, gtfs: &Gtfs, stop_transfer_times: Option<&StopTransferTimes>) -> Network {
+        let transfer_time = stop_transfer_times.map_or(self.default_transfer_time, |stop_transfer_times| stop_transfer_times.network_transfer_time(self.default_transfer_time));
+        let mut network = Network::new(gtfs, None, self.date, transfer_time);
+        let gtfs_paths = std::iter::once(&self.gtfs_path).chain(&self.additional_gtfs_paths).collect::<Vec<_>>();
+        assign_route_styles(&mut network, gtfs, &data_import::gtfs_routes_without_colour(&gtfs_paths), &self.shape_colouring);
+        network.build_connections();
+        network
+    }
+
+    // Applies the route and trip capacity files (if any) and the replacement buses' capacity on top of the default capacity,
+    // then the capacity scale.
+    pub fn load_capacities(&self, network: &Network, gtfs: &Gtfs, trip_capacities: &mut TripCapacities, bus_replacements: &[BusReplacementReport]) -> Result<(), ConfigError> {
+        if let Some(route_capacities_path) = &self.route_capacities {
+            let route_capacities = data_import::import_route_capacities(open(route_capacities_path)?).map_err(|e| ConfigError::Import(route_capacities_path.clone(), e))?;
+            let unknown_routes = trip_capacities.set_route_capacities(network, gtfs, &route_capacities);
+            log::info!("Loaded capacities for {} routes ({} not in network).", route_capacities.len(), unknown_routes.len());
+        }
+        let mut trip_overrides = HashMap::new();
+        if let Some(trip_capacities_path) = &self.trip_capacities {
+            let import_error = |e: DataImportError| ConfigError::Import(trip_capacities_path.clone(), e);
+            // The header tells us whether this is a rolling stock assignment or explicit capacities.
+            let is_consist_file = csv::Reader::from_reader(open(trip_capacities_path)?).headers().map_err(|e| import_error(e.into()))?.get(1) == Some("consist");
+            trip_overrides = if is_consist_file {
+                data_import::import_trip_consists(open(trip_capacities_path)?, &self.consists)
+            } else {
+                data_import::import_trip_capacities(open(trip_capacities_path)?)
+            }.map_err(import_error)?;
+            log::info!("Loaded capacities for {} trips.", trip_overrides.len());
+        }
+        for report in bus_replacements {
+            trip_overrides.extend(report.shuttle_trip_ids.iter().map(|trip_id| (trip_id.clone(), report.capacity)));
+        }
+        if !trip_overrides.is_empty() {
+            trip_capacities.set_trip_capacities(trip_overrides);
+        }
+        if self.route_capacities.is_some() || self.trip_capacities.is_some() {
+            trip_capacities.check_coverage(network).log();
+        }
+        if self.capacity_scale != 1. {
+            *trip_capacities = trip_capacities.scaled(self.capacity_scale);
+        }
+        Ok(())
+    }
+
+    // Loads the OD matrix if one is configured, otherwise generates `num_agents` agents (see `generate_simulation_steps`).
+    // OD rows using a stop in `excluded_stop_ids` are reported as unassignable.
+    pub fn simulation_steps(&self, network: &Network, excluded_stop_ids: &HashSet<String>) -> Result<Vec<SimulationStep>, ConfigError> {
+        match &self.od_matrix {
+            Some(od_path) => {
+                let departures = self.departure_sampler()?;
+                let simulation_steps = data_import::load_od_matrix(open(od_path)?, network, self.seed, self.demand_scale, excluded_stop_ids, departures.as_ref()).map_err(|e| ConfigError::Import(od_path.clone(), e))?;
+                log::info!("Loaded {} simulation steps from {}.", simulation_steps.len(), od_path.display());
+                Ok(self.assign_wheelchair_users(self.assign_segments(simulation_steps)))
+            }
+            None => self.generate_simulation_steps(network, self.num_agents),
+        }
+    }
+
+    // Loads the point OD matrix if one is configured, with the stops each row walks to and from.
+    pub fn load_point_od_matrix(&self, network: &Network) -> Result<Option<(Vec<SimulationStep>, AccessReport)>, ConfigError> {
+        let Some(od_path) = &self.point_od_matrix else {
+            return Ok(None);
+        };
+        let (simulation_steps, report) = access::load_point_od_matrix(open(od_path)?, network, &self.access, self.seed, self.demand_scale).map_err(|e| ConfigError::Import(od_path.clone(), e))?;
+        log::info!("Loaded {} simulation steps from {}.", simulation_steps.len(), od_path.display());
+        report.log();
+        Ok(Some((self.assign_wheelchair_users(self.assign_segments(simulation_steps)), report)))
+    }
+
+    // Generates agents with the gravity model if stop weights are configured, otherwise uniformly at random.
+    pub fn generate_simulation_steps(&self, network: &Network, num_agents: Option<usize>) -> Result<Vec<SimulationStep>, ConfigError> {
+        let departures = self.departure_sampler()?;
+        let party_sizes = self.party_sizes().map_err(|e| ConfigError::InvalidValue("party_size_weights", e))?;
+        let simulation_steps = match (&self.stop_weights, departures) {
+            (Some(weights_path), departures) => {
+                let weights = data_import::import_stop_weights(open(weights_path)?, network).map_err(|e| ConfigError::Import(weights_path.clone(), e))?;
+                let departures = departures.unwrap_or_else(|| DepartureSampler::Peaks(self.gravity.profile.clone()));
+                demand::gen_gravity_simulation_steps(network, &weights, num_agents, &self.gravity.deterrence, &departures, self.seed)
+            }
+            (None, Some(departures)) => demand::gen_profiled_simulation_steps(network, num_agents, &departures, self.seed, party_sizes.as_ref()),
+            (None, None) => simulation::gen_simulation_steps(network, num_agents, self.seed, party_sizes.as_ref()),
+        };
+        Ok(self.assign_wheelchair_users(self.assign_segments(simulation_steps)))
+    }
+
+    // The party size distribution of randomly generated agents, if weights are configured.
+    pub fn party_sizes(&self) -> Result<Option<PartySizes>, String> {
+        if self.party_size_weights.is_empty() {
+            return Ok(None);
+        }
+        PartySizes::new(self.party_size_weights.clone()).map(Some)
+    }
+
+    // Splits the agents between the population segments (if any), normalising the shares if they don't add up to one.
+    fn assign_segments(&self, simulation_steps: Vec<SimulationStep>) -> Vec<SimulationStep> {
+        if self.segments.is_empty() {
+            return simulation_steps;
+        }
+        let total_share = self.segments.iter().map(|segment| segment.share).sum::<f64>();
+        if (total_share - 1.).abs() > 1e-6 {
+            log::warn!("Population segment shares add up to {total_share}, so they have been normalised.");
+        }
+        let shares = self.segments.iter().map(|segment| segment.share / total_share).collect::<Vec<_>>();
+        // Same seed as agent generation, so a seeded run is reproducible.
+        simulation::assign_segments(simulation_steps, &shares, self.seed.unwrap_or(0))
+    }
+
+    // Marks the configured share of agents as needing wheelchair access.
+    fn assign_wheelchair_users(&self, simulation_steps: Vec<SimulationStep>) -> Vec<SimulationStep> {
+        // Offset from the agent generation seed, so the draws don't line up with the segments'.
+        wheelchair::assign_wheelchair_users(simulation_steps, self.wheelchair.share, self.seed.unwrap_or(0).wrapping_add(1))
+    }
+
+    pub fn segment_names(&self) -> Vec<String> {
+        self.segments.iter().map(|segment| segment.name.clone()).collect()
+    }
+
+    // Loads the configured departure profile, if any.
+    pub fn departure_sampler(&self) -> Result<Option<DepartureSampler>, ConfigError> {
+        match &self.departure_profile {
+            None => Ok(None),
+            Some(DepartureProfileConfig::Peaks(profile)) => Ok(Some(DepartureSampler::Peaks(profile.clone()))),
+            Some(DepartureProfileConfig::Bins { file }) => {
+                let profile = data_import::import_departure_profile(open(file)?).map_err(|e| ConfigError::Import(file.clone(), e))?;
+                log::info!("Loaded a departure profile of {} time bins from {}.", profile.bins.len(), file.display());
+                Ok(Some(DepartureSampler::Bins(profile)))
+            }
+        }
+    }
+
+    // Adds the supplementary trips (if any) to the GTFS, running on each of the dates, returning their ids.
+    pub fn add_supplementary_trips(&self, gtfs: &mut Gtfs, dates: &[NaiveDate]) -> Result<Vec<String>, ConfigError> {
+        let Some(trips_path) = &self.supplementary_trips else {
+            return Ok(Vec::new());
+        };
+        let trips = data_import::import_supplementary_trips(open(trips_path)?, gtfs).map_err(|e| ConfigError::Import(trips_path.clone(), e))?;
+        log::info!("Adding {} supplementary trips from {}.", trips.len(), trips_path.display());
+        let trip_ids = trips.iter().map(|trip| trip.id.clone()).collect();
+        data_import::add_supplementary_trips(gtfs, trips, dates);
+        Ok(trip_ids)
+    }
+
+    // Replaces the trains with buses for the bus replacements on the configured date, returning what each changed.
+    pub fn apply_bus_replacements(&self, gtfs: &mut Gtfs) -> Result<Vec<BusReplacementReport>, ConfigError> {
+        let mut reports = Vec::new();
+        for bus_replacement in self.bus_replacements.iter().filter(|bus_replacement| bus_replacement.applies_on(self.date)) {
+            let report = replacement::apply_bus_replacement(gtfs, bus_replacement, self.date).map_err(|e| ConfigError::InvalidValue("bus_replacements", e))?;
+            report.log();
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    // Reads the disruption file, checking its trips and stops against the GTFS before anything is removed from it.
+    pub fn load_disruption(&self, gtfs: &Gtfs) -> Result<Option<Disruption>, ConfigError> {
+        let Some(disruption_path) = &self.disruption else {
+            return Ok(None);
+        };
+        let disruption = data_import::import_disruption(open(disruption_path)?, gtfs).map_err(|e| ConfigError::Import(disruption_path.clone(), e))?;
+        log::info!("Disruption cancels {} trips and truncates {}.", disruption.cancelled_trips.len(), disruption.truncated_trips.len());
+        Ok(Some(disruption))
+    }
+
+    // Applies the trip updates (if any) to the GTFS. Call before building the network.
+    #[cfg(feature = "gtfs_rt")]
+    pub fn apply_trip_updates(&self, gtfs: &mut Gtfs) -> Result<Option<crate::realtime::TripUpdateReport>, ConfigError> {
+        let Some(trip_updates_path) = &self.trip_updates else {
+            return Ok(None);
+        };
+        let trip_updates = crate::realtime::import_trip_updates(trip_updates_path).map_err(|e| ConfigError::Import(trip_updates_path.clone(), e))?;
+        let report = crate::realtime::apply_trip_updates(gtfs, self.date, &trip_updates);
+        report.log();
+        Ok(Some(report))
+    }
+
+    pub fn load_stop_capacities(&self, network: &Network) -> Result<Option<Vec<Option<PopulationCount>>>, ConfigError> {
+        let Some(stop_capacities_path) = &self.stop_capacities else {
+            return Ok(None);
+        };
+        let stop_capacities = data_import::import_stop_capacities(open(stop_capacities_path)?, network).map_err(|e| ConfigError::Import(stop_capacities_path.clone(), e))?;
+        log::info!("Loaded capacities for {} stops from {}.", stop_capacities.iter().flatten().count(), stop_capacities_path.display());
+        Ok(Some(stop_capacities))
+    }
+
+    pub fn load_observed_loads(&self) -> Result<Option<Vec<ObservedLoad>>, ConfigError> {
+        let Some(observed_loads_path) = &self.observed_loads else {
+            return Ok(None);
+        };
+        let observations = data_import::import_observed_loads(open(observed_loads_path)?).map_err(|e| ConfigError::Import(observed_loads_path.clone(), e))?;
+        log::info!("Loaded {} observed loads from {}.", observations.len(), observed_loads_path.display());
+        Ok(Some(observations))
+    }
+
+    pub fn simulation_params(&self) -> DefaultSimulationParams<'static> {
+        DefaultSimulationParams {
+            crowding_function: self.crowding_function.clone(),
+            overcapacity: self.overcapacity,
+            progress_callback: None,
+            journey_preferences: self.journey_preferences(),
+            num_rounds: self.num_rounds,
+            bag_size: self.bag_size,
+            trip_capacities: TripCapacities::new(self.trip_capacity, HashMap::new()),
+            strict_capacity: self.strict_capacity,
+            step_size: self.step_size,
+            convergence_tolerance: self.convergence_tolerance,
+            convergence_rounds: self.convergence_rounds,
+            replanning: Replanning {
+                fraction: self.replan_fraction,
+                decay: self.replan_decay,
+                // Same seed as agent generation, so a seeded run is reproducible.
+                seed: self.seed.unwrap_or(0),
+            },
+            dwell_model: self.dwell,
+            // Same seed as agent generation, so a seeded run is reproducible.
+            route_choice: self.route_choice.map(|route_choice| RouteChoice { seed: self.seed.unwrap_or(0), ..route_choice }),
+            segments: self.segments.iter().map(|segment| SegmentPreferences {
+                crowding_weight: segment.crowding_weight,
+                journey_preferences: Self::weighted_journey_preferences(segment.crowding_weight),
+            }).collect(),
+            cancellation: None,
+            checkpointing: None,
+            // Crowding is weighed against time as in the journey preferences, and the seed is shared for reproducibility.
+            elasticity: self.elasticity.map(|elasticity| DemandElasticity { crowding_weight: self.cost_utility, seed: self.seed.unwrap_or(0), ..elasticity }),
+            warm_start: None,
+            chunk_size: self.chunk_size,
+            plan_cache: self.plan_cache,
+            // Needs the GTFS, so is loaded separately.
+            wheelchair_access: None,
+            // Offset from the agent generation seed like the wheelchair users, so the draws don't line up with replanning's.
+            departure_choice: self.departure_choice.map(|departure_choice| DepartureChoice {
+                early_coefficient: self.schedule_delay_early,
+                late_coefficient: self.schedule_delay_late,
+                crowding_weight: self.cost_utility,
+                seed: self.seed.unwrap_or(0).wrapping_add(2),
+                ..departure_choice
+            }),
+            // Needs the network, so is resolved separately.
+            transfer_times: None,
+            transfer_penalties: TransferPenalties {
+                transfer_penalty: self.transfer_penalty,
+                wait_time_weight: self.wait_time_weight,
+                crowding_weight: self.cost_utility,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL_CONFIG: &str = r#"
+        gtfs_path = "feed.zip"
+        date = "2024-06-03"
+    "#;
+
+    fn invalid_key(config: &str) -> Option<&'static str> {
+        match RunConfig::from_toml(config) {
+            Err(ConfigError::InvalidValue(key, _)) => Some(key),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn default_template_is_valid() {
+        let config = RunConfig::from_toml(DEFAULT_CONFIG_TEMPLATE).unwrap();
+        assert_eq!(config.date, NaiveDate::from_ymd_opt(2024, 6, 3).unwrap());
+        assert_eq!(config.default_transfer_time, default_transfer_time());
+    }
+
+    #[test]
+    fn missing_values_are_defaulted() {
+        let config = RunConfig::from_toml(MINIMAL_CONFIG).unwrap();
+        assert_eq!(config.gtfs_path, PathBuf::from("feed.zip"));
+        assert_eq!(config.num_rounds, default_num_rounds());
+        assert_eq!(config.bag_size, default_bag_size());
+        assert_eq!(config.num_agents, None);
+        assert!(matches!(config.step_size, StepSize::Full));
+    }
+
+    #[test]
+    fn missing_required_key_is_named() {
+        let error = RunConfig::from_toml(r#"gtfs_path = "feed.zip""#).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse(_)));
+        assert!(error.to_string().contains("date"), "{error}");
+    }
+
+    #[test]
+    fn unknown_key_is_rejected() {
+        let error = RunConfig::from_toml(&format!("{MINIMAL_CONFIG}\nnum_agent = 10")).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse(_)));
+        assert!(error.to_string().contains("num_agent"), "{error}");
+    }
+
+    #[test]
+    fn invalid_values_are_rejected_with_their_key() {
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nnum_agents = 0")), Some("num_agents"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nbag_size = 6")), Some("bag_size"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nnum_rounds = 0")), Some("num_rounds"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\ntransfer_penalty = -60.0")), Some("transfer_penalty"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\ntrip_capacity = {{ seated = 0, standing = 10 }}")), Some("trip_capacity"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nstep_size = {{ method = \"constant\", step = 1.5 }}")), Some("step_size"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nreplications = 2\nsweep = {{ beta = [1.0, 5.0] }}")), Some("replications"));
+        assert_eq!(invalid_key(&format!("{MINIMAL_CONFIG}\nod_matrix = \"od.csv\"\npoint_od_matrix = \"points.csv\"")), Some("point_od_matrix"));
+    }
+
+    #[test]
+    fn invalid_crowding_function_is_rejected() {
+        let error = RunConfig::from_toml(&format!("{MINIMAL_CONFIG}\ncrowding_function = {{ func = \"exponential\", params = {{ beta = -1.0 }} }}")).unwrap_err();
+        assert!(matches!(error, ConfigError::CrowdingFunc(_)), "{error}");
+        assert!(parse_crowding_function(r#"{ func = "exponential", params = { beta = 5.0 } }"#).is_ok());
+        assert!(parse_crowding_function(r#"{ func = "step", params = { thresholds = [] } }"#).is_err());
+    }
+}
@@ -1,22 +1,29 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::sync::Arc;
 
-use arrow::array::{Array, ArrayRef, Float32Array, StringArray, Time64MicrosecondArray, TimestampMillisecondArray, UInt32Array};
+use arrow::array::{Array, ArrayRef, Float32Array, Float64Array, StringArray, Time64MicrosecondArray, TimestampMillisecondArray, UInt32Array};
 use arrow::datatypes::{Field, Schema};
+use gtfs_structures::{DirectionType, Gtfs};
 use itertools::{izip, Itertools};
 use parquet::arrow::ArrowWriter;
 use parquet::basic::Compression;
 use parquet::file::properties::WriterProperties;
+use rayon::prelude::*;
 use rgb::RGB8;
 use zip::write::SimpleFileOptions;
 use zip::ZipWriter;
 
-use crate::simulation::{SimulationResult, TripCapacities};
+use crate::data_import::{parse_time, Disruption, DisruptionReport, ParentStations};
+use crate::replacement::is_replacement_trip;
+use crate::stop_geometry::StopGeometry;
+use crate::simulation::{AgentCount, CrowdingCost, DefaultSimulationParams, JourneyRef, PopulationCount, SimulationParams, SimulationResult, SimulationStep, TripCapacities, TripCapacity};
 use crate::utils::{mix_rgb, quadratic_ease_in_out, quadratic_inv_ease_in_out};
 use raptor::journey::JourneyError;
-use raptor::network::{CoordType, NetworkPoint, Timestamp};
+use raptor::network::{CoordType, NetworkPoint, StopIndex, Timestamp};
 use raptor::utils::get_time_str;
 use raptor::Network;
 
@@ -24,6 +31,18 @@ use raptor::Network;
 pub enum DataExportError {
     #[error("No data to export.")]
     NoData,
+    #[error("Missing data: {0}.")]
+    MissingData(&'static str),
+    #[error("Binary chunk {0} of {1} bytes is too large for 32-bit offsets.")]
+    TooLarge(usize, usize),
+    #[error("Invalid binary export: {0}.")]
+    InvalidBin(String),
+    #[error("Binary chunk {0} was summed as {1} bytes, but {2} bytes were written.")]
+    ChunkMismatch(usize, usize, usize),
+    #[error("Round {0} has {1} agent journeys, but the first round has {2}.")]
+    RoundMismatch(usize, usize, usize),
+    #[error("Zip error: {0}.")]
+    ZipError(#[from] zip::result::ZipError),
     #[error("IO error: {0}.")]
     IoError(#[from] std::io::Error),
     #[error("Arrow error: {0}.")]
@@ -32,39 +51,258 @@ pub enum DataExportError {
     ParquetError(#[from] parquet::errors::ParquetError),
     #[error("CSV error: {0}.")]
     CsvError(#[from] csv::Error),
+    #[error("Not bundling the results, as these exports failed: {}.", .0.join(", "))]
+    IncompleteBundle(Vec<String>),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}.")]
+    SqliteError(#[from] rusqlite::Error),
+}
+
+// Every binary export starts with a fixed header of:
+// - The magic bytes "WOBB".
+// - The format version (u16), followed by two bytes of padding.
+// - The chunk count (u32).
+// - A CRC32 of the rest of the file (u32).
+// Version 2 adds the stop sizes and names chunks to the stops export. New chunks are only ever appended, so every version
+// back to BIN_MIN_VERSION can still be read.
+// Version 3 adds the f64 times chunk to the trips export, as f32 only has 24 bits of precision: enough for whole seconds of
+// a day, but the interpolated times lose their fractions as they grow, and unix timestamps would be rounded to minutes.
+// The old f32 times chunk is still filled, for readers not yet updated.
+// Version 4 adds the f32 crowding cost of each point to the trips export, so trips can be coloured by perceived crowding.
+pub const BIN_MAGIC: [u8; 4] = *b"WOBB";
+pub const BIN_VERSION: u16 = 4;
+pub const BIN_MIN_VERSION: u16 = 1;
+const BIN_FIXED_HEADER_SIZE: usize = 16;
+
+// Simple power-of-two alignment.
+fn round_up_to_eight(num: usize) -> usize { (num + 7) & !7 }
+
+// Calculates the 32-bit byte offset and length of each data chunk in the header written by write_bin.
+// Takes only the chunk lengths, so the size limits can be checked without having the data.
+fn bin_header(chunk_lengths: impl ExactSizeIterator<Item=usize>) -> Result<Vec<(u32, u32)>, DataExportError> {
+    if chunk_lengths.len() == 0 {
+        return Err(DataExportError::NoData);
+    }
+
+    // 2 32-bit values per data chunk.
+    let header_size = BIN_FIXED_HEADER_SIZE + chunk_lengths.len() * 2 * size_of::<u32>();
+    let mut index = header_size; // Start past header.
+    let mut header = Vec::with_capacity(chunk_lengths.len());
+    for (chunk_idx, len) in chunk_lengths.enumerate() {
+        // The whole chunk (including padding) has to be addressable with a 32-bit offset.
+        let end = index.checked_add(round_up_to_eight(len)).filter(|&end| end <= u32::MAX as usize);
+        let (Some(end), Ok(len_u32)) = (end, u32::try_from(len)) else {
+            return Err(DataExportError::TooLarge(chunk_idx, len));
+        };
+        header.push((index as u32, len_u32));
+        index = end;
+    }
+    Ok(header)
+}
+
+fn bin_padding(len: usize) -> usize { round_up_to_eight(len) - len }
+
+// The length and checksum of each chunk of a binary export. The header needs them before any data is written, so an
+// export too large to hold in memory sums its chunks a piece at a time in a first pass, then writes them with a BinWriter.
+pub struct BinSums {
+    hashers: Vec<crc32fast::Hasher>,
+    lengths: Vec<usize>,
+}
+
+impl BinSums {
+    pub fn new(num_chunks: usize) -> Self {
+        Self { hashers: vec![crc32fast::Hasher::new(); num_chunks], lengths: vec![0; num_chunks] }
+    }
+
+    // Adds the next piece of a chunk. Pieces of different chunks can be added in any order.
+    pub fn add(&mut self, chunk_idx: usize, data: &[u8]) {
+        self.hashers[chunk_idx].update(data);
+        self.lengths[chunk_idx] += data.len();
+    }
+}
+
+// Writes the chunks of a binary export in order, a piece at a time, after the header from their sums.
+// Each chunk has to be written exactly as it was summed.
+pub struct BinWriter<'a, W: Write> {
+    writer: &'a mut W,
+    lengths: Vec<usize>,
+    chunk_idx: usize,
+    written: usize,
+}
+
+impl<'a, W: Write> BinWriter<'a, W> {
+    // Writes the header, failing if there are no chunks or they're too large for 32-bit offsets.
+    pub fn new(writer: &'a mut W, sums: &BinSums) -> Result<Self, DataExportError> {
+        // A 32-bit byte offset and length for each data chunk, followed by the data chunks.
+        // We want the data to be aligned to 8 bytes.
+        let header = bin_header(sums.lengths.iter().copied())?;
+        let header_bytes = header.iter().flat_map(|(index, len)| [index.to_le_bytes(), len.to_le_bytes()]).flatten().collect::<Vec<u8>>();
+
+        // The checksum covers everything after the fixed header, so it's combined from the header and the chunk sums.
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header_bytes);
+        for (chunk_hasher, &len) in sums.hashers.iter().zip(sums.lengths.iter()) {
+            let mut chunk_hasher = chunk_hasher.clone();
+            chunk_hasher.update(&[0; 8][..bin_padding(len)]);
+            hasher.combine(&chunk_hasher);
+        }
+
+        writer.write_all(&BIN_MAGIC)?;
+        writer.write_all(&BIN_VERSION.to_le_bytes())?;
+        writer.write_all(&[0; 2])?;
+        writer.write_all(&(sums.lengths.len() as u32).to_le_bytes())?;
+        writer.write_all(&hasher.finalize().to_le_bytes())?;
+        writer.write_all(&header_bytes)?;
+
+        Ok(Self { writer, lengths: sums.lengths.clone(), chunk_idx: 0, written: 0 })
+    }
+
+    // Writes the next piece of the current chunk.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), DataExportError> {
+        let chunk_idx = self.chunk_idx;
+        let length = *self.lengths.get(chunk_idx).ok_or(DataExportError::ChunkMismatch(chunk_idx, 0, data.len()))?;
+        if self.written + data.len() > length {
+            return Err(DataExportError::ChunkMismatch(chunk_idx, length, self.written + data.len()));
+        }
+        self.writer.write_all(data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    // Pads the current chunk to 8 bytes, and moves on to the next.
+    pub fn finish_chunk(&mut self) -> Result<(), DataExportError> {
+        let chunk_idx = self.chunk_idx;
+        let length = *self.lengths.get(chunk_idx).ok_or(DataExportError::ChunkMismatch(chunk_idx, 0, self.written))?;
+        if self.written != length {
+            return Err(DataExportError::ChunkMismatch(chunk_idx, length, self.written));
+        }
+        self.writer.write_all(&[0; 8][..bin_padding(length)])?;
+        self.chunk_idx += 1;
+        self.written = 0;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), DataExportError> {
+        match self.lengths.get(self.chunk_idx) {
+            Some(&length) => Err(DataExportError::ChunkMismatch(self.chunk_idx, length, self.written)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The chunks of a binary export, summed as they're built a piece at a time and kept in temporary files until the header
+// can be written. For exports too large to hold in memory whose pieces are expensive to build, so each is only built once.
+struct SpilledBin {
+    sums: BinSums,
+    files: Vec<BufWriter<File>>,
+}
+
+impl SpilledBin {
+    fn new(num_chunks: usize) -> Result<Self, DataExportError> {
+        let files = (0..num_chunks).map(|_| tempfile::tempfile().map(BufWriter::new)).collect::<Result<_, _>>()?;
+        Ok(Self { sums: BinSums::new(num_chunks), files })
+    }
+
+    // Adds the next piece of a chunk. Pieces of different chunks can be added in any order.
+    fn add(&mut self, chunk_idx: usize, data: &[u8]) -> Result<(), DataExportError> {
+        self.sums.add(chunk_idx, data);
+        self.files[chunk_idx].write_all(data)?;
+        Ok(())
+    }
+
+    // Writes the header, then copies each chunk from its file.
+    fn write(self, writer: &mut impl Write) -> Result<(), DataExportError> {
+        let mut bin_writer = BinWriter::new(writer, &self.sums)?;
+        let mut buffer = vec![0; 1 << 16];
+        for file in self.files {
+            let mut file = file.into_inner().map_err(|err| err.into_error())?;
+            file.seek(SeekFrom::Start(0))?;
+            loop {
+                let len = file.read(&mut buffer)?;
+                if len == 0 {
+                    break;
+                }
+                bin_writer.write(&buffer[..len])?;
+            }
+            bin_writer.finish_chunk()?;
+        }
+        bin_writer.finish()
+    }
 }
 
 // Writes a set of binary data to a writer in a simple format:
+// - The fixed header (see BIN_MAGIC).
 // - A 32-bit byte offset and length for each data chunk.
 // - The binary data chunks, each aligned to 8 bytes.
-pub fn write_bin(data_list: &[&[u8]], writer: &mut impl Write) -> std::io::Result<()> {
-    // Simple power-of-two alignment.
-    fn round_up_to_eight(num: usize) -> usize { (num + 7) & !7 }
-
-    // A 32-bit byte offset and length for each data chunk, followed by the data chunks.
-    // We want the data to be aligned to 8 bytes.
-    let header_size = data_list.len() * 2 * size_of::<u32>(); // 2 32-bit values per data chunk.
-    let mut index = header_size as u32; // Start past header.
-    let mut written_bytes = 0;
+// Fails if there are no chunks, or the data is too large for 32-bit offsets.
+pub fn write_bin(data_list: &[&[u8]], writer: &mut impl Write) -> Result<(), DataExportError> {
+    let mut sums = BinSums::new(data_list.len());
+    for (chunk_idx, &data) in data_list.iter().enumerate() {
+        sums.add(chunk_idx, data);
+    }
+
+    let mut bin_writer = BinWriter::new(writer, &sums)?;
     for &data in data_list {
-        written_bytes += writer.write(&index.to_le_bytes())?;
-        written_bytes += writer.write(&(data.len() as u32).to_le_bytes())?;
-        index += round_up_to_eight(data.len()) as u32;
+        bin_writer.write(data)?;
+        bin_writer.finish_chunk()?;
     }
+    bin_writer.finish()
+}
 
-    // Sanity check.
-    assert_eq!(written_bytes, header_size);
+// Reads the chunks from a buffer written by write_bin, checking the header and checksum.
+pub fn parse_bin(bytes: &[u8]) -> Result<Vec<Vec<u8>>, DataExportError> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+    };
 
-    // Write data, maintaining 8-byte alignment.
-    for &data in data_list {
-        writer.write_all(data)?;
-        let padding = round_up_to_eight(data.len()) - data.len();
-        for _ in 0..padding {
-            writer.write_all(&0u8.to_le_bytes())?;
-        }
+    if bytes.len() < BIN_FIXED_HEADER_SIZE || bytes[0..4] != BIN_MAGIC {
+        return Err(DataExportError::InvalidBin("missing magic bytes (files exported before format version 1 are not supported)".to_owned()));
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if !(BIN_MIN_VERSION..=BIN_VERSION).contains(&version) {
+        return Err(DataExportError::InvalidBin(format!("unsupported format version {version}, expected {BIN_MIN_VERSION} to {BIN_VERSION}")));
+    }
+    let num_chunks = read_u32(8).unwrap() as usize;
+    let checksum = read_u32(12).unwrap();
+    if crc32fast::hash(&bytes[BIN_FIXED_HEADER_SIZE..]) != checksum {
+        return Err(DataExportError::InvalidBin("checksum mismatch, the file is corrupt or truncated".to_owned()));
     }
 
-    Ok(())
+    if num_chunks == 0 {
+        return Err(DataExportError::InvalidBin("no chunks".to_owned()));
+    }
+
+    // Chunks are written in order after the header, each starting on an 8-byte boundary.
+    let mut data_start = BIN_FIXED_HEADER_SIZE + num_chunks * 2 * size_of::<u32>();
+    (0..num_chunks).map(|chunk_idx| {
+        let header_offset = BIN_FIXED_HEADER_SIZE + chunk_idx * 2 * size_of::<u32>();
+        let (Some(offset), Some(len)) = (read_u32(header_offset), read_u32(header_offset + 4)) else {
+            return Err(DataExportError::InvalidBin(format!("header for chunk {chunk_idx} is out of bounds")));
+        };
+        let (offset, len) = (offset as usize, len as usize);
+        if offset % 8 != 0 {
+            return Err(DataExportError::InvalidBin(format!("chunk {chunk_idx} at {offset} is not 8-byte aligned")));
+        }
+        if offset < data_start {
+            return Err(DataExportError::InvalidBin(format!("chunk {chunk_idx} at {offset} overlaps the header or previous chunk")));
+        }
+        let chunk = bytes.get(offset..offset + len)
+                         .ok_or_else(|| DataExportError::InvalidBin(format!("chunk {chunk_idx} at {offset} with length {len} is out of bounds")))?;
+        data_start = offset + round_up_to_eight(len);
+        Ok(chunk.to_vec())
+    }).collect()
+}
+
+// Reads the chunks from a file written by write_bin, or a zip written with open_zip.
+pub fn read_bin(path: &Path) -> Result<Vec<Vec<u8>>, DataExportError> {
+    let mut bytes = Vec::new();
+    if path.extension().is_some_and(|extension| extension == "zip") {
+        let mut zip = zip::ZipArchive::new(File::open(path)?)?;
+        zip.by_name("data.bin")?.read_to_end(&mut bytes)?;
+    } else {
+        File::open(path)?.read_to_end(&mut bytes)?;
+    }
+    parse_bin(&bytes)
 }
 
 // Writes a set of binary data to a zip file.
@@ -75,20 +313,376 @@ pub fn open_zip(path: &Path) -> std::io::Result<ZipWriter<File>> {
     Ok(zip)
 }
 
-pub fn export_shape_file(network: &Network, writer: &mut impl Write) -> Result<(), DataExportError> {
+// What exported shapes are coloured by.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum ShapeColourMode {
+    // The GTFS route colour.
+    #[default]
+    Route,
+    // The simulated load factor of each segment between stops.
+    Crowding,
+}
+
+impl std::str::FromStr for ShapeColourMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "route" => Ok(ShapeColourMode::Route),
+            "crowding" => Ok(ShapeColourMode::Crowding),
+            _ => Err(format!("{mode} is not a shape colouring (route or crowding)")),
+        }
+    }
+}
+
+// What routes drawn at the same height share.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum ShapeHeightKey {
+    // Routes of the same colour (e.g. the lines of a group) are drawn at the same height.
+    #[default]
+    Colour,
+    // Every GTFS route is drawn at its own height.
+    Route,
+}
+
+// Colours given to routes without one of their own, assigned in route_id order.
+// Tableau 10 (https://www.tableau.com/blog/colors-upgrade-tableau-10-56782), which stays distinct on a map.
+const ROUTE_PALETTE: [RGB8; 10] = [
+    RGB8 { r: 78, g: 121, b: 167 },
+    RGB8 { r: 242, g: 142, b: 43 },
+    RGB8 { r: 225, g: 87, b: 89 },
+    RGB8 { r: 118, g: 183, b: 178 },
+    RGB8 { r: 89, g: 161, b: 79 },
+    RGB8 { r: 237, g: 201, b: 72 },
+    RGB8 { r: 176, g: 122, b: 161 },
+    RGB8 { r: 255, g: 157, b: 167 },
+    RGB8 { r: 156, g: 117, b: 95 },
+    RGB8 { r: 186, g: 176, b: 172 },
+];
+
+// Sets the colour and height each route's shape and trips are drawn with. Feeds often leave out route_color, so the
+// GTFS routes in `uncoloured_route_ids` (from data_import::gtfs_routes_without_colour) are given a palette colour by
+// route_id (the same route always gets the same colour for a feed). Routes given a colour keep it, even if it's black.
+// Each distinct colour (or GTFS route, by height_by) is then drawn height_step above the last, in network order.
+pub fn assign_route_styles(network: &mut Network, gtfs: &Gtfs, uncoloured_route_ids: &HashSet<String>, colouring: &ShapeColouring) {
+    // The GTFS route of each network route (which is one stop pattern of it), from its trips.
+    let route_ids = network.routes.iter().map(|route| {
+        let trip = route.trip_ids.first().and_then(|trip_id| {
+            let trip_id: &str = trip_id.as_ref();
+            gtfs.trips.get(trip_id)
+        });
+        trip.map_or_else(|| route.line.to_string(), |trip| trip.route_id.clone())
+    }).collect_vec();
+
+    let palette_route_ids = route_ids.iter()
+                                     .filter(|&route_id| uncoloured_route_ids.contains(route_id))
+                                     .map(String::as_str)
+                                     .sorted()
+                                     .dedup()
+                                     .collect_vec();
+    if !palette_route_ids.is_empty() {
+        log::info!("Gave {} routes without a colour one from the palette.", palette_route_ids.len());
+    }
+    let palette_colours = palette_route_ids.iter().enumerate().map(|(i, &route_id)| (route_id.to_owned(), ROUTE_PALETTE[i % ROUTE_PALETTE.len()])).collect::<HashMap<_, _>>();
+
+    let mut heights = HashMap::new();
+    for (route, route_id) in network.routes.iter_mut().zip(route_ids.iter()) {
+        if let Some(&colour) = palette_colours.get(route_id) {
+            route.colour = colour;
+        }
+        let key = match colouring.height_by {
+            ShapeHeightKey::Colour => format!("{:02x}{:02x}{:02x}", route.colour.r, route.colour.g, route.colour.b),
+            ShapeHeightKey::Route => route_id.clone(),
+        };
+        let next_level = heights.len();
+        let level = *heights.entry(key).or_insert(next_level);
+        route.shape_height = level as CoordType * colouring.height_step;
+    }
+}
+
+// Load factor of a shape segment, over all the route's trips on that segment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String"))]
+pub enum SegmentStat {
+    #[default]
+    Max,
+    Mean,
+    // The highest load factor of trips departing the segment from `start` (inclusive) to `end` (exclusive).
+    Window { start: Timestamp, end: Timestamp },
+}
+
+impl std::str::FromStr for SegmentStat {
+    type Err = String;
+
+    // Parses max, mean, or a HH:MM:SS-HH:MM:SS window.
+    fn from_str(stat: &str) -> Result<Self, Self::Err> {
+        match stat {
+            "max" => Ok(SegmentStat::Max),
+            "mean" => Ok(SegmentStat::Mean),
+            _ => {
+                let window = stat.split_once('-').and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?)));
+                match window {
+                    Some((start, end)) if start < end => Ok(SegmentStat::Window { start, end }),
+                    _ => Err(format!("{stat} is not a segment statistic (max, mean, or a HH:MM:SS-HH:MM:SS window)")),
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for SegmentStat {
+    type Error = String;
+
+    fn try_from(stat: String) -> Result<Self, Self::Error> {
+        stat.parse()
+    }
+}
+
+// A HH:MM:SS-HH:MM:SS window of the day, from `start` (inclusive) to `end` (exclusive).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "String"))]
+pub struct TimeWindow {
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, time: Timestamp) -> bool {
+        (self.start..self.end).contains(&time)
+    }
+}
+
+impl std::fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", get_time_str(self.start), get_time_str(self.end))
+    }
+}
+
+impl std::str::FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(window: &str) -> Result<Self, Self::Err> {
+        match window.split_once('-').and_then(|(start, end)| Some((parse_time(start)?, parse_time(end)?))) {
+            Some((start, end)) if start < end => Ok(TimeWindow { start, end }),
+            _ => Err(format!("{window} is not a HH:MM:SS-HH:MM:SS window")),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(window: String) -> Result<Self, Self::Error> {
+        window.parse()
+    }
+}
+
+// A named part of the day (e.g. the AM peak) that aggregate exports are broken down by.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct ReportingPeriod {
+    pub name: String,
+    pub window: TimeWindow,
+}
+
+// The periods aggregate exports are broken down by, as a period column. Period ALL_DAY covers the whole day, followed by the
+// configured periods and then the off-peak period, which is the rest of the day. Each time of day is in exactly one period
+// apart from ALL_DAY, so every total is the sum of its periods.
+#[derive(Clone, Debug)]
+pub struct ReportingPeriods {
+    names: Vec<String>,
+    windows: Vec<TimeWindow>,
+}
+
+impl ReportingPeriods {
+    pub const ALL_DAY: usize = 0;
+    pub const ALL_DAY_NAME: &'static str = "all_day";
+    pub const OFF_PEAK_NAME: &'static str = "off_peak";
+
+    // Fails if names are repeated (or reserved) or windows overlap.
+    pub fn new(periods: &[ReportingPeriod]) -> Result<Self, String> {
+        for (i, period) in periods.iter().enumerate() {
+            if period.name.is_empty() || period.name == Self::ALL_DAY_NAME || period.name == Self::OFF_PEAK_NAME {
+                return Err(format!("period name {:?} is empty or reserved", period.name));
+            }
+            if let Some(other) = periods[..i].iter().find(|other| other.name == period.name || (other.window.start < period.window.end && period.window.start < other.window.end)) {
+                return Err(format!("periods {} ({}) and {} ({}) have the same name or overlap", other.name, other.window, period.name, period.window));
+            }
+        }
+        let names = std::iter::once(Self::ALL_DAY_NAME.to_owned())
+            .chain(periods.iter().map(|period| period.name.clone()))
+            .chain(std::iter::once(Self::OFF_PEAK_NAME.to_owned()))
+            .collect();
+        Ok(Self { names, windows: periods.iter().map(|period| period.window).collect() })
+    }
+
+    // Number of periods, including ALL_DAY and off-peak.
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn name(&self, period: usize) -> &str {
+        &self.names[period]
+    }
+
+    // The period (other than ALL_DAY) a time is in.
+    pub fn period_of(&self, time: Timestamp) -> usize {
+        self.windows.iter().position(|window| window.contains(time)).map_or(self.names.len() - 1, |i| i + 1)
+    }
+
+    // ALL_DAY and the period a time is in, to add to both.
+    pub fn periods_of(&self, time: Timestamp) -> [usize; 2] {
+        [Self::ALL_DAY, self.period_of(time)]
+    }
+}
+
+// How shapes are coloured in the shapes visualisation.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct ShapeColouring {
+    pub mode: ShapeColourMode,
+    pub stat: SegmentStat,
+    // Load factors at which the crowding colour ramp is green, yellow and red.
+    pub breakpoints: [f32; 3],
+    // Height added for each distinct colour (or route), so overlapping lines are drawn apart.
+    pub height_step: CoordType,
+    pub height_by: ShapeHeightKey,
+}
+
+impl Default for ShapeColouring {
+    fn default() -> Self {
+        Self { mode: ShapeColourMode::default(), stat: SegmentStat::default(), breakpoints: [0.5, 0.8, 1.], height_step: 10., height_by: ShapeHeightKey::default() }
+    }
+}
+
+impl ShapeColouring {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.breakpoints.iter().any(|breakpoint| !breakpoint.is_finite() || *breakpoint < 0.) || !self.breakpoints.windows(2).all(|pair| pair[0] < pair[1]) {
+            return Err(format!("breakpoints {:?} must be non-negative and strictly ascending", self.breakpoints));
+        }
+        if !self.height_step.is_finite() || self.height_step < 0. {
+            return Err(format!("height_step {} must be non-negative", self.height_step));
+        }
+        Ok(())
+    }
+
+    // Ramp from green to yellow to red through the breakpoints, or grey when there's no load factor.
+    fn colour(&self, load_factor: Option<f32>) -> RGB8 {
+        const NO_DATA_COLOUR: RGB8 = RGB8 { r: 128, g: 128, b: 128 };
+        const RAMP: [RGB8; 3] = [RGB8 { r: 26, g: 152, b: 80 }, RGB8 { r: 254, g: 224, b: 49 }, RGB8 { r: 215, g: 48, b: 39 }];
+
+        let Some(load_factor) = load_factor else {
+            return NO_DATA_COLOUR;
+        };
+        let [green, yellow, red] = self.breakpoints;
+        if load_factor <= green {
+            RAMP[0]
+        } else if load_factor <= yellow {
+            mix_rgb(RAMP[0], RAMP[1], (load_factor - green) / (yellow - green))
+        } else if load_factor <= red {
+            mix_rgb(RAMP[1], RAMP[2], (load_factor - yellow) / (red - yellow))
+        } else {
+            RAMP[2]
+        }
+    }
+}
+
+// Source of the colours written by export_shape_file.
+#[derive(Clone, Copy)]
+pub enum ShapeColours<'a> {
+    Route,
+    Crowding { simulation_result: &'a SimulationResult, trip_capacities: &'a TripCapacities, colouring: &'a ShapeColouring },
+}
+
+// The load factor statistic on each segment of a route (by departure stop order), or None if no trips are included.
+fn segment_load_factors(network: &Network, route_idx: usize, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, stat: SegmentStat) -> Vec<Option<f32>> {
+    let route = &network.routes[route_idx];
+    let num_segments = network.num_stops_in_route(route_idx).saturating_sub(1);
+    (0..num_segments).map(|dep_stop_order| {
+        let load_factors = (0..route.num_trips as usize).filter(|&trip| match stat {
+            SegmentStat::Window { start, end } => (start..end).contains(&network.get_departure_time(route_idx, trip, dep_stop_order)),
+            SegmentStat::Max | SegmentStat::Mean => true,
+        }).map(|trip| {
+            let count = simulation_result.population_count[route.get_trip_range(trip)][dep_stop_order];
+            count as f32 / trip_capacities.get(route.trip_ids[trip].as_ref()).total() as f32
+        });
+        match stat {
+            SegmentStat::Mean => {
+                let (sum, num) = load_factors.fold((0., 0), |(sum, num), load_factor| (sum + load_factor, num + 1));
+                (num > 0).then(|| sum / num as f32)
+            }
+            SegmentStat::Max | SegmentStat::Window { .. } => load_factors.reduce(f32::max),
+        }
+    }).collect()
+}
+
+// The index of the first shape point of each segment of a route, walking the shape the same way as trip_geometry.
+fn shape_segment_starts(network: &Network, stop_geometry: &StopGeometry, route_idx: usize) -> Vec<usize> {
+    let route_shape = &network.routes[route_idx].shape;
+    let mut segment_starts = Vec::new();
+    let mut shape_idx = 0;
+    for arr_stop_order in 1..network.num_stops_in_route(route_idx) {
+        segment_starts.push(shape_idx);
+        let arr_point = stop_geometry.point(network.get_stop_in_route(route_idx, arr_stop_order));
+        while shape_idx + 1 < route_shape.len() && !route_shape[shape_idx].very_close(arr_point) {
+            shape_idx += 1;
+        }
+        shape_idx += 1;
+    }
+    segment_starts
+}
+
+// Shapes that none of the network's trips use, which nothing is drawn along: those of trips that don't run on the network's
+// date (e.g. weekend-only services), and of retired routes that feeds often keep.
+pub fn count_unused_shapes(gtfs: &Gtfs, network: &Network) -> usize {
+    let used_shapes = network.routes.iter().flat_map(|route| route.trip_ids.iter()).filter_map(|trip_id| {
+        let trip_id: &str = trip_id.as_ref();
+        gtfs.trips.get(trip_id)?.shape_id.as_deref()
+    }).collect::<HashSet<_>>();
+    gtfs.shapes.keys().filter(|shape_id| !used_shapes.contains(shape_id.as_str())).count()
+}
+
+pub fn export_shape_file(network: &Network, stop_geometry: &StopGeometry, colours: ShapeColours, writer: &mut impl Write) -> Result<(), DataExportError> {
     let mut shape_points = Vec::new();
     let mut shape_start_indices = Vec::new();
     let mut shape_colours = Vec::new();
 
-    for route in network.routes.iter() {
-        let colour = route.colour;
+    let mut num_skipped = 0;
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        // Routes without a shape (e.g. the feed's shapes don't match any of the route's trips) have nothing to draw.
+        if route.shape.is_empty() {
+            num_skipped += 1;
+            continue;
+        }
+
         let height = route.shape_height;
 
+        // Each point takes the colour of the segment it's on.
+        let point_colours = match colours {
+            ShapeColours::Route => vec![route.colour; route.shape.len()],
+            ShapeColours::Crowding { simulation_result, trip_capacities, colouring } => {
+                let segment_colours = segment_load_factors(network, route_idx, simulation_result, trip_capacities, colouring.stat).into_iter().map(|load_factor| colouring.colour(load_factor)).collect_vec();
+                let segment_starts = shape_segment_starts(network, stop_geometry, route_idx);
+                (0..route.shape.len()).map(|shape_idx| {
+                    let segment = segment_starts.partition_point(|&start| start <= shape_idx).saturating_sub(1);
+                    segment_colours.get(segment).copied().unwrap_or_else(|| colouring.colour(None))
+                }).collect()
+            }
+        };
+
         // Indices are based on points, not coordinates.
         shape_start_indices.push(shape_points.len() as u32 / 3);
 
         // Construct line string from shape.
-        for point in route.shape.iter() {
+        for (point, colour) in route.shape.iter().zip(point_colours) {
             shape_points.push(point.longitude);
             shape_points.push(point.latitude);
             shape_points.push(height);
@@ -99,149 +693,231 @@ pub fn export_shape_file(network: &Network, writer: &mut impl Write) -> Result<(
         }
     }
 
+    if num_skipped > 0 {
+        log::warn!("Skipped {num_skipped} routes with no shape when exporting shapes.");
+    }
+    if shape_start_indices.is_empty() {
+        return Err(DataExportError::MissingData("no routes have shapes"));
+    }
+
     write_bin(&[bytemuck::must_cast_slice(&shape_points), bytemuck::must_cast_slice(&shape_start_indices), &shape_colours], writer)?;
 
     Ok(())
 }
 
-pub fn export_network_trips(network: &Network, simulation_result: &SimulationResult, writer: &mut impl Write) -> Result<(), DataExportError> {
-    const NUM_COORDS_PER_POINT: u32 = 3;
-
-    // I haven't bothered to calculate capacities, but it's amortised constant to push anyway so there's not really any point.
-    let mut start_indices = Vec::new();
-    let mut trip_points = Vec::new();
-    let mut trip_times = Vec::new();
-    let mut trip_colours = Vec::new();
-
-    for route_idx in 0..network.num_routes() {
-        let num_stops = network.num_stops_in_route(route_idx);
-        let route = &network.routes[route_idx];
-        let route_shape = &route.shape;
-        let height = route.shape_height;
-
-        // Colour blind friendly colours from https://davidmathlogic.com/colorblind/#%23005AB5-%23DC3220
-        const LOW_COLOUR: RGB8 = RGB8 { r: 0, g: 90, b: 181 };
-        const HIGH_COLOUR: RGB8 = RGB8 { r: 220, g: 50, b: 32 };
-
-        for trip_idx in 0..network.num_trips(route_idx) {
-            start_indices.push(trip_points.len() as u32 / NUM_COORDS_PER_POINT);
+// Crowding cost per unit time of each trip segment under its load, indexed like `SimulationResult::population_count`.
+// Uses each trip's own capacity, so trip and route capacity overrides are taken into account.
+pub fn segment_crowding_costs(network: &Network, params: &impl SimulationParams, population_count: &[PopulationCount]) -> Vec<CrowdingCost> {
+    let mut costs = vec![0 as CrowdingCost; network.stop_times.len()];
+    for route in network.routes.iter() {
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let trip_range = route.get_trip_range(trip);
+            for (cost, &count) in costs[trip_range.clone()].iter_mut().zip(&population_count[trip_range]) {
+                *cost = params.cost_fn(trip_id, count);
+            }
+        }
+    }
+    costs
+}
 
-            let agent_counts = &simulation_result.population_count[route.get_trip_range(trip_idx)];
+// The trips visualisation coloured by agent count, with the crowding cost of each segment (from segment_crowding_costs) alongside.
+pub fn export_network_trips(network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult, segment_costs: &[CrowdingCost], writer: &mut impl Write) -> Result<(), DataExportError> {
+    // Segments are coloured from low to high as the agent count goes from zero to this.
+    const MAX_AGENT_COUNT: f32 = 50.;
 
-            let mut shape_idx = 0;
-            for dep_stop_order in 0..num_stops - 1 {
-                let arr_stop_order = dep_stop_order + 1;
+    let population_count = &simulation_result.population_count;
+    if segment_costs.len() != population_count.len() {
+        return Err(DataExportError::MissingData("a crowding cost for every segment"));
+    }
+    let values = population_count.iter().map(|&count| count as f32 / MAX_AGENT_COUNT).collect_vec();
+    let costs = segment_costs.iter().map(|&cost| cost as f32).collect_vec();
+    write_trips_bin(network, stop_geometry, &values, Some(&costs), |idx| {
+        // Ignore trips with no agents.
+        population_count[idx] > 0
+    }, writer)
+}
 
-                let departure_time = network.get_departure_time(route_idx, trip_idx, dep_stop_order) as f32;
+// One trip's part of the trips visualisation.
+struct TripGeometry {
+    points: Vec<CoordType>,
+    // Seconds since midnight of the service day, which are past 86400 for trips running after midnight.
+    times: Vec<f64>,
+    colours: Vec<u8>,
+    // Empty if the export has no crowding costs.
+    costs: Vec<f32>,
+}
 
-                let arr_stop_idx = network.get_stop_in_route(route_idx, arr_stop_order) as usize;
-                let arr_point = network.stop_points[arr_stop_idx];
-                let arrival_time = network.get_arrival_time(route_idx, trip_idx, arr_stop_order) as f32;
+// Writes the trips visualisation, drawing each trip segment for which `draw` is true (given the index of its departure stop time).
+// Colours go from low to high as `values` goes from 0 to 1, interpolated between the departure and arrival stop times.
+// Crowding costs (if any) are interpolated to each point in the same way.
+// Trips are built in parallel and concatenated in network order, so the output is the same as building them one after another.
+// The geometry of every trip is much larger than the simulation result, so it's streamed: trips are built a batch at a time
+// into temporary files for each chunk (see SpilledBin), so only one batch is in memory at a time.
+fn write_trips_bin(network: &Network, stop_geometry: &StopGeometry, values: &[f32], costs: Option<&[f32]>, draw: impl Fn(usize) -> bool + Sync, writer: &mut impl Write) -> Result<(), DataExportError> {
+    const NUM_COORDS_PER_POINT: u32 = 3;
+    const TRIP_BATCH_SIZE: usize = 4096;
+
+    let mut trips = Vec::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        // Trips are drawn along the route shape, so there's nothing to draw without one.
+        if route.shape.is_empty() {
+            log::warn!("Skipping trips on route {}, which has no shape.", route.line);
+            continue;
+        }
+        trips.extend((0..network.num_trips(route_idx)).map(|trip_idx| (route_idx, trip_idx)));
+    }
+    // Chunks are the points, the start index of each trip, the f32 times (kept for compatibility), the colours, the f64 times
+    // and the crowding costs.
+    let mut bin = SpilledBin::new(6)?;
+    let mut start_indices = Vec::with_capacity(trips.len());
+    let mut num_points = 0;
+    for batch in trips.chunks(TRIP_BATCH_SIZE) {
+        let trip_geometries = batch.par_iter().map(|&(route_idx, trip_idx)| trip_geometry(network, stop_geometry, route_idx, trip_idx, values, costs, &draw)).collect::<Vec<_>>();
+        for trip in trip_geometries {
+            assert_eq!(trip.points.len(), trip.times.len() * NUM_COORDS_PER_POINT as usize);
+            start_indices.push(num_points);
+            num_points += trip.times.len() as u32;
+            let f32_times = trip.times.iter().map(|&time| time as f32).collect_vec();
+            bin.add(0, bytemuck::must_cast_slice(&trip.points))?;
+            bin.add(2, bytemuck::must_cast_slice(&f32_times))?;
+            bin.add(3, &trip.colours)?;
+            bin.add(4, bytemuck::must_cast_slice(&trip.times))?;
+            bin.add(5, bytemuck::must_cast_slice(&trip.costs))?;
+        }
+    }
+    bin.add(1, bytemuck::must_cast_slice(&start_indices))?;
+    bin.write(writer)
+}
 
-                // Calculate alpha based on agent count.
-                let dep_count = agent_counts[dep_stop_order];
+// Builds the points, times and colours of one trip (on a route with a shape) for write_trips_bin.
+fn trip_geometry(network: &Network, stop_geometry: &StopGeometry, route_idx: usize, trip_idx: usize, values: &[f32], costs: Option<&[f32]>, draw: &impl Fn(usize) -> bool) -> TripGeometry {
+    // Colour blind friendly colours from https://davidmathlogic.com/colorblind/#%23005AB5-%23DC3220
+    const LOW_COLOUR: RGB8 = RGB8 { r: 0, g: 90, b: 181 };
+    const HIGH_COLOUR: RGB8 = RGB8 { r: 220, g: 50, b: 32 };
 
-                // Ignore trips with no agents.
-                assert!(dep_count >= 0);
-                if dep_count == 0 {
-                    continue;
-                }
-                let dep_count = dep_count as f32;
-                let arr_count = agent_counts[arr_stop_order] as f32;
-                let agent_count_diff = arr_count - dep_count;
-
-                const MAX_AGENT_COUNT: f32 = 50.;
-
-                let mut push_point = |point: NetworkPoint, next_point: NetworkPoint| {
-                    // Location is offset to the left to separate inbound and outbound.
-                    const OFFSET: CoordType = 20.;
-                    let offset_point = point.left_offset(next_point, OFFSET);
-                    trip_points.push(offset_point.longitude);
-                    trip_points.push(offset_point.latitude);
-                    trip_points.push(height);
-                };
+    let num_stops = network.num_stops_in_route(route_idx);
+    let route = &network.routes[route_idx];
+    let route_shape = &route.shape;
+    let height = route.shape_height;
 
-                // Go through shape points and add to point list.
-                let start_shape_idx = shape_idx;
-                let mut current_point = route_shape[shape_idx];
-                let mut distance_along_shape_section = 0. as CoordType;
-                while !current_point.very_close(arr_point) {
-                    if route_shape.len() <= shape_idx + 1 {
-                        log::warn!("Warning: Shape index out of bounds for route {}, stop {}({arr_stop_order}).", network.routes[route_idx].line, network.stops[arr_stop_idx].name);
-                        break;
-                    }
+    let mut trip_points = Vec::new();
+    let mut trip_times = Vec::new();
+    let mut trip_colours = Vec::new();
+    let mut trip_costs = Vec::new();
 
-                    shape_idx += 1;
-                    let next_point = route_shape[shape_idx];
-                    distance_along_shape_section += current_point.distance(next_point);
+    let trip_range = route.get_trip_range(trip_idx);
+    let trip_values = &values[trip_range.clone()];
+    let trip_segment_costs = costs.map(|costs| &costs[trip_range.clone()]);
 
-                    push_point(current_point, next_point);
+    let mut shape_idx = 0;
+    for dep_stop_order in 0..num_stops - 1 {
+        let arr_stop_order = dep_stop_order + 1;
 
-                    current_point = next_point;
-                }
+        let departure_time = network.get_departure_time(route_idx, trip_idx, dep_stop_order) as f64;
 
-                // Push the arrival point.
-                shape_idx += 1;
-                if shape_idx < route_shape.len() {
-                    push_point(current_point, route_shape[shape_idx]);
-                } else {
-                    push_point(arr_point, arr_point);
-                }
+        let arr_stop_idx = network.get_stop_in_route(route_idx, arr_stop_order);
+        let arr_point = stop_geometry.point(arr_stop_idx);
+        let arrival_time = network.get_arrival_time(route_idx, trip_idx, arr_stop_order) as f64;
 
-                // Calculate time based on distance proportion.
-                let section_duration = arrival_time - departure_time;
-                let mut distance = 0.;
-                for shape_idx in start_shape_idx..shape_idx {
-                    assert!(distance >= 0.);
-
-                    // Calculate proportion along this shape we are, for interpolating properties.
-                    // Apply an easing function to the proportion, so trains accelerate and decelerate.
-                    // We use the inverse of the easing function for easing time.
-                    let proportion = if distance_along_shape_section <= 0. {
-                        if shape_idx < route_shape.len() { 0. } else { 1. }
-                    } else {
-                        (distance / distance_along_shape_section) as f32
-                    };
+        if !draw(trip_range.start + dep_stop_order) {
+            continue;
+        }
+        let dep_value = trip_values[dep_stop_order];
+        let value_diff = trip_values[arr_stop_order] - dep_value;
+        let cost_range = trip_segment_costs.map(|costs| (costs[dep_stop_order], costs[arr_stop_order] - costs[dep_stop_order]));
+
+        let mut push_point = |point: NetworkPoint, next_point: NetworkPoint| {
+            // Location is offset to the left to separate inbound and outbound.
+            const OFFSET: CoordType = 20.;
+            let offset_point = point.left_offset(next_point, OFFSET);
+            trip_points.push(offset_point.longitude);
+            trip_points.push(offset_point.latitude);
+            trip_points.push(height);
+        };
+
+        // Go through shape points and add to point list.
+        let start_shape_idx = shape_idx;
+        let mut current_point = route_shape[shape_idx];
+        let mut distance_along_shape_section = 0. as CoordType;
+        while !current_point.very_close(arr_point) {
+            if route_shape.len() <= shape_idx + 1 {
+                log::warn!("Warning: Shape index out of bounds for route {}, stop {}({arr_stop_order}).", network.routes[route_idx].line, stop_geometry.name(arr_stop_idx));
+                break;
+            }
 
-                    let proportion_inv = quadratic_inv_ease_in_out(proportion);
-                    let proportion = quadratic_ease_in_out(proportion);
-                    let time = departure_time + section_duration * proportion_inv;
-                    trip_times.push(time);
+            shape_idx += 1;
+            let next_point = route_shape[shape_idx];
+            distance_along_shape_section += current_point.distance(next_point);
 
-                    // Colour (RGBA). Calculate alpha based on agent count.
-                    let value = (dep_count + agent_count_diff * proportion) / MAX_AGENT_COUNT;
-                    let shape_colour = mix_rgb(LOW_COLOUR, HIGH_COLOUR, value);
+            push_point(current_point, next_point);
 
-                    trip_colours.push(shape_colour.r);
-                    trip_colours.push(shape_colour.g);
-                    trip_colours.push(shape_colour.b);
-                    trip_colours.push(255);
+            current_point = next_point;
+        }
 
-                    let segment_distance = if shape_idx + 1 < route_shape.len() {
-                        route_shape[shape_idx].distance(route_shape[shape_idx + 1])
-                    } else {
-                        0.
-                    };
+        // Push the arrival point.
+        shape_idx += 1;
+        if shape_idx < route_shape.len() {
+            push_point(current_point, route_shape[shape_idx]);
+        } else {
+            push_point(arr_point, arr_point);
+        }
 
-                    distance += segment_distance;
-                }
+        // Calculate time based on distance proportion.
+        let section_duration = arrival_time - departure_time;
+        let mut distance = 0.;
+        for shape_idx in start_shape_idx..shape_idx {
+            assert!(distance >= 0.);
+
+            // Calculate proportion along this shape we are, for interpolating properties.
+            // Apply an easing function to the proportion, so trains accelerate and decelerate.
+            // We use the inverse of the easing function for easing time.
+            let proportion = if distance_along_shape_section <= 0. {
+                if shape_idx < route_shape.len() { 0. } else { 1. }
+            } else {
+                (distance / distance_along_shape_section) as f32
+            };
+
+            let proportion_inv = quadratic_inv_ease_in_out(proportion);
+            let proportion = quadratic_ease_in_out(proportion);
+            let time = departure_time + section_duration * proportion_inv as f64;
+            trip_times.push(time);
+
+            // Colour (RGBA).
+            let value = dep_value + value_diff * proportion;
+            let shape_colour = mix_rgb(LOW_COLOUR, HIGH_COLOUR, value);
+
+            trip_colours.push(shape_colour.r);
+            trip_colours.push(shape_colour.g);
+            trip_colours.push(shape_colour.b);
+            trip_colours.push(255);
+
+            if let Some((dep_cost, cost_diff)) = cost_range {
+                trip_costs.push(dep_cost + cost_diff * proportion);
+            }
 
-                // This is required so we count the last point as the start of the next section.
-                shape_idx -= 1;
+            let segment_distance = if shape_idx + 1 < route_shape.len() {
+                route_shape[shape_idx].distance(route_shape[shape_idx + 1])
+            } else {
+                0.
+            };
 
-                assert_eq!(trip_points.len(), trip_times.len() * NUM_COORDS_PER_POINT as usize);
-            }
+            distance += segment_distance;
         }
-    }
 
-    write_bin(&[bytemuck::must_cast_slice(&trip_points), bytemuck::must_cast_slice(&start_indices), bytemuck::must_cast_slice(&trip_times), &trip_colours], writer)?;
+        // This is required so we count the last point as the start of the next section.
+        shape_idx -= 1;
+    }
 
-    Ok(())
+    TripGeometry { points: trip_points, times: trip_times, colours: trip_colours, costs: trip_costs }
 }
 
-// Exports the agent counts to a parquet (and csv) file.
-pub fn export_agent_counts(path: &Path, network: &Network, simulation_result: &SimulationResult, trip_capacities: &TripCapacities) -> Result<(), DataExportError> {
+// Exports the agent counts, with the crowding cost of each segment (from segment_crowding_costs), to a parquet (and csv) file.
+pub fn export_agent_counts(path: &Path, network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, segment_costs: &[CrowdingCost]) -> Result<(), DataExportError> {
     let path = path.with_extension("parquet");
+    if segment_costs.len() != simulation_result.population_count.len() {
+        return Err(DataExportError::MissingData("a crowding cost for every segment"));
+    }
 
     // This is the utc timestamp for the midnight of the day the network represents.
     let date_timestamp = network.date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
@@ -255,6 +931,8 @@ pub fn export_agent_counts(path: &Path, network: &Network, simulation_result: &S
     let mut arrivals = Vec::new();
     let mut arrival_ids = Vec::new();
     let mut agent_counts = Vec::new();
+    let mut crowding_costs = Vec::new();
+    let mut crowding_levels = Vec::new();
 
     for route in network.routes.iter() {
         for trip in 0..route.num_trips as usize {
@@ -267,18 +945,20 @@ pub fn export_agent_counts(path: &Path, network: &Network, simulation_result: &S
             });
             let stops = route.get_stops(&network.route_stops).iter().tuple_windows();
             let trip_agent_counts = &simulation_result.population_count[trip_range.clone()];
+            let trip_costs = &segment_costs[trip_range.clone()];
 
-            for ((&dep_stop_idx, &arr_stop_idx), time_ms, &agent_count) in izip!(stops, stop_times_ms, trip_agent_counts) {
+            for ((&dep_stop_idx, &arr_stop_idx), time_ms, &agent_count, &crowding_cost) in izip!(stops, stop_times_ms, trip_agent_counts, trip_costs) {
                 trip_ids.push(trip_id);
-                trip_seated.push(trip_capacity.seated as u32);
-                trip_standing.push(trip_capacity.standing as u32);
+                trip_seated.push(trip_capacity.seated);
+                trip_standing.push(trip_capacity.standing);
                 timestamps.push(time_ms);
-                departures.push(network.stops[dep_stop_idx as usize].name.as_ref());
+                departures.push(stop_geometry.name(dep_stop_idx));
                 departure_ids.push(network.stops[dep_stop_idx as usize].id.as_ref());
-                arrivals.push(network.stops[arr_stop_idx as usize].name.as_ref());
+                arrivals.push(stop_geometry.name(arr_stop_idx));
                 arrival_ids.push(network.stops[arr_stop_idx as usize].id.as_ref());
-                assert!(agent_count >= 0, "Negative agent count: {}", agent_count);
-                agent_counts.push(agent_count as u32);
+                agent_counts.push(agent_count);
+                crowding_costs.push(crowding_cost as f64);
+                crowding_levels.push(trip_capacity.crowding_level(agent_count).get_name());
             }
         }
     }
@@ -293,82 +973,1748 @@ pub fn export_agent_counts(path: &Path, network: &Network, simulation_result: &S
     let trip_standing_arr = Arc::new(UInt32Array::from(trip_standing.clone()));
     let trip_standing_field = Field::new("Standing_Capacity", trip_standing_arr.data_type().clone(), false);
 
-    let timestamps_arr = Arc::new(TimestampMillisecondArray::from(timestamps.clone()));
-    let timestamp_field = Field::new("Departure_Timestamp", timestamps_arr.data_type().clone(), false);
+    let timestamps_arr = Arc::new(TimestampMillisecondArray::from(timestamps.clone()));
+    let timestamp_field = Field::new("Departure_Timestamp", timestamps_arr.data_type().clone(), false);
+
+    let departures_arr = Arc::new(StringArray::from(departures.clone()));
+    let departures_field = Field::new("Departure", departures_arr.data_type().clone(), false);
+
+    let departure_ids_arr = Arc::new(StringArray::from(departure_ids.clone()));
+    let departure_ids_field = Field::new("Departure_ID", departure_ids_arr.data_type().clone(), false);
+
+    let arrivals_arr = Arc::new(StringArray::from(arrivals.clone()));
+    let arrivals_field = Field::new("Arrival", arrivals_arr.data_type().clone(), false);
+
+    let arrival_ids_arr = Arc::new(StringArray::from(arrival_ids.clone()));
+    let arrival_ids_field = Field::new("Arrival_ID", arrival_ids_arr.data_type().clone(), false);
+
+    let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts.clone()));
+    let agent_counts_field = Field::new("Agent_Count", agent_counts_arr.data_type().clone(), false);
+
+    let crowding_costs_arr = Arc::new(Float64Array::from(crowding_costs.clone()));
+    let crowding_costs_field = Field::new("Crowding_Cost", crowding_costs_arr.data_type().clone(), false);
+
+    let crowding_levels_arr = Arc::new(StringArray::from(crowding_levels.clone()));
+    let crowding_levels_field = Field::new("Crowding_Level", crowding_levels_arr.data_type().clone(), false);
+
+    let schema = Arc::new(Schema::new(vec![
+        trip_id_field,
+        trip_seated_field,
+        trip_standing_field,
+        timestamp_field,
+        departures_field,
+        departure_ids_field,
+        arrivals_field,
+        arrival_ids_field,
+        agent_counts_field,
+        crowding_costs_field,
+        crowding_levels_field
+    ]));
+
+    let record_batch = arrow::record_batch::RecordBatch::try_new(schema, vec![
+        trip_id_arr,
+        trip_seated_arr,
+        trip_standing_arr,
+        timestamps_arr,
+        departures_arr,
+        departure_ids_arr,
+        arrivals_arr,
+        arrival_ids_arr,
+        agent_counts_arr,
+        crowding_costs_arr,
+        crowding_levels_arr
+    ])?;
+
+    // Write to parquet.
+    {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_column_dictionary_enabled("Crowding_Level".into(), true)
+            .build();
+        let mut writer = ArrowWriter::try_new(File::create(&path)?, record_batch.schema(), Some(props))?;
+
+        writer.write(&record_batch)?;
+
+        writer.close()?;
+    }
+
+    // Write to csv (for debugging).
+    {
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(&["trip_id", "timestamp", "departure", "departure_id", "arrival", "arrival_id", "count", "crowding_cost", "crowding_level"])?;
+        let date_str = network.date.to_string();
+        for (trip_name, timestamp, departure, departure_id, arrival, arrival_id, count, crowding_cost, crowding_level) in izip!(trip_ids, timestamps, departures, departure_ids, arrivals, arrival_ids, agent_counts, crowding_costs, crowding_levels) {
+            let timestamp = format!("{date_str} {}", &get_time_str((timestamp / 1000 - date_timestamp) as Timestamp));
+            csv_writer.write_record(&[trip_name, &timestamp, departure, departure_id, arrival, arrival_id, &count.to_string(), &format!("{crowding_cost:.4}"), crowding_level])?;
+        }
+    }
+
+    Ok(())
+}
+
+// With `parent_stations`, each stop also lists the (space separated) ids of the stops it replaced.
+pub fn export_stops_csv(path: &Path, network: &Network, stop_geometry: &StopGeometry, parent_stations: Option<&ParentStations>) -> Result<(), DataExportError> {
+    // Write stops CSV.
+    let csv_path = path.with_extension("csv");
+    let mut csv_writer = csv::Writer::from_path(csv_path)?;
+    // Stop ID, Name, Latitude, Longitude (and child stop IDs).
+    let children = parent_stations.map(ParentStations::children);
+    match children {
+        Some(_) => csv_writer.write_record(&["id", "name", "latitude", "longitude", "child_stop_ids"])?,
+        None => csv_writer.write_record(&["id", "name", "latitude", "longitude"])?,
+    }
+    for (location, name, stop) in izip!(&stop_geometry.points, &stop_geometry.names, network.stops.iter()) {
+        let mut record = vec![stop.id.to_string(), name.clone(), location.latitude.to_string(), location.longitude.to_string()];
+        if let Some(children) = &children {
+            let stop_id: &str = stop.id.as_ref();
+            record.push(children.get(stop_id).map_or(String::new(), |child_ids| child_ids.join(" ")));
+        }
+        csv_writer.write_record(&record)?;
+    }
+
+    Ok(())
+}
+
+// Writes one row per trip segment with its load, using GTFS ids so it can be joined with other datasets.
+// Replacement buses are marked in the replacement column.
+// Rows are written as they are generated, so this doesn't hold the whole table in memory.
+pub fn export_loads_csv(path: &Path, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities) -> Result<(), DataExportError> {
+    if simulation_result.population_count.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "route_id", "from_stop_id", "to_stop_id", "departure_time", "arrival_time", "passengers_on_board", "capacity", "load_factor", "replacement"])?;
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let route_id = gtfs.trips.get(trip_id).map(|trip| trip.route_id.as_str()).unwrap_or_default();
+            let capacity = trip_capacities.get(trip_id).total();
+            let replacement = is_replacement_trip(gtfs, trip_id);
+            let trip_agent_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+
+            // The count at each stop is the load on the segment departing that stop.
+            for (dep_stop_order, ((&from_stop, &to_stop), &count)) in stops.iter().tuple_windows().zip(trip_agent_counts).enumerate() {
+                csv_writer.write_record(&[
+                    trip_id,
+                    route_id,
+                    network.stops[from_stop as usize].id.as_ref(),
+                    network.stops[to_stop as usize].id.as_ref(),
+                    &get_time_str(network.get_departure_time(route_idx, trip, dep_stop_order)),
+                    &get_time_str(network.get_arrival_time(route_idx, trip, dep_stop_order + 1)),
+                    &count.to_string(),
+                    &capacity.to_string(),
+                    &format!("{:.3}", count as f32 / capacity as f32),
+                    &replacement.to_string(),
+                ])?;
+            }
+        }
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// The most crowded segment of a trip, the trip's "max load point".
+#[derive(Clone, Copy, Debug)]
+pub struct MaxLoadPoint {
+    pub route_idx: usize,
+    pub trip: usize,
+    // The reporting period the max is over, of the trip's segments departing in it.
+    pub period: usize,
+    pub max_load: PopulationCount,
+    // Stop order of the segment's departure stop.
+    pub stop_order: usize,
+    pub load_factor: f32,
+    pub seated_exceeded: bool,
+}
+
+// Finds the max load point of every trip over the whole day, then over the segments departing in each reporting period
+// the trip runs in, in network order. Ties go to the earliest segment.
+pub fn max_load_points(network: &Network, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, periods: &ReportingPeriods) -> Vec<MaxLoadPoint> {
+    let mut max_load_points = Vec::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        let num_segments = network.num_stops_in_route(route_idx).saturating_sub(1);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let capacity = trip_capacities.get(trip_id);
+            // The count at each stop is the load on the segment departing that stop, so the last stop has no segment.
+            let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)][..num_segments];
+            let segment_periods = (0..num_segments).map(|stop_order| periods.period_of(network.get_departure_time(route_idx, trip, stop_order))).collect_vec();
+            for period in 0..periods.len() {
+                let in_period = |stop_order: usize| period == ReportingPeriods::ALL_DAY || segment_periods[stop_order] == period;
+                // min_by_key returns the first of equal elements, so this is the earliest highest load.
+                let Some((stop_order, &max_load)) = trip_counts.iter().enumerate().filter(|&(stop_order, _)| in_period(stop_order)).min_by_key(|&(_, &count)| Reverse(count)) else {
+                    continue;
+                };
+                max_load_points.push(MaxLoadPoint {
+                    route_idx,
+                    trip,
+                    period,
+                    max_load,
+                    stop_order,
+                    load_factor: max_load as f32 / capacity.total() as f32,
+                    seated_exceeded: max_load > capacity.seated,
+                });
+            }
+        }
+    }
+    max_load_points
+}
+
+// The `n` trips with the highest all-day max load factors, most crowded first. Equal trips stay in network order.
+pub fn most_crowded_trips(max_load_points: &[MaxLoadPoint], n: usize) -> Vec<MaxLoadPoint> {
+    max_load_points.iter().copied()
+                   .filter(|point| point.period == ReportingPeriods::ALL_DAY)
+                   .sorted_by(|a, b| b.load_factor.total_cmp(&a.load_factor))
+                   .take(n)
+                   .collect()
+}
+
+// Writes the max load point of every trip to <path>.csv (see max_load_points).
+pub fn export_max_load_points(path: &Path, network: &Network, gtfs: &Gtfs, max_load_points: &[MaxLoadPoint], periods: &ReportingPeriods) -> Result<(), DataExportError> {
+    if max_load_points.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "route_id", "period", "departure_time", "max_load", "max_load_segment_from_stop", "max_load_segment_to_stop", "load_factor", "seated_exceeded"])?;
+    for point in max_load_points {
+        let route = &network.routes[point.route_idx];
+        let trip_id: &str = route.trip_ids[point.trip].as_ref();
+        let route_id = gtfs.trips.get(trip_id).map(|trip| trip.route_id.as_str()).unwrap_or_default();
+        let from_stop = network.get_stop_in_route(point.route_idx, point.stop_order) as usize;
+        let to_stop = network.get_stop_in_route(point.route_idx, point.stop_order + 1) as usize;
+        csv_writer.write_record(&[
+            trip_id,
+            route_id,
+            periods.name(point.period),
+            &get_time_str(network.get_departure_time(point.route_idx, point.trip, 0)),
+            &point.max_load.to_string(),
+            network.stops[from_stop].id.as_ref(),
+            network.stops[to_stop].id.as_ref(),
+            &format!("{:.3}", point.load_factor),
+            if point.seated_exceeded { "true" } else { "false" },
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// GTFS-realtime OccupancyStatus values a simulated load is mapped to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OccupancyStatus {
+    Empty = 0,
+    ManySeatsAvailable = 1,
+    FewSeatsAvailable = 2,
+    StandingRoomOnly = 3,
+    CrushedStandingRoomOnly = 4,
+    Full = 5,
+}
+
+impl OccupancyStatus {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            OccupancyStatus::Empty => "EMPTY",
+            OccupancyStatus::ManySeatsAvailable => "MANY_SEATS_AVAILABLE",
+            OccupancyStatus::FewSeatsAvailable => "FEW_SEATS_AVAILABLE",
+            OccupancyStatus::StandingRoomOnly => "STANDING_ROOM_ONLY",
+            OccupancyStatus::CrushedStandingRoomOnly => "CRUSHED_STANDING_ROOM_ONLY",
+            OccupancyStatus::Full => "FULL",
+        }
+    }
+}
+
+// Load factors (passengers over total capacity) below which each occupancy status applies. Anything above `crushed` is FULL.
+// The defaults suit the default X'Trapolis capacity, where seats run out at a load factor of about 0.66.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct OccupancyThresholds {
+    pub empty: f32,
+    pub many_seats_available: f32,
+    pub few_seats_available: f32,
+    pub standing_room_only: f32,
+    pub crushed: f32,
+}
+
+impl Default for OccupancyThresholds {
+    fn default() -> Self {
+        Self { empty: 0.02, many_seats_available: 0.33, few_seats_available: 0.66, standing_room_only: 1., crushed: 1.3 }
+    }
+}
+
+impl OccupancyThresholds {
+    pub fn validate(&self) -> Result<(), String> {
+        let thresholds = [self.empty, self.many_seats_available, self.few_seats_available, self.standing_room_only, self.crushed];
+        if thresholds.iter().any(|threshold| !threshold.is_finite() || *threshold < 0.) || !thresholds.windows(2).all(|pair| pair[0] <= pair[1]) {
+            return Err(format!("{self:?} must be non-negative and ascending"));
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, load_factor: f32) -> OccupancyStatus {
+        if load_factor < self.empty {
+            OccupancyStatus::Empty
+        } else if load_factor < self.many_seats_available {
+            OccupancyStatus::ManySeatsAvailable
+        } else if load_factor < self.few_seats_available {
+            OccupancyStatus::FewSeatsAvailable
+        } else if load_factor < self.standing_room_only {
+            OccupancyStatus::StandingRoomOnly
+        } else if load_factor < self.crushed {
+            OccupancyStatus::CrushedStandingRoomOnly
+        } else {
+            OccupancyStatus::Full
+        }
+    }
+}
+
+// The occupancy departing each stop of a trip (so not its last stop), with the stop's GTFS stop_sequence.
+struct StopOccupancy<'a> {
+    stop_sequence: u32,
+    stop_id: &'a str,
+    load_factor: f32,
+    status: OccupancyStatus,
+}
+
+fn trip_occupancies<'a>(network: &'a Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, thresholds: &OccupancyThresholds, route_idx: usize, trip: usize) -> Vec<StopOccupancy<'a>> {
+    let route = &network.routes[route_idx];
+    let stops = route.get_stops(&network.route_stops);
+    let trip_id: &str = route.trip_ids[trip].as_ref();
+    let capacity = trip_capacities.get(trip_id).total() as f32;
+    // Use the GTFS stop sequence if the trip's stop times line up with the network, otherwise the stop order.
+    let gtfs_stop_times = gtfs.trips.get(trip_id).map(|trip| &trip.stop_times).filter(|stop_times| stop_times.len() == stops.len());
+    let trip_agent_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+    stops.iter().zip(trip_agent_counts).enumerate().take(stops.len().saturating_sub(1)).map(|(stop_order, (&stop_idx, &count))| {
+        let load_factor = count as f32 / capacity;
+        StopOccupancy {
+            stop_sequence: gtfs_stop_times.map_or(stop_order as u32, |stop_times| stop_times[stop_order].stop_sequence as u32),
+            stop_id: network.stops[stop_idx as usize].id.as_ref(),
+            load_factor,
+            status: thresholds.status(load_factor),
+        }
+    }).collect()
+}
+
+// Writes the predicted occupancy status departing each stop of every trip to <path>.csv, for passenger information.
+pub fn export_occupancy_csv(path: &Path, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, thresholds: &OccupancyThresholds) -> Result<(), DataExportError> {
+    if simulation_result.population_count.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "stop_sequence", "stop_id", "occupancy_status"])?;
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            for stop_occupancy in trip_occupancies(network, gtfs, simulation_result, trip_capacities, thresholds, route_idx, trip) {
+                csv_writer.write_record(&[trip_id, &stop_occupancy.stop_sequence.to_string(), stop_occupancy.stop_id, stop_occupancy.status.get_name()])?;
+            }
+        }
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the predicted occupancy as a GTFS-realtime FeedMessage to <path>.pb. Each trip has a TripUpdate with the departure
+// occupancy at each stop, and a VehiclePosition with the trip's peak occupancy.
+#[cfg(feature = "gtfs_rt")]
+pub fn export_occupancy_feed(path: &Path, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, thresholds: &OccupancyThresholds) -> Result<(), DataExportError> {
+    use gtfs_rt::trip_update::{StopTimeEvent, StopTimeUpdate};
+    use gtfs_rt::{FeedEntity, FeedHeader, FeedMessage, TripDescriptor, TripUpdate, VehiclePosition};
+    use prost::Message;
+
+    if simulation_result.population_count.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let date_timestamp = network.date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+    let start_date = network.date.format("%Y%m%d").to_string();
+    let mut entities = Vec::new();
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let occupancies = trip_occupancies(network, gtfs, simulation_result, trip_capacities, thresholds, route_idx, trip);
+            let Some(peak) = occupancies.iter().max_by(|a, b| a.load_factor.total_cmp(&b.load_factor)) else {
+                continue;
+            };
+            let descriptor = TripDescriptor {
+                trip_id: Some(trip_id.to_owned()),
+                route_id: gtfs.trips.get(trip_id).map(|trip| trip.route_id.clone()),
+                start_date: Some(start_date.clone()),
+                ..Default::default()
+            };
+            let stop_time_update = occupancies.iter().enumerate().map(|(stop_order, stop_occupancy)| StopTimeUpdate {
+                stop_sequence: Some(stop_occupancy.stop_sequence),
+                stop_id: Some(stop_occupancy.stop_id.to_owned()),
+                departure: Some(StopTimeEvent {
+                    time: Some(date_timestamp + network.get_departure_time(route_idx, trip, stop_order) as i64),
+                    ..Default::default()
+                }),
+                departure_occupancy_status: Some(stop_occupancy.status as i32),
+                ..Default::default()
+            }).collect();
+            entities.push(FeedEntity {
+                id: trip_id.to_owned(),
+                trip_update: Some(TripUpdate { trip: descriptor.clone(), stop_time_update, ..Default::default() }),
+                vehicle: Some(VehiclePosition {
+                    trip: Some(descriptor),
+                    occupancy_status: Some(peak.status as i32),
+                    occupancy_percentage: Some((100. * peak.load_factor).round() as u32),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            });
+        }
+    }
+
+    let feed = FeedMessage {
+        header: FeedHeader {
+            gtfs_realtime_version: "2.0".to_owned(),
+            timestamp: Some(date_timestamp as u64),
+            ..Default::default()
+        },
+        entity: entities,
+    };
+    std::fs::write(path.with_extension("pb"), feed.encode_to_vec())?;
+
+    Ok(())
+}
+
+// Agents starting, ending or changing at a stop within a time bin.
+// Transfers are counted separately, so boardings + transfers_out is the total number of agents boarding at the stop.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StopActivity {
+    pub boardings: u64,
+    pub alightings: u64,
+    pub transfers_in: u64,
+    pub transfers_out: u64,
+}
+
+impl StopActivity {
+    fn merge(&mut self, other: &Self) {
+        self.boardings += other.boardings;
+        self.alightings += other.alightings;
+        self.transfers_in += other.transfers_in;
+        self.transfers_out += other.transfers_out;
+    }
+}
+
+// Aggregates the final round's journeys into activity per (stop, time bin), where bin i starts at i * bin_size seconds.
+pub fn aggregate_stop_activity(simulation_result: &SimulationResult, bin_size: Timestamp) -> HashMap<(StopIndex, Timestamp), StopActivity> {
+    assert!(bin_size > 0, "Time bin size must be positive");
+    let Some(agent_journeys) = simulation_result.round_agent_journeys.last() else {
+        return HashMap::new();
+    };
+
+    agent_journeys.par_iter().fold(HashMap::new, |mut activity: HashMap<(StopIndex, Timestamp), StopActivity>, agent_journey| {
+        let Ok(journey) = &agent_journey.result else {
+            return activity;
+        };
+        let count = agent_journey.count as u64;
+        let num_legs = journey.legs.len();
+        for (leg_idx, leg) in journey.legs.iter().enumerate() {
+            let boarding = activity.entry((leg.boarded_stop, leg.boarded_time / bin_size)).or_default();
+            if leg_idx == 0 {
+                boarding.boardings += count;
+            } else {
+                boarding.transfers_out += count;
+            }
+            let alighting = activity.entry((leg.arrival_stop, leg.arrival_time / bin_size)).or_default();
+            if leg_idx + 1 == num_legs {
+                alighting.alightings += count;
+            } else {
+                alighting.transfers_in += count;
+            }
+        }
+        activity
+    }).reduce(HashMap::new, |mut activity, other| {
+        for (key, stop_activity) in other.iter() {
+            activity.entry(*key).or_default().merge(stop_activity);
+        }
+        activity
+    })
+}
+
+// Agent-seconds spent at a stop within a time bin. Divided by the bin size, this is the average number of agents present.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StopOccupancy {
+    // Waiting at the origin for the first trip.
+    pub waiting: f64,
+    // Between trips, including walking to the next platform (which is counted at the stop boarded next).
+    pub transferring: f64,
+}
+
+impl StopOccupancy {
+    fn merge(&mut self, other: &Self) {
+        self.waiting += other.waiting;
+        self.transferring += other.transferring;
+    }
+
+    pub fn total(&self) -> f64 {
+        self.waiting + self.transferring
+    }
+}
+
+// Adds `count` agents at a stop from `start` to `end`, split between the time bins the interval overlaps.
+// Empty or backwards intervals (e.g. from a journey starting after its first boarding) add nothing.
+fn add_stop_interval(occupancy: &mut HashMap<(StopIndex, Timestamp), StopOccupancy>, stop: StopIndex, start: Timestamp, end: Timestamp, bin_size: Timestamp, count: f64, transferring: bool) {
+    if end <= start {
+        return;
+    }
+    for bin in (start / bin_size)..=((end - 1) / bin_size) {
+        let overlap = end.min((bin + 1) * bin_size) - start.max(bin * bin_size);
+        let stop_occupancy = occupancy.entry((stop, bin)).or_default();
+        if transferring {
+            stop_occupancy.transferring += count * overlap as f64;
+        } else {
+            stop_occupancy.waiting += count * overlap as f64;
+        }
+    }
+}
+
+// Aggregates the time agents in the final round spend at stops per (stop, time bin), where bin i starts at i * bin_size seconds.
+pub fn aggregate_stop_occupancy(simulation_result: &SimulationResult, bin_size: Timestamp) -> HashMap<(StopIndex, Timestamp), StopOccupancy> {
+    assert!(bin_size > 0, "Time bin size must be positive");
+    let Some(agent_journeys) = simulation_result.round_agent_journeys.last() else {
+        return HashMap::new();
+    };
+
+    agent_journeys.par_iter().fold(HashMap::new, |mut occupancy: HashMap<(StopIndex, Timestamp), StopOccupancy>, agent_journey| {
+        let Ok(journey) = &agent_journey.result else {
+            return occupancy;
+        };
+        let count = agent_journey.count as f64;
+        if let Some(first_leg) = journey.legs.first() {
+            add_stop_interval(&mut occupancy, first_leg.boarded_stop, agent_journey.start_time, first_leg.boarded_time, bin_size, count, false);
+        }
+        for (leg, next_leg) in journey.legs.iter().tuple_windows() {
+            add_stop_interval(&mut occupancy, next_leg.boarded_stop, leg.arrival_time, next_leg.boarded_time, bin_size, count, true);
+        }
+        occupancy
+    }).reduce(HashMap::new, |mut occupancy, other| {
+        for (key, stop_occupancy) in other.iter() {
+            occupancy.entry(*key).or_default().merge(stop_occupancy);
+        }
+        occupancy
+    })
+}
+
+// Writes the average number of agents present at each stop per time bin (in seconds) to <path>.csv, with the load factor
+// where the stop has a capacity (see `data_import::import_stop_capacities`), and as a binary file for the visualiser to <path>.bin.zip:
+// - u32 bin size, first bin index, number of bins and number of stops.
+// - f32 average agents present for each stop (in network order, like stops.csv) and bin, indexed by stop * num_bins + bin.
+// - f32 capacity of each stop, or 0 where unknown.
+pub fn export_stop_occupancy(path: &Path, network: &Network, simulation_result: &SimulationResult, bin_size: Timestamp, stop_capacities: Option<&[Option<PopulationCount>]>) -> Result<(), DataExportError> {
+    let occupancy = aggregate_stop_occupancy(simulation_result, bin_size);
+    if occupancy.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+    let capacity = |stop_idx: StopIndex| stop_capacities.and_then(|capacities| capacities.get(stop_idx as usize).copied().flatten());
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["stop_id", "stop_name", "bin_start", "waiting", "transferring", "present", "capacity", "load_factor"])?;
+    for (&(stop_idx, bin), stop_occupancy) in occupancy.iter().sorted_unstable_by_key(|(key, _)| **key) {
+        let stop = &network.stops[stop_idx as usize];
+        let present = stop_occupancy.total() / bin_size as f64;
+        let capacity = capacity(stop_idx);
+        csv_writer.write_record(&[
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            &get_time_str(bin * bin_size),
+            &format!("{:.2}", stop_occupancy.waiting / bin_size as f64),
+            &format!("{:.2}", stop_occupancy.transferring / bin_size as f64),
+            &format!("{present:.2}"),
+            &capacity.map_or(String::new(), |capacity| capacity.to_string()),
+            &capacity.map_or(String::new(), |capacity| format!("{:.3}", present / capacity as f64)),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    let first_bin = occupancy.keys().map(|&(_, bin)| bin).min().unwrap_or(0);
+    let num_bins = occupancy.keys().map(|&(_, bin)| bin).max().unwrap_or(0) - first_bin + 1;
+    let num_stops = network.num_stops();
+    let mut present = vec![0f32; num_stops * num_bins as usize];
+    for (&(stop_idx, bin), stop_occupancy) in occupancy.iter() {
+        present[stop_idx as usize * num_bins as usize + (bin - first_bin) as usize] = (stop_occupancy.total() / bin_size as f64) as f32;
+    }
+    let capacities = (0..num_stops as StopIndex).map(|stop_idx| capacity(stop_idx).map_or(0., |capacity| capacity as f32)).collect_vec();
+    let dimensions = [bin_size, first_bin, num_bins, num_stops as u32];
+    write_bin(&[bytemuck::must_cast_slice(&dimensions), bytemuck::must_cast_slice(&present), bytemuck::must_cast_slice(&capacities)], &mut open_zip(&path.with_extension("bin.zip"))?)?;
+
+    Ok(())
+}
+
+// Counts the final round's boardings at each stop over the whole day, including boardings after a transfer.
+fn daily_stop_boardings(simulation_result: &SimulationResult, num_stops: usize) -> Vec<u64> {
+    let mut boardings = vec![0u64; num_stops];
+    for (&(stop_idx, _), stop_activity) in aggregate_stop_activity(simulation_result, Timestamp::MAX).iter() {
+        boardings[stop_idx as usize] += stop_activity.boardings + stop_activity.transfers_out;
+    }
+    boardings
+}
+
+// Writes the stops for the visualiser to draw as circles, in network order, as chunks of:
+// - f32 longitude, latitude and height of each stop.
+// - f32 daily boardings at each stop (see daily_stop_boardings), or zero without a simulation result.
+// - The UTF-8 stop names, concatenated.
+// - u32 byte offset of each stop's name in the names chunk, plus one past the end, so name i is offsets[i]..offsets[i + 1].
+// - The UTF-8 (space separated) ids of the stops each station replaced with `parent_stations`, concatenated. They're
+//   empty for the other stops, and for every stop without `parent_stations`.
+// - u32 byte offsets of each stop's child ids, as for the names.
+// The first chunk is the only one in version 1 files, and later chunks are appended after it.
+pub fn export_stops(network: &Network, stop_geometry: &StopGeometry, simulation_result: Option<&SimulationResult>, parent_stations: Option<&ParentStations>, writer: &mut impl Write) -> Result<(), DataExportError> {
+    let num_stops = network.num_stops();
+    if num_stops == 0 {
+        return Err(DataExportError::NoData);
+    }
+
+    let positions = stop_geometry.points.iter().flat_map(|point| [point.longitude, point.latitude, 0.]).collect_vec();
+    let boardings = simulation_result.map_or_else(|| vec![0.; num_stops], |simulation_result| {
+        daily_stop_boardings(simulation_result, num_stops).into_iter().map(|boardings| boardings as f32).collect_vec()
+    });
+
+    let mut names = Vec::new();
+    let mut name_offsets = Vec::with_capacity(num_stops + 1);
+    for name in stop_geometry.names.iter() {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(name.as_bytes());
+    }
+    name_offsets.push(names.len() as u32);
+
+    let children = parent_stations.map(ParentStations::children).unwrap_or_default();
+    let mut child_ids = Vec::new();
+    let mut child_offsets = Vec::with_capacity(num_stops + 1);
+    for stop in network.stops.iter() {
+        child_offsets.push(child_ids.len() as u32);
+        let stop_id: &str = stop.id.as_ref();
+        if let Some(stop_children) = children.get(stop_id) {
+            child_ids.extend_from_slice(stop_children.join(" ").as_bytes());
+        }
+    }
+    child_offsets.push(child_ids.len() as u32);
+
+    write_bin(&[bytemuck::must_cast_slice(&positions), bytemuck::must_cast_slice(&boardings), &names, bytemuck::must_cast_slice(&name_offsets), &child_ids, bytemuck::must_cast_slice(&child_offsets)], writer)
+}
+
+// How the segment load factors in each route and time bin of the heat grid are combined.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub enum HeatGridStat {
+    #[default]
+    Max,
+    Mean,
+}
+
+// Settings for the route by time of day heat grid.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct HeatGridConfig {
+    // Width of the time bins in seconds.
+    pub bin: Timestamp,
+    pub stat: HeatGridStat,
+}
+
+impl Default for HeatGridConfig {
+    fn default() -> Self {
+        Self { bin: 15 * 60, stat: HeatGridStat::default() }
+    }
+}
+
+impl HeatGridConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.bin == 0 {
+            return Err("bin must be greater than zero".to_owned());
+        }
+        Ok(())
+    }
+}
+
+// Writes the load factor of each GTFS route (rows, sorted by route id) per time bin (columns) to <path>.csv, and as a binary
+// file for the visualiser to <path>.bin.zip. Each trip segment counts towards the bin of its departure time, even if it
+// arrives in a later bin. Bins with no segments are empty in the CSV. The binary file has chunks of:
+// - u32 bin size, first bin index, number of bins and number of routes.
+// - f32 load factor for each route and bin, indexed by route * num_bins + bin, and NaN where there are no segments.
+// - The UTF-8 route ids, concatenated.
+// - u32 byte offset of each route id in the route ids chunk, plus one past the end.
+pub fn export_heat_grid(path: &Path, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, heat_grid: &HeatGridConfig) -> Result<(), DataExportError> {
+    // Running (max or sum, count) of the load factors in each route and bin.
+    let mut cells: HashMap<(&str, Timestamp), (f64, u32)> = HashMap::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        let num_segments = network.num_stops_in_route(route_idx).saturating_sub(1);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let route_id = gtfs.trips.get(trip_id).map(|trip| trip.route_id.as_str()).unwrap_or_default();
+            let capacity = trip_capacities.get(trip_id).total() as f64;
+            let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+            for (dep_stop_order, &count) in trip_counts.iter().take(num_segments).enumerate() {
+                let bin = network.get_departure_time(route_idx, trip, dep_stop_order) / heat_grid.bin;
+                let load_factor = count as f64 / capacity;
+                let (value, num_segments) = cells.entry((route_id, bin)).or_insert((0., 0));
+                *value = match heat_grid.stat {
+                    HeatGridStat::Max => value.max(load_factor),
+                    HeatGridStat::Mean => *value + load_factor,
+                };
+                *num_segments += 1;
+            }
+        }
+    }
+    if cells.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+    let cell_value = |&(value, num_segments): &(f64, u32)| match heat_grid.stat {
+        HeatGridStat::Max => value,
+        HeatGridStat::Mean => value / num_segments as f64,
+    };
+
+    let route_ids = cells.keys().map(|&(route_id, _)| route_id).unique().sorted_unstable().collect_vec();
+    let first_bin = cells.keys().map(|&(_, bin)| bin).min().unwrap_or(0);
+    let num_bins = cells.keys().map(|&(_, bin)| bin).max().unwrap_or(0) - first_bin + 1;
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    let header = ["route_id".to_owned()].into_iter().chain((first_bin..first_bin + num_bins).map(|bin| get_time_str(bin * heat_grid.bin)));
+    csv_writer.write_record(header)?;
+    let mut load_factors = vec![f32::NAN; route_ids.len() * num_bins as usize];
+    for (route_row, &route_id) in route_ids.iter().enumerate() {
+        let mut record = Vec::with_capacity(num_bins as usize + 1);
+        record.push(route_id.to_owned());
+        for bin in first_bin..first_bin + num_bins {
+            match cells.get(&(route_id, bin)).map(cell_value) {
+                Some(load_factor) => {
+                    load_factors[route_row * num_bins as usize + (bin - first_bin) as usize] = load_factor as f32;
+                    record.push(format!("{load_factor:.3}"));
+                }
+                None => record.push(String::new()),
+            }
+        }
+        csv_writer.write_record(&record)?;
+    }
+    csv_writer.flush()?;
+
+    let mut names = Vec::new();
+    let mut name_offsets = Vec::with_capacity(route_ids.len() + 1);
+    for route_id in route_ids.iter() {
+        name_offsets.push(names.len() as u32);
+        names.extend_from_slice(route_id.as_bytes());
+    }
+    name_offsets.push(names.len() as u32);
+    let dimensions = [heat_grid.bin, first_bin, num_bins, route_ids.len() as u32];
+    write_bin(&[bytemuck::must_cast_slice(&dimensions), bytemuck::must_cast_slice(&load_factors), &names, bytemuck::must_cast_slice(&name_offsets)], &mut open_zip(&path.with_extension("bin.zip"))?)?;
+
+    Ok(())
+}
+
+// Writes the final round's boardings, alightings and transfers at each stop to <path>.csv, with a row for the whole day and
+// for each reporting period with any activity, by the time agents board or alight.
+pub fn export_stop_boardings(path: &Path, network: &Network, simulation_result: &SimulationResult, periods: &ReportingPeriods) -> Result<(), DataExportError> {
+    let Some(agent_journeys) = simulation_result.round_agent_journeys.last() else {
+        return Err(DataExportError::NoData);
+    };
+
+    let mut activity: HashMap<(StopIndex, usize), StopActivity> = HashMap::new();
+    for agent_journey in agent_journeys.iter() {
+        let Ok(journey) = agent_journey.result else { continue; };
+        let count = agent_journey.count as u64;
+        let num_legs = journey.legs.len();
+        for (leg_idx, leg) in journey.legs.iter().enumerate() {
+            for period in periods.periods_of(leg.boarded_time) {
+                let boarding = activity.entry((leg.boarded_stop, period)).or_default();
+                if leg_idx == 0 {
+                    boarding.boardings += count;
+                } else {
+                    boarding.transfers_out += count;
+                }
+            }
+            for period in periods.periods_of(leg.arrival_time) {
+                let alighting = activity.entry((leg.arrival_stop, period)).or_default();
+                if leg_idx + 1 == num_legs {
+                    alighting.alightings += count;
+                } else {
+                    alighting.transfers_in += count;
+                }
+            }
+        }
+    }
+    if activity.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["stop_id", "stop_name", "period", "boardings", "alightings", "transfers_in", "transfers_out"])?;
+    for (&(stop_idx, period), stop_activity) in activity.iter().sorted_unstable_by_key(|(key, _)| **key) {
+        let stop = &network.stops[stop_idx as usize];
+        csv_writer.write_record(&[
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            periods.name(period),
+            &stop_activity.boardings.to_string(),
+            &stop_activity.alightings.to_string(),
+            &stop_activity.transfers_in.to_string(),
+            &stop_activity.transfers_out.to_string(),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes boardings, alightings and transfers per stop per time bin (in seconds) to <path>.csv, for station demand profiles.
+pub fn export_stop_activity(path: &Path, network: &Network, simulation_result: &SimulationResult, bin_size: Timestamp) -> Result<(), DataExportError> {
+    let activity = aggregate_stop_activity(simulation_result, bin_size);
+    if activity.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["stop_id", "stop_name", "bin_start", "boardings", "alightings", "transfers_in", "transfers_out"])?;
+    for (&(stop_idx, bin), stop_activity) in activity.iter().sorted_unstable_by_key(|(key, _)| **key) {
+        let stop = &network.stops[stop_idx as usize];
+        csv_writer.write_record(&[
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            &get_time_str(bin * bin_size),
+            &stop_activity.boardings.to_string(),
+            &stop_activity.alightings.to_string(),
+            &stop_activity.transfers_in.to_string(),
+            &stop_activity.transfers_out.to_string(),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the demand suppressed by elastic demand in the final round to <path>.csv, per pair of origin and destination stops,
+// and to <path>_by_time.csv, per departure time bin (in seconds). Both include the agents that did travel for comparison.
+pub fn export_suppressed_demand(path: &Path, network: &Network, simulation_result: &SimulationResult, bin_size: Timestamp) -> Result<(), DataExportError> {
+    assert!(bin_size > 0, "Time bin size must be positive");
+    let (Some(suppressed_counts), Some(agent_journeys)) = (&simulation_result.suppressed_counts, simulation_result.round_agent_journeys.last()) else {
+        return Err(DataExportError::NoData);
+    };
+
+    // (travelled, suppressed) agents.
+    let mut by_od: HashMap<(StopIndex, StopIndex), (u64, u64)> = HashMap::new();
+    let mut by_time: HashMap<Timestamp, (u64, u64)> = HashMap::new();
+    for (agent_journey, &suppressed) in agent_journeys.iter().zip(suppressed_counts.iter()) {
+        for totals in [by_od.entry((agent_journey.origin_stop, agent_journey.dest_stop)).or_default(), by_time.entry(agent_journey.start_time / bin_size).or_default()] {
+            totals.0 += agent_journey.count as u64;
+            totals.1 += suppressed as u64;
+        }
+    }
+    if by_od.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+    let suppressed_share = |(travelled, suppressed): (u64, u64)| suppressed as f64 / (travelled + suppressed).max(1) as f64;
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["origin_stop_id", "destination_stop_id", "travelled", "suppressed", "suppressed_share"])?;
+    for (&(origin_stop, dest_stop), &totals) in by_od.iter().sorted_unstable_by_key(|(od, _)| **od) {
+        csv_writer.write_record(&[
+            network.stops[origin_stop as usize].id.as_ref(),
+            network.stops[dest_stop as usize].id.as_ref(),
+            &totals.0.to_string(),
+            &totals.1.to_string(),
+            &format!("{:.4}", suppressed_share(totals)),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    let by_time_path = path.with_file_name(format!("{}_by_time.csv", path.file_stem().unwrap_or_default().to_string_lossy()));
+    let mut csv_writer = csv::Writer::from_path(by_time_path)?;
+    csv_writer.write_record(&["bin_start", "travelled", "suppressed", "suppressed_share"])?;
+    for (&bin, &totals) in by_time.iter().sorted_unstable_by_key(|(bin, _)| **bin) {
+        csv_writer.write_record(&[
+            get_time_str(bin * bin_size),
+            totals.0.to_string(),
+            totals.1.to_string(),
+            format!("{:.4}", suppressed_share(totals)),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Crowding an agent experienced on its journey, or on its legs on one route.
+#[derive(Clone, Copy, Default)]
+struct ExperiencedCrowding {
+    seconds_above_seated: f32,
+    seconds_above_capacity: f32,
+    max_load_factor: f32,
+}
+
+impl ExperiencedCrowding {
+    fn add_segment(&mut self, seconds: f32, load: PopulationCount, capacity: TripCapacity) {
+        if load > capacity.seated {
+            self.seconds_above_seated += seconds;
+        }
+        if load > capacity.total() {
+            self.seconds_above_capacity += seconds;
+        }
+        self.max_load_factor = self.max_load_factor.max(load as f32 / capacity.total() as f32);
+    }
+
+    fn metric(&self, metric: usize) -> f32 {
+        [self.seconds_above_seated, self.seconds_above_capacity, self.max_load_factor][metric]
+    }
+}
+
+const CROWDING_METRICS: [&str; 3] = ["seconds_above_seated", "seconds_above_capacity", "max_load_factor"];
+// Histogram bin width of each metric: a minute for the times, and 0.1 of load factor.
+const CROWDING_HISTOGRAM_BINS: [f32; 3] = [60., 60., 0.1];
+const CROWDING_PERCENTILES: [f64; 3] = [0.5, 0.85, 0.95];
+// The route_id of the rows covering whole journeys.
+const NETWORK_ROUTE_ID: &str = "all";
+
+// The smallest value at least a fraction `p` of the agents have, of (value, count) pairs sorted by value.
+fn weighted_percentile(sorted: &[(f32, AgentCount)], total: u64, p: f64) -> f32 {
+    let target = ((p * total as f64).ceil() as u64).max(1);
+    let mut cumulative = 0;
+    for &(value, count) in sorted {
+        cumulative += count as u64;
+        if cumulative >= target {
+            return value;
+        }
+    }
+    sorted.last().map_or(0., |&(value, _)| value)
+}
+
+// Writes the distribution over the final round's agents of the time they spent above seated capacity, the time above full
+// capacity and the highest load factor they experienced, to <path>.csv as percentiles and to <path>_histogram.csv.
+// Each GTFS route has rows over the agents that rode it (counting only their legs on it), and route_id "all" covers whole
+// journeys for the network overall. Percentiles come from sorting the flat per-agent values, so memory is linear in agents.
+pub fn export_crowding_percentiles(path: &Path, network: &Network, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities) -> Result<(), DataExportError> {
+    let Some(agent_journeys) = simulation_result.round_agent_journeys.last() else {
+        return Err(DataExportError::NoData);
+    };
+
+    let mut groups: HashMap<&str, Vec<(ExperiencedCrowding, AgentCount)>> = HashMap::new();
+    let mut route_crowding: Vec<(&str, ExperiencedCrowding)> = Vec::new();
+    for agent_journey in agent_journeys.iter() {
+        let Ok(journey) = agent_journey.result else { continue; };
+        let mut journey_crowding = ExperiencedCrowding::default();
+        route_crowding.clear();
+        for leg in journey.legs.iter() {
+            let route_idx = leg.trip.route_idx as usize;
+            let trip = leg.trip.trip_order as usize;
+            let trip_id: &str = network.routes[route_idx].trip_ids[trip].as_ref();
+            let route_id = gtfs.trips.get(trip_id).map_or(trip_id, |trip| trip.route_id.as_str());
+            let capacity = trip_capacities.get(trip_id);
+            let trip_start = network.routes[route_idx].get_trip_range(trip).start;
+            let leg_crowding = match route_crowding.iter_mut().find(|(id, _)| *id == route_id) {
+                Some((_, crowding)) => crowding,
+                None => {
+                    route_crowding.push((route_id, ExperiencedCrowding::default()));
+                    &mut route_crowding.last_mut().unwrap().1
+                }
+            };
+            // The load at a stop time is the load departing that stop.
+            for stop_order in leg.boarded_stop_order as usize..leg.arrival_stop_order as usize {
+                let seconds = network.get_arrival_time(route_idx, trip, stop_order + 1).saturating_sub(network.get_departure_time(route_idx, trip, stop_order)) as f32;
+                let load = simulation_result.population_count[trip_start + stop_order];
+                leg_crowding.add_segment(seconds, load, capacity);
+                journey_crowding.add_segment(seconds, load, capacity);
+            }
+        }
+        groups.entry(NETWORK_ROUTE_ID).or_default().push((journey_crowding, agent_journey.count));
+        for &(route_id, crowding) in route_crowding.iter() {
+            groups.entry(route_id).or_default().push((crowding, agent_journey.count));
+        }
+    }
+    if groups.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["route_id", "metric", "agents", "p50", "p85", "p95"])?;
+    let histogram_path = path.with_file_name(format!("{}_histogram.csv", path.file_stem().unwrap_or_default().to_string_lossy()));
+    let mut histogram_writer = csv::Writer::from_path(histogram_path)?;
+    histogram_writer.write_record(&["route_id", "metric", "bin_start", "bin_end", "agents"])?;
+    // The network overall first, then the routes in order.
+    let sorted_groups = groups.iter().sorted_unstable_by_key(|(&route_id, _)| (route_id != NETWORK_ROUTE_ID, route_id));
+    for (&route_id, agents) in sorted_groups {
+        let total = agents.iter().map(|&(_, count)| count as u64).sum::<u64>();
+        for (metric, &metric_name) in CROWDING_METRICS.iter().enumerate() {
+            let mut values = agents.iter().map(|(crowding, count)| (crowding.metric(metric), *count)).collect_vec();
+            values.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+            let percentiles = CROWDING_PERCENTILES.map(|p| weighted_percentile(&values, total, p));
+            csv_writer.write_record(&[
+                route_id,
+                metric_name,
+                &total.to_string(),
+                &format!("{:.3}", percentiles[0]),
+                &format!("{:.3}", percentiles[1]),
+                &format!("{:.3}", percentiles[2]),
+            ])?;
+
+            // The values are sorted, so each bin's agents are consecutive.
+            let bin_width = CROWDING_HISTOGRAM_BINS[metric];
+            for (bin, bin_values) in &values.iter().chunk_by(|(value, _)| (value / bin_width).floor() as u32) {
+                histogram_writer.write_record(&[
+                    route_id,
+                    metric_name,
+                    &format!("{}", bin as f32 * bin_width),
+                    &format!("{}", (bin + 1) as f32 * bin_width),
+                    &bin_values.map(|&(_, count)| count as u64).sum::<u64>().to_string(),
+                ])?;
+            }
+        }
+    }
+    csv_writer.flush()?;
+    histogram_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the number of agents departing in each time bin (in seconds) to <path>.csv, to check the realised departure profile.
+pub fn export_departures(path: &Path, simulation_steps: &[SimulationStep], bin_size: Timestamp) -> Result<(), DataExportError> {
+    let mut departures = HashMap::new();
+    for simulation_step in simulation_steps.iter() {
+        *departures.entry(simulation_step.departure_time / bin_size).or_insert(0u64) += simulation_step.count() as u64;
+    }
+    let num_agents = departures.values().sum::<u64>();
+    if num_agents == 0 {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["bin_start", "agents", "share"])?;
+    for (&bin, &agents) in departures.iter().sorted_unstable_by_key(|(bin, _)| **bin) {
+        csv_writer.write_record(&[get_time_str(bin * bin_size), agents.to_string(), format!("{:.4}", agents as f64 / num_agents as f64)])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Splits a route's shape into the points between each pair of consecutive stops.
+// Falls back to a straight line between the stops where the route has no shape.
+fn route_segment_shapes(network: &Network, stop_geometry: &StopGeometry, route_idx: usize) -> Vec<Vec<NetworkPoint>> {
+    let route = &network.routes[route_idx];
+    let route_shape = &route.shape;
+    let num_stops = network.num_stops_in_route(route_idx);
+
+    let mut segments = Vec::with_capacity(num_stops.saturating_sub(1));
+    let mut shape_idx = 0;
+    for dep_stop_order in 0..num_stops.saturating_sub(1) {
+        let dep_point = stop_geometry.point(network.get_stop_in_route(route_idx, dep_stop_order));
+        let arr_point = stop_geometry.point(network.get_stop_in_route(route_idx, dep_stop_order + 1));
+        if route_shape.is_empty() {
+            segments.push(vec![dep_point, arr_point]);
+            continue;
+        }
+
+        // Walk along the shape until we reach the arrival stop, the same way as export_network_trips.
+        let mut points = vec![route_shape[shape_idx]];
+        let mut current_point = route_shape[shape_idx];
+        while !current_point.very_close(arr_point) && shape_idx + 1 < route_shape.len() {
+            shape_idx += 1;
+            current_point = route_shape[shape_idx];
+            points.push(current_point);
+        }
+        if points.len() < 2 {
+            // Couldn't follow the shape, so draw a straight line.
+            points = vec![dep_point, arr_point];
+        }
+        segments.push(points);
+    }
+    segments
+}
+
+// Escapes a string for use in a JSON document.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Writes a GeoJSON (RFC 7946) FeatureCollection with a LineString for every trip segment, following the route shape,
+// with properties for the segment's load so it can be styled by crowding in GIS software, and whether it's a replacement bus.
+pub fn export_geojson(path: &Path, network: &Network, stop_geometry: &StopGeometry, gtfs: &Gtfs, simulation_result: &SimulationResult, trip_capacities: &TripCapacities) -> Result<(), DataExportError> {
+    if simulation_result.population_count.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut writer = BufWriter::new(File::create(path.with_extension("geojson"))?);
+    write!(writer, r#"{{"type":"FeatureCollection","features":["#)?;
+    let mut first_feature = true;
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        let segment_shapes = route_segment_shapes(network, stop_geometry, route_idx);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let route_id = gtfs.trips.get(trip_id).map(|trip| trip.route_id.as_str()).unwrap_or_default();
+            let capacity = trip_capacities.get(trip_id).total();
+            let replacement = is_replacement_trip(gtfs, trip_id);
+            let trip_agent_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+
+            for (dep_stop_order, ((&from_stop, &to_stop), &count, points)) in izip!(stops.iter().tuple_windows(), trip_agent_counts, &segment_shapes).enumerate() {
+                if !first_feature {
+                    write!(writer, ",")?;
+                }
+                first_feature = false;
+
+                // GeoJSON positions are longitude then latitude.
+                let coordinates = points.iter().map(|point| format!("[{},{}]", point.longitude, point.latitude)).join(",");
+                write!(writer,
+                       r#"{{"type":"Feature","geometry":{{"type":"LineString","coordinates":[{coordinates}]}},"properties":{{"route_id":{},"trip_id":{},"from_stop_id":{},"from_stop_name":{},"to_stop_id":{},"to_stop_name":{},"departure_time":{},"passengers":{count},"capacity":{capacity},"load_factor":{:.3},"replacement":{replacement}}}}}"#,
+                       json_string(route_id),
+                       json_string(trip_id),
+                       json_string(network.stops[from_stop as usize].id.as_ref()),
+                       json_string(stop_geometry.name(from_stop)),
+                       json_string(network.stops[to_stop as usize].id.as_ref()),
+                       json_string(stop_geometry.name(to_stop)),
+                       json_string(&get_time_str(network.get_departure_time(route_idx, trip, dep_stop_order))),
+                       count as f32 / capacity as f32,
+                )?;
+            }
+        }
+    }
+    write!(writer, "]}}")?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Writes one row of convergence statistics per round that was run to <path>.csv.
+pub fn export_convergence(path: &Path, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    if simulation_result.iteration_history.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let optional = |value: Option<f64>| value.map_or(String::new(), |value| value.to_string());
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["round", "step_size", "total_crowding_cost", "relative_change", "relative_load_gap", "total_passenger_hours", "max_segment_load", "num_replanned", "num_plan_cache_hits", "num_changed_route", "switching_cost_improvement"])?;
+    for stats in simulation_result.iteration_history.iter() {
+        csv_writer.write_record(&[
+            stats.round_number.to_string(),
+            stats.step_size.to_string(),
+            stats.total_crowding_cost.to_string(),
+            optional(stats.relative_change),
+            optional(stats.relative_load_gap),
+            stats.total_passenger_hours.to_string(),
+            stats.max_segment_load.to_string(),
+            stats.num_replanned.to_string(),
+            stats.num_plan_cache_hits.to_string(),
+            stats.num_changed_route.map_or(String::new(), |num| num.to_string()),
+            optional(stats.switching_cost_improvement),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the agents that changed route between rounds for each OD pair to <path>.csv, most switches first, to find the
+// pairs keeping the assignment from converging. The switch rate is per agent and round compared, with the final round's agents.
+pub fn export_plan_switching(path: &Path, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    let num_rounds_compared = simulation_result.iteration_history.iter().filter(|stats| stats.num_changed_route.is_some()).count();
+    let mut od_counts: HashMap<(StopIndex, StopIndex), u64> = HashMap::new();
+    for agent_journey in simulation_result.round_agent_journeys.last().into_iter().flatten() {
+        *od_counts.entry((agent_journey.origin_stop, agent_journey.dest_stop)).or_default() += agent_journey.count as u64;
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["origin_stop_id", "destination_stop_id", "num_agents", "num_switched", "switch_rate", "mean_cost_improvement"])?;
+    let by_switches = simulation_result.plan_switching.iter().sorted_unstable_by(|(a_od, a), (b_od, b)| b.num_switched.cmp(&a.num_switched).then(a_od.cmp(b_od)));
+    for (&(origin_stop, dest_stop), switching) in by_switches {
+        let num_agents = od_counts.get(&(origin_stop, dest_stop)).copied().unwrap_or(0);
+        csv_writer.write_record(&[
+            network.stops[origin_stop as usize].id.as_ref(),
+            network.stops[dest_stop as usize].id.as_ref(),
+            &num_agents.to_string(),
+            &switching.num_switched.to_string(),
+            &format!("{:.4}", switching.num_switched as f64 / (num_agents * num_rounds_compared as u64).max(1) as f64),
+            &format!("{:.4}", switching.cost_improvement / switching.num_switched.max(1) as f64),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Writes the agents and average journey time and crowding cost experienced by each population segment in the final round to <path>.csv.
+pub fn export_segment_summary(path: &Path, simulation_result: &SimulationResult, segment_names: &[String]) -> Result<(), DataExportError> {
+    let agent_journeys = simulation_result.round_agent_journeys.last().ok_or(DataExportError::NoData)?;
+
+    // (agents, agents without a journey, total journey minutes, total crowding cost) per segment.
+    let mut totals = vec![(0u64, 0u64, 0f64, 0f64); segment_names.len().max(1)];
+    for agent_journey in agent_journeys.iter().filter(|agent_journey| agent_journey.count > 0) {
+        let count = agent_journey.count as u64;
+        let Some(segment_totals) = totals.get_mut(agent_journey.segment as usize) else {
+            return Err(DataExportError::MissingData("segment names"));
+        };
+        segment_totals.0 += count;
+        match &agent_journey.result {
+            Ok(journey) => {
+                segment_totals.2 += count as f64 * journey.duration as f64 / 60.;
+                segment_totals.3 += count as f64 * journey.experienced_crowding_cost as f64;
+            }
+            Err(_) => segment_totals.1 += count,
+        }
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["segment", "agents", "agents_without_journey", "mean_journey_minutes", "mean_crowding_cost"])?;
+    for (segment, &(agents, agents_without_journey, journey_minutes, crowding_cost)) in totals.iter().enumerate() {
+        let agents_with_journey = (agents - agents_without_journey).max(1) as f64;
+        csv_writer.write_record(&[
+            segment_names.get(segment).map_or("all", |name| name.as_str()),
+            &agents.to_string(),
+            &agents_without_journey.to_string(),
+            &format!("{:.2}", journey_minutes / agents_with_journey),
+            &format!("{:.3}", crowding_cost / agents_with_journey),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Percentile (0-1) of (value, agent count) pairs weighted by agent count. Sorts the values.
+fn weighted_percentile(values: &mut [(f64, u64)], percentile: f64) -> f64 {
+    values.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    let total = values.iter().map(|&(_, count)| count).sum::<u64>();
+    let target = (percentile * total as f64).ceil().max(1.) as u64;
+    let mut cumulative = 0;
+    for &(value, count) in values.iter() {
+        cumulative += count;
+        if cumulative >= target {
+            return value;
+        }
+    }
+    values.last().map_or(0., |&(value, _)| value)
+}
+
+// Writes a summary of the final round's journeys from each origin stop to <path>.csv: the mean, median and 95th percentile
+// of the experienced crowding cost and journey time, and the mean in-vehicle time, wait time and transfers.
+pub fn export_origin_summary(path: &Path, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    let agent_journeys = simulation_result.round_agent_journeys.last().ok_or(DataExportError::NoData)?;
+
+    let mut origin_journeys = HashMap::new();
+    for agent_journey in agent_journeys.iter() {
+        if let Ok(journey) = agent_journey.result {
+            origin_journeys.entry(agent_journey.origin_stop).or_insert_with(Vec::new).push((journey, agent_journey.count as u64));
+        }
+    }
+    if origin_journeys.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&[
+        "stop_id", "stop_name", "agents",
+        "mean_crowding_cost", "median_crowding_cost", "p95_crowding_cost",
+        "mean_journey_minutes", "median_journey_minutes", "p95_journey_minutes",
+        "mean_in_vehicle_minutes", "mean_wait_minutes", "mean_transfers",
+    ])?;
+    for (&origin_stop, journeys) in origin_journeys.iter().sorted_unstable_by_key(|(stop, _)| **stop) {
+        let agents = journeys.iter().map(|&(_, count)| count).sum::<u64>();
+        let mean = |value: &dyn Fn(&JourneyRef) -> f64| journeys.iter().map(|(journey, count)| value(journey) * *count as f64).sum::<f64>() / agents as f64;
+        let mut crowding_costs = journeys.iter().map(|&(journey, count)| (journey.experienced_crowding_cost as f64, count)).collect_vec();
+        let mut journey_minutes = journeys.iter().map(|&(journey, count)| (journey.duration as f64 / 60., count)).collect_vec();
+        let stop = &network.stops[origin_stop as usize];
+        csv_writer.write_record(&[
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            &agents.to_string(),
+            &format!("{:.3}", mean(&|journey| journey.experienced_crowding_cost as f64)),
+            &format!("{:.3}", weighted_percentile(&mut crowding_costs, 0.5)),
+            &format!("{:.3}", weighted_percentile(&mut crowding_costs, 0.95)),
+            &format!("{:.2}", mean(&|journey| journey.duration as f64 / 60.)),
+            &format!("{:.2}", weighted_percentile(&mut journey_minutes, 0.5)),
+            &format!("{:.2}", weighted_percentile(&mut journey_minutes, 0.95)),
+            &format!("{:.2}", mean(&|journey| journey.in_vehicle_time as f64 / 60.)),
+            &format!("{:.2}", mean(&|journey| journey.wait_time as f64 / 60.)),
+            &format!("{:.2}", mean(&|journey| journey.num_transfers as f64)),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Totals for one origin and destination in the assigned OD matrix.
+#[derive(Clone, Copy, Default)]
+struct OdTotals {
+    num_agents: u64,
+    journey_time: f64,
+    transfers: f64,
+}
+
+// Writes the final round's assigned demand to <path>.csv, with one row for each pair of stops that agents first boarded and last
+// alighted at (not the stops they transferred at), giving the agents and their mean journey time (in minutes) and transfers.
+// With `parent_stations`, stops are combined into their GTFS parent station. Totals are kept for the pairs with agents only,
+// and rows are formatted as they're written.
+pub fn export_od_matrix(path: &Path, network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult, parent_stations: bool) -> Result<(), DataExportError> {
+    let agent_journeys = simulation_result.round_agent_journeys.last().ok_or(DataExportError::NoData)?;
+
+    // The id each stop is reported as, and an index for each id so the totals don't key on strings.
+    let mut location_ids: Vec<&str> = Vec::new();
+    let mut location_idx_map = HashMap::new();
+    let stop_locations = network.stops.iter().enumerate().map(|(stop_idx, stop)| {
+        let stop_id: &str = stop.id.as_ref();
+        let location_id = if parent_stations {
+            stop_geometry.parent_station(stop_idx as StopIndex).unwrap_or(stop_id)
+        } else {
+            stop_id
+        };
+        *location_idx_map.entry(location_id).or_insert_with(|| {
+            location_ids.push(location_id);
+            location_ids.len() as u32 - 1
+        })
+    }).collect_vec();
+
+    let mut od_totals: HashMap<(u32, u32), OdTotals> = HashMap::new();
+    for agent_journey in agent_journeys.iter() {
+        let Ok(journey) = agent_journey.result else { continue; };
+        let origin_stop = journey.legs.first().map_or(agent_journey.origin_stop, |leg| leg.boarded_stop);
+        let dest_stop = journey.legs.last().map_or(agent_journey.dest_stop, |leg| leg.arrival_stop);
+        let totals = od_totals.entry((stop_locations[origin_stop as usize], stop_locations[dest_stop as usize])).or_default();
+        let count = agent_journey.count as u64;
+        totals.num_agents += count;
+        totals.journey_time += count as f64 * journey.duration as f64;
+        totals.transfers += count as f64 * journey.num_transfers as f64;
+    }
+    if od_totals.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["origin_stop_id", "destination_stop_id", "agents", "mean_journey_time", "mean_transfers"])?;
+    for (&(origin, destination), totals) in od_totals.iter().sorted_unstable_by_key(|(od, _)| **od) {
+        let num_agents = totals.num_agents as f64;
+        csv_writer.write_record(&[
+            location_ids[origin as usize],
+            location_ids[destination as usize],
+            &totals.num_agents.to_string(),
+            &format!("{:.2}", totals.journey_time / num_agents / 60.),
+            &format!("{:.2}", totals.transfers / num_agents),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Totals for one GTFS route and direction in the route summary.
+#[derive(Clone, Default)]
+struct RouteDirectionSummary {
+    num_trips: usize,
+    boardings: u64,
+    passenger_km: f64,
+    // Highest segment load, with its departure and arrival stops.
+    max_load: Option<(PopulationCount, StopIndex, StopIndex)>,
+    peak_load_factor: f64,
+    // Crowding cost of each segment, summed over the agents on it.
+    total_crowding_cost: f64,
+    total_segment_agents: f64,
+}
+
+// Writes one row per GTFS route and direction (from the GTFS trips) to <path>.csv: the final round's boardings, the passenger-km,
+// the highest load segment, the peak load factor and the mean crowding cost per agent per segment.
+// Every route in the network is included, with zeros if no agents used it. Each route and direction has a row for the whole
+// day and for each reporting period, with segments and boardings counted in the period they depart in, and trips in the
+// period of their first departure.
+pub fn export_route_summary(path: &Path, network: &Network, stop_geometry: &StopGeometry, gtfs: &Gtfs, simulation_result: &SimulationResult, params: &DefaultSimulationParams, periods: &ReportingPeriods) -> Result<(), DataExportError> {
+    let direction_name = |direction_id: Option<DirectionType>| match direction_id {
+        Some(DirectionType::Outbound) => "0",
+        Some(DirectionType::Inbound) => "1",
+        None => "",
+    };
+    let trip_group = |trip_id: &str| gtfs.trips.get(trip_id).map_or(("", ""), |trip| (trip.route_id.as_str(), direction_name(trip.direction_id)));
+
+    let mut summaries: HashMap<(&str, &str, usize), RouteDirectionSummary> = HashMap::new();
+    for (route_idx, route) in network.routes.iter().enumerate() {
+        let stops = route.get_stops(&network.route_stops);
+        let segment_km = route_segment_km(network, stop_geometry, route_idx);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let capacity = params.trip_capacities.get(trip_id).total() as f64;
+            let (route_id, direction_id) = trip_group(trip_id);
+            // Every route gets an all-day row.
+            summaries.entry((route_id, direction_id, ReportingPeriods::ALL_DAY)).or_default();
+            for period in periods.periods_of(network.get_departure_time(route_idx, trip, 0)) {
+                summaries.entry((route_id, direction_id, period)).or_default().num_trips += 1;
+            }
+
+            let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+            for (dep_stop_order, (&count, &km)) in izip!(trip_counts, segment_km.iter()).enumerate() {
+                for period in periods.periods_of(network.get_departure_time(route_idx, trip, dep_stop_order)) {
+                    let summary = summaries.entry((route_id, direction_id, period)).or_default();
+                    summary.passenger_km += count as f64 * km;
+                    summary.peak_load_factor = summary.peak_load_factor.max(count as f64 / capacity);
+                    if count > 0 {
+                        summary.total_crowding_cost += count as f64 * params.cost_fn(trip_id, count) as f64;
+                        summary.total_segment_agents += count as f64;
+                    }
+                    // An unused route has no highest load segment.
+                    if count > 0 && !summary.max_load.is_some_and(|(max_load, _, _)| count <= max_load) {
+                        summary.max_load = Some((count, stops[dep_stop_order], stops[dep_stop_order + 1]));
+                    }
+                }
+            }
+        }
+    }
+    if summaries.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    for agent_journey in simulation_result.round_agent_journeys.last().into_iter().flatten() {
+        let Ok(journey) = agent_journey.result else { continue; };
+        for leg in journey.legs.iter() {
+            let trip_id: &str = network.routes[leg.trip.route_idx as usize].trip_ids[leg.trip.trip_order as usize].as_ref();
+            let (route_id, direction_id) = trip_group(trip_id);
+            for period in periods.periods_of(leg.boarded_time) {
+                if let Some(summary) = summaries.get_mut(&(route_id, direction_id, period)) {
+                    summary.boardings += agent_journey.count as u64;
+                }
+            }
+        }
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&[
+        "route_id", "route_short_name", "direction_id", "period", "trips", "boardings", "passenger_km",
+        "max_load", "max_load_from_stop_id", "max_load_to_stop_id", "peak_load_factor", "mean_crowding_cost",
+    ])?;
+    for (&(route_id, direction_id, period), summary) in summaries.iter().sorted_unstable_by_key(|(group, _)| **group) {
+        let route_short_name = gtfs.routes.get(route_id).and_then(|route| route.short_name.as_deref()).unwrap_or_default();
+        let (max_load, from_stop_id, to_stop_id) = summary.max_load.map_or((0, "", ""), |(load, from_stop, to_stop)| {
+            (load, network.stops[from_stop as usize].id.as_ref(), network.stops[to_stop as usize].id.as_ref())
+        });
+        let mean_crowding_cost = if summary.total_segment_agents > 0. { summary.total_crowding_cost / summary.total_segment_agents } else { 0. };
+        csv_writer.write_record(&[
+            route_id,
+            route_short_name,
+            direction_id,
+            periods.name(period),
+            &summary.num_trips.to_string(),
+            &summary.boardings.to_string(),
+            &format!("{:.1}", summary.passenger_km),
+            &max_load.to_string(),
+            from_stop_id,
+            to_stop_id,
+            &format!("{:.3}", summary.peak_load_factor),
+            &format!("{mean_crowding_cost:.3}"),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+// Exports the trips visualisation coloured by the change in agent count from the base to the scenario.
+// Segments that lost agents are coloured towards low and segments that gained agents towards high, with unchanged segments in between.
+pub fn export_delta_trips(network: &Network, stop_geometry: &StopGeometry, base: &SimulationResult, scenario: &SimulationResult, writer: &mut impl Write) -> Result<(), DataExportError> {
+    // Segments are fully coloured once their agent count changes by this much.
+    const MAX_AGENT_COUNT_DELTA: f32 = 50.;
+
+    if base.population_count.len() != scenario.population_count.len() {
+        return Err(DataExportError::MissingData("scenario results for the same network"));
+    }
+    let values = base.population_count.iter().zip(scenario.population_count.iter()).map(|(&base_count, &scenario_count)| {
+        (0.5 + (scenario_count as f32 - base_count as f32) / (2. * MAX_AGENT_COUNT_DELTA)).clamp(0., 1.)
+    }).collect_vec();
+    write_trips_bin(network, stop_geometry, &values, None, |idx| base.population_count[idx] > 0 || scenario.population_count[idx] > 0, writer)
+}
+
+// Crowding cost and duration totals over a set of journeys, weighted by agent count.
+#[derive(Clone, Copy, Default)]
+struct StopJourneyTotals {
+    num_agents: u64,
+    crowding_cost: f64,
+    duration: f64,
+}
+
+impl StopJourneyTotals {
+    fn add(&mut self, count: AgentCount, crowding_cost: CrowdingCost, duration: Timestamp) {
+        self.num_agents += count as u64;
+        self.crowding_cost += count as f64 * crowding_cost as f64;
+        self.duration += count as f64 * duration as f64;
+    }
+
+    fn mean_crowding_cost(&self) -> f64 {
+        if self.num_agents == 0 { 0. } else { self.crowding_cost / self.num_agents as f64 }
+    }
+
+    fn mean_duration(&self) -> f64 {
+        if self.num_agents == 0 { 0. } else { self.duration / self.num_agents as f64 }
+    }
+}
 
-    let departures_arr = Arc::new(StringArray::from(departures.clone()));
-    let departures_field = Field::new("Departure", departures_arr.data_type().clone(), false);
+fn journey_totals_by_origin(simulation_result: &SimulationResult, num_stops: usize) -> Vec<StopJourneyTotals> {
+    let mut totals = vec![StopJourneyTotals::default(); num_stops];
+    for agent_journey in simulation_result.round_agent_journeys.last().into_iter().flatten() {
+        if let Ok(journey) = &agent_journey.result {
+            totals[agent_journey.origin_stop as usize].add(agent_journey.count, journey.crowding_cost, journey.duration);
+        }
+    }
+    totals
+}
 
-    let departure_ids_arr = Arc::new(StringArray::from(departure_ids.clone()));
-    let departure_ids_field = Field::new("Departure_ID", departure_ids_arr.data_type().clone(), false);
+// Compares two runs of the same network and demand (e.g. with different capacities or crowding functions), writing into `dir`:
+// - segment_deltas.csv: the change in load and load factor of every trip segment.
+// - stop_deltas.csv: the change in agents boarding at each stop, and in the average crowding cost and duration of journeys starting there.
+// - summary.csv: network totals, and the number of agents whose crowding cost went down (winners) or up (losers).
+// - trips.bin.zip: the visualisation coloured by the change in load (see export_delta_trips).
+pub fn export_scenario_comparison(dir: &Path, network: &Network, stop_geometry: &StopGeometry, base: &SimulationResult, base_capacities: &TripCapacities, scenario: &SimulationResult, scenario_capacities: &TripCapacities) -> Result<(), DataExportError> {
+    // Changes in an agent's crowding cost smaller than this are treated as no change.
+    const COST_EPSILON: f64 = 1e-6;
+
+    if base.population_count.is_empty() || scenario.population_count.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+    if base.population_count.len() != scenario.population_count.len() {
+        return Err(DataExportError::MissingData("scenario results for the same network"));
+    }
+    std::fs::create_dir_all(dir)?;
 
-    let arrivals_arr = Arc::new(StringArray::from(arrivals.clone()));
-    let arrivals_field = Field::new("Arrival", arrivals_arr.data_type().clone(), false);
+    // Per-segment deltas.
+    let mut csv_writer = csv::Writer::from_path(dir.join("segment_deltas.csv"))?;
+    csv_writer.write_record(&["trip_id", "from_stop_id", "to_stop_id", "departure_time", "base_load", "scenario_load", "load_change", "base_load_factor", "scenario_load_factor", "load_factor_change"])?;
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let base_capacity = base_capacities.get(trip_id).total() as f32;
+            let scenario_capacity = scenario_capacities.get(trip_id).total() as f32;
+            let trip_range = route.get_trip_range(trip);
+            let counts = base.population_count[trip_range.clone()].iter().zip(&scenario.population_count[trip_range]);
+            for (dep_stop_order, ((&from_stop, &to_stop), (&base_count, &scenario_count))) in stops.iter().tuple_windows().zip(counts).enumerate() {
+                if base_count == 0 && scenario_count == 0 {
+                    continue;
+                }
+                let base_load_factor = base_count as f32 / base_capacity;
+                let scenario_load_factor = scenario_count as f32 / scenario_capacity;
+                csv_writer.write_record(&[
+                    trip_id,
+                    network.stops[from_stop as usize].id.as_ref(),
+                    network.stops[to_stop as usize].id.as_ref(),
+                    &get_time_str(network.get_departure_time(route_idx, trip, dep_stop_order)),
+                    &base_count.to_string(),
+                    &scenario_count.to_string(),
+                    &(scenario_count as i64 - base_count as i64).to_string(),
+                    &format!("{base_load_factor:.3}"),
+                    &format!("{scenario_load_factor:.3}"),
+                    &format!("{:.3}", scenario_load_factor - base_load_factor),
+                ])?;
+            }
+        }
+    }
+    csv_writer.flush()?;
+
+    // Per-stop deltas. Boardings include transfers, so they're comparable to segment loads.
+    let num_stops = network.stops.len();
+    let base_boardings = daily_stop_boardings(base, num_stops);
+    let scenario_boardings = daily_stop_boardings(scenario, num_stops);
+    let base_totals = journey_totals_by_origin(base, num_stops);
+    let scenario_totals = journey_totals_by_origin(scenario, num_stops);
+
+    let mut csv_writer = csv::Writer::from_path(dir.join("stop_deltas.csv"))?;
+    csv_writer.write_record(&["stop_id", "stop_name", "base_boardings", "scenario_boardings", "boardings_change", "base_mean_crowding_cost", "scenario_mean_crowding_cost", "mean_crowding_cost_change", "mean_duration_change"])?;
+    for (stop, &base_count, &scenario_count, base_total, scenario_total) in izip!(network.stops.iter(), &base_boardings, &scenario_boardings, &base_totals, &scenario_totals) {
+        if base_count == 0 && scenario_count == 0 {
+            continue;
+        }
+        csv_writer.write_record(&[
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            &base_count.to_string(),
+            &scenario_count.to_string(),
+            &(scenario_count as i64 - base_count as i64).to_string(),
+            &format!("{:.4}", base_total.mean_crowding_cost()),
+            &format!("{:.4}", scenario_total.mean_crowding_cost()),
+            &format!("{:.4}", scenario_total.mean_crowding_cost() - base_total.mean_crowding_cost()),
+            &format!("{:.1}", scenario_total.mean_duration() - base_total.mean_duration()),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    // Winners and losers, matching each agent's journey between the runs.
+    let base_journeys = base.round_agent_journeys.last().into_iter().flatten()
+        .map(|agent_journey| ((agent_journey.sim_step_idx, agent_journey.journey_idx), agent_journey))
+        .collect::<HashMap<_, _>>();
+    let (mut num_winners, mut num_losers, mut num_unchanged, mut num_unmatched) = (0u64, 0u64, 0u64, 0u64);
+    for agent_journey in scenario.round_agent_journeys.last().into_iter().flatten() {
+        let count = agent_journey.count as u64;
+        let base_journey = base_journeys.get(&(agent_journey.sim_step_idx, agent_journey.journey_idx)).and_then(|base_journey| base_journey.result.as_ref().ok());
+        match (base_journey, &agent_journey.result) {
+            (Some(base_journey), Ok(journey)) => {
+                let cost_change = journey.crowding_cost as f64 - base_journey.crowding_cost as f64;
+                if cost_change < -COST_EPSILON {
+                    num_winners += count;
+                } else if cost_change > COST_EPSILON {
+                    num_losers += count;
+                } else {
+                    num_unchanged += count;
+                }
+            }
+            _ => num_unmatched += count,
+        }
+    }
 
-    let arrival_ids_arr = Arc::new(StringArray::from(arrival_ids.clone()));
-    let arrival_ids_field = Field::new("Arrival_ID", arrival_ids_arr.data_type().clone(), false);
+    let network_totals = |totals: &[StopJourneyTotals]| totals.iter().fold(StopJourneyTotals::default(), |mut total, stop_total| {
+        total.num_agents += stop_total.num_agents;
+        total.crowding_cost += stop_total.crowding_cost;
+        total.duration += stop_total.duration;
+        total
+    });
+    let base_total = network_totals(&base_totals);
+    let scenario_total = network_totals(&scenario_totals);
+    let max_load = |simulation_result: &SimulationResult| simulation_result.population_count.iter().copied().max().unwrap_or(0) as f64;
+
+    let mut csv_writer = csv::Writer::from_path(dir.join("summary.csv"))?;
+    csv_writer.write_record(&["metric", "base", "scenario", "change"])?;
+    for (metric, base_value, scenario_value) in [
+        ("agents_with_journey", base_total.num_agents as f64, scenario_total.num_agents as f64),
+        ("mean_crowding_cost", base_total.mean_crowding_cost(), scenario_total.mean_crowding_cost()),
+        ("mean_duration_seconds", base_total.mean_duration(), scenario_total.mean_duration()),
+        ("max_segment_load", max_load(base), max_load(scenario)),
+    ] {
+        csv_writer.write_record(&[metric.to_owned(), base_value.to_string(), scenario_value.to_string(), (scenario_value - base_value).to_string()])?;
+    }
+    for (metric, value) in [("winners", num_winners), ("losers", num_losers), ("unchanged", num_unchanged), ("unmatched", num_unmatched)] {
+        csv_writer.write_record(&[metric, "", &value.to_string(), ""])?;
+    }
+    csv_writer.flush()?;
 
-    let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts.clone()));
-    let agent_counts_field = Field::new("Agent_Count", agent_counts_arr.data_type().clone(), false);
+    if network.has_shapes {
+        export_shape_file(network, stop_geometry, ShapeColours::Route, &mut open_zip(&dir.join("shapes.bin.zip"))?)?;
+        export_delta_trips(network, stop_geometry, base, scenario, &mut open_zip(&dir.join("trips.bin.zip"))?)?;
+    }
 
-    let schema = Arc::new(Schema::new(vec![
-        trip_id_field,
-        trip_seated_field,
-        trip_standing_field,
-        timestamp_field,
-        departures_field,
-        departure_ids_field,
-        arrivals_field,
-        arrival_ids_field,
-        agent_counts_field
-    ]));
+    Ok(())
+}
 
-    let record_batch = arrow::record_batch::RecordBatch::try_new(schema, vec![
-        trip_id_arr,
-        trip_seated_arr,
-        trip_standing_arr,
-        timestamps_arr,
-        departures_arr,
-        departure_ids_arr,
-        arrivals_arr,
-        arrival_ids_arr,
-        agent_counts_arr
-    ])?;
+// Writes the timetabled and realised (after dwell delays) times of every stop time to <path>.csv.
+pub fn export_realised_stop_times(path: &Path, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    let realised_stop_times = simulation_result.realised_stop_times.as_ref().ok_or(DataExportError::MissingData("realised stop times"))?;
 
-    // Write to parquet.
-    {
-        let props = WriterProperties::builder()
-            .set_compression(Compression::SNAPPY)
-            .build();
-        let mut writer = ArrowWriter::try_new(File::create(&path)?, record_batch.schema(), Some(props))?;
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "stop_order", "stop_id", "stop_name", "timetabled_arrival", "timetabled_departure", "realised_arrival", "realised_departure", "delay_seconds"])?;
+    for route in network.routes.iter() {
+        let stops = route.get_stops(&network.route_stops);
+        for trip in 0..route.num_trips as usize {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            let trip_range = route.get_trip_range(trip);
+            for (stop_order, (&stop_idx, stop_time, &(realised_arrival, realised_departure))) in izip!(stops, &network.stop_times[trip_range.clone()], &realised_stop_times[trip_range]).enumerate() {
+                let stop = &network.stops[stop_idx as usize];
+                csv_writer.write_record(&[
+                    trip_id,
+                    &stop_order.to_string(),
+                    stop.id.as_ref(),
+                    stop.name.as_ref(),
+                    &get_time_str(stop_time.arrival_time),
+                    &get_time_str(stop_time.departure_time),
+                    &get_time_str(realised_arrival),
+                    &get_time_str(realised_departure),
+                    &realised_departure.saturating_sub(stop_time.departure_time).to_string(),
+                ])?;
+            }
+        }
+    }
+    csv_writer.flush()?;
 
-        writer.write(&record_batch)?;
+    Ok(())
+}
 
-        writer.close()?;
+// Writes the cancelled and truncated trips of a disruption to <path>.csv (so the visualiser can grey them out), with the
+// agents whose undisrupted journey used each one.
+pub fn export_disrupted_trips(path: &Path, disruption: &Disruption, report: &DisruptionReport) -> Result<(), DataExportError> {
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "status", "terminus_stop_id", "affected_agents"])?;
+    let cancelled = disruption.cancelled_trips.iter().map(|trip_id| (trip_id, "cancelled", ""));
+    let truncated = disruption.truncated_trips.iter().map(|(trip_id, stop_id)| (trip_id, "truncated", stop_id.as_str()));
+    for (trip_id, status, terminus_stop_id) in cancelled.chain(truncated).sorted_unstable() {
+        let affected_agents = report.agents_per_trip.get(trip_id).copied().unwrap_or(0);
+        csv_writer.write_record(&[trip_id.as_str(), status, terminus_stop_id, &affected_agents.to_string()])?;
     }
+    csv_writer.flush()?;
 
-    // Write to csv (for debugging).
-    {
-        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
-        csv_writer.write_record(&["trip_id", "timestamp", "departure", "departure_id", "arrival", "arrival_id", "count"])?;
-        let date_str = network.date.to_string();
-        for (trip_name, timestamp, departure, departure_id, arrival, arrival_id, count) in izip!(trip_ids, timestamps, departures, departure_ids, arrivals, arrival_ids, agent_counts) {
-            let timestamp = format!("{date_str} {}", &get_time_str((timestamp / 1000 - date_timestamp) as Timestamp));
-            csv_writer.write_record(&[trip_name, &timestamp, departure, departure_id, arrival, arrival_id, &count.to_string()])?;
-        }
+    Ok(())
+}
+
+// Writes the busiest segment of each supplementary trip to <path>.csv, so added services are easy to pick out.
+// Trips that aren't in the network (e.g. because they don't run on the date) are left out.
+pub fn export_supplementary_trips(path: &Path, network: &Network, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, trip_ids: &[String]) -> Result<(), DataExportError> {
+    let trip_idx_map: HashMap<&str, (usize, usize)> = network.routes.iter().enumerate().flat_map(|(route_idx, route)| {
+        (0..route.num_trips as usize).map(move |trip| {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            (trip_id, (route_idx, trip))
+        })
+    }).collect();
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["trip_id", "first_stop_id", "departure_time", "max_load", "max_load_factor"])?;
+    for trip_id in trip_ids.iter() {
+        let Some(&(route_idx, trip)) = trip_idx_map.get(trip_id.as_str()) else {
+            continue;
+        };
+        let route = &network.routes[route_idx];
+        let first_stop = route.get_stops(&network.route_stops)[0];
+        let max_load = simulation_result.population_count[route.get_trip_range(trip)].iter().copied().max().unwrap_or(0);
+        csv_writer.write_record(&[
+            trip_id.as_str(),
+            network.stops[first_stop as usize].id.as_ref(),
+            &get_time_str(network.get_departure_time(route_idx, trip, 0)),
+            &max_load.to_string(),
+            &format!("{:.3}", max_load as f32 / trip_capacities.get(trip_id).total() as f32),
+        ])?;
     }
+    csv_writer.flush()?;
 
     Ok(())
 }
 
-pub fn export_stops_csv(path: &Path, network: &Network) -> Result<(), DataExportError> {
-    // Write stops CSV.
-    let csv_path = path.with_extension("csv");
-    let mut csv_writer = csv::Writer::from_path(csv_path)?;
-    // Stop ID, Name, Latitude, Longitude.
-    csv_writer.write_record(&["id", "name", "latitude", "longitude"])?;
-    for (location, stop) in network.stops.iter().enumerate().map(|(i, stop)| (network.stop_points[i], stop)) {
-        csv_writer.write_record(&[&stop.id.to_string(), &stop.name.to_string(), &location.latitude.to_string(), &location.longitude.to_string()])?;
+// Writes the boardings denied by strict capacity to <path>.csv, and the delay of each affected agent to <path>_agents.csv.
+pub fn export_denied_boardings(path: &Path, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    let capacity_report = simulation_result.capacity_report.as_ref().ok_or(DataExportError::NoData)?;
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["agent_id", "stop_id", "stop_name", "trip_id", "time", "count"])?;
+    for denied in capacity_report.denied_boardings.iter() {
+        let stop = &network.stops[denied.stop as usize];
+        csv_writer.write_record(&[
+            denied.agent_idx.to_string().as_str(),
+            stop.id.as_ref(),
+            stop.name.as_ref(),
+            network.get_trip_id(denied.trip),
+            &get_time_str(denied.time),
+            &denied.count.to_string(),
+        ])?;
+    }
+
+    let agents_path = path.with_file_name(format!("{}_agents.csv", path.file_stem().unwrap_or_default().to_string_lossy()));
+    let mut csv_writer = csv::Writer::from_path(agents_path)?;
+    csv_writer.write_record(&["agent_id", "num_denied", "delay_seconds", "stranded"])?;
+    for delay in capacity_report.delays.iter() {
+        csv_writer.write_record(&[
+            delay.agent_idx.to_string(),
+            delay.num_denied.to_string(),
+            delay.delay.to_string(),
+            delay.stranded.to_string(),
+        ])?;
     }
 
     Ok(())
@@ -379,13 +2725,49 @@ fn timestamp_to_micro(sec: Timestamp) -> i64 {
     sec as i64 * 1_000_000
 }
 
-pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simulation_result: &SimulationResult, legs: bool) -> Result<(), DataExportError> {
-    let num_records = simulation_result.round_agent_journeys.iter().fold(0, |acc, journeys| acc + journeys.len());
-
+// Every round has a journey result for each agent, in the same order, so the rounds of an agent can be exported together.
+fn num_agents_per_round(simulation_result: &SimulationResult) -> Result<usize, DataExportError> {
     let num_agents = simulation_result.round_agent_journeys.first().ok_or(DataExportError::NoData)?.len();
-    for agent_journeys in simulation_result.round_agent_journeys.iter() {
-        assert_eq!(agent_journeys.len(), num_agents, "Agent journey count does not match agent count.");
+    match simulation_result.round_agent_journeys.iter().position(|agent_journeys| agent_journeys.len() != num_agents) {
+        Some(round) => Err(DataExportError::RoundMismatch(round, simulation_result.round_agent_journeys[round].len(), num_agents)),
+        None => Ok(num_agents),
+    }
+}
+
+// Writes every round's journeys (or their legs) to parquet. The columns of every journey would take several times the memory
+// of the simulation result, so they're built and written a batch of agents at a time. Batches are small enough that one takes
+// little memory next to the simulation result, and the parquet writer combines them into row groups.
+pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult, legs: bool) -> Result<(), DataExportError> {
+    const AGENT_BATCH_SIZE: usize = 1 << 14;
+
+    let num_agents = num_agents_per_round(simulation_result)?;
+
+    // Write to parquet.
+    let use_dictionary = true; // TODO: compare with false.
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        // Because this is a string column with only two possible values, dictionary encoding is useful.
+        .set_column_dictionary_enabled("Status".into(), use_dictionary)
+        .set_column_dictionary_enabled("Origin_Station".into(), use_dictionary)
+        .set_column_dictionary_enabled("Destination_Station".into(), use_dictionary)
+        .build();
+    // There's always a first batch (maybe empty), so the schema is written even without agents.
+    let first_batch = agent_journeys_batch(network, simulation_result, 0..num_agents.min(AGENT_BATCH_SIZE), legs)?;
+    let mut writer = ArrowWriter::try_new(writer, first_batch.schema(), Some(props))?;
+    writer.write(&first_batch)?;
+    drop(first_batch);
+    for batch_start in (AGENT_BATCH_SIZE..num_agents).step_by(AGENT_BATCH_SIZE) {
+        let record_batch = agent_journeys_batch(network, simulation_result, batch_start..(batch_start + AGENT_BATCH_SIZE).min(num_agents), legs)?;
+        writer.write(&record_batch)?;
     }
+    writer.close()?;
+
+    Ok(())
+}
+
+// The journeys parquet rows of a range of agents, with every round of each agent together.
+fn agent_journeys_batch(network: &Network, simulation_result: &SimulationResult, agents: std::ops::Range<usize>, legs: bool) -> Result<arrow::record_batch::RecordBatch, DataExportError> {
+    let num_records = agents.len() * simulation_result.round_agent_journeys.len();
 
     // Note: when legs = true, these are underestimated capacities.
     let mut agent_ids = Vec::with_capacity(num_records);
@@ -402,23 +2784,27 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
     let mut journey_end_times_ms = Vec::with_capacity(num_records);
     let mut leg_transfer_times_ms = Vec::with_capacity(num_records);
     let mut crowding_costs = Vec::with_capacity(num_records);
+    let mut experienced_crowding_costs = Vec::with_capacity(num_records);
+    let mut in_vehicle_times_ms = Vec::with_capacity(num_records);
+    let mut wait_times_ms = Vec::with_capacity(num_records);
     let mut num_transfers = Vec::with_capacity(num_records);
     let mut agent_counts = Vec::with_capacity(num_records);
+    let mut segments = Vec::with_capacity(num_records);
 
-    for i in 0..num_agents {
+    for i in agents {
         for round in 0..simulation_result.round_agent_journeys.len() {
-            let journey = &simulation_result.round_agent_journeys[round][i];
+            let journey = simulation_result.round_agent_journeys[round].get(i);
             match &journey.result {
                 Ok(result) => {
                     if legs {
-                        for leg in &result.legs {
+                        for leg in result.legs.iter() {
                             agent_ids.push(i as u32);
                             status.push("Ok");
                             round_number.push(round as u32);
 
-                            origins.push(network.stops[leg.boarded_stop as usize].name.as_ref());
+                            origins.push(stop_geometry.name(leg.boarded_stop));
                             origin_trip_ids.push(Some(network.get_trip_id(leg.trip)));
-                            destinations.push(network.stops[leg.arrival_stop as usize].name.as_ref());
+                            destinations.push(stop_geometry.name(leg.arrival_stop));
 
                             journey_times_ms.push(Some(timestamp_to_micro(leg.arrival_time - leg.boarded_time)));
                             journey_start_times_ms.push(timestamp_to_micro(leg.boarded_time));
@@ -426,16 +2812,17 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
                             leg_transfer_times_ms.push(leg.transfer_time.map(timestamp_to_micro));
 
                             agent_counts.push(journey.count as u32);
+                            segments.push(journey.segment as u32);
                         }
                     } else {
                         agent_ids.push(i as u32);
                         status.push("Ok");
                         round_number.push(round as u32);
 
-                        origins.push(network.stops[journey.origin_stop as usize].name.as_ref());
+                        origins.push(stop_geometry.name(journey.origin_stop));
                         origin_trip_ids.push(Some(network.get_trip_id(result.origin_trip)));
 
-                        destinations.push(network.stops[journey.dest_stop as usize].name.as_ref());
+                        destinations.push(stop_geometry.name(journey.dest_stop));
                         destination_trip_ids.push(Some(network.get_trip_id(result.dest_trip)));
 
                         journey_times_ms.push(Some(timestamp_to_micro(result.duration)));
@@ -443,9 +2830,14 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
                         journey_end_times_ms.push(Some(timestamp_to_micro(journey.start_time + result.duration)));
                         leg_transfer_times_ms.push(None);
 
-                        crowding_costs.push(Some(result.crowding_cost));
+                        // Written as f32 whatever the crowding cost precision, so the schema doesn't depend on the build.
+                        crowding_costs.push(Some(result.crowding_cost as f32));
+                        experienced_crowding_costs.push(Some(result.experienced_crowding_cost as f32));
+                        in_vehicle_times_ms.push(Some(timestamp_to_micro(result.in_vehicle_time)));
+                        wait_times_ms.push(Some(timestamp_to_micro(result.wait_time)));
                         num_transfers.push(Some(result.num_transfers as u32));
                         agent_counts.push(journey.count as u32);
+                        segments.push(journey.segment as u32);
                     }
                 }
                 Err(err) => {
@@ -457,10 +2849,10 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
                     agent_ids.push(i as u32);
 
                     round_number.push(round as u32);
-                    origins.push(network.stops[journey.origin_stop as usize].name.as_ref());
+                    origins.push(stop_geometry.name(journey.origin_stop));
                     origin_trip_ids.push(None);
 
-                    destinations.push(network.stops[journey.dest_stop as usize].name.as_ref());
+                    destinations.push(stop_geometry.name(journey.dest_stop));
                     destination_trip_ids.push(None);
 
                     journey_times_ms.push(None);
@@ -469,8 +2861,12 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
                     leg_transfer_times_ms.push(None);
 
                     crowding_costs.push(None);
+                    experienced_crowding_costs.push(None);
+                    in_vehicle_times_ms.push(None);
+                    wait_times_ms.push(None);
                     num_transfers.push(None);
                     agent_counts.push(journey.count as u32);
+                    segments.push(journey.segment as u32);
                 }
             }
         }
@@ -478,48 +2874,60 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
 
     // Set up arrow arrays.
 
-    let agent_ids_arr = Arc::new(UInt32Array::from(agent_ids.clone()));
+    let agent_ids_arr = Arc::new(UInt32Array::from(agent_ids));
     let agent_ids_field = Field::new("Agent_Id", agent_ids_arr.data_type().clone(), false);
 
-    let status_arr = Arc::new(StringArray::from(status.clone()));
+    let status_arr = Arc::new(StringArray::from(status));
     let status_field = Field::new("Status", status_arr.data_type().clone(), false);
 
-    let round_number_arr = Arc::new(UInt32Array::from(round_number.clone()));
+    let round_number_arr = Arc::new(UInt32Array::from(round_number));
     let round_number_field = Field::new("Round_Number", round_number_arr.data_type().clone(), false);
 
-    let origins_arr = Arc::new(StringArray::from(origins.clone()));
+    let origins_arr = Arc::new(StringArray::from(origins));
     let origins_field = Field::new("Origin_Station", origins_arr.data_type().clone(), false);
 
-    let origin_trips_arr = Arc::new(StringArray::from(origin_trip_ids.clone()));
+    let origin_trips_arr = Arc::new(StringArray::from(origin_trip_ids));
     let origin_trips_field = Field::new("Origin_Trip_ID", origin_trips_arr.data_type().clone(), true);
 
-    let destinations_arr = Arc::new(StringArray::from(destinations.clone()));
+    let destinations_arr = Arc::new(StringArray::from(destinations));
     let destination_field = Field::new("Destination_Station", destinations_arr.data_type().clone(), false);
 
-    let destination_trips_arr = Arc::new(StringArray::from(destination_trip_ids.clone()));
+    let destination_trips_arr = Arc::new(StringArray::from(destination_trip_ids));
     let destination_trips_field = Field::new("Destination_Trip_ID", destination_trips_arr.data_type().clone(), true);
 
-    let journey_durations_arr = Arc::new(Time64MicrosecondArray::from(journey_times_ms.clone()));
+    let journey_durations_arr = Arc::new(Time64MicrosecondArray::from(journey_times_ms));
     let journey_durations_field = Field::new("Journey_Duration", journey_durations_arr.data_type().clone(), true);
 
-    let journey_start_times_arr = Arc::new(Time64MicrosecondArray::from(journey_start_times_ms.clone()));
+    let journey_start_times_arr = Arc::new(Time64MicrosecondArray::from(journey_start_times_ms));
     let journey_start_times_field = Field::new("Journey_Start_Time", journey_start_times_arr.data_type().clone(), false);
 
-    let journey_end_times_arr = Arc::new(Time64MicrosecondArray::from(journey_end_times_ms.clone()));
+    let journey_end_times_arr = Arc::new(Time64MicrosecondArray::from(journey_end_times_ms));
     let journey_end_times_field = Field::new("Journey_End_Time", journey_start_times_arr.data_type().clone(), true);
 
-    let leg_transfer_times_arr = Arc::new(Time64MicrosecondArray::from(leg_transfer_times_ms.clone()));
+    let leg_transfer_times_arr = Arc::new(Time64MicrosecondArray::from(leg_transfer_times_ms));
     let leg_transfer_times_field = Field::new("Leg_Transfer_Time", leg_transfer_times_arr.data_type().clone(), true);
 
-    let crowding_costs_arr = Arc::new(Float32Array::from(crowding_costs.clone()));
+    let crowding_costs_arr = Arc::new(Float32Array::from(crowding_costs));
     let crowding_costs_field = Field::new("Crowding_Cost", crowding_costs_arr.data_type().clone(), true);
 
-    let num_transfers_arr = Arc::new(UInt32Array::from(num_transfers.clone()));
+    let experienced_crowding_costs_arr = Arc::new(Float32Array::from(experienced_crowding_costs));
+    let experienced_crowding_costs_field = Field::new("Experienced_Crowding_Cost", experienced_crowding_costs_arr.data_type().clone(), true);
+
+    let in_vehicle_times_arr = Arc::new(Time64MicrosecondArray::from(in_vehicle_times_ms));
+    let in_vehicle_times_field = Field::new("In_Vehicle_Time", in_vehicle_times_arr.data_type().clone(), true);
+
+    let wait_times_arr = Arc::new(Time64MicrosecondArray::from(wait_times_ms));
+    let wait_times_field = Field::new("Wait_Time", wait_times_arr.data_type().clone(), true);
+
+    let num_transfers_arr = Arc::new(UInt32Array::from(num_transfers));
     let num_transfers_field = Field::new("Num_Transfers", num_transfers_arr.data_type().clone(), true);
 
-    let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts.clone()));
+    let agent_counts_arr = Arc::new(UInt32Array::from(agent_counts));
     let agent_counts_field = Field::new("Agent_Count", agent_counts_arr.data_type().clone(), false);
 
+    let segments_arr = Arc::new(UInt32Array::from(segments));
+    let segments_field = Field::new("Segment", segments_arr.data_type().clone(), false);
+
     let schema = if legs {
         Arc::new(Schema::new(vec![
             agent_ids_field,
@@ -533,6 +2941,7 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
             journey_end_times_field,
             leg_transfer_times_field,
             agent_counts_field,
+            segments_field,
         ]))
     } else {
         Arc::new(Schema::new(vec![
@@ -547,8 +2956,12 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
             journey_start_times_field,
             journey_end_times_field,
             crowding_costs_field,
+            experienced_crowding_costs_field,
+            in_vehicle_times_field,
+            wait_times_field,
             num_transfers_field,
-            agent_counts_field
+            agent_counts_field,
+            segments_field,
         ]))
     };
 
@@ -565,6 +2978,7 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
             journey_end_times_arr,
             leg_transfer_times_arr,
             agent_counts_arr,
+            segments_arr,
         ]
     } else {
         vec![
@@ -579,40 +2993,22 @@ pub fn export_agent_journeys(writer: impl Write + Send, network: &Network, simul
             journey_start_times_arr,
             journey_end_times_arr,
             crowding_costs_arr,
+            experienced_crowding_costs_arr,
+            in_vehicle_times_arr,
+            wait_times_arr,
             num_transfers_arr,
-            agent_counts_arr
+            agent_counts_arr,
+            segments_arr,
         ]
     };
 
-    let record_batch = arrow::record_batch::RecordBatch::try_new(schema, arrays)?;
-
-    // Write to parquet.
-    {
-        let use_dictionary = true; // TODO: compare with false.
-        let props = WriterProperties::builder()
-            .set_compression(Compression::SNAPPY)
-            // Because this is a string column with only two possible values, dictionary encoding is useful.
-            .set_column_dictionary_enabled("Status".into(), use_dictionary)
-            .set_column_dictionary_enabled("Origin_Station".into(), use_dictionary)
-            .set_column_dictionary_enabled("Destination_Station".into(), use_dictionary)
-            .build();
-        let mut writer = ArrowWriter::try_new(writer, record_batch.schema(), Some(props))?;
-
-        writer.write(&record_batch)?;
-
-        writer.close()?;
-    }
-
-    Ok(())
+    Ok(arrow::record_batch::RecordBatch::try_new(schema, arrays)?)
 }
 
-pub fn export_agent_transfers(writer: impl Write + Send, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+pub fn export_agent_transfers(writer: impl Write + Send, network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
     let num_records = simulation_result.round_agent_journeys.iter().fold(0, |acc, journeys| acc + journeys.len());
 
-    let num_agents = simulation_result.round_agent_journeys.first().ok_or(DataExportError::NoData)?.len();
-    for agent_journeys in simulation_result.round_agent_journeys.iter() {
-        assert_eq!(agent_journeys.len(), num_agents, "Agent journey count does not match agent count.");
-    }
+    let num_agents = num_agents_per_round(simulation_result)?;
 
     let mut agent_ids = Vec::with_capacity(num_records);
     let mut status = Vec::with_capacity(num_records);
@@ -624,21 +3020,25 @@ pub fn export_agent_transfers(writer: impl Write + Send, network: &Network, simu
 
     let mut leg_transfer_times_us = Vec::with_capacity(num_records);
     let mut agent_counts = Vec::with_capacity(num_records);
+    let mut num_mismatched_transfers = 0;
 
     for i in 0..num_agents {
         for round in 0..simulation_result.round_agent_journeys.len() {
-            let journey = &simulation_result.round_agent_journeys[round][i];
+            let journey = simulation_result.round_agent_journeys[round].get(i);
             match &journey.result {
                 Ok(result) => {
                     for (incoming, outgoing) in result.legs.iter().tuple_windows() {
+                        // A transfer is recorded at one station, so one between differently named stops can't be.
+                        if stop_geometry.name(incoming.arrival_stop) != stop_geometry.name(outgoing.boarded_stop) {
+                            num_mismatched_transfers += 1;
+                            continue;
+                        }
                         agent_ids.push(i as u32);
                         status.push("Ok");
                         round_number.push(round as u32);
 
-                        assert_eq!(network.stops[incoming.arrival_stop as usize].name, network.stops[outgoing.boarded_stop as usize].name);
-
                         incoming_trip_ids.push(Some(network.get_trip_id(incoming.trip)));
-                        transfer_station.push(Some(network.stops[incoming.arrival_stop as usize].name.as_ref()));
+                        transfer_station.push(Some(stop_geometry.name(incoming.arrival_stop)));
                         outgoing_trip_ids.push(Some(network.get_trip_id(outgoing.trip)));
 
                         leg_transfer_times_us.push(incoming.transfer_time.map(timestamp_to_micro));
@@ -667,6 +3067,10 @@ pub fn export_agent_transfers(writer: impl Write + Send, network: &Network, simu
         }
     }
 
+    if num_mismatched_transfers > 0 {
+        log::warn!("Skipped {num_mismatched_transfers} transfers between stops with different names.");
+    }
+
     // Set up arrow arrays.
 
     let agent_ids_arr = Arc::new(UInt32Array::from(agent_ids.clone()));
@@ -734,3 +3138,785 @@ pub fn export_agent_transfers(writer: impl Write + Send, network: &Network, simu
 
     Ok(())
 }
+
+// Great circle distance between two network points in kilometres.
+fn point_distance_km(a: NetworkPoint, b: NetworkPoint) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.;
+    let (lat_a, lon_a) = ((a.latitude as f64).to_radians(), (a.longitude as f64).to_radians());
+    let (lat_b, lon_b) = ((b.latitude as f64).to_radians(), (b.longitude as f64).to_radians());
+    let h = ((lat_b - lat_a) / 2.).sin().powi(2) + lat_a.cos() * lat_b.cos() * ((lon_b - lon_a) / 2.).sin().powi(2);
+    2. * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+// Length of each segment of a trip in kilometres, by departure stop order. Uses the GTFS shape_dist_traveled
+// (times `shape_dist_km`, the kilometres per unit of the feed) when every stop time of the trip has one and they line
+// up with the network, and the straight-line `stop_segment_km` (from route_segment_km) otherwise.
+fn trip_segment_km(gtfs: Option<&Gtfs>, trip_id: &str, shape_dist_km: f64, stop_segment_km: &[f64]) -> Vec<f64> {
+    let shape_dists = gtfs.and_then(|gtfs| gtfs.trips.get(trip_id))
+                          .filter(|trip| trip.stop_times.len() == stop_segment_km.len() + 1)
+                          .and_then(|trip| trip.stop_times.iter().map(|stop_time| stop_time.shape_dist_traveled).collect::<Option<Vec<_>>>());
+    match shape_dists {
+        // Distances have to increase along the trip, otherwise they're unusable.
+        Some(shape_dists) if shape_dists.windows(2).all(|pair| pair[0] <= pair[1]) => {
+            shape_dists.windows(2).map(|pair| (pair[1] - pair[0]) as f64 * shape_dist_km).collect()
+        }
+        _ => stop_segment_km.to_vec(),
+    }
+}
+
+// Headline totals for the final loads.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TravelStats {
+    pub passenger_km: f64,
+    // Time agents spent on board.
+    pub passenger_hours: f64,
+    pub vehicle_km: f64,
+    pub vehicle_hours: f64,
+    // Load factor of every trip segment, weighted by the segment length.
+    pub mean_load_factor: f64,
+}
+
+impl TravelStats {
+    // Calculates the totals from the segment loads. Segment lengths come from trip_segment_km, so trips without
+    // shape distances (or without a GTFS) use straight lines between stops.
+    pub fn new(network: &Network, stop_geometry: &StopGeometry, gtfs: Option<&Gtfs>, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, shape_dist_km: f64) -> Self {
+        let mut stats = Self::default();
+        let mut load_factor_km = 0.;
+        for (route_idx, route) in network.routes.iter().enumerate() {
+            let stop_segment_km = route_segment_km(network, stop_geometry, route_idx);
+            for trip in 0..route.num_trips as usize {
+                let trip_id: &str = route.trip_ids[trip].as_ref();
+                let capacity = trip_capacities.get(trip_id).total() as f64;
+                let segment_km = trip_segment_km(gtfs, trip_id, shape_dist_km, &stop_segment_km);
+                let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+                for (dep_stop_order, (&count, &km)) in izip!(trip_counts, segment_km.iter()).enumerate() {
+                    let hours = network.get_arrival_time(route_idx, trip, dep_stop_order + 1).saturating_sub(network.get_departure_time(route_idx, trip, dep_stop_order)) as f64 / 3600.;
+                    stats.passenger_km += count as f64 * km;
+                    stats.passenger_hours += count as f64 * hours;
+                    stats.vehicle_km += km;
+                    stats.vehicle_hours += hours;
+                    load_factor_km += count as f64 / capacity * km;
+                }
+            }
+        }
+        if stats.vehicle_km > 0. {
+            stats.mean_load_factor = load_factor_km / stats.vehicle_km;
+        }
+        stats
+    }
+
+    pub fn log(&self) {
+        log::info!("{:.0} passenger-km, {:.0} passenger-hours on board, {:.0} vehicle-km, {:.0} vehicle-hours, mean load factor {:.3} (by distance).",
+                   self.passenger_km, self.passenger_hours, self.vehicle_km, self.vehicle_hours, self.mean_load_factor);
+    }
+
+    // Writes the totals to <path>.json.
+    pub fn export(&self, path: &Path) -> Result<(), DataExportError> {
+        let mut writer = BufWriter::new(File::create(path.with_extension("json"))?);
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"passenger_km\": {:.3},", self.passenger_km)?;
+        writeln!(writer, "  \"passenger_hours\": {:.3},", self.passenger_hours)?;
+        writeln!(writer, "  \"vehicle_km\": {:.3},", self.vehicle_km)?;
+        writeln!(writer, "  \"vehicle_hours\": {:.3},", self.vehicle_hours)?;
+        writeln!(writer, "  \"mean_load_factor\": {:.5}", self.mean_load_factor)?;
+        writeln!(writer, "}}")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+// Totals for one day of a multi-day run.
+#[derive(Clone, Debug)]
+pub struct DailySummary {
+    pub date: chrono::NaiveDate,
+    pub num_agents: u64,
+    // Distance travelled by agents, using straight lines between stops.
+    pub passenger_km: f64,
+    pub passenger_hours: f64,
+    // Highest load on any trip segment.
+    pub max_load: PopulationCount,
+    // Mean experienced crowding cost of the agents with a journey.
+    pub mean_crowding_cost: f64,
+}
+
+// Straight-line length of each segment of a route in kilometres, by departure stop order.
+fn route_segment_km(network: &Network, stop_geometry: &StopGeometry, route_idx: usize) -> Vec<f64> {
+    let num_stops = network.num_stops_in_route(route_idx);
+    (0..num_stops.saturating_sub(1)).map(|stop_order| {
+        let dep_point = stop_geometry.point(network.get_stop_in_route(route_idx, stop_order));
+        let arr_point = stop_geometry.point(network.get_stop_in_route(route_idx, stop_order + 1));
+        point_distance_km(dep_point, arr_point)
+    }).collect()
+}
+
+impl DailySummary {
+    pub fn new(date: chrono::NaiveDate, network: &Network, stop_geometry: &StopGeometry, simulation_result: &SimulationResult, num_agents: u64) -> Self {
+        let mut passenger_km = 0.;
+        for (route_idx, route) in network.routes.iter().enumerate() {
+            let segment_km = route_segment_km(network, stop_geometry, route_idx);
+            for trip in 0..route.num_trips as usize {
+                let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+                passenger_km += izip!(trip_counts, segment_km.iter()).map(|(&count, &km)| count as f64 * km).sum::<f64>();
+            }
+        }
+
+        let (num_with_journey, total_crowding_cost) = simulation_result.round_agent_journeys.last().map_or((0, 0.), |agent_journeys| {
+            agent_journeys.iter().filter_map(|agent_journey| agent_journey.result.ok().map(|journey| (agent_journey.count, journey))).fold((0u64, 0f64), |(num, cost), (count, journey)| {
+                (num + count as u64, cost + count as f64 * journey.experienced_crowding_cost as f64)
+            })
+        });
+
+        Self {
+            date,
+            num_agents,
+            passenger_km,
+            passenger_hours: simulation_result.iteration_history.last().map_or(0., |stats| stats.total_passenger_hours),
+            max_load: simulation_result.population_count.iter().copied().max().unwrap_or(0),
+            mean_crowding_cost: total_crowding_cost / num_with_journey.max(1) as f64,
+        }
+    }
+}
+
+// Writes one row per simulated day to <path>.csv.
+pub fn export_daily_summary(path: &Path, daily_summaries: &[DailySummary]) -> Result<(), DataExportError> {
+    if daily_summaries.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["date", "agents", "passenger_km", "passenger_hours", "max_load", "mean_crowding_cost"])?;
+    for summary in daily_summaries.iter() {
+        csv_writer.write_record(&[
+            summary.date.to_string(),
+            summary.num_agents.to_string(),
+            format!("{:.1}", summary.passenger_km),
+            format!("{:.1}", summary.passenger_hours),
+            summary.max_load.to_string(),
+            format!("{:.3}", summary.mean_crowding_cost),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::{run_simulation, JourneyTable};
+    use crate::test_utils::*;
+    use arrow::array::AsArray;
+    use arrow::datatypes::UInt32Type;
+    use gtfs_structures::Shape;
+
+    fn write_test_bin(chunks: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_bin(chunks, &mut bytes).unwrap();
+        bytes
+    }
+
+    // Updates the checksum after editing the header, so parse_bin gets past it to the check being tested.
+    fn update_checksum(bytes: &mut [u8]) {
+        let checksum = crc32fast::hash(&bytes[BIN_FIXED_HEADER_SIZE..]);
+        bytes[12..16].copy_from_slice(&checksum.to_le_bytes());
+    }
+
+    // Decodes a chunk of parse_bin, which isn't aligned for casting.
+    fn f32_chunk(chunk: &[u8]) -> Vec<f32> {
+        chunk.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect()
+    }
+
+    fn u32_chunk(chunk: &[u8]) -> Vec<u32> {
+        chunk.chunks_exact(4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())).collect()
+    }
+
+    fn f64_chunk(chunk: &[u8]) -> Vec<f64> {
+        chunk.chunks_exact(8).map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap())).collect()
+    }
+
+    fn invalid_bin_message(bytes: &[u8]) -> String {
+        match parse_bin(bytes) {
+            Err(DataExportError::InvalidBin(message)) => message,
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("expected an invalid binary export"),
+        }
+    }
+
+    #[test]
+    fn bin_round_trip() {
+        let chunks: [&[u8]; 3] = [b"abc", &[], &[1, 2, 3, 4, 5, 6, 7, 8, 9]];
+        let bytes = write_test_bin(&chunks);
+        assert_eq!(bytes.len() % 8, 0);
+        assert_eq!(&bytes[0..4], &BIN_MAGIC);
+        assert_eq!(u16::from_le_bytes([bytes[4], bytes[5]]), BIN_VERSION);
+        assert_eq!(parse_bin(&bytes).unwrap(), chunks.map(|chunk| chunk.to_vec()));
+    }
+
+    #[test]
+    fn bin_without_chunks_is_not_written() {
+        assert!(matches!(write_bin(&[], &mut Vec::new()), Err(DataExportError::NoData)));
+    }
+
+    #[test]
+    fn bin_with_bad_magic_is_rejected() {
+        let mut bytes = write_test_bin(&[b"abc"]);
+        bytes[0] = b'X';
+        assert!(invalid_bin_message(&bytes).contains("magic"));
+        assert!(invalid_bin_message(&bytes[..8]).contains("magic"));
+    }
+
+    #[test]
+    fn bin_with_bad_version_is_rejected() {
+        for version in [0, BIN_VERSION + 1] {
+            let mut bytes = write_test_bin(&[b"abc"]);
+            bytes[4..6].copy_from_slice(&version.to_le_bytes());
+            assert!(invalid_bin_message(&bytes).contains(&format!("version {version}")));
+        }
+        // Older versions are still read.
+        let mut bytes = write_test_bin(&[b"abc"]);
+        bytes[4..6].copy_from_slice(&BIN_MIN_VERSION.to_le_bytes());
+        assert!(parse_bin(&bytes).is_ok());
+    }
+
+    #[test]
+    fn truncated_bin_is_rejected() {
+        let bytes = write_test_bin(&[b"abc", b"defghijk"]);
+        assert!(invalid_bin_message(&bytes[..bytes.len() - 8]).contains("checksum"));
+
+        // With the checksum updated, the missing chunk is found out of bounds.
+        let mut truncated = bytes[..bytes.len() - 8].to_vec();
+        update_checksum(&mut truncated);
+        assert!(invalid_bin_message(&truncated).contains("out of bounds"));
+    }
+
+    #[test]
+    fn misaligned_bin_chunk_is_rejected() {
+        let mut bytes = write_test_bin(&[b"abc", b"defghijk"]);
+        // The second chunk's offset.
+        let offset_idx = BIN_FIXED_HEADER_SIZE + 2 * size_of::<u32>();
+        let offset = u32::from_le_bytes(bytes[offset_idx..offset_idx + 4].try_into().unwrap());
+        bytes[offset_idx..offset_idx + 4].copy_from_slice(&(offset + 4).to_le_bytes());
+        update_checksum(&mut bytes);
+        assert!(invalid_bin_message(&bytes).contains("not 8-byte aligned"));
+
+        // A chunk can't start inside the previous one either.
+        bytes[offset_idx..offset_idx + 4].copy_from_slice(&(offset - 8).to_le_bytes());
+        update_checksum(&mut bytes);
+        assert!(invalid_bin_message(&bytes).contains("overlaps"));
+    }
+
+    #[test]
+    fn bin_checksum_mismatch_is_rejected() {
+        let mut bytes = write_test_bin(&[b"abc", b"defghijk"]);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(invalid_bin_message(&bytes).contains("checksum"));
+    }
+
+    #[test]
+    fn bin_writer_checks_chunk_lengths() {
+        let mut sums = BinSums::new(2);
+        sums.add(0, b"abc");
+        sums.add(1, b"de");
+        let mut bytes = Vec::new();
+        let mut bin_writer = BinWriter::new(&mut bytes, &sums).unwrap();
+        bin_writer.write(b"ab").unwrap();
+        assert!(matches!(bin_writer.finish_chunk(), Err(DataExportError::ChunkMismatch(0, 3, 2))));
+        assert!(matches!(bin_writer.write(b"cd"), Err(DataExportError::ChunkMismatch(0, 3, 4))));
+    }
+
+    #[test]
+    fn stop_activity_counts_transfers_separately() {
+        const T8: Timestamp = 8 * 3600;
+        // Four agents from stop 0 to 2, changing at stop 1, and two from stop 1 to 2.
+        let simulation_result = synthetic_result(&[
+            (4, &[((0, T8), (1, T8 + 600)), ((1, T8 + 960), (2, T8 + 1800))]),
+            (2, &[((1, T8 + 1200), (2, T8 + 2400))]),
+            (5, &[]),
+        ]);
+        let activity = aggregate_stop_activity(&simulation_result, 900);
+
+        let bin = |time: Timestamp| time / 900;
+        let get = |stop: StopIndex, time: Timestamp| {
+            let stop_activity = activity[&(stop, bin(time))];
+            (stop_activity.boardings, stop_activity.alightings, stop_activity.transfers_in, stop_activity.transfers_out)
+        };
+        assert_eq!(activity.len(), 4);
+        assert_eq!(get(0, T8), (4, 0, 0, 0));
+        assert_eq!(get(1, T8 + 600), (0, 0, 4, 0));
+        assert_eq!(get(1, T8 + 960), (2, 0, 0, 4));
+        assert_eq!(get(2, T8 + 1800), (0, 6, 0, 0));
+        // Both of stop 2's arrivals are in the 08:30 bin.
+        assert_eq!(bin(T8 + 1800), bin(T8 + 2400));
+    }
+
+    #[test]
+    fn convergence_export_has_a_row_per_round() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_result = transferring_result(&network, 3);
+        let path = temp_path("convergence");
+        export_convergence(&path, &simulation_result).unwrap();
+        let mut csv_reader = csv::Reader::from_path(path.with_extension("csv")).unwrap();
+        let rounds = csv_reader.records().map(|record| record.unwrap()[0].to_owned()).collect_vec();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+        assert_eq!(rounds, ["0", "1", "2"]);
+    }
+
+    // Three agents from Alpha to Delta, who change from the Red or Green line to the Blue line at Charlie.
+    fn transferring_result(network: &Network, num_rounds: u16) -> SimulationResult {
+        let simulation_steps = vec![simulation_step(network, 7 * 3600 + 55 * 60, "ALP", "DEL", 3)];
+        run_simulation(network, &simulation_steps, &fixture_params(num_rounds))
+    }
+
+    #[test]
+    fn multi_leg_journey_transfers_are_exported() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_result = transferring_result(&network, 1);
+        let path = temp_path("transfers.parquet");
+        export_agent_transfers(File::create(&path).unwrap(), &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result).unwrap();
+        let transfers = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(transfers.num_rows(), 1);
+        let column = |name| transfers.column_by_name(name).unwrap();
+        assert_eq!(column("Status").as_string::<i32>().value(0), "Ok");
+        assert_eq!(column("Transfer_Station").as_string::<i32>().value(0), "Charlie");
+        assert!(["RED_0800", "GREEN_0800"].contains(&column("Incoming_Trip_ID").as_string::<i32>().value(0)));
+        assert_eq!(column("Outgoing_Trip_ID").as_string::<i32>().value(0), "BLUE_0815");
+        assert_eq!(column("Agent_Count").as_primitive::<UInt32Type>().value(0), 3);
+    }
+
+    #[test]
+    fn transfers_at_a_stop_without_coordinates_are_exported() {
+        let gtfs = load_fixture_gtfs("no_coordinates");
+        let network = build_fixture_network(&gtfs);
+        let simulation_result = transferring_result(&network, 1);
+        let path = temp_path("transfers_no_coordinates.parquet");
+        export_agent_transfers(File::create(&path).unwrap(), &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result).unwrap();
+        let transfers = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(transfers.num_rows(), 1);
+        assert_eq!(transfers.column_by_name("Transfer_Station").unwrap().as_string::<i32>().value(0), "Charlie");
+    }
+
+    #[test]
+    fn multi_leg_journey_legs_are_exported() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_result = transferring_result(&network, 1);
+        let path = temp_path("legs.parquet");
+        export_agent_journeys(File::create(&path).unwrap(), &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result, true).unwrap();
+        let legs = read_parquet(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(legs.num_rows(), 2);
+        let origins = legs.column_by_name("Origin_Station").unwrap().as_string::<i32>();
+        let destinations = legs.column_by_name("Destination_Station").unwrap().as_string::<i32>();
+        assert_eq!((origins.value(0), destinations.value(0)), ("Alpha", "Charlie"));
+        assert_eq!((origins.value(1), destinations.value(1)), ("Charlie", "Delta"));
+    }
+
+    #[test]
+    fn mismatched_rounds_are_an_error() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut simulation_result = transferring_result(&network, 2);
+        simulation_result.round_agent_journeys[1] = JourneyTable::default();
+
+        let result = export_agent_transfers(std::io::sink(), &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result);
+        assert!(matches!(result, Err(DataExportError::RoundMismatch(1, 0, 1))), "{result:?}");
+        let result = export_agent_journeys(std::io::sink(), &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result, false);
+        assert!(matches!(result, Err(DataExportError::RoundMismatch(1, 0, 1))), "{result:?}");
+    }
+
+    #[test]
+    fn parallel_trips_export_matches_serial() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let mut network = build_fixture_network(&gtfs);
+        add_stop_shapes(&mut network);
+        let params = fixture_params(2);
+        let simulation_result = run_simulation(&network, &morning_peak_steps(&network, 200, 5), &params);
+        let segment_costs = segment_crowding_costs(&network, &params, &simulation_result.population_count);
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+
+        let export_trips = |num_threads| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+            let mut bytes = Vec::new();
+            pool.install(|| export_network_trips(&network, &stop_geometry, &simulation_result, &segment_costs, &mut bytes)).unwrap();
+            bytes
+        };
+        let serial = export_trips(1);
+        let chunks = parse_bin(&serial).unwrap();
+        assert!(!chunks[0].is_empty(), "no trips were drawn");
+        assert_eq!(serial, export_trips(4));
+        assert_eq!(serial, export_trips(16));
+    }
+
+    #[test]
+    fn stops_export_round_trip() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_result = transferring_result(&network, 1);
+        let mut bytes = Vec::new();
+        export_stops(&network, &StopGeometry::new(&network, Some(&gtfs)), Some(&simulation_result), None, &mut bytes).unwrap();
+        let chunks = parse_bin(&bytes).unwrap();
+        assert_eq!(chunks.len(), 6);
+
+        let num_stops = network.num_stops();
+        let positions = f32_chunk(&chunks[0]);
+        assert_eq!(positions.len(), 3 * num_stops);
+        for (stop_position, point) in positions.chunks(3).zip(&network.stop_points) {
+            assert_eq!(stop_position, [point.longitude, point.latitude, 0.]);
+        }
+
+        let boardings = f32_chunk(&chunks[1]);
+        let expected_boardings = network.stops.iter().map(|stop| {
+            let id: &str = stop.id.as_ref();
+            // The three agents board at Alpha and again at Charlie after changing to the Blue line.
+            if id == "ALP" || id == "CHA" { 3. } else { 0. }
+        }).collect_vec();
+        assert_eq!(boardings, expected_boardings);
+
+        let name_offsets = u32_chunk(&chunks[3]);
+        assert_eq!(name_offsets.len(), num_stops + 1);
+        let names = name_offsets.windows(2).map(|w| std::str::from_utf8(&chunks[2][w[0] as usize..w[1] as usize]).unwrap()).collect_vec();
+        assert_eq!(names, network.stops.iter().map(|stop| -> &str { stop.name.as_ref() }).collect_vec());
+        // No stops were collapsed, so none have children.
+        assert!(chunks[4].is_empty());
+        assert_eq!(u32_chunk(&chunks[5]), vec![0; num_stops + 1]);
+
+        let mut bytes = Vec::new();
+        export_stops(&network, &StopGeometry::new(&network, Some(&gtfs)), None, None, &mut bytes).unwrap();
+        let chunks = parse_bin(&bytes).unwrap();
+        assert!(f32_chunk(&chunks[1]).iter().all(|&boardings| boardings == 0.));
+    }
+
+    #[test]
+    fn max_load_points_pick_the_earliest_highest_load() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut simulation_result = synthetic_result(&[]);
+        simulation_result.population_count = vec![0; network.stop_times.len()];
+        // The loads departing Alpha, Bravo and Charlie (where the trip ends).
+        for (trip_id, loads) in [("RED_0800", [10, 25, 0]), ("RED_0815", [15, 15, 0])] {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            simulation_result.population_count[network.routes[route_idx].get_trip_range(trip)].copy_from_slice(&loads);
+        }
+        let trip_capacities = TripCapacities::new(FIXTURE_CAPACITY, HashMap::new());
+        let periods = ReportingPeriods::new(&[ReportingPeriod { name: "am_peak".to_owned(), window: "08:00:00-08:10:00".parse().unwrap() }]).unwrap();
+        let max_load_points = max_load_points(&network, &simulation_result, &trip_capacities, &periods);
+
+        let point = |trip_id: &str, period: usize| {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            max_load_points.iter().find(|point| (point.route_idx, point.trip, point.period) == (route_idx, trip, period)).copied()
+        };
+        let red_0800 = point("RED_0800", ReportingPeriods::ALL_DAY).unwrap();
+        assert_eq!((red_0800.max_load, red_0800.stop_order, red_0800.seated_exceeded), (25, 1, true));
+        assert!((red_0800.load_factor - 25. / 30.).abs() < 1e-6);
+        assert_eq!(point("RED_0800", 1).unwrap().stop_order, 1);
+        // RED_0800 runs entirely in the AM peak.
+        assert!(point("RED_0800", 2).is_none());
+        let red_0815 = point("RED_0815", ReportingPeriods::ALL_DAY).unwrap();
+        assert_eq!((red_0815.max_load, red_0815.stop_order, red_0815.seated_exceeded), (15, 0, false));
+        // RED_0815 runs entirely after the AM peak.
+        assert!(point("RED_0815", 1).is_none());
+        assert_eq!(point("RED_0815", 2).unwrap().stop_order, 0);
+
+        let most_crowded = most_crowded_trips(&max_load_points, 2);
+        assert_eq!(most_crowded.iter().map(|point| (point.route_idx, point.trip)).collect_vec(), [trip_position(&network, "RED_0800"), trip_position(&network, "RED_0815")]);
+
+        let path = temp_path("max_load_points");
+        export_max_load_points(&path, &network, &gtfs, &max_load_points, &periods).unwrap();
+        let mut csv_reader = csv::Reader::from_path(path.with_extension("csv")).unwrap();
+        let rows = csv_reader.records().map(|record| record.unwrap()).collect_vec();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+        let row = rows.iter().find(|row| &row[0] == "RED_0800" && &row[2] == "all_day").unwrap();
+        assert_eq!(row.iter().skip(3).collect_vec(), [get_time_str(8 * 3600).as_str(), "25", "BRA", "CHA", "0.833", "true"]);
+        assert_eq!(&row[1], "RED");
+    }
+
+    #[test]
+    fn travel_stats_on_a_two_stop_network() {
+        let gtfs = load_fixture_gtfs("two_stops");
+        let network = build_fixture_network(&gtfs);
+        let mut simulation_result = synthetic_result(&[]);
+        simulation_result.population_count = vec![0; network.stop_times.len()];
+        for (trip_id, load) in [("SHT_0800", 20), ("SHT_0900", 10)] {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            simulation_result.population_count[network.routes[route_idx].get_trip_range(trip).start] = load;
+        }
+        let trip_capacities = TripCapacities::new(FIXTURE_CAPACITY, HashMap::new());
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        let stats = TravelStats::new(&network, &stop_geometry, Some(&gtfs), &simulation_result, &trip_capacities, 1.);
+
+        // SHT_0800 is 15 km by its shape distances, and SHT_0900 is the length of 0.09 degrees of the equator.
+        let straight_km = 6371. * 0.09f64.to_radians();
+        assert!((stats.passenger_km - (20. * 15. + 10. * straight_km)).abs() < 0.01, "{stats:?}");
+        assert!((stats.passenger_hours - (20. * 0.5 + 10. * 0.25)).abs() < 1e-9, "{stats:?}");
+        assert!((stats.vehicle_km - (15. + straight_km)).abs() < 0.01, "{stats:?}");
+        assert!((stats.vehicle_hours - 0.75).abs() < 1e-9, "{stats:?}");
+        let mean_load_factor = (20. / 30. * 15. + 10. / 30. * straight_km) / (15. + straight_km);
+        assert!((stats.mean_load_factor - mean_load_factor).abs() < 1e-4, "{stats:?}");
+
+        // Without the GTFS both trips are straight lines.
+        let stats = TravelStats::new(&network, &stop_geometry, None, &simulation_result, &trip_capacities, 1.);
+        assert!((stats.vehicle_km - 2. * straight_km).abs() < 0.01, "{stats:?}");
+
+        let path = temp_path("stats");
+        stats.export(&path).unwrap();
+        let json = std::fs::read_to_string(path.with_extension("json")).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+        assert!(json.contains("\"passenger_hours\": 12.500,"), "{json}");
+    }
+
+    #[test]
+    fn trip_times_round_trip_exactly() {
+        let gtfs = load_fixture_gtfs("two_stops");
+        let mut network = build_fixture_network(&gtfs);
+        add_stop_shapes(&mut network);
+        // The last second of the day, arriving at the last second of the next (a time only a next-day trip has).
+        let (route_idx, trip_idx) = trip_position(&network, "SHT_0800");
+        let trip_range = network.routes[route_idx].get_trip_range(trip_idx);
+        network.stop_times[trip_range.start].departure_time = 86399;
+        network.stop_times[trip_range.end - 1].arrival_time = 172799;
+
+        let values = vec![0.; network.stop_times.len()];
+        let trip = trip_geometry(&network, route_idx, trip_idx, &values, None, &|_| true);
+        assert_eq!(trip.times.first(), Some(&86399.));
+        assert_eq!(trip.times.last(), Some(&172799.));
+
+        let mut bytes = Vec::new();
+        write_trips_bin(&network, &values, None, |idx| trip_range.contains(&idx), &mut bytes).unwrap();
+        let chunks = parse_bin(&bytes).unwrap();
+        assert_eq!(u32_chunk(&chunks[1]), [0]);
+        assert_eq!(f64_chunk(&chunks[4]), trip.times);
+        // The old f32 chunk is still filled for older readers, and whole seconds of two days fit in an f32.
+        let f32_times = f32_chunk(&chunks[2]);
+        assert_eq!(f32_times.len(), trip.times.len());
+        assert_eq!((f32_times[0], f32_times[f32_times.len() - 1]), (86399., 172799.));
+    }
+
+    #[test]
+    fn colourless_routes_get_their_own_colour_and_height() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let mut network = build_fixture_network(&gtfs);
+        // Red is given its colour, and stays that colour after being made an explicit black.
+        let (red_route, _) = trip_position(&network, "RED_0800");
+        network.routes[red_route].colour = RGB8 { r: 0, g: 0, b: 0 };
+        let uncoloured_route_ids = crate::data_import::gtfs_routes_without_colour(&[fixture_path("two_lines")]);
+        assert_eq!(uncoloured_route_ids, HashSet::from(["BLUE".to_string(), "GREEN".to_string()]));
+        assign_route_styles(&mut network, &gtfs, &uncoloured_route_ids, &ShapeColouring::default());
+
+        let style = |trip_id| {
+            let route = &network.routes[trip_position(&network, trip_id).0];
+            (route.colour, route.shape_height)
+        };
+        let (blue_colour, blue_height) = style("BLUE_0815");
+        let (green_colour, green_height) = style("GREEN_0800");
+        let (red_colour, red_height) = style("RED_0800");
+        assert_ne!(blue_colour, green_colour);
+        assert_ne!(blue_height, green_height);
+        assert!(ROUTE_PALETTE.contains(&blue_colour) && ROUTE_PALETTE.contains(&green_colour));
+        assert_eq!(red_colour, RGB8 { r: 0, g: 0, b: 0 });
+        assert!(red_height != blue_height && red_height != green_height);
+    }
+
+    // two_lines with a shape along the Red line, an orphan shape that no trip uses, and a weekend-only trip on a shape of its own.
+    fn gtfs_with_unused_shapes() -> Gtfs {
+        let mut gtfs = load_fixture_gtfs("two_lines");
+        let shape = |shape_id: &str, stop_ids: &[&str]| stop_ids.iter().enumerate().map(|(sequence, &stop_id)| {
+            let stop = &gtfs.stops[stop_id];
+            Shape { id: shape_id.to_owned(), latitude: stop.latitude.unwrap(), longitude: stop.longitude.unwrap(), sequence, dist_traveled: None }
+        }).collect_vec();
+        let red_shape = shape("RED_SHAPE", &["ALP", "BRA", "CHA"]);
+        let orphan_shape = shape("RETIRED", &["ALP", "ECH"]);
+        let weekend_shape = shape("WEEKEND_SHAPE", &["ALP", "BRA", "CHA"]);
+        gtfs.shapes.insert("RED_SHAPE".to_owned(), red_shape);
+        gtfs.shapes.insert("RETIRED".to_owned(), orphan_shape);
+        gtfs.shapes.insert("WEEKEND_SHAPE".to_owned(), weekend_shape);
+        for trip in gtfs.trips.values_mut().filter(|trip| trip.route_id == "RED") {
+            trip.shape_id = Some("RED_SHAPE".to_owned());
+        }
+
+        let mut weekend = gtfs.calendar["WD"].clone();
+        weekend.id = "WE".to_owned();
+        (weekend.monday, weekend.tuesday, weekend.wednesday, weekend.thursday, weekend.friday, weekend.saturday, weekend.sunday) = (false, false, false, false, false, true, true);
+        gtfs.calendar.insert(weekend.id.clone(), weekend);
+        let mut weekend_trip = gtfs.trips["RED_0800"].clone();
+        weekend_trip.id = "RED_SAT_0800".to_owned();
+        weekend_trip.service_id = "WE".to_owned();
+        weekend_trip.shape_id = Some("WEEKEND_SHAPE".to_owned());
+        gtfs.trips.insert(weekend_trip.id.clone(), weekend_trip);
+        gtfs
+    }
+
+    #[test]
+    fn shapes_no_trip_on_the_date_uses_are_excluded() {
+        let mut gtfs = gtfs_with_unused_shapes();
+        let network = build_fixture_network(&gtfs);
+        // The weekend trip doesn't run on the (Monday) fixture date, so its shape is excluded along with the orphan.
+        assert!(network.routes.iter().flat_map(|route| route.trip_ids.iter()).all(|trip_id| {
+            let trip_id: &str = trip_id.as_ref();
+            trip_id != "RED_SAT_0800"
+        }));
+        assert_eq!(count_unused_shapes(&gtfs, &network), 2);
+        let mut bytes = Vec::new();
+        export_shape_file(&network, &StopGeometry::new(&network, Some(&gtfs)), ShapeColours::Route, &mut bytes).unwrap();
+        let chunks = parse_bin(&bytes).unwrap();
+        // Only the Red line is drawn.
+        assert_eq!(u32_chunk(&chunks[1]), [0]);
+        assert!(!f32_chunk(&chunks[0]).is_empty());
+
+        // Once no trip has a shape, none are used and there's nothing to draw.
+        gtfs.trips.values_mut().for_each(|trip| trip.shape_id = None);
+        let network = build_fixture_network(&gtfs);
+        assert_eq!(count_unused_shapes(&gtfs, &network), 3);
+        let result = export_shape_file(&network, &StopGeometry::new(&network, Some(&gtfs)), ShapeColours::Route, &mut Vec::new());
+        assert!(matches!(result, Err(DataExportError::MissingData(_))), "{result:?}");
+    }
+
+    #[cfg(feature = "gtfs_rt")]
+    #[test]
+    fn occupancy_feed_round_trips() {
+        use prost::Message;
+
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut simulation_result = synthetic_result(&[]);
+        simulation_result.population_count = vec![0; network.stop_times.len()];
+        // The loads departing Alpha, Bravo and Charlie (where the trip ends), of the fixture's capacity of 30.
+        for (trip_id, loads) in [("RED_0800", [0, 25, 0]), ("RED_0815", [15, 40, 0])] {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            simulation_result.population_count[network.routes[route_idx].get_trip_range(trip)].copy_from_slice(&loads);
+        }
+        let trip_capacities = TripCapacities::new(FIXTURE_CAPACITY, HashMap::new());
+        let thresholds = OccupancyThresholds::default();
+
+        let path = temp_path("occupancy_feed");
+        export_occupancy_feed(&path, &network, &gtfs, &simulation_result, &trip_capacities, &thresholds).unwrap();
+        let bytes = std::fs::read(path.with_extension("pb")).unwrap();
+        std::fs::remove_file(path.with_extension("pb")).unwrap();
+        let feed = gtfs_rt::FeedMessage::decode(&bytes[..]).unwrap();
+
+        assert_eq!(feed.header.gtfs_realtime_version, "2.0");
+        assert_eq!(feed.entity.len(), gtfs.trips.len());
+        for entity in feed.entity.iter() {
+            let (route_idx, trip) = trip_position(&network, &entity.id);
+            let trip_update = entity.trip_update.as_ref().unwrap();
+            assert_eq!(trip_update.trip.trip_id.as_deref(), Some(entity.id.as_str()));
+            assert_eq!(trip_update.trip.start_date.as_deref(), Some("20240603"));
+
+            // Departing every stop but the last.
+            let loads = &simulation_result.population_count[network.routes[route_idx].get_trip_range(trip)];
+            let statuses = loads[..loads.len() - 1].iter().map(|&load| thresholds.status(load as f32 / FIXTURE_CAPACITY.total() as f32) as i32).collect_vec();
+            assert_eq!(trip_update.stop_time_update.iter().map(|update| update.departure_occupancy_status.unwrap()).collect_vec(), statuses, "{}", entity.id);
+            let stop_sequences = gtfs.trips[&entity.id].stop_times.iter().map(|stop_time| stop_time.stop_sequence as u32).take(statuses.len()).collect_vec();
+            assert_eq!(trip_update.stop_time_update.iter().map(|update| update.stop_sequence.unwrap()).collect_vec(), stop_sequences);
+
+            let vehicle = entity.vehicle.as_ref().unwrap();
+            assert_eq!(vehicle.trip.as_ref().and_then(|trip| trip.trip_id.as_deref()), Some(entity.id.as_str()));
+            assert_eq!(vehicle.occupancy_status, statuses.iter().copied().max());
+        }
+
+        let statuses = |trip_id: &str| {
+            let entity = feed.entity.iter().find(|entity| entity.id == trip_id).unwrap();
+            let stop_statuses = entity.trip_update.as_ref().unwrap().stop_time_update.iter().map(|update| update.departure_occupancy_status.unwrap()).collect_vec();
+            let vehicle = entity.vehicle.as_ref().unwrap();
+            (stop_statuses, vehicle.occupancy_status.unwrap(), vehicle.occupancy_percentage.unwrap())
+        };
+        assert_eq!(statuses("RED_0800"), (vec![OccupancyStatus::Empty as i32, OccupancyStatus::StandingRoomOnly as i32], OccupancyStatus::StandingRoomOnly as i32, 83));
+        assert_eq!(statuses("RED_0815"), (vec![OccupancyStatus::FewSeatsAvailable as i32, OccupancyStatus::Full as i32], OccupancyStatus::Full as i32, 133));
+        assert_eq!(statuses("BLUE_0815"), (vec![OccupancyStatus::Empty as i32; 2], OccupancyStatus::Empty as i32, 0));
+    }
+
+    #[test]
+    fn route_summary_has_the_max_load_of_each_route_and_direction() {
+        let gtfs = load_fixture_gtfs("both_directions");
+        let network = build_fixture_network(&gtfs);
+        let mut simulation_result = synthetic_result(&[]);
+        simulation_result.population_count = vec![0; network.stop_times.len()];
+        // The loads departing each stop of the local trips, and nobody on the express.
+        for (trip_id, loads) in [("LOC_OUT_0800", [10, 25, 0]), ("LOC_IN_0830", [12, 5, 0])] {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            simulation_result.population_count[network.routes[route_idx].get_trip_range(trip)].copy_from_slice(&loads);
+        }
+        let periods = ReportingPeriods::new(&[]).unwrap();
+
+        let path = temp_path("route_summary");
+        export_route_summary(&path, &network, &StopGeometry::new(&network, Some(&gtfs)), &gtfs, &simulation_result, &fixture_params(1), &periods).unwrap();
+        let mut csv_reader = csv::Reader::from_path(path.with_extension("csv")).unwrap();
+        let rows = csv_reader.records().map(|record| record.unwrap()).collect_vec();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+
+        let all_day_rows = rows.iter().filter(|row| &row[3] == "all_day").collect_vec();
+        assert_eq!(all_day_rows.iter().map(|row| (&row[0], &row[2])).collect_vec(), [("EXP", "0"), ("EXP", "1"), ("LOC", "0"), ("LOC", "1")]);
+        // The trips, then the max load segment and the peak load factor.
+        let summary = |row: &csv::StringRecord| (row[4].to_owned(), [&row[7], &row[8], &row[9], &row[10]].map(str::to_owned));
+        assert_eq!(summary(all_day_rows[2]), ("1".to_owned(), ["25", "MID", "STH", "0.833"].map(str::to_owned)));
+        assert_eq!(summary(all_day_rows[3]), ("1".to_owned(), ["12", "STH", "MID", "0.400"].map(str::to_owned)));
+        assert!(all_day_rows[2][6].parse::<f64>().unwrap() > 0.);
+
+        // The express had no passengers in either direction, so its rows are all zeros.
+        for row in &all_day_rows[..2] {
+            assert_eq!(row.iter().skip(4).collect_vec(), ["1", "0", "0.0", "0", "", "", "0.000", "0.000"]);
+        }
+    }
+
+    #[test]
+    fn frequency_trips_are_exported_by_their_synthetic_ids() {
+        let mut gtfs = load_fixture_gtfs("frequencies");
+        let expansion = crate::data_import::expand_frequencies(&mut gtfs);
+        assert_eq!((expansion.num_frequency_trips, expansion.num_generated_trips), (2, 5));
+        assert_eq!(expansion.expansion_factor(), 2.5);
+        let network = build_fixture_network(&gtfs);
+
+        // Four agents at West at 08:05 catch the exact-times trip departing at 08:10.
+        let simulation_result = run_simulation(&network, &[simulation_step(&network, 8 * 3600 + 5 * 60, "WST", "EST", 4)], &fixture_params(1));
+        let trip_capacities = TripCapacities::new(FIXTURE_CAPACITY, HashMap::new());
+        let segment_costs = segment_crowding_costs(&network, &fixture_params(1), &simulation_result.population_count);
+
+        let path = temp_path("frequency_counts");
+        export_agent_counts(&path, &network, &StopGeometry::new(&network, Some(&gtfs)), &simulation_result, &trip_capacities, &segment_costs).unwrap();
+        let batch = read_parquet(&path.with_extension("parquet"));
+        std::fs::remove_file(path.with_extension("parquet")).unwrap();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+
+        let trip_ids = batch.column_by_name("Trip_ID").unwrap().as_string::<i32>().iter().map(Option::unwrap).collect_vec();
+        let agent_counts = batch.column_by_name("Agent_Count").unwrap().as_primitive::<UInt32Type>().values().to_vec();
+        let counts: HashMap<&str, u32> = trip_ids.into_iter().zip(agent_counts).collect();
+        // The templates are replaced by a trip per headway, from the start time up to (but not including) the end time.
+        assert_eq!(counts.keys().copied().sorted().collect_vec(), ["SHT_0730", "SHT_EXACT@08:00:00", "SHT_EXACT@08:10:00", "SHT_EXACT@08:20:00", "SHT_HEADWAY@09:00:00", "SHT_HEADWAY@09:15:00"]);
+        assert_eq!(counts["SHT_EXACT@08:10:00"], 4);
+        assert_eq!(counts.values().sum::<u32>(), 4);
+    }
+
+    #[test]
+    fn stations_list_their_platforms_in_the_stops_exports() {
+        let mut gtfs = load_fixture_gtfs("platforms");
+        let parent_stations = crate::data_import::collapse_to_parent_stations(&mut gtfs);
+        let network = build_fixture_network(&gtfs);
+
+        let path = temp_path("station_stops");
+        export_stops_csv(&path, &network, &StopGeometry::new(&network, Some(&gtfs)), Some(&parent_stations)).unwrap();
+        let mut csv_reader = csv::Reader::from_path(path.with_extension("csv")).unwrap();
+        assert_eq!(csv_reader.headers().unwrap().iter().last(), Some("child_stop_ids"));
+        let rows = csv_reader.records().map(|record| record.unwrap()).collect_vec();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+        // One row per station, and stops without a parent have no children.
+        assert_eq!(rows.iter().map(|row| (row[0].to_owned(), row[4].to_owned())).sorted().collect_vec(), [("ALP", ""), ("CHA", "CHA_1 CHA_2"), ("DEL", "")].map(|(id, children)| (id.to_owned(), children.to_owned())));
+
+        let mut bytes = Vec::new();
+        export_stops(&network, &StopGeometry::new(&network, Some(&gtfs)), None, Some(&parent_stations), &mut bytes).unwrap();
+        let chunks = parse_bin(&bytes).unwrap();
+        let child_offsets = u32_chunk(&chunks[5]);
+        let child_ids = child_offsets.windows(2).map(|w| std::str::from_utf8(&chunks[4][w[0] as usize..w[1] as usize]).unwrap()).collect_vec();
+        let expected_child_ids = network.stops.iter().map(|stop| {
+            let id: &str = stop.id.as_ref();
+            if id == "CHA" { "CHA_1 CHA_2" } else { "" }
+        }).collect_vec();
+        assert_eq!(child_ids, expected_child_ids);
+    }
+}
@@ -1,13 +1,21 @@
-use crate::simulation::{AgentCount, PopulationCount, SimulationStep, TripCapacity};
+use crate::checkpoint::WarmStart;
+use crate::demand::{BinnedProfile, DepartureBin, DepartureSampler};
+use crate::simulation::{AgentCount, CrowdingCost, PopulationCount, SimulationResult, SimulationStep, TripCapacity};
 use arrow::array::AsArray;
 use arrow::datatypes::{Int64Type, Time64NanosecondType};
-use chrono::NaiveDate;
+use chrono::{Datelike, NaiveDate, Weekday};
+use gtfs_structures::{Calendar, CalendarDate, Exception, Gtfs, Route, RouteType, Stop, StopTime, Trip};
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 use parquet::file::reader::ChunkReader;
+use rand::prelude::*;
 use raptor::network::{StopIndex, Timestamp};
-use raptor::Network;
-use std::collections::HashMap;
-use std::io::Read;
+use raptor::utils::get_time_str;
+use raptor::{Leg, Network};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::Path;
+use std::sync::Arc;
 use itertools::Itertools;
 
 #[derive(thiserror::Error, Debug)]
@@ -26,6 +34,55 @@ pub enum DataImportError {
     ColumnWrongFormat(&'static str, &'static str),
     #[error("No data found")]
     NoData,
+    #[error("Unknown consist {1} on line {0}")]
+    UnknownConsist(u64, String),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Unknown stop {1} on line {0}")]
+    UnknownStop(u64, String),
+    #[error("Invalid time {1} on line {0}: expected HH:MM:SS or HH:MM:SS-HH:MM:SS")]
+    InvalidTime(u64, String),
+    #[error("Invalid count {1} on line {0}: expected a non-negative number")]
+    InvalidCount(u64, String),
+    #[error("Invalid weight {1} on line {0}: expected a non-negative number")]
+    InvalidWeight(u64, String),
+    #[error("Invalid party size {1} on line {0}: expected a positive whole number")]
+    InvalidPartySize(u64, String),
+    #[error("Invalid requires_accessible {1} on line {0}: expected true, false, 1 or 0")]
+    InvalidRequiresAccessible(u64, String),
+    #[error("Invalid coordinate {1} on line {0}: expected decimal degrees")]
+    InvalidCoordinate(u64, String),
+    #[error("Invalid load {1} on line {0}: expected a non-negative number")]
+    InvalidLoad(u64, String),
+    #[error("Unknown trip {1} on line {0}")]
+    UnknownTrip(u64, String),
+    #[error("Invalid action {1} on line {0}: expected cancel or truncate")]
+    InvalidAction(u64, String),
+    #[error("Trip {1} on line {0} doesn't stop at {2}")]
+    StopNotOnTrip(u64, String, String),
+    #[error("Unknown route {1} on line {0}")]
+    UnknownRoute(u64, String),
+    #[error("Unknown shape {1} on line {0}")]
+    UnknownShape(u64, String),
+    #[error("Trip {1} on line {0} already exists in the GTFS")]
+    DuplicateTrip(u64, String),
+    #[error("Stop times of trip {1} go backwards on line {0}")]
+    NonMonotonicTimes(u64, String),
+    #[error("Trip {0} has fewer than two stops")]
+    TooFewStops(String),
+    #[error("Invalid capacity {1} on line {0}: expected a positive whole number")]
+    InvalidCapacity(u64, String),
+    #[error("Time bin {1} on line {0} is empty or overlaps the next bin")]
+    InvalidBin(u64, String),
+    #[error("Invalid transfer time {1} on line {0}: expected a whole number of seconds")]
+    InvalidTransferTime(u64, String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[cfg(feature = "gtfs_rt")]
+    #[error("Invalid GTFS-realtime feed: {0}")]
+    Protobuf(#[from] prost::DecodeError),
 }
 
 //type Date32TypeNative = <Date32Type as ArrowPrimitiveType>::Native;
@@ -131,16 +188,18 @@ pub fn build_simulation_steps_from_patronage_data(reader: impl ChunkReader + 'st
     if simulation_steps.len() == 0 {
         Err(DataImportError::NoDataForDate(network.date))
     } else {
-        Ok(simulation_steps.into_values().collect_vec())
+        // Sort so the simulation (and replanning selection) is deterministic.
+        Ok(simulation_steps.into_iter().sorted_unstable_by_key(|(key, _)| *key).map(|(_, step)| step).collect_vec())
     }
 }
 
-pub fn import_trip_capacities(reader: impl Read) -> Result<HashMap<String, TripCapacity>, DataImportError> {
+// Reads a CSV of <id_column>,seated,standing into a map of capacities.
+fn import_capacities(reader: impl Read, id_column: &'static str) -> Result<HashMap<String, TripCapacity>, DataImportError> {
     let mut csv_reader = csv::Reader::from_reader(reader);
     let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
 
-    if headers.get(0) != Some("trip_id") {
-        return Err(DataImportError::ColumnNotFound("trip_id"));
+    if headers.get(0) != Some(id_column) {
+        return Err(DataImportError::ColumnNotFound(id_column));
     }
     if headers.get(1) != Some("seated") {
         return Err(DataImportError::ColumnNotFound("seated"));
@@ -149,18 +208,1319 @@ pub fn import_trip_capacities(reader: impl Read) -> Result<HashMap<String, TripC
         return Err(DataImportError::ColumnNotFound("standing"));
     }
 
-    let capacities: HashMap<_, _> = csv_reader.into_records().filter_map(|record| {
-        let record = record.ok()?;
-        // trip_id,seated,standing
-        let trip_id = record.get(0)?;
-        let seated = record.get(1)?.parse::<PopulationCount>().ok()?;
-        let standing = record.get(2)?.parse::<PopulationCount>().ok()?;
-        Some((trip_id.to_string(), TripCapacity { seated, standing }))
-    }).collect();
+    let mut capacities = HashMap::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        // <id>,seated,standing. Every trip needs a seat for the crowding cost to be well-defined, but standing room is optional.
+        let capacity = |i, min_capacity| {
+            field(i).parse::<PopulationCount>()
+                    .ok()
+                    .filter(|capacity| *capacity >= min_capacity)
+                    .ok_or_else(|| DataImportError::InvalidCapacity(line, field(i).to_string()))
+        };
+        let seated = capacity(1, 1)?;
+        let standing = capacity(2, 0)?;
+        capacities.insert(field(0).to_string(), TripCapacity { seated, standing });
+    }
+
+    if capacities.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(capacities)
+    }
+}
+
+pub fn import_trip_capacities(reader: impl Read) -> Result<HashMap<String, TripCapacity>, DataImportError> {
+    import_capacities(reader, "trip_id")
+}
+
+// Reads a rolling stock assignment CSV of trip_id,consist, looking up each consist code's capacity.
+pub fn import_trip_consists(reader: impl Read, consists: &HashMap<String, TripCapacity>) -> Result<HashMap<String, TripCapacity>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    if headers.get(0) != Some("trip_id") {
+        return Err(DataImportError::ColumnNotFound("trip_id"));
+    }
+    if headers.get(1) != Some("consist") {
+        return Err(DataImportError::ColumnNotFound("consist"));
+    }
+
+    let mut capacities = HashMap::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let (Some(trip_id), Some(consist)) = (record.get(0), record.get(1)) else {
+            continue;
+        };
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let capacity = consists.get(consist).ok_or_else(|| DataImportError::UnknownConsist(line, consist.to_string()))?;
+        capacities.insert(trip_id.to_string(), *capacity);
+    }
 
     if capacities.is_empty() {
         Err(DataImportError::NoData)
     } else {
         Ok(capacities)
     }
-}
\ No newline at end of file
+}
+
+// Route capacities are keyed by GTFS route_id, and are applied with TripCapacities::set_route_capacities.
+pub fn import_route_capacities(reader: impl Read) -> Result<HashMap<String, TripCapacity>, DataImportError> {
+    import_capacities(reader, "route_id")
+}
+
+// Reads a CSV of stop_id,weight (e.g. the population or jobs in each station's catchment) into a weight per network stop.
+// Stops not in the file get a weight of zero, and rows for stops not in the network (e.g. removed by a RouteFilter) are skipped.
+pub fn import_stop_weights(reader: impl Read, network: &Network) -> Result<Vec<f64>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    if headers.get(0) != Some("stop_id") {
+        return Err(DataImportError::ColumnNotFound("stop_id"));
+    }
+    if headers.get(1) != Some("weight") {
+        return Err(DataImportError::ColumnNotFound("weight"));
+    }
+
+    let stop_idx_map: HashMap<&str, StopIndex> = network.stops.iter().enumerate().map(|(i, stop)| (&stop.id[..], i as StopIndex)).collect();
+
+    let mut weights = vec![0.; network.stops.len()];
+    let mut num_weighted_stops = 0;
+    let mut num_unknown_stops = 0;
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let weight_str = field(1);
+        let weight = weight_str.parse::<f64>()
+                               .ok()
+                               .filter(|weight| weight.is_finite() && *weight >= 0.)
+                               .ok_or_else(|| DataImportError::InvalidWeight(line, weight_str.to_string()))?;
+        match stop_idx_map.get(field(0)) {
+            Some(&stop_idx) => {
+                weights[stop_idx as usize] += weight;
+                num_weighted_stops += 1;
+            }
+            None => num_unknown_stops += 1,
+        }
+    }
+
+    if num_unknown_stops > 0 {
+        log::warn!("{num_unknown_stops} stop weights are for stops not in the network.");
+    }
+    if num_weighted_stops == 0 || weights.iter().all(|&weight| weight == 0.) {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(weights)
+    }
+}
+
+// Reads a CSV of stop_id,capacity (the number of people the platforms and concourse can hold) into a capacity per network stop.
+// Stops not in the file have no capacity, and rows for stops not in the network are skipped.
+pub fn import_stop_capacities(reader: impl Read, network: &Network) -> Result<Vec<Option<PopulationCount>>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    if headers.get(0) != Some("stop_id") {
+        return Err(DataImportError::ColumnNotFound("stop_id"));
+    }
+    if headers.get(1) != Some("capacity") {
+        return Err(DataImportError::ColumnNotFound("capacity"));
+    }
+
+    let stop_idx_map: HashMap<&str, StopIndex> = network.stops.iter().enumerate().map(|(i, stop)| (&stop.id[..], i as StopIndex)).collect();
+
+    let mut capacities = vec![None; network.stops.len()];
+    let mut num_unknown_stops = 0;
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let capacity_str = field(1);
+        let capacity = capacity_str.parse::<PopulationCount>()
+                                   .ok()
+                                   .filter(|capacity| *capacity > 0)
+                                   .ok_or_else(|| DataImportError::InvalidCapacity(line, capacity_str.to_string()))?;
+        match stop_idx_map.get(field(0)) {
+            Some(&stop_idx) => capacities[stop_idx as usize] = Some(capacity),
+            None => num_unknown_stops += 1,
+        }
+    }
+
+    if num_unknown_stops > 0 {
+        log::warn!("{num_unknown_stops} stop capacities are for stops not in the network.");
+    }
+    if capacities.iter().all(Option::is_none) {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(capacities)
+    }
+}
+
+// A surveyed load: the passengers on board the trip between two of its stops, using GTFS ids.
+#[derive(Clone, Debug)]
+pub struct ObservedLoad {
+    pub trip_id: String,
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub observed_load: f64,
+}
+
+// Reads a CSV of trip_id,from_stop_id,to_stop_id,observed_load (e.g. from load surveys), for `validation::validate_loads`.
+pub fn import_observed_loads(reader: impl Read) -> Result<Vec<ObservedLoad>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in ["trip_id", "from_stop_id", "to_stop_id", "observed_load"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+
+    let mut observations = Vec::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let load_str = field(3);
+        let observed_load = load_str.parse::<f64>()
+                                    .ok()
+                                    .filter(|load| load.is_finite() && *load >= 0.)
+                                    .ok_or_else(|| DataImportError::InvalidLoad(line, load_str.to_string()))?;
+        observations.push(ObservedLoad {
+            trip_id: field(0).to_string(),
+            from_stop_id: field(1).to_string(),
+            to_stop_id: field(2).to_string(),
+            observed_load,
+        });
+    }
+
+    if observations.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(observations)
+    }
+}
+
+// Reads the loads.csv of a previous run to warm start the simulation from. Segments are matched by trip_id, from_stop_id
+// and to_stop_id (in order, for trips that pass a stop more than once), so the network can differ. Segments without a
+// row start from zero, and rows for segments not in the network are skipped.
+pub fn import_warm_start_loads(reader: impl Read, network: &Network) -> Result<WarmStart, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in [(0, "trip_id"), (2, "from_stop_id"), (3, "to_stop_id"), (6, "passengers_on_board")] {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+
+    // The stop time index of each segment's departure, in trip order.
+    let mut segments: HashMap<(&str, &str, &str), Vec<usize>> = HashMap::new();
+    for route in network.routes.iter() {
+        let stops = route.get_stops(&network.route_stops);
+        for trip in 0..route.num_trips as usize {
+            let trip_start = route.get_trip_range(trip).start;
+            for (stop_order, (&from_stop, &to_stop)) in stops.iter().tuple_windows().enumerate() {
+                let key = (route.trip_ids[trip].as_ref(), &network.stops[from_stop as usize].id[..], &network.stops[to_stop as usize].id[..]);
+                segments.entry(key).or_default().push(trip_start + stop_order);
+            }
+        }
+    }
+    let num_segments = segments.values().map(Vec::len).sum::<usize>();
+    for stop_times in segments.values_mut() {
+        stop_times.reverse();
+    }
+
+    let mut averaged_population = vec![0.; network.stop_times.len()];
+    let mut num_matched = 0;
+    let mut num_unknown_segments = 0;
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let load_str = field(6);
+        let load = load_str.parse::<CrowdingCost>()
+                           .ok()
+                           .filter(|load| load.is_finite() && *load >= 0.)
+                           .ok_or_else(|| DataImportError::InvalidLoad(line, load_str.to_string()))?;
+        match segments.get_mut(&(field(0), field(2), field(3))).and_then(Vec::pop) {
+            Some(stop_time_idx) => {
+                averaged_population[stop_time_idx] = load;
+                num_matched += 1;
+            }
+            None => num_unknown_segments += 1,
+        }
+    }
+
+    if num_unknown_segments > 0 {
+        log::warn!("{num_unknown_segments} warm start loads are for segments not in the network.");
+    }
+    if num_matched == 0 {
+        return Err(DataImportError::NoData);
+    }
+    Ok(WarmStart { averaged_population, num_matched, num_unmatched: num_segments - num_matched, cold_start_rounds: None })
+}
+
+// Parses a GTFS-style HH:MM:SS time (hours may be past 24).
+pub(crate) fn parse_time(time: &str) -> Option<Timestamp> {
+    let mut parts = time.trim().split(':');
+    let hours = parts.next()?.parse::<Timestamp>().ok()?;
+    let minutes = parts.next()?.parse::<Timestamp>().ok()?;
+    let seconds = parts.next()?.parse::<Timestamp>().ok()?;
+    if parts.next().is_some() || minutes >= 60 || seconds >= 60 {
+        return None;
+    }
+    Some(hours * 60 * 60 + minutes * 60 + seconds)
+}
+
+// Reads a departure profile CSV of time_bin,weight, where the time bin is either a HH:MM:SS-HH:MM:SS window or a HH:MM:SS start time.
+// A bin with only a start time runs until the next bin starts, and the last bin is as long as the one before it (or an hour if there's only one).
+pub fn import_departure_profile(reader: impl Read) -> Result<BinnedProfile, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    if headers.get(0) != Some("time_bin") {
+        return Err(DataImportError::ColumnNotFound("time_bin"));
+    }
+    if headers.get(1) != Some("weight") {
+        return Err(DataImportError::ColumnNotFound("weight"));
+    }
+
+    let mut rows = Vec::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let time_bin = field(0);
+        let (start_time, end_time) = match time_bin.split_once('-') {
+            Some((start, end)) => (parse_time(start), parse_time(end).map(Some)),
+            None => (parse_time(time_bin), Some(None)),
+        };
+        let (Some(start_time), Some(end_time)) = (start_time, end_time) else {
+            return Err(DataImportError::InvalidTime(line, time_bin.to_string()));
+        };
+
+        let weight_str = field(1);
+        let weight = weight_str.parse::<f64>()
+                               .ok()
+                               .filter(|weight| weight.is_finite() && *weight >= 0.)
+                               .ok_or_else(|| DataImportError::InvalidWeight(line, weight_str.to_string()))?;
+        rows.push((line, time_bin.to_string(), start_time, end_time, weight));
+    }
+    rows.sort_by_key(|&(_, _, start_time, _, _)| start_time);
+
+    let mut bins: Vec<DepartureBin> = Vec::with_capacity(rows.len());
+    for (i, (line, time_bin, start_time, end_time, weight)) in rows.iter().enumerate() {
+        let next_start_time = rows.get(i + 1).map(|&(_, _, next_start_time, _, _)| next_start_time);
+        let end_time = end_time.or(next_start_time).unwrap_or_else(|| {
+            start_time + bins.last().map_or(60 * 60, |bin| bin.end_time - bin.start_time)
+        });
+        if end_time <= *start_time || next_start_time.is_some_and(|next_start_time| next_start_time < end_time) {
+            return Err(DataImportError::InvalidBin(*line, time_bin.clone()));
+        }
+        bins.push(DepartureBin { start_time: *start_time, end_time, weight: *weight });
+    }
+
+    if bins.iter().all(|bin| bin.weight == 0.) {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(BinnedProfile { bins })
+    }
+}
+
+// Reads an origin-destination matrix CSV of origin_stop_id,destination_stop_id,departure_time,count using GTFS stop ids.
+// The departure time is either a single HH:MM:SS time, or a HH:MM:SS-HH:MM:SS window that the agents are spread evenly across.
+// If `departures` is set, the departure time may be left empty to draw each agent's departure time from the profile.
+// Fractional counts are rounded up or down at random (in proportion to the fraction) using the seed.
+// Rows using a stop in `excluded_stop_ids` (e.g. removed by a RouteFilter) can't be assigned, so are skipped and reported.
+// Each row's count is multiplied by its optional expansion weight column and by `demand_scale` (e.g. 10 for a 10% sample),
+// then stochastically rounded to a whole number of agents, so the expected total is unchanged.
+// An optional party_size column (after the weight column, if any) makes the row's count a number of parties of that many
+// agents, such as school groups, which travel together and are never split.
+pub fn load_od_matrix(reader: impl Read, network: &Network, seed: Option<u64>, demand_scale: f64, excluded_stop_ids: &HashSet<String>, departures: Option<&DepartureSampler>) -> Result<Vec<SimulationStep>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in ["origin_stop_id", "destination_stop_id", "departure_time", "count"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+    let has_weights = headers.get(4) == Some("weight");
+    let party_size_column = headers.iter().position(|column| column == "party_size");
+    let requires_accessible_column = headers.iter().position(|column| column == "requires_accessible");
+
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    };
+
+    let stop_idx_map: HashMap<&str, StopIndex> = network.stops.iter().enumerate().map(|(i, stop)| (&stop.id[..], i as StopIndex)).collect();
+
+    // Agents needing wheelchair access plan separately, so they're in their own steps.
+    let new_step = |departure_time, origin_stop, requires_accessible| SimulationStep { requires_accessible, ..SimulationStep::new(departure_time, origin_stop) };
+    let mut simulation_steps = HashMap::new();
+    let mut num_unassignable_rows = 0;
+    let mut num_unassignable_agents = 0.;
+    let mut input_total = 0.;
+    // Expected (scaled) and generated agents from each origin.
+    let mut origin_totals: HashMap<StopIndex, (f64, u64)> = HashMap::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        if excluded_stop_ids.contains(field(0)) || excluded_stop_ids.contains(field(1)) {
+            log::debug!("OD row on line {line} from {} to {} uses a stop outside the network.", field(0), field(1));
+            num_unassignable_rows += 1;
+            num_unassignable_agents += field(3).parse::<f64>().unwrap_or(0.);
+            continue;
+        }
+
+        let get_stop = |stop_id: &str| stop_idx_map.get(stop_id).copied().ok_or_else(|| DataImportError::UnknownStop(line, stop_id.to_string()));
+        let origin_stop = get_stop(field(0))?;
+        let dest_stop = get_stop(field(1))?;
+
+        let departure_time = field(2);
+        let count_str = field(3);
+        let count = count_str.parse::<f64>()
+                             .ok()
+                             .filter(|count| count.is_finite() && *count >= 0.)
+                             .ok_or_else(|| DataImportError::InvalidCount(line, count_str.to_string()))?;
+        let weight_str = if has_weights { field(4) } else { "" };
+        let weight = if weight_str.is_empty() {
+            1.
+        } else {
+            weight_str.parse::<f64>()
+                      .ok()
+                      .filter(|weight| weight.is_finite() && *weight >= 0.)
+                      .ok_or_else(|| DataImportError::InvalidWeight(line, weight_str.to_string()))?
+        };
+        let party_size_str = party_size_column.map_or("", field);
+        let party_size = if party_size_str.is_empty() {
+            1
+        } else {
+            party_size_str.parse::<AgentCount>()
+                          .ok()
+                          .filter(|&party_size| party_size > 0)
+                          .ok_or_else(|| DataImportError::InvalidPartySize(line, party_size_str.to_string()))?
+        };
+        let requires_accessible_str = requires_accessible_column.map_or("", field);
+        let requires_accessible = match requires_accessible_str {
+            "" | "0" | "false" => false,
+            "1" | "true" => true,
+            _ => return Err(DataImportError::InvalidRequiresAccessible(line, requires_accessible_str.to_string())),
+        };
+        input_total += count * party_size as f64;
+        // With parties, this is the number of parties.
+        let count = count * weight * demand_scale;
+        let expected_count = count;
+        let count = count.floor() as AgentCount + rng.gen_bool(count.fract()) as AgentCount;
+        let origin_total = origin_totals.entry(origin_stop).or_default();
+        origin_total.0 += expected_count * party_size as f64;
+        origin_total.1 += count as u64 * party_size as u64;
+
+        if let (true, Some(departures)) = (departure_time.is_empty(), departures) {
+            for _ in 0..count {
+                let departure_time = departures.sample(&mut rng);
+                let simulation_step = simulation_steps.entry((departure_time, origin_stop, requires_accessible))
+                                                      .or_insert_with(|| new_step(departure_time, origin_stop, requires_accessible));
+                simulation_step.push_parties(dest_stop, party_size, 1);
+            }
+            continue;
+        }
+
+        let (window_start, window_end) = match departure_time.split_once('-') {
+            Some((start, end)) => (parse_time(start), parse_time(end)),
+            None => (parse_time(departure_time), parse_time(departure_time)),
+        };
+        let (Some(window_start), Some(window_end)) = (window_start, window_end) else {
+            return Err(DataImportError::InvalidTime(line, departure_time.to_string()));
+        };
+        if window_end < window_start {
+            return Err(DataImportError::InvalidTime(line, departure_time.to_string()));
+        }
+
+        if count == 0 {
+            continue;
+        }
+        if window_start == window_end {
+            let simulation_step = simulation_steps.entry((window_start, origin_stop, requires_accessible))
+                                                  .or_insert_with(|| new_step(window_start, origin_stop, requires_accessible));
+            simulation_step.push_parties(dest_stop, party_size, count);
+            continue;
+        }
+
+        // Expand into one simulation step per agent (or party), spaced evenly over the time window.
+        let window_length = (window_end - window_start) as f64;
+        for agent in 0..count {
+            let departure_time = window_start + (window_length * (agent as f64 + 0.5) / count as f64) as Timestamp;
+            let simulation_step = simulation_steps.entry((departure_time, origin_stop, requires_accessible))
+                                                  .or_insert_with(|| new_step(departure_time, origin_stop, requires_accessible));
+            simulation_step.push_parties(dest_stop, party_size, 1);
+        }
+    }
+
+    if num_unassignable_rows > 0 {
+        log::warn!("{num_unassignable_agents:.0} agents in {num_unassignable_rows} OD rows use stops excluded from the network and can't be assigned.");
+    }
+    let expected_total = origin_totals.values().map(|&(expected, _)| expected).sum::<f64>();
+    let expanded_total = origin_totals.values().map(|&(_, agents)| agents).sum::<u64>();
+    if has_weights || demand_scale != 1. {
+        log::info!("OD matrix has {input_total:.1} trips, expanded to {expected_total:.1} (generating {expanded_total} agents).");
+    }
+    let rounded_origins = origin_totals.iter().filter(|(_, &(expected, agents))| (agents as f64 - expected).abs() > 1.).sorted_unstable_by_key(|(&origin, _)| origin).collect_vec();
+    if !rounded_origins.is_empty() {
+        log::info!("Rounding to whole agents changed the demand from {} origins by more than one agent.", rounded_origins.len());
+        for (&origin, &(expected, agents)) in rounded_origins {
+            log::debug!("Origin {}: {expected:.2} expected, {agents} generated.", network.stops[origin as usize].id);
+        }
+    }
+
+    if simulation_steps.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        // Sort so the simulation (and replanning selection) is deterministic.
+        Ok(simulation_steps.into_iter().sorted_unstable_by_key(|(key, _)| *key).map(|(_, step)| step).collect_vec())
+    }
+}
+
+// Trips to cancel or cut short in a disruption scenario, using GTFS ids.
+#[derive(Clone, Debug, Default)]
+pub struct Disruption {
+    pub cancelled_trips: HashSet<String>,
+    // Trip id to the stop the trip now terminates at.
+    pub truncated_trips: HashMap<String, String>,
+}
+
+// Reads a CSV of trip_id,action,stop_id, where action is `cancel` or `truncate` (stopping the trip at stop_id).
+// Truncating a trip at its first stop cancels it.
+pub fn import_disruption(reader: impl Read, gtfs: &Gtfs) -> Result<Disruption, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in ["trip_id", "action", "stop_id"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+
+    let mut disruption = Disruption::default();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let trip_id = field(0);
+        let trip = gtfs.trips.get(trip_id).ok_or_else(|| DataImportError::UnknownTrip(line, trip_id.to_string()))?;
+        match field(1) {
+            "cancel" => {
+                disruption.cancelled_trips.insert(trip_id.to_string());
+            }
+            "truncate" => {
+                let stop_id = field(2);
+                match trip.stop_times.iter().position(|stop_time| stop_time.stop.id == stop_id) {
+                    Some(0) => {
+                        disruption.cancelled_trips.insert(trip_id.to_string());
+                    }
+                    Some(_) => {
+                        disruption.truncated_trips.insert(trip_id.to_string(), stop_id.to_string());
+                    }
+                    None => return Err(DataImportError::StopNotOnTrip(line, trip_id.to_string(), stop_id.to_string())),
+                }
+            }
+            action => return Err(DataImportError::InvalidAction(line, action.to_string())),
+        }
+    }
+
+    if disruption.cancelled_trips.is_empty() && disruption.truncated_trips.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(disruption)
+    }
+}
+
+impl Disruption {
+    // Removes cancelled trips and the stop times after each truncated trip's new terminus.
+    // Stops are left alone, so a network built from the result has the same stops as one built before.
+    pub fn apply(&self, gtfs: &mut Gtfs) {
+        gtfs.trips.retain(|trip_id, _| !self.cancelled_trips.contains(trip_id));
+        for (trip_id, stop_id) in self.truncated_trips.iter() {
+            let Some(trip) = gtfs.trips.get_mut(trip_id) else {
+                continue;
+            };
+            if let Some(terminus) = trip.stop_times.iter().position(|stop_time| stop_time.stop.id == *stop_id) {
+                trip.stop_times.truncate(terminus + 1);
+            }
+        }
+    }
+
+    // Moves truncation points at stops replaced by collapse_to_parent_stations to their station.
+    pub fn collapse_to_parent_stations(&mut self, parent_stations: &ParentStations) {
+        for stop_id in self.truncated_trips.values_mut() {
+            *stop_id = parent_stations.station_id(stop_id).to_owned();
+        }
+    }
+
+    // Whether the leg (in a network built before the disruption was applied) rides a cancelled trip or past a truncated trip's terminus.
+    pub fn is_leg_disrupted(&self, network: &Network, leg: &Leg) -> bool {
+        let route_idx = leg.trip.route_idx as usize;
+        let route = &network.routes[route_idx];
+        let trip_id: &str = route.trip_ids[leg.trip.trip_order as usize].as_ref();
+        if self.cancelled_trips.contains(trip_id) {
+            return true;
+        }
+        let Some(terminus_id) = self.truncated_trips.get(trip_id) else {
+            return false;
+        };
+        let stops = route.get_stops(&network.route_stops);
+        let terminus_order = stops.iter().position(|&stop_idx| {
+            let stop_id: &str = network.stops[stop_idx as usize].id.as_ref();
+            stop_id == terminus_id
+        });
+        match terminus_order {
+            Some(terminus_order) => leg.arrival_stop_order as usize > terminus_order,
+            None => false,
+        }
+    }
+
+    // Finds the agents whose journey (in a simulation of the network before the disruption) used a disrupted trip.
+    pub fn affected_agents(&self, network: &Network, simulation_result: &SimulationResult) -> DisruptionReport {
+        let mut report = DisruptionReport::default();
+        for agent_journey in simulation_result.round_agent_journeys.last().into_iter().flatten() {
+            let Ok(journey) = &agent_journey.result else {
+                continue;
+            };
+            let mut affected = false;
+            for leg in journey.legs.iter().filter(|leg| self.is_leg_disrupted(network, leg)) {
+                let trip_id: &str = network.routes[leg.trip.route_idx as usize].trip_ids[leg.trip.trip_order as usize].as_ref();
+                *report.agents_per_trip.entry(trip_id.to_string()).or_default() += agent_journey.count as u64;
+                affected = true;
+            }
+            if affected {
+                report.num_affected_agents += agent_journey.count as u64;
+            }
+        }
+        report
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DisruptionReport {
+    // Agents whose journey used each disrupted trip.
+    pub agents_per_trip: HashMap<String, u64>,
+    // Agents whose journey used any disrupted trip.
+    pub num_affected_agents: u64,
+}
+
+// Service id given to supplementary trips, which runs only on the modelled date.
+pub const SUPPLEMENTARY_SERVICE_ID: &str = "supplementary";
+
+// Reads extra trips (e.g. a service uplift) from a stop_times-like CSV of trip_id,route_id,stop_id,arrival_time,departure_time
+// with an optional shape_id column. Each trip's rows must be in stop order, with times that never go backwards.
+// Trips use routes, stops and shapes that are already in the GTFS, and run on `SUPPLEMENTARY_SERVICE_ID`.
+pub fn import_supplementary_trips(reader: impl Read, gtfs: &Gtfs) -> Result<Vec<Trip>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in ["trip_id", "route_id", "stop_id", "arrival_time", "departure_time"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+    let has_shape_ids = headers.get(5) == Some("shape_id");
+
+    let mut trips: Vec<Trip> = Vec::new();
+    let mut trip_indices = HashMap::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let trip_id = field(0);
+        if gtfs.trips.contains_key(trip_id) {
+            return Err(DataImportError::DuplicateTrip(line, trip_id.to_string()));
+        }
+        let trip_idx = *trip_indices.entry(trip_id.to_string()).or_insert_with(|| {
+            trips.push(Trip { id: trip_id.to_string(), service_id: SUPPLEMENTARY_SERVICE_ID.to_string(), ..Default::default() });
+            trips.len() - 1
+        });
+        let trip = &mut trips[trip_idx];
+
+        let route_id = field(1);
+        if !gtfs.routes.contains_key(route_id) {
+            return Err(DataImportError::UnknownRoute(line, route_id.to_string()));
+        }
+        trip.route_id = route_id.to_string();
+        let shape_id = if has_shape_ids { field(5) } else { "" };
+        if !shape_id.is_empty() {
+            if !gtfs.shapes.contains_key(shape_id) {
+                return Err(DataImportError::UnknownShape(line, shape_id.to_string()));
+            }
+            trip.shape_id = Some(shape_id.to_string());
+        }
+
+        let stop_id = field(2);
+        let stop = gtfs.stops.get(stop_id).ok_or_else(|| DataImportError::UnknownStop(line, stop_id.to_string()))?;
+        let parse = |time: &str| parse_time(time).ok_or_else(|| DataImportError::InvalidTime(line, time.to_string()));
+        let arrival_time = parse(field(3))?;
+        let departure_time = parse(field(4))?;
+        let previous_departure = trip.stop_times.last().and_then(|stop_time| stop_time.departure_time);
+        if departure_time < arrival_time || previous_departure.is_some_and(|previous| arrival_time < previous) {
+            return Err(DataImportError::NonMonotonicTimes(line, trip_id.to_string()));
+        }
+        trip.stop_times.push(StopTime {
+            arrival_time: Some(arrival_time),
+            departure_time: Some(departure_time),
+            stop: stop.clone(),
+            stop_sequence: trip.stop_times.len() as _,
+            ..Default::default()
+        });
+    }
+
+    if let Some(trip) = trips.iter().find(|trip| trip.stop_times.len() < 2) {
+        return Err(DataImportError::TooFewStops(trip.id.clone()));
+    }
+    if trips.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(trips)
+    }
+}
+
+// Adds supplementary trips to the feed, with their service running on each of the dates.
+pub fn add_supplementary_trips(gtfs: &mut Gtfs, trips: Vec<Trip>, dates: &[NaiveDate]) {
+    gtfs.calendar_dates.insert(SUPPLEMENTARY_SERVICE_ID.to_string(), dates.iter().map(|&date| CalendarDate {
+        service_id: SUPPLEMENTARY_SERVICE_ID.to_string(),
+        date,
+        exception_type: Exception::Added,
+    }).collect());
+    for trip in trips {
+        gtfs.trips.insert(trip.id.clone(), trip);
+    }
+}
+
+// What expand_frequencies added to the feed.
+#[derive(Debug, Default)]
+pub struct FrequencyExpansion {
+    // Template trips with frequencies, which are replaced by the generated trips.
+    pub num_frequency_trips: usize,
+    pub num_generated_trips: usize,
+}
+
+impl FrequencyExpansion {
+    // Generated trips per template trip.
+    pub fn expansion_factor(&self) -> f64 {
+        self.num_generated_trips as f64 / self.num_frequency_trips.max(1) as f64
+    }
+}
+
+// Replaces each trip with frequencies.txt entries by explicit trips departing every headway from each entry's start time
+// (inclusive) to its end time (exclusive), keeping the template trip's times relative to its first departure.
+// Exact and frequency-based (exact_times = 0) entries are expanded the same way. Generated trips are named <trip_id>@<HH:MM:SS>
+// after their first departure, which is the id used in every export.
+pub fn expand_frequencies(gtfs: &mut Gtfs) -> FrequencyExpansion {
+    let mut expansion = FrequencyExpansion::default();
+    let frequency_trip_ids = gtfs.trips.values().filter(|trip| !trip.frequencies.is_empty()).map(|trip| trip.id.clone()).collect_vec();
+    for trip_id in frequency_trip_ids {
+        let Some(template) = gtfs.trips.remove(&trip_id) else {
+            continue;
+        };
+        let Some(first_departure) = template.stop_times.first().and_then(|stop_time| stop_time.departure_time.or(stop_time.arrival_time)) else {
+            log::warn!("Frequency trip {trip_id} has no times, so it was left out.");
+            continue;
+        };
+        expansion.num_frequency_trips += 1;
+        for frequency in template.frequencies.iter().filter(|frequency| frequency.headway_secs > 0) {
+            for start_time in (frequency.start_time..frequency.end_time).step_by(frequency.headway_secs as usize) {
+                let mut trip = template.clone();
+                trip.id = format!("{trip_id}@{}", get_time_str(start_time));
+                trip.frequencies.clear();
+                for stop_time in trip.stop_times.iter_mut() {
+                    stop_time.arrival_time = stop_time.arrival_time.map(|time| (time + start_time).saturating_sub(first_departure));
+                    stop_time.departure_time = stop_time.departure_time.map(|time| (time + start_time).saturating_sub(first_departure));
+                }
+                gtfs.trips.insert(trip.id.clone(), trip);
+                expansion.num_generated_trips += 1;
+            }
+        }
+    }
+    expansion
+}
+
+// Which routes to build the network from. Empty lists don't filter.
+#[derive(Clone, Debug, Default)]
+pub struct RouteFilter {
+    // GTFS route_type codes to keep, e.g. 2 for rail.
+    pub route_types: Vec<i16>,
+    pub route_ids: Vec<String>,
+}
+
+// What a RouteFilter removed from the feed.
+#[derive(Debug, Default)]
+pub struct RouteFilterResult {
+    pub num_routes_removed: usize,
+    pub num_trips_removed: usize,
+    pub num_shapes_removed: usize,
+    // Stops that were only served by removed routes.
+    pub removed_stop_ids: HashSet<String>,
+}
+
+fn route_type_code(route_type: &RouteType) -> i16 {
+    match route_type {
+        RouteType::Tramway => 0,
+        RouteType::Subway => 1,
+        RouteType::Rail => 2,
+        RouteType::Bus => 3,
+        RouteType::Ferry => 4,
+        RouteType::CableCar => 5,
+        RouteType::Gondola => 6,
+        RouteType::Funicular => 7,
+        RouteType::Coach => 200,
+        RouteType::Air => 1100,
+        RouteType::Taxi => 1500,
+        RouteType::Other(code) => *code,
+    }
+}
+
+impl RouteFilter {
+    pub fn is_empty(&self) -> bool {
+        self.route_types.is_empty() && self.route_ids.is_empty()
+    }
+
+    fn keeps(&self, route: &Route) -> bool {
+        (self.route_types.is_empty() || self.route_types.contains(&route_type_code(&route.route_type)))
+            && (self.route_ids.is_empty() || self.route_ids.contains(&route.id))
+    }
+
+    // Removes the routes that don't pass the filter from the feed, along with their trips, their shapes and any stops only they serve,
+    // so they never make it into the network.
+    pub fn apply(&self, gtfs: &mut Gtfs) -> RouteFilterResult {
+        if self.is_empty() {
+            return RouteFilterResult::default();
+        }
+
+        let num_routes = gtfs.routes.len();
+        gtfs.routes.retain(|_, route| self.keeps(route));
+        let num_trips = gtfs.trips.len();
+        gtfs.trips.retain(|_, trip| gtfs.routes.contains_key(&trip.route_id));
+
+        let used_shape_ids = gtfs.trips.values().filter_map(|trip| trip.shape_id.as_deref()).collect::<HashSet<_>>();
+        let num_shapes = gtfs.shapes.len();
+        gtfs.shapes.retain(|shape_id, _| used_shape_ids.contains(shape_id.as_str()));
+
+        // Keep the parent stations of served stops too.
+        let mut served_stop_ids = HashSet::new();
+        for stop_time in gtfs.trips.values().flat_map(|trip| trip.stop_times.iter()) {
+            served_stop_ids.insert(stop_time.stop.id.as_str());
+            if let Some(parent_station) = &stop_time.stop.parent_station {
+                served_stop_ids.insert(parent_station.as_str());
+            }
+        }
+        let removed_stop_ids = gtfs.stops.keys().filter(|stop_id| !served_stop_ids.contains(stop_id.as_str())).cloned().collect::<HashSet<_>>();
+        gtfs.stops.retain(|stop_id, _| !removed_stop_ids.contains(stop_id));
+
+        RouteFilterResult {
+            num_routes_removed: num_routes - gtfs.routes.len(),
+            num_trips_removed: num_trips - gtfs.trips.len(),
+            num_shapes_removed: num_shapes - gtfs.shapes.len(),
+            removed_stop_ids,
+        }
+    }
+}
+
+// Stops that collapse_to_parent_stations replaced with their parent station.
+#[derive(Debug, Default)]
+pub struct ParentStations {
+    // The station each replaced stop became.
+    pub station_ids: HashMap<String, String>,
+}
+
+impl ParentStations {
+    // The id a stop of the original feed has in the collapsed feed.
+    pub fn station_id<'a>(&'a self, stop_id: &'a str) -> &'a str {
+        self.station_ids.get(stop_id).map_or(stop_id, String::as_str)
+    }
+
+    // The replaced stops of each station, in id order.
+    pub fn children(&self) -> HashMap<&str, Vec<&str>> {
+        let mut children = HashMap::new();
+        for (stop_id, station_id) in self.station_ids.iter().sorted_unstable() {
+            children.entry(station_id.as_str()).or_insert_with(Vec::new).push(stop_id.as_str());
+        }
+        children
+    }
+}
+
+// Replaces every stop that has a parent station in the feed with that station (following parents of parents), so a station's platforms
+// become one stop in the network and changing between them uses the network's default transfer time.
+// Consecutive stop times at the same station are combined, keeping the first arrival and last departure. Stops without a parent are untouched.
+pub fn collapse_to_parent_stations(gtfs: &mut Gtfs) -> ParentStations {
+    let parent_id = |stop_id: &str| gtfs.stops.get(stop_id)?.parent_station.as_deref().filter(|parent_id| gtfs.stops.contains_key(*parent_id));
+    let mut parent_stations = ParentStations::default();
+    for stop_id in gtfs.stops.keys() {
+        let mut station_id = stop_id.as_str();
+        // Bounded in case a feed's parents form a cycle.
+        for _ in 0..gtfs.stops.len() {
+            match parent_id(station_id) {
+                Some(parent_id) if parent_id != stop_id.as_str() => station_id = parent_id,
+                _ => break,
+            }
+        }
+        if station_id != stop_id.as_str() {
+            parent_stations.station_ids.insert(stop_id.clone(), station_id.to_owned());
+        }
+    }
+
+    for trip in gtfs.trips.values_mut() {
+        let mut stop_times: Vec<StopTime> = Vec::with_capacity(trip.stop_times.len());
+        for mut stop_time in std::mem::take(&mut trip.stop_times) {
+            if let Some(station_id) = parent_stations.station_ids.get(&stop_time.stop.id) {
+                stop_time.stop = gtfs.stops[station_id].clone();
+            }
+            match stop_times.last_mut() {
+                Some(previous) if previous.stop.id == stop_time.stop.id => previous.departure_time = stop_time.departure_time,
+                _ => stop_times.push(stop_time),
+            }
+        }
+        trip.stop_times = stop_times;
+    }
+    gtfs.stops.retain(|stop_id, _| !parent_stations.station_ids.contains_key(stop_id));
+
+    parent_stations
+}
+
+// Summary of one source feed after merging.
+#[derive(Debug)]
+pub struct FeedStats {
+    pub name: String,
+    pub num_stops: usize,
+    pub num_routes: usize,
+    pub num_trips: usize,
+    // Stops that were merged into a stop from an earlier feed.
+    pub num_merged_stops: usize,
+}
+
+impl FeedStats {
+    fn new(name: String, gtfs: &Gtfs) -> Self {
+        Self { name, num_stops: gtfs.stops.len(), num_routes: gtfs.routes.len(), num_trips: gtfs.trips.len(), num_merged_stops: 0 }
+    }
+}
+
+// The route_ids of a GTFS routes.txt without a route_color (or with an empty one). gtfs_structures gives these a colour
+// like any other, so this is the only way to tell them from routes that were given that colour.
+pub fn import_routes_without_colour(reader: impl Read) -> Result<HashSet<String>, DataImportError> {
+    let mut csv_reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(reader);
+    let headers = csv_reader.headers()?.clone();
+    let route_id_idx = headers.iter().position(|header| header == "route_id").ok_or(DataImportError::ColumnNotFound("route_id"))?;
+    let colour_idx = headers.iter().position(|header| header == "route_color");
+
+    let mut route_ids = HashSet::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let colour = colour_idx.and_then(|idx| record.get(idx)).unwrap_or("");
+        if let Some(route_id) = record.get(route_id_idx).filter(|_| colour.is_empty()) {
+            route_ids.insert(route_id.to_string());
+        }
+    }
+    Ok(route_ids)
+}
+
+// As import_routes_without_colour, from a zipped feed (with routes.txt at the top level or in a folder).
+pub fn import_zip_routes_without_colour(reader: impl Read + Seek) -> Result<HashSet<String>, DataImportError> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let routes_name = zip.file_names().find(|name| name.rsplit('/').next() == Some("routes.txt")).ok_or(DataImportError::NoData)?.to_string();
+    import_routes_without_colour(zip.by_name(&routes_name)?)
+}
+
+// The routes without a route_color in feeds read from directories or zips, with route_ids namespaced as merge_feeds does.
+// Feeds that can't be read again (e.g. URLs) are skipped with a warning, so their routes keep the colour they were read with.
+pub fn gtfs_routes_without_colour(gtfs_paths: &[impl AsRef<Path>]) -> HashSet<String> {
+    let mut route_ids = HashSet::new();
+    for (feed_idx, gtfs_path) in gtfs_paths.iter().enumerate() {
+        let gtfs_path = gtfs_path.as_ref();
+        let feed_route_ids = if gtfs_path.is_dir() {
+            File::open(gtfs_path.join("routes.txt")).map_err(DataImportError::from).and_then(import_routes_without_colour)
+        } else {
+            File::open(gtfs_path).map_err(DataImportError::from).and_then(import_zip_routes_without_colour)
+        };
+        match feed_route_ids {
+            Ok(feed_route_ids) if feed_idx == 0 => route_ids.extend(feed_route_ids),
+            Ok(feed_route_ids) => route_ids.extend(feed_route_ids.into_iter().map(|route_id| format!("{feed_idx}:{route_id}"))),
+            Err(err) => log::warn!("Couldn't read the route colours of {}: {err}", gtfs_path.display()),
+        }
+    }
+    route_ids
+}
+
+// A minimum time to change from one stop to another, by stop id (the same stop for changing trips at a stop).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StopTransferTime {
+    pub from_stop_id: String,
+    pub to_stop_id: String,
+    pub seconds: Timestamp,
+}
+
+// Reads a CSV of from_stop_id,to_stop_id,seconds minimum transfer times.
+pub fn import_transfer_times(reader: impl Read) -> Result<Vec<StopTransferTime>, DataImportError> {
+    let mut csv_reader = csv::Reader::from_reader(reader);
+    let headers = csv_reader.headers().map_err(|_| DataImportError::ColumnNotFound("header"))?;
+
+    for (i, column) in ["from_stop_id", "to_stop_id", "seconds"].into_iter().enumerate() {
+        if headers.get(i) != Some(column) {
+            return Err(DataImportError::ColumnNotFound(column));
+        }
+    }
+
+    let mut transfer_times = Vec::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |i| record.get(i).unwrap_or("").trim();
+
+        let seconds_str = field(2);
+        let seconds = seconds_str.parse::<Timestamp>().map_err(|_| DataImportError::InvalidTransferTime(line, seconds_str.to_string()))?;
+        transfer_times.push(StopTransferTime { from_stop_id: field(0).to_string(), to_stop_id: field(1).to_string(), seconds });
+    }
+    Ok(transfer_times)
+}
+
+// The min_transfer_time of each transfer in a feed's transfers.txt. Transfers between particular trips or routes, those
+// marked as not possible (transfer_type 3) and those without a min_transfer_time are left out.
+pub fn import_gtfs_transfer_times(reader: impl Read) -> Result<Vec<StopTransferTime>, DataImportError> {
+    let mut csv_reader = csv::ReaderBuilder::new().flexible(true).trim(csv::Trim::All).from_reader(reader);
+    let headers = csv_reader.headers()?.clone();
+    let column = |name| headers.iter().position(|header| header == name);
+    let from_stop_idx = column("from_stop_id").ok_or(DataImportError::ColumnNotFound("from_stop_id"))?;
+    let to_stop_idx = column("to_stop_id").ok_or(DataImportError::ColumnNotFound("to_stop_id"))?;
+    let transfer_type_idx = column("transfer_type");
+    let min_transfer_time_idx = column("min_transfer_time");
+    let specific_idxs = ["from_trip_id", "to_trip_id", "from_route_id", "to_route_id"].into_iter().filter_map(column).collect_vec();
+
+    let mut transfer_times = Vec::new();
+    for record in csv_reader.into_records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        let field = |idx: Option<usize>| idx.and_then(|idx| record.get(idx)).unwrap_or("");
+
+        let seconds_str = field(min_transfer_time_idx);
+        if seconds_str.is_empty() || field(transfer_type_idx) == "3" || specific_idxs.iter().any(|&idx| !field(Some(idx)).is_empty()) {
+            continue;
+        }
+        let seconds = seconds_str.parse::<Timestamp>().map_err(|_| DataImportError::InvalidTransferTime(line, seconds_str.to_string()))?;
+        transfer_times.push(StopTransferTime { from_stop_id: field(Some(from_stop_idx)).to_string(), to_stop_id: field(Some(to_stop_idx)).to_string(), seconds });
+    }
+    Ok(transfer_times)
+}
+
+// As import_gtfs_transfer_times, from a zipped feed. A feed without a transfers.txt has no transfer times.
+pub fn import_zip_gtfs_transfer_times(reader: impl Read + Seek) -> Result<Vec<StopTransferTime>, DataImportError> {
+    let mut zip = zip::ZipArchive::new(reader)?;
+    let Some(transfers_name) = zip.file_names().find(|name| name.rsplit('/').next() == Some("transfers.txt")).map(str::to_string) else {
+        return Ok(Vec::new());
+    };
+    import_gtfs_transfer_times(zip.by_name(&transfers_name)?)
+}
+
+// The transfer times of feeds read from directories or zips (merge_feeds keeps stop ids, so they aren't namespaced).
+// Feeds that can't be read again (e.g. URLs) are skipped with a warning.
+pub fn gtfs_transfer_times(gtfs_paths: &[impl AsRef<Path>]) -> Vec<StopTransferTime> {
+    let mut transfer_times = Vec::new();
+    for gtfs_path in gtfs_paths.iter() {
+        let gtfs_path = gtfs_path.as_ref();
+        let feed_transfer_times = if gtfs_path.is_dir() {
+            match File::open(gtfs_path.join("transfers.txt")) {
+                Ok(file) => import_gtfs_transfer_times(file),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+                Err(err) => Err(err.into()),
+            }
+        } else {
+            File::open(gtfs_path).map_err(DataImportError::from).and_then(import_zip_gtfs_transfer_times)
+        };
+        match feed_transfer_times {
+            Ok(feed_transfer_times) => transfer_times.extend(feed_transfer_times),
+            Err(err) => log::warn!("Couldn't read the transfer times of {}: {err}", gtfs_path.display()),
+        }
+    }
+    transfer_times
+}
+
+// Great circle distance between two stops in metres, if both have a location.
+fn stop_distance(a: &Stop, b: &Stop) -> Option<f64> {
+    const EARTH_RADIUS: f64 = 6_371_000.;
+    let (lat_a, lon_a) = (a.latitude?.to_radians(), a.longitude?.to_radians());
+    let (lat_b, lon_b) = (b.latitude?.to_radians(), b.longitude?.to_radians());
+    let h = ((lat_b - lat_a) / 2.).sin().powi(2) + lat_a.cos() * lat_b.cos() * ((lon_b - lon_a) / 2.).sin().powi(2);
+    Some(2. * EARTH_RADIUS * h.sqrt().asin())
+}
+
+// Merges several feeds (e.g. trains and trams) into one, so they can be built into a single network.
+// Route, trip, service, shape and agency ids from every feed after the first are prefixed with the feed's index (e.g. "1:") to avoid collisions.
+// Stops aren't prefixed: a stop with the same id as one in an earlier feed is the same stop, as is a stop with the same name
+// within `merge_distance` metres. Changing between merged stops then uses the network's default transfer time.
+pub fn merge_feeds(feeds: Vec<(String, Gtfs)>, merge_distance: f64) -> Option<(Gtfs, Vec<FeedStats>)> {
+    let mut feeds = feeds.into_iter();
+    let (first_name, mut merged) = feeds.next()?;
+    let mut feed_stats = vec![FeedStats::new(first_name, &merged)];
+
+    let mut stops_by_name = HashMap::new();
+    for stop in merged.stops.values() {
+        stops_by_name.entry(stop.name.clone()).or_insert_with(Vec::new).push(stop.clone());
+    }
+
+    for (feed_idx, (name, feed)) in feeds.enumerate() {
+        let prefix = format!("{}:", feed_idx + 1);
+        let namespaced = |id: &str| format!("{prefix}{id}");
+        let mut stats = FeedStats::new(name, &feed);
+
+        // The stop each of this feed's stops becomes in the merged feed.
+        let mut stop_map: HashMap<String, Arc<Stop>> = HashMap::new();
+        for (stop_id, stop) in feed.stops.iter() {
+            let existing = merged.stops.get(stop_id).cloned().or_else(|| {
+                stops_by_name.get(&stop.name)?.iter().find(|existing| stop_distance(existing, stop).is_some_and(|distance| distance <= merge_distance)).cloned()
+            });
+            match existing {
+                Some(existing) => {
+                    stats.num_merged_stops += 1;
+                    stop_map.insert(stop_id.clone(), existing);
+                }
+                None => {
+                    stops_by_name.entry(stop.name.clone()).or_insert_with(Vec::new).push(stop.clone());
+                    stop_map.insert(stop_id.clone(), stop.clone());
+                }
+            }
+        }
+        for stop in stop_map.values() {
+            merged.stops.entry(stop.id.clone()).or_insert_with(|| stop.clone());
+        }
+
+        for (_, mut route) in feed.routes {
+            route.id = namespaced(&route.id);
+            route.agency_id = route.agency_id.as_deref().map(namespaced);
+            merged.routes.insert(route.id.clone(), route);
+        }
+        for (_, mut trip) in feed.trips {
+            trip.id = namespaced(&trip.id);
+            trip.route_id = namespaced(&trip.route_id);
+            trip.service_id = namespaced(&trip.service_id);
+            trip.shape_id = trip.shape_id.as_deref().map(namespaced);
+            for stop_time in trip.stop_times.iter_mut() {
+                if let Some(stop) = stop_map.get(&stop_time.stop.id) {
+                    stop_time.stop = stop.clone();
+                }
+            }
+            merged.trips.insert(trip.id.clone(), trip);
+        }
+        for (service_id, mut calendar) in feed.calendar {
+            calendar.id = namespaced(&service_id);
+            merged.calendar.insert(calendar.id.clone(), calendar);
+        }
+        for (service_id, mut calendar_dates) in feed.calendar_dates {
+            for calendar_date in calendar_dates.iter_mut() {
+                calendar_date.service_id = namespaced(&service_id);
+            }
+            merged.calendar_dates.insert(namespaced(&service_id), calendar_dates);
+        }
+        for (shape_id, mut shape) in feed.shapes {
+            for point in shape.iter_mut() {
+                point.id = namespaced(&shape_id);
+            }
+            merged.shapes.insert(namespaced(&shape_id), shape);
+        }
+        for mut agency in feed.agencies {
+            agency.id = agency.id.as_deref().map(namespaced);
+            merged.agencies.push(agency);
+        }
+
+        feed_stats.push(stats);
+    }
+
+    Some((merged, feed_stats))
+}
+
+fn runs_on_weekday(calendar: &Calendar, weekday: Weekday) -> bool {
+    match weekday {
+        Weekday::Mon => calendar.monday,
+        Weekday::Tue => calendar.tuesday,
+        Weekday::Wed => calendar.wednesday,
+        Weekday::Thu => calendar.thursday,
+        Weekday::Fri => calendar.friday,
+        Weekday::Sat => calendar.saturday,
+        Weekday::Sun => calendar.sunday,
+    }
+}
+
+// Whether a service runs on a date, using calendar.txt with exceptions from calendar_dates.txt.
+pub fn is_service_active(gtfs: &Gtfs, service_id: &str, date: NaiveDate) -> bool {
+    if let Some(calendar_date) = gtfs.calendar_dates.get(service_id).and_then(|dates| dates.iter().find(|d| d.date == date)) {
+        return matches!(calendar_date.exception_type, Exception::Added);
+    }
+    gtfs.calendar.get(service_id).is_some_and(|calendar| {
+        calendar.start_date <= date && date <= calendar.end_date && runs_on_weekday(calendar, date.weekday())
+    })
+}
+
+// The service ids that run on a date.
+pub fn active_service_ids(gtfs: &Gtfs, date: NaiveDate) -> Vec<&str> {
+    let service_ids = gtfs.calendar.keys().chain(gtfs.calendar_dates.keys()).map(|id| id.as_str()).unique();
+    service_ids.filter(|service_id| is_service_active(gtfs, service_id, date)).sorted_unstable().collect()
+}
+
+// The first and last dates covered by the feed's calendar.txt and calendar_dates.txt.
+pub fn service_date_range(gtfs: &Gtfs) -> Option<(NaiveDate, NaiveDate)> {
+    let calendar_dates = gtfs.calendar.values().flat_map(|calendar| [calendar.start_date, calendar.end_date]);
+    let exception_dates = gtfs.calendar_dates.values().flatten().map(|calendar_date| calendar_date.date);
+    calendar_dates.chain(exception_dates).minmax().into_option()
+}
+
+// The number of trips that run on a date.
+pub fn count_active_trips(gtfs: &Gtfs, date: NaiveDate) -> usize {
+    let active_service_ids = active_service_ids(gtfs, date).into_iter().collect::<std::collections::HashSet<_>>();
+    gtfs.trips.values().filter(|trip| active_service_ids.contains(trip.service_id.as_str())).count()
+}
+
+// The dates in the feed's range closest to the given date with any service, nearest first.
+// With weekdays_only, weekends are skipped.
+pub fn nearest_service_dates(gtfs: &Gtfs, date: NaiveDate, count: usize, weekdays_only: bool) -> Vec<NaiveDate> {
+    let Some((first, last)) = service_date_range(gtfs) else {
+        return Vec::new();
+    };
+
+    let mut dates = Vec::with_capacity(count);
+    let max_offset = (last - first).num_days().max((date - first).num_days().abs()).max((last - date).num_days().abs());
+    for offset in 1..=max_offset {
+        for candidate in [date - chrono::Days::new(offset as u64), date + chrono::Days::new(offset as u64)] {
+            let is_weekend = matches!(candidate.weekday(), Weekday::Sat | Weekday::Sun);
+            if dates.len() < count && first <= candidate && candidate <= last && !(weekdays_only && is_weekend) && !active_service_ids(gtfs, candidate).is_empty() {
+                dates.push(candidate);
+            }
+        }
+        if dates.len() >= count {
+            break;
+        }
+    }
+    dates
+}
+
+// Picks the year for a day and month, preferring a year in the feed's range where that date has service.
+pub fn infer_service_year(gtfs: &Gtfs, day: u32, month: u32) -> Option<NaiveDate> {
+    let (first, last) = service_date_range(gtfs)?;
+    let candidates = (first.year()..=last.year()).filter_map(|year| NaiveDate::from_ymd_opt(year, month, day)).collect_vec();
+    candidates.iter()
+              .find(|&&date| !active_service_ids(gtfs, date).is_empty())
+              .or_else(|| candidates.iter().find(|&&date| first <= date && date <= last))
+              .or(candidates.first())
+              .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacities_are_imported() {
+        let capacities = import_trip_capacities("trip_id,seated,standing\nT1,400,200\nT2,100,0\n".as_bytes()).unwrap();
+        assert_eq!(capacities.len(), 2);
+        assert_eq!((capacities["T1"].seated, capacities["T1"].standing), (400, 200));
+        assert_eq!((capacities["T2"].seated, capacities["T2"].standing), (100, 0));
+    }
+
+    #[test]
+    fn invalid_capacities_report_their_line() {
+        for (csv, expected_line, expected_value) in [
+            ("route_id,seated,standing\nR1,400,200\nR2,many,200\n", 3, "many"),
+            ("route_id,seated,standing\nR1,0,200\n", 2, "0"),
+            ("route_id,seated,standing\nR1,400,200\nR2,400,200\nR3,400,-1\n", 4, "-1"),
+            ("route_id,seated,standing\nR1,400,\n", 2, ""),
+        ] {
+            match import_route_capacities(csv.as_bytes()) {
+                Err(DataImportError::InvalidCapacity(line, value)) => assert_eq!((line, &value[..]), (expected_line, expected_value), "{csv:?}"),
+                Err(err) => panic!("{csv:?}: unexpected error {err}"),
+                Ok(_) => panic!("{csv:?}: expected an error"),
+            }
+        }
+    }
+
+    #[test]
+    fn missing_capacity_columns_are_named() {
+        assert!(matches!(import_trip_capacities("trip_id,seats,standing\nT1,400,200\n".as_bytes()), Err(DataImportError::ColumnNotFound("seated"))));
+        assert!(matches!(import_trip_capacities("trip_id,seated,standing\n".as_bytes()), Err(DataImportError::NoData)));
+    }
+
+    #[test]
+    fn only_routes_without_a_colour_are_uncoloured() {
+        let routes = "route_id,route_short_name,route_color\nMISSING,Missing\nEMPTY,Empty,\nBLANK,Blank, \nBLACK,Black,000000\nRED,Red,CC0000\n";
+        let route_ids = import_routes_without_colour(routes.as_bytes()).unwrap();
+        assert_eq!(route_ids, HashSet::from(["MISSING", "EMPTY", "BLANK"].map(String::from)));
+
+        let route_ids = import_routes_without_colour("route_id,route_short_name\nR1,One\n".as_bytes()).unwrap();
+        assert_eq!(route_ids, HashSet::from(["R1".to_string()]));
+        assert!(matches!(import_routes_without_colour("agency_id\nA1\n".as_bytes()), Err(DataImportError::ColumnNotFound("route_id"))));
+    }
+
+    #[test]
+    fn only_stop_to_stop_gtfs_transfer_times_are_read() {
+        let transfers = "from_stop_id,to_stop_id,transfer_type,min_transfer_time,from_trip_id\n\
+                         A,A,2,300,\n\
+                         A,B,2,240,\n\
+                         B,C,3,,\n\
+                         B,B,0,,\n\
+                         C,C,2,600,T1\n\
+                         C,D,1,60,\n";
+        let transfer_times = import_gtfs_transfer_times(transfers.as_bytes()).unwrap();
+        let times = transfer_times.iter().map(|time| (&time.from_stop_id[..], &time.to_stop_id[..], time.seconds)).collect_vec();
+        assert_eq!(times, [("A", "A", 300), ("A", "B", 240), ("C", "D", 60)]);
+
+        assert!(matches!(import_gtfs_transfer_times("from_stop_id,to_stop_id,min_transfer_time\nA,A,soon\n".as_bytes()), Err(DataImportError::InvalidTransferTime(2, _))));
+        assert!(matches!(import_transfer_times("from_stop_id,to_stop_id,minutes\nA,A,5\n".as_bytes()), Err(DataImportError::ColumnNotFound("seconds"))));
+        assert_eq!(import_transfer_times("from_stop_id,to_stop_id,seconds\nA,B,90\n".as_bytes()).unwrap(), [StopTransferTime { from_stop_id: "A".into(), to_stop_id: "B".into(), seconds: 90 }]);
+    }
+
+    #[test]
+    fn platforms_collapse_to_their_station() {
+        use crate::simulation::run_simulation;
+        use crate::test_utils::*;
+
+        let mut gtfs = load_fixture_gtfs("platforms");
+        let alpha = gtfs.stops["ALP"].clone();
+        let parent_stations = collapse_to_parent_stations(&mut gtfs);
+        assert_eq!(parent_stations.station_ids.iter().map(|(stop_id, station_id)| (&stop_id[..], &station_id[..])).sorted().collect_vec(), [("CHA_1", "CHA"), ("CHA_2", "CHA")]);
+        assert_eq!(parent_stations.children(), HashMap::from([("CHA", vec!["CHA_1", "CHA_2"])]));
+        assert_eq!((parent_stations.station_id("CHA_2"), parent_stations.station_id("ALP")), ("CHA", "ALP"));
+
+        // The platforms are gone and the trips call at the station, while stops without a parent are untouched.
+        assert_eq!(gtfs.stops.keys().map(String::as_str).sorted().collect_vec(), ["ALP", "CHA", "DEL"]);
+        assert!(Arc::ptr_eq(&gtfs.stops["ALP"], &alpha));
+        let trip_stops = |trip_id: &str| gtfs.trips[trip_id].stop_times.iter().map(|stop_time| stop_time.stop.id.clone()).collect_vec();
+        assert_eq!(trip_stops("RED_0800"), ["ALP", "CHA"]);
+        assert_eq!(trip_stops("BLUE_0812"), ["CHA", "DEL"]);
+
+        let network = build_fixture_network(&gtfs);
+        assert_eq!(network.stops.iter().map(|stop| -> &str { stop.id.as_ref() }).sorted().collect_vec(), ["ALP", "CHA", "DEL"]);
+
+        // OD rows by station id resolve to the station, and the platforms' ids are no longer stops.
+        let od = "origin_stop_id,destination_stop_id,departure_time,count\nCHA,DEL,08:15:00,2\n";
+        let steps = load_od_matrix(od.as_bytes(), &network, Some(1), 1., &HashSet::new(), None).unwrap();
+        assert_eq!(steps.len(), 1);
+        assert_eq!(steps[0].origin_stop, stop_idx(&network, "CHA"));
+        assert_eq!(steps[0].destinations().collect_vec(), [(stop_idx(&network, "DEL"), 2)]);
+        let od = "origin_stop_id,destination_stop_id,departure_time,count\nCHA_1,DEL,08:15:00,2\n";
+        assert!(matches!(load_od_matrix(od.as_bytes(), &network, Some(1), 1., &HashSet::new(), None), Err(DataImportError::UnknownStop(2, stop_id)) if stop_id == "CHA_1"));
+
+        // Changing from platform 1 to platform 2 is a transfer within the station, which takes the default transfer time,
+        // so the two minutes to the 08:12 aren't enough.
+        let simulation_result = run_simulation(&network, &[simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "DEL", 3)], &fixture_params(1));
+        let journey = simulation_result.round_agent_journeys[0].get(0).result.unwrap();
+        assert_eq!(journey.legs.iter().map(|leg| network.get_trip_id(leg.trip).to_string()).collect_vec(), ["RED_0800", "BLUE_0820"]);
+        assert_eq!((journey.legs[0].arrival_stop, journey.legs[1].boarded_stop), (stop_idx(&network, "CHA"), stop_idx(&network, "CHA")));
+    }
+}
@@ -0,0 +1,399 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use itertools::Itertools;
+use rand::prelude::*;
+use raptor::network::{StopIndex, Timestamp};
+use raptor::Network;
+use rayon::prelude::*;
+
+use crate::simulation::{AgentCount, PartySizes, SimulationStep};
+
+// How demand between two stops falls off with the travel time between them (in minutes).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase", tag = "func", content = "params"))]
+pub enum Deterrence {
+    // e^(-beta * t)
+    Exponential { beta: f64 },
+    // t^(-exponent), with t at least one minute.
+    Power { exponent: f64 },
+    // t^alpha * e^(-beta * t), with t at least one minute. Peaks at alpha / beta minutes, so very short trips are rare too.
+    Combined { alpha: f64, beta: f64 },
+}
+
+impl Deterrence {
+    pub fn factor(&self, minutes: f64) -> f64 {
+        match self {
+            Deterrence::Exponential { beta } => (-beta * minutes).exp(),
+            Deterrence::Power { exponent } => minutes.max(1.).powf(-exponent),
+            Deterrence::Combined { alpha, beta } => minutes.max(1.).powf(*alpha) * (-beta * minutes).exp(),
+        }
+    }
+
+    // Returns a description of the problem if a parameter is negative or not a number.
+    pub fn validate(&self) -> Result<(), String> {
+        let params = match self {
+            Deterrence::Exponential { beta } => vec![("beta", *beta)],
+            Deterrence::Power { exponent } => vec![("exponent", *exponent)],
+            Deterrence::Combined { alpha, beta } => vec![("alpha", *alpha), ("beta", *beta)],
+        };
+        match params.into_iter().find(|(_, value)| !value.is_finite() || *value < 0.) {
+            Some((name, value)) => Err(format!("{name} ({value}) must be a non-negative number")),
+            None => Ok(()),
+        }
+    }
+}
+
+// A normally distributed peak in departures.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct PeakPeriod {
+    // Time of the peak, in seconds after midnight.
+    pub centre: Timestamp,
+    // Standard deviation, in seconds.
+    pub spread: Timestamp,
+    // Proportion of the day's agents departing in this peak.
+    pub share: f64,
+}
+
+// When agents depart: a mix of peaks, with the remaining share spread evenly between the start and end times.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct DepartureProfile {
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    pub peaks: Vec<PeakPeriod>,
+}
+
+impl Default for DepartureProfile {
+    // Same start as `gen_simulation_steps`, with a sharp AM peak and a broader PM peak. It ends at midnight, so departures
+    // after it need an end_time past 86400.
+    fn default() -> Self {
+        Self {
+            start_time: 4 * 60 * 60,
+            end_time: 24 * 60 * 60,
+            peaks: vec![
+                PeakPeriod { centre: 8 * 60 * 60 + 15 * 60, spread: 45 * 60, share: 0.35 },
+                PeakPeriod { centre: 17 * 60 * 60 + 30 * 60, spread: 60 * 60, share: 0.3 },
+            ],
+        }
+    }
+}
+
+impl DepartureProfile {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_time >= self.end_time {
+            return Err(format!("start_time ({}) must be before end_time ({})", self.start_time, self.end_time));
+        }
+        if let Some(peak) = self.peaks.iter().find(|peak| peak.spread == 0 || !peak.share.is_finite() || peak.share < 0.) {
+            return Err(format!("{peak:?} must have a positive spread and non-negative share"));
+        }
+        let total_share = self.peaks.iter().map(|peak| peak.share).sum::<f64>();
+        if total_share > 1. {
+            return Err(format!("peak shares add up to {total_share}, which is more than 1"));
+        }
+        Ok(())
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Timestamp {
+        let (start_time, end_time) = (self.start_time as f64, self.end_time as f64);
+        let mut choice = rng.gen::<f64>();
+        for peak in self.peaks.iter() {
+            if choice >= peak.share {
+                choice -= peak.share;
+                continue;
+            }
+            // Box-Muller, redrawing times outside the day (which is rare unless the peak is near the start or end).
+            for _ in 0..100 {
+                let uniform = rng.gen_range(f64::EPSILON..1.);
+                let normal = (-2. * uniform.ln()).sqrt() * (std::f64::consts::TAU * rng.gen::<f64>()).cos();
+                let time = peak.centre as f64 + normal * peak.spread as f64;
+                if (start_time..end_time).contains(&time) {
+                    return time as Timestamp;
+                }
+            }
+            return peak.centre.clamp(self.start_time, self.end_time - 1);
+        }
+        rng.gen_range(self.start_time..self.end_time)
+    }
+}
+
+// A time bin of a binned departure profile.
+#[derive(Clone, Copy, Debug)]
+pub struct DepartureBin {
+    pub start_time: Timestamp,
+    pub end_time: Timestamp,
+    pub weight: f64,
+}
+
+// Departures falling in each bin in proportion to its weight, spread evenly within the bin (see `data_import::import_departure_profile`).
+#[derive(Clone, Debug)]
+pub struct BinnedProfile {
+    // Sorted by start time, without overlaps.
+    pub bins: Vec<DepartureBin>,
+}
+
+impl BinnedProfile {
+    fn sample(&self, rng: &mut impl Rng) -> Timestamp {
+        let total_weight = self.bins.iter().map(|bin| bin.weight).sum::<f64>();
+        let mut choice = rng.gen::<f64>() * total_weight;
+        for bin in self.bins.iter() {
+            if choice < bin.weight {
+                return rng.gen_range(bin.start_time..bin.end_time);
+            }
+            choice -= bin.weight;
+        }
+        // Only reached through rounding, so use the last bin with any departures.
+        let bin = self.bins.iter().rev().find(|bin| bin.weight > 0.).expect("Binned profile has no weight");
+        rng.gen_range(bin.start_time..bin.end_time)
+    }
+}
+
+// Where agent departure times are drawn from.
+#[derive(Clone, Debug)]
+pub enum DepartureSampler {
+    Peaks(DepartureProfile),
+    Bins(BinnedProfile),
+}
+
+impl DepartureSampler {
+    pub fn sample(&self, rng: &mut impl Rng) -> Timestamp {
+        match self {
+            DepartureSampler::Peaks(profile) => profile.sample(rng),
+            DepartureSampler::Bins(profile) => profile.sample(rng),
+        }
+    }
+
+    // Length of the profile's day in seconds, from the first possible departure to the last.
+    pub fn day_length(&self) -> Timestamp {
+        match self {
+            DepartureSampler::Peaks(profile) => profile.end_time - profile.start_time,
+            DepartureSampler::Bins(profile) => match (profile.bins.first(), profile.bins.last()) {
+                (Some(first), Some(last)) => last.end_time - first.start_time,
+                _ => 0,
+            },
+        }
+    }
+}
+
+// Generates `number` random agents (one every second of the profile's day if not set) like `simulation::gen_simulation_steps`,
+// but with departure times drawn from the profile instead of spaced evenly.
+pub fn gen_profiled_simulation_steps(network: &Network, number: Option<usize>, departures: &DepartureSampler, seed: Option<u64>, party_sizes: Option<&PartySizes>) -> Vec<SimulationStep> {
+    let num_stops = network.num_stops() as StopIndex;
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    };
+
+    let number = number.unwrap_or(departures.day_length() as usize);
+    let mut simulation_steps = (0..number).map(|_| {
+        let mut simulation_step = SimulationStep::new(departures.sample(&mut rng), rng.gen_range(0..num_stops));
+        let dest_stop = rng.gen_range(0..num_stops);
+        PartySizes::push_agents(party_sizes, &mut simulation_step, dest_stop, &mut rng);
+        simulation_step
+    }).collect_vec();
+
+    // Sort so the simulation (and replanning selection) is deterministic.
+    simulation_steps.sort_by_key(|step| step.departure_time);
+    simulation_steps
+}
+
+// Synthetic demand for when there's no OD matrix: trips between each pair of stops in proportion to
+// weight(origin) * weight(destination) * deterrence(travel time), departing according to the profile (unless the run
+// configures a departure profile, which takes precedence).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct GravityModel {
+    pub deterrence: Deterrence,
+    pub profile: DepartureProfile,
+}
+
+impl Default for GravityModel {
+    // Demand halves about every 14 minutes of travel time.
+    fn default() -> Self {
+        Self { deterrence: Deterrence::Exponential { beta: 0.05 }, profile: DepartureProfile::default() }
+    }
+}
+
+// The shortest in-vehicle time between every pair of consecutive stops on any route.
+// This ignores waiting and transfers, so it's only an estimate of how far apart stops are.
+pub(crate) fn travel_time_graph(network: &Network) -> Vec<Vec<(StopIndex, Timestamp)>> {
+    let mut graph = vec![Vec::new(); network.num_stops()];
+    for route_idx in 0..network.num_routes() {
+        let route = &network.routes[route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        for (stop_order, (&from_stop, &to_stop)) in stops.iter().tuple_windows().enumerate() {
+            let min_time = (0..route.num_trips as usize).map(|trip| {
+                network.get_arrival_time(route_idx, trip, stop_order + 1).saturating_sub(network.get_departure_time(route_idx, trip, stop_order))
+            }).min();
+            if let Some(min_time) = min_time {
+                graph[from_stop as usize].push((to_stop, min_time));
+            }
+        }
+    }
+    graph
+}
+
+// Dijkstra's algorithm from the origin. Unreachable stops are None.
+pub(crate) fn travel_times_from(graph: &[Vec<(StopIndex, Timestamp)>], origin: StopIndex) -> Vec<Option<Timestamp>> {
+    let mut travel_times = vec![None; graph.len()];
+    let mut queue = BinaryHeap::from([Reverse((0, origin))]);
+    while let Some(Reverse((time, stop))) = queue.pop() {
+        if travel_times[stop as usize].is_some() {
+            continue;
+        }
+        travel_times[stop as usize] = Some(time);
+        for &(next_stop, edge_time) in graph[stop as usize].iter() {
+            if travel_times[next_stop as usize].is_none() {
+                queue.push(Reverse((time + edge_time, next_stop)));
+            }
+        }
+    }
+    travel_times
+}
+
+// Unnormalised trips from the origin to every stop.
+fn gravity_row(graph: &[Vec<(StopIndex, Timestamp)>], weights: &[f64], origin: StopIndex, deterrence: &Deterrence) -> Vec<f64> {
+    let origin_weight = weights[origin as usize];
+    if origin_weight == 0. {
+        return vec![0.; weights.len()];
+    }
+    travel_times_from(graph, origin).into_iter().zip(weights).enumerate().map(|(dest, (travel_time, &dest_weight))| {
+        match travel_time {
+            Some(travel_time) if dest != origin as usize => origin_weight * dest_weight * deterrence.factor(travel_time as f64 / 60.),
+            _ => 0.,
+        }
+    }).collect()
+}
+
+// Splits the total in proportion to the weights using the largest remainder method, so the parts add up to the total exactly.
+fn apportion(total: usize, weights: &[f64]) -> Vec<usize> {
+    let weight_sum = weights.iter().sum::<f64>();
+    if weight_sum <= 0. {
+        return vec![0; weights.len()];
+    }
+    let quotas = weights.iter().map(|&weight| total as f64 * weight / weight_sum).collect_vec();
+    let mut parts = quotas.iter().map(|&quota| quota.floor() as usize).collect_vec();
+    let remainder = total.saturating_sub(parts.iter().sum());
+    let by_fraction = (0..weights.len()).filter(|&i| weights[i] > 0.).sorted_unstable_by(|&a, &b| (quotas[b] - quotas[b].floor()).total_cmp(&(quotas[a] - quotas[a].floor())));
+    for i in by_fraction.cycle().take(remainder) {
+        parts[i] += 1;
+    }
+    parts
+}
+
+// Generates `num_agents` agents (one every second of the profile's day if not set) with a gravity model over stop weights
+// (see `data_import::import_stop_weights`). OD flows are rounded so they add up to exactly the number of agents, and only
+// the departure times are random.
+pub fn gen_gravity_simulation_steps(network: &Network, weights: &[f64], num_agents: Option<usize>, deterrence: &Deterrence, departures: &DepartureSampler, seed: Option<u64>) -> Vec<SimulationStep> {
+    assert_eq!(weights.len(), network.num_stops(), "There must be a weight for every stop");
+    let num_agents = num_agents.unwrap_or(departures.day_length() as usize);
+    let seed = seed.unwrap_or_else(|| SmallRng::from_entropy().gen());
+
+    let graph = travel_time_graph(network);
+    let origin_totals = (0..weights.len() as StopIndex).into_par_iter().map(|origin| {
+        gravity_row(&graph, weights, origin, deterrence).iter().sum::<f64>()
+    }).collect::<Vec<_>>();
+    if origin_totals.iter().all(|&total| total == 0.) {
+        log::warn!("No stops with a weight can reach each other, so the gravity model generated no agents.");
+        return Vec::new();
+    }
+    let origin_counts = apportion(num_agents, &origin_totals);
+
+    let mut simulation_steps = origin_counts.par_iter().enumerate().filter(|&(_, &count)| count > 0).flat_map_iter(|(origin, &count)| {
+        let origin = origin as StopIndex;
+        let dest_counts = apportion(count, &gravity_row(&graph, weights, origin, deterrence));
+        let mut rng = SmallRng::seed_from_u64(seed ^ origin as u64);
+        let agents = dest_counts.iter().enumerate().flat_map(|(dest, &dest_count)| {
+            std::iter::repeat(dest as StopIndex).take(dest_count)
+        }).map(|dest| (departures.sample(&mut rng), dest)).sorted_unstable().collect_vec();
+
+        // Agents from this origin departing at the same time share a simulation step.
+        agents.into_iter().chunk_by(|&(departure_time, _)| departure_time).into_iter().map(|(departure_time, agents)| {
+            let mut simulation_step = SimulationStep::new(departure_time, origin);
+            for (dest, agents) in agents.chunk_by(|&(_, dest)| dest).into_iter() {
+                simulation_step.push(dest, agents.count() as AgentCount);
+            }
+            simulation_step
+        }).collect_vec()
+    }).collect::<Vec<_>>();
+
+    // Sort so the simulation (and replanning selection) is deterministic.
+    simulation_steps.sort_unstable_by_key(|step| (step.departure_time, step.origin_stop));
+
+    let num_generated = simulation_steps.iter().map(|step| step.count() as usize).sum::<usize>();
+    assert_eq!(num_generated, num_agents, "The gravity model generated {num_generated} agents instead of {num_agents}");
+    log::info!("Gravity model generated {num_agents} agents in {} simulation steps.", simulation_steps.len());
+
+    simulation_steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn apportioned_parts_add_up() {
+        for total in [0, 1, 7, 100, 1001] {
+            for weights in [vec![1., 1., 1.], vec![0.2, 0.5, 0., 0.3], vec![1e-9, 1., 3.]] {
+                let parts = apportion(total, &weights);
+                assert_eq!(parts.iter().sum::<usize>(), total, "{total} split by {weights:?}");
+                for (&part, &weight) in parts.iter().zip(&weights) {
+                    let quota = total as f64 * weight / weights.iter().sum::<f64>();
+                    assert!((part as f64 - quota).abs() < 1., "{part} is not the quota {quota} rounded");
+                }
+            }
+        }
+        assert_eq!(apportion(10, &[0., 0.]), [0, 0]);
+    }
+
+    #[test]
+    fn gravity_model_generates_the_requested_agents() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut weights = vec![1.; network.num_stops()];
+        weights[stop_idx(&network, "BRA") as usize] = 0.;
+        weights[stop_idx(&network, "ECH") as usize] = 4.;
+        let profile = DepartureProfile::default();
+        let departures = DepartureSampler::Peaks(profile.clone());
+
+        for num_agents in [1, 97, 1000] {
+            let simulation_steps = gen_gravity_simulation_steps(&network, &weights, Some(num_agents), &Deterrence::Exponential { beta: 0.05 }, &departures, Some(0));
+            assert_eq!(simulation_steps.iter().map(|step| step.count() as usize).sum::<usize>(), num_agents);
+            for simulation_step in simulation_steps.iter() {
+                assert!((profile.start_time..profile.end_time).contains(&simulation_step.departure_time));
+                assert_ne!(simulation_step.origin_stop, stop_idx(&network, "BRA"));
+                assert!(simulation_step.destinations().all(|(dest_stop, _)| dest_stop != stop_idx(&network, "BRA") && dest_stop != simulation_step.origin_stop));
+            }
+        }
+
+        // Echo is the only destination from Delta, and has four times the weight of Delta as a destination from Charlie.
+        let simulation_steps = gen_gravity_simulation_steps(&network, &weights, Some(1000), &Deterrence::Exponential { beta: 0. }, &departures, Some(0));
+        let agents_between = |origin: &str, dest: &str| simulation_steps.iter()
+            .filter(|step| step.origin_stop == stop_idx(&network, origin))
+            .flat_map(|step| step.destinations())
+            .filter(|&(dest_stop, _)| dest_stop == stop_idx(&network, dest))
+            .map(|(_, count)| count)
+            .sum::<AgentCount>();
+        assert_eq!(agents_between("DEL", "ALP"), 0);
+        assert!(agents_between("DEL", "ECH") > 0);
+        let (to_delta, to_echo) = (agents_between("CHA", "DEL"), agents_between("CHA", "ECH"));
+        assert!((to_echo as f64 / to_delta as f64 - 4.).abs() < 0.1, "{to_echo} to Echo, {to_delta} to Delta");
+    }
+
+    #[test]
+    fn deterrence_falls_with_travel_time() {
+        for deterrence in [Deterrence::Exponential { beta: 0.05 }, Deterrence::Power { exponent: 2. }, Deterrence::Combined { alpha: 0.5, beta: 0.1 }] {
+            deterrence.validate().unwrap();
+            // The combined function peaks at alpha / beta minutes.
+            let factors = (5..120).map(|minutes| deterrence.factor(minutes as f64)).collect_vec();
+            assert!(factors.windows(2).all(|w| w[1] <= w[0]), "{deterrence:?}");
+        }
+        assert!(Deterrence::Exponential { beta: -1. }.validate().is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+// Give up on a download that stalls for this long.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(thiserror::Error, Debug)]
+pub enum DownloadError {
+    #[error("Downloading {0} failed with HTTP status {1}")]
+    Status(String, u16),
+    #[error("Downloading {0} failed: {1}")]
+    Transport(String, String),
+    #[error("IO error while downloading: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+// True if the GTFS path should be downloaded rather than read from disk.
+pub fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("http://") || path.starts_with("https://"))
+}
+
+// Validators from the last download, so the server can tell us if the feed hasn't changed.
+#[derive(Default)]
+struct CacheValidators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    fn read(path: &Path) -> Self {
+        let mut validators = Self::default();
+        for line in fs::read_to_string(path).unwrap_or_default().lines() {
+            match line.split_once(": ") {
+                Some(("etag", etag)) => validators.etag = Some(etag.to_owned()),
+                Some(("last-modified", last_modified)) => validators.last_modified = Some(last_modified.to_owned()),
+                _ => {}
+            }
+        }
+        validators
+    }
+
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut contents = String::new();
+        if let Some(etag) = &self.etag {
+            contents.push_str(&format!("etag: {etag}\n"));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            contents.push_str(&format!("last-modified: {last_modified}\n"));
+        }
+        fs::write(path, contents)
+    }
+}
+
+// Downloads a GTFS feed into the cache directory (named by a hash of the URL) and returns the cached file.
+// A cached copy is only downloaded again if the server reports it has changed, or `refresh` is set.
+pub fn fetch_gtfs(url: &str, cache_dir: &Path, refresh: bool) -> Result<PathBuf, DownloadError> {
+    fs::create_dir_all(cache_dir)?;
+    let url_hash = Sha256::digest(url.as_bytes()).iter().take(8).map(|byte| format!("{byte:02x}")).collect::<String>();
+    let cache_path = cache_dir.join(format!("{url_hash}.zip"));
+    let validators_path = cache_dir.join(format!("{url_hash}.validators"));
+
+    let agent = ureq::AgentBuilder::new().timeout(DOWNLOAD_TIMEOUT).build();
+    let mut request = agent.get(url);
+    if cache_path.exists() && !refresh {
+        let validators = CacheValidators::read(&validators_path);
+        if let Some(etag) = &validators.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.set("If-Modified-Since", last_modified);
+        }
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => return Err(DownloadError::Status(url.to_owned(), status)),
+        Err(ureq::Error::Transport(transport)) => return Err(DownloadError::Transport(url.to_owned(), transport.to_string())),
+    };
+    if response.status() == 304 {
+        log::info!("Cached GTFS {} is up to date.", cache_path.display());
+        return Ok(cache_path);
+    }
+
+    log::info!("Downloading GTFS from {url}.");
+    let validators = CacheValidators {
+        etag: response.header("ETag").map(str::to_owned),
+        last_modified: response.header("Last-Modified").map(str::to_owned),
+    };
+    // Download to a temporary file, so a failed download doesn't replace a good cached copy.
+    let partial_path = cache_path.with_extension("zip.partial");
+    let mut writer = BufWriter::new(File::create(&partial_path)?);
+    let num_bytes = std::io::copy(&mut response.into_reader(), &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    fs::rename(&partial_path, &cache_path)?;
+    validators.write(&validators_path)?;
+    log::info!("Downloaded {num_bytes} bytes to {}.", cache_path.display());
+
+    Ok(cache_path)
+}
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rayon::prelude::*;
+use raptor::network::{GlobalTripIndex, StopIndex, Timestamp};
+use raptor::Network;
+
+use crate::data_export::{json_string, DataExportError};
+use crate::simulation::{AgentCount, SimulationResult};
+
+// Something that happened to an agent in the final round of the simulation.
+// Agents are identified by their index in the round's journeys, and each event applies to all `count` people of the agent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimulationEvent {
+    AgentDeparted { agent: u32, stop: StopIndex, time: Timestamp, count: AgentCount },
+    Boarded { agent: u32, trip: GlobalTripIndex, stop: StopIndex, time: Timestamp, count: AgentCount },
+    Alighted { agent: u32, trip: GlobalTripIndex, stop: StopIndex, time: Timestamp, count: AgentCount },
+    // Starts when alighting, and ends at the stop the next trip is boarded from once the transfer time has passed.
+    TransferStarted { agent: u32, stop: StopIndex, time: Timestamp, count: AgentCount },
+    TransferEnded { agent: u32, stop: StopIndex, time: Timestamp, count: AgentCount },
+    AgentArrived { agent: u32, stop: StopIndex, time: Timestamp, count: AgentCount },
+    // Only emitted when capacity is strict.
+    BoardingDenied { agent: u32, trip: GlobalTripIndex, stop: StopIndex, time: Timestamp, count: AgentCount },
+}
+
+// Names and fields of each event type, as written by NdjsonEventSink.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct EventSchema {
+    pub name: &'static str,
+    pub fields: &'static [&'static str],
+}
+
+pub const EVENT_SCHEMA: &[EventSchema] = &[
+    EventSchema { name: "AgentDeparted", fields: &["agent", "stop_id", "time", "count"] },
+    EventSchema { name: "Boarded", fields: &["agent", "trip_id", "stop_id", "time", "count"] },
+    EventSchema { name: "Alighted", fields: &["agent", "trip_id", "stop_id", "time", "count"] },
+    EventSchema { name: "TransferStarted", fields: &["agent", "stop_id", "time", "count"] },
+    EventSchema { name: "TransferEnded", fields: &["agent", "stop_id", "time", "count"] },
+    EventSchema { name: "AgentArrived", fields: &["agent", "stop_id", "time", "count"] },
+    EventSchema { name: "BoardingDenied", fields: &["agent", "trip_id", "stop_id", "time", "count"] },
+];
+
+impl SimulationEvent {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            SimulationEvent::AgentDeparted { .. } => "AgentDeparted",
+            SimulationEvent::Boarded { .. } => "Boarded",
+            SimulationEvent::Alighted { .. } => "Alighted",
+            SimulationEvent::TransferStarted { .. } => "TransferStarted",
+            SimulationEvent::TransferEnded { .. } => "TransferEnded",
+            SimulationEvent::AgentArrived { .. } => "AgentArrived",
+            SimulationEvent::BoardingDenied { .. } => "BoardingDenied",
+        }
+    }
+
+    pub fn time(&self) -> Timestamp {
+        match *self {
+            SimulationEvent::AgentDeparted { time, .. }
+            | SimulationEvent::Boarded { time, .. }
+            | SimulationEvent::Alighted { time, .. }
+            | SimulationEvent::TransferStarted { time, .. }
+            | SimulationEvent::TransferEnded { time, .. }
+            | SimulationEvent::AgentArrived { time, .. }
+            | SimulationEvent::BoardingDenied { time, .. } => time,
+        }
+    }
+
+    pub fn agent(&self) -> u32 {
+        match *self {
+            SimulationEvent::AgentDeparted { agent, .. }
+            | SimulationEvent::Boarded { agent, .. }
+            | SimulationEvent::Alighted { agent, .. }
+            | SimulationEvent::TransferStarted { agent, .. }
+            | SimulationEvent::TransferEnded { agent, .. }
+            | SimulationEvent::AgentArrived { agent, .. }
+            | SimulationEvent::BoardingDenied { agent, .. } => agent,
+        }
+    }
+}
+
+// Receives the simulation events in time order.
+pub trait EventSink {
+    fn emit(&mut self, event: &SimulationEvent) -> Result<(), DataExportError>;
+    // Called once every event has been emitted.
+    fn finish(&mut self) -> Result<(), DataExportError> { Ok(()) }
+}
+
+// Writes one JSON object per line, with the fields given by EVENT_SCHEMA.
+pub struct NdjsonEventSink<'a, W: Write> {
+    network: &'a Network,
+    writer: W,
+}
+
+impl<'a, W: Write> NdjsonEventSink<'a, W> {
+    pub fn new(network: &'a Network, writer: W) -> Self {
+        Self { network, writer }
+    }
+}
+
+impl<W: Write> EventSink for NdjsonEventSink<'_, W> {
+    fn emit(&mut self, event: &SimulationEvent) -> Result<(), DataExportError> {
+        let stop_id = |stop: StopIndex| json_string(&self.network.stops[stop as usize].id);
+        let trip_id = |trip: GlobalTripIndex| json_string(self.network.get_trip_id(trip));
+        let name = event.get_name();
+        match *event {
+            SimulationEvent::AgentDeparted { agent, stop, time, count }
+            | SimulationEvent::TransferStarted { agent, stop, time, count }
+            | SimulationEvent::TransferEnded { agent, stop, time, count }
+            | SimulationEvent::AgentArrived { agent, stop, time, count } => {
+                writeln!(self.writer, r#"{{"type":"{name}","agent":{agent},"stop_id":{},"time":{time},"count":{count}}}"#, stop_id(stop))?;
+            }
+            SimulationEvent::Boarded { agent, trip, stop, time, count }
+            | SimulationEvent::Alighted { agent, trip, stop, time, count }
+            | SimulationEvent::BoardingDenied { agent, trip, stop, time, count } => {
+                writeln!(self.writer, r#"{{"type":"{name}","agent":{agent},"trip_id":{},"stop_id":{},"time":{time},"count":{count}}}"#, trip_id(trip), stop_id(stop))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), DataExportError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Escapes a string for use in an XML attribute.
+fn xml_attribute(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Writes a MATSim events file. Each agent of `count` people becomes `count` persons with ids <agent>_<n>, trips are the
+// vehicles and stops are the links. Times are seconds past midnight of the service day, so trips after midnight are past 86400.
+// Transfers and denied boardings have no MATSim equivalent here, so aren't written.
+pub struct MatsimEventSink<'a, W: Write> {
+    network: &'a Network,
+    writer: W,
+}
+
+impl<'a, W: Write> MatsimEventSink<'a, W> {
+    pub fn new(network: &'a Network, mut writer: W) -> Result<Self, DataExportError> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+        writeln!(writer, r#"<events version="1.0">"#)?;
+        Ok(Self { network, writer })
+    }
+
+    // Returns the writer, e.g. to finish a compressed stream once the closing tag has been written.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> EventSink for MatsimEventSink<'_, W> {
+    fn emit(&mut self, event: &SimulationEvent) -> Result<(), DataExportError> {
+        let link = |stop: StopIndex| xml_attribute(&self.network.stops[stop as usize].id);
+        let vehicle = |trip: GlobalTripIndex| xml_attribute(self.network.get_trip_id(trip));
+        let (agent, count) = match *event {
+            SimulationEvent::AgentDeparted { agent, count, .. }
+            | SimulationEvent::Boarded { agent, count, .. }
+            | SimulationEvent::Alighted { agent, count, .. }
+            | SimulationEvent::AgentArrived { agent, count, .. } => (agent, count),
+            SimulationEvent::TransferStarted { .. } | SimulationEvent::TransferEnded { .. } | SimulationEvent::BoardingDenied { .. } => return Ok(()),
+        };
+        let time = event.time();
+        for person in 0..count {
+            match *event {
+                SimulationEvent::AgentDeparted { stop, .. } => {
+                    let link = link(stop);
+                    writeln!(self.writer, r#"  <event time="{time}.0" type="actend" person="{agent}_{person}" link="{link}" actType="origin"/>"#)?;
+                    writeln!(self.writer, r#"  <event time="{time}.0" type="departure" person="{agent}_{person}" link="{link}" legMode="pt"/>"#)?;
+                }
+                SimulationEvent::Boarded { trip, .. } => {
+                    writeln!(self.writer, r#"  <event time="{time}.0" type="PersonEntersVehicle" person="{agent}_{person}" vehicle="{}"/>"#, vehicle(trip))?;
+                }
+                SimulationEvent::Alighted { trip, .. } => {
+                    writeln!(self.writer, r#"  <event time="{time}.0" type="PersonLeavesVehicle" person="{agent}_{person}" vehicle="{}"/>"#, vehicle(trip))?;
+                }
+                SimulationEvent::AgentArrived { stop, .. } => {
+                    writeln!(self.writer, r#"  <event time="{time}.0" type="arrival" person="{agent}_{person}" link="{}" legMode="pt"/>"#, link(stop))?;
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), DataExportError> {
+        writeln!(self.writer, "</events>")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Keeps every event, e.g. to check them in a test.
+#[derive(Default)]
+pub struct MemoryEventSink {
+    pub events: Vec<SimulationEvent>,
+}
+
+impl EventSink for MemoryEventSink {
+    fn emit(&mut self, event: &SimulationEvent) -> Result<(), DataExportError> {
+        self.events.push(*event);
+        Ok(())
+    }
+}
+
+// Builds the events of the final round in time order. Each thread collects the events of its agents into its own buffer,
+// and the buffers are merged and sorted at the end. The sort is stable, so an agent's simultaneous events stay in order.
+pub fn simulation_events(simulation_result: &SimulationResult) -> Vec<SimulationEvent> {
+    let Some(journeys) = simulation_result.round_agent_journeys.last() else {
+        return Vec::new();
+    };
+
+    let buffers = journeys.par_iter().enumerate().fold(Vec::new, |mut buffer, (agent, agent_journey)| {
+        let agent = agent as u32;
+        let count = agent_journey.count;
+        let Ok(journey) = &agent_journey.result else {
+            return buffer;
+        };
+        buffer.push(SimulationEvent::AgentDeparted { agent, stop: agent_journey.origin_stop, time: agent_journey.start_time, count });
+        for (i, leg) in journey.legs.iter().enumerate() {
+            if i > 0 {
+                // Transfers end when the agent could first board at the next stop, which is no later than the boarding itself.
+                let previous = &journey.legs[i - 1];
+                let time = previous.transfer_time.map_or(leg.boarded_time, |transfer_time| (previous.arrival_time + transfer_time).min(leg.boarded_time));
+                buffer.push(SimulationEvent::TransferEnded { agent, stop: leg.boarded_stop, time, count });
+            }
+            buffer.push(SimulationEvent::Boarded { agent, trip: leg.trip, stop: leg.boarded_stop, time: leg.boarded_time, count });
+            buffer.push(SimulationEvent::Alighted { agent, trip: leg.trip, stop: leg.arrival_stop, time: leg.arrival_time, count });
+            if i + 1 < journey.legs.len() {
+                buffer.push(SimulationEvent::TransferStarted { agent, stop: leg.arrival_stop, time: leg.arrival_time, count });
+            }
+        }
+        if let Some(last_leg) = journey.legs.last() {
+            buffer.push(SimulationEvent::AgentArrived { agent, stop: last_leg.arrival_stop, time: last_leg.arrival_time, count });
+        }
+        buffer
+    }).collect::<Vec<_>>();
+
+    let mut events = buffers.concat();
+    if let Some(capacity_report) = &simulation_result.capacity_report {
+        events.extend(capacity_report.denied_boardings.iter().map(|denied| SimulationEvent::BoardingDenied {
+            agent: denied.agent_idx,
+            trip: denied.trip,
+            stop: denied.stop,
+            time: denied.time,
+            count: denied.count,
+        }));
+    }
+    events.par_sort_by_key(SimulationEvent::time);
+    events
+}
+
+// Emits the events of the final round to a sink in time order. Nothing is built unless this is called.
+pub fn emit_events(simulation_result: &SimulationResult, sink: &mut impl EventSink) -> Result<usize, DataExportError> {
+    let events = simulation_events(simulation_result);
+    for event in events.iter() {
+        sink.emit(event)?;
+    }
+    sink.finish()?;
+    Ok(events.len())
+}
+
+// Writes the events of the final round as a MATSim events file to <path>.xml, or <path>.xml.gz when compressed.
+pub fn export_matsim_events(path: &Path, network: &Network, simulation_result: &SimulationResult, gzip: bool) -> Result<(), DataExportError> {
+    if gzip {
+        let file = File::create(path.with_extension("xml.gz"))?;
+        let mut sink = MatsimEventSink::new(network, GzEncoder::new(BufWriter::new(file), Compression::default()))?;
+        emit_events(simulation_result, &mut sink)?;
+        sink.into_inner().finish()?.flush()?;
+    } else {
+        let mut sink = MatsimEventSink::new(network, BufWriter::new(File::create(path.with_extension("xml"))?))?;
+        emit_events(simulation_result, &mut sink)?;
+    }
+    Ok(())
+}
+
+// Writes the events of the final round to <path>.ndjson.
+pub fn export_events(path: &Path, network: &Network, simulation_result: &SimulationResult) -> Result<(), DataExportError> {
+    let mut sink = NdjsonEventSink::new(network, BufWriter::new(File::create(path.with_extension("ndjson"))?));
+    let num_events = emit_events(simulation_result, &mut sink)?;
+    log::debug!("Wrote {num_events} events.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::run_simulation;
+    use crate::test_utils::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn matsim_events_match_the_golden_file() {
+        let gtfs = load_fixture_gtfs("after_midnight");
+        let network = build_fixture_network(&gtfs);
+        // Two people on SHT_2300, and one on SHT_2440, whose times are past 86400.
+        let simulation_steps = [
+            simulation_step(&network, 22 * 3600 + 55 * 60, "WST", "EST", 2),
+            simulation_step(&network, 24 * 3600 + 30 * 60, "WST", "EST", 1),
+        ];
+        let result = run_simulation(&network, &simulation_steps, &fixture_params(1));
+        let golden = std::fs::read(fixture_path("after_midnight_events.xml")).unwrap();
+
+        let path = temp_path("matsim_events");
+        export_matsim_events(&path, &network, &result, false).unwrap();
+        let written = std::fs::read(path.with_extension("xml")).unwrap();
+        std::fs::remove_file(path.with_extension("xml")).unwrap();
+        assert_eq!(String::from_utf8_lossy(&written), String::from_utf8_lossy(&golden));
+        assert_eq!(written, golden);
+
+        // Compressed, it's the same file.
+        export_matsim_events(&path, &network, &result, true).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(File::open(path.with_extension("xml.gz")).unwrap()).read_to_end(&mut decompressed).unwrap();
+        std::fs::remove_file(path.with_extension("xml.gz")).unwrap();
+        assert_eq!(decompressed, golden);
+    }
+}
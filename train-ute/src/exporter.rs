@@ -0,0 +1,226 @@
+use std::fs::File;
+use std::path::Path;
+
+use chrono::NaiveDate;
+use gtfs_structures::Gtfs;
+use raptor::Network;
+
+use crate::data_export::{self, DataExportError, ShapeColourMode, ShapeColouring, ShapeColours};
+use crate::data_import::ParentStations;
+use crate::simulation::{CrowdingCost, SimulationResult, TripCapacities};
+use crate::stop_geometry::StopGeometry;
+
+// Exporters run unless the config lists others.
+pub const DEFAULT_EXPORTERS: &[&str] = &["counts", "stops", "shapes", "trips"];
+
+// Selects the configured exporters and both groups of the other exports.
+pub const ALL_EXPORTS: &str = "all";
+// The tables of the run (CSV, parquet and GeoJSON of the loads, journeys, stops and events).
+pub const CSV_EXPORTS: &str = "csv";
+// The aggregate reports (stats.json, the route and stop summaries, convergence, validation and comparisons).
+pub const SUMMARY_EXPORTS: &str = "summary";
+
+// Everything an exporter has to export from.
+#[derive(Clone, Copy)]
+pub struct ExportContext<'a> {
+    pub network: &'a Network,
+    // The network's stop positions and names, built once for every exporter.
+    pub stop_geometry: &'a StopGeometry,
+    pub gtfs: &'a Gtfs,
+    pub simulation_result: &'a SimulationResult,
+    pub trip_capacities: &'a TripCapacities,
+    // Crowding cost of each segment under the run's final parameters, from data_export::segment_crowding_costs.
+    pub segment_costs: &'a [CrowdingCost],
+    pub parent_stations: Option<&'a ParentStations>,
+    // The day simulated.
+    pub date: NaiveDate,
+    pub output_dir: &'a Path,
+}
+
+// An export that can be selected by name. Other crates can implement this and register it in an `ExporterRegistry`.
+pub trait Exporter: Send + Sync {
+    // Name the exporter is selected by in the config, and reported under in the export log.
+    fn name(&self) -> &str;
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError>;
+    // Exporters that need the GTFS shapes are skipped when they aren't loaded.
+    fn needs_shapes(&self) -> bool { false }
+    // Names of the exporters whose files this one's are read with, which are also run when it's selected.
+    fn requires(&self) -> &[&str] { &[] }
+}
+
+// The agent counts on each trip, as counts.parquet and counts.csv.
+pub struct CountsExporter;
+
+impl Exporter for CountsExporter {
+    fn name(&self) -> &str { "counts" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        data_export::export_agent_counts(&ctx.output_dir.join("counts"), ctx.network, ctx.stop_geometry, ctx.simulation_result, ctx.trip_capacities, ctx.segment_costs)
+    }
+}
+
+// The stops as stops.csv, and stops.bin.zip (with their boardings and the platforms of each station) for the visualiser.
+pub struct StopsExporter;
+
+impl Exporter for StopsExporter {
+    fn name(&self) -> &str { "stops" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        data_export::export_stops_csv(&ctx.output_dir.join("stops"), ctx.network, ctx.stop_geometry, ctx.parent_stations)?;
+        data_export::export_stops(ctx.network, ctx.stop_geometry, Some(ctx.simulation_result), ctx.parent_stations, &mut data_export::open_zip(&ctx.output_dir.join("stops.bin.zip"))?)
+    }
+}
+
+// The route shapes as shapes.bin.zip for the visualiser, coloured as configured.
+pub struct ShapesExporter {
+    pub colouring: ShapeColouring,
+}
+
+impl Exporter for ShapesExporter {
+    fn name(&self) -> &str { "shapes" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        let shape_colours = match self.colouring.mode {
+            ShapeColourMode::Route => ShapeColours::Route,
+            ShapeColourMode::Crowding => ShapeColours::Crowding { simulation_result: ctx.simulation_result, trip_capacities: ctx.trip_capacities, colouring: &self.colouring },
+        };
+        let num_unused_shapes = data_export::count_unused_shapes(ctx.gtfs, ctx.network);
+        if num_unused_shapes > 0 {
+            log::info!("Excluded {num_unused_shapes} of {} shapes that no trip on {} uses.", ctx.gtfs.shapes.len(), ctx.network.date);
+        }
+        data_export::export_shape_file(ctx.network, ctx.stop_geometry, shape_colours, &mut data_export::open_zip(&ctx.output_dir.join("shapes.bin.zip"))?)
+    }
+
+    fn needs_shapes(&self) -> bool { true }
+}
+
+// The trips' paths and loads as trips.bin.zip for the visualiser.
+pub struct TripsExporter;
+
+impl Exporter for TripsExporter {
+    fn name(&self) -> &str { "trips" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        data_export::export_network_trips(ctx.network, ctx.stop_geometry, ctx.simulation_result, ctx.segment_costs, &mut data_export::open_zip(&ctx.output_dir.join("trips.bin.zip"))?)
+    }
+
+    fn needs_shapes(&self) -> bool { true }
+
+    // The visualiser draws the trips along the shapes.
+    fn requires(&self) -> &[&str] { &["shapes"] }
+}
+
+// Every round's transfers as transfers.parquet.
+pub struct TransfersExporter;
+
+impl Exporter for TransfersExporter {
+    fn name(&self) -> &str { "transfers" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        data_export::export_agent_transfers(File::create(ctx.output_dir.join("transfers.parquet"))?, ctx.network, ctx.stop_geometry, ctx.simulation_result)
+    }
+}
+
+// The exporters that can be selected by name.
+#[derive(Default)]
+pub struct ExporterRegistry {
+    exporters: Vec<Box<dyn Exporter>>,
+}
+
+impl ExporterRegistry {
+    // A registry of the exporters in this crate. The SQLite exporter needs the sqlite feature.
+    pub fn with_builtin(shape_colouring: ShapeColouring, sqlite_journeys: bool) -> Self {
+        let mut registry = Self::default();
+        registry.register(Box::new(CountsExporter));
+        registry.register(Box::new(StopsExporter));
+        registry.register(Box::new(ShapesExporter { colouring: shape_colouring }));
+        registry.register(Box::new(TripsExporter));
+        registry.register(Box::new(TransfersExporter));
+        #[cfg(feature = "sqlite")]
+        registry.register(Box::new(crate::sqlite::SqliteExporter { journeys: sqlite_journeys }));
+        #[cfg(not(feature = "sqlite"))]
+        let _ = sqlite_journeys;
+        registry
+    }
+
+    // Adds an exporter, replacing any with the same name.
+    pub fn register(&mut self, exporter: Box<dyn Exporter>) {
+        match self.exporters.iter().position(|registered| registered.name() == exporter.name()) {
+            Some(i) => self.exporters[i] = exporter,
+            None => self.exporters.push(exporter),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Exporter> {
+        self.exporters.iter().find(|exporter| exporter.name() == name).map(|exporter| exporter.as_ref())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.exporters.iter().map(|exporter| exporter.name())
+    }
+
+    // The exporters with the given names, in that order, or an error naming the first that isn't registered.
+    pub fn select(&self, names: &[String]) -> Result<Vec<&dyn Exporter>, String> {
+        names.iter().map(|name| {
+            self.get(name).ok_or_else(|| format!("{name} is not an exporter (one of {})", self.names().collect::<Vec<_>>().join(", ")))
+        }).collect()
+    }
+}
+
+// Which exports a run writes: the exporters by name, and whether the other exports in each group are written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportSet {
+    pub exporters: Vec<String>,
+    pub csv: bool,
+    pub summary: bool,
+}
+
+impl ExportSet {
+    // Parses export names (exporters, groups or "all", which is the configured exporters and both groups), or an error
+    // listing the valid names. Exporters needed by a selected exporter are added, or reported if they aren't registered.
+    pub fn parse(names: &[String], configured_exporters: &[String], registry: &ExporterRegistry) -> Result<Self, String> {
+        let mut export_set = Self { exporters: Vec::new(), csv: false, summary: false };
+        let add_exporter = |exporters: &mut Vec<String>, name: &str| {
+            if !exporters.iter().any(|exporter| exporter == name) {
+                exporters.push(name.to_owned());
+            }
+        };
+        for name in names.iter().map(|name| name.trim()) {
+            match name {
+                ALL_EXPORTS => {
+                    for exporter in configured_exporters {
+                        add_exporter(&mut export_set.exporters, exporter);
+                    }
+                    export_set.csv = true;
+                    export_set.summary = true;
+                }
+                CSV_EXPORTS => export_set.csv = true,
+                SUMMARY_EXPORTS => export_set.summary = true,
+                name if registry.get(name).is_some() => add_exporter(&mut export_set.exporters, name),
+                name => return Err(format!("{name} is not an export (one of {ALL_EXPORTS}, {CSV_EXPORTS}, {SUMMARY_EXPORTS}, {})", registry.names().collect::<Vec<_>>().join(", "))),
+            }
+        }
+        export_set.add_required(registry)?;
+        Ok(export_set)
+    }
+
+    // Adds the exporters the selected ones need, including those the added ones need in turn.
+    pub fn add_required(&mut self, registry: &ExporterRegistry) -> Result<(), String> {
+        let mut i = 0;
+        while i < self.exporters.len() {
+            let exporter = registry.get(&self.exporters[i]).ok_or_else(|| format!("{} is not an exporter (one of {})", self.exporters[i], registry.names().collect::<Vec<_>>().join(", ")))?;
+            for &required in exporter.requires() {
+                if self.exporters.iter().any(|name| name == required) {
+                    continue;
+                }
+                if registry.get(required).is_none() {
+                    return Err(format!("{} needs the {required} exporter, which isn't registered", exporter.name()));
+                }
+                log::info!("Also exporting {required}, which {} needs.", exporter.name());
+                self.exporters.push(required.to_owned());
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
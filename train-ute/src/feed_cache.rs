@@ -0,0 +1,194 @@
+// A cache of GTFS feeds cut down to the trips of the modelled dates, so rerunning on the same feed and dates (e.g. with
+// other crowding parameters) reads a fraction of the feed's stop times instead of all of them.
+//
+// A feed's cut down copy is a folder of its tables in the `feeds` folder of the cache directory, named by a hash of:
+// - The feed file's SHA-256, so a changed feed is read again.
+// - The modelled dates.
+// - The crate version, so a copy written by another version (which may cut feeds down differently) is never read.
+// Trips are kept if they run on one of the dates or on the nearest weekday with service to one (which the reduced service
+// check compares with), along with their stop times, frequencies and transfers. The other tables are copied as they are,
+// so the calendar, stops, routes and shapes are the whole feed's. Route filters, the transfer time and the rest of the
+// network's settings are applied to the cut down feed the same way as to the whole feed, so aren't part of the key.
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+use gtfs_structures::{Gtfs, GtfsReader};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::data_import;
+use crate::metadata::sha256_file;
+
+// How long reading the whole feed took, kept with the cut down copy to report the time saved.
+const READ_TIME_FILE: &str = "full_read_seconds";
+
+#[derive(thiserror::Error, Debug)]
+pub enum FeedCacheError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Zip error: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+}
+
+pub struct CachedFeed {
+    pub path: PathBuf,
+}
+
+impl CachedFeed {
+    // Where the copy of a feed file cut down to `dates` is cached, which hashes the feed.
+    pub fn new(cache_dir: &Path, feed_file: &Path, dates: &[NaiveDate]) -> std::io::Result<Self> {
+        let key = format!("{}\n{}\n{}", env!("CARGO_PKG_VERSION"), sha256_file(feed_file)?, dates.iter().join(","));
+        let key_hash = Sha256::digest(key.as_bytes()).iter().take(8).map(|byte| format!("{byte:02x}")).collect::<String>();
+        Ok(Self { path: cache_dir.join("feeds").join(key_hash) })
+    }
+
+    // Reads the cut down feed if it's cached, logging the time it took and saved.
+    pub fn read(&self) -> Option<Gtfs> {
+        let full_read_time = Duration::try_from_secs_f64(fs::read_to_string(self.path.join(READ_TIME_FILE)).ok()?.trim().parse().ok()?).ok()?;
+        let read_start = Instant::now();
+        let gtfs = match GtfsReader::default().read_from_path(&self.path) {
+            Ok(gtfs) => gtfs,
+            Err(e) => {
+                log::warn!("Couldn't read the cached feed in {} ({e}), so reading the whole feed.", self.path.display());
+                return None;
+            }
+        };
+        let read_duration = read_start.elapsed();
+        let saved = full_read_time.saturating_sub(read_duration);
+        log::info!("Feed cache hit: read {} trips from {} in {read_duration:?}, {saved:?} less than the whole feed.", gtfs.trips.len(), self.path.display());
+        Some(gtfs)
+    }
+
+    // Writes the copy of `feed_file` (a zip, read as `gtfs` in `read_duration`) cut down to `dates`, logging the miss.
+    // The copy is written beside its folder and renamed, so a cached folder is always complete.
+    pub fn write(&self, feed_file: &Path, gtfs: &Gtfs, dates: &[NaiveDate], read_duration: Duration) -> Result<(), FeedCacheError> {
+        let write_start = Instant::now();
+        let kept_trip_ids = kept_trip_ids(gtfs, dates);
+        let partial_path = self.path.with_extension("partial");
+        if partial_path.exists() {
+            fs::remove_dir_all(&partial_path)?;
+        }
+        fs::create_dir_all(&partial_path)?;
+
+        let mut archive = ZipArchive::new(File::open(feed_file)?)?;
+        for i in 0..archive.len() {
+            let table = archive.by_index(i)?;
+            // Tables in a folder in the zip are written to the top of the copy.
+            let Some(file_name) = table.enclosed_name().filter(|_| table.is_file()).and_then(|name| name.file_name().map(|file_name| file_name.to_owned())) else {
+                continue;
+            };
+            if Path::new(&file_name).extension().is_some_and(|extension| extension == "txt") {
+                copy_table(table, &partial_path.join(file_name), &kept_trip_ids)?;
+            }
+        }
+        fs::write(partial_path.join(READ_TIME_FILE), read_duration.as_secs_f64().to_string())?;
+
+        if self.path.exists() {
+            fs::remove_dir_all(&self.path)?;
+        }
+        fs::rename(&partial_path, &self.path)?;
+        log::info!("Feed cache miss: read the whole feed in {read_duration:?}, and cached its {} trips on the modelled dates (of {}) in {} in {:?}.",
+                   kept_trip_ids.len(), gtfs.trips.len(), self.path.display(), write_start.elapsed());
+        Ok(())
+    }
+}
+
+fn kept_trip_ids<'a>(gtfs: &'a Gtfs, dates: &[NaiveDate]) -> HashSet<&'a str> {
+    let kept_dates = dates.iter().flat_map(|&date| std::iter::once(date).chain(data_import::nearest_service_dates(gtfs, date, 1, true)));
+    let service_ids = kept_dates.flat_map(|date| data_import::active_service_ids(gtfs, date)).collect::<HashSet<_>>();
+    gtfs.trips.values().filter(|trip| service_ids.contains(trip.service_id.as_str())).map(|trip| trip.id.as_str()).collect()
+}
+
+// Copies a table, leaving out the rows of trips that aren't kept (by their trip_id, from_trip_id or to_trip_id).
+fn copy_table(table: impl Read, path: &Path, kept_trip_ids: &HashSet<&str>) -> Result<(), FeedCacheError> {
+    let mut csv_reader = csv::ReaderBuilder::new().flexible(true).from_reader(table);
+    let headers = csv_reader.byte_headers()?.clone();
+    let trip_columns = headers.iter().positions(|header| {
+        matches!(std::str::from_utf8(header).map(|header| header.trim_start_matches('\u{feff}').trim()), Ok("trip_id" | "from_trip_id" | "to_trip_id"))
+    }).collect_vec();
+    let mut csv_writer = csv::WriterBuilder::new().flexible(true).from_path(path)?;
+    csv_writer.write_byte_record(&headers)?;
+    for record in csv_reader.byte_records() {
+        let record = record?;
+        let is_kept = trip_columns.iter().all(|&column| {
+            let trip_id = std::str::from_utf8(record.get(column).unwrap_or_default()).unwrap_or_default().trim();
+            trip_id.is_empty() || kept_trip_ids.contains(trip_id)
+        });
+        if is_kept {
+            csv_writer.write_byte_record(&record)?;
+        }
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use raptor::Network;
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    // two_lines zipped in a folder, with a weekend service whose trip RED_SAT runs like RED_0800.
+    fn weekend_feed(path: &Path) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for entry in fs::read_dir(fixture_path("two_lines")).unwrap() {
+            let entry = entry.unwrap();
+            let mut table = fs::read_to_string(entry.path()).unwrap();
+            match entry.file_name().to_str().unwrap() {
+                "calendar.txt" => table.push_str("WE,0,0,0,0,0,1,1,20240101,20241231\n"),
+                "trips.txt" => table.push_str("RED,WE,RED_SAT\n"),
+                "stop_times.txt" => table.push_str("RED_SAT,08:00:00,08:00:00,ALP,1\nRED_SAT,08:05:00,08:05:00,BRA,2\nRED_SAT,08:10:00,08:10:00,CHA,3\n"),
+                _ => {}
+            }
+            zip.start_file(format!("two_lines/{}", entry.file_name().to_str().unwrap()), SimpleFileOptions::default()).unwrap();
+            zip.write_all(table.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn cached_feeds_keep_the_trips_of_their_dates() {
+        let (feed_file, cache_dir) = (temp_path("feed_cache.zip"), temp_path("feed_cache"));
+        weekend_feed(&feed_file);
+        let gtfs = GtfsReader::default().read_from_path(&feed_file).unwrap();
+        assert_eq!(gtfs.trips.len(), 9);
+
+        let cached_feed = CachedFeed::new(&cache_dir, &feed_file, &[fixture_date()]).unwrap();
+        assert!(cached_feed.read().is_none());
+        cached_feed.write(&feed_file, &gtfs, &[fixture_date()], Duration::from_secs(1)).unwrap();
+        let cached_gtfs = cached_feed.read().unwrap();
+        // The weekend trip is left out, but not its service.
+        assert_eq!(cached_gtfs.trips.len(), 8);
+        assert!(!cached_gtfs.trips.contains_key("RED_SAT"));
+        assert_eq!(cached_gtfs.calendar.len(), 2);
+        let (network, cached_network) = (build_fixture_network(&gtfs), build_fixture_network(&cached_gtfs));
+        assert_eq!(cached_network.stop_times.len(), network.stop_times.len());
+        assert_eq!(cached_network.routes.iter().flat_map(|route| route.trip_ids.iter().map(|trip_id| trip_id.to_string())).sorted().collect_vec(),
+                   network.routes.iter().flat_map(|route| route.trip_ids.iter().map(|trip_id| trip_id.to_string())).sorted().collect_vec());
+
+        // Saturday's copy is its own, and keeps the weekday trips of the nearest weekday with service.
+        let saturday = NaiveDate::from_ymd_opt(2024, 6, 8).unwrap();
+        let saturday_feed = CachedFeed::new(&cache_dir, &feed_file, &[saturday]).unwrap();
+        assert_ne!(saturday_feed.path, cached_feed.path);
+        saturday_feed.write(&feed_file, &gtfs, &[saturday], Duration::from_secs(1)).unwrap();
+        let saturday_gtfs = saturday_feed.read().unwrap();
+        assert_eq!(saturday_gtfs.trips.len(), 9);
+        let saturday_network = Network::new(&saturday_gtfs, None, saturday, FIXTURE_TRANSFER_TIME);
+        assert_eq!((0..saturday_network.num_routes()).map(|route_idx| saturday_network.num_trips(route_idx)).sum::<usize>(), 1);
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+        fs::remove_file(&feed_file).unwrap();
+    }
+}
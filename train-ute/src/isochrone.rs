@@ -0,0 +1,82 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use itertools::izip;
+use raptor::network::{StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::data_export::{json_string, DataExportError};
+use crate::stop_geometry::StopGeometry;
+
+// Upper bounds (in minutes) of the travel time bands the stops are put in.
+pub const BAND_MINUTES: [Timestamp; 4] = [15, 30, 45, 60];
+
+// Marks unreached stops in the CSV, where a blank would look like a missing value.
+const UNREACHED: &str = "inf";
+
+// The smallest band a travel time (in seconds) is within, or None if it's past the last band or the stop wasn't reached.
+pub fn band(travel_time: Option<Timestamp>) -> Option<Timestamp> {
+    BAND_MINUTES.into_iter().find(|&minutes| travel_time.is_some_and(|travel_time| travel_time <= minutes * 60))
+}
+
+pub fn log_isochrone(network: &Network, origin_stop: StopIndex, departure_time: Timestamp, travel_times: &[Option<Timestamp>]) {
+    let num_reached = travel_times.iter().filter(|travel_time| travel_time.is_some()).count();
+    log::info!("{} of {} stops can be reached from {} departing {}.",
+               num_reached,
+               network.stops.len(),
+               network.stops[origin_stop as usize].name,
+               get_time_str(departure_time));
+    for minutes in BAND_MINUTES {
+        let num_within = travel_times.iter().filter(|travel_time| travel_time.is_some_and(|travel_time| travel_time <= minutes * 60)).count();
+        log::info!("  Within {minutes} min: {num_within} stops.");
+    }
+}
+
+// Writes every stop's travel time (in seconds, or "inf" if it wasn't reached) and band to <path>.csv, and the stops as
+// GeoJSON (RFC 7946) Points with the same properties (null if unreached) to <path>.geojson, so it can be styled by band in GIS software.
+pub fn export_isochrone(path: &Path, network: &Network, stop_geometry: &StopGeometry, origin_stop: StopIndex, departure_time: Timestamp, travel_times: &[Option<Timestamp>]) -> Result<(), DataExportError> {
+    if travel_times.len() != network.stops.len() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["stop_id", "stop_name", "travel_time", "arrival_time", "band_minutes"])?;
+    for (stop, name, &travel_time) in izip!(network.stops.iter(), &stop_geometry.names, travel_times) {
+        csv_writer.write_record(&[
+            &stop.id[..],
+            &name[..],
+            &travel_time.map_or(UNREACHED.to_owned(), |travel_time| travel_time.to_string()),
+            &travel_time.map_or(String::new(), |travel_time| get_time_str(departure_time + travel_time)),
+            &band(travel_time).map_or(String::new(), |minutes| minutes.to_string()),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    let origin_stop_id = json_string(network.stops[origin_stop as usize].id.as_ref());
+    let departure_time_str = json_string(&get_time_str(departure_time));
+    let mut writer = BufWriter::new(File::create(path.with_extension("geojson"))?);
+    write!(writer, r#"{{"type":"FeatureCollection","features":["#)?;
+    for (i, (stop, &travel_time)) in network.stops.iter().zip(travel_times).enumerate() {
+        if i > 0 {
+            write!(writer, ",")?;
+        }
+        let point = stop_geometry.points[i];
+        let optional = |value: Option<Timestamp>| value.map_or("null".to_owned(), |value| value.to_string());
+        // GeoJSON positions are longitude then latitude.
+        write!(writer,
+               r#"{{"type":"Feature","geometry":{{"type":"Point","coordinates":[{},{}]}},"properties":{{"stop_id":{},"stop_name":{},"origin_stop_id":{origin_stop_id},"departure_time":{departure_time_str},"travel_time":{},"band_minutes":{}}}}}"#,
+               point.longitude,
+               point.latitude,
+               json_string(stop.id.as_ref()),
+               json_string(&stop_geometry.names[i]),
+               optional(travel_time),
+               optional(band(travel_time)),
+        )?;
+    }
+    write!(writer, "]}}")?;
+    writer.flush()?;
+
+    Ok(())
+}
@@ -1,4 +1,48 @@
+// Who's on Board? agent-based rail crowding simulation.
+//
+// The usual flow is to build a `RunConfig`, build the network from the GTFS with `RunConfig::build_network`,
+// get the simulation steps and parameters from the config, call `run_simulation` and then export the result
+// with the `data_export` functions. See `examples/train_ute_melbourne.rs`.
+
+pub mod access;
+pub mod calibration;
+pub mod checkpoint;
+#[cfg(feature = "config")]
+pub mod config;
 pub mod data_export;
 pub mod data_import;
+pub mod demand;
+#[cfg(feature = "download")]
+pub mod download;
+pub mod events;
+pub mod exporter;
+#[cfg(feature = "config")]
+pub mod feed_cache;
+pub mod isochrone;
+#[cfg(feature = "config")]
+pub mod metadata;
+pub mod network_stats;
+pub mod peak_spreading;
+pub mod query;
+pub mod reachability;
+#[cfg(feature = "gtfs_rt")]
+pub mod realtime;
+pub mod replacement;
+pub mod replication;
 pub mod simulation;
-mod utils;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stop_geometry;
+pub mod sweep;
+#[cfg(test)]
+mod test_utils;
+pub mod transfer_times;
+pub mod utils;
+pub mod validation;
+pub mod wheelchair;
+
+#[cfg(feature = "config")]
+pub use config::{ConfigError, RunConfig};
+pub use data_export::DataExportError;
+pub use data_import::DataImportError;
+pub use simulation::{run_simulation, DefaultSimulationParams, SimulationParams, SimulationResult, SimulationStep};
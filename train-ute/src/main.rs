@@ -0,0 +1,1900 @@
+use chrono::NaiveDate;
+use clap::Parser;
+use gtfs_structures::{Gtfs, GtfsReader};
+use itertools::Itertools;
+use raptor::network::{Network, Timestamp};
+use raptor::utils::get_time_str;
+use rayon::prelude::*;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use train_ute::checkpoint::{self, Checkpointing, SimulationCheckpoint};
+use train_ute::config::{parse_crowding_function, DepartureProfileConfig, RunConfig, DEFAULT_CONFIG_TEMPLATE, DEFAULT_GTFS_CACHE_DIR, DEFAULT_STOP_MERGE_DISTANCE};
+use train_ute::simulation::{CrowdingCost, CrowdingFunc, DefaultSimulationParams, PopulationCount, SimulationResult, SimulationStep};
+use train_ute::replication::{self, ReplicationSummary};
+use train_ute::sweep::{self, SweepPoint, SweepRun};
+use train_ute::data_export::DataExportError;
+use train_ute::exporter::{ExportContext, ExportSet, ExporterRegistry};
+use train_ute::feed_cache::CachedFeed;
+use train_ute::metadata::{self, RunMetadata, WarmStartReport};
+use train_ute::network_stats::NetworkStats;
+use train_ute::stop_geometry::StopGeometry;
+use train_ute::transfer_times::TransferTimes;
+use train_ute::wheelchair::{self, WheelchairAccess};
+use train_ute::{access, calibration, data_export, data_import, download, events, isochrone, peak_spreading, query, reachability, replacement, simulation, utils, validation};
+
+// Exit code when a cancelled run has exported its partial results.
+const CANCELLED_EXIT_CODE: i32 = 2;
+// Exit code when the run is aborted without exporting anything (the conventional code for SIGINT).
+const ABORTED_EXIT_CODE: i32 = 130;
+// Exit code when the simulation finished but some of its exports couldn't be written.
+const EXPORT_FAILED_EXIT_CODE: i32 = 3;
+
+// Logs to stderr, and optionally a file. Debug and trace messages are only shown for this crate and raptor.
+struct CliLogger {
+    log_file: Mutex<Option<File>>,
+}
+
+static LOGGER: CliLogger = CliLogger { log_file: Mutex::new(None) };
+
+impl log::Log for CliLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+            && (metadata.level() <= log::Level::Info || metadata.target().starts_with("train_ute") || metadata.target().starts_with("raptor"))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+        eprintln!("{line}");
+        if let Some(log_file) = self.log_file.lock().unwrap().as_mut() {
+            // There's nowhere to report a failure to log.
+            let _ = writeln!(log_file, "{line}");
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(log_file) = self.log_file.lock().unwrap().as_mut() {
+            let _ = log_file.flush();
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Build the network for the date and print its statistics, without simulating or exporting anything.
+    Inspect {
+        /// Also write the statistics to network_stats.json in the export folder.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Plan from one stop to every other and export the travel time to each to isochrone.csv and isochrone.geojson.
+    Isochrone {
+        /// Stop to plan from, by id or by part of its name.
+        #[arg(long, value_name = "STOP")]
+        from: String,
+        /// Departure time (HH:MM or HH:MM:SS).
+        #[arg(long, value_name = "TIME")]
+        depart: String,
+        /// Plan under the loads of an earlier run (a checkpoint, or the loads.csv of an export folder) instead of an uncrowded network.
+        #[arg(long, value_name = "PATH")]
+        crowding: Option<PathBuf>,
+    },
+}
+
+// Options needed to build the network are global, so they can also follow a subcommand.
+#[derive(Parser)]
+#[command(version, about = "Who's on Board? rail service demand model.")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Show more detail (-v for debug, -vv for trace).
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only show errors.
+    #[arg(short, long, conflicts_with = "verbose", global = true)]
+    quiet: bool,
+    /// Also write the log to train_ute.log in the export folder.
+    #[arg(long)]
+    log_file: bool,
+    /// Print GTFS and network statistics.
+    #[arg(long)]
+    stats: bool,
+    /// TOML run configuration to load. Other options override values in this file.
+    #[arg(long, value_name = "PATH", global = true)]
+    config: Option<PathBuf>,
+    /// Write a commented default configuration (to stdout if no path is given) and exit.
+    #[arg(long, value_name = "PATH")]
+    write_default_config: Option<Option<PathBuf>>,
+    /// GTFS feed to build the network from, as a path or an http(s) URL. Repeat to merge several feeds into one network.
+    #[arg(long, value_name = "PATH", global = true)]
+    gtfs: Vec<PathBuf>,
+    /// Merge stops in different feeds with the same name within this many metres.
+    #[arg(long, value_name = "METRES", global = true)]
+    stop_merge_distance: Option<f64>,
+    /// Folder downloaded GTFS feeds are cached in.
+    #[arg(long, value_name = "PATH", global = true)]
+    gtfs_cache_dir: Option<PathBuf>,
+    /// Download the GTFS feed again even if the cached copy is up to date.
+    #[arg(long, global = true)]
+    refresh: bool,
+    /// Read the whole GTFS feed instead of its cached copy cut down to the modelled dates, and don't cache one.
+    #[arg(long, global = true)]
+    no_cache: bool,
+    /// Day to model (YYYY-MM-DD).
+    #[arg(long, global = true)]
+    date: Option<NaiveDate>,
+    /// Model every day from START to END inclusive (YYYY-MM-DD..YYYY-MM-DD), exporting each day to its own folder in the export folder.
+    #[arg(long, value_name = "START..END", value_parser = parse_date_range_arg, conflicts_with = "date")]
+    dates: Option<(NaiveDate, NaiveDate)>,
+    /// Only simulate routes with these GTFS route_type codes (comma separated, e.g. 2 for rail).
+    #[arg(long, value_delimiter = ',', global = true)]
+    route_types: Option<Vec<i16>>,
+    /// Only simulate these GTFS route ids (comma separated).
+    #[arg(long, value_delimiter = ',', global = true)]
+    route_ids: Option<Vec<String>>,
+    /// Replace stops with their GTFS parent station, combining each station's platforms into one stop.
+    #[arg(long, global = true)]
+    parent_stations: bool,
+    /// Number of randomly generated agents.
+    #[arg(long)]
+    agents: Option<usize>,
+    /// Seed for random agent generation.
+    #[arg(long)]
+    seed: Option<u64>,
+    /// CSV of origin_stop_id,destination_stop_id,departure_time,count(,weight) demand to simulate instead of random agents.
+    #[arg(long, value_name = "PATH")]
+    od: Option<PathBuf>,
+    /// CSV of origin_lat,origin_lon,destination_lat,destination_lon,departure_time,count demand between addresses, which walk to nearby stops.
+    #[arg(long, value_name = "PATH")]
+    point_od: Option<PathBuf>,
+    /// Straight-line distance in metres agents walk to and from stops with --point-od.
+    #[arg(long, value_name = "METRES")]
+    walk_radius: Option<f64>,
+    /// Multiplier on every OD matrix count (e.g. 10 for a 10% sample).
+    #[arg(long)]
+    demand_scale: Option<f64>,
+    /// Fail if any OD matrix pair has no journey on the date, instead of leaving it out.
+    #[arg(long)]
+    fail_on_unreachable: bool,
+    /// CSV of trip_id,from_stop_id,to_stop_id,observed_load survey data to validate the simulated loads against.
+    #[arg(long, value_name = "PATH")]
+    observed_loads: Option<PathBuf>,
+    /// CSV of trip_id,route_id,stop_id,arrival_time,departure_time trips to add to the timetable.
+    #[arg(long, value_name = "PATH")]
+    supplementary_trips: Option<PathBuf>,
+    /// CSV of trip_id,action,stop_id trips to cancel or truncate before simulating.
+    #[arg(long, value_name = "PATH")]
+    disruption: Option<PathBuf>,
+    /// GTFS-realtime TripUpdates feed (or a directory of .pb feeds) to shift the timetable by what actually ran.
+    #[arg(long, value_name = "PATH")]
+    trip_updates: Option<PathBuf>,
+    /// CSV of stop_id,weight catchment weights to generate agents from with a gravity model.
+    #[arg(long, value_name = "PATH")]
+    stop_weights: Option<PathBuf>,
+    /// CSV of time_bin,weight to draw generated agents' departure times (and OD rows without a time) from.
+    #[arg(long, value_name = "PATH")]
+    departure_profile: Option<PathBuf>,
+    /// Default seated capacity of each trip.
+    #[arg(long)]
+    seated_capacity: Option<i32>,
+    /// Default standing capacity of each trip.
+    #[arg(long)]
+    standing_capacity: Option<i32>,
+    /// CSV of trip_id,seated,standing or trip_id,consist capacities.
+    #[arg(long, value_name = "PATH")]
+    trip_capacities: Option<PathBuf>,
+    /// CSV of route_id,seated,standing capacities.
+    #[arg(long, value_name = "PATH")]
+    route_capacities: Option<PathBuf>,
+    /// Deny boarding once a trip reaches its total capacity.
+    #[arg(long)]
+    strict_capacity: bool,
+    /// Crowding cost function as an inline TOML table, e.g. '{ func = "exponential", params = { beta = 5.0 } }'.
+    #[arg(long, value_parser = parse_crowding_function_arg)]
+    crowding_function: Option<CrowdingFunc>,
+    /// Minimum time (in seconds) to change between trips at a stop.
+    #[arg(long, value_name = "SECONDS")]
+    transfer_time: Option<Timestamp>,
+    /// Use the min_transfer_time of the GTFS transfers.txt for the transfers it lists.
+    #[arg(long)]
+    gtfs_transfer_times: bool,
+    /// CSV of from_stop_id,to_stop_id,seconds minimum transfer times, overriding the GTFS and the default transfer time.
+    #[arg(long, value_name = "PATH")]
+    transfer_times: Option<PathBuf>,
+    /// Maximum number of simulation rounds.
+    #[arg(long)]
+    rounds: Option<u16>,
+    /// Stop early once the relative change in total crowding cost between rounds is below this.
+    #[arg(long)]
+    convergence_tolerance: Option<f64>,
+    /// Number of consecutive rounds the change must stay below the tolerance before stopping.
+    #[arg(long)]
+    convergence_rounds: Option<u16>,
+    /// Proportion of agents that recompute their journey each round after the first.
+    #[arg(long)]
+    replan_fraction: Option<CrowdingCost>,
+    /// Multiplier applied to the replan fraction each round.
+    #[arg(long)]
+    replan_decay: Option<CrowdingCost>,
+    /// Number of threads to simulate with (defaults to the number of available processors).
+    #[arg(long)]
+    threads: Option<usize>,
+    /// Number of sweep runs or replications to simulate at the same time.
+    #[arg(long, value_name = "NUM")]
+    max_parallel_runs: Option<usize>,
+    /// Repeat the run this many times with consecutive seeds, exporting the mean loads and confidence intervals.
+    #[arg(long, value_name = "NUM")]
+    replications: Option<usize>,
+    /// Number of simulation steps each thread assigns at a time (defaults to a size chosen from the network and thread count).
+    #[arg(long, value_name = "STEPS")]
+    chunk_size: Option<usize>,
+    /// Print simulation progress every this many simulation steps (0 to disable).
+    #[arg(long, value_name = "STEPS")]
+    progress_interval: Option<usize>,
+    /// Write a checkpoint at the end of each round, keeping this many of the newest (0 disables checkpoints).
+    #[arg(long, value_name = "NUM")]
+    keep_checkpoints: Option<usize>,
+    /// Folder to export results to.
+    #[arg(long, value_name = "PATH", global = true)]
+    export_dir: Option<PathBuf>,
+    /// Also export loads.csv with the load of every trip segment.
+    #[arg(long)]
+    export_loads: bool,
+    /// Also export transfer_times.csv with the transfer time used between each pair of stops.
+    #[arg(long)]
+    export_transfer_times: bool,
+    /// Also export loads.geojson with a line for every trip segment, for GIS software.
+    #[arg(long)]
+    export_geojson: bool,
+    /// Colour exported shapes by route colour or by simulated crowding (route or crowding).
+    #[arg(long)]
+    shape_colouring: Option<data_export::ShapeColourMode>,
+    /// Load factor to colour crowded shapes by: max, mean, or the max over a HH:MM:SS-HH:MM:SS departure window.
+    #[arg(long, value_name = "STAT")]
+    shape_colouring_stat: Option<data_export::SegmentStat>,
+    /// Also export stop_activity.csv with the boardings, alightings and transfers at each stop.
+    #[arg(long)]
+    export_stop_activity: bool,
+    /// Also export stop_occupancy.csv with the average number of agents waiting or transferring at each stop.
+    #[arg(long)]
+    export_stop_occupancy: bool,
+    /// Width of the stop activity and occupancy time bins.
+    #[arg(long, value_name = "SECONDS")]
+    stop_activity_bin: Option<Timestamp>,
+    /// Also export heat_grid.csv with the load factor of each route per time bin.
+    #[arg(long)]
+    export_heat_grid: bool,
+    /// Width of the heat grid time bins.
+    #[arg(long, value_name = "SECONDS")]
+    heat_grid_bin: Option<Timestamp>,
+    /// Exporters to run by name (comma separated, e.g. counts,stops,sqlite).
+    #[arg(long, value_delimiter = ',')]
+    exporters: Option<Vec<String>>,
+    /// Exports to write (comma separated): exporters by name, csv for the tables, summary for the reports, or all.
+    #[arg(long, value_delimiter = ',', default_value = "all")]
+    export: Vec<String>,
+    /// Also write the agent_journeys table with the sqlite exporter.
+    #[arg(long)]
+    sqlite_journeys: bool,
+    /// Also export occupancy.csv with the GTFS-realtime occupancy status departing each stop of every trip.
+    #[arg(long)]
+    export_occupancy: bool,
+    /// Also export the occupancy as a GTFS-realtime occupancy.pb feed.
+    #[arg(long)]
+    export_occupancy_feed: bool,
+    /// Also export od_matrix.csv with the agents assigned between each pair of first boarding and last alighting stops.
+    #[arg(long)]
+    export_od_matrix: bool,
+    /// Combine stops into their GTFS parent stations in the OD matrix.
+    #[arg(long)]
+    od_matrix_parent_stations: bool,
+    /// Also export events.ndjson with every boarding, alighting and transfer in time order.
+    #[arg(long)]
+    export_events: bool,
+    /// Also export the events as a MATSim events.xml.
+    #[arg(long)]
+    export_matsim_events: bool,
+    /// Gzip the MATSim events file.
+    #[arg(long)]
+    matsim_events_gzip: bool,
+    /// Benchmark the simulation on pools with each of these thread counts (comma separated), writing simulation_scaling.csv instead of the usual exports.
+    #[arg(long, value_delimiter = ',', value_name = "THREADS")]
+    benchmark: Vec<usize>,
+    /// Number of benchmark runs for each thread count.
+    #[arg(long, default_value_t = 3)]
+    benchmark_runs: usize,
+    /// Also simulate the scenario in this TOML configuration with the same network and demand, and export the differences to the comparison folder.
+    #[arg(long, value_name = "PATH")]
+    compare: Option<PathBuf>,
+    /// After the simulation, plan a journey from this stop (id or part of its name) under the final loads and print it.
+    #[arg(long, value_name = "STOP", requires_all = ["query_to", "query_depart"])]
+    query_from: Option<String>,
+    /// Destination stop of the --query-from journey.
+    #[arg(long, value_name = "STOP", requires = "query_from")]
+    query_to: Option<String>,
+    /// Departure time of the --query-from journey (HH:MM or HH:MM:SS).
+    #[arg(long, value_name = "TIME", requires = "query_from")]
+    query_depart: Option<String>,
+    /// Resume the simulation from a checkpoint written by a run with the same network, demand and parameters.
+    #[arg(long, value_name = "PATH")]
+    resume: Option<PathBuf>,
+    /// Start the assignment from the loads of a previous run instead of free-flow: a checkpoint of the same network, or an
+    /// exported loads.csv, matched by trip (segments it doesn't have start from zero).
+    #[arg(long, value_name = "PATH", conflicts_with = "resume")]
+    warm_start: Option<PathBuf>,
+    /// Also write the exports, run metadata and a manifest of their SHA-256 hashes into a single zip.
+    #[arg(long, value_name = "PATH")]
+    bundle: Option<PathBuf>,
+    /// Don't write the --bundle if any export failed, instead of bundling the rest.
+    #[arg(long, requires = "bundle")]
+    bundle_strict: bool,
+}
+
+impl Cli {
+    // Command line values take precedence over the config file.
+    fn apply_overrides(&self, config: &mut RunConfig) {
+        if let Some((gtfs_path, additional_gtfs_paths)) = self.gtfs.split_first() {
+            config.gtfs_path = gtfs_path.clone();
+            config.additional_gtfs_paths = additional_gtfs_paths.to_vec();
+        }
+        if let Some(stop_merge_distance) = self.stop_merge_distance {
+            config.stop_merge_distance = stop_merge_distance;
+        }
+        if let Some(gtfs_cache_dir) = &self.gtfs_cache_dir {
+            config.gtfs_cache_dir = gtfs_cache_dir.clone();
+        }
+        if let Some(date) = self.date {
+            config.date = date;
+        }
+        if let Some((start, _)) = self.dates {
+            config.date = start;
+        }
+        if let Some(route_types) = &self.route_types {
+            config.route_types = route_types.clone();
+        }
+        if let Some(route_ids) = &self.route_ids {
+            config.route_ids = route_ids.clone();
+        }
+        if self.parent_stations {
+            config.parent_stations = true;
+        }
+        if let Some(agents) = self.agents {
+            config.num_agents = Some(agents);
+        }
+        if let Some(seed) = self.seed {
+            config.seed = Some(seed);
+        }
+        if let Some(od) = &self.od {
+            config.od_matrix = Some(od.clone());
+        }
+        if let Some(point_od) = &self.point_od {
+            config.point_od_matrix = Some(point_od.clone());
+        }
+        if let Some(walk_radius) = self.walk_radius {
+            config.access.walk_radius = walk_radius;
+        }
+        if let Some(demand_scale) = self.demand_scale {
+            config.demand_scale = demand_scale;
+        }
+        if self.fail_on_unreachable {
+            config.fail_on_unreachable = true;
+        }
+        if let Some(observed_loads) = &self.observed_loads {
+            config.observed_loads = Some(observed_loads.clone());
+        }
+        if let Some(supplementary_trips) = &self.supplementary_trips {
+            config.supplementary_trips = Some(supplementary_trips.clone());
+        }
+        if let Some(disruption) = &self.disruption {
+            config.disruption = Some(disruption.clone());
+        }
+        if let Some(trip_updates) = &self.trip_updates {
+            config.trip_updates = Some(trip_updates.clone());
+        }
+        if let Some(stop_weights) = &self.stop_weights {
+            config.stop_weights = Some(stop_weights.clone());
+        }
+        if let Some(departure_profile) = &self.departure_profile {
+            config.departure_profile = Some(DepartureProfileConfig::Bins { file: departure_profile.clone() });
+        }
+        if let Some(seated) = self.seated_capacity {
+            config.trip_capacity.seated = seated;
+        }
+        if let Some(standing) = self.standing_capacity {
+            config.trip_capacity.standing = standing;
+        }
+        if let Some(trip_capacities) = &self.trip_capacities {
+            config.trip_capacities = Some(trip_capacities.clone());
+        }
+        if let Some(route_capacities) = &self.route_capacities {
+            config.route_capacities = Some(route_capacities.clone());
+        }
+        if self.strict_capacity {
+            config.strict_capacity = true;
+        }
+        if let Some(crowding_function) = &self.crowding_function {
+            config.crowding_function = crowding_function.clone();
+        }
+        if let Some(transfer_time) = self.transfer_time {
+            config.default_transfer_time = transfer_time;
+        }
+        if self.gtfs_transfer_times {
+            config.gtfs_transfer_times = true;
+        }
+        if let Some(transfer_times) = &self.transfer_times {
+            config.transfer_times = Some(transfer_times.clone());
+        }
+        if let Some(rounds) = self.rounds {
+            config.num_rounds = rounds;
+        }
+        if let Some(convergence_tolerance) = self.convergence_tolerance {
+            config.convergence_tolerance = Some(convergence_tolerance);
+        }
+        if let Some(convergence_rounds) = self.convergence_rounds {
+            config.convergence_rounds = convergence_rounds;
+        }
+        if let Some(replan_fraction) = self.replan_fraction {
+            config.replan_fraction = replan_fraction;
+        }
+        if let Some(replan_decay) = self.replan_decay {
+            config.replan_decay = replan_decay;
+        }
+        if let Some(threads) = self.threads {
+            config.threads = Some(threads);
+        }
+        if let Some(max_parallel_runs) = self.max_parallel_runs {
+            config.max_parallel_runs = max_parallel_runs;
+        }
+        if let Some(replications) = self.replications {
+            config.replications = replications;
+        }
+        if let Some(chunk_size) = self.chunk_size {
+            config.chunk_size = Some(chunk_size);
+        }
+        if let Some(progress_interval) = self.progress_interval {
+            config.progress_interval = progress_interval;
+        }
+        if let Some(keep_checkpoints) = self.keep_checkpoints {
+            config.keep_checkpoints = keep_checkpoints;
+        }
+        if let Some(export_dir) = &self.export_dir {
+            config.export_dir = export_dir.clone();
+        }
+        if self.export_loads {
+            config.export_loads = true;
+        }
+        if self.export_transfer_times {
+            config.export_transfer_times = true;
+        }
+        if self.export_geojson {
+            config.export_geojson = true;
+        }
+        if self.export_stop_activity {
+            config.export_stop_activity = true;
+        }
+        if let Some(shape_colouring) = self.shape_colouring {
+            config.shape_colouring.mode = shape_colouring;
+        }
+        if let Some(shape_colouring_stat) = self.shape_colouring_stat {
+            config.shape_colouring.stat = shape_colouring_stat;
+        }
+        if self.export_stop_occupancy {
+            config.export_stop_occupancy = true;
+        }
+        if let Some(stop_activity_bin) = self.stop_activity_bin {
+            config.stop_activity_bin = stop_activity_bin;
+        }
+        if self.export_heat_grid {
+            config.export_heat_grid = true;
+        }
+        if let Some(heat_grid_bin) = self.heat_grid_bin {
+            config.heat_grid.bin = heat_grid_bin;
+        }
+        if let Some(exporters) = &self.exporters {
+            config.exporters = exporters.clone();
+        }
+        if self.sqlite_journeys {
+            config.sqlite_journeys = true;
+        }
+        if self.export_occupancy {
+            config.export_occupancy = true;
+        }
+        if self.export_occupancy_feed {
+            config.export_occupancy_feed = true;
+        }
+        if self.export_od_matrix {
+            config.export_od_matrix = true;
+        }
+        if self.od_matrix_parent_stations {
+            config.od_matrix_parent_stations = true;
+        }
+        if self.export_events {
+            config.export_events = true;
+        }
+        if self.export_matsim_events {
+            config.export_matsim_events = true;
+        }
+        if self.matsim_events_gzip {
+            config.matsim_events_gzip = true;
+        }
+    }
+}
+
+fn parse_crowding_function_arg(arg: &str) -> Result<CrowdingFunc, String> {
+    parse_crowding_function(arg).map_err(|e| e.to_string())
+}
+
+fn parse_date_range_arg(arg: &str) -> Result<(NaiveDate, NaiveDate), String> {
+    let (start, end) = arg.split_once("..").ok_or(format!("{arg} is not a date range like 2024-03-11..2024-03-17"))?;
+    let parse = |date_str: &str| NaiveDate::parse_from_str(date_str.trim(), "%Y-%m-%d").map_err(|e| format!("Invalid date {date_str}: {e}"));
+    let (start, end) = (parse(start)?, parse(end)?);
+    if end < start {
+        return Err(format!("The date range ends ({end}) before it starts ({start})"));
+    }
+    Ok((start, end))
+}
+
+fn user_input(prompt: &str) -> Result<Option<String>, std::io::Error> {
+    utils::read_input(&mut std::io::stdin().lock(), &mut std::io::stdout(), prompt)
+}
+
+// Asks for a number greater than zero until one is given.
+fn prompt_count(prompt: &str) -> Result<usize, std::io::Error> {
+    utils::prompt_parse(&mut std::io::stdin().lock(), &mut std::io::stdout(), prompt, |&count| count > 0, "a number greater than zero")
+}
+
+fn prompt_yes_no(prompt: &str) -> Result<bool, std::io::Error> {
+    let input = user_input(prompt)?.unwrap_or(String::new());
+    Ok(matches!(input.trim(), "y" | "Y" | "yes"))
+}
+
+fn prompt_gtfs_path() -> Result<PathBuf, std::io::Error> {
+    loop {
+        let gtfs_path = user_input("Enter GTFS path or URL (default ../gtfs/2/google_transit.zip): ")?;
+        let gtfs_path = Path::new(gtfs_path.as_deref().unwrap_or("../gtfs/2/google_transit.zip"));
+
+        if gtfs_path.exists() || download::is_url(gtfs_path) {
+            break Ok(gtfs_path.to_path_buf());
+        } else {
+            println!("GTFS path {} does not exist.", gtfs_path.display());
+        }
+    }
+}
+
+// Reads a GTFS feed, downloading it first if it's a URL. Returns the local file it was read from.
+// With `cache_dates`, a zipped feed is read from its copy cut down to those dates if it's cached, or else one is cached.
+fn load_gtfs(gtfs_path: &Path, gtfs_cache_dir: &Path, refresh: bool, cache_dates: Option<&[NaiveDate]>) -> Result<(PathBuf, Gtfs), Box<dyn std::error::Error>> {
+    let gtfs_file = if download::is_url(gtfs_path) {
+        download::fetch_gtfs(&gtfs_path.to_string_lossy(), gtfs_cache_dir, refresh)?
+    } else {
+        gtfs_path.to_path_buf()
+    };
+    let cached_feed = match cache_dates {
+        Some(dates) if gtfs_file.is_file() => Some((CachedFeed::new(gtfs_cache_dir, &gtfs_file, dates)?, dates)),
+        _ => None,
+    };
+    if let Some(gtfs) = cached_feed.as_ref().and_then(|(cached_feed, _)| cached_feed.read()) {
+        return Ok((gtfs_file, gtfs));
+    }
+    log::info!("Reading GTFS from {}.", gtfs_file.display());
+    let read_start = Instant::now();
+    let gtfs = GtfsReader::default().read_from_path(&gtfs_file)?;
+    if let Some((cached_feed, dates)) = cached_feed {
+        // The run carries on with the whole feed if it can't be cached.
+        if let Err(e) = cached_feed.write(&gtfs_file, &gtfs, dates, read_start.elapsed()) {
+            log::warn!("Couldn't cache the feed in {}: {e}", cached_feed.path.display());
+        }
+    }
+    Ok((gtfs_file, gtfs))
+}
+
+// Parses YYYY-MM-DD or DD/MM/YYYY, or DD/MM with the year inferred from the feed's calendar.
+fn parse_date(date_str: &str, gtfs: &Gtfs) -> Option<NaiveDate> {
+    let date_str = date_str.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(date_str, "%Y-%m-%d").or_else(|_| NaiveDate::parse_from_str(date_str, "%d/%m/%Y")) {
+        return Some(date);
+    }
+    let (day, month) = date_str.split_once('/')?;
+    data_import::infer_service_year(gtfs, day.parse().ok()?, month.parse().ok()?)
+}
+
+// Returns false (after explaining why) if nothing runs on the date.
+fn check_service(gtfs: &Gtfs, date: NaiveDate) -> bool {
+    if !data_import::active_service_ids(gtfs, date).is_empty() {
+        return true;
+    }
+    log::error!("The GTFS feed has no service on {date}.");
+    if let Some((first, last)) = data_import::service_date_range(gtfs) {
+        log::error!("The feed covers {first} to {last}.");
+    }
+    if let Some(nearest) = data_import::nearest_service_dates(gtfs, date, 1, false).first() {
+        log::error!("The nearest date with service is {nearest}.");
+    }
+    false
+}
+
+// Returns true (after explaining why) if the network has no trips, or far fewer than a normal weekday.
+fn is_service_reduced(gtfs: &Gtfs, date: NaiveDate, num_trips: usize) -> bool {
+    // A network with less than this proportion of a normal weekday's trips is probably a holiday timetable.
+    const REDUCED_SERVICE_PROPORTION: f64 = 0.25;
+
+    let nearest_weekdays = data_import::nearest_service_dates(gtfs, date, 3, true);
+    let weekday_trips = nearest_weekdays.first().map_or(0, |&weekday| data_import::count_active_trips(gtfs, weekday));
+    if num_trips > 0 && num_trips as f64 >= REDUCED_SERVICE_PROPORTION * weekday_trips as f64 {
+        return false;
+    }
+
+    log::warn!("Only {num_trips} trips run on {date}, compared to {weekday_trips} on a normal weekday.");
+    if let Some((first, last)) = data_import::service_date_range(gtfs) {
+        log::warn!("The feed covers {first} to {last}.");
+    }
+    log::warn!("{} service ids are active on {date}.", data_import::active_service_ids(gtfs, date).len());
+    if !nearest_weekdays.is_empty() {
+        log::warn!("Nearest weekdays with service: {}.", nearest_weekdays.iter().join(", "));
+    }
+    true
+}
+
+fn prompt_date(gtfs: &Gtfs) -> Result<NaiveDate, std::io::Error> {
+    loop {
+        let date_str = user_input("Which day to model? (YYYY-MM-DD, DD/MM/YYYY or DD/MM): ")?.unwrap_or(String::new());
+        match parse_date(&date_str, gtfs) {
+            Some(date) if check_service(gtfs, date) => break Ok(date),
+            Some(_) => println!("Please choose another date."),
+            None => println!("Invalid date {date_str}. Please try again."),
+        }
+    }
+}
+
+// Runs the exports of a run into one folder. A failed export is logged and recorded, and the remaining exports still run,
+// so one full disk or unwritable file doesn't lose the rest of the results. Exports in a group the export set leaves out are skipped.
+struct ExportLog<'a> {
+    dir: &'a Path,
+    export_set: &'a ExportSet,
+    produced: Mutex<Vec<String>>,
+    failed: Mutex<Vec<String>>,
+}
+
+impl<'a> ExportLog<'a> {
+    fn new(dir: &'a Path, export_set: &'a ExportSet) -> Self {
+        Self { dir, export_set, produced: Mutex::new(Vec::new()), failed: Mutex::new(Vec::new()) }
+    }
+
+    // Exports one file, reporting when it starts and how long it took, or why it failed.
+    fn step(&self, name: &str, export: impl FnOnce() -> Result<(), DataExportError>) {
+        log::debug!("Exporting {name}.");
+        let start = Instant::now();
+        match export() {
+            Ok(()) => {
+                log::info!("Exported {name} in {:?}.", start.elapsed());
+                self.produced.lock().unwrap().push(name.to_owned());
+            }
+            Err(err) => {
+                log::error!("Failed to export {name} to {}: {err}", self.dir.display());
+                self.failed.lock().unwrap().push(name.to_owned());
+            }
+        }
+    }
+
+    // An export in the csv group.
+    fn csv(&self, name: &str, export: impl FnOnce() -> Result<(), DataExportError>) {
+        if self.export_set.csv {
+            self.step(name, export);
+        }
+    }
+
+    // An export in the summary group.
+    fn summary(&self, name: &str, export: impl FnOnce() -> Result<(), DataExportError>) {
+        if self.export_set.summary {
+            self.step(name, export);
+        }
+    }
+
+    fn produced(&self) -> Vec<String> {
+        self.produced.lock().unwrap().clone()
+    }
+
+    fn failed(&self) -> Vec<String> {
+        self.failed.lock().unwrap().clone()
+    }
+
+    // Logs which exports failed, returning false if any did.
+    fn log_summary(&self) -> bool {
+        let failed = self.failed.lock().unwrap();
+        if !failed.is_empty() {
+            log::error!("{} exports to {} failed: {}.", failed.len(), self.dir.display(), failed.join(", "));
+        }
+        failed.is_empty()
+    }
+}
+
+// Prints simulation progress and an ETA every `interval` completed simulation steps.
+// Called from the rayon workers, so it only uses atomics and rarely prints.
+struct ProgressReporter {
+    interval: usize,
+    completed: AtomicUsize,
+    total: AtomicUsize,
+    start: Mutex<Instant>,
+}
+
+impl ProgressReporter {
+    fn new(interval: usize) -> Self {
+        Self { interval, completed: AtomicUsize::new(0), total: AtomicUsize::new(0), start: Mutex::new(Instant::now()) }
+    }
+
+    fn reset(&self, total: usize) {
+        self.completed.store(0, Ordering::Relaxed);
+        self.total.store(total, Ordering::Relaxed);
+        *self.start.lock().unwrap() = Instant::now();
+    }
+
+    fn step(&self) {
+        let completed = self.completed.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.interval == 0 || completed % self.interval != 0 {
+            return;
+        }
+        let total = self.total.load(Ordering::Relaxed).max(completed);
+        let elapsed = self.start.lock().unwrap().elapsed();
+        let remaining = elapsed.mul_f64((total - completed) as f64 / completed as f64);
+        log::info!("Simulated {completed}/{total} steps ({:.1}%), ETA {}s.", 100. * completed as f64 / total as f64, remaining.as_secs());
+    }
+}
+
+// Reads a warm start from a checkpoint, or a loads.csv checked against the run metadata exported with it.
+fn load_warm_start(path: &Path, network: &Network) -> Result<checkpoint::WarmStart, Box<dyn std::error::Error + Send + Sync>> {
+    if !path.extension().is_some_and(|extension| extension == "csv") {
+        return Ok(checkpoint::WarmStart::from_checkpoint(&SimulationCheckpoint::read(path)?, network)?);
+    }
+
+    let mut warm_start = data_import::import_warm_start_loads(File::open(path)?, network)?;
+    let previous_run = metadata::read_previous_run(path.parent().unwrap_or(Path::new(".")));
+    match previous_run.as_ref().and_then(|previous_run| previous_run.network_hash) {
+        Some(network_hash) if network_hash == checkpoint::network_hash(network) => {}
+        Some(_) => log::warn!("The warm start loads are from a different network, so they were matched by trip."),
+        None => log::warn!("No run metadata next to {}, so the warm start network can't be checked.", path.display()),
+    }
+    if warm_start.num_unmatched > 0 {
+        log::warn!("{} segments aren't in the warm start loads, so they start from zero.", warm_start.num_unmatched);
+    }
+    warm_start.cold_start_rounds = previous_run.and_then(|previous_run| previous_run.cold_start_rounds);
+    Ok(warm_start)
+}
+
+// Rayon treats zero threads as its own default, so it's rejected here so the pool is always the size asked for.
+fn create_pool(num_threads: usize) -> Result<rayon::ThreadPool, Box<dyn std::error::Error>> {
+    if num_threads == 0 {
+        return Err("The thread pool needs at least one thread.".into());
+    }
+    Ok(rayon::ThreadPoolBuilder::new().num_threads(num_threads).build()?)
+}
+
+// Simulates the steps `num_runs` times on a pool with each thread count, writing the duration of every run to `path`
+// and logging the mean, min and max duration per agent for each thread count.
+fn run_benchmark(path: &Path, thread_counts: &[usize], num_runs: usize, network: &mut Network, simulation_steps: &[SimulationStep], params: &DefaultSimulationParams, progress: &ProgressReporter) -> Result<(), Box<dyn std::error::Error>> {
+    let num_agents = simulation_steps.iter().map(|step| step.count() as u64).sum::<u64>();
+    let mut csv_writer = csv::Writer::from_path(path)?;
+    csv_writer.write_record(&["thread_count", "agents", "run", "duration_us"])?;
+    for &thread_count in thread_counts {
+        let pool = create_pool(thread_count)?;
+        let mut durations = Vec::with_capacity(num_runs);
+        for run in 0..num_runs {
+            progress.reset(simulation_steps.len() * params.num_rounds as usize);
+            let simulation_start = Instant::now();
+            let simulation_result = pool.install(|| simulation::run_simulation_with_dwell(network, simulation_steps, params));
+            let duration = simulation_start.elapsed();
+            if simulation_result.cancelled {
+                csv_writer.flush()?;
+                return Err("The benchmark was cancelled.".into());
+            }
+            csv_writer.write_record(&[thread_count.to_string(), num_agents.to_string(), (run + 1).to_string(), duration.as_micros().to_string()])?;
+            durations.push(duration);
+        }
+
+        let per_agent = |duration: Duration| duration.div_f64(num_agents.max(1) as f64);
+        let mean = durations.iter().sum::<Duration>() / num_runs as u32;
+        log::info!("Benchmark with {thread_count} threads: mean {:?}, min {:?}, max {:?} per agent over {num_runs} runs.",
+                   per_agent(mean),
+                   per_agent(durations.iter().copied().min().unwrap_or_default()),
+                   per_agent(durations.iter().copied().max().unwrap_or_default()));
+    }
+    csv_writer.flush()?;
+    log::info!("Wrote benchmark results to {}.", path.display());
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    if let Some(path) = &cli.write_default_config {
+        match path {
+            Some(path) => {
+                fs::write(path, DEFAULT_CONFIG_TEMPLATE)?;
+                println!("Wrote default config to {}.", path.display());
+            }
+            None => print!("{DEFAULT_CONFIG_TEMPLATE}"),
+        }
+        return Ok(());
+    }
+
+    let exec_start = Instant::now();
+
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(match (cli.quiet, cli.verbose) {
+        (true, _) => log::LevelFilter::Error,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        (false, _) => log::LevelFilter::Trace,
+    });
+
+    // Without a config file we fall back to asking for anything not given on the command line.
+    let interactive = cli.config.is_none();
+    let file_config = match &cli.config {
+        Some(path) => Some(RunConfig::from_file(path)?),
+        None => None,
+    };
+    let gtfs_paths = match (cli.gtfs.is_empty(), &file_config) {
+        (false, _) => cli.gtfs.clone(),
+        (true, Some(config)) => std::iter::once(config.gtfs_path.clone()).chain(config.additional_gtfs_paths.iter().cloned()).collect(),
+        (true, None) => vec![prompt_gtfs_path()?],
+    };
+    let gtfs_cache_dir = match (&cli.gtfs_cache_dir, &file_config) {
+        (Some(gtfs_cache_dir), _) => gtfs_cache_dir.clone(),
+        (None, Some(config)) => config.gtfs_cache_dir.clone(),
+        (None, None) => PathBuf::from(DEFAULT_GTFS_CACHE_DIR),
+    };
+    let stop_merge_distance = match (cli.stop_merge_distance, &file_config) {
+        (Some(stop_merge_distance), _) => stop_merge_distance,
+        (None, Some(config)) => config.stop_merge_distance,
+        (None, None) => DEFAULT_STOP_MERGE_DISTANCE,
+    };
+
+    // With a config file the dates are known before the feeds are read, so each is read from its copy cut down to them if
+    // it's cached (see `feed_cache`). A disruption or trip updates can name the trips of any day, so need the whole feed.
+    let cache_dates = match &file_config {
+        _ if cli.no_cache || cli.disruption.is_some() || cli.trip_updates.is_some() => None,
+        Some(config) if config.disruption.is_some() || config.trip_updates.is_some() => None,
+        Some(config) => Some(match cli.dates {
+            Some((start, end)) => start.iter_days().take_while(|&date| date <= end).collect_vec(),
+            None => vec![cli.date.unwrap_or(config.date)],
+        }),
+        None => None,
+    };
+
+    // The GTFS is loaded before asking for a date, so the date can be checked against the feed's calendar.
+    let gtfs_start = Instant::now();
+    let mut gtfs_files = Vec::with_capacity(gtfs_paths.len());
+    let mut feeds = Vec::with_capacity(gtfs_paths.len());
+    for gtfs_path in gtfs_paths.iter() {
+        let (gtfs_file, feed) = load_gtfs(gtfs_path, &gtfs_cache_dir, cli.refresh, cache_dates.as_deref())?;
+        feeds.push((gtfs_path.display().to_string(), feed));
+        gtfs_files.push(gtfs_file);
+    }
+    let (mut gtfs, feed_stats) = data_import::merge_feeds(feeds, stop_merge_distance).ok_or("No GTFS feed given.")?;
+    if feed_stats.len() > 1 {
+        for stats in feed_stats.iter() {
+            log::info!("Feed {}: {} stops ({} merged with an earlier feed), {} routes, {} trips.", stats.name, stats.num_stops, stats.num_merged_stops, stats.num_routes, stats.num_trips);
+        }
+    }
+    let gtfs_duration = gtfs_start.elapsed();
+    log::info!("GTFS import: {:?}", gtfs_duration);
+    if cli.stats {
+        gtfs.print_stats();
+    }
+
+    let mut config = match file_config {
+        Some(config) => config,
+        None => {
+            let date = match cli.date.or(cli.dates.map(|(start, _)| start)) {
+                Some(date) => date,
+                None => prompt_date(&gtfs)?,
+            };
+            let mut config = RunConfig::new(gtfs_paths[0].clone(), date);
+            config.additional_gtfs_paths = gtfs_paths[1..].to_vec();
+            config
+        }
+    };
+    cli.apply_overrides(&mut config);
+    config.validate()?;
+    let exporter_registry = ExporterRegistry::with_builtin(config.shape_colouring, config.sqlite_journeys);
+    let export_set = ExportSet::parse(&cli.export, &config.exporters, &exporter_registry)?;
+    let exporters = exporter_registry.select(&export_set.exporters)?;
+    if cli.log_file {
+        fs::create_dir_all(&config.export_dir)?;
+        let log_path = config.export_dir.join("train_ute.log");
+        *LOGGER.log_file.lock().unwrap() = Some(File::create(&log_path)?);
+        log::info!("Logging to {}.", log_path.display());
+    }
+    let route_filter_result = config.route_filter().apply(&mut gtfs);
+    if !config.route_filter().is_empty() {
+        log::info!("Route filter removed {} routes, {} trips, {} shapes and {} stops.",
+                   route_filter_result.num_routes_removed,
+                   route_filter_result.num_trips_removed,
+                   route_filter_result.num_shapes_removed,
+                   route_filter_result.removed_stop_ids.len());
+        if gtfs.routes.is_empty() {
+            return Err("The route filter removed every route.".into());
+        }
+    }
+
+    let frequency_expansion = data_import::expand_frequencies(&mut gtfs);
+    if frequency_expansion.num_frequency_trips > 0 {
+        log::info!("Expanded {} frequency-based trips into {} trips ({:.1} per trip).",
+                   frequency_expansion.num_frequency_trips,
+                   frequency_expansion.num_generated_trips,
+                   frequency_expansion.expansion_factor());
+    }
+
+    // Each day of a date range is modelled on its own network, built from the same feed.
+    let multi_day = cli.dates.is_some();
+    let dates = match cli.dates {
+        Some((start, end)) => start.iter_days().take_while(|&date| date <= end).collect_vec(),
+        None => vec![config.date],
+    };
+    if !multi_day && !check_service(&gtfs, config.date) {
+        return Err(format!("No service on {}.", config.date).into());
+    }
+    // The undisrupted network is built from the feed before the disruption is applied to it, which only happens once.
+    if multi_day && config.disruption.is_some() {
+        return Err("A disruption can't be modelled over a date range.".into());
+    }
+    // Trains are replaced in the feed, which every day is built from.
+    if multi_day && !config.bus_replacements.is_empty() {
+        return Err("A bus replacement can't be modelled over a date range.".into());
+    }
+    if multi_day && config.trip_updates.is_some() {
+        return Err("Trip updates can't be applied over a date range.".into());
+    }
+    if multi_day && cli.resume.is_some() {
+        return Err("A checkpoint can't be resumed over a date range.".into());
+    }
+    if multi_day && cli.warm_start.is_some() {
+        return Err("A warm start can't be used over a date range.".into());
+    }
+    if multi_day && cli.bundle.is_some() {
+        return Err("A bundle can't be written over a date range.".into());
+    }
+    // Used by the first run only, later interactive runs start from scratch.
+    let mut resume_checkpoint = match &cli.resume {
+        Some(path) => Some(Arc::new(SimulationCheckpoint::read(path)?)),
+        None => None,
+    };
+    let mut warm_start_path = cli.warm_start.clone();
+    let supplementary_trip_ids = config.add_supplementary_trips(&mut gtfs, &dates)?;
+    let mut disruption = config.load_disruption(&gtfs)?;
+    let bus_replacements = config.apply_bus_replacements(&mut gtfs)?;
+    #[cfg(feature = "gtfs_rt")]
+    let trip_update_report = config.apply_trip_updates(&mut gtfs)?;
+    #[cfg(not(feature = "gtfs_rt"))]
+    if config.trip_updates.is_some() {
+        log::warn!("Built without the gtfs_rt feature, so the trip updates can't be applied.");
+    }
+    // Collapsed after the supplementary trips and disruption are loaded, so they can still refer to platforms.
+    let parent_stations = if config.parent_stations {
+        let parent_stations = data_import::collapse_to_parent_stations(&mut gtfs);
+        log::info!("Replaced {} stops with {} parent stations.", parent_stations.station_ids.len(), parent_stations.children().len());
+        if let Some(disruption) = &mut disruption {
+            disruption.collapse_to_parent_stations(&parent_stations);
+        }
+        Some(parent_stations)
+    } else {
+        None
+    };
+
+    // The first Ctrl-C stops the simulation and exports the partial results, a second one aborts immediately.
+    let cancellation = Arc::new(AtomicBool::new(false));
+    {
+        let cancellation = cancellation.clone();
+        ctrlc::set_handler(move || {
+            if cancellation.swap(true, Ordering::Relaxed) {
+                std::process::exit(ABORTED_EXIT_CODE);
+            }
+            log::warn!("Cancelling, partial results will be exported. Press Ctrl-C again to abort.");
+        })?;
+    }
+    let progress = Arc::new(ProgressReporter::new(config.progress_interval));
+
+    // The scenario only changes how the simulation runs (capacities, crowding, route choice, ...), so the network and demand are those of the base run.
+    let scenario_config = match &cli.compare {
+        Some(path) => {
+            let scenario_config = RunConfig::from_file(path)?;
+            scenario_config.validate()?;
+            if scenario_config.gtfs_path != config.gtfs_path || scenario_config.date != config.date || scenario_config.od_matrix != config.od_matrix {
+                log::warn!("The scenario in {} uses a different GTFS feed, date or demand, which is ignored. Only its simulation settings are compared.", path.display());
+            }
+            Some(scenario_config)
+        }
+        None => None,
+    };
+
+    let benchmark = !cli.benchmark.is_empty();
+    if benchmark && (cli.benchmark.contains(&0) || cli.benchmark_runs == 0) {
+        return Err("Benchmark thread counts and runs must be greater than zero.".into());
+    }
+    let num_processors = match config.threads {
+        Some(threads) => threads,
+        // The benchmark makes its own pools.
+        None if benchmark => 1,
+        None => std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+    };
+    // Created once and shared by every day and interactive run.
+    let pool = create_pool(num_processors)?;
+    let inspect_json = match &cli.command {
+        Some(Command::Inspect { json }) => Some(*json),
+        _ => None,
+    };
+    let isochrone_query = match &cli.command {
+        Some(Command::Isochrone { from, depart, crowding }) => Some((from, depart, crowding)),
+        _ => None,
+    };
+    if !benchmark && inspect_json.is_none() && isochrone_query.is_none() {
+        log::info!("Simulating with {num_processors} threads.");
+    }
+
+    let base_export_dir = config.export_dir.clone();
+    let mut daily_summaries = Vec::new();
+    let mut run_cancelled = false;
+    let mut export_failed = false;
+    let uncoloured_route_ids = data_import::gtfs_routes_without_colour(&gtfs_files);
+    let stop_transfer_times = config.load_transfer_times(&gtfs_files)?;
+    let network_transfer_time = stop_transfer_times.as_ref().map_or(config.default_transfer_time, |stop_transfer_times| stop_transfer_times.network_transfer_time(config.default_transfer_time));
+    // Everything built for a day (network, demand and results) is dropped before the next day is modelled.
+    'days: for date in dates {
+        if multi_day {
+            if data_import::active_service_ids(&gtfs, date).is_empty() {
+                log::warn!("No service on {date}, skipping it.");
+                continue;
+            }
+            log::info!("Modelling {date}.");
+            config.date = date;
+            config.export_dir = base_export_dir.join(date.to_string());
+        }
+
+        // Set up network.
+        let mut network_duration = Duration::ZERO;
+        let mut connections_duration = Duration::ZERO;
+        let mut network = 'network: loop {
+            let network_start = Instant::now();
+            let mut network = Network::new(&gtfs, None, config.date, network_transfer_time);
+            data_export::assign_route_styles(&mut network, &gtfs, &uncoloured_route_ids, &config.shape_colouring);
+            network_duration = network_start.elapsed();
+            log::info!("Network parse: {:?}", network_duration);
+
+            let num_trips = (0..network.num_routes()).map(|route_idx| network.num_trips(route_idx)).sum::<usize>();
+            // Over a date range, days with reduced service (e.g. weekends) are modelled anyway.
+            if is_service_reduced(&gtfs, config.date, num_trips) && !multi_day {
+                if !interactive {
+                    return Err(format!("Only {num_trips} trips run on {}.", config.date).into());
+                }
+                // Rebuild the network if the user picks another date, otherwise carry on with this one.
+                while let Some(date_str) = user_input("Enter another date, or press enter to continue anyway: ")? {
+                    match parse_date(&date_str, &gtfs) {
+                        Some(date) => {
+                            config.date = date;
+                            continue 'network;
+                        }
+                        None => println!("Invalid date {date_str}. Please try again."),
+                    }
+                }
+            }
+
+            let connections_start = Instant::now();
+            network.build_connections();
+            connections_duration = connections_start.elapsed();
+            log::info!("Build connections: {:?}", connections_duration);
+
+            if cli.stats {
+                network.print_stats();
+            }
+
+            break network;
+        };
+
+        // The undisrupted network is kept to find the agents whose journeys the disruption affects.
+        let mut undisrupted_network = match &disruption {
+            Some(disruption) => {
+                disruption.apply(&mut gtfs);
+                let disrupted_network = config.build_network_with_transfer_times(&gtfs, stop_transfer_times.as_ref());
+                let undisrupted_network = std::mem::replace(&mut network, disrupted_network);
+                // Agents are simulated on both networks, so they must have the same stops (which the disruption leaves alone).
+                if undisrupted_network.stops.len() != network.stops.len() || undisrupted_network.stops.iter().zip(network.stops.iter()).any(|(a, b)| a.id != b.id) {
+                    return Err("The disruption changed the network's stops.".into());
+                }
+                Some(undisrupted_network)
+            }
+            None => None,
+        };
+        // The disruption leaves the stops alone, so this is the same for both networks.
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        stop_geometry.log();
+
+        if let Some(json) = inspect_json {
+            let network_stats = NetworkStats::new(&network);
+            network_stats.log();
+            if json {
+                fs::create_dir_all(&config.export_dir)?;
+                let path = config.export_dir.join("network_stats.json");
+                serde_json::to_writer_pretty(std::io::BufWriter::new(File::create(&path)?), &network_stats)?;
+                log::info!("Wrote {}.", path.display());
+            }
+            continue;
+        }
+
+        // Set up simulation.
+        let mut params = config.simulation_params();
+        params.cancellation = Some(cancellation.clone());
+        if config.progress_interval > 0 {
+            let progress = progress.clone();
+            params.progress_callback = Some(Box::new(move || progress.step()));
+        }
+        config.load_capacities(&network, &gtfs, &mut params.trip_capacities, &bus_replacements)?;
+
+        if let Some((from, depart, crowding)) = isochrone_query {
+            let origin_stop = query::find_stop(&network, from)?;
+            let departure_time = query::parse_query_time(depart)?;
+            // Loaded like a warm start, so the loads can be from a checkpoint or the loads.csv of a different network.
+            let population_count = match crowding {
+                Some(path) => Some(load_warm_start(path, &network)?.averaged_population.iter().map(|&count| count.round() as PopulationCount).collect_vec()),
+                None => None,
+            };
+            let travel_times = simulation::plan_isochrone(&network, &params, population_count.as_deref(), origin_stop, departure_time);
+            isochrone::log_isochrone(&network, origin_stop, departure_time, &travel_times);
+            fs::create_dir_all(&config.export_dir)?;
+            let path = config.export_dir.join("isochrone");
+            isochrone::export_isochrone(&path, &network, &stop_geometry, origin_stop, departure_time, &travel_times)?;
+            log::info!("Wrote {} and {}.", path.with_extension("csv").display(), path.with_extension("geojson").display());
+            continue;
+        }
+
+        let (mut od_simulation_steps, access_report) = match config.od_matrix {
+            Some(_) => (Some(config.simulation_steps(&network, &route_filter_result.removed_stop_ids)?), None),
+            None => match config.load_point_od_matrix(&network)? {
+                Some((simulation_steps, access_report)) => (Some(simulation_steps), Some(access_report)),
+                None => (None, None),
+            },
+        };
+
+        // Leave out the OD pairs no agent could find a journey for, rather than planning them every round.
+        let reachability_report = match &mut od_simulation_steps {
+            Some(simulation_steps) => {
+                let reachability_report = reachability::check_reachability(&network, simulation_steps, &params.journey_preferences);
+                reachability_report.log();
+                if config.fail_on_unreachable && !reachability_report.unreachable.is_empty() {
+                    for pair in reachability_report.unreachable.iter().take(10) {
+                        log::error!("No journey from {} to {} ({} agents).", network.stops[pair.origin_stop as usize].name, network.stops[pair.dest_stop as usize].name, pair.count);
+                    }
+                    return Err(format!("{} OD pairs with {} agents have no journey on {}.", reachability_report.unreachable.len(), reachability_report.unreachable_count(), network.date).into());
+                }
+                reachability::remove_unreachable(simulation_steps, &reachability_report);
+                Some(reachability_report)
+            }
+            None => None,
+        };
+
+        if config.wheelchair.share > 0. || od_simulation_steps.iter().flatten().any(|sim_step| sim_step.requires_accessible) {
+            let wheelchair_access = WheelchairAccess::new(&network, &gtfs, config.wheelchair.assume_unknown_accessible);
+            wheelchair_access.log();
+            params.wheelchair_access = Some(Arc::new(wheelchair_access));
+        }
+
+        if let Some(stop_transfer_times) = &stop_transfer_times {
+            let (transfer_times, unknown_stop_ids) = TransferTimes::new(&network, config.default_transfer_time, stop_transfer_times);
+            if !unknown_stop_ids.is_empty() {
+                log::warn!("{} stops in the transfer times aren't in the network: {}.", unknown_stop_ids.len(), unknown_stop_ids.iter().take(10).join(", "));
+            }
+            transfer_times.log();
+            if config.export_transfer_times {
+                fs::create_dir_all(&config.export_dir)?;
+                let path = config.export_dir.join("transfer_times");
+                transfer_times.export_csv(&path, &network)?;
+                log::info!("Wrote {}.", path.with_extension("csv").display());
+            }
+            params.transfer_times = Some(Arc::new(transfer_times));
+        }
+
+        let journey_query = match (&cli.query_from, &cli.query_to, &cli.query_depart) {
+            (Some(from), Some(to), Some(depart)) => Some((query::find_stop(&network, from)?, query::find_stop(&network, to)?, query::parse_query_time(depart)?)),
+            _ => None,
+        };
+
+        let observed_loads = config.load_observed_loads()?;
+        let stop_capacities = config.load_stop_capacities(&network)?;
+        let periods = config.reporting_periods()?;
+
+        let scenario = match &scenario_config {
+            Some(scenario_config) => {
+                let mut scenario_params = scenario_config.simulation_params();
+                scenario_params.cancellation = params.cancellation.clone();
+                if config.progress_interval > 0 {
+                    let progress = progress.clone();
+                    scenario_params.progress_callback = Some(Box::new(move || progress.step()));
+                }
+                // The network is the base run's, so the shuttles are too.
+                scenario_config.load_capacities(&network, &gtfs, &mut scenario_params.trip_capacities, &bus_replacements)?;
+                // The demand is the base run's, so its agents needing wheelchair access are too.
+                scenario_params.wheelchair_access = params.wheelchair_access.clone();
+                scenario_params.transfer_times = params.transfer_times.clone();
+                Some(scenario_params)
+            }
+            None => None,
+        };
+
+        if benchmark {
+            let generated_simulation_steps;
+            let simulation_steps = match &od_simulation_steps {
+                Some(simulation_steps) => simulation_steps,
+                None => {
+                    generated_simulation_steps = config.generate_simulation_steps(&network, config.num_agents)?;
+                    &generated_simulation_steps
+                }
+            };
+            fs::create_dir_all(&config.export_dir)?;
+            run_benchmark(&config.export_dir.join("simulation_scaling.csv"), &cli.benchmark, cli.benchmark_runs, &mut network, simulation_steps, &params, &progress)?;
+            continue;
+        }
+
+        // A sweep runs every combination of its parameters on the network and agents instead of a single run,
+        // exporting each into a subfolder named by its parameters.
+        if let Some(sweep) = &config.sweep {
+            let generated_simulation_steps;
+            let simulation_steps = match &od_simulation_steps {
+                Some(simulation_steps) => simulation_steps,
+                None => {
+                    generated_simulation_steps = config.generate_simulation_steps(&network, config.num_agents)?;
+                    &generated_simulation_steps
+                }
+            };
+            let points = sweep.points();
+            // The dwell model changes the network's stop times during a run, so runs using it can't share the network.
+            let max_parallel_runs = if params.dwell_model.is_some() && config.max_parallel_runs > 1 {
+                log::warn!("The dwell model changes the network during a run, so the sweep runs one at a time.");
+                1
+            } else {
+                config.max_parallel_runs.min(points.len())
+            };
+            log::info!("Sweeping {} combinations of parameters, {max_parallel_runs} at a time.", points.len());
+            for point in points.iter() {
+                fs::create_dir_all(config.export_dir.join(point.name()))?;
+            }
+
+            // Only the swept parameters differ from the base run's.
+            let sweep_params = |point: &SweepPoint| {
+                let mut sweep_params = config.simulation_params();
+                sweep_params.cancellation = params.cancellation.clone();
+                sweep_params.wheelchair_access = params.wheelchair_access.clone();
+                sweep_params.transfer_times = params.transfer_times.clone();
+                point.apply(&mut sweep_params, &params.trip_capacities);
+                sweep_params
+            };
+            let sweep_export_failed = AtomicBool::new(false);
+            // Exports the run into its folder, returning its headline results.
+            let finish_run = |point: &SweepPoint, network: &Network, sweep_params: &DefaultSimulationParams, simulation_result: SimulationResult, duration: Duration| {
+                let run_dir = config.export_dir.join(point.name());
+                let exports = ExportLog::new(&run_dir, &export_set);
+                let segment_costs = data_export::segment_crowding_costs(network, sweep_params, &simulation_result.population_count);
+                let export_context = ExportContext {
+                    network,
+                    stop_geometry: &stop_geometry,
+                    gtfs: &gtfs,
+                    simulation_result: &simulation_result,
+                    trip_capacities: &sweep_params.trip_capacities,
+                    segment_costs: &segment_costs,
+                    parent_stations: parent_stations.as_ref(),
+                    date: config.date,
+                    output_dir: &run_dir,
+                };
+                for exporter in exporters.iter().filter(|exporter| network.has_shapes || !exporter.needs_shapes()) {
+                    exports.step(exporter.name(), || exporter.export(&export_context));
+                }
+                exports.summary("convergence", || data_export::export_convergence(&run_dir.join("convergence"), &simulation_result));
+                if config.export_loads {
+                    exports.csv("loads csv", || data_export::export_loads_csv(&run_dir.join("loads"), network, &gtfs, &simulation_result, &sweep_params.trip_capacities));
+                }
+                let travel_stats = data_export::TravelStats::new(network, &stop_geometry, Some(&gtfs), &simulation_result, &sweep_params.trip_capacities, config.shape_dist_km);
+                exports.summary("stats", || travel_stats.export(&run_dir.join("stats")));
+                let validation_report = observed_loads.as_ref().map(|observed_loads| validation::validate_loads(network, &simulation_result, observed_loads));
+                if let Some(validation_report) = &validation_report {
+                    exports.summary("validation", || validation_report.export(&run_dir.join("validation")));
+                }
+                if !exports.log_summary() {
+                    sweep_export_failed.store(true, Ordering::Relaxed);
+                }
+
+                let last_round = simulation_result.iteration_history.last();
+                let run = SweepRun {
+                    point: *point,
+                    num_rounds: simulation_result.iteration_history.len(),
+                    total_crowding_cost: last_round.map_or(0., |stats| stats.total_crowding_cost),
+                    max_segment_load: last_round.map_or(0, |stats| stats.max_segment_load),
+                    passenger_km: travel_stats.passenger_km,
+                    passenger_hours: travel_stats.passenger_hours,
+                    mean_load_factor: travel_stats.mean_load_factor,
+                    rmse: validation_report.and_then(|report| report.rmse()),
+                    duration,
+                };
+                log::info!("Sweep run {}: {} rounds, total crowding cost {:.1}, {:.1} passenger hours, max load {}, in {:?}.",
+                           point.name(),
+                           run.num_rounds,
+                           run.total_crowding_cost,
+                           run.passenger_hours,
+                           run.max_segment_load,
+                           run.duration);
+                run
+            };
+
+            let mut sweep_runs = Vec::with_capacity(points.len());
+            let mut sweep_cancelled = false;
+            if max_parallel_runs == 1 {
+                for point in points.iter() {
+                    let mut sweep_params = sweep_params(point);
+                    if config.progress_interval > 0 {
+                        let progress = progress.clone();
+                        sweep_params.progress_callback = Some(Box::new(move || progress.step()));
+                    }
+                    progress.reset(simulation_steps.len() * sweep_params.num_rounds as usize);
+                    let simulation_start = Instant::now();
+                    let simulation_result = pool.install(|| simulation::run_simulation_with_dwell(&mut network, simulation_steps, &sweep_params));
+                    if simulation_result.cancelled {
+                        sweep_cancelled = true;
+                        break;
+                    }
+                    sweep_runs.push(finish_run(point, &network, &sweep_params, simulation_result, simulation_start.elapsed()));
+                }
+            } else {
+                // The runs share the network and the pool, taking the next point as each finishes. Their progress would
+                // interleave, so only finished runs are reported.
+                let network = &network;
+                let next_point = AtomicUsize::new(0);
+                let any_cancelled = AtomicBool::new(false);
+                let finished_runs = Mutex::new(Vec::new());
+                std::thread::scope(|scope| {
+                    for _ in 0..max_parallel_runs {
+                        scope.spawn(|| loop {
+                            let i = next_point.fetch_add(1, Ordering::Relaxed);
+                            if i >= points.len() || any_cancelled.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            let sweep_params = sweep_params(&points[i]);
+                            let simulation_start = Instant::now();
+                            let simulation_result = pool.install(|| simulation::run_simulation(network, simulation_steps, &sweep_params));
+                            if simulation_result.cancelled {
+                                any_cancelled.store(true, Ordering::Relaxed);
+                                break;
+                            }
+                            let run = finish_run(&points[i], network, &sweep_params, simulation_result, simulation_start.elapsed());
+                            finished_runs.lock().unwrap().push((i, run));
+                        });
+                    }
+                });
+                sweep_cancelled = any_cancelled.into_inner();
+                sweep_runs = finished_runs.into_inner().unwrap().into_iter().sorted_by_key(|&(i, _)| i).map(|(_, run)| run).collect();
+            }
+
+            let exports = ExportLog::new(&config.export_dir, &export_set);
+            exports.step("sweep summary", || sweep::export_sweep_summary(&config.export_dir.join("sweep_summary"), &sweep_runs));
+            export_failed |= !exports.log_summary() || sweep_export_failed.into_inner();
+            if sweep_cancelled {
+                run_cancelled = true;
+                break 'days;
+            }
+            continue;
+        }
+
+        // Replications repeat the run with consecutive seeds on the same network, so the spread of the loads and
+        // headline statistics over the random demand and choices can be reported.
+        if config.replications > 1 {
+            let base_seed = config.seed;
+            let first_seed = base_seed.unwrap_or(0);
+            // A dwell model updates the network's stop times between rounds, so those replications can't run side by side.
+            let max_parallel_runs = if params.dwell_model.is_some() && config.max_parallel_runs > 1 {
+                log::warn!("The dwell model changes the network during a run, so the replications run one at a time.");
+                1
+            } else {
+                config.max_parallel_runs.min(config.replications)
+            };
+            log::info!("Running {} replications with seeds {first_seed} to {}, {max_parallel_runs} at a time.", config.replications, first_seed.wrapping_add(config.replications as u64 - 1));
+
+            let mut replication_summary = ReplicationSummary::new(network.stop_times.len());
+            let mut last_result = None;
+            let mut replication_cancelled = false;
+            let replications = (0..config.replications as u64).collect_vec();
+            for batch in replications.chunks(max_parallel_runs) {
+                // The demand is drawn with the config's seed, so each replication's is drawn in turn before the batch runs.
+                let mut batch_runs = Vec::with_capacity(batch.len());
+                for &replication in batch {
+                    let seed = first_seed.wrapping_add(replication);
+                    config.seed = Some(seed);
+                    let mut simulation_steps = if config.od_matrix.is_some() {
+                        config.simulation_steps(&network, &route_filter_result.removed_stop_ids)?
+                    } else if let Some((simulation_steps, _)) = config.load_point_od_matrix(&network)? {
+                        simulation_steps
+                    } else {
+                        config.generate_simulation_steps(&network, config.num_agents)?
+                    };
+                    if let Some(reachability_report) = &reachability_report {
+                        reachability::remove_unreachable(&mut simulation_steps, reachability_report);
+                    }
+                    let mut replication_params = config.simulation_params();
+                    replication_params.cancellation = params.cancellation.clone();
+                    replication_params.wheelchair_access = params.wheelchair_access.clone();
+                    replication_params.transfer_times = params.transfer_times.clone();
+                    replication_params.trip_capacities = params.trip_capacities.clone();
+                    batch_runs.push((seed, simulation_steps, replication_params));
+                }
+
+                let results = if let [(_, simulation_steps, replication_params)] = &mut batch_runs[..] {
+                    if config.progress_interval > 0 {
+                        let progress = progress.clone();
+                        replication_params.progress_callback = Some(Box::new(move || progress.step()));
+                    }
+                    progress.reset(simulation_steps.len() * replication_params.num_rounds as usize);
+                    vec![pool.install(|| simulation::run_simulation_with_dwell(&mut network, &*simulation_steps, &*replication_params))]
+                } else {
+                    // The batch shares the network and the pool. Their progress would interleave, so only finished runs are reported.
+                    let (network, pool) = (&network, &pool);
+                    std::thread::scope(|scope| {
+                        let handles = batch_runs.iter().map(|(_, simulation_steps, replication_params)| {
+                            scope.spawn(move || pool.install(|| simulation::run_simulation(network, simulation_steps, replication_params)))
+                        }).collect_vec();
+                        handles.into_iter().map(|handle| handle.join().expect("Replication thread panicked.")).collect_vec()
+                    })
+                };
+
+                for ((seed, simulation_steps, replication_params), simulation_result) in batch_runs.into_iter().zip(results) {
+                    if simulation_result.cancelled {
+                        replication_cancelled = true;
+                        continue;
+                    }
+                    let metrics = replication::headline_metrics(&network, &stop_geometry, &gtfs, &simulation_steps, &simulation_result, &replication_params.trip_capacities, config.shape_dist_km);
+                    log::info!("Replication with seed {seed}: {} rounds, total crowding cost {:.1}, {:.1} passenger hours.", metrics[1], metrics[2], metrics[5]);
+                    replication_summary.add(seed, metrics, &simulation_result.population_count);
+                    last_result = Some(simulation_result);
+                }
+                if replication_cancelled {
+                    break;
+                }
+            }
+            config.seed = base_seed;
+
+            fs::create_dir_all(&config.export_dir)?;
+            replication_summary.log();
+            let exports = ExportLog::new(&config.export_dir, &export_set);
+            exports.step("replications", || replication_summary.export_metrics(&config.export_dir.join("replications")));
+            exports.step("replication loads", || replication_summary.export_loads(&config.export_dir.join("replication_loads"), &network));
+            if let Some(last_result) = last_result {
+                // The exporters (including the visualiser's counts) get the mean loads, with the last replication's journeys.
+                let mean_result = SimulationResult { population_count: replication_summary.mean_population_count(), ..last_result };
+                let segment_costs = data_export::segment_crowding_costs(&network, &params, &mean_result.population_count);
+                let export_context = ExportContext {
+                    network: &network,
+                    stop_geometry: &stop_geometry,
+                    gtfs: &gtfs,
+                    simulation_result: &mean_result,
+                    trip_capacities: &params.trip_capacities,
+                    segment_costs: &segment_costs,
+                    parent_stations: parent_stations.as_ref(),
+                    date: config.date,
+                    output_dir: &config.export_dir,
+                };
+                pool.install(|| {
+                    exporters.par_iter()
+                             .filter(|exporter| network.has_shapes || !exporter.needs_shapes())
+                             .for_each(|exporter| exports.step(exporter.name(), || exporter.export(&export_context)));
+                });
+            }
+            export_failed |= !exports.log_summary();
+            if replication_cancelled {
+                run_cancelled = true;
+                break 'days;
+            }
+            continue;
+        }
+
+        loop {
+            let (cancelled, exported) = pool.install(|| -> Result<(bool, bool), Box<dyn std::error::Error + Send + Sync>> {
+                let exports = ExportLog::new(&config.export_dir, &export_set);
+                // Run simulation.
+                let generated_simulation_steps;
+                let simulation_steps = match &od_simulation_steps {
+                    Some(simulation_steps) => simulation_steps,
+                    None => {
+                        let num_agents = if interactive && config.num_agents.is_none() {
+                            Some(prompt_count("Enter number of agents to use: ")?)
+                        } else {
+                            config.num_agents
+                        };
+                        generated_simulation_steps = config.generate_simulation_steps(&network, num_agents)?;
+                        &generated_simulation_steps
+                    }
+                };
+
+                // The agents' best journeys without the disruption (or crowding).
+                let disruption_report = match (&disruption, &mut undisrupted_network) {
+                    (Some(disruption), Some(undisrupted_network)) => {
+                        let num_rounds = params.num_rounds;
+                        params.num_rounds = 1;
+                        progress.reset(simulation_steps.len());
+                        let undisrupted_result = simulation::run_simulation_with_dwell(undisrupted_network, simulation_steps, &params);
+                        params.num_rounds = num_rounds;
+                        let disruption_report = disruption.affected_agents(undisrupted_network, &undisrupted_result);
+                        log::info!("{} agents' best journeys without the disruption used a cancelled or truncated trip.", disruption_report.num_affected_agents);
+                        Some(disruption_report)
+                    }
+                    _ => None,
+                };
+
+                // Calibration reuses the network and agents, and leaves the best parameters in place for the final run.
+                if let (Some(calibration), Some(observed_loads)) = (&config.calibration, &observed_loads) {
+                    let base_capacities = params.trip_capacities.clone();
+                    let calibration_points = calibration.run(|beta, capacity_scale| {
+                        params.crowding_function = CrowdingFunc::Exponential { beta: beta as CrowdingCost };
+                        params.trip_capacities = base_capacities.scaled(capacity_scale);
+                        progress.reset(simulation_steps.len() * config.num_rounds as usize);
+                        let calibration_result = simulation::run_simulation_with_dwell(&mut network, simulation_steps, &params);
+                        if calibration_result.cancelled {
+                            return None;
+                        }
+                        Some(validation::validate_loads(&network, &calibration_result, observed_loads).rmse().unwrap_or(f64::INFINITY))
+                    });
+                    let best = calibration::best_point(&calibration_points).copied().ok_or("Calibration didn't complete any runs.")?;
+                    log::info!("Best calibration: beta {:.4}, capacity scale {:.3}, RMSE {:.3}.", best.beta, best.capacity_scale, best.rmse);
+                    params.crowding_function = CrowdingFunc::Exponential { beta: best.beta as CrowdingCost };
+                    params.trip_capacities = base_capacities.scaled(best.capacity_scale);
+
+                    let data_export_folder = config.export_dir.as_path();
+                    fs::create_dir_all(data_export_folder)?;
+                    exports.step("calibration runs", || calibration::export_calibration_runs(&data_export_folder.join("calibration"), &calibration_points));
+                    // The searched scale is on top of the configured one.
+                    let calibrated = calibration::CalibrationPoint { capacity_scale: best.capacity_scale * config.capacity_scale, ..best };
+                    exports.step("calibrated config", || calibration::write_calibrated_config(&data_export_folder.join("calibration"), &calibrated, calibration_points.len()));
+                }
+
+                // Every round simulates every step, though convergence can end the simulation early.
+                progress.reset(simulation_steps.len() * config.num_rounds as usize);
+                if config.keep_checkpoints > 0 || resume_checkpoint.is_some() {
+                    let checkpointing = Checkpointing {
+                        dir: config.export_dir.join("checkpoints"),
+                        keep: config.keep_checkpoints,
+                        params_hash: checkpoint::params_hash(&params),
+                        resume: resume_checkpoint.take(),
+                    };
+                    if let Some(resume) = &checkpointing.resume {
+                        resume.verify(&network, simulation_steps, &params)?;
+                    }
+                    params.checkpointing = Some(checkpointing);
+                }
+                // Like resuming, only the first run is warm started.
+                let warm_start_source = warm_start_path.take();
+                params.warm_start = match &warm_start_source {
+                    Some(path) => Some(Arc::new(load_warm_start(path, &network)?)),
+                    None => None,
+                };
+                let simulation_start = Instant::now();
+                let simulation_result = simulation::run_simulation_with_dwell(&mut network, simulation_steps, &params);
+                let simulation_duration = simulation_start.elapsed();
+                for stats in simulation_result.iteration_history.iter() {
+                    log::info!("Round {}: total crowding cost {:.1}, relative change {}, load gap {}, {:.1} passenger hours, max load {}, {} agents replanned, {} changed route.",
+                               stats.round_number,
+                               stats.total_crowding_cost,
+                               stats.relative_change.map_or("-".to_owned(), |change| format!("{change:.4}")),
+                               stats.relative_load_gap.map_or("-".to_owned(), |gap| format!("{gap:.4}")),
+                               stats.total_passenger_hours,
+                               stats.max_segment_load,
+                               stats.num_replanned,
+                               stats.num_changed_route.map_or("-".to_owned(), |num| num.to_string()));
+                    if config.plan_cache.is_some() && stats.num_replanned + stats.num_plan_cache_hits > 0 {
+                        log::info!("Round {}: {:.1}% of the agents due to replan kept their cached plan.",
+                                   stats.round_number,
+                                   100. * stats.num_plan_cache_hits as f64 / (stats.num_replanned + stats.num_plan_cache_hits) as f64);
+                    }
+                }
+
+                // The crowding reduction from peak spreading is measured against the same run with everyone departing when they prefer.
+                let peak_spreading_report = match params.departure_choice.take() {
+                    Some(departure_choice) if !simulation_result.cancelled => {
+                        let checkpointing = params.checkpointing.take();
+                        progress.reset(simulation_steps.len() * config.num_rounds as usize);
+                        let baseline_result = simulation::run_simulation_with_dwell(&mut network, simulation_steps, &params);
+                        params.checkpointing = checkpointing;
+                        params.departure_choice = Some(departure_choice);
+                        let report = peak_spreading::PeakSpreadingReport::new(simulation_steps, &simulation_result, (!baseline_result.cancelled).then_some(&baseline_result), config.stop_activity_bin);
+                        report.log();
+                        Some(report)
+                    }
+                    departure_choice => {
+                        params.departure_choice = departure_choice;
+                        None
+                    }
+                };
+
+                if let Some((origin_stop, dest_stop, departure_time)) = journey_query {
+                    println!("Journey from {} to {} departing {} under the final loads:", network.stops[origin_stop as usize].name, network.stops[dest_stop as usize].name, get_time_str(departure_time));
+                    match simulation::plan_journey(&network, &params, &simulation_result.population_count, origin_stop, departure_time, dest_stop) {
+                        Ok(journey) => {
+                            for line in query::format_itinerary(&network, &gtfs, &journey, &simulation_result.population_count, &params.trip_capacities) {
+                                println!("{line}");
+                            }
+                        }
+                        Err(_) => println!("No journey found."),
+                    }
+                }
+
+                let data_export_folder = config.export_dir.as_path();
+                log::info!("Exporting results to {}.", data_export_folder.display());
+                let export_start = Instant::now();
+                fs::create_dir_all(data_export_folder)?;
+                // The exporters are independent and include the largest exports, so they run at the same time on the pool.
+                let segment_costs = data_export::segment_crowding_costs(&network, &params, &simulation_result.population_count);
+                let export_context = ExportContext {
+                    network: &network,
+                    stop_geometry: &stop_geometry,
+                    gtfs: &gtfs,
+                    simulation_result: &simulation_result,
+                    trip_capacities: &params.trip_capacities,
+                    segment_costs: &segment_costs,
+                    parent_stations: parent_stations.as_ref(),
+                    date: config.date,
+                    output_dir: data_export_folder,
+                };
+                exporters.par_iter()
+                         .filter(|exporter| network.has_shapes || !exporter.needs_shapes())
+                         .for_each(|exporter| exports.step(exporter.name(), || exporter.export(&export_context)));
+                if !network.has_shapes && exporters.iter().any(|exporter| exporter.needs_shapes()) {
+                    log::warn!("GTFS shapes not loaded, no visualisation export.");
+                }
+                exports.summary("convergence", || data_export::export_convergence(&data_export_folder.join("convergence"), &simulation_result));
+                exports.csv("plan switching", || data_export::export_plan_switching(&data_export_folder.join("switching"), &network, &simulation_result));
+                exports.csv("departures", || data_export::export_departures(&data_export_folder.join("departures"), simulation_steps, config.stop_activity_bin));
+                if let Some(peak_spreading_report) = &peak_spreading_report {
+                    exports.csv("peak spreading", || peak_spreading_report.export(&data_export_folder.join("peak_spreading")));
+                }
+                if config.export_loads {
+                    exports.csv("loads csv", || data_export::export_loads_csv(&data_export_folder.join("loads"), &network, &gtfs, &simulation_result, &params.trip_capacities));
+                }
+                if config.export_geojson {
+                    exports.csv("loads geojson", || data_export::export_geojson(&data_export_folder.join("loads"), &network, &stop_geometry, &gtfs, &simulation_result, &params.trip_capacities));
+                }
+                if config.export_occupancy {
+                    exports.csv("occupancy csv", || data_export::export_occupancy_csv(&data_export_folder.join("occupancy"), &network, &gtfs, &simulation_result, &params.trip_capacities, &config.occupancy_thresholds));
+                }
+                if config.export_occupancy_feed {
+                    #[cfg(feature = "gtfs_rt")]
+                    exports.csv("occupancy feed", || data_export::export_occupancy_feed(&data_export_folder.join("occupancy"), &network, &gtfs, &simulation_result, &params.trip_capacities, &config.occupancy_thresholds));
+                    #[cfg(not(feature = "gtfs_rt"))]
+                    log::warn!("Built without the gtfs_rt feature, so the occupancy feed can't be exported.");
+                }
+                if config.export_events {
+                    exports.csv("events", || events::export_events(&data_export_folder.join("events"), &network, &simulation_result));
+                }
+                if config.export_matsim_events {
+                    exports.csv("matsim events", || events::export_matsim_events(&data_export_folder.join("events"), &network, &simulation_result, config.matsim_events_gzip));
+                }
+                if config.export_heat_grid {
+                    exports.csv("heat grid", || data_export::export_heat_grid(&data_export_folder.join("heat_grid"), &network, &gtfs, &simulation_result, &params.trip_capacities, &config.heat_grid));
+                }
+                if config.export_stop_activity {
+                    exports.csv("stop activity", || data_export::export_stop_activity(&data_export_folder.join("stop_activity"), &network, &simulation_result, config.stop_activity_bin));
+                }
+                if config.export_stop_occupancy {
+                    exports.csv("stop occupancy", || data_export::export_stop_occupancy(&data_export_folder.join("stop_occupancy"), &network, &simulation_result, config.stop_activity_bin, stop_capacities.as_deref()));
+                }
+                exports.csv("journeys", || data_export::export_agent_journeys(File::create(&data_export_folder.join("journeys.parquet"))?, &network, &stop_geometry, &simulation_result, false));
+                exports.summary("origin summary", || data_export::export_origin_summary(&data_export_folder.join("origin_summary"), &network, &simulation_result));
+                if config.export_od_matrix {
+                    exports.csv("od matrix", || data_export::export_od_matrix(&data_export_folder.join("od_matrix"), &network, &stop_geometry, &simulation_result, config.od_matrix_parent_stations));
+                }
+                if let Some(reachability_report) = reachability_report.as_ref().filter(|report| !report.unreachable.is_empty()) {
+                    exports.csv("unreachable", || reachability::export_unreachable(&data_export_folder.join("unreachable"), &network, reachability_report));
+                }
+                if params.wheelchair_access.is_some() {
+                    let comparisons = wheelchair::compare_journey_times(&network, &params, simulation_steps, &simulation_result);
+                    wheelchair::log_unservable(&comparisons);
+                    exports.csv("wheelchair", || wheelchair::export_wheelchair_comparison(&data_export_folder.join("wheelchair"), &network, &comparisons));
+                }
+                if let Some(access_report) = &access_report {
+                    exports.csv("access", || access::export_access(&data_export_folder.join("access"), &network, access_report));
+                }
+                exports.summary("route summary", || data_export::export_route_summary(&data_export_folder.join("route_summary"), &network, &stop_geometry, &gtfs, &simulation_result, &params, &periods));
+                exports.summary("stop boardings", || data_export::export_stop_boardings(&data_export_folder.join("stop_boardings"), &network, &simulation_result, &periods));
+                let travel_stats = data_export::TravelStats::new(&network, &stop_geometry, Some(&gtfs), &simulation_result, &params.trip_capacities, config.shape_dist_km);
+                travel_stats.log();
+                exports.summary("stats", || travel_stats.export(&data_export_folder.join("stats")));
+                let max_load_points = data_export::max_load_points(&network, &simulation_result, &params.trip_capacities, &periods);
+                exports.summary("max load points", || data_export::export_max_load_points(&data_export_folder.join("max_load_points"), &network, &gtfs, &max_load_points, &periods));
+                exports.summary("crowding percentiles", || data_export::export_crowding_percentiles(&data_export_folder.join("crowding_percentiles"), &network, &gtfs, &simulation_result, &params.trip_capacities));
+                if !config.segments.is_empty() {
+                    exports.summary("segments", || data_export::export_segment_summary(&data_export_folder.join("segments"), &simulation_result, &config.segment_names()));
+                }
+                if simulation_result.realised_stop_times.is_some() {
+                    exports.csv("realised stop times", || data_export::export_realised_stop_times(&data_export_folder.join("realised_stop_times"), &network, &simulation_result));
+                }
+                if !supplementary_trip_ids.is_empty() {
+                    exports.csv("supplementary trips", || data_export::export_supplementary_trips(&data_export_folder.join("supplementary_trips"), &network, &simulation_result, &params.trip_capacities, &supplementary_trip_ids));
+                }
+                if !bus_replacements.is_empty() {
+                    let shuttle_loads = replacement::shuttle_loads(&network, &simulation_result, &params.trip_capacities, &bus_replacements);
+                    replacement::log_shuttle_loads(&shuttle_loads);
+                    exports.csv("bus replacement", || replacement::export_shuttle_loads(&data_export_folder.join("bus_replacement"), &shuttle_loads));
+                }
+                #[cfg(feature = "gtfs_rt")]
+                if let Some(trip_update_report) = &trip_update_report {
+                    exports.csv("trip updates", || trip_update_report.export(&data_export_folder.join("trip_updates")));
+                }
+                if let (Some(disruption), Some(disruption_report)) = (&disruption, &disruption_report) {
+                    exports.csv("disrupted trips", || data_export::export_disrupted_trips(&data_export_folder.join("disrupted_trips"), disruption, disruption_report));
+                }
+                if let Some(observed_loads) = &observed_loads {
+                    let validation_report = validation::validate_loads(&network, &simulation_result, observed_loads);
+                    validation_report.log();
+                    exports.summary("validation", || validation_report.export(&data_export_folder.join("validation")));
+                }
+                if let Some(suppressed_counts) = &simulation_result.suppressed_counts {
+                    log::info!("Elastic demand: {} agents didn't travel in the final round.", suppressed_counts.iter().map(|&count| count as u64).sum::<u64>());
+                    exports.csv("suppressed demand", || data_export::export_suppressed_demand(&data_export_folder.join("suppressed_demand"), &network, &simulation_result, config.stop_activity_bin));
+                }
+                if let Some(capacity_report) = &simulation_result.capacity_report {
+                    log::info!("Denied boardings: {} ({} agents stranded).", capacity_report.denied_boardings.len(), capacity_report.num_stranded());
+                    exports.csv("denied boardings", || data_export::export_denied_boardings(&data_export_folder.join("denied_boardings"), &network, &simulation_result));
+                }
+                let export_duration = export_start.elapsed();
+                log::info!("Export duration: {:?}", export_duration);
+
+                if let Some(scenario_params) = &scenario {
+                    if simulation_result.cancelled {
+                        log::warn!("Simulation was cancelled, skipping the scenario comparison.");
+                    } else {
+                        log::info!("Simulating comparison scenario.");
+                        progress.reset(simulation_steps.len() * scenario_params.num_rounds as usize);
+                        let scenario_result = simulation::run_simulation_with_dwell(&mut network, simulation_steps, scenario_params);
+                        if !scenario_result.cancelled {
+                            exports.summary("scenario comparison", || data_export::export_scenario_comparison(&data_export_folder.join("comparison"), &network, &stop_geometry, &simulation_result, &params.trip_capacities, &scenario_result, &scenario_params.trip_capacities));
+                        }
+                    }
+                }
+
+                let num_agents = simulation_steps.iter().map(|step| step.count() as u64).sum();
+                if multi_day && !simulation_result.cancelled {
+                    daily_summaries.push(data_export::DailySummary::new(config.date, &network, &stop_geometry, &simulation_result, num_agents));
+                }
+                let mut run_metadata = RunMetadata::new(&config, &gtfs_files, checkpoint::network_hash(&network), &simulation_result, num_agents, num_processors)?;
+                if let (Some(path), Some(warm_start)) = (&warm_start_source, &params.warm_start) {
+                    let report = WarmStartReport::new(path, warm_start, simulation_result.iteration_history.len());
+                    match report.rounds_saved {
+                        Some(rounds_saved) => log::info!("The warm start took {} rounds, {rounds_saved} fewer than the cold start.", report.rounds),
+                        None => log::info!("The warm start took {} rounds.", report.rounds),
+                    }
+                    run_metadata.warm_start = Some(report);
+                }
+                run_metadata.add_timing("gtfs_import", gtfs_duration);
+                run_metadata.add_timing("network_parse", network_duration);
+                run_metadata.add_timing("build_connections", connections_duration);
+                run_metadata.add_timing("simulation", simulation_duration);
+                run_metadata.add_timing("export", export_duration);
+                run_metadata.exports = exports.produced();
+                run_metadata.failed_exports = exports.failed();
+                exports.step("run metadata", || {
+                    run_metadata.collect_export_files(data_export_folder)?;
+                    run_metadata.write(data_export_folder)
+                });
+                if let Some(bundle_path) = &cli.bundle {
+                    exports.step("bundle", || run_metadata.write_bundle(bundle_path, data_export_folder, &exports.failed(), cli.bundle_strict));
+                }
+                let exported = exports.log_summary();
+
+                let crowded_trips = data_export::most_crowded_trips(&max_load_points, config.num_crowded_trips);
+                if !crowded_trips.is_empty() {
+                    println!("Most crowded trips:");
+                    for point in crowded_trips {
+                        let trip_id = &network.routes[point.route_idx].trip_ids[point.trip];
+                        let from_stop = &network.stops[network.get_stop_in_route(point.route_idx, point.stop_order) as usize].name;
+                        let to_stop = &network.stops[network.get_stop_in_route(point.route_idx, point.stop_order + 1) as usize].name;
+                        println!("  {trip_id} ({} departure): load factor {:.2} ({} agents) from {from_stop} to {to_stop}{}.",
+                                 get_time_str(network.get_departure_time(point.route_idx, point.trip, 0)),
+                                 point.load_factor,
+                                 point.max_load,
+                                 if point.seated_exceeded { ", standing" } else { "" });
+                    }
+                }
+
+                log::info!("Total time: {:?}", exec_start.elapsed());
+
+                Ok((simulation_result.cancelled, exported))
+            })?;
+            export_failed |= !exported;
+
+            if cancelled {
+                run_cancelled = true;
+                break 'days;
+            }
+
+            // A config file or date range describes a single run of each day.
+            if !interactive || multi_day || !prompt_yes_no("Run again? (y/n): ")? {
+                break;
+            }
+        }
+    }
+
+    if multi_day && !benchmark && inspect_json.is_none() && isochrone_query.is_none() && config.sweep.is_none() && config.replications == 1 {
+        if daily_summaries.is_empty() && !run_cancelled {
+            return Err("No day in the date range has service.".into());
+        }
+        if !daily_summaries.is_empty() {
+            let exports = ExportLog::new(&base_export_dir, &export_set);
+            exports.summary("daily summary", || data_export::export_daily_summary(&base_export_dir.join("daily_summary"), &daily_summaries));
+            export_failed |= !exports.log_summary();
+        }
+    }
+    if run_cancelled {
+        log::warn!("Simulation was cancelled, exported results are partial.");
+        std::process::exit(CANCELLED_EXIT_CODE);
+    }
+    if export_failed {
+        std::process::exit(EXPORT_FAILED_EXIT_CODE);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_config() -> RunConfig {
+        RunConfig::from_toml(r#"
+            gtfs_path = "file.zip"
+            date = "2024-06-03"
+            num_agents = 100
+            seed = 1
+            num_rounds = 2
+            default_transfer_time = 120
+        "#).unwrap()
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let cli = Cli::parse_from(["train-ute", "--gtfs", "cli.zip", "--date", "2024-06-04", "--agents", "5", "--rounds", "7"]);
+        let mut config = file_config();
+        cli.apply_overrides(&mut config);
+
+        assert_eq!(config.gtfs_path, PathBuf::from("cli.zip"));
+        assert_eq!(config.date, NaiveDate::from_ymd_opt(2024, 6, 4).unwrap());
+        assert_eq!(config.num_agents, Some(5));
+        assert_eq!(config.num_rounds, 7);
+        // Values not given on the command line keep the file's.
+        assert_eq!(config.seed, Some(1));
+        assert_eq!(config.default_transfer_time, 120);
+    }
+
+    #[test]
+    fn no_arguments_keep_config_file() {
+        let cli = Cli::parse_from(["train-ute"]);
+        let mut config = file_config();
+        cli.apply_overrides(&mut config);
+
+        assert_eq!(config.gtfs_path, PathBuf::from("file.zip"));
+        assert_eq!(config.num_agents, Some(100));
+        assert_eq!(config.num_rounds, 2);
+    }
+
+    #[test]
+    fn thread_pool_needs_a_thread() {
+        let err = create_pool(0).err().expect("a pool with no threads should be rejected");
+        assert_eq!(err.to_string(), "The thread pool needs at least one thread.");
+        assert_eq!(create_pool(3).unwrap().current_num_threads(), 3);
+    }
+
+    #[test]
+    fn threads_are_set_on_the_command_line() {
+        let mut config = file_config();
+        assert_eq!(config.threads, None);
+        Cli::parse_from(["train-ute", "--threads", "4"]).apply_overrides(&mut config);
+        assert_eq!(config.threads, Some(4));
+        config.threads = Some(0);
+        assert!(matches!(config.validate(), Err(train_ute::ConfigError::InvalidValue("threads", _))));
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::NaiveDate;
+use raptor::utils::get_time_str;
+use sha2::{Digest, Sha256};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::checkpoint::WarmStart;
+use crate::config::RunConfig;
+use crate::data_export::DataExportError;
+use crate::events::{EventSchema, EVENT_SCHEMA};
+use crate::simulation::{self, CrowdingCost, CrowdingFunc, SimulationResult, StepSize, TripCapacity};
+
+// File name of the metadata written into the export folder.
+pub const RUN_METADATA_FILE_NAME: &str = "run_metadata.json";
+// File name of the manifest in a results bundle.
+pub const BUNDLE_MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Debug, serde::Serialize)]
+pub struct PhaseTiming {
+    pub phase: String,
+    pub seconds: f64,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct GtfsSource {
+    pub path: PathBuf,
+    pub file: PathBuf,
+    pub sha256: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct PeriodDefinition {
+    pub name: String,
+    pub start: String,
+    pub end: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct WarmStartReport {
+    pub source: PathBuf,
+    pub matched_segments: usize,
+    pub unmatched_segments: usize,
+    // Rounds the run warm started from took from free-flow, if known.
+    pub cold_start_rounds: Option<usize>,
+    pub rounds: usize,
+    // cold_start_rounds less the rounds this run took, which is only a fair comparison when both runs converged.
+    pub rounds_saved: Option<i64>,
+}
+
+impl WarmStartReport {
+    pub fn new(source: &Path, warm_start: &WarmStart, rounds: usize) -> Self {
+        Self {
+            source: source.to_path_buf(),
+            matched_segments: warm_start.num_matched,
+            unmatched_segments: warm_start.num_unmatched,
+            cold_start_rounds: warm_start.cold_start_rounds,
+            rounds,
+            rounds_saved: warm_start.cold_start_rounds.map(|cold_start_rounds| cold_start_rounds as i64 - rounds as i64),
+        }
+    }
+}
+
+// What a warm start needs from the metadata of the run it starts from.
+pub struct PreviousRun {
+    pub network_hash: Option<u32>,
+    // The rounds the run took, or if it was warm started itself, the rounds its cold start took.
+    pub cold_start_rounds: Option<usize>,
+}
+
+// Reads what it can from the run_metadata.json in an export folder, or None if there isn't one.
+pub fn read_previous_run(export_dir: &Path) -> Option<PreviousRun> {
+    let file = File::open(export_dir.join(RUN_METADATA_FILE_NAME)).ok()?;
+    let metadata: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file)).ok()?;
+    let as_usize = |value: &serde_json::Value| value.as_u64().map(|value| value as usize);
+    Some(PreviousRun {
+        network_hash: metadata["network_hash"].as_u64().map(|hash| hash as u32),
+        cold_start_rounds: as_usize(&metadata["warm_start"]["cold_start_rounds"]).or_else(|| as_usize(&metadata["num_rounds"])),
+    })
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ExportFile {
+    pub name: String,
+    pub size_bytes: u64,
+}
+
+// Everything needed to know how an export folder was produced.
+#[derive(Debug, serde::Serialize)]
+pub struct RunMetadata {
+    pub crate_version: &'static str,
+    pub gtfs_path: PathBuf,
+    // The file the feed was read from, which differs from gtfs_path when it was downloaded.
+    pub gtfs_file: PathBuf,
+    pub gtfs_sha256: String,
+    // Feeds merged with the first one.
+    pub additional_gtfs: Vec<GtfsSource>,
+    pub date: NaiveDate,
+    // See `checkpoint::network_hash`, which a warm start from this run's loads is checked against.
+    pub network_hash: u32,
+    pub num_agents: u64,
+    pub seed: Option<u64>,
+    // Multiplier applied to the OD matrix counts, which the exported loads already include.
+    pub demand_scale: f64,
+    pub trip_capacity: TripCapacity,
+    pub trip_capacities: Option<PathBuf>,
+    pub route_capacities: Option<PathBuf>,
+    pub capacity_scale: f64,
+    pub strict_capacity: bool,
+    pub crowding_function: CrowdingFunc,
+    pub cost_utility: CrowdingCost,
+    // Type the crowding costs and averaged loads were computed in (f32, or f64 with the f64_crowding_cost feature).
+    pub crowding_cost_precision: &'static str,
+    pub step_size: StepSize,
+    pub num_rounds: usize,
+    // Reporting periods in the period column of the aggregate exports, apart from all_day and off_peak (the rest of the day).
+    pub periods: Vec<PeriodDefinition>,
+    // The loads the assignment started from, if it was warm started.
+    pub warm_start: Option<WarmStartReport>,
+    // Heap memory held by the agent journeys of every round.
+    pub journeys_memory_bytes: usize,
+    pub bag_size: usize,
+    // Size of the thread pool the simulation ran on.
+    pub threads: usize,
+    // The run was cancelled, so the results are partial.
+    pub partial: bool,
+    pub timings: Vec<PhaseTiming>,
+    pub files: Vec<ExportFile>,
+    // Names of the exports that were written (see `--export`), so files left in the folder by earlier runs can be told apart.
+    pub exports: Vec<String>,
+    // Exports that couldn't be written, so are missing from (or incomplete in) files.
+    pub failed_exports: Vec<String>,
+    // Fields of each event type in events.ndjson, if it was exported.
+    pub event_schema: Option<&'static [EventSchema]>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ManifestFile {
+    pub name: String,
+    pub size_bytes: u64,
+    pub sha256: String,
+}
+
+// Lists the files in a results bundle, so a recipient can check none are missing or changed.
+#[derive(Debug, serde::Serialize)]
+pub struct BundleManifest {
+    pub files: Vec<ManifestFile>,
+    // Exports that couldn't be written, so are missing from (or incomplete in) the bundle.
+    pub failed_exports: Vec<String>,
+}
+
+// Copies a file into the zip in blocks, hashing it on the way.
+fn write_hashed(zip: &mut ZipWriter<File>, path: &Path) -> std::io::Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let num_read = file.read(&mut buffer)?;
+        if num_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..num_read]);
+        zip.write_all(&buffer[..num_read])?;
+        size += num_read as u64;
+    }
+    Ok((size, hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()))
+}
+
+// Hashes a file so the exact GTFS feed used can be identified later.
+pub fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let num_read = file.read(&mut buffer)?;
+        if num_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..num_read]);
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+impl RunMetadata {
+    // `gtfs_files` are the local files the feeds were read from, in the same order as the config's paths.
+    pub fn new(config: &RunConfig, gtfs_files: &[PathBuf], network_hash: u32, simulation_result: &SimulationResult, num_agents: u64, threads: usize) -> std::io::Result<Self> {
+        let gtfs_file = gtfs_files.first().unwrap_or(&config.gtfs_path);
+        let additional_gtfs = config.additional_gtfs_paths.iter().zip(gtfs_files.iter().skip(1)).map(|(path, file)| {
+            Ok(GtfsSource { path: path.clone(), file: file.clone(), sha256: sha256_file(file)? })
+        }).collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            gtfs_path: config.gtfs_path.clone(),
+            gtfs_file: gtfs_file.to_path_buf(),
+            gtfs_sha256: sha256_file(gtfs_file)?,
+            additional_gtfs,
+            date: config.date,
+            network_hash,
+            num_agents,
+            seed: config.seed,
+            demand_scale: config.demand_scale,
+            trip_capacity: config.trip_capacity,
+            trip_capacities: config.trip_capacities.clone(),
+            route_capacities: config.route_capacities.clone(),
+            capacity_scale: config.capacity_scale,
+            strict_capacity: config.strict_capacity,
+            crowding_function: config.crowding_function.clone(),
+            cost_utility: config.cost_utility,
+            crowding_cost_precision: simulation::crowding_cost_precision(),
+            step_size: config.step_size,
+            num_rounds: simulation_result.round_agent_journeys.len(),
+            periods: config.periods.iter().map(|period| PeriodDefinition {
+                name: period.name.clone(),
+                start: get_time_str(period.window.start),
+                end: get_time_str(period.window.end),
+            }).collect(),
+            warm_start: None,
+            journeys_memory_bytes: simulation_result.journeys_memory_bytes(),
+            bag_size: config.bag_size,
+            threads,
+            partial: simulation_result.cancelled,
+            timings: Vec::new(),
+            files: Vec::new(),
+            exports: Vec::new(),
+            failed_exports: Vec::new(),
+            event_schema: config.export_events.then_some(EVENT_SCHEMA),
+        })
+    }
+
+    pub fn add_timing(&mut self, phase: &str, duration: Duration) {
+        self.timings.push(PhaseTiming { phase: phase.to_owned(), seconds: duration.as_secs_f64() });
+    }
+
+    // Lists the files in the export folder (apart from the metadata itself) with their sizes.
+    pub fn collect_export_files(&mut self, export_dir: &Path) -> std::io::Result<()> {
+        self.files.clear();
+        for entry in std::fs::read_dir(export_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if metadata.is_file() && name != RUN_METADATA_FILE_NAME {
+                self.files.push(ExportFile { name, size_bytes: metadata.len() });
+            }
+        }
+        self.files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    // Writes the exported files (as listed by `collect_export_files`) and run_metadata.json into one zip at `path`, with a
+    // manifest.json of their sizes and SHA-256 hashes. Files are streamed into the zip, so large exports aren't held in memory.
+    // If any exports failed, the bundle is written without them (listing them in the manifest), or with `strict` not at all.
+    pub fn write_bundle(&self, path: &Path, export_dir: &Path, failed_exports: &[String], strict: bool) -> Result<(), DataExportError> {
+        if strict && !failed_exports.is_empty() {
+            return Err(DataExportError::IncompleteBundle(failed_exports.to_vec()));
+        }
+
+        // Written to a temporary file which is then renamed, so a failed bundle doesn't look complete.
+        let temp_path = path.with_extension("tmp");
+        let mut zip = ZipWriter::new(File::create(&temp_path)?);
+        let mut manifest = BundleManifest { files: Vec::with_capacity(self.files.len() + 1), failed_exports: failed_exports.to_vec() };
+        let names = self.files.iter().map(|file| file.name.as_str()).chain(std::iter::once(RUN_METADATA_FILE_NAME));
+        for name in names {
+            let file_path = export_dir.join(name);
+            // The bundle may be written into the export folder, and is listed if a previous run left one there.
+            if file_path == path || file_path == temp_path {
+                continue;
+            }
+            // Zipped exports won't compress any further.
+            let compression = if name.ends_with(".zip") || name.ends_with(".parquet") { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+            let size = std::fs::metadata(&file_path)?.len();
+            zip.start_file(name, SimpleFileOptions::default().compression_method(compression).large_file(size >= u32::MAX as u64))?;
+            let (size_bytes, sha256) = write_hashed(&mut zip, &file_path)?;
+            manifest.files.push(ManifestFile { name: name.to_owned(), size_bytes, sha256 });
+        }
+        zip.start_file(BUNDLE_MANIFEST_FILE_NAME, SimpleFileOptions::default())?;
+        serde_json::to_writer_pretty(&mut zip, &manifest).map_err(std::io::Error::from)?;
+        zip.finish()?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    // Writes run_metadata.json into the export folder.
+    pub fn write(&self, export_dir: &Path) -> Result<(), DataExportError> {
+        let mut writer = BufWriter::new(File::create(export_dir.join(RUN_METADATA_FILE_NAME))?);
+        serde_json::to_writer_pretty(&mut writer, self).map_err(std::io::Error::from)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
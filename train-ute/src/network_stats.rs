@@ -0,0 +1,123 @@
+use chrono::NaiveDate;
+use itertools::Itertools;
+use raptor::network::Timestamp;
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::demand::travel_time_graph;
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RouteTrips {
+    pub route: String,
+    pub num_trips: usize,
+}
+
+// A summary of the network built for a date, to sanity check a feed without simulating it.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct NetworkStats {
+    pub date: NaiveDate,
+    pub num_stops: usize,
+    pub num_routes: usize,
+    pub num_trips: usize,
+    // Every trip's journey between consecutive stops.
+    pub num_trip_segments: usize,
+    // HH:MM:SS, None if no trips run.
+    pub first_departure: Option<String>,
+    pub last_departure: Option<String>,
+    // In network order.
+    pub trips_per_route: Vec<RouteTrips>,
+    // Ids of the stops no trip departs from (they may still be the last stop of a trip).
+    pub stops_without_departures: Vec<String>,
+    // Number of stops in each group of stops connected by services (changing at shared stops), largest first.
+    // More than one group means some journeys are impossible whatever the timetable.
+    pub component_sizes: Vec<usize>,
+    pub has_shapes: bool,
+}
+
+impl NetworkStats {
+    pub fn new(network: &Network) -> Self {
+        let mut num_trips = 0;
+        let mut num_trip_segments = 0;
+        let mut departure_times: Option<(Timestamp, Timestamp)> = None;
+        let mut has_departures = vec![false; network.num_stops()];
+        let mut trips_per_route = Vec::with_capacity(network.num_routes());
+        for (route_idx, route) in network.routes.iter().enumerate() {
+            let route_trips = network.num_trips(route_idx);
+            let num_stops = network.num_stops_in_route(route_idx);
+            num_trips += route_trips;
+            num_trip_segments += route_trips * num_stops.saturating_sub(1);
+            trips_per_route.push(RouteTrips { route: route.line.to_string(), num_trips: route_trips });
+            if route_trips == 0 || num_stops < 2 {
+                continue;
+            }
+
+            for &stop in &route.get_stops(&network.route_stops)[..num_stops - 1] {
+                has_departures[stop as usize] = true;
+            }
+            for trip in 0..route_trips {
+                let (first, last) = (network.get_departure_time(route_idx, trip, 0), network.get_departure_time(route_idx, trip, num_stops - 2));
+                departure_times = Some(departure_times.map_or((first, last), |(min, max)| (min.min(first), max.max(last))));
+            }
+        }
+
+        Self {
+            date: network.date,
+            num_stops: network.num_stops(),
+            num_routes: network.num_routes(),
+            num_trips,
+            num_trip_segments,
+            first_departure: departure_times.map(|(first, _)| get_time_str(first)),
+            last_departure: departure_times.map(|(_, last)| get_time_str(last)),
+            trips_per_route,
+            stops_without_departures: has_departures.iter().positions(|&has_departures| !has_departures).map(|stop| network.stops[stop].id.to_string()).collect(),
+            component_sizes: component_sizes(network),
+            has_shapes: network.has_shapes,
+        }
+    }
+
+    pub fn log(&self) {
+        log::info!("Network on {}: {} stops, {} routes, {} trips, {} trip segments.", self.date, self.num_stops, self.num_routes, self.num_trips, self.num_trip_segments);
+        match (&self.first_departure, &self.last_departure) {
+            (Some(first), Some(last)) => log::info!("Departures from {first} to {last}."),
+            _ => log::warn!("No trips run on {}.", self.date),
+        }
+        for route in self.trips_per_route.iter() {
+            log::info!("Route {}: {} trips.", route.route, route.num_trips);
+        }
+        if !self.stops_without_departures.is_empty() {
+            log::warn!("{} stops have no departures: {}.", self.stops_without_departures.len(), self.stops_without_departures.iter().take(10).join(", "));
+        }
+        if self.component_sizes.len() > 1 {
+            log::warn!("The network is split into {} groups of connected stops, of {} stops.", self.component_sizes.len(), self.component_sizes.iter().join(", "));
+        }
+        log::info!("Shapes {}.", if self.has_shapes { "present" } else { "missing" });
+    }
+}
+
+// Sizes of the connected components of the graph of stops joined by a trip segment in either direction.
+fn component_sizes(network: &Network) -> Vec<usize> {
+    // Union-find over the stops.
+    fn find(parents: &mut [usize], stop: usize) -> usize {
+        let mut root = stop;
+        while parents[root] != root {
+            root = parents[root];
+        }
+        let mut stop = stop;
+        while parents[stop] != root {
+            stop = std::mem::replace(&mut parents[stop], root);
+        }
+        root
+    }
+
+    let mut parents = (0..network.num_stops()).collect_vec();
+    for (from_stop, edges) in travel_time_graph(network).iter().enumerate() {
+        for &(to_stop, _) in edges {
+            let (a, b) = (find(&mut parents, from_stop), find(&mut parents, to_stop as usize));
+            parents[a] = b;
+        }
+    }
+    let roots = (0..parents.len()).map(|stop| find(&mut parents, stop)).collect_vec();
+    roots.into_iter().counts().into_values().sorted_unstable_by(|a, b| b.cmp(a)).collect()
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use raptor::network::Timestamp;
+use raptor::utils::get_time_str;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{SimulationResult, SimulationStep};
+
+// How departure time choice spread the agents' departures, compared against a run without it.
+#[derive(Clone, Debug, Default)]
+pub struct PeakSpreadingReport {
+    pub bin_size: Timestamp,
+    // (preferred, simulated) agents departing in each bin, keyed by the bin's start.
+    pub departures: BTreeMap<Timestamp, (u64, u64)>,
+    pub num_agents: u64,
+    pub num_earlier: u64,
+    pub num_later: u64,
+    // Mean absolute shift (in seconds) of the agents that shifted.
+    pub mean_shift: f64,
+    // Total crowding cost of the final round, with and without departure time choice.
+    pub total_crowding_cost: f64,
+    pub baseline_crowding_cost: Option<f64>,
+    // Crowding cost the agents experienced in the final round, with and without departure time choice.
+    pub experienced_crowding_cost: f64,
+    pub baseline_experienced_crowding_cost: Option<f64>,
+}
+
+fn experienced_crowding_cost(result: &SimulationResult) -> f64 {
+    result.round_agent_journeys.last().map_or(0., |journeys| {
+        journeys.iter().filter_map(|agent| agent.result.ok().map(|journey| agent.count as f64 * journey.experienced_crowding_cost as f64)).sum()
+    })
+}
+
+fn total_crowding_cost(result: &SimulationResult) -> f64 {
+    result.iteration_history.last().map_or(0., |stats| stats.total_crowding_cost)
+}
+
+impl PeakSpreadingReport {
+    // The preferred departure of each agent is its simulation step's, and the simulated one is where its final journey started.
+    pub fn new(simulation_steps: &[SimulationStep], result: &SimulationResult, baseline: Option<&SimulationResult>, bin_size: Timestamp) -> Self {
+        let mut report = PeakSpreadingReport {
+            bin_size,
+            total_crowding_cost: total_crowding_cost(result),
+            baseline_crowding_cost: baseline.map(total_crowding_cost),
+            experienced_crowding_cost: experienced_crowding_cost(result),
+            baseline_experienced_crowding_cost: baseline.map(experienced_crowding_cost),
+            ..Default::default()
+        };
+        let Some(journeys) = result.round_agent_journeys.last() else {
+            return report;
+        };
+
+        let mut total_shift = 0.;
+        for agent in journeys.iter().filter(|agent| agent.count > 0) {
+            let count = agent.count as u64;
+            let preferred_departure = simulation_steps[agent.sim_step_idx as usize].departure_time;
+            report.departures.entry(preferred_departure / bin_size * bin_size).or_default().0 += count;
+            report.departures.entry(agent.start_time / bin_size * bin_size).or_default().1 += count;
+            report.num_agents += count;
+            if agent.start_time < preferred_departure {
+                report.num_earlier += count;
+            } else if agent.start_time > preferred_departure {
+                report.num_later += count;
+            }
+            total_shift += count as f64 * agent.start_time.abs_diff(preferred_departure) as f64;
+        }
+        let num_shifted = report.num_earlier + report.num_later;
+        if num_shifted > 0 {
+            report.mean_shift = total_shift / num_shifted as f64;
+        }
+        report
+    }
+
+    // Crowding cost removed by departure time choice, if there's a baseline to compare against.
+    pub fn crowding_reduction(&self) -> Option<f64> {
+        self.baseline_crowding_cost.map(|baseline| baseline - self.total_crowding_cost)
+    }
+
+    pub fn log(&self) {
+        log::info!("Departure time choice: {} of {} agents departed earlier and {} later than they preferred, by {:.1} minutes on average.",
+                   self.num_earlier,
+                   self.num_agents,
+                   self.num_later,
+                   self.mean_shift / 60.);
+        if let (Some(reduction), Some(baseline)) = (self.crowding_reduction(), self.baseline_crowding_cost) {
+            log::info!("Departure time choice reduced the total crowding cost from {:.1} to {:.1} ({:.2}%).",
+                       baseline,
+                       self.total_crowding_cost,
+                       reduction / baseline.max(f64::EPSILON) * 100.);
+        }
+    }
+
+    // Writes the preferred and simulated departure histograms to <path>.csv, and the totals to <path>.json.
+    pub fn export(&self, path: &Path) -> Result<(), DataExportError> {
+        if self.num_agents == 0 {
+            return Err(DataExportError::NoData);
+        }
+
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(&["bin_start", "preferred_agents", "simulated_agents"])?;
+        for (&bin_start, &(preferred, simulated)) in self.departures.iter() {
+            csv_writer.write_record(&[get_time_str(bin_start), preferred.to_string(), simulated.to_string()])?;
+        }
+        csv_writer.flush()?;
+
+        let optional = |value: Option<f64>| value.map_or("null".to_owned(), |value| format!("{value:.3}"));
+        let mut writer = BufWriter::new(File::create(path.with_extension("json"))?);
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"num_agents\": {},", self.num_agents)?;
+        writeln!(writer, "  \"num_earlier\": {},", self.num_earlier)?;
+        writeln!(writer, "  \"num_later\": {},", self.num_later)?;
+        writeln!(writer, "  \"mean_shift\": {:.3},", self.mean_shift)?;
+        writeln!(writer, "  \"total_crowding_cost\": {:.3},", self.total_crowding_cost)?;
+        writeln!(writer, "  \"baseline_crowding_cost\": {},", optional(self.baseline_crowding_cost))?;
+        writeln!(writer, "  \"crowding_reduction\": {},", optional(self.crowding_reduction()))?;
+        writeln!(writer, "  \"experienced_crowding_cost\": {:.3},", self.experienced_crowding_cost)?;
+        writeln!(writer, "  \"baseline_experienced_crowding_cost\": {}", optional(self.baseline_experienced_crowding_cost))?;
+        writeln!(writer, "}}")?;
+        writer.flush()?;
+        Ok(())
+    }
+}
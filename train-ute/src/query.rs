@@ -0,0 +1,89 @@
+use gtfs_structures::Gtfs;
+use itertools::Itertools;
+use raptor::network::{StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::simulation::{AgentJourney, PopulationCount, TripCapacities};
+
+#[derive(thiserror::Error, Debug)]
+pub enum QueryError {
+    #[error("No stop id or name matches {0:?}.")]
+    UnknownStop(String),
+    #[error("{0:?} matches several stops ({1}), use more of the name or a stop id.")]
+    AmbiguousStop(String, String),
+    #[error("Invalid time {0:?}: expected HH:MM or HH:MM:SS.")]
+    InvalidTime(String),
+}
+
+// Parses a HH:MM or HH:MM:SS time (hours may be past 24).
+pub fn parse_query_time(time: &str) -> Result<Timestamp, QueryError> {
+    let parts = time.trim().split(':').map(|part| part.parse::<Timestamp>().ok()).collect::<Option<Vec<_>>>();
+    match parts.as_deref() {
+        Some(&[hours, minutes]) if minutes < 60 => Ok(hours * 60 * 60 + minutes * 60),
+        Some(&[hours, minutes, seconds]) if minutes < 60 && seconds < 60 => Ok(hours * 60 * 60 + minutes * 60 + seconds),
+        _ => Err(QueryError::InvalidTime(time.to_owned())),
+    }
+}
+
+// Finds a stop by id, then by whole name, then by part of its name (ignoring case).
+// Stops with the same name (e.g. the platforms of a station) count as one match, and the first of them is used.
+pub fn find_stop(network: &Network, query: &str) -> Result<StopIndex, QueryError> {
+    if let Some(stop) = network.stops.iter().position(|stop| &stop.id[..] == query) {
+        return Ok(stop as StopIndex);
+    }
+
+    let query_lower = query.trim().to_lowercase();
+    let names = network.stops.iter().map(|stop| stop.name.to_lowercase()).collect_vec();
+    let mut matches = names.iter().positions(|name| *name == query_lower).collect_vec();
+    if matches.is_empty() {
+        matches = names.iter().positions(|name| name.contains(&query_lower)).collect_vec();
+    }
+
+    let matched_names = matches.iter().map(|&stop| &network.stops[stop].name[..]).unique().collect_vec();
+    match matched_names.len() {
+        0 => Err(QueryError::UnknownStop(query.to_owned())),
+        1 => Ok(matches[0] as StopIndex),
+        num_matches => {
+            let mut candidates = matched_names.iter().take(10).join(", ");
+            if num_matches > 10 {
+                candidates.push_str(&format!(" and {} more", num_matches - 10));
+            }
+            Err(QueryError::AmbiguousStop(query.to_owned(), candidates))
+        }
+    }
+}
+
+// Describes a journey leg by leg, with the highest load factor on each leg under the given (prefix-summed) loads.
+pub fn format_itinerary(network: &Network, gtfs: &Gtfs, journey: &AgentJourney, population_count: &[PopulationCount], trip_capacities: &TripCapacities) -> Vec<String> {
+    let stop_name = |stop: StopIndex| &network.stops[stop as usize].name;
+    let mut lines = Vec::with_capacity(journey.legs.len() * 2 + 1);
+    for (i, leg) in journey.legs.iter().enumerate() {
+        if i > 0 {
+            let previous = &journey.legs[i - 1];
+            lines.push(format!("  Transfer at {}, waiting {} min.", stop_name(leg.boarded_stop), (leg.boarded_time - previous.arrival_time) / 60));
+        }
+
+        let trip_id = network.get_trip_id(leg.trip);
+        let route_name = gtfs.trips.get(trip_id)
+                             .and_then(|trip| gtfs.routes.get(&trip.route_id))
+                             .map(|route| route.short_name.clone().filter(|name| !name.is_empty()).unwrap_or_else(|| route.id.clone()))
+                             .unwrap_or_default();
+        let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+        // The load at a stop time is the load departing that stop.
+        let max_load = population_count[(trip_start + leg.boarded_stop_order as usize)..(trip_start + leg.arrival_stop_order as usize)].iter().copied().max().unwrap_or(0);
+        let load_factor = max_load as f64 / trip_capacities.get(trip_id).total() as f64;
+        lines.push(format!("{} {} to {} {} on {route_name} (trip {trip_id}), load factor up to {load_factor:.2}.",
+                           get_time_str(leg.boarded_time),
+                           stop_name(leg.boarded_stop),
+                           get_time_str(leg.arrival_time),
+                           stop_name(leg.arrival_stop)));
+    }
+    lines.push(format!("{} min in total ({} min on board, {} min waiting), {} transfers, crowding cost {:.2}.",
+                       journey.duration / 60,
+                       journey.in_vehicle_time / 60,
+                       journey.wait_time / 60,
+                       journey.num_transfers,
+                       journey.crowding_cost));
+    lines
+}
@@ -0,0 +1,114 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use itertools::Itertools;
+use rayon::prelude::*;
+use raptor::journey::JourneyPreferences;
+use raptor::network::{PathfindingCost, StopIndex, Timestamp};
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::simulation::SimulationStep;
+
+// An OD pair with demand but no journey on the modelled date.
+#[derive(Clone, Copy, Debug)]
+pub struct UnreachablePair {
+    pub origin_stop: StopIndex,
+    pub dest_stop: StopIndex,
+    // The earliest departure of the pair's agents, which the check searched from.
+    pub earliest_departure: Timestamp,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReachabilityReport {
+    pub num_pairs: usize,
+    pub total_count: u64,
+    // Sorted by origin then destination.
+    pub unreachable: Vec<UnreachablePair>,
+}
+
+impl ReachabilityReport {
+    pub fn unreachable_count(&self) -> u64 {
+        self.unreachable.iter().map(|pair| pair.count).sum()
+    }
+
+    pub fn log(&self) {
+        if self.unreachable.is_empty() {
+            log::info!("All {} OD pairs are reachable.", self.num_pairs);
+            return;
+        }
+        let unreachable_count = self.unreachable_count();
+        log::warn!("{} of {} OD pairs ({} of {} agents, {:.2}%) have no journey on this date, so aren't simulated.",
+                   self.unreachable.len(),
+                   self.num_pairs,
+                   unreachable_count,
+                   self.total_count,
+                   unreachable_count as f64 / self.total_count.max(1) as f64 * 100.);
+    }
+}
+
+// Finds the OD pairs in the simulation steps with no journey at all, by running one raptor query from each origin with no
+// crowding cost, from the earliest departure of any agent at that origin. This is the same search the simulation plans with,
+// so a pair is only reported if the simulation can't find a journey for any of its agents. Pairs reachable early in the day
+// but not late (e.g. after the last service) are kept, and those agents still fail to plan.
+pub fn check_reachability(network: &Network, simulation_steps: &[SimulationStep], journey_preferences: &JourneyPreferences) -> ReachabilityReport {
+    // (earliest departure, agents to each destination) of each origin.
+    let mut origins: HashMap<StopIndex, (Timestamp, HashMap<StopIndex, u64>)> = HashMap::new();
+    for sim_step in simulation_steps {
+        let (earliest_departure, dest_counts) = origins.entry(sim_step.origin_stop).or_insert((sim_step.departure_time, HashMap::new()));
+        *earliest_departure = (*earliest_departure).min(sim_step.departure_time);
+        for (dest_stop, count) in sim_step.destinations() {
+            *dest_counts.entry(dest_stop).or_default() += count as u64;
+        }
+    }
+
+    let zero_cost = vec![0 as PathfindingCost; network.stop_times.len()];
+    let mut unreachable = origins.par_iter().flat_map_iter(|(&origin_stop, (earliest_departure, dest_counts))| {
+        let dest_stops = dest_counts.keys().copied().collect_vec();
+        let journeys = raptor::mc_raptor_query::<1>(network, origin_stop, *earliest_departure, &dest_stops, &zero_cost, journey_preferences);
+        dest_stops.into_iter().zip(journeys).filter(|(dest_stop, journey)| journey.is_err() && *dest_stop != origin_stop).map(move |(dest_stop, _)| {
+            UnreachablePair { origin_stop, dest_stop, earliest_departure: *earliest_departure, count: dest_counts[&dest_stop] }
+        }).collect_vec()
+    }).collect::<Vec<_>>();
+    unreachable.sort_unstable_by_key(|pair| (pair.origin_stop, pair.dest_stop));
+
+    ReachabilityReport {
+        num_pairs: origins.values().map(|(_, dest_counts)| dest_counts.len()).sum(),
+        total_count: origins.values().flat_map(|(_, dest_counts)| dest_counts.values()).sum(),
+        unreachable,
+    }
+}
+
+// Removes the report's unreachable pairs from the simulation steps, dropping steps left with no destinations.
+pub fn remove_unreachable(simulation_steps: &mut Vec<SimulationStep>, report: &ReachabilityReport) {
+    if report.unreachable.is_empty() {
+        return;
+    }
+    let unreachable = report.unreachable.iter().map(|pair| (pair.origin_stop, pair.dest_stop)).collect::<HashSet<_>>();
+    for sim_step in simulation_steps.iter_mut() {
+        let origin_stop = sim_step.origin_stop;
+        sim_step.retain_destinations(|dest_stop| !unreachable.contains(&(origin_stop, dest_stop)));
+    }
+    simulation_steps.retain(|sim_step| sim_step.len() > 0);
+}
+
+pub fn export_unreachable(path: &Path, network: &Network, report: &ReachabilityReport) -> Result<(), DataExportError> {
+    let stop = |stop: StopIndex| &network.stops[stop as usize];
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["origin_stop_id", "origin_stop_name", "destination_stop_id", "destination_stop_name", "earliest_departure", "count"])?;
+    for pair in report.unreachable.iter() {
+        csv_writer.write_record(&[
+            &stop(pair.origin_stop).id[..],
+            &stop(pair.origin_stop).name[..],
+            &stop(pair.dest_stop).id[..],
+            &stop(pair.dest_stop).name[..],
+            &get_time_str(pair.earliest_departure),
+            &pair.count.to_string(),
+        ])?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::NaiveDate;
+use gtfs_rt::trip_descriptor::ScheduleRelationship as TripRelationship;
+use gtfs_rt::trip_update::stop_time_update::ScheduleRelationship as StopRelationship;
+use gtfs_rt::trip_update::{StopTimeEvent, StopTimeUpdate};
+use gtfs_rt::{FeedMessage, TripUpdate};
+use gtfs_structures::{Gtfs, StopTime, Trip};
+use prost::Message;
+
+use crate::data_export::DataExportError;
+use crate::data_import::{self, DataImportError, SUPPLEMENTARY_SERVICE_ID};
+
+// Reads the trip updates from a GTFS-realtime feed, or every .pb feed in a directory.
+// Feeds are combined in header timestamp order: a later update to a trip replaces its descriptor, and its stop time
+// updates replace earlier ones for the same stops, so stops that dropped out of later feeds keep their last update.
+pub fn import_trip_updates(path: &Path) -> Result<Vec<TripUpdate>, DataImportError> {
+    let paths = if path.is_dir() {
+        let mut paths = std::fs::read_dir(path)?.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|path| path.extension().is_some_and(|extension| extension == "pb"));
+        paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+    let mut feeds = paths.iter().map(|path| Ok(FeedMessage::decode(std::fs::read(path)?.as_slice())?)).collect::<Result<Vec<_>, DataImportError>>()?;
+    feeds.sort_by_key(|feed| feed.header.timestamp);
+
+    let mut trip_updates: Vec<TripUpdate> = Vec::new();
+    let mut trip_indices = HashMap::new();
+    for trip_update in feeds.into_iter().flat_map(|feed| feed.entity).filter_map(|entity| entity.trip_update) {
+        let Some(trip_id) = trip_update.trip.trip_id.clone() else {
+            trip_updates.push(trip_update);
+            continue;
+        };
+        match trip_indices.get(&trip_id) {
+            Some(&idx) => {
+                let existing: &mut TripUpdate = &mut trip_updates[idx];
+                existing.trip = trip_update.trip;
+                existing.delay = trip_update.delay.or(existing.delay);
+                for stop_time_update in trip_update.stop_time_update {
+                    let same_stop = |existing: &StopTimeUpdate| match (existing.stop_sequence, stop_time_update.stop_sequence) {
+                        (Some(a), Some(b)) => a == b,
+                        _ => existing.stop_id.is_some() && existing.stop_id == stop_time_update.stop_id,
+                    };
+                    match existing.stop_time_update.iter().position(same_stop) {
+                        Some(position) => existing.stop_time_update[position] = stop_time_update,
+                        None => existing.stop_time_update.push(stop_time_update),
+                    }
+                }
+            }
+            None => {
+                trip_indices.insert(trip_id, trip_updates.len());
+                trip_updates.push(trip_update);
+            }
+        }
+    }
+
+    if trip_updates.is_empty() {
+        Err(DataImportError::NoData)
+    } else {
+        Ok(trip_updates)
+    }
+}
+
+// What happened to a trip update when applying it to the feed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TripUpdateOutcome {
+    // The trip's times were shifted and/or stops skipped.
+    Updated,
+    Cancelled,
+    Added,
+    // The update has no trip id.
+    NoTripId,
+    // The trip isn't in the feed (e.g. it was removed by a route filter).
+    UnknownTrip,
+    // The update is for a different service date.
+    OtherDate,
+    // An added trip without a known route, or without a known stop and time for at least two stops.
+    IncompleteAddedTrip,
+    // Duplicated and replacement trips aren't modelled.
+    Unsupported,
+}
+
+impl TripUpdateOutcome {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            TripUpdateOutcome::Updated => "updated",
+            TripUpdateOutcome::Cancelled => "cancelled",
+            TripUpdateOutcome::Added => "added",
+            TripUpdateOutcome::NoTripId => "no_trip_id",
+            TripUpdateOutcome::UnknownTrip => "unknown_trip",
+            TripUpdateOutcome::OtherDate => "other_date",
+            TripUpdateOutcome::IncompleteAddedTrip => "incomplete_added_trip",
+            TripUpdateOutcome::Unsupported => "unsupported",
+        }
+    }
+
+    pub fn is_matched(&self) -> bool {
+        matches!(self, TripUpdateOutcome::Updated | TripUpdateOutcome::Cancelled | TripUpdateOutcome::Added)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TripUpdateRow {
+    pub trip_id: String,
+    pub outcome: TripUpdateOutcome,
+    pub num_delayed_stops: usize,
+    pub num_skipped_stops: usize,
+    // Largest absolute delay applied to a stop time, in seconds (negative when running early).
+    pub max_delay: i64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TripUpdateReport {
+    pub rows: Vec<TripUpdateRow>,
+}
+
+impl TripUpdateReport {
+    pub fn num_matched(&self) -> usize {
+        self.rows.iter().filter(|row| row.outcome.is_matched()).count()
+    }
+
+    // Trip ids of the trips whose times or stops were changed, or that were added.
+    pub fn modified_trip_ids(&self) -> impl Iterator<Item=&str> {
+        self.rows.iter().filter(|row| matches!(row.outcome, TripUpdateOutcome::Updated | TripUpdateOutcome::Added)).map(|row| row.trip_id.as_str())
+    }
+
+    pub fn log(&self) {
+        let count = |outcome: TripUpdateOutcome| self.rows.iter().filter(|row| row.outcome == outcome).count();
+        log::info!("Applied {} of {} trip updates: {} updated, {} cancelled, {} added.",
+                   self.num_matched(),
+                   self.rows.len(),
+                   count(TripUpdateOutcome::Updated),
+                   count(TripUpdateOutcome::Cancelled),
+                   count(TripUpdateOutcome::Added));
+        if self.num_matched() < self.rows.len() {
+            log::warn!("{} trip updates couldn't be applied, see trip_updates.csv.", self.rows.len() - self.num_matched());
+        }
+    }
+
+    // Writes one row per trip update with its outcome to <path>.csv.
+    pub fn export(&self, path: &Path) -> Result<(), DataExportError> {
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(&["trip_id", "outcome", "delayed_stops", "skipped_stops", "max_delay_seconds"])?;
+        for row in self.rows.iter() {
+            csv_writer.write_record(&[
+                row.trip_id.as_str(),
+                row.outcome.get_name(),
+                &row.num_delayed_stops.to_string(),
+                &row.num_skipped_stops.to_string(),
+                &row.max_delay.to_string(),
+            ])?;
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+}
+
+// Applies trip updates for `date` to the feed before the network is built: cancelled trips are removed, delays shift stop times
+// (carrying each delay on to later stops without an update, as in the GTFS-realtime spec), skipped stops are removed, and added trips
+// with a known route and at least two known stops with times are added as supplementary trips running on `date`.
+// Absolute event times are taken relative to midnight UTC of `date`, like the exported occupancy feed, so times after midnight
+// belong to the same service day. Delays are used in preference to absolute times where a feed gives both.
+pub fn apply_trip_updates(gtfs: &mut Gtfs, date: NaiveDate, trip_updates: &[TripUpdate]) -> TripUpdateReport {
+    let date_timestamp = date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp();
+    let start_date = date.format("%Y%m%d").to_string();
+    let event_delay = |event: &StopTimeEvent, scheduled: Option<u32>| -> Option<i64> {
+        event.delay.map(i64::from).or_else(|| Some(event.time? - date_timestamp - scheduled? as i64))
+    };
+
+    let mut report = TripUpdateReport::default();
+    let mut added_trips = Vec::new();
+    for trip_update in trip_updates.iter() {
+        let mut row = TripUpdateRow {
+            trip_id: trip_update.trip.trip_id.clone().unwrap_or_default(),
+            outcome: TripUpdateOutcome::Updated,
+            num_delayed_stops: 0,
+            num_skipped_stops: 0,
+            max_delay: 0,
+        };
+        let relationship = trip_update.trip.schedule_relationship();
+        if row.trip_id.is_empty() {
+            row.outcome = TripUpdateOutcome::NoTripId;
+        } else if trip_update.trip.start_date.as_ref().is_some_and(|trip_start_date| *trip_start_date != start_date) {
+            row.outcome = TripUpdateOutcome::OtherDate;
+        } else if relationship == TripRelationship::Added {
+            match added_trip(gtfs, date_timestamp, &row.trip_id, trip_update) {
+                Some(trip) => {
+                    row.outcome = TripUpdateOutcome::Added;
+                    added_trips.push(trip);
+                }
+                None => row.outcome = TripUpdateOutcome::IncompleteAddedTrip,
+            }
+        } else if !matches!(relationship, TripRelationship::Scheduled | TripRelationship::Canceled | TripRelationship::Deleted) {
+            row.outcome = TripUpdateOutcome::Unsupported;
+        } else if !gtfs.trips.contains_key(&row.trip_id) {
+            row.outcome = TripUpdateOutcome::UnknownTrip;
+        } else if matches!(relationship, TripRelationship::Canceled | TripRelationship::Deleted) {
+            gtfs.trips.remove(&row.trip_id);
+            row.outcome = TripUpdateOutcome::Cancelled;
+        } else {
+            let trip = gtfs.trips.get_mut(&row.trip_id).unwrap();
+
+            // Match each stop time update to a stop time, by stop sequence or else the next stop time at the stop.
+            let mut stop_updates = vec![None; trip.stop_times.len()];
+            let mut next_stop_order = 0;
+            for stop_time_update in trip_update.stop_time_update.iter() {
+                let stop_order = match (stop_time_update.stop_sequence, &stop_time_update.stop_id) {
+                    (Some(stop_sequence), _) => trip.stop_times.iter().position(|stop_time| stop_time.stop_sequence as u32 == stop_sequence),
+                    (None, Some(stop_id)) => trip.stop_times[next_stop_order..].iter().position(|stop_time| stop_time.stop.id == *stop_id).map(|position| next_stop_order + position),
+                    (None, None) => None,
+                };
+                if let Some(stop_order) = stop_order {
+                    stop_updates[stop_order] = Some(stop_time_update);
+                    next_stop_order = stop_order + 1;
+                }
+            }
+
+            let mut delay = trip_update.delay.map(i64::from);
+            let mut previous_time = 0;
+            let mut skipped = vec![false; trip.stop_times.len()];
+            for (stop_order, stop_time) in trip.stop_times.iter_mut().enumerate() {
+                let (mut arrival_delay, mut departure_delay) = (delay, delay);
+                if let Some(stop_time_update) = stop_updates[stop_order] {
+                    if stop_time_update.schedule_relationship() == StopRelationship::Skipped {
+                        skipped[stop_order] = true;
+                        row.num_skipped_stops += 1;
+                        continue;
+                    }
+                    let arrival = stop_time_update.arrival.as_ref().and_then(|event| event_delay(event, stop_time.arrival_time));
+                    let departure = stop_time_update.departure.as_ref().and_then(|event| event_delay(event, stop_time.departure_time));
+                    if arrival.is_some() || departure.is_some() {
+                        arrival_delay = arrival.or(departure);
+                        departure_delay = departure.or(arrival);
+                        delay = departure_delay;
+                    }
+                }
+
+                // Delays are applied in order, and times never go backwards.
+                let mut shift = |time: Option<u32>, delay: Option<i64>| time.map(|time| {
+                    let shifted = (time as i64 + delay.unwrap_or(0)).max(previous_time as i64) as u32;
+                    previous_time = shifted;
+                    shifted
+                });
+                stop_time.arrival_time = shift(stop_time.arrival_time, arrival_delay);
+                stop_time.departure_time = shift(stop_time.departure_time, departure_delay);
+                if let Some(delay) = departure_delay.or(arrival_delay).filter(|&delay| delay != 0) {
+                    row.num_delayed_stops += 1;
+                    if delay.abs() > row.max_delay.abs() {
+                        row.max_delay = delay;
+                    }
+                }
+            }
+
+            let mut stop_order = 0;
+            trip.stop_times.retain(|_| {
+                stop_order += 1;
+                !skipped[stop_order - 1]
+            });
+            if trip.stop_times.len() < 2 {
+                gtfs.trips.remove(&row.trip_id);
+                row.outcome = TripUpdateOutcome::Cancelled;
+            }
+        }
+        report.rows.push(row);
+    }
+
+    if !added_trips.is_empty() {
+        data_import::add_supplementary_trips(gtfs, added_trips, &[date]);
+    }
+    report
+}
+
+// Builds an added trip from its stop time updates, if its route and at least two of its stops and their times are known.
+fn added_trip(gtfs: &Gtfs, date_timestamp: i64, trip_id: &str, trip_update: &TripUpdate) -> Option<Trip> {
+    let route_id = trip_update.trip.route_id.as_ref().filter(|route_id| gtfs.routes.contains_key(*route_id))?;
+    if gtfs.trips.contains_key(trip_id) {
+        return None;
+    }
+
+    let event_time = |event: &Option<StopTimeEvent>| event.as_ref().and_then(|event| event.time).map(|time| time - date_timestamp).filter(|&time| time >= 0).map(|time| time as u32);
+    let mut stop_times: Vec<StopTime> = Vec::new();
+    for stop_time_update in trip_update.stop_time_update.iter().filter(|stop_time_update| stop_time_update.schedule_relationship() != StopRelationship::Skipped) {
+        let stop = gtfs.stops.get(stop_time_update.stop_id.as_ref()?)?;
+        let arrival_time = event_time(&stop_time_update.arrival).or(event_time(&stop_time_update.departure))?;
+        let departure_time = event_time(&stop_time_update.departure).unwrap_or(arrival_time);
+        let previous_departure = stop_times.last().and_then(|stop_time| stop_time.departure_time);
+        if departure_time < arrival_time || previous_departure.is_some_and(|previous| arrival_time < previous) {
+            return None;
+        }
+        stop_times.push(StopTime {
+            arrival_time: Some(arrival_time),
+            departure_time: Some(departure_time),
+            stop: stop.clone(),
+            stop_sequence: stop_times.len() as _,
+            ..Default::default()
+        });
+    }
+    if stop_times.len() < 2 {
+        return None;
+    }
+
+    Some(Trip {
+        id: trip_id.to_owned(),
+        route_id: route_id.clone(),
+        service_id: SUPPLEMENTARY_SERVICE_ID.to_owned(),
+        stop_times,
+        ..Default::default()
+    })
+}
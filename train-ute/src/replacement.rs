@@ -0,0 +1,409 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use chrono::NaiveDate;
+use gtfs_structures::{Availability, CalendarDate, Exception, Gtfs, RouteType, StopTime, Trip};
+use itertools::Itertools;
+use raptor::network::Timestamp;
+use raptor::utils::get_time_str;
+use raptor::Network;
+use rgb::RGB8;
+
+use crate::data_export::DataExportError;
+use crate::data_import::{is_service_active, parse_time};
+use crate::simulation::{PopulationCount, SimulationResult, TripCapacities, TripCapacity};
+
+// Service id the replacement buses run on, which only runs on the modelled date.
+pub const REPLACEMENT_SERVICE_ID: &str = "bus_replacement";
+
+fn default_headway() -> Timestamp { 10 * 60 }
+
+fn default_bus_capacity() -> TripCapacity {
+    // A typical route bus, 60 in total.
+    TripCapacity { seated: 45, standing: 15 }
+}
+
+fn default_run_time_factor() -> f64 { 1.5 }
+
+fn default_colour() -> [u8; 3] { [255, 130, 0] }
+
+// The bus run time between two adjacent stops, overriding the scaled train run time.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct StopPairRunTime {
+    // Stop names, in either order.
+    pub from_stop: String,
+    pub to_stop: String,
+    // In seconds.
+    pub run_time: Timestamp,
+}
+
+// A section of line where buses replace the trains, e.g. during a rail occupation.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct BusReplacement {
+    // Used in the ids of the shuttles and their route.
+    pub name: String,
+    // GTFS route ids whose trains are replaced.
+    pub routes: Vec<String>,
+    // Names of the stops at either end of the section, matching any of a station's platforms.
+    pub from_stop: String,
+    pub to_stop: String,
+    // Dates the buses run instead of the trains, every date if empty.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dates: Vec<NaiveDate>,
+    // Seconds between shuttles in each direction.
+    #[cfg_attr(feature = "serde", serde(default = "default_headway"))]
+    pub headway: Timestamp,
+    // HH:MM:SS of the first and last shuttles from each end, defaulting to the first and last replaced trains.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub first_departure: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub last_departure: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default = "default_bus_capacity"))]
+    pub capacity: TripCapacity,
+    // Bus run times are the train's times this many times over, unless given in run_times.
+    #[cfg_attr(feature = "serde", serde(default = "default_run_time_factor"))]
+    pub run_time_factor: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub run_times: Vec<StopPairRunTime>,
+    // Colour of the shuttle route (RGB), so the buses can be told apart from the trains in the visualisation.
+    #[cfg_attr(feature = "serde", serde(default = "default_colour"))]
+    pub colour: [u8; 3],
+}
+
+impl BusReplacement {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.routes.is_empty() {
+            return Err("every replacement needs a name and at least one route".to_owned());
+        }
+        if self.from_stop == self.to_stop {
+            return Err(format!("{} starts and ends at {}", self.name, self.from_stop));
+        }
+        if self.headway == 0 {
+            return Err(format!("{} must have a headway greater than zero", self.name));
+        }
+        if !self.run_time_factor.is_finite() || self.run_time_factor <= 0. {
+            return Err(format!("{} run_time_factor ({}) must be greater than zero", self.name, self.run_time_factor));
+        }
+        if self.capacity.seated == 0 {
+            return Err(format!("{} capacity {:?} must have at least one seat", self.name, self.capacity));
+        }
+        if let Some(run_time) = self.run_times.iter().find(|run_time| run_time.run_time == 0) {
+            return Err(format!("{} run time from {} to {} must be greater than zero", self.name, run_time.from_stop, run_time.to_stop));
+        }
+        let (first_departure, last_departure) = self.departure_window()?;
+        if let (Some(first_departure), Some(last_departure)) = (first_departure, last_departure) {
+            if first_departure > last_departure {
+                return Err(format!("{} first_departure is after last_departure", self.name));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn applies_on(&self, date: NaiveDate) -> bool {
+        self.dates.is_empty() || self.dates.contains(&date)
+    }
+
+    fn departure_window(&self) -> Result<(Option<Timestamp>, Option<Timestamp>), String> {
+        let parse = |time: &Option<String>| match time {
+            Some(time) => parse_time(time).map(Some).ok_or_else(|| format!("{} has an invalid time {time}", self.name)),
+            None => Ok(None),
+        };
+        Ok((parse(&self.first_departure)?, parse(&self.last_departure)?))
+    }
+
+    fn pair_run_time(&self, from_stop: &str, to_stop: &str) -> Option<Timestamp> {
+        self.run_times.iter().find(|run_time| {
+            (run_time.from_stop == from_stop && run_time.to_stop == to_stop) || (run_time.from_stop == to_stop && run_time.to_stop == from_stop)
+        }).map(|run_time| run_time.run_time)
+    }
+}
+
+// What a bus replacement changed in the feed.
+#[derive(Clone, Debug)]
+pub struct BusReplacementReport {
+    pub name: String,
+    // Trains that only ran within the section.
+    pub num_cancelled: usize,
+    // Trains cut back to one side of the section.
+    pub num_shortened: usize,
+    // Trains that now run on both sides of the section as two trips.
+    pub num_split: usize,
+    pub shuttle_trip_ids: Vec<String>,
+    pub capacity: TripCapacity,
+}
+
+impl BusReplacementReport {
+    pub fn log(&self) {
+        log::info!("Bus replacement {}: {} trains cancelled, {} shortened and {} split, replaced by {} shuttles.",
+                   self.name, self.num_cancelled, self.num_shortened, self.num_split, self.shuttle_trip_ids.len());
+    }
+}
+
+// A train running through the section.
+struct ReplacedTrip {
+    trip_id: String,
+    // Stop orders of the section's first and last stops on the trip.
+    first: usize,
+    last: usize,
+    // Whether the trip runs from from_stop to to_stop.
+    forward: bool,
+}
+
+// Replaces the trains on the replacement's routes between its two stops on the date with shuttle buses in both directions,
+// stopping where the trains did. Trains that only run within the section are cancelled, and the rest are cut short at the
+// section, with the part after it becoming its own trip (<trip_id>-after-<name>) when the train also runs before it.
+// Trains serving only one of the two stops are left alone.
+// Each direction's shuttles take the times and shape of the train with the most stops in the section, with run times
+// scaled by run_time_factor (unless given for the stop pair) and no dwell. They run on their own bus route.
+pub fn apply_bus_replacement(gtfs: &mut Gtfs, replacement: &BusReplacement, date: NaiveDate) -> Result<BusReplacementReport, String> {
+    if let Some(route_id) = replacement.routes.iter().find(|route_id| !gtfs.routes.contains_key(*route_id)) {
+        return Err(format!("{} replaces unknown route {route_id}", replacement.name));
+    }
+    let shuttle_route_id = format!("{}-bus", replacement.name);
+    if gtfs.routes.contains_key(&shuttle_route_id) {
+        return Err(format!("route {shuttle_route_id} is already in the GTFS"));
+    }
+
+    let is_at = |stop_time: &StopTime, name: &str| stop_time.stop.name.as_deref() == Some(name);
+    let mut replaced_trips = Vec::new();
+    for trip in gtfs.trips.values().filter(|trip| replacement.routes.contains(&trip.route_id) && is_service_active(gtfs, &trip.service_id, date)) {
+        let from = trip.stop_times.iter().position(|stop_time| is_at(stop_time, &replacement.from_stop));
+        let to = trip.stop_times.iter().position(|stop_time| is_at(stop_time, &replacement.to_stop));
+        if let (Some(from), Some(to)) = (from, to) {
+            replaced_trips.push(ReplacedTrip { trip_id: trip.id.clone(), first: from.min(to), last: from.max(to), forward: from < to });
+        }
+    }
+    if replaced_trips.is_empty() {
+        return Err(format!("no trains on {} run between {} and {} on {date}", replacement.routes.join(", "), replacement.from_stop, replacement.to_stop));
+    }
+    // Sorted so the shuttles don't depend on the order of the trips in the feed.
+    replaced_trips.sort_unstable_by(|a, b| a.trip_id.cmp(&b.trip_id));
+
+    let (first_departure, last_departure) = replacement.departure_window()?;
+    let mut shuttles = Vec::new();
+    let mut shuttle_route = None;
+    for (forward, direction) in [(true, "forward"), (false, "reverse")] {
+        let direction_trips = replaced_trips.iter().filter(|replaced| replaced.forward == forward).collect_vec();
+        let Some(template) = direction_trips.iter().max_by_key(|replaced| replaced.last - replaced.first) else {
+            log::warn!("No trains run from {} to {} on {date}, so bus replacement {} only runs the other way.",
+                       if forward { &replacement.from_stop } else { &replacement.to_stop },
+                       if forward { &replacement.to_stop } else { &replacement.from_stop },
+                       replacement.name);
+            continue;
+        };
+        let template_trip = &gtfs.trips[&template.trip_id];
+        let mut stop_times = template_trip.stop_times[template.first..=template.last].to_vec();
+        if !forward {
+            stop_times.reverse();
+        }
+
+        // The shuttle's time from its first stop to each stop.
+        let mut offsets = vec![0];
+        for (from, to) in stop_times.iter().tuple_windows() {
+            let names = (from.stop.name.as_deref().unwrap_or_default(), to.stop.name.as_deref().unwrap_or_default());
+            let run_time = match replacement.pair_run_time(names.0, names.1) {
+                Some(run_time) => run_time,
+                None => {
+                    // The train's run time, which is the same whichever way round the stops are listed.
+                    let times = [from.departure_time.or(from.arrival_time), to.arrival_time.or(to.departure_time)];
+                    let [Some(a), Some(b)] = times else {
+                        return Err(format!("trip {} has no times between {} and {}, so give the run time in run_times", template.trip_id, names.0, names.1));
+                    };
+                    (a.abs_diff(b) as f64 * replacement.run_time_factor).round() as Timestamp
+                }
+            };
+            offsets.push(offsets.last().copied().unwrap_or(0) + run_time);
+        }
+
+        let section_departures = direction_trips.iter().filter_map(|replaced| {
+            let stop_time = &gtfs.trips[&replaced.trip_id].stop_times[if forward { replaced.first } else { replaced.last }];
+            stop_time.departure_time.or(stop_time.arrival_time)
+        });
+        let Some((first_train, last_train)) = section_departures.minmax().into_option() else {
+            return Err(format!("the trains from {} have no times", stop_times[0].stop.name.as_deref().unwrap_or_default()));
+        };
+        let window = first_departure.unwrap_or(first_train)..=last_departure.unwrap_or(last_train);
+
+        for start_time in window.step_by(replacement.headway as usize) {
+            let trip_id = format!("{}-bus-{direction}@{}", replacement.name, get_time_str(start_time));
+            let shuttle_stop_times = stop_times.iter().zip(&offsets).enumerate().map(|(stop_sequence, (stop_time, offset))| StopTime {
+                arrival_time: Some(start_time + offset),
+                departure_time: Some(start_time + offset),
+                stop: stop_time.stop.clone(),
+                stop_sequence: stop_sequence as _,
+                ..Default::default()
+            }).collect();
+            shuttles.push(Trip {
+                id: trip_id,
+                service_id: REPLACEMENT_SERVICE_ID.to_string(),
+                route_id: shuttle_route_id.clone(),
+                shape_id: template_trip.shape_id.clone(),
+                direction_id: template_trip.direction_id,
+                stop_times: shuttle_stop_times,
+                wheelchair_accessible: Availability::Available,
+                ..Default::default()
+            });
+        }
+        shuttle_route.get_or_insert_with(|| template_trip.route_id.clone());
+    }
+
+    let mut report = BusReplacementReport {
+        name: replacement.name.clone(),
+        num_cancelled: 0,
+        num_shortened: 0,
+        num_split: 0,
+        shuttle_trip_ids: shuttles.iter().map(|trip| trip.id.clone()).collect(),
+        capacity: replacement.capacity,
+    };
+
+    // Trains are cut short once the shuttles have been built from their times.
+    for replaced in replaced_trips {
+        let Some(mut trip) = gtfs.trips.remove(&replaced.trip_id) else {
+            continue;
+        };
+        let after = trip.stop_times.split_off(replaced.last);
+        trip.stop_times.truncate(replaced.first + 1);
+        match (trip.stop_times.len() >= 2, after.len() >= 2) {
+            (false, false) => report.num_cancelled += 1,
+            (true, false) => {
+                report.num_shortened += 1;
+                gtfs.trips.insert(trip.id.clone(), trip);
+            }
+            (false, true) => {
+                report.num_shortened += 1;
+                trip.stop_times = after;
+                gtfs.trips.insert(trip.id.clone(), trip);
+            }
+            (true, true) => {
+                report.num_split += 1;
+                let after_trip = Trip { id: format!("{}-after-{}", trip.id, replacement.name), stop_times: after, ..trip.clone() };
+                gtfs.trips.insert(after_trip.id.clone(), after_trip);
+                gtfs.trips.insert(trip.id.clone(), trip);
+            }
+        }
+    }
+
+    // The shuttle route copies the replaced route (e.g. its agency), as a bus.
+    if let Some(route_id) = shuttle_route {
+        let mut route = gtfs.routes[&route_id].clone();
+        route.id = shuttle_route_id;
+        route.short_name = Some(format!("{} bus", replacement.name));
+        route.route_type = RouteType::Bus;
+        route.color = RGB8::from(replacement.colour);
+        gtfs.routes.insert(route.id.clone(), route);
+    }
+    let calendar_dates = gtfs.calendar_dates.entry(REPLACEMENT_SERVICE_ID.to_string()).or_default();
+    if !calendar_dates.iter().any(|calendar_date| calendar_date.date == date) {
+        calendar_dates.push(CalendarDate { service_id: REPLACEMENT_SERVICE_ID.to_string(), date, exception_type: Exception::Added });
+    }
+    for shuttle in shuttles {
+        gtfs.trips.insert(shuttle.id.clone(), shuttle);
+    }
+    Ok(report)
+}
+
+// Whether the GTFS trip is a replacement bus, for tagging exports.
+pub fn is_replacement_trip(gtfs: &Gtfs, trip_id: &str) -> bool {
+    gtfs.trips.get(trip_id).is_some_and(|trip| trip.service_id == REPLACEMENT_SERVICE_ID)
+}
+
+// The passengers on one shuttle.
+#[derive(Clone, Debug)]
+pub struct ShuttleLoad {
+    pub replacement: String,
+    pub trip_id: String,
+    pub first_stop_id: String,
+    pub departure_time: Timestamp,
+    pub boardings: u64,
+    pub max_load: PopulationCount,
+    pub capacity: PopulationCount,
+}
+
+impl ShuttleLoad {
+    pub fn max_load_factor(&self) -> f32 {
+        self.max_load as f32 / self.capacity as f32
+    }
+}
+
+// The boardings and busiest segment of every shuttle in the network, in the order of the reports.
+pub fn shuttle_loads(network: &Network, simulation_result: &SimulationResult, trip_capacities: &TripCapacities, reports: &[BusReplacementReport]) -> Vec<ShuttleLoad> {
+    let shuttle_ids = reports.iter().flat_map(|report| report.shuttle_trip_ids.iter().map(String::as_str)).collect::<HashSet<_>>();
+    let trip_idx_map: HashMap<&str, (usize, usize)> = network.routes.iter().enumerate().flat_map(|(route_idx, route)| {
+        (0..route.num_trips as usize).map(move |trip| {
+            let trip_id: &str = route.trip_ids[trip].as_ref();
+            (trip_id, (route_idx, trip))
+        })
+    }).filter(|(trip_id, _)| shuttle_ids.contains(trip_id)).collect();
+
+    let mut boardings: HashMap<(usize, usize), u64> = HashMap::new();
+    for agent_journey in simulation_result.round_agent_journeys.last().into_iter().flatten() {
+        let Ok(journey) = &agent_journey.result else {
+            continue;
+        };
+        for leg in journey.legs.iter() {
+            *boardings.entry((leg.trip.route_idx as usize, leg.trip.trip_order as usize)).or_default() += agent_journey.count as u64;
+        }
+    }
+
+    let mut loads = Vec::new();
+    for report in reports {
+        for trip_id in report.shuttle_trip_ids.iter() {
+            // Shuttles outside the network (e.g. left out by the route filter) have no loads.
+            let Some(&(route_idx, trip)) = trip_idx_map.get(trip_id.as_str()) else {
+                continue;
+            };
+            let route = &network.routes[route_idx];
+            let first_stop = route.get_stops(&network.route_stops)[0];
+            loads.push(ShuttleLoad {
+                replacement: report.name.clone(),
+                trip_id: trip_id.clone(),
+                first_stop_id: network.stops[first_stop as usize].id.to_string(),
+                departure_time: network.get_departure_time(route_idx, trip, 0),
+                boardings: boardings.get(&(route_idx, trip)).copied().unwrap_or(0),
+                max_load: simulation_result.population_count[route.get_trip_range(trip)].iter().copied().max().unwrap_or(0),
+                capacity: trip_capacities.get(trip_id).total(),
+            });
+        }
+    }
+    loads
+}
+
+pub fn log_shuttle_loads(loads: &[ShuttleLoad]) {
+    for (replacement, shuttles) in &loads.iter().chunk_by(|load| load.replacement.as_str()) {
+        let shuttles = shuttles.collect_vec();
+        let busiest = shuttles.iter().max_by(|a, b| a.max_load_factor().total_cmp(&b.max_load_factor()));
+        log::info!("Bus replacement {replacement}: {} passengers on {} shuttles, {} over capacity, busiest at {:.0}% of capacity.",
+                   shuttles.iter().map(|load| load.boardings).sum::<u64>(),
+                   shuttles.len(),
+                   shuttles.iter().filter(|load| load.max_load > load.capacity).count(),
+                   busiest.map_or(0., |load| load.max_load_factor() * 100.));
+    }
+}
+
+pub fn export_shuttle_loads(path: &Path, loads: &[ShuttleLoad]) -> Result<(), DataExportError> {
+    if loads.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["replacement", "trip_id", "first_stop_id", "departure_time", "boardings", "max_load", "capacity", "max_load_factor"])?;
+    for load in loads {
+        csv_writer.write_record(&[
+            load.replacement.as_str(),
+            load.trip_id.as_str(),
+            load.first_stop_id.as_str(),
+            &get_time_str(load.departure_time),
+            &load.boardings.to_string(),
+            &load.max_load.to_string(),
+            &load.capacity.to_string(),
+            &format!("{:.3}", load.max_load_factor()),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
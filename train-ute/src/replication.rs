@@ -0,0 +1,182 @@
+use std::path::Path;
+
+use gtfs_structures::Gtfs;
+use itertools::Itertools;
+use raptor::utils::get_time_str;
+use raptor::Network;
+
+use crate::data_export::{DataExportError, TravelStats};
+use crate::simulation::{PopulationCount, SimulationResult, SimulationStep, TripCapacities};
+use crate::stop_geometry::StopGeometry;
+
+// Headline statistics of each replication, in the order of the columns of replications.csv.
+pub const METRICS: [&str; 7] = ["num_agents", "rounds", "total_crowding_cost", "max_segment_load", "passenger_km", "passenger_hours", "mean_load_factor"];
+
+// Two-sided 95% critical values of Student's t distribution for 1 to 30 degrees of freedom.
+const T_CRITICAL_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+    2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+    2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+];
+
+// Past 30 degrees of freedom the normal distribution's is close enough.
+fn t_critical_95(degrees_of_freedom: usize) -> f64 {
+    T_CRITICAL_95.get(degrees_of_freedom.wrapping_sub(1)).copied().unwrap_or(1.96)
+}
+
+// Running mean and variance of each of a fixed number of values, using Welford's algorithm
+// so the replications don't need to be kept.
+#[derive(Clone, Debug)]
+pub struct RunningStats {
+    count: usize,
+    mean: Vec<f64>,
+    m2: Vec<f64>,
+}
+
+impl RunningStats {
+    pub fn new(len: usize) -> Self {
+        Self { count: 0, mean: vec![0.; len], m2: vec![0.; len] }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn add(&mut self, values: impl IntoIterator<Item = f64>) {
+        self.count += 1;
+        for ((mean, m2), value) in self.mean.iter_mut().zip(self.m2.iter_mut()).zip(values) {
+            let delta = value - *mean;
+            *mean += delta / self.count as f64;
+            *m2 += delta * (value - *mean);
+        }
+    }
+
+    pub fn mean(&self, i: usize) -> f64 {
+        self.mean[i]
+    }
+
+    // Sample standard deviation, or zero with fewer than two replications.
+    pub fn std_dev(&self, i: usize) -> f64 {
+        if self.count < 2 { 0. } else { (self.m2[i] / (self.count - 1) as f64).sqrt() }
+    }
+
+    // 95% confidence interval of the mean, using Student's t distribution.
+    pub fn confidence_interval(&self, i: usize) -> (f64, f64) {
+        if self.count < 2 {
+            return (self.mean[i], self.mean[i]);
+        }
+        let half_width = t_critical_95(self.count - 1) * self.std_dev(i) / (self.count as f64).sqrt();
+        (self.mean[i] - half_width, self.mean[i] + half_width)
+    }
+}
+
+// The per-segment loads and headline statistics of every replication, aggregated as they finish.
+#[derive(Clone, Debug)]
+pub struct ReplicationSummary {
+    // (seed, headline statistics) of each replication, in the order they were added.
+    pub runs: Vec<(u64, [f64; METRICS.len()])>,
+    pub metrics: RunningStats,
+    // Indexed like `SimulationResult::population_count`.
+    pub loads: RunningStats,
+}
+
+impl ReplicationSummary {
+    pub fn new(num_segments: usize) -> Self {
+        Self { runs: Vec::new(), metrics: RunningStats::new(METRICS.len()), loads: RunningStats::new(num_segments) }
+    }
+
+    pub fn add(&mut self, seed: u64, metrics: [f64; METRICS.len()], population_count: &[PopulationCount]) {
+        self.runs.push((seed, metrics));
+        self.metrics.add(metrics);
+        self.loads.add(population_count.iter().map(|&count| count as f64));
+    }
+
+    // Mean load of each segment, rounded to whole agents like a simulation result's.
+    pub fn mean_population_count(&self) -> Vec<PopulationCount> {
+        (0..self.loads.mean.len()).map(|i| self.loads.mean(i).round() as PopulationCount).collect()
+    }
+
+    pub fn log(&self) {
+        log::info!("Over {} replications:", self.metrics.count());
+        for (i, metric) in METRICS.iter().enumerate() {
+            let (low, high) = self.metrics.confidence_interval(i);
+            log::info!("  {metric}: mean {:.3}, standard deviation {:.3}, 95% CI [{low:.3}, {high:.3}].", self.metrics.mean(i), self.metrics.std_dev(i));
+        }
+    }
+
+    // Writes each replication's seed and headline statistics to <path>.csv, and their mean, standard deviation and
+    // 95% confidence interval to <path>_summary.csv.
+    pub fn export_metrics(&self, path: &Path) -> Result<(), DataExportError> {
+        if self.runs.is_empty() {
+            return Err(DataExportError::NoData);
+        }
+
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(["replication", "seed"].into_iter().chain(METRICS))?;
+        for (i, (seed, metrics)) in self.runs.iter().enumerate() {
+            csv_writer.write_record([(i + 1).to_string(), seed.to_string()].into_iter().chain(metrics.iter().map(|value| format!("{value:.3}"))))?;
+        }
+        csv_writer.flush()?;
+
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push("_summary.csv");
+        let mut csv_writer = csv::Writer::from_path(path.with_file_name(file_name))?;
+        csv_writer.write_record(&["metric", "replications", "mean", "std_dev", "ci_low", "ci_high"])?;
+        for (i, metric) in METRICS.iter().enumerate() {
+            let (low, high) = self.metrics.confidence_interval(i);
+            csv_writer.write_record(&[metric.to_string(), self.metrics.count().to_string(), format!("{:.3}", self.metrics.mean(i)), format!("{:.3}", self.metrics.std_dev(i)), format!("{low:.3}"), format!("{high:.3}")])?;
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+
+    // Writes the mean, standard deviation and 95% confidence interval of every trip segment's load to <path>.csv.
+    pub fn export_loads(&self, path: &Path, network: &Network) -> Result<(), DataExportError> {
+        if self.loads.count() == 0 || self.loads.mean.len() != network.stop_times.len() {
+            return Err(DataExportError::NoData);
+        }
+
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(&["trip_id", "from_stop_id", "to_stop_id", "departure_time", "mean_load", "std_dev", "ci_low", "ci_high"])?;
+        for route_idx in 0..network.num_routes() {
+            let route = &network.routes[route_idx];
+            let stops = route.get_stops(&network.route_stops);
+            for trip in 0..route.num_trips as usize {
+                let trip_id: &str = route.trip_ids[trip].as_ref();
+                // The load at each stop is the load on the segment departing that stop.
+                for (dep_stop_order, ((&from_stop, &to_stop), i)) in stops.iter().tuple_windows().zip(route.get_trip_range(trip)).enumerate() {
+                    let (low, high) = self.loads.confidence_interval(i);
+                    csv_writer.write_record(&[
+                        trip_id,
+                        network.stops[from_stop as usize].id.as_ref(),
+                        network.stops[to_stop as usize].id.as_ref(),
+                        &get_time_str(network.get_departure_time(route_idx, trip, dep_stop_order)),
+                        &format!("{:.3}", self.loads.mean(i)),
+                        &format!("{:.3}", self.loads.std_dev(i)),
+                        &format!("{low:.3}"),
+                        &format!("{high:.3}"),
+                    ])?;
+                }
+            }
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+}
+
+// The headline statistics of a replication, in the order of `METRICS`.
+pub fn headline_metrics(network: &Network, stop_geometry: &StopGeometry, gtfs: &Gtfs, simulation_steps: &[SimulationStep], simulation_result: &SimulationResult, trip_capacities: &TripCapacities, shape_dist_km: f64) -> [f64; METRICS.len()] {
+    let travel_stats = TravelStats::new(network, stop_geometry, Some(gtfs), simulation_result, trip_capacities, shape_dist_km);
+    let last_round = simulation_result.iteration_history.last();
+    [
+        simulation_steps.iter().map(|step| step.count() as f64).sum(),
+        simulation_result.iteration_history.len() as f64,
+        last_round.map_or(0., |stats| stats.total_crowding_cost),
+        last_round.map_or(0., |stats| stats.max_segment_load as f64),
+        travel_stats.passenger_km,
+        travel_stats.passenger_hours,
+        travel_stats.mean_load_factor,
+    ]
+}
@@ -1,5 +1,5 @@
-use either::Either;
-use itertools::izip;
+use gtfs_structures::Gtfs;
+use itertools::{izip, Itertools};
 #[cfg(feature = "progress_bar")]
 use kdam::{par_tqdm, tqdm};
 use rand::prelude::*;
@@ -7,18 +7,30 @@ use raptor::journey::{JourneyError, JourneyPreferences};
 use raptor::network::{GlobalTripIndex, PathfindingCost, StopIndex, Timestamp};
 use raptor::{Leg, Network};
 use rayon::prelude::*;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 #[cfg(feature = "progress_bar")]
 use std::io::IsTerminal;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::checkpoint::{self, Checkpointing, WarmStart};
+use crate::transfer_times::TransferTimes;
+use crate::wheelchair::WheelchairAccess;
 
 pub type AgentCount = u32;
-pub type PopulationCount = i32;
-pub type PopulationCountAtomic = AtomicI32;
+pub type PopulationCount = u32;
+// Crowding costs and averaged loads are the journey planner's cost type, unless the f64_crowding_cost feature is enabled.
+// That doubles the memory of the per stop time arrays, but averages the loads over many rounds more accurately for calibration.
+#[cfg(not(feature = "f64_crowding_cost"))]
 pub type CrowdingCost = PathfindingCost;
+#[cfg(feature = "f64_crowding_cost")]
+pub type CrowdingCost = f64;
+pub type SegmentIndex = u8;
 
 #[derive(Clone, Copy, Debug)]
-#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
 pub struct TripCapacity {
     pub seated: PopulationCount,
@@ -29,6 +41,42 @@ impl TripCapacity {
     pub fn total(&self) -> PopulationCount {
         self.seated + self.standing
     }
+
+    // Multiplies both capacities by the factor, keeping at least one seat so the crowding cost stays well-defined.
+    pub fn scaled(&self, factor: f64) -> Self {
+        Self {
+            seated: ((self.seated as f64 * factor).round() as PopulationCount).max(1),
+            standing: (self.standing as f64 * factor).round() as PopulationCount,
+        }
+    }
+
+    pub fn crowding_level(&self, count: PopulationCount) -> CrowdingLevel {
+        if count <= self.seated {
+            CrowdingLevel::Seated
+        } else if count <= self.total() {
+            CrowdingLevel::Standing
+        } else {
+            CrowdingLevel::Crush
+        }
+    }
+}
+
+// Whether passengers on a trip segment can all sit, some must stand, or the train is over capacity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrowdingLevel {
+    Seated,
+    Standing,
+    Crush,
+}
+
+impl CrowdingLevel {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CrowdingLevel::Seated => "seated",
+            CrowdingLevel::Standing => "standing",
+            CrowdingLevel::Crush => "crush",
+        }
+    }
 }
 
 impl Default for TripCapacity {
@@ -41,47 +89,225 @@ impl Default for TripCapacity {
     }
 }
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, Debug)]
 pub struct TripCapacities {
     default: TripCapacity,
     overrides: HashMap<String, TripCapacity>,
+    // Capacities from per-route overrides, resolved to trip ids so lookups don't need the GTFS.
+    route_overrides: HashMap<String, TripCapacity>,
 }
 
 impl TripCapacities {
     pub fn new(default: TripCapacity, overrides: HashMap<String, TripCapacity>) -> Self {
-        Self { default, overrides }
+        Self { default, overrides, route_overrides: HashMap::new() }
+    }
+
+    pub fn default_capacity(&self) -> TripCapacity {
+        self.default
     }
 
     pub fn set_default_capacity(&mut self, default: TripCapacity) {
         self.default = default;
     }
 
+    // Applies capacities keyed by GTFS route_id to every trip of that route in the network.
+    // Per-trip overrides still take precedence. Returns the route ids that matched no trips in the network.
+    pub fn set_route_capacities(&mut self, network: &Network, gtfs: &Gtfs, route_capacities: &HashMap<String, TripCapacity>) -> Vec<String> {
+        self.route_overrides.clear();
+        let mut used_route_ids = HashSet::new();
+        for route in network.routes.iter() {
+            for trip_id in route.trip_ids.iter() {
+                let trip_id: &str = trip_id.as_ref();
+                let Some(trip) = gtfs.trips.get(trip_id) else {
+                    continue;
+                };
+                if let Some(&capacity) = route_capacities.get(&trip.route_id) {
+                    used_route_ids.insert(trip.route_id.as_str());
+                    self.route_overrides.insert(trip_id.to_string(), capacity);
+                }
+            }
+        }
+
+        let mut unknown_route_ids = route_capacities.keys().filter(|route_id| !used_route_ids.contains(route_id.as_str())).cloned().collect_vec();
+        unknown_route_ids.sort();
+        for route_id in unknown_route_ids.iter() {
+            log::warn!("Route capacity given for route {route_id}, which has no trips in the network.");
+        }
+        unknown_route_ids
+    }
+
+    pub fn set_trip_capacities(&mut self, overrides: HashMap<String, TripCapacity>) {
+        self.overrides = overrides;
+    }
+
+    // Compares the per-trip and per-route overrides against the trips running in the network.
+    pub fn check_coverage(&self, network: &Network) -> TripCapacityCoverage {
+        let mut network_trip_ids = HashSet::new();
+        let mut trips_with_default = 0;
+        for route in network.routes.iter() {
+            for trip_id in route.trip_ids.iter() {
+                let trip_id: &str = trip_id.as_ref();
+                network_trip_ids.insert(trip_id);
+                if !self.overrides.contains_key(trip_id) && !self.route_overrides.contains_key(trip_id) {
+                    trips_with_default += 1;
+                }
+            }
+        }
+
+        let mut unknown_trip_ids = self.overrides.keys().filter(|trip_id| !network_trip_ids.contains(trip_id.as_str())).cloned().collect_vec();
+        unknown_trip_ids.sort();
+
+        TripCapacityCoverage {
+            num_trips: network_trip_ids.len(),
+            trips_with_default,
+            unknown_trip_ids,
+        }
+    }
+
+    // Scales every capacity (default and overrides) by the factor.
+    pub fn scaled(&self, factor: f64) -> Self {
+        let scale_all = |capacities: &HashMap<String, TripCapacity>| capacities.iter().map(|(id, capacity)| (id.clone(), capacity.scaled(factor))).collect();
+        Self {
+            default: self.default.scaled(factor),
+            overrides: scale_all(&self.overrides),
+            route_overrides: scale_all(&self.route_overrides),
+        }
+    }
+
     pub fn get(&self, trip_id: &str) -> TripCapacity {
-        *self.overrides.get(trip_id).unwrap_or(&self.default)
+        *self.overrides.get(trip_id)
+                       .or_else(|| self.route_overrides.get(trip_id))
+                       .unwrap_or(&self.default)
+    }
+}
+
+#[derive(Debug)]
+pub struct TripCapacityCoverage {
+    pub num_trips: usize,
+    // Trips in the network that fall back to the default capacity.
+    pub trips_with_default: usize,
+    // Trip ids with a capacity override that don't run in the network.
+    pub unknown_trip_ids: Vec<String>,
+}
+
+impl TripCapacityCoverage {
+    pub fn log(&self) {
+        for trip_id in self.unknown_trip_ids.iter() {
+            log::warn!("Capacity given for trip {trip_id}, which does not run in the network.");
+        }
+        if self.trips_with_default > 0 {
+            log::info!("{} of {} trips use the default capacity.", self.trips_with_default, self.num_trips);
+        }
     }
 }
 
 pub type SimulationProgressCallback<'a> = dyn Fn() + Sync + Send + 'a;
 pub trait SimulationParams: Sync {
+    // Crowding cost of a trip segment carrying `count` agents.
     fn cost_fn(&self, trip_id: &str, count: PopulationCount) -> CrowdingCost;
+    // Crowding cost of a trip segment loaded to `load_factor` of its total capacity, which can be above 1.
+    fn cost_for_load_factor(&self, trip_id: &str, load_factor: f64) -> CrowdingCost;
     fn get_journey_preferences(&self) -> &JourneyPreferences;
     fn get_num_rounds(&self) -> u16;
     fn get_bag_size(&self) -> usize;
+    // Weight given to the newest round's loads when averaging with previous rounds (1 uses only the newest round).
+    fn get_step_size(&self, _round_number: u16) -> CrowdingCost { 1. }
+    // Stop before the maximum number of rounds once the relative change in total crowding cost is below this.
+    fn get_convergence_tolerance(&self) -> Option<f64> { None }
+    // Number of consecutive rounds the relative change must stay below the tolerance before stopping.
+    fn get_convergence_rounds(&self) -> u16 { 1 }
+    // When true, agents are denied boarding trips that are at capacity and must re-plan.
+    fn is_capacity_strict(&self) -> bool { false }
+    // The maximum number of agents a trip can carry when capacity is strict.
+    fn get_capacity(&self, _trip_id: &str) -> PopulationCount { PopulationCount::MAX }
+    // Proportion of agents that recompute their journey in the given round (the rest keep their previous plan).
+    fn get_replan_fraction(&self, _round_number: u16) -> CrowdingCost { 1. }
+    // Seed used to select which agents replan each round.
+    fn get_replan_seed(&self) -> u64 { 0 }
+    // Checked between simulation steps. Once true, the remaining agents are skipped and no more rounds are run.
+    fn is_cancelled(&self) -> bool { false }
+    // Crowding-dependent dwell times, applied between rounds by `run_simulation_with_dwell`.
+    fn get_dwell_model(&self) -> Option<&DwellModel> { None }
+    // When set, agents sample their journey from a logit over the Pareto bag instead of using the journey preferences.
+    fn get_route_choice(&self) -> Option<&RouteChoice> { None }
+    // Journey preferences of agents in a population segment (see `PopulationSegment`).
+    fn get_segment_journey_preferences(&self, _segment: SegmentIndex) -> &JourneyPreferences { self.get_journey_preferences() }
+    // Crowding weight of a population segment, which replaces the route choice crowding coefficient.
+    fn get_segment_crowding_weight(&self, _segment: SegmentIndex) -> Option<CrowdingCost> { None }
     fn get_progress_callback(&self) -> Option<&SimulationProgressCallback> { None }
+    // Where to write a checkpoint at the end of each round, and optionally a checkpoint to resume from.
+    fn get_checkpointing(&self) -> Option<&Checkpointing> { None }
+    // Optional demand elasticity, which stops some agents travelling when their journeys get much worse than uncrowded.
+    fn get_elasticity(&self) -> Option<&DemandElasticity> { None }
+    // Loads from a previous run to start the assignment from, which is ignored when resuming from a checkpoint.
+    fn get_warm_start(&self) -> Option<&WarmStart> { None }
+    // Optional cache of the previous round's plans, kept by agents due to replan whose journeys crowding hasn't made much worse.
+    fn get_plan_cache(&self) -> Option<&PlanCache> { None }
+    // Number of simulation steps assigned together on a thread, or None to size chunks from the network and thread count.
+    fn get_chunk_size(&self) -> Option<usize> { None }
+    // Which trips and stops agents needing wheelchair access (see `SimulationStep::requires_accessible`) can use.
+    fn get_wheelchair_access(&self) -> Option<&WheelchairAccess> { None }
+    // Minimum transfer times of particular stops, which journeys are planned again to keep to.
+    fn get_transfer_times(&self) -> Option<&TransferTimes> { None }
+    // Optional departure time choice, letting agents shift their departure to avoid crowding.
+    fn get_departure_choice(&self) -> Option<&DepartureChoice> { None }
+    // Penalties on transfers and waiting when choosing journeys, which are none by default.
+    fn get_transfer_penalties(&self) -> TransferPenalties { TransferPenalties::default() }
     // Called by the simulation to report progress (0-1).
     fn run_progress_callback(&self) {
         self.get_progress_callback().map(|f| f());
     }
 }
 
-#[derive(Debug)]
-#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
 #[cfg_attr(feature = "serde", serde(rename_all = "camelCase", tag = "func", content = "params"))]
 pub enum CrowdingFunc {
     Linear,
     Quadratic,
+    // Load factor raised to the given exponent.
+    Power { exponent: CrowdingCost },
+    // (e^(beta * x) - 1) / (e^beta - 1), where x is the load factor, continuing with the slope at x = 1 above capacity.
+    Exponential { beta: CrowdingCost },
+    // Ascending load factor thresholds. The cost is the proportion of thresholds the load factor has reached.
+    Step { thresholds: Vec<CrowdingCost> },
     OneStep { a0: CrowdingCost, a: CrowdingCost, b: CrowdingCost },
     TwoStep { a0: CrowdingCost, a1: CrowdingCost, a: CrowdingCost, b: CrowdingCost, c: CrowdingCost },
+    // Continuous piecewise-linear cost through (0, 0), (seated, seated_cost), (total, standing_cost) and
+    // (crush_load_factor * total, crush_cost), continuing with the crush slope past crush load.
+    #[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+    SeatedStanding { seated_cost: CrowdingCost, standing_cost: CrowdingCost, crush_cost: CrowdingCost, crush_load_factor: CrowdingCost },
+}
+
+// What the crowding cost does once a trip is loaded past its total capacity (a load factor above 1).
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase", tag = "policy"))]
+pub enum Overcapacity {
+    // Keep following the crowding function.
+    #[default]
+    Continue,
+    // Stay at the cost at capacity.
+    Clamp,
+    // The cost at capacity plus `slope` per unit of load factor above capacity.
+    Linear { slope: CrowdingCost },
+}
+
+impl Overcapacity {
+    pub fn validate(&self) -> Result<(), CrowdingFuncError> {
+        match self {
+            Overcapacity::Linear { slope } if !slope.is_finite() || *slope < 0. => {
+                Err(CrowdingFuncError::InvalidParameter("overcapacity", "slope", format!("{slope} must be finite and non-negative")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum CrowdingFuncError {
+    #[error("Invalid {0} crowding function parameter `{1}`: {2}")]
+    InvalidParameter(&'static str, &'static str, String),
 }
 
 impl CrowdingFunc {
@@ -89,45 +315,170 @@ impl CrowdingFunc {
         match self {
             CrowdingFunc::Linear => "linear",
             CrowdingFunc::Quadratic => "quadratic",
+            CrowdingFunc::Power { .. } => "power",
+            CrowdingFunc::Exponential { .. } => "exponential",
+            CrowdingFunc::Step { .. } => "step",
             CrowdingFunc::OneStep { .. } => "one_step",
             CrowdingFunc::TwoStep { .. } => "two_step",
+            CrowdingFunc::SeatedStanding { .. } => "seated_standing",
+        }
+    }
+
+    // Checks the parameters give a well-defined, non-decreasing cost function.
+    pub fn validate(&self) -> Result<(), CrowdingFuncError> {
+        let name = self.get_name();
+        let check = |param: &'static str, value: CrowdingCost, positive: bool| {
+            if !value.is_finite() {
+                Err(CrowdingFuncError::InvalidParameter(name, param, format!("{value} is not finite")))
+            } else if positive && value <= 0. {
+                Err(CrowdingFuncError::InvalidParameter(name, param, format!("{value} must be greater than zero")))
+            } else {
+                Ok(())
+            }
+        };
+
+        match self {
+            CrowdingFunc::Linear | CrowdingFunc::Quadratic => Ok(()),
+            CrowdingFunc::Power { exponent } => check("exponent", *exponent, true),
+            CrowdingFunc::Exponential { beta } => check("beta", *beta, true),
+            CrowdingFunc::Step { thresholds } => {
+                if thresholds.is_empty() {
+                    return Err(CrowdingFuncError::InvalidParameter(name, "thresholds", "at least one threshold is required".to_owned()));
+                }
+                for &threshold in thresholds {
+                    check("thresholds", threshold, true)?;
+                }
+                if !thresholds.windows(2).all(|w| w[0] < w[1]) {
+                    return Err(CrowdingFuncError::InvalidParameter(name, "thresholds", format!("{thresholds:?} must be strictly increasing")));
+                }
+                Ok(())
+            }
+            CrowdingFunc::OneStep { a0, a, b } => {
+                check("a0", *a0, true)?;
+                check("a", *a, true)?;
+                check("b", *b, false)
+            }
+            CrowdingFunc::TwoStep { a0, a1, a, b, c } => {
+                check("a0", *a0, false)?;
+                check("a1", *a1, false)?;
+                check("a", *a, false)?;
+                check("b", *b, false)?;
+                check("c", *c, false)?;
+                if a1 < a0 {
+                    return Err(CrowdingFuncError::InvalidParameter(name, "a1", format!("{a1} must not be less than a0 ({a0})")));
+                }
+                Ok(())
+            }
+            CrowdingFunc::SeatedStanding { seated_cost, standing_cost, crush_cost, crush_load_factor } => {
+                check("seated_cost", *seated_cost, false)?;
+                check("standing_cost", *standing_cost, false)?;
+                check("crush_cost", *crush_cost, false)?;
+                check("crush_load_factor", *crush_load_factor, false)?;
+                if *seated_cost < 0. || standing_cost < seated_cost || crush_cost < standing_cost {
+                    return Err(CrowdingFuncError::InvalidParameter(name, "crush_cost", format!("costs must satisfy 0 <= seated_cost ({seated_cost}) <= standing_cost ({standing_cost}) <= crush_cost ({crush_cost})")));
+                }
+                if *crush_load_factor <= 1. {
+                    return Err(CrowdingFuncError::InvalidParameter(name, "crush_load_factor", format!("{crush_load_factor} must be greater than one")));
+                }
+                Ok(())
+            }
         }
     }
 
-    fn linear(cap: TripCapacity, x: PopulationCount) -> CrowdingCost {
-        x as CrowdingCost / cap.total() as CrowdingCost
+    fn quadratic(load_factor: CrowdingCost) -> CrowdingCost {
+        load_factor.powi(2)
+    }
+
+    fn power(load_factor: CrowdingCost, exponent: CrowdingCost) -> CrowdingCost {
+        load_factor.powf(exponent)
+    }
+
+    fn exponential(load_factor: CrowdingCost, beta: CrowdingCost) -> CrowdingCost {
+        // Evaluated as e^(beta * (x - 1)) * (1 - e^(-beta * x)) / (1 - e^(-beta)) in f64, which can't overflow for x <= 1 however large beta is.
+        let beta = beta as f64;
+        let load_factor = load_factor as f64;
+        let cost = if load_factor <= 1. {
+            (beta * (load_factor - 1.)).exp() * (-beta * load_factor).exp_m1() / (-beta).exp_m1()
+        } else {
+            // Past capacity the exponential would soon overflow, so it's extended linearly, which keeps the cost finite and increasing.
+            let slope = -beta / (-beta).exp_m1();
+            1. + slope * (load_factor - 1.)
+        };
+        cost as CrowdingCost
     }
 
-    fn quadratic(cap: TripCapacity, x: PopulationCount) -> CrowdingCost {
-        let total_capacity = cap.total();
-        (x * x) as CrowdingCost / (total_capacity * total_capacity) as CrowdingCost
+    fn step(load_factor: CrowdingCost, thresholds: &[CrowdingCost]) -> CrowdingCost {
+        let num_reached = thresholds.iter().take_while(|&&threshold| threshold <= load_factor).count();
+        num_reached as CrowdingCost / thresholds.len() as CrowdingCost
     }
 
-    fn one_step(cap: TripCapacity, x: PopulationCount, a0: CrowdingCost, a: CrowdingCost, b: CrowdingCost) -> CrowdingCost {
-        if x == 0 {
+    // The remaining functions are defined on the number of agents, so take the (fractional) number the load factor is of.
+    fn one_step(cap: TripCapacity, load_factor: CrowdingCost, a0: CrowdingCost, a: CrowdingCost, b: CrowdingCost) -> CrowdingCost {
+        if load_factor <= 0. {
             return 0.;
         }
-        let seated = cap.seated;
-        let x_on_s = x as CrowdingCost / seated as CrowdingCost;
-        let s_on_x = seated as CrowdingCost / x as CrowdingCost;
+        let x = load_factor * cap.total() as CrowdingCost;
+        let seated = cap.seated as CrowdingCost;
+        let x_on_s = x / seated;
+        let s_on_x = seated / x;
 
         (a0 * s_on_x + (1. - s_on_x) * a0 * (1. + b * (a * (x_on_s - 1.)).exp())).max(a0)
     }
 
-    fn two_step(cap: TripCapacity, x: PopulationCount, a0: CrowdingCost, a1: CrowdingCost, a: CrowdingCost, b: CrowdingCost, c: CrowdingCost) -> CrowdingCost {
-        if x == 0 {
+    fn two_step(cap: TripCapacity, load_factor: CrowdingCost, a0: CrowdingCost, a1: CrowdingCost, a: CrowdingCost, b: CrowdingCost, c: CrowdingCost) -> CrowdingCost {
+        if load_factor <= 0. {
             return 0.;
         }
+        let total = cap.total() as CrowdingCost;
+        let x = load_factor * total;
 
-        a0 + (a1 - a0) / (1. + (a * (cap.seated - x) as CrowdingCost).exp()) + b * (c * (x - cap.total()) as CrowdingCost).exp()
+        a0 + (a1 - a0) / (1. + (a * (cap.seated as CrowdingCost - x)).exp()) + b * (c * (x - total)).exp()
     }
 
-    pub fn crowding_cost(&self, cap: TripCapacity, count: PopulationCount) -> CrowdingCost {
+    fn seated_standing(cap: TripCapacity, load_factor: CrowdingCost, seated_cost: CrowdingCost, standing_cost: CrowdingCost, crush_cost: CrowdingCost, crush_load_factor: CrowdingCost) -> CrowdingCost {
+        let seated = cap.seated as CrowdingCost;
+        let total = cap.total() as CrowdingCost;
+        let x = load_factor * total;
+        let crush = crush_load_factor * total;
+
+        if x <= seated {
+            seated_cost * x / seated
+        } else if x <= total {
+            // No standing capacity means this segment is never reached.
+            seated_cost + (standing_cost - seated_cost) * (x - seated) / (total - seated)
+        } else {
+            standing_cost + (crush_cost - standing_cost) * (x - total) / (crush - total)
+        }
+    }
+
+    // Cost at `load_factor` of the trip's total capacity, without adjusting for overcapacity.
+    fn load_factor_cost(&self, cap: TripCapacity, load_factor: CrowdingCost) -> CrowdingCost {
         match &self {
-            CrowdingFunc::Linear => Self::linear(cap, count),
-            CrowdingFunc::Quadratic => Self::quadratic(cap, count),
-            CrowdingFunc::OneStep { a0, a, b } => Self::one_step(cap, count, *a0, *a, *b),
-            CrowdingFunc::TwoStep { a0, a1, a, b, c } => Self::two_step(cap, count, *a0, *a1, *a, *b, *c),
+            CrowdingFunc::Linear => load_factor,
+            CrowdingFunc::Quadratic => Self::quadratic(load_factor),
+            CrowdingFunc::Power { exponent } => Self::power(load_factor, *exponent),
+            CrowdingFunc::Exponential { beta } => Self::exponential(load_factor, *beta),
+            CrowdingFunc::Step { thresholds } => Self::step(load_factor, thresholds),
+            CrowdingFunc::OneStep { a0, a, b } => Self::one_step(cap, load_factor, *a0, *a, *b),
+            CrowdingFunc::TwoStep { a0, a1, a, b, c } => Self::two_step(cap, load_factor, *a0, *a1, *a, *b, *c),
+            CrowdingFunc::SeatedStanding { seated_cost, standing_cost, crush_cost, crush_load_factor } => Self::seated_standing(cap, load_factor, *seated_cost, *standing_cost, *crush_cost, *crush_load_factor),
+        }
+    }
+
+    pub fn crowding_cost(&self, cap: TripCapacity, count: PopulationCount) -> CrowdingCost {
+        self.load_factor_cost(cap, count as CrowdingCost / cap.total() as CrowdingCost)
+    }
+
+    // Cost at `load_factor` of the trip's total capacity, using `overcapacity` above 1. The load factor isn't rounded to a
+    // whole number of agents, so averaged loads and small capacities get the cost in between.
+    pub fn cost_for_load_factor(&self, cap: TripCapacity, load_factor: f64, overcapacity: Overcapacity) -> CrowdingCost {
+        if load_factor <= 1. {
+            return self.load_factor_cost(cap, load_factor as CrowdingCost);
+        }
+        match overcapacity {
+            Overcapacity::Continue => self.load_factor_cost(cap, load_factor as CrowdingCost),
+            Overcapacity::Clamp => self.load_factor_cost(cap, 1.),
+            Overcapacity::Linear { slope } => self.load_factor_cost(cap, 1.) + slope * (load_factor - 1.) as CrowdingCost,
         }
     }
 
@@ -142,348 +493,1852 @@ impl CrowdingFunc {
     }
 }
 
-// This default simulation parameter implementation uses a simple exponential crowding cost function, and can report progress.
-pub struct DefaultSimulationParams<'a> {
-    pub crowding_function: CrowdingFunc,
-    pub progress_callback: Option<Box<SimulationProgressCallback<'a>>>,
-    pub journey_preferences: JourneyPreferences,
-    pub num_rounds: u16,
-    pub bag_size: usize,
-    pub trip_capacities: TripCapacities,
+// How loads are averaged between rounds.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase", tag = "method", content = "step"))]
+pub enum StepSize {
+    // Only use the newest round.
+    #[default]
+    Full,
+    // Method of successive averages: round n has weight 1 / (n + 1), so every round is weighted equally.
+    SuccessiveAverages,
+    Constant(CrowdingCost),
 }
 
-impl SimulationParams for DefaultSimulationParams<'_> {
-    fn cost_fn(&self, trip_id: &str, count: PopulationCount) -> CrowdingCost {
-        debug_assert!(count >= 0, "Negative population count");
-        self.crowding_function.crowding_cost(self.trip_capacities.get(trip_id), count)
-    }
-
-    fn get_journey_preferences(&self) -> &JourneyPreferences {
-        &self.journey_preferences
+impl StepSize {
+    pub fn get_step_size(&self, round_number: u16) -> CrowdingCost {
+        match self {
+            StepSize::Full => 1.,
+            StepSize::SuccessiveAverages => 1. / (round_number as CrowdingCost + 1.),
+            StepSize::Constant(step) => *step,
+        }
     }
+}
 
-    fn get_num_rounds(&self) -> u16 {
-        self.num_rounds
-    }
+// Only a fraction of agents recompute their journeys each round, which damps oscillation on congested corridors.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct Replanning {
+    // Proportion of agents that replan in the second round.
+    pub fraction: CrowdingCost,
+    // The fraction is multiplied by this for each later round.
+    pub decay: CrowdingCost,
+    pub seed: u64,
+}
 
-    fn get_bag_size(&self) -> usize {
-        self.bag_size
+impl Default for Replanning {
+    fn default() -> Self {
+        Self { fraction: 1., decay: 1., seed: 0 }
     }
+}
 
-    fn get_progress_callback(&self) -> Option<&SimulationProgressCallback> {
-        self.progress_callback.as_ref().map(|f| f.as_ref())
+impl Replanning {
+    pub fn get_fraction(&self, round_number: u16) -> CrowdingCost {
+        // Everyone plans in the first round.
+        if round_number == 0 {
+            return 1.;
+        }
+        (self.fraction * self.decay.powi(round_number as i32 - 1)).clamp(0., 1.)
     }
 }
 
-pub struct SimulationStep {
-    pub departure_time: Timestamp,
-    pub origin_stop: StopIndex,
-    dest_stops: Vec<StopIndex>,
-    counts: Vec<AgentCount>,
+// Stochastic route choice: each agent picks from its Pareto bag of journeys (so the bag size is the number of alternatives)
+// with multinomial logit probabilities, rather than always taking the journey with the lowest generalised cost.
+//
+// The planner's labels only carry the arrival time and crowding cost, so transfers and waiting can't be priced while
+// planning. When they have a coefficient, each journey is compared with the journeys planned with each of its legs blocked
+// in turn, and the agent takes whichever has the lowest cost with them (see `transfer_and_wait_cost`).
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct RouteChoice {
+    // Weight of journey time, per second.
+    pub time_coefficient: CrowdingCost,
+    // Weight of crowding cost.
+    pub crowding_coefficient: CrowdingCost,
+    // Logit scale, in the same units as the generalised cost. Larger values spread agents more evenly over the alternatives.
+    pub scale: CrowdingCost,
+    // Weight of each transfer.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub transfer_coefficient: CrowdingCost,
+    // Weight of time spent waiting at the origin and between legs, per second, on top of it counting as journey time.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub wait_coefficient: CrowdingCost,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub seed: u64,
 }
 
-impl SimulationStep {
-    pub fn new(departure_time: Timestamp, origin_stop: StopIndex) -> Self {
-        Self {
-            departure_time,
-            origin_stop,
-            dest_stops: Vec::new(),
-            counts: Vec::new(),
-        }
+impl RouteChoice {
+    // Route choice for a population segment, valuing crowding `crowding_weight` times as much as journey time.
+    fn for_segment(&self, crowding_weight: CrowdingCost) -> Self {
+        Self { crowding_coefficient: self.time_coefficient * crowding_weight, ..*self }
     }
-    pub fn len(&self) -> usize {
-        self.dest_stops.len()
+
+    fn step_seed(&self, round_number: u16, sim_step_idx: usize) -> u64 {
+        self.seed ^ ((round_number as u64) << 48) ^ sim_step_idx as u64
     }
-    pub fn count(&self) -> AgentCount {
-        self.counts.iter().sum()
+
+    // Whether transfers or waiting add to the cost, so journeys are chosen from alternatives avoiding each of their legs.
+    fn penalises_transfers(&self) -> bool {
+        self.transfer_coefficient != 0. || self.wait_coefficient != 0.
     }
-    pub fn push(&mut self, dest_stop: StopIndex, count: AgentCount) {
-        self.dest_stops.push(dest_stop);
-        self.counts.push(count);
+
+    // Generalised cost of arriving at `arrival_time` with crowding cost `crowding_cost`, with its Gumbel noise.
+    fn label_cost(&self, step_seed: u64, start_time: Timestamp, arrival_time: Timestamp, crowding_cost: PathfindingCost) -> PathfindingCost {
+        let (time_coefficient, crowding_coefficient, scale) = (self.time_coefficient as PathfindingCost, self.crowding_coefficient as PathfindingCost, self.scale as PathfindingCost);
+        let cost = time_coefficient * (arrival_time - start_time) as PathfindingCost + crowding_coefficient * crowding_cost;
+        // The noise is seeded by the label, so a journey gets the same cost every time it's compared.
+        let mut rng = SmallRng::seed_from_u64(step_seed ^ ((arrival_time as u64) << 32) ^ crowding_cost.to_bits() as u64);
+        let uniform = rng.gen_range(f64::EPSILON..1.);
+        cost - scale * -(-uniform.ln()).ln() as PathfindingCost
+    }
+
+    // The label cost of a journey departing at `start_time`, with the cost of its transfers and waiting.
+    fn journey_cost(&self, step_seed: u64, start_time: Timestamp, duration: Timestamp, crowding_cost: PathfindingCost, legs: &[Leg]) -> PathfindingCost {
+        self.label_cost(step_seed, start_time, start_time + duration, crowding_cost) + transfer_and_wait_cost(self.transfer_coefficient, self.wait_coefficient, duration, legs)
+    }
+
+    // Choosing the lowest cost after adding Gumbel noise is the same as sampling from the logit choice probabilities.
+    fn journey_preferences(&self, round_number: u16, sim_step_idx: usize) -> JourneyPreferences {
+        let route_choice = *self;
+        let step_seed = self.step_seed(round_number, sim_step_idx);
+        JourneyPreferences {
+            utility_function: Box::new(move |label, start_time| route_choice.label_cost(step_seed, start_time, label.arrival_time, label.cost))
+        }
     }
 }
 
-pub struct AgentJourney {
-    pub origin_trip: GlobalTripIndex,
-    pub dest_trip: GlobalTripIndex,
-    pub duration: Timestamp,
-    pub crowding_cost: CrowdingCost,
-    pub num_transfers: u8,
-    pub legs: Vec<Leg>,
+// Penalties on transfers and waiting in the generalised cost of agents without route choice (which has its own
+// coefficients), chosen between alternatives the same way. Both 0 plans journeys as the journey preferences alone do.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferPenalties {
+    // Equivalent seconds of journey time per transfer.
+    pub transfer_penalty: CrowdingCost,
+    // Weight of each second waiting at the origin and between legs, on top of it counting as journey time.
+    pub wait_time_weight: CrowdingCost,
+    // Seconds of journey time per unit of crowding cost, as in the journey preferences, unless the step's segment has its
+    // own crowding weight.
+    pub crowding_weight: CrowdingCost,
 }
 
-pub struct AgentJourneyResult {
-    pub sim_step_idx: u32,
-    pub journey_idx: u32,
-    pub origin_stop: StopIndex,
-    pub dest_stop: StopIndex,
-    pub start_time: Timestamp,
-    pub count: AgentCount,
-    pub result: Result<AgentJourney, JourneyError>,
+impl TransferPenalties {
+    pub fn is_zero(&self) -> bool {
+        self.transfer_penalty == 0. && self.wait_time_weight == 0.
+    }
+
+    fn journey_cost(&self, crowding_weight: CrowdingCost, duration: Timestamp, crowding_cost: PathfindingCost, legs: &[Leg]) -> PathfindingCost {
+        duration as PathfindingCost + crowding_weight as PathfindingCost * crowding_cost + transfer_and_wait_cost(self.transfer_penalty, self.wait_time_weight, duration, legs)
+    }
 }
 
-pub struct SimulationRoundResult {
-    pub population_count: Vec<PopulationCount>,
-    pub crowding_cost: Vec<CrowdingCost>,
-    pub agent_journeys: Vec<AgentJourneyResult>,
+// Cost of a journey's transfers and of its time waiting at the origin and between legs, at the given cost of each.
+fn transfer_and_wait_cost(transfer_cost: CrowdingCost, wait_cost: CrowdingCost, duration: Timestamp, legs: &[Leg]) -> PathfindingCost {
+    let in_vehicle_time: Timestamp = legs.iter().map(|leg| leg.arrival_time - leg.boarded_time).sum();
+    (transfer_cost * legs.len().saturating_sub(1) as CrowdingCost + wait_cost * duration.saturating_sub(in_vehicle_time) as CrowdingCost) as PathfindingCost
 }
 
-pub struct SimulationResult {
-    pub population_count: Vec<PopulationCount>,
-    pub round_agent_journeys: Vec<Vec<AgentJourneyResult>>,
+// Agents due to replan keep their previous journey unless its crowding cost under the new loads is more than `threshold`
+// (a fraction) above its cost when it was planned, which skips most journey queries once the assignment settles.
+// Plans are cached per simulation step (an origin and departure time) and destination, as the previous round's journeys.
+// Crowding only ever makes a cached plan worse, so every `full_replan_interval` rounds everyone due to replan does,
+// letting agents find journeys that got better.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct PlanCache {
+    pub threshold: CrowdingCost,
+    // Zero never forces a full replan.
+    pub full_replan_interval: u16,
 }
 
-impl SimulationResult {
-    pub fn print_stats(&self) {
-        log::info!("Rounds: {}", self.round_agent_journeys.len());
-        log::info!("Agent journeys: {}", self.round_agent_journeys.last().map(|v| v.len()).unwrap_or(0));
+impl PlanCache {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.threshold.is_finite() || self.threshold < 0. {
+            return Err(format!("threshold {} must not be negative", self.threshold));
+        }
+        Ok(())
+    }
+
+    fn is_full_replan(&self, round_number: u16) -> bool {
+        self.full_replan_interval > 0 && round_number % self.full_replan_interval == 0
+    }
+
+    fn is_stale(&self, planned_cost: CrowdingCost, cost: CrowdingCost) -> bool {
+        cost > planned_cost * (1. + self.threshold)
     }
 }
 
-pub fn gen_simulation_steps(network: &Network, number: Option<usize>, seed: Option<u64>) -> Vec<SimulationStep> {
-    let num_stops = network.num_stops() as StopIndex;
-    let mut rng = match seed {
-        Some(seed) => SmallRng::seed_from_u64(seed),
-        None => SmallRng::from_entropy(),
-    };
+// Elastic demand: after each round, each agent travels in the next round with probability (cost / baseline)^-elasticity,
+// where cost is the generalised cost of its latest journey (duration plus crowding_weight times the experienced crowding cost)
+// and baseline is its journey time in the uncrowded first round. Agents whose journeys got no worse always travel.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct DemandElasticity {
+    pub elasticity: CrowdingCost,
+    // Seconds of journey time per unit of crowding cost.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub crowding_weight: CrowdingCost,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub seed: u64,
+}
 
-    // New agent journey every second.
-    let sim_start_time = 4 * 60 * 60; // Start at 4am.
-    let sim_end_time = 24 * 60 * 60; // Final journey begins at midnight.
-    let sim_length = sim_end_time - sim_start_time;
-    let number = number.unwrap_or(sim_length as usize);
-    let interval = sim_length as f64 / number as f64;
-    let mut simulation_steps = Vec::with_capacity(number);
-    for i in 0..number {
-        let start_time = sim_start_time + (i as f64 * interval) as Timestamp;
-        simulation_steps.push(SimulationStep {
-            departure_time: start_time,
-            origin_stop: rng.gen_range(0..num_stops),
-            dest_stops: vec![rng.gen_range(0..num_stops)],
-            counts: vec![rng.gen_range(1..=10)],
-        });
+impl DemandElasticity {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.elasticity.is_finite() || self.elasticity < 0. {
+            return Err(format!("elasticity {} must not be negative", self.elasticity));
+        }
+        Ok(())
+    }
+
+    pub fn travel_probability(&self, baseline_cost: f64, cost: f64) -> f64 {
+        if baseline_cost <= 0. {
+            return 1.;
+        }
+        (cost / baseline_cost).max(1.).powf(-self.elasticity as f64)
+    }
+
+    fn generalised_cost(&self, journey: &AgentJourney) -> f64 {
+        journey.duration as f64 + (self.crowding_weight * journey.experienced_crowding_cost) as f64
+    }
+
+    // The number of agents of each journey (in round order) that travel in the next round. Each party decides separately,
+    // seeded by the round and journey, so the decisions don't depend on scheduling.
+    fn travelling_counts(&self, simulation_steps: &[SimulationStep], baseline_costs: &[Option<f64>], costs: &[Option<f64>], round_number: u16) -> Vec<AgentCount> {
+        let counts = simulation_steps.iter().flat_map(|sim_step| izip!(sim_step.counts.iter().copied(), sim_step.party_sizes.iter().copied())).collect_vec();
+        counts.into_par_iter().enumerate().map(|(journey_idx, (count, party_size))| {
+            let (Some(baseline_cost), Some(cost)) = (baseline_costs[journey_idx], costs[journey_idx]) else {
+                return count;
+            };
+            let probability = self.travel_probability(baseline_cost, cost);
+            if probability >= 1. {
+                return count;
+            }
+            let mut rng = SmallRng::seed_from_u64(self.seed ^ ((round_number as u64) << 48) ^ journey_idx as u64);
+            party_size * (0..count / party_size).filter(|_| rng.gen::<f64>() < probability).count() as AgentCount
+        }).collect()
     }
-    simulation_steps
 }
 
-fn run_simulation_round(network: &Network,
-                        simulation_steps: &[SimulationStep],
-                        params: &impl SimulationParams,
-                        crowding_cost: Option<&[CrowdingCost]>,
-                        round_number: u16) -> SimulationRoundResult {
-    // Initialise agent counts to zero. To allow parallelism, we use an atomic type.
-    let mut trip_stops_pop = Vec::new();
-    trip_stops_pop.resize_with(network.stop_times.len(), PopulationCountAtomic::default);
+// Departure time choice (peak spreading): each round after the first, a share of the simulation steps due to replan (chosen
+// using the seed) also try departing `interval` seconds earlier and later than they currently do, moving if that lowers the
+// combined cost of the step's agents. This is their journey time, plus crowding_weight times their planned crowding cost,
+// plus the schedule delay of early_coefficient (or late_coefficient) per second before (or after) the preferred departure,
+// which is the simulation step's departure time. Shifts are bounded to `window` seconds either side of it. A step's agents
+// share a journey query, so they shift together.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct DepartureChoice {
+    // Largest shift from the preferred departure, in seconds.
+    pub window: Timestamp,
+    // Seconds between the departures tried.
+    pub interval: Timestamp,
+    // Proportion of the steps due to replan that consider shifting each round.
+    pub share: CrowdingCost,
+    // Cost per second of departing early or late, in seconds of journey time.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub early_coefficient: CrowdingCost,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub late_coefficient: CrowdingCost,
+    // Seconds of journey time per unit of crowding cost, unless the step's segment has its own crowding weight.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub crowding_weight: CrowdingCost,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub seed: u64,
+}
 
-    let mut zero_crowding_cost = Vec::new();
+impl DepartureChoice {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.interval == 0 || self.interval > self.window {
+            return Err(format!("interval ({}) must be greater than zero and at most the window ({})", self.interval, self.window));
+        }
+        if !(0. ..=1.).contains(&self.share) {
+            return Err(format!("share ({}) must be between 0 and 1", self.share));
+        }
+        Ok(())
+    }
 
-    let crowding_cost = crowding_cost.unwrap_or_else(|| {
-        zero_crowding_cost = vec![0 as CrowdingCost; network.stop_times.len()];
-        &zero_crowding_cost
-    });
+    pub fn schedule_delay_cost(&self, preferred_departure: Timestamp, departure_time: Timestamp) -> f64 {
+        if departure_time < preferred_departure {
+            (preferred_departure - departure_time) as f64 * self.early_coefficient as f64
+        } else {
+            (departure_time - preferred_departure) as f64 * self.late_coefficient as f64
+        }
+    }
 
-    assert_eq!(trip_stops_pop.len(), crowding_cost.len());
+    // Seeded by the round and step like replanning, but from its own seed so the draws don't line up.
+    fn is_considering(&self, round_number: u16, sim_step_idx: usize) -> bool {
+        round_number > 0 && is_replanning(self.seed, round_number, sim_step_idx, self.share)
+    }
 
-    let journey_preferences = params.get_journey_preferences();
+    // One interval either side of the current departure, within the window.
+    fn candidates(&self, preferred_departure: Timestamp, departure_time: Timestamp) -> impl Iterator<Item=Timestamp> {
+        let earliest = preferred_departure.saturating_sub(self.window);
+        let latest = preferred_departure + self.window;
+        [departure_time.checked_sub(self.interval), Some(departure_time + self.interval)].into_iter().flatten().filter(move |time| (earliest..=latest).contains(time))
+    }
 
-    let num_agents = simulation_steps.iter().fold(0, |acc, step| acc + step.len());
+    // The combined cost of a step's journeys departing at `departure_time`, from each destination's count and journey
+    // (duration and crowding cost), or None if any agents have no journey.
+    fn step_cost(&self, crowding_weight: CrowdingCost, preferred_departure: Timestamp, departure_time: Timestamp, journeys: impl Iterator<Item=(AgentCount, Option<(Timestamp, PathfindingCost)>)>) -> Option<f64> {
+        let schedule_delay_cost = self.schedule_delay_cost(preferred_departure, departure_time);
+        journeys.filter(|(count, _)| *count > 0).map(|(count, journey)| {
+            let (duration, crowding_cost) = journey?;
+            Some(count as f64 * (duration as f64 + crowding_weight as f64 * crowding_cost as f64 + schedule_delay_cost))
+        }).sum()
+    }
+}
 
-    let step_iterator = simulation_steps.par_iter();
+// A group of agents with their own value of crowding (e.g. commuters, students or leisure travellers).
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct PopulationSegment {
+    pub name: String,
+    // Proportion of agents in this segment.
+    pub share: f64,
+    // Weight of crowding cost relative to journey time when choosing a route.
+    pub crowding_weight: CrowdingCost,
+}
 
-    #[cfg(feature = "progress_bar")]
-    let step_iterator = {
-        par_tqdm!(
-            step_iterator,
-            desc = " Simulation Steps", 
-            position = round_number + 1,
-            animation = kdam::Animation::FillUp
-        )
-    };
+// Route choice coefficients of a population segment.
+pub struct SegmentPreferences {
+    pub crowding_weight: CrowdingCost,
+    pub journey_preferences: JourneyPreferences,
+}
 
-    // Use a bag size of 1 for the first round, because there's no crowding data yet.
-    let bag_size = if round_number == 0 { 1 } else { params.get_bag_size().clamp(2, 5) };
+// Splits each simulation step's agents between the segments at random in proportion to their shares (which should add up
+// to one). Agents in a simulation step share a journey query, so each segment gets its own simulation step.
+pub fn assign_segments(simulation_steps: Vec<SimulationStep>, shares: &[f64], seed: u64) -> Vec<SimulationStep> {
+    if shares.len() <= 1 {
+        return simulation_steps;
+    }
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut sample_segment = || {
+        let mut choice = rng.gen::<f64>();
+        shares.iter().position(|&share| {
+            choice -= share;
+            choice < 0.
+        }).unwrap_or(shares.len() - 1)
+    };
 
-    let mut agent_journeys = Vec::with_capacity(num_agents);
-    agent_journeys.par_extend(step_iterator
-        .enumerate()
-        .flat_map_iter(|(sim_step_idx, sim_step)| {
-            params.run_progress_callback();
-
-            let sim_step_idx = sim_step_idx as u32;
-            // TODO: This doesn't account for when there are zero agents for one of the destinations.
-            if sim_step.count() == 0 {
-                // Ignore zero-count agents.
-                return Either::Left((0..sim_step.dest_stops.len() as u32).map(move |journey_idx| {
-                    AgentJourneyResult {
-                        sim_step_idx,
-                        journey_idx,
-                        origin_stop: sim_step.origin_stop,
-                        dest_stop: sim_step.dest_stops[journey_idx as usize],
-                        start_time: sim_step.departure_time,
-                        count: 0,
-                        result: Err(JourneyError::ZeroAgents),
-                    }
-                }));
+    let mut segmented_steps = Vec::with_capacity(simulation_steps.len() * shares.len());
+    for simulation_step in simulation_steps {
+        let mut segment_steps = (0..shares.len()).map(|segment| SimulationStep {
+            segment: segment as SegmentIndex,
+            requires_accessible: simulation_step.requires_accessible,
+            ..SimulationStep::new(simulation_step.departure_time, simulation_step.origin_stop)
+        }).collect_vec();
+        for (&dest_stop, &count, &party_size) in izip!(&simulation_step.dest_stops, &simulation_step.counts, &simulation_step.party_sizes) {
+            // Whole parties are assigned to a segment.
+            let mut segment_parties = vec![0; shares.len()];
+            for _ in 0..count / party_size {
+                segment_parties[sample_segment()] += 1;
             }
-
-            macro_rules! mc_raptor {
-                ($bag_size:expr) => {
-                    raptor::mc_raptor_query::<$bag_size>(network,
-                                                         sim_step.origin_stop,
-                                                         sim_step.departure_time,
-                                                         &sim_step.dest_stops,
-                                                         crowding_cost,
-                                                         &journey_preferences)
-                };
+            for (segment_step, &num_parties) in izip!(segment_steps.iter_mut(), &segment_parties) {
+                if num_parties > 0 {
+                    segment_step.push_parties(dest_stop, party_size, num_parties);
+                }
             }
+        }
+        segmented_steps.extend(segment_steps.into_iter().filter(|segment_step| segment_step.len() > 0));
+    }
+    segmented_steps
+}
 
-            let journeys = match bag_size {
-                // TODO: Implement bag size 1 with normal raptor (extend to multi-dest).
-                1 => mc_raptor!(1),
-                2 => mc_raptor!(2),
-                3 => mc_raptor!(3),
-                4 => mc_raptor!(4),
-                5 => mc_raptor!(5),
-                _ => unreachable!(),
-            };
+// Busy stops take longer to board and alight, which delays the rest of the trip.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct DwellModel {
+    // Dwell time (in seconds) with nobody boarding or alighting.
+    pub base: CrowdingCost,
+    // Seconds added per boarding agent.
+    pub alpha: CrowdingCost,
+    // Seconds added per alighting agent.
+    pub beta: CrowdingCost,
+    // Cap on the delay (in seconds) a trip can accumulate from its own dwell times, so schedules can't run away.
+    pub max_delay: Timestamp,
+}
+
+impl DwellModel {
+    pub fn dwell_time(&self, boardings: PopulationCount, alightings: PopulationCount) -> Timestamp {
+        (self.base + self.alpha * boardings as CrowdingCost + self.beta * alightings as CrowdingCost).max(0.).round() as Timestamp
+    }
+
+    // Computes the realised (arrival, departure) time of every stop time from the timetabled ones and the agents boarding and alighting there.
+    // Dwelling longer than timetabled delays the rest of the trip. Trips on a route can't overtake, so a delayed trip also holds up the ones behind it.
+    pub fn realised_stop_times(&self, network: &Network, timetable: &[(Timestamp, Timestamp)], boardings: &[PopulationCount], alightings: &[PopulationCount]) -> Vec<(Timestamp, Timestamp)> {
+        let mut realised = timetable.to_vec();
+        for route in network.routes.iter() {
+            let mut previous_trip_range: Option<std::ops::Range<usize>> = None;
+            for trip in 0..route.num_trips as usize {
+                let trip_range = route.get_trip_range(trip);
+                let mut delay: Timestamp = 0;
+                for idx in trip_range.clone() {
+                    let (arrival_time, departure_time) = timetable[idx];
+                    let arrival_delay = delay;
+                    let extra_dwell = self.dwell_time(boardings[idx], alightings[idx]).saturating_sub(departure_time.saturating_sub(arrival_time));
+                    delay = (delay + extra_dwell).min(self.max_delay);
+                    realised[idx] = (arrival_time + arrival_delay, departure_time + delay);
+                }
+                if let Some(previous_trip_range) = previous_trip_range {
+                    for (previous_idx, idx) in previous_trip_range.zip(trip_range.clone()) {
+                        realised[idx].0 = realised[idx].0.max(realised[previous_idx].0);
+                        realised[idx].1 = realised[idx].1.max(realised[previous_idx].1);
+                    }
+                }
+                previous_trip_range = Some(trip_range);
+            }
+        }
+        realised
+    }
+}
+
+// Counts the agents boarding and alighting at each stop time.
+fn count_boardings_and_alightings(network: &Network, agent_journeys: &[AgentJourneyResult]) -> (Vec<PopulationCount>, Vec<PopulationCount>) {
+    let mut boardings = vec![0 as PopulationCount; network.stop_times.len()];
+    let mut alightings = vec![0 as PopulationCount; network.stop_times.len()];
+    for agent_journey in agent_journeys.iter() {
+        let Ok(journey) = &agent_journey.result else {
+            continue;
+        };
+        for leg in journey.legs.iter() {
+            let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+            boardings[trip_start + leg.boarded_stop_order as usize] += agent_journey.count as PopulationCount;
+            alightings[trip_start + leg.arrival_stop_order as usize] += agent_journey.count as PopulationCount;
+        }
+    }
+    (boardings, alightings)
+}
+
+fn set_stop_times(network: &mut Network, stop_times: &[(Timestamp, Timestamp)]) {
+    for (stop_time, &(arrival_time, departure_time)) in network.stop_times.iter_mut().zip(stop_times) {
+        stop_time.arrival_time = arrival_time;
+        stop_time.departure_time = departure_time;
+    }
+}
+
+// This default simulation parameter implementation uses a simple exponential crowding cost function, and can report progress.
+pub struct DefaultSimulationParams<'a> {
+    pub crowding_function: CrowdingFunc,
+    pub overcapacity: Overcapacity,
+    pub progress_callback: Option<Box<SimulationProgressCallback<'a>>>,
+    pub journey_preferences: JourneyPreferences,
+    pub num_rounds: u16,
+    pub bag_size: usize,
+    pub trip_capacities: TripCapacities,
+    pub strict_capacity: bool,
+    pub step_size: StepSize,
+    pub convergence_tolerance: Option<f64>,
+    pub convergence_rounds: u16,
+    pub replanning: Replanning,
+    pub dwell_model: Option<DwellModel>,
+    pub route_choice: Option<RouteChoice>,
+    // Indexed by `SimulationStep::segment`. Empty if every agent uses the journey preferences.
+    pub segments: Vec<SegmentPreferences>,
+    // Set from another thread (e.g. a Ctrl-C handler) to stop the simulation early.
+    pub cancellation: Option<Arc<AtomicBool>>,
+    pub checkpointing: Option<Checkpointing>,
+    pub elasticity: Option<DemandElasticity>,
+    pub warm_start: Option<Arc<WarmStart>>,
+    pub chunk_size: Option<usize>,
+    pub plan_cache: Option<PlanCache>,
+    pub wheelchair_access: Option<Arc<WheelchairAccess>>,
+    pub departure_choice: Option<DepartureChoice>,
+    pub transfer_times: Option<Arc<TransferTimes>>,
+    pub transfer_penalties: TransferPenalties,
+}
+
+// The callback and journey preferences are closures, so are left out.
+// Checkpointing doesn't change the result, so it's left out too (the debug output is hashed to identify the parameters).
+// The number of rounds only changes how many are run, so a run stopped by its round limit can be resumed with a higher one.
+// Neither is the warm start, which only affects the first round, so a warm-started run can be resumed without it.
+// Chunks are merged in step order, so the chunk size doesn't change the result either.
+impl std::fmt::Debug for DefaultSimulationParams<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DefaultSimulationParams")
+         .field("crowding_function", &self.crowding_function)
+         .field("overcapacity", &self.overcapacity)
+         .field("bag_size", &self.bag_size)
+         .field("trip_capacities", &self.trip_capacities)
+         .field("strict_capacity", &self.strict_capacity)
+         .field("step_size", &self.step_size)
+         .field("convergence_tolerance", &self.convergence_tolerance)
+         .field("convergence_rounds", &self.convergence_rounds)
+         .field("replanning", &self.replanning)
+         .field("dwell_model", &self.dwell_model)
+         .field("route_choice", &self.route_choice)
+         .field("segment_crowding_weights", &self.segments.iter().map(|segment| segment.crowding_weight).collect_vec())
+         .field("cancellation", &self.cancellation)
+         .field("elasticity", &self.elasticity)
+         .field("plan_cache", &self.plan_cache)
+         .field("wheelchair_access", &self.wheelchair_access.as_ref().map(|access| access.num_inaccessible()))
+         .field("departure_choice", &self.departure_choice)
+         .field("transfer_times", &self.transfer_times.as_ref().map(|transfer_times| transfer_times.len()))
+         .field("transfer_penalties", &self.transfer_penalties)
+         .finish_non_exhaustive()
+    }
+}
+
+impl SimulationParams for DefaultSimulationParams<'_> {
+    fn cost_fn(&self, trip_id: &str, count: PopulationCount) -> CrowdingCost {
+        let cap = self.trip_capacities.get(trip_id);
+        self.cost_for_load_factor(trip_id, count as f64 / cap.total() as f64)
+    }
+
+    fn cost_for_load_factor(&self, trip_id: &str, load_factor: f64) -> CrowdingCost {
+        self.crowding_function.cost_for_load_factor(self.trip_capacities.get(trip_id), load_factor, self.overcapacity)
+    }
+
+    fn get_journey_preferences(&self) -> &JourneyPreferences {
+        &self.journey_preferences
+    }
+
+    fn get_num_rounds(&self) -> u16 {
+        self.num_rounds
+    }
+
+    fn get_bag_size(&self) -> usize {
+        self.bag_size
+    }
+
+    fn get_step_size(&self, round_number: u16) -> CrowdingCost {
+        self.step_size.get_step_size(round_number)
+    }
+
+    fn get_convergence_tolerance(&self) -> Option<f64> {
+        self.convergence_tolerance
+    }
+
+    fn get_convergence_rounds(&self) -> u16 {
+        self.convergence_rounds
+    }
+
+    fn is_capacity_strict(&self) -> bool {
+        self.strict_capacity
+    }
+
+    fn get_capacity(&self, trip_id: &str) -> PopulationCount {
+        self.trip_capacities.get(trip_id).total()
+    }
+
+    fn get_replan_fraction(&self, round_number: u16) -> CrowdingCost {
+        self.replanning.get_fraction(round_number)
+    }
+
+    fn get_replan_seed(&self) -> u64 {
+        self.replanning.seed
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation.as_ref().is_some_and(|cancelled| cancelled.load(Ordering::Relaxed))
+    }
+
+    fn get_dwell_model(&self) -> Option<&DwellModel> {
+        self.dwell_model.as_ref()
+    }
+
+    fn get_route_choice(&self) -> Option<&RouteChoice> {
+        self.route_choice.as_ref()
+    }
+
+    fn get_segment_journey_preferences(&self, segment: SegmentIndex) -> &JourneyPreferences {
+        self.segments.get(segment as usize).map_or(&self.journey_preferences, |segment| &segment.journey_preferences)
+    }
+
+    fn get_segment_crowding_weight(&self, segment: SegmentIndex) -> Option<CrowdingCost> {
+        self.segments.get(segment as usize).map(|segment| segment.crowding_weight)
+    }
+
+    fn get_progress_callback(&self) -> Option<&SimulationProgressCallback> {
+        self.progress_callback.as_ref().map(|f| f.as_ref())
+    }
+
+    fn get_checkpointing(&self) -> Option<&Checkpointing> {
+        self.checkpointing.as_ref()
+    }
+
+    fn get_elasticity(&self) -> Option<&DemandElasticity> {
+        self.elasticity.as_ref()
+    }
+
+    fn get_warm_start(&self) -> Option<&WarmStart> {
+        self.warm_start.as_deref()
+    }
+
+    fn get_chunk_size(&self) -> Option<usize> {
+        self.chunk_size
+    }
+
+    fn get_plan_cache(&self) -> Option<&PlanCache> {
+        self.plan_cache.as_ref()
+    }
+
+    fn get_wheelchair_access(&self) -> Option<&WheelchairAccess> {
+        self.wheelchair_access.as_deref()
+    }
+
+    fn get_departure_choice(&self) -> Option<&DepartureChoice> {
+        self.departure_choice.as_ref()
+    }
+
+    fn get_transfer_times(&self) -> Option<&TransferTimes> {
+        self.transfer_times.as_deref()
+    }
+
+    fn get_transfer_penalties(&self) -> TransferPenalties {
+        self.transfer_penalties
+    }
+}
+
+#[derive(Debug)]
+pub struct SimulationStep {
+    pub departure_time: Timestamp,
+    pub origin_stop: StopIndex,
+    // Population segment of every agent in this step (see `assign_segments`).
+    pub segment: SegmentIndex,
+    // Every agent in this step only uses wheelchair-accessible trips and stops.
+    pub requires_accessible: bool,
+    dest_stops: Vec<StopIndex>,
+    // Agents travelling to each destination. They travel (and board) together, so the whole count is on the same journey.
+    counts: Vec<AgentCount>,
+    // Size of the parties (e.g. school groups) the agents to each destination are in, so each count is a multiple of it.
+    // Parties are never split, while agents in parties of one can be split between segments or suppressed separately.
+    party_sizes: Vec<AgentCount>,
+}
+
+impl SimulationStep {
+    pub fn new(departure_time: Timestamp, origin_stop: StopIndex) -> Self {
+        Self {
+            departure_time,
+            origin_stop,
+            segment: 0,
+            requires_accessible: false,
+            dest_stops: Vec::new(),
+            counts: Vec::new(),
+            party_sizes: Vec::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.dest_stops.len()
+    }
+    pub fn count(&self) -> AgentCount {
+        self.counts.iter().sum()
+    }
+    pub fn destinations(&self) -> impl Iterator<Item=(StopIndex, AgentCount)> + '_ {
+        self.dest_stops.iter().copied().zip(self.counts.iter().copied())
+    }
+    // Sizes of the parties the agents to each destination are in, in the same order as `destinations`.
+    pub fn party_sizes(&self) -> &[AgentCount] {
+        &self.party_sizes
+    }
+    pub fn push(&mut self, dest_stop: StopIndex, count: AgentCount) {
+        self.push_parties(dest_stop, 1, count);
+    }
+    // Adds `num_parties` parties of `party_size` agents each, which travel together to the destination.
+    pub fn push_parties(&mut self, dest_stop: StopIndex, party_size: AgentCount, num_parties: AgentCount) {
+        debug_assert!(party_size > 0, "Parties must have at least one agent");
+        self.dest_stops.push(dest_stop);
+        self.counts.push(party_size * num_parties);
+        self.party_sizes.push(party_size);
+    }
+    // Drops the destinations (and their agents) `keep` returns false for.
+    pub fn retain_destinations(&mut self, mut keep: impl FnMut(StopIndex) -> bool) {
+        (self.dest_stops, self.counts, self.party_sizes) = izip!(&self.dest_stops, &self.counts, &self.party_sizes)
+            .filter(|(&dest_stop, _, _)| keep(dest_stop))
+            .map(|(&dest_stop, &count, &party_size)| (dest_stop, count, party_size))
+            .multiunzip();
+    }
+}
+
+#[derive(Clone)]
+pub struct AgentJourney {
+    pub origin_trip: GlobalTripIndex,
+    pub dest_trip: GlobalTripIndex,
+    pub duration: Timestamp,
+    // Crowding cost the journey was planned with, from the previous round's loads.
+    pub crowding_cost: CrowdingCost,
+    // Crowding cost of the legs travelled under the loads averaged up to and including this round,
+    // so in the final round it's the crowding experienced with the final loads.
+    pub experienced_crowding_cost: CrowdingCost,
+    // Time spent on board.
+    pub in_vehicle_time: Timestamp,
+    // Time spent waiting at the origin and between legs, including walking between platforms.
+    pub wait_time: Timestamp,
+    pub num_transfers: u8,
+    pub legs: Vec<Leg>,
+}
+
+impl AgentJourney {
+    // Sets the in-vehicle and wait times from the legs and duration.
+    fn set_times(&mut self) {
+        self.in_vehicle_time = self.legs.iter().map(|leg| leg.arrival_time - leg.boarded_time).sum();
+        self.wait_time = self.duration.saturating_sub(self.in_vehicle_time);
+    }
+}
+
+// Sum of the crowding cost of the trip segments travelled on a leg.
+fn leg_crowding_cost(network: &Network, crowding_cost: &[CrowdingCost], leg: &Leg) -> CrowdingCost {
+    let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+    crowding_cost[(trip_start + leg.boarded_stop_order as usize + 1)..=(trip_start + leg.arrival_stop_order as usize)].iter().sum()
+}
+
+pub struct AgentJourneyResult {
+    pub sim_step_idx: u32,
+    pub journey_idx: u32,
+    pub origin_stop: StopIndex,
+    pub dest_stop: StopIndex,
+    pub start_time: Timestamp,
+    pub count: AgentCount,
+    pub segment: SegmentIndex,
+    pub result: Result<AgentJourney, JourneyError>,
+}
+
+impl AgentJourneyResult {
+    // Copies this result into the next round for an agent that keeps its plan.
+    fn keep_plan(&self) -> Self {
+        Self {
+            result: match &self.result {
+                Ok(journey) => Ok(journey.clone()),
+                Err(JourneyError::ZeroAgents) => Err(JourneyError::ZeroAgents),
+                Err(JourneyError::NoJourneyFound) => Err(JourneyError::NoJourneyFound),
+                Err(JourneyError::InfiniteLoop) => Err(JourneyError::InfiniteLoop),
+            },
+            ..*self
+        }
+    }
+
+    // A hash of the trips ridden leg by leg and the stops they're boarded and left at, so an agent's plans can be compared
+    // cheaply between rounds. Every failed journey has the same fingerprint.
+    fn plan_fingerprint(&self) -> u32 {
+        let Ok(journey) = &self.result else {
+            return 0;
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        for leg in journey.legs.iter() {
+            for word in [leg.trip.route_idx as u32, leg.trip.trip_order as u32, leg.boarded_stop_order as u32, leg.arrival_stop_order as u32] {
+                hasher.update(&word.to_le_bytes());
+            }
+        }
+        // Keeps a journey with no legs apart from a failed one.
+        hasher.finalize() | 1
+    }
+}
+
+// An agent's journey without its legs, as stored in a JourneyTable.
+#[derive(Clone, Copy)]
+struct JourneySummary {
+    origin_trip: GlobalTripIndex,
+    dest_trip: GlobalTripIndex,
+    duration: Timestamp,
+    crowding_cost: CrowdingCost,
+    experienced_crowding_cost: CrowdingCost,
+    in_vehicle_time: Timestamp,
+    wait_time: Timestamp,
+    num_transfers: u8,
+}
+
+struct AgentRecord {
+    sim_step_idx: u32,
+    journey_idx: u32,
+    origin_stop: StopIndex,
+    dest_stop: StopIndex,
+    start_time: Timestamp,
+    count: AgentCount,
+    segment: SegmentIndex,
+    result: Result<JourneySummary, JourneyError>,
+}
+
+// A finished round's agent journeys. The legs of every agent are stored in one array (and the rest of each journey in another)
+// rather than a Vec of legs per agent, so a million agents are a few large allocations instead of a million small ones.
+// The journeys are read through AgentJourneyRef views, in the same order as the round's AgentJourneyResults.
+#[derive(Default)]
+pub struct JourneyTable {
+    agents: Vec<AgentRecord>,
+    legs: Vec<Leg>,
+    // Agent i's legs are legs[leg_offsets[i]..leg_offsets[i + 1]].
+    leg_offsets: Vec<usize>,
+}
+
+// A view of an agent's journey in a JourneyTable, with the same fields as AgentJourneyResult.
+#[derive(Clone, Copy)]
+pub struct AgentJourneyRef<'a> {
+    pub sim_step_idx: u32,
+    pub journey_idx: u32,
+    pub origin_stop: StopIndex,
+    pub dest_stop: StopIndex,
+    pub start_time: Timestamp,
+    pub count: AgentCount,
+    pub segment: SegmentIndex,
+    pub result: Result<JourneyRef<'a>, &'a JourneyError>,
+}
+
+// A view of an AgentJourney in a JourneyTable.
+#[derive(Clone, Copy)]
+pub struct JourneyRef<'a> {
+    pub origin_trip: GlobalTripIndex,
+    pub dest_trip: GlobalTripIndex,
+    pub duration: Timestamp,
+    pub crowding_cost: CrowdingCost,
+    pub experienced_crowding_cost: CrowdingCost,
+    pub in_vehicle_time: Timestamp,
+    pub wait_time: Timestamp,
+    pub num_transfers: u8,
+    pub legs: &'a [Leg],
+}
+
+impl JourneyTable {
+    pub fn from_journeys(agent_journeys: Vec<AgentJourneyResult>) -> Self {
+        let num_legs = agent_journeys.iter().map(|agent_journey| agent_journey.result.as_ref().map_or(0, |journey| journey.legs.len())).sum();
+        let mut table = Self {
+            agents: Vec::with_capacity(agent_journeys.len()),
+            legs: Vec::with_capacity(num_legs),
+            leg_offsets: Vec::with_capacity(agent_journeys.len() + 1),
+        };
+        table.leg_offsets.push(0);
+        for agent_journey in agent_journeys {
+            let result = agent_journey.result.map(|journey| {
+                table.legs.extend(journey.legs);
+                JourneySummary {
+                    origin_trip: journey.origin_trip,
+                    dest_trip: journey.dest_trip,
+                    duration: journey.duration,
+                    crowding_cost: journey.crowding_cost,
+                    experienced_crowding_cost: journey.experienced_crowding_cost,
+                    in_vehicle_time: journey.in_vehicle_time,
+                    wait_time: journey.wait_time,
+                    num_transfers: journey.num_transfers,
+                }
+            });
+            table.leg_offsets.push(table.legs.len());
+            table.agents.push(AgentRecord {
+                sim_step_idx: agent_journey.sim_step_idx,
+                journey_idx: agent_journey.journey_idx,
+                origin_stop: agent_journey.origin_stop,
+                dest_stop: agent_journey.dest_stop,
+                start_time: agent_journey.start_time,
+                count: agent_journey.count,
+                segment: agent_journey.segment,
+                result,
+            });
+        }
+        table
+    }
+
+    pub fn len(&self) -> usize {
+        self.agents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.agents.is_empty()
+    }
+
+    pub fn get(&self, agent_idx: usize) -> AgentJourneyRef<'_> {
+        let agent = &self.agents[agent_idx];
+        let legs = &self.legs[self.leg_offsets[agent_idx]..self.leg_offsets[agent_idx + 1]];
+        AgentJourneyRef {
+            sim_step_idx: agent.sim_step_idx,
+            journey_idx: agent.journey_idx,
+            origin_stop: agent.origin_stop,
+            dest_stop: agent.dest_stop,
+            start_time: agent.start_time,
+            count: agent.count,
+            segment: agent.segment,
+            result: agent.result.as_ref().map(|journey| JourneyRef {
+                origin_trip: journey.origin_trip,
+                dest_trip: journey.dest_trip,
+                duration: journey.duration,
+                crowding_cost: journey.crowding_cost,
+                experienced_crowding_cost: journey.experienced_crowding_cost,
+                in_vehicle_time: journey.in_vehicle_time,
+                wait_time: journey.wait_time,
+                num_transfers: journey.num_transfers,
+                legs,
+            }),
+        }
+    }
+
+    pub fn iter(&self) -> JourneyTableIter<'_> {
+        JourneyTableIter { table: self, agent_idx: 0 }
+    }
+
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = AgentJourneyRef<'_>> {
+        (0..self.len()).into_par_iter().map(|agent_idx| self.get(agent_idx))
+    }
+
+    // Heap memory held by the table in bytes.
+    pub fn memory_bytes(&self) -> usize {
+        self.agents.capacity() * std::mem::size_of::<AgentRecord>() + self.legs.capacity() * std::mem::size_of::<Leg>() + self.leg_offsets.capacity() * std::mem::size_of::<usize>()
+    }
+}
+
+pub struct JourneyTableIter<'a> {
+    table: &'a JourneyTable,
+    agent_idx: usize,
+}
+
+impl<'a> Iterator for JourneyTableIter<'a> {
+    type Item = AgentJourneyRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.agent_idx >= self.table.len() {
+            return None;
+        }
+        self.agent_idx += 1;
+        Some(self.table.get(self.agent_idx - 1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.table.len() - self.agent_idx;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for JourneyTableIter<'_> {}
+
+impl<'a> IntoIterator for &'a JourneyTable {
+    type Item = AgentJourneyRef<'a>;
+    type IntoIter = JourneyTableIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// An agent that could not board a trip because it was at capacity.
+#[derive(Clone, Copy)]
+pub struct DeniedBoarding {
+    // Index into the round's agent journeys.
+    pub agent_idx: u32,
+    pub stop: StopIndex,
+    pub trip: GlobalTripIndex,
+    pub time: Timestamp,
+    pub count: AgentCount,
+}
+
+#[derive(Clone, Copy)]
+pub struct CapacityDelay {
+    // Index into the round's agent journeys.
+    pub agent_idx: u32,
+    pub num_denied: u32,
+    // Extra time taken to reach the destination compared to the journey originally planned.
+    pub delay: Timestamp,
+    // No journey with room could be found after being denied boarding, so the agent never arrives.
+    pub stranded: bool,
+}
+
+// Results of enforcing strict capacity on a round.
+#[derive(Default)]
+pub struct CapacityReport {
+    pub denied_boardings: Vec<DeniedBoarding>,
+    pub delays: Vec<CapacityDelay>,
+}
+
+impl CapacityReport {
+    pub fn num_stranded(&self) -> usize {
+        self.delays.iter().filter(|delay| delay.stranded).count()
+    }
+}
+
+pub struct SimulationRoundResult {
+    pub population_count: Vec<PopulationCount>,
+    pub crowding_cost: Vec<CrowdingCost>,
+    pub agent_journeys: Vec<AgentJourneyResult>,
+    pub capacity_report: Option<CapacityReport>,
+    // Number of agents that recomputed their journey this round.
+    pub num_replanned: usize,
+    // Number of agents due to replan that kept their journey from the plan cache instead.
+    pub num_plan_cache_hits: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IterationStats {
+    pub round_number: u16,
+    // Weight given to this round's loads when averaging with the previous rounds.
+    pub step_size: CrowdingCost,
+    // Total crowding cost over all segments, using the averaged loads.
+    pub total_crowding_cost: f64,
+    // Relative change in total crowding cost from the previous round. This is what convergence is tested on.
+    pub relative_change: Option<f64>,
+    // Total absolute change in the averaged segment loads, relative to the previous round's total load.
+    pub relative_load_gap: Option<f64>,
+    // Time spent travelling by the agents in this round.
+    pub total_passenger_hours: f64,
+    // Highest averaged load on any trip segment.
+    pub max_segment_load: PopulationCount,
+    // Number of agents that recomputed their journey this round.
+    pub num_replanned: usize,
+    // Number of agents due to replan that kept their journey from the plan cache instead.
+    pub num_plan_cache_hits: usize,
+    // Number of agents whose route differs from the previous round.
+    pub num_changed_route: Option<usize>,
+    // Crowding cost saved by the agents that changed route, under the loads they replanned with.
+    pub switching_cost_improvement: Option<f64>,
+}
+
+// How much the agents between an OD pair switched plans between rounds, over the rounds run (since resuming, if resumed).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlanSwitching {
+    // Agents that changed route, summed over the rounds.
+    pub num_switched: u64,
+    // Crowding cost saved by switching, summed over the agents and rounds.
+    pub cost_improvement: f64,
+}
+
+pub struct SimulationResult {
+    pub population_count: Vec<PopulationCount>,
+    pub round_agent_journeys: Vec<JourneyTable>,
+    // Denied boardings in the final round, if capacity is strict.
+    pub capacity_report: Option<CapacityReport>,
+    // One entry per round that was run.
+    pub iteration_history: Vec<IterationStats>,
+    // The simulation was cancelled, so the results are partial.
+    pub cancelled: bool,
+    // Realised (arrival, departure) time of each stop time after dwell delays, if a dwell model was used.
+    // The network keeps the timetabled times.
+    pub realised_stop_times: Option<Vec<(Timestamp, Timestamp)>>,
+    // Agents of each journey of the final round (in the same order) that didn't travel, if demand is elastic.
+    pub suppressed_counts: Option<Vec<AgentCount>>,
+    // Keyed by (origin stop, destination stop).
+    pub plan_switching: HashMap<(StopIndex, StopIndex), PlanSwitching>,
+}
+
+// Summarises the result rather than printing every journey.
+impl std::fmt::Debug for SimulationResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimulationResult")
+         .field("num_segments", &self.population_count.len())
+         .field("num_rounds", &self.round_agent_journeys.len())
+         .field("num_agent_journeys", &self.round_agent_journeys.last().map_or(0, |journeys| journeys.len()))
+         .field("num_denied_boardings", &self.capacity_report.as_ref().map(|report| report.denied_boardings.len()))
+         .field("iteration_history", &self.iteration_history)
+         .field("cancelled", &self.cancelled)
+         .field("has_realised_stop_times", &self.realised_stop_times.is_some())
+         .field("num_suppressed", &self.suppressed_counts.as_ref().map(|counts| counts.iter().map(|&count| count as u64).sum::<u64>()))
+         .field("num_switching_od_pairs", &self.plan_switching.len())
+         .finish()
+    }
+}
+
+impl SimulationResult {
+    // Heap memory held by the agent journeys of every round in bytes.
+    pub fn journeys_memory_bytes(&self) -> usize {
+        self.round_agent_journeys.iter().map(JourneyTable::memory_bytes).sum()
+    }
+
+    pub fn print_stats(&self) {
+        log::info!("Rounds: {}", self.round_agent_journeys.len());
+        log::info!("Agent journeys: {}", self.round_agent_journeys.last().map(|v| v.len()).unwrap_or(0));
+    }
+}
+
+// Runs a multi-criteria raptor query with a bag size chosen at runtime.
+macro_rules! mc_raptor_query {
+    ($bag_size:expr, $($arg:expr),+ $(,)?) => {
+        match $bag_size {
+            // TODO: Implement bag size 1 with normal raptor (extend to multi-dest).
+            1 => raptor::mc_raptor_query::<1>($($arg),+),
+            2 => raptor::mc_raptor_query::<2>($($arg),+),
+            3 => raptor::mc_raptor_query::<3>($($arg),+),
+            4 => raptor::mc_raptor_query::<4>($($arg),+),
+            5 => raptor::mc_raptor_query::<5>($($arg),+),
+            _ => unreachable!(),
+        }
+    };
+}
+
+// Distribution of the party size of randomly generated agents, from the weight of parties of 1, 2, 3, ... agents.
+#[derive(Clone, Debug)]
+pub struct PartySizes {
+    weights: Vec<f64>,
+}
+
+impl PartySizes {
+    pub fn new(weights: Vec<f64>) -> Result<Self, String> {
+        if weights.iter().any(|weight| !weight.is_finite() || *weight < 0.) || !weights.iter().any(|&weight| weight > 0.) {
+            return Err(format!("party size weights {weights:?} must be non-negative, with at least one positive"));
+        }
+        Ok(Self { weights })
+    }
+
+    pub fn sample(&self, rng: &mut impl Rng) -> AgentCount {
+        let mut choice = rng.gen::<f64>() * self.weights.iter().sum::<f64>();
+        let size_idx = self.weights.iter().position(|&weight| {
+            choice -= weight;
+            choice < 0.
+        }).unwrap_or_else(|| self.weights.iter().rposition(|&weight| weight > 0.).unwrap());
+        size_idx as AgentCount + 1
+    }
+
+    // Adds one party of a sampled size, or 1 to 10 agents in parties of one without a distribution, like gen_simulation_steps.
+    pub fn push_agents(party_sizes: Option<&Self>, simulation_step: &mut SimulationStep, dest_stop: StopIndex, rng: &mut impl Rng) {
+        match party_sizes {
+            Some(party_sizes) => simulation_step.push_parties(dest_stop, party_sizes.sample(rng), 1),
+            None => simulation_step.push(dest_stop, rng.gen_range(1..=10)),
+        }
+    }
+}
+
+pub fn gen_simulation_steps(network: &Network, number: Option<usize>, seed: Option<u64>, party_sizes: Option<&PartySizes>) -> Vec<SimulationStep> {
+    let num_stops = network.num_stops() as StopIndex;
+    let mut rng = match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    };
+
+    // New agent journey every second.
+    let sim_start_time = 4 * 60 * 60; // Start at 4am.
+    // Final journey begins at midnight, or at the last departure if trips run after midnight (GTFS times past 24:00:00).
+    let sim_end_time = network.stop_times.iter().map(|stop_time| stop_time.departure_time).max().unwrap_or(0).max(24 * 60 * 60);
+    let sim_length = sim_end_time - sim_start_time;
+    let number = number.unwrap_or(sim_length as usize);
+    let interval = sim_length as f64 / number as f64;
+    let mut simulation_steps = Vec::with_capacity(number);
+    for i in 0..number {
+        let start_time = sim_start_time + (i as f64 * interval) as Timestamp;
+        let mut simulation_step = SimulationStep::new(start_time, rng.gen_range(0..num_stops));
+        let dest_stop = rng.gen_range(0..num_stops);
+        PartySizes::push_agents(party_sizes, &mut simulation_step, dest_stop, &mut rng);
+        simulation_steps.push(simulation_step);
+    }
+    simulation_steps
+}
+
+// Deterministically decides whether a simulation step replans in the given round.
+fn is_replanning(seed: u64, round_number: u16, sim_step_idx: usize, fraction: CrowdingCost) -> bool {
+    if fraction >= 1. {
+        return true;
+    }
+    let mut rng = SmallRng::seed_from_u64(seed ^ ((round_number as u64) << 48) ^ sim_step_idx as u64);
+    rng.gen::<CrowdingCost>() < fraction
+}
+
+// Records the agents boarding and alighting along a leg as (stop time index, count) pairs, which are added up and then
+// summed along each trip (see trip_loads).
+fn add_leg_to_population(network: &Network, loads: &mut (Vec<(usize, PopulationCount)>, Vec<(usize, PopulationCount)>), leg: &Leg, count: PopulationCount) {
+    let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+    let (boardings, alightings) = loads;
+
+    boardings.push((trip_start + leg.boarded_stop_order as usize, count));
+    // Alighting at the arrival stop, so it's an inclusive-exclusive range.
+    alightings.push((trip_start + leg.arrival_stop_order as usize, count));
+}
+
+// The agents on board departing each stop time, from those boarding and alighting at each. An agent alights after it
+// boards, so the load never goes below zero.
+fn trip_loads(network: &Network, boardings: &[PopulationCount], alightings: &[PopulationCount]) -> Vec<PopulationCount> {
+    let mut loads = vec![0 as PopulationCount; network.stop_times.len()];
+    for route in network.routes.iter() {
+        for trip in 0..route.num_trips as usize {
+            let mut load: PopulationCount = 0;
+            for i in route.get_trip_range(trip) {
+                load = load + boardings[i] - alightings[i];
+                loads[i] = load;
+            }
+        }
+    }
+    loads
+}
+
+// Crowding cost given to segments journeys can't use (e.g. full with strict capacity), so re-planned journeys avoid them.
+const BLOCKED_SEGMENT_COST: PathfindingCost = 1e9;
+// After this many re-plans to avoid inaccessible stops or transfers that are too quick, an agent has no journey.
+const MAX_BLOCKED_REPLANS: usize = 8;
+
+// Enough steps per chunk to amortise scheduling them, but enough chunks (about eight per thread) to balance threads whose
+// steps have long journeys. Queries on a small network are quick, so its chunks need more steps to be worth scheduling.
+fn default_chunk_size(network: &Network, num_steps: usize) -> usize {
+    let balanced_size = num_steps.div_ceil(rayon::current_num_threads() * 8);
+    let min_size = (100_000 / network.stop_times.len().max(1)).clamp(1, 64);
+    balanced_size.min(256).max(min_size)
+}
+
+fn run_simulation_round(network: &Network,
+                        simulation_steps: &[SimulationStep],
+                        params: &impl SimulationParams,
+                        crowding_cost: Option<&[CrowdingCost]>,
+                        previous_journeys: Option<&[AgentJourneyResult]>,
+                        demand_counts: Option<&[AgentCount]>,
+                        round_number: u16) -> SimulationRoundResult {
+    let mut zero_crowding_cost = Vec::new();
+
+    let crowding_cost = crowding_cost.unwrap_or_else(|| {
+        zero_crowding_cost = vec![0 as CrowdingCost; network.stop_times.len()];
+        &zero_crowding_cost
+    });
+
+    assert_eq!(network.stop_times.len(), crowding_cost.len());
+    let planner_crowding_cost = planner_costs(crowding_cost);
+    // Agents needing wheelchair access plan with the inaccessible trips blocked.
+    let wheelchair_access = params.get_wheelchair_access().filter(|_| simulation_steps.iter().any(|sim_step| sim_step.requires_accessible));
+    let accessible_crowding_cost = wheelchair_access.map(|access| access.block_inaccessible_trips(network, &planner_crowding_cost, BLOCKED_SEGMENT_COST));
+    let transfer_times = params.get_transfer_times();
+
+    let num_agents = simulation_steps.iter().fold(0, |acc, step| acc + step.len());
+
+    // Offset of the first journey of each simulation step in the previous round's journeys.
+    let journey_offsets = simulation_steps.iter().scan(0, |offset, sim_step| {
+        let step_offset = *offset;
+        *offset += sim_step.len();
+        Some(step_offset)
+    }).collect::<Vec<_>>();
+    // The number of agents travelling to each destination of a step, which elastic demand can reduce from the step's counts.
+    let step_counts = izip!(simulation_steps, &journey_offsets).map(|(sim_step, &offset)| {
+        demand_counts.map_or(&sim_step.counts[..], |counts| &counts[offset..offset + sim_step.len()])
+    }).collect::<Vec<_>>();
+
+    // Choose which simulation steps replan this round. The others keep their journeys from the previous round,
+    // unless the number of agents travelling changed.
+    let replan_fraction = params.get_replan_fraction(round_number);
+    let replan_seed = params.get_replan_seed();
+    // Steps due to replan can keep their previous plans from the plan cache instead.
+    let plan_cache = params.get_plan_cache().filter(|plan_cache| !plan_cache.is_full_replan(round_number));
+    let (replan, plan_cache_hit): (Vec<bool>, Vec<bool>) = (0..simulation_steps.len()).into_par_iter().map(|sim_step_idx| {
+        let Some(previous_journeys) = previous_journeys else {
+            return (true, false);
+        };
+        let offset = journey_offsets[sim_step_idx];
+        let previous_step_journeys = &previous_journeys[offset..offset + simulation_steps[sim_step_idx].len()];
+        if previous_step_journeys.iter().map(|previous| previous.count).ne(step_counts[sim_step_idx].iter().copied()) {
+            return (true, false);
+        }
+        if !is_replanning(replan_seed, round_number, sim_step_idx, replan_fraction) {
+            return (false, false);
+        }
+        // Agents without a journey didn't find one because of the timetable, not crowding, so they stay cached.
+        let cached = plan_cache.is_some_and(|plan_cache| previous_step_journeys.iter().all(|previous| match &previous.result {
+            Ok(journey) => !plan_cache.is_stale(journey.crowding_cost, journey.legs.iter().map(|leg| leg_crowding_cost(network, crowding_cost, leg)).sum()),
+            Err(_) => true,
+        }));
+        (!cached, cached)
+    }).unzip();
+    let num_agents_in = |steps: &[bool]| -> usize {
+        steps.iter()
+             .positions(|&step| step)
+             .map(|sim_step_idx| step_counts[sim_step_idx].iter().sum::<AgentCount>() as usize)
+             .sum()
+    };
+    let num_replanned = num_agents_in(&replan);
+    let num_plan_cache_hits = num_agents_in(&plan_cache_hit);
+
+    // Steps are planned in chunks, each adding up its own loads, so threads don't contend over the counts of busy segments.
+    let chunk_size = params.get_chunk_size().unwrap_or_else(|| default_chunk_size(network, simulation_steps.len()));
+    let chunk_iterator = simulation_steps.par_chunks(chunk_size);
+
+    #[cfg(feature = "progress_bar")]
+    let chunk_iterator = {
+        par_tqdm!(
+            chunk_iterator,
+            desc = " Simulation Chunks", 
+            position = round_number + 1,
+            animation = kdam::Animation::FillUp
+        )
+    };
+
+    // Use a bag size of 1 for the first round, because there's no crowding data yet.
+    let bag_size = if round_number == 0 { 1 } else { params.get_bag_size().clamp(2, 5) };
+
+    // Plans a simulation step, adding its journeys and the changes in load along their legs to its chunk's.
+    let plan_step = |sim_step_idx: usize, sim_step: &SimulationStep, chunk_journeys: &mut Vec<AgentJourneyResult>, chunk_loads: &mut (Vec<(usize, PopulationCount)>, Vec<(usize, PopulationCount)>)| {
+        params.run_progress_callback();
+
+        if let (false, Some(previous_journeys)) = (replan[sim_step_idx], previous_journeys) {
+            // Keep the previous plan, adding it to this round's counts.
+            let offset = journey_offsets[sim_step_idx];
+            chunk_journeys.extend(previous_journeys[offset..offset + sim_step.len()].iter().map(|previous| {
+                if let Ok(journey) = &previous.result {
+                    for leg in journey.legs.iter() {
+                        add_leg_to_population(network, chunk_loads, leg, previous.count as PopulationCount);
+                    }
+                }
+                previous.keep_plan()
+            }));
+            return;
+        }
 
-            // Bind to reference so we can use in the move closure.
-            let trip_stops_pop = &trip_stops_pop;
-            Either::Right(
-                izip!(0..journeys.len() as u32, journeys.into_iter(), &sim_step.counts, &sim_step.dest_stops)
-                    .map(move |(journey_idx, journey, &count, &dest_stop)| {
-                        if count == 0 {
-                            // Ignore zero-count agents.
-                            return AgentJourneyResult {
-                                sim_step_idx,
-                                journey_idx,
-                                origin_stop: sim_step.origin_stop,
-                                dest_stop,
-                                start_time: sim_step.departure_time,
-                                count,
-                                result: Err(JourneyError::ZeroAgents),
-                            };
+        let route_choice = params.get_route_choice().map(|route_choice| {
+            params.get_segment_crowding_weight(sim_step.segment).map_or(*route_choice, |crowding_weight| route_choice.for_segment(crowding_weight))
+        });
+        let step_journey_preferences;
+        let journey_preferences = match &route_choice {
+            Some(route_choice) => {
+                step_journey_preferences = route_choice.journey_preferences(round_number, sim_step_idx);
+                &step_journey_preferences
+            }
+            None => params.get_segment_journey_preferences(sim_step.segment),
+        };
+        // Pricing transfers or waiting compares each journey with alternatives, by the route choice cost with the same
+        // noise, or otherwise the journey preferences' cost with the transfer penalties.
+        let step_seed = route_choice.map_or(0, |route_choice| route_choice.step_seed(round_number, sim_step_idx));
+        let transfer_penalties = params.get_transfer_penalties();
+        let crowding_weight = params.get_segment_crowding_weight(sim_step.segment).unwrap_or(transfer_penalties.crowding_weight);
+        let penalises_transfers = route_choice.map_or(!transfer_penalties.is_zero(), |route_choice| route_choice.penalises_transfers());
+        let penalised_cost = |start_time: Timestamp, duration: Timestamp, crowding_cost: PathfindingCost, legs: &[Leg]| match &route_choice {
+            Some(route_choice) => route_choice.journey_cost(step_seed, start_time, duration, crowding_cost, legs),
+            None => transfer_penalties.journey_cost(crowding_weight, duration, crowding_cost, legs),
+        };
+
+        let counts = step_counts[sim_step_idx];
+        // Departure time choice starts from where the step departed last round.
+        let departure_choice = params.get_departure_choice();
+        let previous_departure = match (departure_choice, previous_journeys) {
+            (Some(_), Some(previous_journeys)) => previous_journeys[journey_offsets[sim_step_idx]].start_time,
+            _ => sim_step.departure_time,
+        };
+        let departure_choice = departure_choice.filter(|departure_choice| departure_choice.is_considering(round_number, sim_step_idx));
+        let sim_step_idx = sim_step_idx as u32;
+        // TODO: This doesn't account for when there are zero agents for one of the destinations.
+        if counts.iter().all(|&count| count == 0) || params.is_cancelled() {
+            // Ignore zero-count agents, and skip the remaining agents once cancelled.
+            chunk_journeys.extend((0..sim_step.dest_stops.len() as u32).map(|journey_idx| {
+                AgentJourneyResult {
+                    sim_step_idx,
+                    journey_idx,
+                    origin_stop: sim_step.origin_stop,
+                    dest_stop: sim_step.dest_stops[journey_idx as usize],
+                    start_time: previous_departure,
+                    count: 0,
+                    segment: sim_step.segment,
+                    result: Err(JourneyError::ZeroAgents),
+                }
+            }));
+            return;
+        }
+
+        let step_crowding_cost: &[PathfindingCost] = match (&accessible_crowding_cost, sim_step.requires_accessible) {
+            (Some(accessible_crowding_cost), true) => accessible_crowding_cost,
+            _ => &planner_crowding_cost,
+        };
+        // Plans the step's journeys departing at `departure_time`, also returning the duration and crowding cost of each.
+        let plan_at = |departure_time: Timestamp| {
+            let mut journeys = mc_raptor_query!(bag_size,
+                                                network,
+                                                sim_step.origin_stop,
+                                                departure_time,
+                                                &sim_step.dest_stops,
+                                                step_crowding_cost,
+                                                &journey_preferences);
+
+            let step_wheelchair_access = wheelchair_access.filter(|_| sim_step.requires_accessible);
+            // Blocked trips are only avoided if there's another way, so whatever is left is checked.
+            let is_allowed = |legs: &[Leg]| {
+                step_wheelchair_access.map_or(true, |access| access.is_journey_accessible(legs))
+                    && transfer_times.map_or(true, |transfer_times| transfer_times.missed_transfer_stop_time(network, legs).is_none())
+            };
+            let mut blocked_cost = None;
+            if step_wheelchair_access.is_some() || transfer_times.is_some() {
+                // The planner can't forbid boarding or alighting at a stop, so a journey that does at an inaccessible stop is
+                // planned again with that trip segment blocked, as strict capacity does for full trips. This also blocks riding
+                // through the stop on that trip, so replanning can miss a journey that only passes through. A transfer
+                // that's quicker than its stops allow is avoided the same way, blocking the trip it changes to.
+                let blocked_stop_time = |legs: &[Leg]| {
+                    step_wheelchair_access.and_then(|access| access.inaccessible_stop_time(network, legs))
+                                          .or_else(|| transfer_times.and_then(|transfer_times| transfer_times.missed_transfer_stop_time(network, legs)))
+                };
+                for (journey, &dest_stop) in journeys.iter_mut().zip(&sim_step.dest_stops) {
+                    for _ in 0..MAX_BLOCKED_REPLANS {
+                        let Some(stop_time) = journey.as_ref().ok().and_then(|journey| blocked_stop_time(&journey.legs)) else {
+                            break;
+                        };
+                        let blocked_cost = blocked_cost.get_or_insert_with(|| step_crowding_cost.to_vec());
+                        blocked_cost[stop_time] = BLOCKED_SEGMENT_COST;
+                        *journey = mc_raptor_query!(bag_size,
+                                                    network,
+                                                    sim_step.origin_stop,
+                                                    departure_time,
+                                                    &vec![dest_stop],
+                                                    blocked_cost,
+                                                    &journey_preferences).pop().unwrap_or(Err(JourneyError::NoJourneyFound));
+                    }
+                    if journey.as_ref().is_ok_and(|journey| !is_allowed(&journey.legs)) {
+                        *journey = Err(JourneyError::NoJourneyFound);
+                    }
+                }
+            }
+
+            if penalises_transfers {
+                // Each journey is compared with those planned with each of its legs blocked in turn (from the segment it
+                // departs its boarding stop on), and the agent takes the one with the lowest cost including its transfers
+                // and waiting. Only one leg is blocked at a time, so this can miss journeys that would avoid several.
+                let mut alternative_cost = blocked_cost.unwrap_or_else(|| step_crowding_cost.to_vec());
+                for (journey, &dest_stop) in journeys.iter_mut().zip(&sim_step.dest_stops) {
+                    let Some(planned) = journey.as_ref().ok().filter(|journey| !journey.legs.is_empty()) else {
+                        continue;
+                    };
+                    let mut best_cost = penalised_cost(departure_time, planned.duration, planned.cost, &planned.legs);
+                    let mut best_alternative = None;
+                    for leg in planned.legs.iter() {
+                        let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+                        let stop_time = trip_start + leg.boarded_stop_order as usize + 1;
+                        let unblocked_cost = std::mem::replace(&mut alternative_cost[stop_time], BLOCKED_SEGMENT_COST);
+                        let alternative = mc_raptor_query!(bag_size,
+                                                           network,
+                                                           sim_step.origin_stop,
+                                                           departure_time,
+                                                           &vec![dest_stop],
+                                                           &alternative_cost,
+                                                           &journey_preferences).pop();
+                        alternative_cost[stop_time] = unblocked_cost;
+                        let Some(Ok(alternative)) = alternative else {
+                            continue;
+                        };
+                        // The planner only avoids the blocked leg if the crowding cost is weighed, so journeys still taking it are left out.
+                        if alternative.legs.is_empty() || alternative.cost >= BLOCKED_SEGMENT_COST || !is_allowed(&alternative.legs) {
+                            continue;
                         }
+                        let cost = penalised_cost(departure_time, alternative.duration, alternative.cost, &alternative.legs);
+                        if cost < best_cost {
+                            best_cost = cost;
+                            best_alternative = Some(alternative);
+                        }
+                    }
+                    if let Some(alternative) = best_alternative {
+                        *journey = Ok(alternative);
+                    }
+                }
+            }
 
-                        let journey = match journey {
-                            Ok(journey) => journey,
-                            Err(err) => return AgentJourneyResult {
-                                sim_step_idx,
-                                journey_idx,
-                                origin_stop: sim_step.origin_stop,
-                                dest_stop,
-                                start_time: sim_step.departure_time,
-                                count,
-                                result: Err(err),
-                            },
+            let summary = journeys.iter().map(|journey| {
+                journey.as_ref().ok().filter(|journey| !journey.legs.is_empty()).map(|journey| (journey.duration, journey.cost))
+            }).collect::<Vec<_>>();
+            (journeys, summary)
+        };
+
+        let mut departure_time = previous_departure;
+        let (mut journeys, summary) = plan_at(departure_time);
+        if let Some(departure_choice) = departure_choice {
+            // Shift to whichever neighbouring departure has the lowest combined cost, if it is lower than the current one.
+            let crowding_weight = params.get_segment_crowding_weight(sim_step.segment).unwrap_or(departure_choice.crowding_weight);
+            let step_cost = |departure_time: Timestamp, summary: Vec<Option<(Timestamp, PathfindingCost)>>| {
+                departure_choice.step_cost(crowding_weight, sim_step.departure_time, departure_time, counts.iter().copied().zip(summary))
+            };
+            let mut best_cost = step_cost(departure_time, summary);
+            for candidate in departure_choice.candidates(sim_step.departure_time, previous_departure) {
+                let (candidate_journeys, candidate_summary) = plan_at(candidate);
+                let Some(cost) = step_cost(candidate, candidate_summary) else {
+                    continue;
+                };
+                if best_cost.map_or(true, |best_cost| cost < best_cost) {
+                    best_cost = Some(cost);
+                    departure_time = candidate;
+                    journeys = candidate_journeys;
+                }
+            }
+        }
+
+        chunk_journeys.extend(
+            izip!(0..journeys.len() as u32, journeys.into_iter(), counts, &sim_step.dest_stops)
+                .map(move |(journey_idx, journey, &count, &dest_stop)| {
+                    if count == 0 {
+                        // Ignore zero-count agents.
+                        return AgentJourneyResult {
+                            sim_step_idx,
+                            journey_idx,
+                            origin_stop: sim_step.origin_stop,
+                            dest_stop,
+                            start_time: departure_time,
+                            count,
+                            segment: sim_step.segment,
+                            result: Err(JourneyError::ZeroAgents),
                         };
+                    }
 
-                        if journey.legs.is_empty() {
-                            // Ignore empty journeys.
+                    let journey = match journey {
+                        Ok(journey) => journey,
+                        Err(err) => {
+                            log::trace!("Agent {sim_step_idx}/{journey_idx} found no journey from stop {} to stop {dest_stop} at {}.", sim_step.origin_stop, departure_time);
                             return AgentJourneyResult {
                                 sim_step_idx,
                                 journey_idx,
                                 origin_stop: sim_step.origin_stop,
                                 dest_stop,
-                                start_time: sim_step.departure_time,
+                                start_time: departure_time,
                                 count,
-                                result: Err(JourneyError::NoJourneyFound),
+                                segment: sim_step.segment,
+                                result: Err(err),
                             };
                         }
+                    };
 
-                        // Because journey.legs.len() > 0, these are guaranteed to be set in the loop;
-                        let mut origin_trip = GlobalTripIndex::default();
-                        let mut dest_trip = GlobalTripIndex::default();
-
-                        for (i, leg) in journey.legs.iter().enumerate() {
-                            let route = &network.routes[leg.trip.route_idx as usize];
-                            let trip = &trip_stops_pop[route.get_trip_range(leg.trip.trip_order as usize)];
-
-                            // Record first and last trip.
-                            if i == 0 {
-                                origin_trip = leg.trip;
-                            }
-                            if i == journey.legs.len() - 1 {
-                                dest_trip = leg.trip;
-                            }
-
-                            let count = count as PopulationCount;
-                            let boarded_stop_order = leg.boarded_stop_order as usize;
-                            let arrival_stop_order = leg.arrival_stop_order as usize;
-                            // Add one agent to this span of trip stops.
-                            trip[boarded_stop_order].fetch_add(count, Ordering::Relaxed);
-                            // Remove agent at stop (for inclusive-exclusive range).
-                            trip[arrival_stop_order].fetch_sub(count, Ordering::Relaxed);
-
-                            // Non-prefix-sum version.
-                            //{
-                            //    assert!(boarded_stop_order < arrival_stop_order, "{boarded_stop_order} < {arrival_stop_order}")
-                            //    // Iterate over all stops in the trip, adding the agent count.
-                            //    for i in boarded_stop_order..arrival_stop_order {
-                            //        trip[i].fetch_add(count, Ordering::Relaxed);
-                            //    }
-                            //}
-                        }
-
-                        AgentJourneyResult {
+                    if journey.legs.is_empty() {
+                        // Ignore empty journeys.
+                        return AgentJourneyResult {
                             sim_step_idx,
                             journey_idx,
                             origin_stop: sim_step.origin_stop,
                             dest_stop,
-                            start_time: sim_step.departure_time,
+                            start_time: departure_time,
                             count,
-                            result: Ok(AgentJourney {
-                                origin_trip,
-                                dest_trip,
-                                duration: journey.duration,
-                                crowding_cost: journey.cost,
-                                num_transfers: (journey.legs.len() - 1) as u8,
-                                legs: journey.legs,
-                            }),
+                            segment: sim_step.segment,
+                            result: Err(JourneyError::NoJourneyFound),
+                        };
+                    }
+
+                    // Because journey.legs.len() > 0, these are guaranteed to be set in the loop;
+                    let mut origin_trip = GlobalTripIndex::default();
+                    let mut dest_trip = GlobalTripIndex::default();
+
+                    for (i, leg) in journey.legs.iter().enumerate() {
+                        // Record first and last trip.
+                        if i == 0 {
+                            origin_trip = leg.trip;
+                        }
+                        if i == journey.legs.len() - 1 {
+                            dest_trip = leg.trip;
                         }
-                    }))
-        }));
 
-    let mut trip_stops_cost = vec![0 as CrowdingCost; network.stop_times.len()];
+                        add_leg_to_population(network, chunk_loads, leg, count as PopulationCount);
+                    }
+
+                    let mut agent_journey = AgentJourney {
+                        origin_trip,
+                        dest_trip,
+                        duration: journey.duration,
+                        crowding_cost: journey.cost as CrowdingCost,
+                        experienced_crowding_cost: 0.,
+                        in_vehicle_time: 0,
+                        wait_time: 0,
+                        num_transfers: (journey.legs.len() - 1) as u8,
+                        legs: journey.legs,
+                    };
+                    agent_journey.set_times();
+
+                    AgentJourneyResult {
+                        sim_step_idx,
+                        journey_idx,
+                        origin_stop: sim_step.origin_stop,
+                        dest_stop,
+                        start_time: departure_time,
+                        count,
+                        segment: sim_step.segment,
+                        result: Ok(agent_journey),
+                    }
+                }));
+    };
+
+    let chunk_results = chunk_iterator.enumerate().map(|(chunk_idx, chunk)| {
+        let mut chunk_journeys = Vec::with_capacity(chunk.iter().map(SimulationStep::len).sum());
+        let mut chunk_loads = (Vec::new(), Vec::new());
+        for (i, sim_step) in chunk.iter().enumerate() {
+            plan_step(chunk_idx * chunk_size + i, sim_step, &mut chunk_journeys, &mut chunk_loads);
+        }
+        (chunk_journeys, chunk_loads)
+    }).collect::<Vec<_>>();
+
+    // Merged in chunk order, so the journeys are in step order.
+    let mut agent_journeys = Vec::with_capacity(num_agents);
+    let mut boardings = vec![0 as PopulationCount; network.stop_times.len()];
+    let mut alightings = vec![0 as PopulationCount; network.stop_times.len()];
+    for (chunk_journeys, (chunk_boardings, chunk_alightings)) in chunk_results {
+        agent_journeys.extend(chunk_journeys);
+        for (stop_time_idx, count) in chunk_boardings {
+            boardings[stop_time_idx] += count;
+        }
+        for (stop_time_idx, count) in chunk_alightings {
+            alightings[stop_time_idx] += count;
+        }
+    }
+
+    let capacity_report = if params.is_capacity_strict() {
+        // Boarding has to be replayed in time order, so the parallel counts are discarded.
+        let capacity_report = enforce_capacity(network, params, simulation_steps, crowding_cost, bag_size, &mut agent_journeys);
+        (boardings, alightings) = count_boardings_and_alightings(network, &agent_journeys);
+        Some(capacity_report)
+    } else {
+        None
+    };
+
+    let trip_stops_pop = trip_loads(network, &boardings, &alightings);
+
+    let trip_stops_cost = calculate_crowding_cost(network, params, &trip_stops_pop);
+
+    SimulationRoundResult {
+        population_count: trip_stops_pop,
+        crowding_cost: trip_stops_cost,
+        agent_journeys,
+        capacity_report,
+        num_replanned,
+        num_plan_cache_hits,
+    }
+}
 
-    // Copy counts from Vec<PopulationCountAtomic> to Vec<PopulationCount>.
-    let mut trip_stops_pop = trip_stops_pop.iter().map(|x| x.load(Ordering::Relaxed)).collect::<Vec<PopulationCount>>();
+// The crowding costs as the journey planner's cost type, which is only a copy when the crowding cost is more precise.
+#[cfg(not(feature = "f64_crowding_cost"))]
+fn planner_costs(crowding_cost: &[CrowdingCost]) -> Cow<'_, [PathfindingCost]> {
+    Cow::Borrowed(crowding_cost)
+}
+
+#[cfg(feature = "f64_crowding_cost")]
+fn planner_costs(crowding_cost: &[CrowdingCost]) -> Cow<'_, [PathfindingCost]> {
+    Cow::Owned(crowding_cost.iter().map(|&cost| cost as PathfindingCost).collect())
+}
+
+// Name of the crowding cost type, recorded in the run metadata.
+pub fn crowding_cost_precision() -> &'static str {
+    std::any::type_name::<CrowdingCost>()
+}
+
+// Calculates the crowding cost of travelling along each trip segment, given the (prefix-summed) population counts.
+// The cost at a stop time is for the segment arriving at that stop.
+fn calculate_crowding_cost(network: &Network, params: &impl SimulationParams, population_count: &[PopulationCount]) -> Vec<CrowdingCost> {
+    let mut trip_stops_cost = vec![0 as CrowdingCost; network.stop_times.len()];
 
-    // Build sums of agent counts, and calculate crowding cost.
     // Note: this ends up running through the trip_pop in order, so it's cache-friendly.
-    for route_idx in 0..network.routes.len() {
-        let route = &network.routes[route_idx];
+    for route in network.routes.iter() {
         for trip in 0..route.num_trips as usize {
             let trip_id = &route.trip_ids[trip];
             let trip_range = route.get_trip_range(trip);
             let stop_times = &network.stop_times[trip_range.clone()];
-            let trip = &mut trip_stops_pop[trip_range.clone()];
+            let trip = &population_count[trip_range.clone()];
             let costs = &mut trip_stops_cost[trip_range];
 
             costs[0] = params.cost_fn(trip_id, trip[0]);
             for i in 0..(trip.len() - 1) {
-                // Calculate prefix sums
-                trip[i + 1] += trip[i];
-                // Calculate crowding cost.
                 let cost_per_unit_time = params.cost_fn(trip_id, trip[i + 1]);
                 let connection_time = stop_times[i + 1].departure_time.checked_sub(stop_times[i].arrival_time).unwrap_or_else(|| {
                     log::warn!("Negative connection time: {} -> {}", raptor::utils::get_time_str(stop_times[i].arrival_time), raptor::utils::get_time_str(stop_times[i + 1].departure_time));
                     0
                 });
                 costs[i + 1] = cost_per_unit_time * connection_time as CrowdingCost;
-                assert!(trip[i] >= 0);
             }
         }
     }
 
-    SimulationRoundResult {
-        population_count: trip_stops_pop,
-        crowding_cost: trip_stops_cost,
-        agent_journeys,
+    trip_stops_cost
+}
+
+// Replays boarding in time order so no trip carries more agents than its capacity.
+// Agents denied boarding re-plan from the stop they were denied at, avoiding segments that are already full.
+// Returns boarding/alighting counts in the same form as the parallel assignment (to be prefix-summed).
+fn enforce_capacity(network: &Network,
+                    params: &impl SimulationParams,
+                    simulation_steps: &[SimulationStep],
+                    crowding_cost: &[CrowdingCost],
+                    bag_size: usize,
+                    agent_journeys: &mut [AgentJourneyResult]) -> CapacityReport {
+    // After this many denials the agent gives up, so a busy corridor can't keep it re-planning forever.
+    const MAX_DENIALS: u32 = 16;
+    let wheelchair_access = params.get_wheelchair_access();
+    let transfer_times = params.get_transfer_times();
+
+    let mut segment_capacity = vec![PopulationCount::MAX; network.stop_times.len()];
+    for route in network.routes.iter() {
+        for trip in 0..route.num_trips as usize {
+            segment_capacity[route.get_trip_range(trip)].fill(params.get_capacity(&route.trip_ids[trip]));
+        }
+    }
+
+    // Agents on board when departing each stop time.
+    let mut segment_load = vec![0 as PopulationCount; network.stop_times.len()];
+    let mut blocked_cost = planner_costs(crowding_cost).into_owned();
+
+    let mut original_arrival_times = vec![0 as Timestamp; agent_journeys.len()];
+    let mut num_denied = vec![0u32; agent_journeys.len()];
+    let mut stranded = vec![false; agent_journeys.len()];
+    let mut denied_boardings = Vec::new();
+
+    // Process boardings in time order, so agents already on board keep their place.
+    let mut boardings = BinaryHeap::new();
+    for (agent_idx, agent_journey) in agent_journeys.iter().enumerate() {
+        if let Ok(journey) = &agent_journey.result {
+            original_arrival_times[agent_idx] = agent_journey.start_time + journey.duration;
+            boardings.push(Reverse((journey.legs[0].boarded_time, agent_idx, 0usize)));
+        }
+    }
+
+    while let Some(Reverse((boarded_time, agent_idx, leg_idx))) = boardings.pop() {
+        let agent_journey = &mut agent_journeys[agent_idx];
+        let count = agent_journey.count as PopulationCount;
+        let dest_stops = vec![agent_journey.dest_stop];
+        let journey_preferences = params.get_segment_journey_preferences(agent_journey.segment);
+        // Re-planned journeys aren't steered away from inaccessible trips and stops, so an agent needing access is stranded if it needs one.
+        // Likewise for transfers that are too quick.
+        let step_wheelchair_access = wheelchair_access.filter(|_| simulation_steps[agent_journey.sim_step_idx as usize].requires_accessible);
+        let Ok(journey) = &mut agent_journey.result else {
+            continue;
+        };
+
+        let leg = &journey.legs[leg_idx];
+        let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+        let segments = (trip_start + leg.boarded_stop_order as usize)..(trip_start + leg.arrival_stop_order as usize);
+
+        // The whole group boards together or not at all.
+        if segments.clone().all(|i| segment_load[i] + count <= segment_capacity[i]) {
+            for i in segments {
+                segment_load[i] += count;
+                if segment_load[i] >= segment_capacity[i] {
+                    blocked_cost[i + 1] = BLOCKED_SEGMENT_COST;
+                }
+            }
+            if leg_idx + 1 < journey.legs.len() {
+                boardings.push(Reverse((journey.legs[leg_idx + 1].boarded_time, agent_idx, leg_idx + 1)));
+            }
+            continue;
+        }
+
+        let boarded_stop = leg.boarded_stop;
+        denied_boardings.push(DeniedBoarding {
+            agent_idx: agent_idx as u32,
+            stop: boarded_stop,
+            trip: leg.trip,
+            time: boarded_time,
+            count: agent_journey.count,
+        });
+        num_denied[agent_idx] += 1;
+
+        // Wait for a later departure from the same stop.
+        let replanned = if num_denied[agent_idx] <= MAX_DENIALS {
+            mc_raptor_query!(bag_size,
+                             network,
+                             boarded_stop,
+                             boarded_time + 1,
+                             &dest_stops,
+                             &blocked_cost,
+                             journey_preferences).pop()
+        } else {
+            None
+        };
+
+        match replanned {
+            Some(Ok(replanned)) if !replanned.legs.is_empty()
+                && step_wheelchair_access.map_or(true, |access| access.is_journey_accessible(&replanned.legs))
+                && transfer_times.map_or(true, |transfer_times| transfer_times.missed_transfer_stop_time(network, &replanned.legs).is_none()) => {
+                journey.legs.truncate(leg_idx);
+                journey.legs.extend(replanned.legs);
+                boardings.push(Reverse((journey.legs[leg_idx].boarded_time, agent_idx, leg_idx)));
+            }
+            _ => {
+                // No later service with room, e.g. denied boarding the last service of the day.
+                stranded[agent_idx] = true;
+                agent_journey.result = Err(JourneyError::NoJourneyFound);
+            }
+        }
+    }
+
+    // Update the journeys of agents that were re-planned.
+    let mut delays = Vec::new();
+    for (agent_idx, agent_journey) in agent_journeys.iter_mut().enumerate() {
+        if let Ok(journey) = &mut agent_journey.result {
+            if num_denied[agent_idx] > 0 {
+                let first_leg = journey.legs.first().unwrap();
+                let last_leg = journey.legs.last().unwrap();
+                journey.origin_trip = first_leg.trip;
+                journey.dest_trip = last_leg.trip;
+                journey.duration = last_leg.arrival_time - agent_journey.start_time;
+                journey.num_transfers = (journey.legs.len() - 1) as u8;
+                journey.crowding_cost = journey.legs.iter().map(|leg| leg_crowding_cost(network, crowding_cost, leg)).sum();
+                journey.set_times();
+            }
+        }
+
+        if num_denied[agent_idx] > 0 {
+            let delay = match &agent_journey.result {
+                Ok(journey) => (agent_journey.start_time + journey.duration).saturating_sub(original_arrival_times[agent_idx]),
+                Err(_) => 0,
+            };
+            delays.push(CapacityDelay {
+                agent_idx: agent_idx as u32,
+                num_denied: num_denied[agent_idx],
+                delay,
+                stranded: stranded[agent_idx],
+            });
+        }
     }
+
+    CapacityReport { denied_boardings, delays }
+}
+
+// Plans one journey under the crowding cost of the given loads, for the query mode to print.
+pub fn plan_journey(network: &Network, params: &impl SimulationParams, population_count: &[PopulationCount], origin_stop: StopIndex, departure_time: Timestamp, dest_stop: StopIndex) -> Result<AgentJourney, JourneyError> {
+    let crowding_cost = calculate_crowding_cost(network, params, population_count);
+    let bag_size = params.get_bag_size().clamp(2, 5);
+    let journeys = mc_raptor_query!(bag_size,
+                                    network,
+                                    origin_stop,
+                                    departure_time,
+                                    &vec![dest_stop],
+                                    &planner_costs(&crowding_cost),
+                                    params.get_journey_preferences());
+    let journey = journeys.into_iter().next().unwrap_or(Err(JourneyError::NoJourneyFound))?;
+    let (Some(first_leg), Some(last_leg)) = (journey.legs.first(), journey.legs.last()) else {
+        return Err(JourneyError::NoJourneyFound);
+    };
+
+    let mut agent_journey = AgentJourney {
+        origin_trip: first_leg.trip,
+        dest_trip: last_leg.trip,
+        duration: journey.duration,
+        crowding_cost: journey.cost as CrowdingCost,
+        experienced_crowding_cost: journey.legs.iter().map(|leg| leg_crowding_cost(network, &crowding_cost, leg)).sum(),
+        in_vehicle_time: 0,
+        wait_time: 0,
+        num_transfers: (journey.legs.len() - 1) as u8,
+        legs: journey.legs,
+    };
+    agent_journey.set_times();
+    Ok(agent_journey)
+}
+
+// Plans from one stop to every stop in a single query, giving the travel time to each (None if it can't be reached).
+// Without loads there's no crowding, so a bag size of 1 finds the fastest journeys. With (prefix-summed) loads, the
+// travel times are of the journeys agents would take weighing that crowding against time.
+pub fn plan_isochrone(network: &Network, params: &impl SimulationParams, population_count: Option<&[PopulationCount]>, origin_stop: StopIndex, departure_time: Timestamp) -> Vec<Option<Timestamp>> {
+    let crowding_cost = match population_count {
+        Some(population_count) => calculate_crowding_cost(network, params, population_count),
+        None => vec![0 as CrowdingCost; network.stop_times.len()],
+    };
+    let bag_size = population_count.map_or(1, |_| params.get_bag_size().clamp(2, 5));
+    let dest_stops = (0..network.stops.len() as StopIndex).collect::<Vec<_>>();
+    let journeys = mc_raptor_query!(bag_size,
+                                    network,
+                                    origin_stop,
+                                    departure_time,
+                                    &dest_stops,
+                                    &planner_costs(&crowding_cost),
+                                    params.get_journey_preferences());
+    izip!(dest_stops, journeys).map(|(dest_stop, journey)| match journey {
+        _ if dest_stop == origin_stop => Some(0),
+        Ok(journey) if !journey.legs.is_empty() => Some(journey.duration),
+        _ => None,
+    }).collect()
 }
 
+// The result only depends on the simulation steps and parameters, not the number of threads or scheduling:
+// journeys are collected in simulation step order, population counts are integer sums (so the chunk size and the
+// order chunks are planned in don't matter), and everything order-dependent (capacity enforcement, averaging) runs sequentially.
 pub fn run_simulation(network: &Network, simulation_steps: &[SimulationStep], params: &impl SimulationParams) -> SimulationResult {
+    if params.get_dwell_model().is_some() {
+        log::warn!("The dwell model needs run_simulation_with_dwell to update stop times, so it is ignored.");
+    }
+    run_simulation_impl(SimulationNetwork::Shared(network), simulation_steps, params)
+}
+
+// Like run_simulation, but applies the dwell model (if any) between rounds, so each round plans on the stop times
+// realised in the previous one. The network's stop times are restored to the timetable before returning.
+pub fn run_simulation_with_dwell(network: &mut Network, simulation_steps: &[SimulationStep], params: &impl SimulationParams) -> SimulationResult {
+    run_simulation_impl(SimulationNetwork::Mutable(network), simulation_steps, params)
+}
+
+// The simulation either reads a shared network, or updates the stop times of a mutable one between rounds.
+enum SimulationNetwork<'a> {
+    Shared(&'a Network),
+    Mutable(&'a mut Network),
+}
+
+impl SimulationNetwork<'_> {
+    fn get(&self) -> &Network {
+        match self {
+            SimulationNetwork::Shared(network) => network,
+            SimulationNetwork::Mutable(network) => network,
+        }
+    }
+}
+
+fn run_simulation_impl(mut simulation_network: SimulationNetwork, simulation_steps: &[SimulationStep], params: &impl SimulationParams) -> SimulationResult {
     #[cfg(feature = "progress_bar")]
     if params.get_progress_callback().is_some() {
         fn handle_io_error<T>(result: std::io::Result<T>) {
@@ -497,44 +2352,794 @@ pub fn run_simulation(network: &Network, simulation_steps: &[SimulationStep], pa
     }
 
     let num_rounds = params.get_num_rounds();
-    let mut simulation_rounds = Vec::with_capacity(num_rounds as usize);
-
-    let round_iterator = (0..num_rounds).into_iter();
-    let mut run_round = |round_number| {
-        simulation_rounds.push(
-            run_simulation_round(network,
-                                 simulation_steps,
-                                 params,
-                                 simulation_rounds.last().map(|r: &SimulationRoundResult| r.crowding_cost.as_ref()),
-                                 round_number,
-            )
+    let convergence_tolerance = params.get_convergence_tolerance();
+    let convergence_rounds = params.get_convergence_rounds().max(1);
+    let mut num_converged_rounds = 0;
+    // Only the last round is kept as AgentJourneyResults (for replanning from), earlier rounds are packed into JourneyTables.
+    let mut last_round: Option<SimulationRoundResult> = None;
+    let mut round_agent_journeys = Vec::with_capacity(num_rounds as usize);
+    let mut iteration_history = Vec::with_capacity(num_rounds as usize);
+
+    // Loads averaged over the rounds so far, which the crowding cost for the next round is calculated from.
+    let mut averaged_population = Vec::new();
+    let mut population_count = Vec::new();
+    let mut crowding_cost: Option<Vec<CrowdingCost>> = None;
+
+    let mut cancelled = false;
+    let mut plan_switching: HashMap<(StopIndex, StopIndex), PlanSwitching> = HashMap::new();
+
+    // Elastic demand state, indexed like the round's agent journeys: the uncrowded journey time, the cost of the latest journey,
+    // the agents travelling in the next round and the agents that travelled in the last round run.
+    let elasticity = params.get_elasticity();
+    let mut baseline_costs: Vec<Option<f64>> = Vec::new();
+    let mut latest_costs: Vec<Option<f64>> = Vec::new();
+    let mut demand_counts: Option<Vec<AgentCount>> = None;
+    let mut round_demand_counts: Option<Vec<AgentCount>> = None;
+
+    let dwell_model = match simulation_network {
+        SimulationNetwork::Mutable(_) => params.get_dwell_model(),
+        SimulationNetwork::Shared(_) => None,
+    };
+    let timetable = match dwell_model {
+        Some(_) => simulation_network.get().stop_times.iter().map(|stop_time| (stop_time.arrival_time, stop_time.departure_time)).collect_vec(),
+        None => Vec::new(),
+    };
+    let mut realised_stop_times = None;
+
+    let checkpointing = params.get_checkpointing();
+    // Hashed before the dwell model changes the stop times, so they match the network the checkpoint is verified against.
+    let checkpoint_hashes = checkpointing.map(|_| (checkpoint::network_hash(simulation_network.get()), checkpoint::demand_hash(simulation_steps)));
+    let mut first_round = 0;
+    if let Some(checkpoint) = checkpointing.and_then(|checkpointing| checkpointing.resume.as_deref()) {
+        log::info!("Resuming the simulation from round {}.", checkpoint.num_rounds_run);
+        let agent_journeys = checkpoint.agent_journeys.iter().map(AgentJourneyResult::keep_plan).collect_vec();
+        if let Some(dwell_model) = dwell_model {
+            let network = simulation_network.get();
+            let (boardings, alightings) = count_boardings_and_alightings(network, &agent_journeys);
+            let realised = dwell_model.realised_stop_times(network, &timetable, &boardings, &alightings);
+            if let SimulationNetwork::Mutable(network) = &mut simulation_network {
+                set_stop_times(network, &realised);
+            }
+            realised_stop_times = Some(realised);
+        }
+        averaged_population = checkpoint.averaged_population.clone();
+        population_count = averaged_population.iter().map(|&count| count.round() as PopulationCount).collect();
+        crowding_cost = Some(calculate_crowding_cost(simulation_network.get(), params, &population_count));
+        iteration_history = checkpoint.iteration_history.clone();
+        num_converged_rounds = checkpoint.num_converged_rounds;
+        last_round = Some(SimulationRoundResult {
+            population_count: population_count.clone(),
+            crowding_cost: Vec::new(),
+            agent_journeys,
+            capacity_report: None,
+            num_replanned: iteration_history.last().map_or(0, |stats| stats.num_replanned),
+            num_plan_cache_hits: iteration_history.last().map_or(0, |stats| stats.num_plan_cache_hits),
+        });
+        // A checkpoint written after the assignment converged has no rounds left to run.
+        first_round = if num_converged_rounds >= convergence_rounds { num_rounds } else { checkpoint.num_rounds_run.min(num_rounds) };
+    }
+
+    // The warm start loads are averaged in like an extra round before the first.
+    let warm_start = params.get_warm_start().filter(|_| last_round.is_none());
+    if let Some(warm_start) = warm_start {
+        log::info!("Warm starting the simulation from the loads of a previous run ({} of {} segments matched).", warm_start.num_matched, warm_start.num_matched + warm_start.num_unmatched);
+        averaged_population = warm_start.averaged_population.clone();
+        population_count = averaged_population.iter().map(|&count| count.round() as PopulationCount).collect();
+        crowding_cost = Some(calculate_crowding_cost(simulation_network.get(), params, &population_count));
+    }
+    let step_round_offset = warm_start.is_some() as u16;
+
+    if let (Some(elasticity), true) = (elasticity, first_round > 0 || warm_start.is_some()) {
+        // The checkpoint doesn't include the uncrowded first round (and a warm-started first round is crowded), so it's
+        // planned again for the baseline.
+        log::info!("Planning an uncrowded round for the elastic demand baseline.");
+        let network = simulation_network.get();
+        let baseline_round = run_simulation_round(network, simulation_steps, params, None, None, None, 0);
+        baseline_costs = baseline_round.agent_journeys.iter().map(|agent_journey| agent_journey.result.as_ref().ok().map(|journey| journey.duration as f64)).collect();
+        match &last_round {
+            Some(last_round) => {
+                let agent_journeys = &last_round.agent_journeys;
+                latest_costs = agent_journeys.iter().map(|agent_journey| agent_journey.result.as_ref().ok().map(|journey| elasticity.generalised_cost(journey))).collect();
+                demand_counts = Some(elasticity.travelling_counts(simulation_steps, &baseline_costs, &latest_costs, first_round));
+                round_demand_counts = Some(agent_journeys.iter().map(|agent_journey| agent_journey.count).collect());
+            }
+            // Everyone travels in the first round.
+            None => latest_costs = vec![None; baseline_costs.len()],
+        }
+    }
+
+    // Of the last round's journeys.
+    let mut plan_fingerprints = last_round.as_ref().map_or_else(Vec::new, |round| round.agent_journeys.par_iter().map(AgentJourneyResult::plan_fingerprint).collect());
+
+    let round_iterator = (first_round..num_rounds).into_iter();
+    // Returns true once the assignment has converged or been cancelled.
+    let mut run_round = |round_number| -> bool {
+        let network = simulation_network.get();
+        let mut round = run_simulation_round(network,
+                                         simulation_steps,
+                                         params,
+                                         crowding_cost.as_deref(),
+                                         last_round.as_ref().map(|r| r.agent_journeys.as_slice()),
+                                         demand_counts.as_deref(),
+                                         round_number,
         );
+        if params.is_cancelled() {
+            log::warn!("Simulation cancelled during round {round_number}.");
+            cancelled = true;
+            // A partial round would skew the averaged loads, so only keep it if it's all we have.
+            if last_round.is_some() {
+                return true;
+            }
+        }
+
+        // Compare each agent's plan with its previous one, costing both with the loads this round was planned with.
+        let round_fingerprints = round.agent_journeys.par_iter().map(AgentJourneyResult::plan_fingerprint).collect::<Vec<_>>();
+        let (num_changed_route, switching_cost_improvement) = match (&last_round, &crowding_cost) {
+            (Some(previous), Some(crowding_cost)) => {
+                let mut num_changed_route = 0;
+                let mut round_cost_improvement = 0.;
+                for (previous, agent_journey, previous_fingerprint, fingerprint) in izip!(&previous.agent_journeys, &round.agent_journeys, &plan_fingerprints, &round_fingerprints) {
+                    if previous_fingerprint == fingerprint {
+                        continue;
+                    }
+                    num_changed_route += agent_journey.count as usize;
+                    let switching = plan_switching.entry((agent_journey.origin_stop, agent_journey.dest_stop)).or_default();
+                    switching.num_switched += agent_journey.count as u64;
+                    if let (Ok(previous), Ok(journey)) = (&previous.result, &agent_journey.result) {
+                        let cost = journey.legs.iter().map(|leg| leg_crowding_cost(network, crowding_cost, leg)).sum::<CrowdingCost>();
+                        let cost_improvement = (previous.experienced_crowding_cost - cost) as f64 * agent_journey.count as f64;
+                        switching.cost_improvement += cost_improvement;
+                        round_cost_improvement += cost_improvement;
+                    }
+                }
+                (Some(num_changed_route), Some(round_cost_improvement))
+            }
+            _ => (None, None),
+        };
+        plan_fingerprints = round_fingerprints;
+
+        // The first round has nothing to average with, unless it's warm started.
+        let step_round = round_number + step_round_offset;
+        let step_size = if step_round == 0 { 1. } else { params.get_step_size(step_round) };
+        let relative_load_gap = if averaged_population.is_empty() {
+            averaged_population = round.population_count.iter().map(|&count| count as CrowdingCost).collect();
+            None
+        } else {
+            let mut total_change = 0.;
+            let mut total_load = 0.;
+            for (averaged, &count) in averaged_population.iter_mut().zip(round.population_count.iter()) {
+                let change = step_size * (count as CrowdingCost - *averaged);
+                total_change += change.abs() as f64;
+                total_load += *averaged as f64;
+                *averaged += change;
+            }
+            Some(if total_load > 0. { total_change / total_load } else { 0. })
+        };
+        population_count = averaged_population.iter().map(|&count| count.round() as PopulationCount).collect();
+        let max_segment_load = population_count.iter().copied().max().unwrap_or(0);
+        let total_passenger_hours = round.agent_journeys.iter().filter_map(|agent_journey| {
+            agent_journey.result.as_ref().ok().map(|journey| journey.duration as f64 * agent_journey.count as f64)
+        }).sum::<f64>() / 3600.;
+
+        // Delays are always calculated from the timetable, so they don't compound between rounds.
+        if let Some(dwell_model) = dwell_model {
+            let (boardings, alightings) = count_boardings_and_alightings(network, &round.agent_journeys);
+            let realised = dwell_model.realised_stop_times(network, &timetable, &boardings, &alightings);
+            if let SimulationNetwork::Mutable(network) = &mut simulation_network {
+                set_stop_times(network, &realised);
+            }
+            realised_stop_times = Some(realised);
+        }
+
+        let next_crowding_cost = calculate_crowding_cost(simulation_network.get(), params, &population_count);
+        let network = simulation_network.get();
+        round.agent_journeys.par_iter_mut().filter_map(|agent_journey| agent_journey.result.as_mut().ok()).for_each(|journey| {
+            journey.experienced_crowding_cost = journey.legs.iter().map(|leg| leg_crowding_cost(network, &next_crowding_cost, leg)).sum();
+        });
+        round_demand_counts = demand_counts.take();
+        if let Some(elasticity) = elasticity {
+            // The first round is planned without crowding, so its journey times are the baseline (unless already planned).
+            if round_number == 0 && baseline_costs.is_empty() {
+                baseline_costs = round.agent_journeys.iter().map(|agent_journey| agent_journey.result.as_ref().ok().map(|journey| journey.duration as f64)).collect();
+                latest_costs = vec![None; round.agent_journeys.len()];
+            }
+            // Agents that didn't travel keep the cost of their last journey.
+            for (latest_cost, agent_journey) in latest_costs.iter_mut().zip(round.agent_journeys.iter()) {
+                if let Ok(journey) = &agent_journey.result {
+                    *latest_cost = Some(elasticity.generalised_cost(journey));
+                }
+            }
+            demand_counts = Some(elasticity.travelling_counts(simulation_steps, &baseline_costs, &latest_costs, round_number + 1));
+        }
+
+        let total_crowding_cost = next_crowding_cost.iter().map(|&cost| cost as f64).sum::<f64>();
+        let relative_change = iteration_history.last().map(|last: &IterationStats| {
+            if last.total_crowding_cost > 0. {
+                (total_crowding_cost - last.total_crowding_cost).abs() / last.total_crowding_cost
+            } else {
+                0.
+            }
+        });
+        log::debug!("Round {round_number}: total crowding cost {total_crowding_cost}, relative change {relative_change:?}, {} agents replanned, {num_changed_route:?} changed route.", round.num_replanned);
+
+        iteration_history.push(IterationStats {
+            round_number,
+            step_size,
+            total_crowding_cost,
+            relative_change,
+            relative_load_gap,
+            total_passenger_hours,
+            max_segment_load,
+            num_replanned: round.num_replanned,
+            num_plan_cache_hits: round.num_plan_cache_hits,
+            num_changed_route,
+            switching_cost_improvement,
+        });
+        crowding_cost = Some(next_crowding_cost);
+        if let Some(previous) = last_round.replace(round) {
+            round_agent_journeys.push(JourneyTable::from_journeys(previous.agent_journeys));
+        }
+
+        if matches!((convergence_tolerance, relative_change), (Some(tolerance), Some(change)) if change < tolerance) {
+            num_converged_rounds += 1;
+        } else {
+            num_converged_rounds = 0;
+        }
+
+        // A cancelled round is partial, so isn't worth resuming from.
+        if let (Some(checkpointing), Some((network_hash, demand_hash)), false) = (checkpointing.filter(|checkpointing| checkpointing.keep > 0), checkpoint_hashes, cancelled) {
+            let agent_journeys = &last_round.as_ref().unwrap().agent_journeys;
+            match checkpointing.write(network_hash, demand_hash, round_number + 1, num_converged_rounds, &averaged_population, &iteration_history, agent_journeys) {
+                Ok(path) => log::debug!("Wrote checkpoint {}.", path.display()),
+                Err(err) => log::error!("Failed to write the checkpoint for round {round_number}: {err}"),
+            }
+        }
+        cancelled || num_converged_rounds >= convergence_rounds
     };
 
     #[cfg(feature = "progress_bar")]
     if params.get_progress_callback().is_some() {
         for round_number in tqdm!(round_iterator, desc = "Simulation Rounds", position = 0) {
-            run_round(round_number);
+            if run_round(round_number) {
+                break;
+            }
         }
     } else {
         for round_number in round_iterator {
-            run_round(round_number);
+            if run_round(round_number) {
+                break;
+            }
         }
     }
 
     #[cfg(not(feature = "progress_bar"))]
     for round_number in round_iterator {
-        run_round(round_number);
+        if run_round(round_number) {
+            break;
+        }
     }
 
-    // Use the population count of the last round as the final population count.
-    let last_simulation_round = simulation_rounds.last_mut().unwrap();
-    let population_count = std::mem::take(&mut last_simulation_round.population_count);
+    if let (Some(_), SimulationNetwork::Mutable(network)) = (dwell_model, &mut simulation_network) {
+        set_stop_times(network, &timetable);
+    }
 
-    let round_agent_journeys = simulation_rounds.into_iter().map(|r| r.agent_journeys).collect();
+    // The averaged population count is the final population count (with StepSize::Full this is just the last round's count).
+    let last_round = last_round.unwrap();
+    let capacity_report = last_round.capacity_report;
+    let suppressed_counts = elasticity.map(|_| {
+        let counts = simulation_steps.iter().flat_map(|sim_step| sim_step.counts.iter().copied());
+        match &round_demand_counts {
+            Some(travelling) => counts.zip(travelling.iter()).map(|(count, &travelling)| count - travelling).collect(),
+            None => vec![0; last_round.agent_journeys.len()],
+        }
+    });
+    round_agent_journeys.push(JourneyTable::from_journeys(last_round.agent_journeys));
 
     SimulationResult {
         population_count,
         round_agent_journeys,
+        capacity_report,
+        iteration_history,
+        cancelled,
+        realised_stop_times,
+        suppressed_counts,
+        plan_switching,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stop_geometry::StopGeometry;
+    use crate::test_utils::*;
+
+    const CAPACITY: TripCapacity = TripCapacity { seated: 50, standing: 50 };
+
+    fn crowding_functions() -> Vec<CrowdingFunc> {
+        vec![
+            CrowdingFunc::Linear,
+            CrowdingFunc::Quadratic,
+            CrowdingFunc::Power { exponent: 1.5 },
+            CrowdingFunc::Exponential { beta: 3. },
+            CrowdingFunc::Step { thresholds: vec![0.5, 0.8, 1.] },
+            CrowdingFunc::OneStep { a0: 0.25, a: 2., b: 0.5 },
+            CrowdingFunc::TwoStep { a0: 0.25, a1: 0.5, a: 5., b: 0.5, c: 0.02 },
+            CrowdingFunc::SeatedStanding { seated_cost: 0.2, standing_cost: 1., crush_cost: 3., crush_load_factor: 1.5 },
+        ]
+    }
+
+    #[test]
+    fn crowding_functions_are_non_decreasing() {
+        for crowding_function in crowding_functions() {
+            crowding_function.validate().unwrap();
+            let costs = (0..=2 * CAPACITY.total()).map(|count| crowding_function.crowding_cost(CAPACITY, count)).collect_vec();
+            assert!(costs.iter().all(|cost| cost.is_finite()), "{} cost is not finite", crowding_function.get_name());
+            for (count, w) in costs.windows(2).enumerate() {
+                assert!(w[0] <= w[1], "{} cost decreases from {} to {} at count {}", crowding_function.get_name(), w[0], w[1], count + 1);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_trip_has_no_crowding_cost() {
+        for crowding_function in crowding_functions() {
+            assert_eq!(crowding_function.crowding_cost(CAPACITY, 0), 0., "{} cost of an empty trip", crowding_function.get_name());
+        }
+    }
+
+    #[test]
+    fn normalised_functions_cost_one_at_capacity() {
+        let normalised = [
+            CrowdingFunc::Linear,
+            CrowdingFunc::Quadratic,
+            CrowdingFunc::Power { exponent: 0.5 },
+            CrowdingFunc::Power { exponent: 3. },
+            CrowdingFunc::Exponential { beta: 0.1 },
+            CrowdingFunc::Exponential { beta: 3. },
+            // Large enough to overflow if evaluated directly.
+            CrowdingFunc::Exponential { beta: 1000. },
+            CrowdingFunc::Step { thresholds: vec![0.5, 0.8, 1.] },
+        ];
+        for crowding_function in normalised {
+            let cost = crowding_function.crowding_cost(CAPACITY, CAPACITY.total());
+            assert!((cost - 1.).abs() < 1e-5, "{} cost at capacity is {cost}", crowding_function.get_name());
+        }
+    }
+
+    #[test]
+    fn exponential_is_well_behaved_for_any_beta() {
+        for beta in [1e-3, 0.5, 1., 5., 20., 50., 200., 1e4] {
+            let crowding_function = CrowdingFunc::Exponential { beta };
+            crowding_function.validate().unwrap();
+            // Load factors from 0 to 3.
+            let costs = (0..=3 * CAPACITY.total()).map(|count| crowding_function.crowding_cost(CAPACITY, count)).collect_vec();
+            assert!(costs.iter().all(|&cost| cost.is_finite() && cost >= 0.), "beta {beta} gives a cost that isn't finite and non-negative");
+            for (count, w) in costs.windows(2).enumerate() {
+                assert!(w[0] <= w[1], "beta {beta} cost decreases from {} to {} at count {}", w[0], w[1], count + 1);
+            }
+        }
+        for beta in [0., -1., CrowdingCost::NAN] {
+            assert!(CrowdingFunc::Exponential { beta }.validate().is_err(), "beta {beta} should be rejected");
+        }
+    }
+
+    #[test]
+    fn seated_standing_passes_through_its_costs() {
+        let crowding_function = CrowdingFunc::SeatedStanding { seated_cost: 0.2, standing_cost: 1., crush_cost: 3., crush_load_factor: 1.5 };
+        assert!((crowding_function.crowding_cost(CAPACITY, CAPACITY.seated) - 0.2).abs() < 1e-5);
+        assert!((crowding_function.crowding_cost(CAPACITY, CAPACITY.total()) - 1.).abs() < 1e-5);
+        assert!((crowding_function.crowding_cost(CAPACITY, 150) - 3.).abs() < 1e-5);
+    }
+
+    #[test]
+    fn load_factors_between_whole_counts_are_not_rounded() {
+        // On a trip for four agents, a load factor of 0.3 is between one and two of them.
+        let small = TripCapacity { seated: 3, standing: 1 };
+        let cost = |crowding_function: &CrowdingFunc, load_factor| crowding_function.cost_for_load_factor(small, load_factor, Overcapacity::Continue);
+        assert!((cost(&CrowdingFunc::Linear, 0.3) - 0.3).abs() < 1e-6);
+        assert!((cost(&CrowdingFunc::Quadratic, 0.3) - 0.09).abs() < 1e-6);
+        // 1.2 of the 3 seats are taken.
+        let seated_standing = CrowdingFunc::SeatedStanding { seated_cost: 0.2, standing_cost: 1., crush_cost: 3., crush_load_factor: 1.5 };
+        assert!((cost(&seated_standing, 0.3) - 0.08).abs() < 1e-6);
+        for crowding_function in crowding_functions() {
+            let (below, between, above) = (cost(&crowding_function, 0.25), cost(&crowding_function, 0.3), cost(&crowding_function, 0.5));
+            assert!(below <= between && between <= above, "{} costs {below}, {between} and {above} are out of order", crowding_function.get_name());
+        }
+
+        // Overcapacity is applied to the load factor too.
+        assert!((CrowdingFunc::Linear.cost_for_load_factor(small, 1.1, Overcapacity::Clamp) - 1.).abs() < 1e-6);
+        assert!((CrowdingFunc::Linear.cost_for_load_factor(small, 1.1, Overcapacity::Continue) - 1.1).abs() < 1e-6);
+    }
+
+    // Averages the loads of each round as run_simulation does, returning the averaged load after each round.
+    fn averaged_loads(step_size: StepSize, round_loads: &[CrowdingCost]) -> Vec<CrowdingCost> {
+        let mut averaged = round_loads[0];
+        let mut history = vec![averaged];
+        for (round_number, &load) in round_loads.iter().enumerate().skip(1) {
+            averaged += step_size.get_step_size(round_number as u16) * (load - averaged);
+            history.push(averaged);
+        }
+        history
+    }
+
+    #[test]
+    fn step_sizes() {
+        assert_eq!(StepSize::default().get_step_size(3), 1.);
+        assert_eq!(StepSize::SuccessiveAverages.get_step_size(1), 0.5);
+        assert_eq!(StepSize::SuccessiveAverages.get_step_size(3), 0.25);
+        assert_eq!(StepSize::Constant(0.3).get_step_size(7), 0.3);
+    }
+
+    #[test]
+    fn full_step_keeps_the_newest_round() {
+        let round_loads = [10., 30., 20., 40.];
+        assert_eq!(averaged_loads(StepSize::Full, &round_loads), round_loads);
+    }
+
+    #[test]
+    fn successive_averages_converge_on_oscillating_loads() {
+        // Agents that all switch between two trips each round, which a full step never settles.
+        let round_loads = (0..100).map(|round| if round % 2 == 0 { 100. } else { 0. }).collect_vec();
+        let full = averaged_loads(StepSize::Full, &round_loads);
+        assert!(full.windows(2).all(|w| (w[1] - w[0]).abs() == 100.));
+
+        let averaged = averaged_loads(StepSize::SuccessiveAverages, &round_loads);
+        for (round_number, load) in averaged.iter().enumerate() {
+            // Every round is weighted equally, so this is the mean of the rounds so far.
+            let mean = round_loads[..=round_number].iter().sum::<CrowdingCost>() / (round_number + 1) as CrowdingCost;
+            assert!((load - mean).abs() < 1e-2, "round {round_number}: {load} != {mean}");
+        }
+        assert!((averaged.last().unwrap() - 50.).abs() < 1e-2);
+        assert!(averaged.windows(2).skip(10).all(|w| (w[1] - w[0]).abs() < 10.));
+    }
+
+    #[test]
+    fn constant_step_converges_geometrically() {
+        let averaged = averaged_loads(StepSize::Constant(0.5), &[0., 80., 80., 80., 80.]);
+        assert_eq!(averaged, [0., 40., 60., 70., 75.]);
+    }
+
+    #[test]
+    fn invalid_parameters_are_rejected() {
+        let invalid = [
+            CrowdingFunc::Power { exponent: 0. },
+            CrowdingFunc::Exponential { beta: -1. },
+            CrowdingFunc::Exponential { beta: CrowdingCost::NAN },
+            CrowdingFunc::Step { thresholds: Vec::new() },
+            CrowdingFunc::Step { thresholds: vec![0.8, 0.5] },
+            CrowdingFunc::OneStep { a0: 0., a: 2., b: 0.5 },
+            CrowdingFunc::TwoStep { a0: 0.5, a1: 0.25, a: 5., b: 0.5, c: 0.02 },
+            CrowdingFunc::SeatedStanding { seated_cost: 1., standing_cost: 0.5, crush_cost: 3., crush_load_factor: 1.5 },
+            CrowdingFunc::SeatedStanding { seated_cost: 0.2, standing_cost: 1., crush_cost: 3., crush_load_factor: 1. },
+        ];
+        for crowding_function in invalid {
+            assert!(crowding_function.validate().is_err(), "{crowding_function:?} should be invalid");
+        }
+    }
+
+    #[test]
+    fn simulation_is_reproducible_on_four_threads() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 0);
+        let mut params = fixture_params(4);
+        params.replanning = Replanning { fraction: 0.5, decay: 1., seed: 0 };
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+        let run = || {
+            let result = pool.install(|| run_simulation(&network, &simulation_steps, &params));
+            let mut journeys = Vec::new();
+            crate::data_export::export_agent_journeys(&mut journeys, &network, &StopGeometry::new(&network, Some(&gtfs)), &result, true).unwrap();
+            (result.population_count, journeys)
+        };
+        let (population_count, journeys) = run();
+        assert!(population_count.iter().any(|&count| count > FIXTURE_CAPACITY.total()), "the trips should be crowded");
+        for _ in 0..3 {
+            assert!(run() == (population_count.clone(), journeys.clone()), "runs with the same inputs differ");
+        }
+    }
+
+    #[test]
+    fn simulation_does_not_depend_on_the_chunk_size() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 1);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+
+        let run = |chunk_size: Option<usize>| {
+            let mut params = fixture_params(4);
+            params.replanning = Replanning { fraction: 0.5, decay: 1., seed: 0 };
+            params.chunk_size = chunk_size;
+            let result = pool.install(|| run_simulation(&network, &simulation_steps, &params));
+            let mut journeys = Vec::new();
+            crate::data_export::export_agent_journeys(&mut journeys, &network, &StopGeometry::new(&network, Some(&gtfs)), &result, true).unwrap();
+            (result.population_count, journeys)
+        };
+        let default_chunks = run(None);
+        // From one step per chunk to every step in one chunk, with a size that doesn't divide the steps evenly.
+        for chunk_size in [1, 7, 64, simulation_steps.len()] {
+            assert!(run(Some(chunk_size)) == default_chunks, "chunks of {chunk_size} steps differ from the default chunks");
+        }
+    }
+
+    #[test]
+    fn route_choice_shares_approach_the_logit_shares() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let (origin, dest) = (stop_idx(&network, "ALP"), stop_idx(&network, "CHA"));
+        let departure_time = 7 * 3600 + 55 * 60;
+
+        // Red arrives two minutes sooner than Green, but is crowded, so both are in the Pareto bag.
+        let red_route_idx = network.routes.iter().position(|route| route.trip_ids.iter().any(|trip_id| {
+            let trip_id: &str = trip_id.as_ref();
+            trip_id.starts_with("RED")
+        })).unwrap();
+        let mut crowding_cost = vec![0 as PathfindingCost; network.stop_times.len()];
+        let red_route = &network.routes[red_route_idx];
+        for trip in 0..red_route.num_trips as usize {
+            // The cost at a stop time is for the segment arriving there, so the first stop has none.
+            for idx in red_route.get_trip_range(trip).skip(1) {
+                crowding_cost[idx] = 5000.;
+            }
+        }
+
+        let route_choice = RouteChoice { time_coefficient: 1., crowding_coefficient: 0.01, scale: 40., transfer_coefficient: 0., wait_coefficient: 0., seed: 0 };
+        // Red costs 900 seconds plus 0.01 * 10000, Green 1020 seconds.
+        let (red_utility, green_utility) = (900. + 0.01 * 10000., 1020.);
+        let red_share = 1. / (1. + ((red_utility - green_utility) / route_choice.scale as f64).exp());
+
+        const NUM_AGENTS: usize = 4000;
+        let num_red = (0..NUM_AGENTS).filter(|&sim_step_idx| {
+            let journey_preferences = route_choice.journey_preferences(1, sim_step_idx);
+            let journeys = mc_raptor_query!(5, &network, origin, departure_time, &vec![dest], &crowding_cost, &journey_preferences);
+            let journey = journeys.into_iter().next().unwrap().unwrap();
+            journey.legs[0].trip.route_idx as usize == red_route_idx
+        }).count();
+
+        // Within about four standard deviations of the binomial share.
+        let observed_share = num_red as f64 / NUM_AGENTS as f64;
+        let tolerance = 4. * (red_share * (1. - red_share) / NUM_AGENTS as f64).sqrt();
+        assert!((observed_share - red_share).abs() < tolerance, "observed Red share {observed_share}, expected {red_share}");
+    }
+
+    #[test]
+    fn route_choice_prices_transfers_and_waiting() {
+        let gtfs = load_fixture_gtfs("direct_or_change");
+        let network = build_fixture_network(&gtfs);
+        let trip_ids = |route_choice: RouteChoice| {
+            let mut params = fixture_params(1);
+            params.route_choice = Some(route_choice);
+            let result = run_simulation(&network, &[simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "DEL", 1)], &params);
+            let journey = result.round_agent_journeys[0].get(0).result.unwrap();
+            journey.legs.iter().map(|leg| network.get_trip_id(leg.trip).to_string()).collect_vec()
+        };
+
+        // Without noise, so the lowest cost journey is always taken.
+        let route_choice = RouteChoice { time_coefficient: 1., crowding_coefficient: 0.01, scale: 0., transfer_coefficient: 0., wait_coefficient: 0., seed: 0 };
+        assert_eq!(trip_ids(route_choice), ["FAST_0805", "LINK_0820"]);
+        // The change saves ten minutes, less than a transfer is worth.
+        assert_eq!(trip_ids(RouteChoice { transfer_coefficient: 900., ..route_choice }), ["SLOW_0800"]);
+        // The change waits a quarter of an hour (at Alpha and Charlie) and Slow five minutes, at twice the weight of the saving.
+        assert_eq!(trip_ids(RouteChoice { wait_coefficient: 2., ..route_choice }), ["SLOW_0800"]);
+        assert_eq!(trip_ids(RouteChoice { wait_coefficient: 0.5, ..route_choice }), ["FAST_0805", "LINK_0820"]);
+    }
+
+    #[test]
+    fn transfer_penalties_avoid_transfers_and_waiting() {
+        let gtfs = load_fixture_gtfs("direct_or_change");
+        let network = build_fixture_network(&gtfs);
+        let journey = |transfer_penalty: CrowdingCost, wait_time_weight: CrowdingCost| {
+            let mut params = fixture_params(1);
+            params.transfer_penalties = TransferPenalties { transfer_penalty, wait_time_weight, ..params.transfer_penalties };
+            let result = run_simulation(&network, &[simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "DEL", 1)], &params);
+            let journey = result.round_agent_journeys[0].get(0).result.unwrap();
+            (journey.legs.iter().map(|leg| network.get_trip_id(leg.trip).to_string()).collect_vec(), journey.num_transfers)
+        };
+
+        assert_eq!(journey(0., 0.), (vec!["FAST_0805".to_string(), "LINK_0820".to_string()], 1));
+        // The change saves ten minutes, less than a transfer is worth.
+        assert_eq!(journey(900., 0.), (vec!["SLOW_0800".to_string()], 0));
+        // The change waits a quarter of an hour (at Alpha and Charlie) and Slow five minutes.
+        assert_eq!(journey(0., 2.), (vec!["SLOW_0800".to_string()], 0));
+        assert_eq!(journey(0., 0.5).1, 1);
+    }
+
+    #[test]
+    fn zero_transfer_penalties_reproduce_the_journeys_without_them() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 2);
+
+        let run = |transfer_penalties: Option<TransferPenalties>| {
+            let mut params = fixture_params(4);
+            params.replanning = Replanning { fraction: 0.5, decay: 1., seed: 0 };
+            if let Some(transfer_penalties) = transfer_penalties {
+                params.transfer_penalties = transfer_penalties;
+            }
+            let result = run_simulation(&network, &simulation_steps, &params);
+            let mut journeys = Vec::new();
+            crate::data_export::export_agent_journeys(&mut journeys, &network, &StopGeometry::new(&network, Some(&gtfs)), &result, true).unwrap();
+            (result.population_count, journeys)
+        };
+        let without_penalties = run(None);
+        assert!(without_penalties.0.iter().any(|&count| count > FIXTURE_CAPACITY.total()), "the trips should be crowded");
+        // The crowding weight only matters alongside a penalty.
+        let zero_penalties = TransferPenalties { transfer_penalty: 0., wait_time_weight: 0., crowding_weight: 3. };
+        assert!(run(Some(zero_penalties)) == without_penalties, "zero transfer penalties changed the journeys");
+    }
+
+    #[test]
+    fn iteration_history_has_a_row_per_round() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 0);
+        let result = run_simulation(&network, &simulation_steps, &fixture_params(5));
+
+        assert_eq!(result.iteration_history.iter().map(|stats| stats.round_number).collect_vec(), (0..5).collect_vec());
+        assert_eq!(result.round_agent_journeys.len(), 5);
+        // Round 0 has nothing to compare with.
+        assert!(result.iteration_history[0].relative_change.is_none() && result.iteration_history[0].num_changed_route.is_none());
+        assert!(result.iteration_history[1..].iter().all(|stats| stats.relative_change.is_some() && stats.num_changed_route.is_some()));
+        assert!(result.iteration_history.iter().all(|stats| stats.total_passenger_hours > 0. && stats.max_segment_load > 0));
+    }
+
+    #[test]
+    fn converged_assignment_stops_early() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        // Too few agents for waiting for the next Red trip to be better, so the loads are the same every round.
+        let simulation_steps = vec![simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "BRA", 3)];
+
+        for (convergence_rounds, expected_rounds) in [(1, 2), (3, 4)] {
+            let mut params = fixture_params(10);
+            params.convergence_tolerance = Some(0.01);
+            params.convergence_rounds = convergence_rounds;
+            let result = run_simulation(&network, &simulation_steps, &params);
+            assert_eq!(result.iteration_history.len(), expected_rounds, "converging for {convergence_rounds} rounds");
+            assert_eq!(result.round_agent_journeys.len(), expected_rounds);
+            assert!(result.iteration_history[1..].iter().all(|stats| stats.relative_change == Some(0.) && stats.num_changed_route == Some(0)));
+        }
+    }
+
+    #[test]
+    fn parallel_counts_match_the_journeys() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut rng = SmallRng::seed_from_u64(53);
+        let simulation_steps = (0..5000).map(|i| {
+            let dest = if i % 2 == 0 { "BRA" } else { "CHA" };
+            simulation_step(&network, rng.gen_range((7 * 3600 + 50 * 60)..(8 * 3600 + 30 * 60)), "ALP", dest, rng.gen_range(1..=10))
+        }).collect_vec();
+        let mut params = fixture_params(1);
+        // One step per chunk, so every chunk's loads are merged into the few Red and Green segments.
+        params.chunk_size = Some(1);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(16).build().unwrap();
+        let result = pool.install(|| run_simulation(&network, &simulation_steps, &params));
+
+        let mut num_boarded = 0;
+        let mut num_segments_travelled = 0;
+        for agent_journey in &result.round_agent_journeys[0] {
+            let journey = agent_journey.result.unwrap();
+            num_boarded += agent_journey.count as i64;
+            for leg in journey.legs {
+                num_segments_travelled += agent_journey.count as i64 * (leg.arrival_stop_order - leg.boarded_stop_order) as i64;
+            }
+        }
+        assert_eq!(num_boarded, simulation_steps.iter().map(|step| step.count() as i64).sum::<i64>());
+        assert_eq!(result.population_count.iter().map(|&count| count as i64).sum::<i64>(), num_segments_travelled);
+    }
+
+    #[test]
+    fn party_is_denied_boarding_when_only_part_of_it_fits() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        // 25 agents take RED_0800 from Alpha, leaving room for 5 of the party of 10 that arrives after them.
+        let mut party_step = SimulationStep::new(7 * 3600 + 56 * 60, stop_idx(&network, "ALP"));
+        party_step.push_parties(stop_idx(&network, "BRA"), 10, 1);
+        let simulation_steps = vec![simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "BRA", 25), party_step];
+        let mut params = fixture_params(1);
+        params.strict_capacity = true;
+        let result = run_simulation(&network, &simulation_steps, &params);
+
+        let trip_of = |leg: &Leg| (leg.trip.route_idx as usize, leg.trip.trip_order as usize);
+        let capacity_report = result.capacity_report.as_ref().unwrap();
+        assert_eq!(capacity_report.denied_boardings.len(), 1);
+        let denied = &capacity_report.denied_boardings[0];
+        assert_eq!((denied.agent_idx, denied.stop, denied.count), (1, stop_idx(&network, "ALP"), 10));
+        assert_eq!((denied.trip.route_idx as usize, denied.trip.trip_order as usize), trip_position(&network, "RED_0800"));
+
+        // The whole party waits for the next train rather than splitting.
+        let agent_journeys = &result.round_agent_journeys[0];
+        assert_eq!(trip_of(&agent_journeys.get(0).result.unwrap().legs[0]), trip_position(&network, "RED_0800"));
+        assert_eq!(trip_of(&agent_journeys.get(1).result.unwrap().legs[0]), trip_position(&network, "RED_0815"));
+        for (trip_id, load) in [("RED_0800", 25), ("RED_0815", 10)] {
+            let (route_idx, trip) = trip_position(&network, trip_id);
+            assert_eq!(result.population_count[network.routes[route_idx].get_trip_range(trip).start], load, "load departing Alpha on {trip_id}");
+        }
+    }
+
+    // Runs rounds as run_simulation does, but with the averaged loads accumulated in the given float type, until the relative
+    // load gap is below the tolerance (or 200 rounds). Returns the averaged loads and the number of rounds run.
+    macro_rules! converged_loads {
+        ($float:ty, $network:expr, $simulation_steps:expr, $params:expr, $tolerance:expr) => {{
+            let (network, simulation_steps, params) = ($network, $simulation_steps, $params);
+            let mut averaged: Vec<$float> = Vec::new();
+            let mut crowding_cost: Option<Vec<CrowdingCost>> = None;
+            let mut last_round: Option<SimulationRoundResult> = None;
+            let mut num_rounds = 0;
+            loop {
+                let mut round = run_simulation_round(network, simulation_steps, params, crowding_cost.as_deref(), last_round.as_ref().map(|round| round.agent_journeys.as_slice()), None, num_rounds);
+                let relative_load_gap = if averaged.is_empty() {
+                    averaged = round.population_count.iter().map(|&count| count as $float).collect();
+                    None
+                } else {
+                    let step_size = params.get_step_size(num_rounds) as $float;
+                    let (mut total_change, mut total_load) = (0., 0.);
+                    for (averaged, &count) in averaged.iter_mut().zip(&round.population_count) {
+                        let change = step_size * (count as $float - *averaged);
+                        total_change += change.abs() as f64;
+                        total_load += *averaged as f64;
+                        *averaged += change;
+                    }
+                    Some(total_change / total_load)
+                };
+                let population_count = averaged.iter().map(|&count| count.round() as PopulationCount).collect_vec();
+                let next_crowding_cost = calculate_crowding_cost(network, params, &population_count);
+                for journey in round.agent_journeys.iter_mut().filter_map(|agent_journey| agent_journey.result.as_mut().ok()) {
+                    journey.experienced_crowding_cost = journey.legs.iter().map(|leg| leg_crowding_cost(network, &next_crowding_cost, leg)).sum();
+                }
+                crowding_cost = Some(next_crowding_cost);
+                last_round = Some(round);
+                num_rounds += 1;
+                if relative_load_gap.is_some_and(|gap| gap < $tolerance) || num_rounds == 200 {
+                    break;
+                }
+            }
+            (averaged.iter().map(|&count| count as f64).collect_vec(), num_rounds)
+        }};
+    }
+
+    #[test]
+    fn converged_loads_agree_between_precisions() {
+        const TOLERANCE: f64 = 0.02;
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let simulation_steps = morning_peak_steps(&network, 300, 2);
+        let mut params = fixture_params(200);
+        params.step_size = StepSize::SuccessiveAverages;
+        params.convergence_tolerance = None;
+
+        let (f32_loads, f32_rounds) = converged_loads!(f32, &network, &simulation_steps, &params, TOLERANCE);
+        let (f64_loads, f64_rounds) = converged_loads!(f64, &network, &simulation_steps, &params, TOLERANCE);
+        assert!(f32_rounds < 200 && f64_rounds < 200, "f32 ran {f32_rounds} rounds and f64 {f64_rounds} without converging");
+
+        // The loop above is run_simulation's, so running as many rounds gives the loads of the compiled precision.
+        let (compiled_loads, compiled_rounds) = if cfg!(feature = "f64_crowding_cost") { (&f64_loads, f64_rounds) } else { (&f32_loads, f32_rounds) };
+        params.num_rounds = compiled_rounds;
+        let result = run_simulation(&network, &simulation_steps, &params);
+        assert_eq!(result.population_count, compiled_loads.iter().map(|&count| count.round() as PopulationCount).collect_vec());
+
+        // Converged separately, the loads differ by no more than the tolerance they converged to.
+        let total = f64_loads.iter().sum::<f64>();
+        let difference = f32_loads.iter().zip(&f64_loads).map(|(a, b)| (a - b).abs()).sum::<f64>();
+        assert!(total > 0.);
+        assert!(difference <= TOLERANCE * total, "f32 and f64 converged loads differ by {difference} of {total}");
+    }
+
+    #[test]
+    fn agent_after_midnight_boards_the_after_midnight_trip() {
+        let gtfs = load_fixture_gtfs("after_midnight");
+        let network = build_fixture_network(&gtfs);
+        // The trip keeps its times past 24:00:00 on the service day.
+        let (route_idx, trip) = trip_position(&network, "SHT_2440");
+        assert_eq!(network.get_departure_time(route_idx, trip, 0), 24 * 3600 + 40 * 60);
+
+        // Departing at 24:30, after SHT_2300 has gone, the agent takes SHT_2440 ten minutes later.
+        let result = run_simulation(&network, &[simulation_step(&network, 24 * 3600 + 30 * 60, "WST", "EST", 1)], &fixture_params(1));
+        let journey = result.round_agent_journeys[0].get(0).result.unwrap();
+        assert_eq!(journey.legs.len(), 1);
+        assert_eq!((journey.legs[0].trip.route_idx as usize, journey.legs[0].trip.trip_order as usize), (route_idx, trip));
+        assert_eq!(journey.legs[0].arrival_time, 25 * 3600 + 10 * 60);
+        assert_eq!(result.population_count[network.routes[route_idx].get_trip_range(trip).start], 1);
     }
 }
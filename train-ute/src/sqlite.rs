@@ -0,0 +1,275 @@
+use itertools::izip;
+use raptor::network::Timestamp;
+use rusqlite::{params, Connection};
+
+use crate::data_export::{aggregate_stop_activity, DataExportError};
+use crate::exporter::{ExportContext, Exporter};
+
+// File name of the database written into the export folder.
+pub const SQLITE_FILE_NAME: &str = "results.sqlite";
+
+const SCHEMA: &str = "
+CREATE TABLE runs (
+    run_id INTEGER PRIMARY KEY,
+    crate_version TEXT NOT NULL,
+    date TEXT NOT NULL,
+    num_agents INTEGER NOT NULL,
+    num_rounds INTEGER NOT NULL,
+    partial INTEGER NOT NULL,
+    total_crowding_cost REAL,
+    total_passenger_hours REAL
+);
+CREATE TABLE stops (
+    stop_idx INTEGER PRIMARY KEY,
+    stop_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    latitude REAL NOT NULL,
+    longitude REAL NOT NULL
+);
+CREATE TABLE trips (
+    trip_idx INTEGER PRIMARY KEY,
+    trip_id TEXT NOT NULL,
+    route_id TEXT NOT NULL,
+    departure_time INTEGER NOT NULL,
+    seated_capacity INTEGER NOT NULL,
+    standing_capacity INTEGER NOT NULL
+);
+CREATE TABLE segment_loads (
+    trip_idx INTEGER NOT NULL,
+    stop_order INTEGER NOT NULL,
+    from_stop_idx INTEGER NOT NULL,
+    to_stop_idx INTEGER NOT NULL,
+    departure_time INTEGER NOT NULL,
+    arrival_time INTEGER NOT NULL,
+    load INTEGER NOT NULL,
+    load_factor REAL NOT NULL,
+    PRIMARY KEY (trip_idx, stop_order)
+);
+CREATE TABLE stop_boardings (
+    stop_idx INTEGER PRIMARY KEY,
+    boardings INTEGER NOT NULL,
+    alightings INTEGER NOT NULL,
+    transfers_in INTEGER NOT NULL,
+    transfers_out INTEGER NOT NULL
+);
+";
+
+const JOURNEYS_SCHEMA: &str = "
+CREATE TABLE agent_journeys (
+    agent_idx INTEGER PRIMARY KEY,
+    origin_stop_idx INTEGER NOT NULL,
+    dest_stop_idx INTEGER NOT NULL,
+    start_time INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    segment INTEGER NOT NULL,
+    duration INTEGER,
+    crowding_cost REAL,
+    num_transfers INTEGER,
+    first_trip_idx INTEGER
+);
+";
+
+// Created after the rows are inserted, which is faster than keeping them up to date.
+const INDICES: &str = "
+CREATE INDEX trips_route_id ON trips (route_id);
+CREATE INDEX trips_trip_id ON trips (trip_id);
+CREATE INDEX segment_loads_from_stop ON segment_loads (from_stop_idx);
+CREATE INDEX segment_loads_to_stop ON segment_loads (to_stop_idx);
+";
+
+const JOURNEYS_INDICES: &str = "
+CREATE INDEX agent_journeys_origin_stop ON agent_journeys (origin_stop_idx);
+CREATE INDEX agent_journeys_dest_stop ON agent_journeys (dest_stop_idx);
+CREATE INDEX agent_journeys_first_trip ON agent_journeys (first_trip_idx);
+";
+
+// Writes the results to results.sqlite, for querying with SQL. Trips are numbered in network order, and journeys are
+// the final round's. Stops and trips are joined by stop_idx and trip_idx, with the GTFS ids in the stops and trips tables.
+pub struct SqliteExporter {
+    // Also write the (large) agent_journeys table.
+    pub journeys: bool,
+}
+
+impl Exporter for SqliteExporter {
+    fn name(&self) -> &str { "sqlite" }
+
+    fn export(&self, ctx: &ExportContext) -> Result<(), DataExportError> {
+        let path = ctx.output_dir.join(SQLITE_FILE_NAME);
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        let mut connection = Connection::open(&path)?;
+        // The database is written once from scratch, so a crash can only lose the export, not corrupt other data.
+        connection.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+        connection.execute_batch(SCHEMA)?;
+        if self.journeys {
+            connection.execute_batch(JOURNEYS_SCHEMA)?;
+        }
+
+        let network = ctx.network;
+        let simulation_result = ctx.simulation_result;
+        let agent_journeys = simulation_result.round_agent_journeys.last();
+
+        let tx = connection.transaction()?;
+        {
+            let last_round = simulation_result.iteration_history.last();
+            let num_agents = agent_journeys.into_iter().flatten().map(|agent_journey| agent_journey.count as i64).sum::<i64>();
+            tx.execute("INSERT INTO runs VALUES (1, ?1, ?2, ?3, ?4, ?5, ?6, ?7)", params![
+                env!("CARGO_PKG_VERSION"),
+                ctx.date.to_string(),
+                num_agents,
+                simulation_result.iteration_history.len() as i64,
+                simulation_result.cancelled,
+                last_round.map(|stats| stats.total_crowding_cost),
+                last_round.map(|stats| stats.total_passenger_hours),
+            ])?;
+        }
+        tx.commit()?;
+
+        let tx = connection.transaction()?;
+        {
+            let mut insert = tx.prepare("INSERT INTO stops VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            for (stop_idx, (stop, location, name)) in izip!(network.stops.iter(), &ctx.stop_geometry.points, &ctx.stop_geometry.names).enumerate() {
+                insert.execute(params![stop_idx as i64, &stop.id[..], &name[..], location.latitude as f64, location.longitude as f64])?;
+            }
+        }
+        tx.commit()?;
+
+        let tx = connection.transaction()?;
+        {
+            let mut insert = tx.prepare("INSERT INTO trips VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            let mut trip_idx = 0i64;
+            for (route_idx, route) in network.routes.iter().enumerate() {
+                for trip in 0..route.num_trips as usize {
+                    let trip_id: &str = route.trip_ids[trip].as_ref();
+                    let route_id = ctx.gtfs.trips.get(trip_id).map(|trip| trip.route_id.as_str()).unwrap_or_default();
+                    let capacity = ctx.trip_capacities.get(trip_id);
+                    insert.execute(params![trip_idx, trip_id, route_id, network.get_departure_time(route_idx, trip, 0), capacity.seated, capacity.standing])?;
+                    trip_idx += 1;
+                }
+            }
+        }
+        tx.commit()?;
+
+        let tx = connection.transaction()?;
+        {
+            let mut insert = tx.prepare("INSERT INTO segment_loads VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?;
+            let mut trip_idx = 0i64;
+            for (route_idx, route) in network.routes.iter().enumerate() {
+                let stops = route.get_stops(&network.route_stops);
+                for trip in 0..route.num_trips as usize {
+                    let capacity = ctx.trip_capacities.get(&route.trip_ids[trip]).total() as f64;
+                    // The count at each stop is the load on the segment departing that stop.
+                    let trip_counts = &simulation_result.population_count[route.get_trip_range(trip)];
+                    for (stop_order, (stops, &count)) in stops.windows(2).zip(trip_counts).enumerate() {
+                        insert.execute(params![
+                            trip_idx,
+                            stop_order as i64,
+                            stops[0] as i64,
+                            stops[1] as i64,
+                            network.get_departure_time(route_idx, trip, stop_order),
+                            network.get_arrival_time(route_idx, trip, stop_order + 1),
+                            count,
+                            count as f64 / capacity,
+                        ])?;
+                    }
+                    trip_idx += 1;
+                }
+            }
+        }
+        tx.commit()?;
+
+        let tx = connection.transaction()?;
+        {
+            let mut insert = tx.prepare("INSERT INTO stop_boardings VALUES (?1, ?2, ?3, ?4, ?5)")?;
+            // A single time bin covers the whole day.
+            for ((stop_idx, _), activity) in aggregate_stop_activity(simulation_result, Timestamp::MAX) {
+                insert.execute(params![stop_idx as i64, activity.boardings as i64, activity.alightings as i64, activity.transfers_in as i64, activity.transfers_out as i64])?;
+            }
+        }
+        tx.commit()?;
+
+        if let (true, Some(agent_journeys)) = (self.journeys, agent_journeys) {
+            // Trip indices are in network order, so each route's trips start after the trips of the routes before it.
+            let route_trip_offsets = network.routes.iter().scan(0i64, |offset, route| {
+                let route_offset = *offset;
+                *offset += route.num_trips as i64;
+                Some(route_offset)
+            }).collect::<Vec<_>>();
+
+            let tx = connection.transaction()?;
+            {
+                let mut insert = tx.prepare("INSERT INTO agent_journeys VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)")?;
+                for (agent_idx, agent_journey) in agent_journeys.iter().enumerate() {
+                    let journey = agent_journey.result.ok();
+                    insert.execute(params![
+                        agent_idx as i64,
+                        agent_journey.origin_stop as i64,
+                        agent_journey.dest_stop as i64,
+                        agent_journey.start_time,
+                        agent_journey.count,
+                        agent_journey.segment,
+                        journey.map(|journey| journey.duration),
+                        journey.map(|journey| journey.crowding_cost as f64),
+                        journey.map(|journey| journey.num_transfers),
+                        journey.map(|journey| route_trip_offsets[journey.origin_trip.route_idx as usize] + journey.origin_trip.trip_order as i64),
+                    ])?;
+                }
+            }
+            tx.commit()?;
+            connection.execute_batch(JOURNEYS_INDICES)?;
+        }
+
+        connection.execute_batch(INDICES)?;
+        connection.close().map_err(|(_, err)| err)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_simulation;
+    use crate::simulation::TripCapacities;
+    use crate::stop_geometry::StopGeometry;
+    use crate::test_utils::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn exported_database_can_be_queried() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        // Three agents from Alpha change to the Blue line at Charlie for Delta.
+        let simulation_steps = vec![simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "DEL", 3)];
+        let simulation_result = run_simulation(&network, &simulation_steps, &fixture_params(1));
+        let trip_capacities = TripCapacities::new(FIXTURE_CAPACITY, HashMap::new());
+        let output_dir = temp_path("sqlite");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        let ctx = ExportContext {
+            network: &network,
+            stop_geometry: &stop_geometry,
+            gtfs: &gtfs,
+            simulation_result: &simulation_result,
+            trip_capacities: &trip_capacities,
+            segment_costs: &[],
+            parent_stations: None,
+            date: fixture_date(),
+            output_dir: &output_dir,
+        };
+
+        let query = |journeys: bool, sql: &str| -> i64 {
+            SqliteExporter { journeys }.export(&ctx).unwrap();
+            let connection = Connection::open(output_dir.join(SQLITE_FILE_NAME)).unwrap();
+            connection.query_row(sql, [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(query(true, "SELECT num_agents FROM runs"), 3);
+        assert_eq!(query(true, "SELECT COUNT(*) FROM stops"), 5);
+        assert_eq!(query(true, "SELECT COUNT(*) FROM trips"), 8);
+        assert_eq!(query(true, "SELECT boardings FROM stop_boardings JOIN stops USING (stop_idx) WHERE stop_id = 'ALP'"), 3);
+        assert_eq!(query(true, "SELECT load FROM segment_loads JOIN trips USING (trip_idx) WHERE trip_id = 'BLUE_0815' AND stop_order = 0"), 3);
+        assert_eq!(query(true, "SELECT SUM(count) FROM agent_journeys JOIN trips ON first_trip_idx = trip_idx WHERE trip_id IN ('RED_0800', 'GREEN_0800')"), 3);
+        assert_eq!(query(false, "SELECT COUNT(*) FROM sqlite_master WHERE name = 'agent_journeys'"), 0);
+        std::fs::remove_dir_all(&output_dir).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+// The position, name and parent station of each of the network's stops, built once per run and shared by the exporters,
+// so they don't each look stops up in the feed.
+//
+// Stops the feed gives no coordinates are placed once, here, so every export draws them in the same place:
+// - At the mean position of their neighbours along the network's routes (the stops just before and after them on any
+//   route) that have coordinates.
+// - Failing that, at the mean position of all the stops with coordinates, so they're at least inside the network.
+// - If no stop has coordinates, where the network put them.
+
+use gtfs_structures::Gtfs;
+use itertools::Itertools;
+use raptor::network::{CoordType, NetworkPoint, StopIndex};
+use raptor::Network;
+
+pub struct StopGeometry {
+    // By stop index, like the network's stops.
+    pub points: Vec<NetworkPoint>,
+    pub names: Vec<String>,
+    // The stop's parent_station in the feed, if it has one.
+    pub parent_stations: Vec<Option<String>>,
+    // Stops without coordinates in the feed, which were placed by the fallback above (sorted).
+    pub placed_stops: Vec<StopIndex>,
+}
+
+// Mean latitude and longitude of some points, or None if there aren't any.
+fn mean_point(points: impl Iterator<Item=NetworkPoint>) -> Option<(CoordType, CoordType)> {
+    let (latitude, longitude, num_points) = points.fold((0., 0., 0), |(latitude, longitude, num_points), point| {
+        (latitude + point.latitude as f64, longitude + point.longitude as f64, num_points + 1)
+    });
+    (num_points > 0).then(|| ((latitude / num_points as f64) as CoordType, (longitude / num_points as f64) as CoordType))
+}
+
+impl StopGeometry {
+    // Without the feed, every stop is taken to have coordinates and none have a parent station.
+    pub fn new(network: &Network, gtfs: Option<&Gtfs>) -> Self {
+        let gtfs_stop = |stop_idx: usize| {
+            let stop_id: &str = network.stops[stop_idx].id.as_ref();
+            gtfs?.stops.get(stop_id)
+        };
+        let num_stops = network.stops.len();
+        let names = network.stops.iter().map(|stop| stop.name.to_string()).collect_vec();
+        let parent_stations = (0..num_stops).map(|stop_idx| {
+            gtfs_stop(stop_idx)?.parent_station.clone().filter(|parent_station| !parent_station.is_empty())
+        }).collect_vec();
+        let has_coordinates = (0..num_stops).map(|stop_idx| {
+            !gtfs_stop(stop_idx).is_some_and(|stop| stop.latitude.is_none() || stop.longitude.is_none())
+        }).collect_vec();
+
+        let mut points = network.stop_points.clone();
+        let placed_stops = (0..num_stops).filter(|&stop_idx| !has_coordinates[stop_idx]).map(|stop_idx| stop_idx as StopIndex).collect_vec();
+        let network_mean = mean_point((0..num_stops).filter(|&stop_idx| has_coordinates[stop_idx]).map(|stop_idx| network.stop_points[stop_idx]));
+        if !placed_stops.is_empty() && network_mean.is_some() {
+            let mut neighbours = vec![Vec::new(); num_stops];
+            for route in network.routes.iter() {
+                for (&dep_stop, &arr_stop) in route.get_stops(&network.route_stops).iter().tuple_windows() {
+                    neighbours[dep_stop as usize].push(arr_stop as usize);
+                    neighbours[arr_stop as usize].push(dep_stop as usize);
+                }
+            }
+            for &stop in placed_stops.iter() {
+                let stop_neighbours = neighbours[stop as usize].iter().copied().filter(|&neighbour| has_coordinates[neighbour]).sorted_unstable().dedup();
+                let (latitude, longitude) = mean_point(stop_neighbours.map(|neighbour| network.stop_points[neighbour])).or(network_mean).unwrap();
+                let point = &mut points[stop as usize];
+                point.latitude = latitude;
+                point.longitude = longitude;
+            }
+        }
+
+        Self { points, names, parent_stations, placed_stops }
+    }
+
+    pub fn num_stops(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn point(&self, stop: StopIndex) -> NetworkPoint {
+        self.points[stop as usize]
+    }
+
+    pub fn name(&self, stop: StopIndex) -> &str {
+        &self.names[stop as usize]
+    }
+
+    pub fn parent_station(&self, stop: StopIndex) -> Option<&str> {
+        self.parent_stations[stop as usize].as_deref()
+    }
+
+    pub fn log(&self) {
+        if !self.placed_stops.is_empty() {
+            let names = self.placed_stops.iter().take(5).map(|&stop| self.name(stop)).join(", ");
+            log::warn!("{} stops have no coordinates in the feed and were placed near the stops around them ({names}{}).", self.placed_stops.len(), if self.placed_stops.len() > 5 { ", ..." } else { "" });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+
+    fn assert_point(point: NetworkPoint, latitude: f64, longitude: f64) {
+        assert!((point.latitude as f64 - latitude).abs() < 1e-4 && (point.longitude as f64 - longitude).abs() < 1e-4, "{} {} isn't {latitude} {longitude}", point.latitude, point.longitude);
+    }
+
+    #[test]
+    fn stops_without_coordinates_are_placed_between_their_neighbours() {
+        let mut gtfs = load_fixture_gtfs("no_coordinates");
+        let network = build_fixture_network(&gtfs);
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        let charlie = stop_idx(&network, "CHA");
+        assert_eq!(stop_geometry.placed_stops, [charlie]);
+        // Charlie is after Bravo on the Red line, after Alpha on the Green line and before Delta on the Blue line.
+        assert_point(stop_geometry.point(charlie), (-37.8 - 37.8 - 37.81) / 3., (144.90 + 144.91 + 144.92) / 3.);
+        assert_eq!(stop_geometry.name(charlie), "Charlie");
+        for stop_id in ["ALP", "BRA", "DEL", "ECH"] {
+            let stop = stop_idx(&network, stop_id);
+            assert_eq!((stop_geometry.point(stop).latitude, stop_geometry.point(stop).longitude), (network.stop_points[stop as usize].latitude, network.stop_points[stop as usize].longitude));
+        }
+
+        // Neighbours without coordinates don't count, and stops with none that have them are placed in the middle of the
+        // stops that do.
+        for stop_id in ["ALP", "BRA"] {
+            let stop = Arc::make_mut(gtfs.stops.get_mut(stop_id).unwrap());
+            (stop.latitude, stop.longitude) = (None, None);
+        }
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        assert_eq!(stop_geometry.placed_stops.len(), 3);
+        assert_point(stop_geometry.point(charlie), -37.81, 144.92);
+        for stop_id in ["ALP", "BRA"] {
+            assert_point(stop_geometry.point(stop_idx(&network, stop_id)), (-37.81 - 37.82) / 2., 144.92);
+        }
+
+        // Without the feed, the network's points are kept.
+        let stop_geometry = StopGeometry::new(&network, None);
+        assert!(stop_geometry.placed_stops.is_empty());
+        assert_eq!(stop_geometry.num_stops(), network.stops.len());
+    }
+
+    #[test]
+    fn stops_have_their_parent_station() {
+        let gtfs = load_fixture_gtfs("platforms");
+        let network = build_fixture_network(&gtfs);
+        let stop_geometry = StopGeometry::new(&network, Some(&gtfs));
+        assert_eq!(stop_geometry.parent_station(stop_idx(&network, "CHA_1")), Some("CHA"));
+        assert_eq!(stop_geometry.parent_station(stop_idx(&network, "CHA_2")), Some("CHA"));
+        assert_eq!(stop_geometry.parent_station(stop_idx(&network, "ALP")), None);
+        assert!(stop_geometry.placed_stops.is_empty());
+    }
+}
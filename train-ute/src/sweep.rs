@@ -0,0 +1,150 @@
+use std::path::Path;
+use std::time::Duration;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{CrowdingCost, CrowdingFunc, DefaultSimulationParams, PopulationCount, TripCapacities, TripCapacity};
+
+// Grids of simulation parameters to run every combination of on the same network and agents.
+// A parameter with an empty grid is left as configured.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct Sweep {
+    // Total capacities of the trips without one of their own, split into seated and standing like the configured default.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capacity: Vec<PopulationCount>,
+    // Multipliers of every capacity, on top of the configured capacity scale.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub capacity_scale: Vec<f64>,
+    // Betas of an exponential crowding function, replacing the configured crowding function.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub beta: Vec<f64>,
+}
+
+// One combination of the swept parameters. None if the parameter isn't swept.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SweepPoint {
+    pub capacity: Option<PopulationCount>,
+    pub capacity_scale: Option<f64>,
+    pub beta: Option<f64>,
+}
+
+// Headline results of one sweep run.
+#[derive(Clone, Copy, Debug)]
+pub struct SweepRun {
+    pub point: SweepPoint,
+    pub num_rounds: usize,
+    pub total_crowding_cost: f64,
+    pub max_segment_load: PopulationCount,
+    pub passenger_km: f64,
+    pub passenger_hours: f64,
+    pub mean_load_factor: f64,
+    // RMSE against the observed loads, if there are any.
+    pub rmse: Option<f64>,
+    pub duration: Duration,
+}
+
+impl Sweep {
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(capacity) = self.capacity.iter().find(|&&capacity| capacity < 1) {
+            return Err(format!("capacity {capacity} must be at least 1"));
+        }
+        if let Some(scale) = self.capacity_scale.iter().find(|scale| !scale.is_finite() || **scale <= 0.) {
+            return Err(format!("capacity_scale {scale} must be greater than zero"));
+        }
+        if let Some(beta) = self.beta.iter().find(|beta| !beta.is_finite() || **beta < 0.) {
+            return Err(format!("beta {beta} must be non-negative"));
+        }
+        if self.capacity.is_empty() && self.capacity_scale.is_empty() && self.beta.is_empty() {
+            return Err("needs at least one parameter to sweep".to_owned());
+        }
+        Ok(())
+    }
+
+    // Every combination of the grids, varying beta fastest.
+    pub fn points(&self) -> Vec<SweepPoint> {
+        // An empty grid contributes a single unswept value to the product.
+        fn grid<T: Copy>(values: &[T]) -> Vec<Option<T>> {
+            if values.is_empty() { vec![None] } else { values.iter().copied().map(Some).collect() }
+        }
+        let mut points = Vec::new();
+        for &capacity in grid(&self.capacity).iter() {
+            for &capacity_scale in grid(&self.capacity_scale).iter() {
+                for &beta in grid(&self.beta).iter() {
+                    points.push(SweepPoint { capacity, capacity_scale, beta });
+                }
+            }
+        }
+        points
+    }
+}
+
+impl SweepPoint {
+    // Name of the run's export folder, e.g. capacity_794_beta_5.
+    pub fn name(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(capacity) = self.capacity {
+            parts.push(format!("capacity_{capacity}"));
+        }
+        if let Some(capacity_scale) = self.capacity_scale {
+            parts.push(format!("capacity_scale_{capacity_scale}"));
+        }
+        if let Some(beta) = self.beta {
+            parts.push(format!("beta_{beta}"));
+        }
+        parts.join("_")
+    }
+
+    // Sets the swept parameters, starting from the configured capacities.
+    pub fn apply(&self, params: &mut DefaultSimulationParams, base_capacities: &TripCapacities) {
+        let mut trip_capacities = base_capacities.clone();
+        if let Some(capacity) = self.capacity {
+            trip_capacities.set_default_capacity(with_total(trip_capacities.default_capacity(), capacity));
+        }
+        if let Some(capacity_scale) = self.capacity_scale {
+            trip_capacities = trip_capacities.scaled(capacity_scale);
+        }
+        params.trip_capacities = trip_capacities;
+        if let Some(beta) = self.beta {
+            params.crowding_function = CrowdingFunc::Exponential { beta: beta as CrowdingCost };
+        }
+    }
+}
+
+// The capacity with the given total, keeping its share of seats (and at least one seat).
+fn with_total(capacity: TripCapacity, total: PopulationCount) -> TripCapacity {
+    let seated_share = capacity.seated as f64 / capacity.total().max(1) as f64;
+    let seated = ((total as f64 * seated_share).round() as PopulationCount).clamp(1, total);
+    TripCapacity { seated, standing: total - seated }
+}
+
+// Writes one row per sweep run (its parameters and headline results) to <path>.csv, in the order the points were given.
+pub fn export_sweep_summary(path: &Path, runs: &[SweepRun]) -> Result<(), DataExportError> {
+    if runs.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["run", "name", "capacity", "capacity_scale", "beta", "rounds", "total_crowding_cost", "max_segment_load", "passenger_km", "passenger_hours", "mean_load_factor", "rmse", "duration_s"])?;
+    for (i, run) in runs.iter().enumerate() {
+        csv_writer.write_record(&[
+            (i + 1).to_string(),
+            run.point.name(),
+            optional(run.point.capacity.map(|capacity| capacity.to_string())),
+            optional(run.point.capacity_scale.map(|capacity_scale| capacity_scale.to_string())),
+            optional(run.point.beta.map(|beta| beta.to_string())),
+            run.num_rounds.to_string(),
+            format!("{:.3}", run.total_crowding_cost),
+            run.max_segment_load.to_string(),
+            format!("{:.3}", run.passenger_km),
+            format!("{:.3}", run.passenger_hours),
+            format!("{:.4}", run.mean_load_factor),
+            optional(run.rmse.map(|rmse| format!("{rmse:.3}"))),
+            format!("{:.3}", run.duration.as_secs_f64()),
+        ])?;
+    }
+    csv_writer.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,189 @@
+// Helpers for the unit tests, which run on the small feeds in tests/fixtures.
+//
+// two_lines runs on weekdays of 2024. The Red (ALP-BRA-CHA) and Green (ALP-CHA) lines both depart Alpha at 08:00, 08:15
+// and (Red only) 08:30. Red reaches Charlie ten minutes later and Green twelve, in time for the Blue line (CHA-DEL-ECH),
+// which departs at 08:15, 08:30 and 08:45. Only Red has a route_color.
+//
+// no_coordinates is two_lines with Charlie's stop_lat and stop_lon left blank.
+//
+// both_directions has a Local (North-Middle-South) and an Express (North-South) route, each with one trip in each
+// direction: LOC_OUT_0800 and EXP_OUT_0805 southbound (direction 0), and LOC_IN_0830 and EXP_IN_0835 northbound.
+//
+// platforms has Charlie as a station (location_type 1) with two platforms, CHA_1 and CHA_2. The Red line (RED_0800)
+// runs from Alpha to platform 1 at 08:00-08:10, and the Blue line from platform 2 to Delta at 08:12 and 08:20. Alpha and
+// Delta have no parent station.
+//
+// direct_or_change has Slow going straight from Alpha to Delta (08:00-08:40, calling at Charlie at 08:20), and a change at
+// Charlie from Fast (Alpha 08:05, Charlie 08:15) to Link (Charlie 08:20, Delta 08:30), which arrives ten minutes sooner.
+//
+// two_stops is a shuttle from West to East, 0.09 degrees apart on the equator. SHT_0800 takes half an hour and has
+// shape_dist_traveled (15 units apart), and SHT_0900 takes a quarter of an hour without.
+//
+// after_midnight is the same shuttle with the last trains of the day: SHT_2300 (23:00-23:15), and SHT_2440, which runs
+// after midnight (24:40-25:10) on the same service day.
+//
+// frequencies is the two_stops shuttle with SHT_0730 and two frequencies.txt templates: SHT_EXACT every ten minutes from
+// 08:00 to 08:30 (exact_times 1), and SHT_HEADWAY every quarter of an hour from 09:00 to 09:20 (exact_times 0).
+//
+// after_midnight_events.xml is the MATSim events file of two people taking SHT_2300 and one taking SHT_2440.
+
+use arrow::record_batch::{RecordBatch, RecordBatchReader};
+use chrono::NaiveDate;
+use gtfs_structures::{Gtfs, GtfsReader};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use rand::prelude::*;
+use raptor::journey::JourneyError;
+use raptor::network::{GlobalTripIndex, StopIndex, Timestamp};
+use raptor::{Leg, Network};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::simulation::{AgentCount, AgentJourney, AgentJourneyResult, DefaultSimulationParams, JourneyTable, SimulationResult, SimulationStep, TripCapacity};
+
+pub const FIXTURE_TRANSFER_TIME: Timestamp = 180;
+
+pub fn fixture_date() -> NaiveDate {
+    NaiveDate::from_ymd_opt(2024, 6, 3).unwrap()
+}
+
+pub fn fixture_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+pub fn load_fixture_gtfs(name: &str) -> Gtfs {
+    GtfsReader::default().read_from_path(fixture_path(name)).unwrap()
+}
+
+pub fn build_fixture_network(gtfs: &Gtfs) -> Network {
+    let mut network = Network::new(gtfs, None, fixture_date(), FIXTURE_TRANSFER_TIME);
+    network.build_connections();
+    network
+}
+
+// The fixture feed has no shapes.txt, so this draws each route as straight lines between its stops for the visualisation exports.
+pub fn add_stop_shapes(network: &mut Network) {
+    for route_idx in 0..network.num_routes() {
+        let shape = (0..network.num_stops_in_route(route_idx)).map(|stop_order| network.stop_points[network.get_stop_in_route(route_idx, stop_order) as usize]).collect();
+        network.routes[route_idx].shape = shape;
+    }
+}
+
+pub fn stop_idx(network: &Network, stop_id: &str) -> StopIndex {
+    network.stops.iter().position(|stop| {
+        let id: &str = stop.id.as_ref();
+        id == stop_id
+    }).unwrap_or_else(|| panic!("No stop {stop_id}")) as StopIndex
+}
+
+// The route index and trip order of a trip.
+pub fn trip_position(network: &Network, trip_id: &str) -> (usize, usize) {
+    network.routes.iter().enumerate().find_map(|(route_idx, route)| {
+        route.trip_ids.iter().position(|id| {
+            let id: &str = id.as_ref();
+            id == trip_id
+        }).map(|trip| (route_idx, trip))
+    }).unwrap_or_else(|| panic!("No trip {trip_id}"))
+}
+
+// A simulation step of `count` agents departing `origin` at `departure_time` for `dest`.
+pub fn simulation_step(network: &Network, departure_time: Timestamp, origin: &str, dest: &str, count: u32) -> SimulationStep {
+    let mut simulation_step = SimulationStep::new(departure_time, stop_idx(network, origin));
+    simulation_step.push(stop_idx(network, dest), count);
+    simulation_step
+}
+
+// `num_steps` simulation steps of one to ten agents each between random stops, departing from 07:50 to 08:20.
+pub fn morning_peak_steps(network: &Network, num_steps: usize, seed: u64) -> Vec<SimulationStep> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let num_stops = network.stops.len() as StopIndex;
+    (0..num_steps).map(|_| {
+        let mut simulation_step = SimulationStep::new(rng.gen_range((7 * 3600 + 50 * 60)..(8 * 3600 + 20 * 60)), rng.gen_range(0..num_stops));
+        simulation_step.push(rng.gen_range(0..num_stops), rng.gen_range(1..=10));
+        simulation_step
+    }).collect()
+}
+
+// Trip capacity of the fixture parameters, small enough that a few hundred agents crowd the trips.
+pub const FIXTURE_CAPACITY: TripCapacity = TripCapacity { seated: 20, standing: 10 };
+
+// The default parameters of a config for the fixture date, with `num_rounds` rounds and the fixture capacity.
+#[cfg(feature = "config")]
+pub fn fixture_params(num_rounds: u16) -> DefaultSimulationParams<'static> {
+    let mut config = crate::config::RunConfig::new(PathBuf::new(), fixture_date());
+    config.num_rounds = num_rounds;
+    config.trip_capacity = FIXTURE_CAPACITY;
+    config.simulation_params()
+}
+
+// A path in the temporary directory for a test's output, unique to the test process.
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("whos_on_board_{}_{name}", std::process::id()))
+}
+
+// Reads every row of a parquet file into one record batch.
+pub fn read_parquet(path: &Path) -> RecordBatch {
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap().build().unwrap();
+    let schema = reader.schema();
+    let batches = reader.collect::<Result<Vec<_>, _>>().unwrap();
+    arrow::compute::concat_batches(&schema, &batches).unwrap()
+}
+
+// A leg from (stop, time) to (stop, time), on a trip that doesn't matter.
+pub type SyntheticLeg = ((StopIndex, Timestamp), (StopIndex, Timestamp));
+
+// A result of one round without a network, with an agent journey of `count` agents along each list of legs
+// (or no journey found, if there are none).
+pub fn synthetic_result(journeys: &[(AgentCount, &[SyntheticLeg])]) -> SimulationResult {
+    let agent_journeys = journeys.iter().enumerate().map(|(sim_step_idx, &(count, legs))| {
+        let ((origin_stop, start_time), (dest_stop, _)) = (legs.first().map_or((0, 0), |leg| leg.0), legs.last().map_or((0, 0), |leg| leg.1));
+        let result = match (legs.first(), legs.last()) {
+            (Some(((_, first_time), _)), Some((_, (_, last_time)))) => {
+                let legs = legs.iter().map(|&((boarded_stop, boarded_time), (arrival_stop, arrival_time))| Leg {
+                    trip: GlobalTripIndex::default(),
+                    boarded_stop,
+                    boarded_stop_order: 0,
+                    boarded_time,
+                    arrival_stop,
+                    arrival_stop_order: 1,
+                    arrival_time,
+                    transfer_time: None,
+                }).collect::<Vec<_>>();
+                let in_vehicle_time: Timestamp = legs.iter().map(|leg| leg.arrival_time - leg.boarded_time).sum();
+                Ok(AgentJourney {
+                    origin_trip: GlobalTripIndex::default(),
+                    dest_trip: GlobalTripIndex::default(),
+                    duration: last_time - first_time,
+                    crowding_cost: 0.,
+                    experienced_crowding_cost: 0.,
+                    in_vehicle_time,
+                    wait_time: last_time - first_time - in_vehicle_time,
+                    num_transfers: (legs.len() - 1) as u8,
+                    legs,
+                })
+            }
+            _ => Err(JourneyError::NoJourneyFound),
+        };
+        AgentJourneyResult {
+            sim_step_idx: sim_step_idx as u32,
+            journey_idx: 0,
+            origin_stop,
+            dest_stop,
+            start_time,
+            count,
+            segment: 0,
+            result,
+        }
+    }).collect();
+
+    SimulationResult {
+        population_count: Vec::new(),
+        round_agent_journeys: vec![JourneyTable::from_journeys(agent_journeys)],
+        capacity_report: None,
+        iteration_history: Vec::new(),
+        cancelled: false,
+        realised_stop_times: None,
+        suppressed_counts: None,
+        plan_switching: HashMap::new(),
+    }
+}
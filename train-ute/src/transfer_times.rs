@@ -0,0 +1,210 @@
+// Minimum times to change between trips that differ between stops: the min_transfer_time of the feeds' transfers.txt,
+// and an override CSV of from_stop_id,to_stop_id,seconds whose times win over the feeds'. Other transfers take the
+// network's default transfer time.
+//
+// The network has a single transfer time, so it's built with the shortest of them (see
+// `StopTransferTimes::network_transfer_time`), and a
+// journey making a transfer quicker than its stops allow is planned again with the outgoing trip blocked, as for
+// wheelchair access. Only transfers the network has can be made, so times between stops it doesn't connect do nothing.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use itertools::Itertools;
+use raptor::network::{StopIndex, Timestamp};
+use raptor::{Leg, Network};
+
+use crate::data_export::DataExportError;
+use crate::data_import::StopTransferTime;
+
+// Where a transfer time comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransferTimeSource {
+    Default,
+    Gtfs,
+    Override,
+}
+
+impl TransferTimeSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferTimeSource::Default => "default",
+            TransferTimeSource::Gtfs => "gtfs",
+            TransferTimeSource::Override => "override",
+        }
+    }
+}
+
+// The configured transfer times by stop id, before they're resolved to a network's stops.
+#[derive(Clone, Debug, Default)]
+pub struct StopTransferTimes {
+    // From the feeds' transfers.txt.
+    pub gtfs: Vec<StopTransferTime>,
+    // From the override file, which win over the feeds'.
+    pub overrides: Vec<StopTransferTime>,
+}
+
+impl StopTransferTimes {
+    // The transfer time to build the network with: the shortest, so the planner finds every transfer that's allowed.
+    pub fn network_transfer_time(&self, default_transfer_time: Timestamp) -> Timestamp {
+        self.gtfs.iter().chain(&self.overrides).map(|transfer_time| transfer_time.seconds).fold(default_transfer_time, Timestamp::min)
+    }
+}
+
+// The minimum transfer time between each pair of the network's stops.
+pub struct TransferTimes {
+    default_transfer_time: Timestamp,
+    times: HashMap<(StopIndex, StopIndex), (Timestamp, TransferTimeSource)>,
+}
+
+impl TransferTimes {
+    // The feeds' transfer times with the overrides on top. Also returns the stop ids of the overrides that aren't in the
+    // network (sorted). The feeds' transfers at stops the network leaves out (e.g. of filtered routes) are ignored.
+    pub fn new(network: &Network, default_transfer_time: Timestamp, stop_transfer_times: &StopTransferTimes) -> (Self, Vec<String>) {
+        let stop_idx_map: HashMap<&str, StopIndex> = network.stops.iter().enumerate().map(|(i, stop)| (&stop.id[..], i as StopIndex)).collect();
+
+        let mut times = HashMap::new();
+        let mut unknown_stop_ids = Vec::new();
+        for (transfer_times, source) in [(&stop_transfer_times.gtfs, TransferTimeSource::Gtfs), (&stop_transfer_times.overrides, TransferTimeSource::Override)] {
+            for transfer_time in transfer_times {
+                let from_stop = stop_idx_map.get(&transfer_time.from_stop_id[..]);
+                let to_stop = stop_idx_map.get(&transfer_time.to_stop_id[..]);
+                if let (Some(&from_stop), Some(&to_stop)) = (from_stop, to_stop) {
+                    times.insert((from_stop, to_stop), (transfer_time.seconds, source));
+                } else if source == TransferTimeSource::Override {
+                    unknown_stop_ids.extend([(from_stop, &transfer_time.from_stop_id), (to_stop, &transfer_time.to_stop_id)].into_iter().filter(|(stop, _)| stop.is_none()).map(|(_, stop_id)| stop_id.clone()));
+                }
+            }
+        }
+        unknown_stop_ids.sort();
+        unknown_stop_ids.dedup();
+
+        (Self { default_transfer_time, times }, unknown_stop_ids)
+    }
+
+    // Number of stop pairs with a transfer time of their own.
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    pub fn transfer_time(&self, from_stop: StopIndex, to_stop: StopIndex) -> (Timestamp, TransferTimeSource) {
+        self.times.get(&(from_stop, to_stop)).copied().unwrap_or((self.default_transfer_time, TransferTimeSource::Default))
+    }
+
+    // The stop time whose cost to block so a journey avoids its first transfer that's quicker than the stops allow: the
+    // segment the next trip departs the transfer stop on.
+    pub fn missed_transfer_stop_time(&self, network: &Network, legs: &[Leg]) -> Option<usize> {
+        legs.iter().tuple_windows().find_map(|(incoming, outgoing)| {
+            let (transfer_time, _) = self.transfer_time(incoming.arrival_stop, outgoing.boarded_stop);
+            if outgoing.boarded_time >= incoming.arrival_time + transfer_time {
+                return None;
+            }
+            let trip_start = network.routes[outgoing.trip.route_idx as usize].get_trip_range(outgoing.trip.trip_order as usize).start;
+            Some(trip_start + outgoing.boarded_stop_order as usize + 1)
+        })
+    }
+
+    pub fn log(&self) {
+        let num_overrides = self.times.values().filter(|(_, source)| *source == TransferTimeSource::Override).count();
+        log::info!("{} stop pairs have their own transfer time ({num_overrides} from the override file), and the rest take {} seconds.", self.times.len(), self.default_transfer_time);
+    }
+
+    // Writes the transfer time used for each stop pair as CSV: every stop's own (for changing trips there), then the pairs
+    // of different stops with a time of their own.
+    pub fn export_csv(&self, path: &Path, network: &Network) -> Result<(), DataExportError> {
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(["from_stop_id", "to_stop_id", "seconds", "source"])?;
+        let stop_pairs = (0..network.stops.len() as StopIndex).map(|stop| (stop, stop))
+                                                               .chain(self.times.keys().copied().filter(|(from_stop, to_stop)| from_stop != to_stop).sorted());
+        for (from_stop, to_stop) in stop_pairs {
+            let (seconds, source) = self.transfer_time(from_stop, to_stop);
+            let stop_id = |stop: StopIndex| -> &str { network.stops[stop as usize].id.as_ref() };
+            csv_writer.write_record([stop_id(from_stop), stop_id(to_stop), &seconds.to_string()[..], source.as_str()])?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::run_simulation;
+    use crate::test_utils::*;
+    use std::sync::Arc;
+
+    fn overrides(times: &[(&str, &str, Timestamp)]) -> StopTransferTimes {
+        let overrides = times.iter().map(|&(from_stop_id, to_stop_id, seconds)| StopTransferTime { from_stop_id: from_stop_id.into(), to_stop_id: to_stop_id.into(), seconds }).collect();
+        StopTransferTimes { gtfs: Vec::new(), overrides }
+    }
+
+    // The trip three agents from Alpha to Delta change to at Charlie, with the given default and per-stop transfer times.
+    fn outgoing_trip(default_transfer_time: Timestamp, stop_transfer_times: Option<&StopTransferTimes>) -> String {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network_transfer_time = stop_transfer_times.map_or(default_transfer_time, |stop_transfer_times| stop_transfer_times.network_transfer_time(default_transfer_time));
+        let mut network = Network::new(&gtfs, None, fixture_date(), network_transfer_time);
+        network.build_connections();
+        let mut params = fixture_params(1);
+        params.transfer_times = stop_transfer_times.map(|stop_transfer_times| Arc::new(TransferTimes::new(&network, default_transfer_time, stop_transfer_times).0));
+
+        let simulation_result = run_simulation(&network, &[simulation_step(&network, 7 * 3600 + 55 * 60, "ALP", "DEL", 3)], &params);
+        let journey = simulation_result.round_agent_journeys[0].get(0).result.unwrap();
+        assert_eq!(journey.legs.len(), 2);
+        network.get_trip_id(journey.legs[1].trip).to_string()
+    }
+
+    #[test]
+    fn a_transfer_quicker_than_its_stop_allows_is_planned_again() {
+        assert_eq!(outgoing_trip(FIXTURE_TRANSFER_TIME, None), "BLUE_0815");
+        // Fifteen minutes at Charlie misses the 08:15 from either line arriving at 08:10 or 08:12.
+        assert_eq!(outgoing_trip(FIXTURE_TRANSFER_TIME, Some(&overrides(&[("CHA", "CHA", 15 * 60)]))), "BLUE_0830");
+        // The override is only for Charlie.
+        assert_eq!(outgoing_trip(FIXTURE_TRANSFER_TIME, Some(&overrides(&[("BRA", "BRA", 15 * 60)]))), "BLUE_0815");
+    }
+
+    #[test]
+    fn a_transfer_time_below_the_default_allows_quicker_transfers() {
+        assert_eq!(outgoing_trip(25 * 60, None), "BLUE_0845");
+        assert_eq!(outgoing_trip(25 * 60, Some(&overrides(&[("CHA", "CHA", FIXTURE_TRANSFER_TIME)]))), "BLUE_0815");
+    }
+
+    #[test]
+    fn overrides_win_and_unknown_stops_are_reported() {
+        let gtfs = load_fixture_gtfs("two_lines");
+        let network = build_fixture_network(&gtfs);
+        let mut stop_transfer_times = overrides(&[("CHA", "CHA", 600), ("CHA", "NOPE", 60), ("GHOST", "NOPE", 60), ("ALP", "BRA", 120)]);
+        stop_transfer_times.gtfs = vec![
+            StopTransferTime { from_stop_id: "CHA".into(), to_stop_id: "CHA".into(), seconds: 300 },
+            StopTransferTime { from_stop_id: "DEL".into(), to_stop_id: "DEL".into(), seconds: 240 },
+            // Not reported, as the feed's transfers can be at stops the network leaves out.
+            StopTransferTime { from_stop_id: "FILTERED".into(), to_stop_id: "FILTERED".into(), seconds: 240 },
+        ];
+        assert_eq!(stop_transfer_times.network_transfer_time(FIXTURE_TRANSFER_TIME), 60);
+
+        let (transfer_times, unknown_stop_ids) = TransferTimes::new(&network, FIXTURE_TRANSFER_TIME, &stop_transfer_times);
+        assert_eq!(unknown_stop_ids, ["GHOST", "NOPE"]);
+        assert_eq!(transfer_times.len(), 3);
+        let transfer_time = |from_stop_id, to_stop_id| transfer_times.transfer_time(stop_idx(&network, from_stop_id), stop_idx(&network, to_stop_id));
+        assert_eq!(transfer_time("CHA", "CHA"), (600, TransferTimeSource::Override));
+        assert_eq!(transfer_time("DEL", "DEL"), (240, TransferTimeSource::Gtfs));
+        assert_eq!(transfer_time("ALP", "BRA"), (120, TransferTimeSource::Override));
+        assert_eq!(transfer_time("BRA", "ALP"), (FIXTURE_TRANSFER_TIME, TransferTimeSource::Default));
+
+        let path = temp_path("transfer_times");
+        transfer_times.export_csv(&path, &network).unwrap();
+        let csv = std::fs::read_to_string(path.with_extension("csv")).unwrap();
+        std::fs::remove_file(path.with_extension("csv")).unwrap();
+        let rows = csv.lines().collect_vec();
+        assert_eq!(rows[0], "from_stop_id,to_stop_id,seconds,source");
+        // Every stop's own transfer time, then the one between different stops.
+        assert_eq!(rows.len(), 1 + network.stops.len() + 1);
+        assert!(rows.contains(&"CHA,CHA,600,override"));
+        assert!(rows.contains(&"DEL,DEL,240,gtfs"));
+        assert!(rows.contains(&"ECH,ECH,180,default"));
+        assert_eq!(rows.last(), Some(&"ALP,BRA,120,override"));
+    }
+}
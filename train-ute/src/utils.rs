@@ -1,4 +1,6 @@
 use rgb::RGB8;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
 
 pub fn mix_rgb(a: RGB8, b: RGB8, t: f32) -> RGB8 {
     RGB8 {
@@ -26,3 +28,75 @@ pub fn quadratic_inv_ease_in_out(t: f32) -> f32 {
     }
 }
 
+
+// Prints the prompt and reads a line of input without the trailing whitespace, or None at the end of the input.
+fn read_line(input: &mut impl BufRead, output: &mut impl Write, prompt: &str) -> std::io::Result<Option<String>> {
+    write!(output, "{prompt}")?;
+    output.flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    line.truncate(line.trim_end().len());
+    Ok(Some(line))
+}
+
+// Prints the prompt and reads a line of input, without the trailing whitespace (or None if the line is empty).
+pub fn read_input(input: &mut impl BufRead, output: &mut impl Write, prompt: &str) -> std::io::Result<Option<String>> {
+    Ok(read_line(input, output, prompt)?.filter(|line| !line.is_empty()))
+}
+
+// Asks until the input parses as a value that `is_valid` accepts, explaining that it should be `requirement` otherwise.
+// Running out of input is an error, so it doesn't ask forever.
+pub fn prompt_parse<T: FromStr>(input: &mut impl BufRead, output: &mut impl Write, prompt: &str, is_valid: impl Fn(&T) -> bool, requirement: &str) -> std::io::Result<T> {
+    loop {
+        let line = read_line(input, output, prompt)?.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "no more input"))?;
+        match line.trim().parse::<T>() {
+            Ok(value) if is_valid(&value) => break Ok(value),
+            _ => writeln!(output, "{line} is not {requirement}. Please try again.")?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn prompt_count(input: &str) -> (std::io::Result<usize>, String) {
+        let mut output = Vec::new();
+        let result = prompt_parse(&mut Cursor::new(input), &mut output, "Count: ", |&count| count > 0, "a number greater than zero");
+        (result, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn prompt_parse_accepts_valid_input() {
+        let (result, output) = prompt_count(" 500 \n");
+        assert_eq!(result.unwrap(), 500);
+        assert_eq!(output, "Count: ");
+    }
+
+    #[test]
+    fn prompt_parse_asks_again_after_invalid_input() {
+        let (result, output) = prompt_count("500k\n\n0\n5 00\n42\n");
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(output.matches("Count: ").count(), 5);
+        assert!(output.contains("500k is not a number greater than zero"));
+        assert!(output.contains("0 is not a number greater than zero"));
+        assert!(output.contains("5 00 is not a number greater than zero"));
+    }
+
+    #[test]
+    fn prompt_parse_fails_at_the_end_of_input() {
+        let (result, _) = prompt_count("abc\n");
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn read_input_trims_the_line() {
+        let mut output = Vec::new();
+        assert_eq!(read_input(&mut Cursor::new("  path.zip \r\n"), &mut output, "Path: ").unwrap().as_deref(), Some("  path.zip"));
+        assert_eq!(read_input(&mut Cursor::new("\n"), &mut output, "Path: ").unwrap(), None);
+        assert_eq!(read_input(&mut Cursor::new(""), &mut output, "Path: ").unwrap(), None);
+    }
+}
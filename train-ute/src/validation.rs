@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use raptor::Network;
+
+use crate::data_export::DataExportError;
+use crate::data_import::ObservedLoad;
+use crate::simulation::SimulationResult;
+
+// Why an observation couldn't be compared with the simulation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmatchedReason {
+    // The trip isn't in the network, e.g. it doesn't run on the modelled date or was removed by a route filter.
+    TripNotRunning,
+    // The trip doesn't stop at the from stop and later at the to stop.
+    StopsNotOnTrip,
+}
+
+impl UnmatchedReason {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            UnmatchedReason::TripNotRunning => "trip_not_running",
+            UnmatchedReason::StopsNotOnTrip => "stops_not_on_trip",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ValidationRow {
+    pub observation: ObservedLoad,
+    // Agents on board departing the from stop.
+    pub simulated_load: f64,
+    pub geh: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub rows: Vec<ValidationRow>,
+    pub unmatched: Vec<(ObservedLoad, UnmatchedReason)>,
+}
+
+// The GEH statistic, which weights the difference between modelled and observed counts by their size.
+// A GEH below 5 is usually considered a good match.
+pub fn geh(simulated: f64, observed: f64) -> f64 {
+    if simulated + observed <= 0. {
+        0.
+    } else {
+        (2. * (simulated - observed).powi(2) / (simulated + observed)).sqrt()
+    }
+}
+
+impl ValidationReport {
+    pub fn num_matched(&self) -> usize {
+        self.rows.len()
+    }
+
+    // Root mean squared error of the simulated loads, or None if nothing matched.
+    pub fn rmse(&self) -> Option<f64> {
+        self.mean(|row| (row.simulated_load - row.observation.observed_load).powi(2)).map(f64::sqrt)
+    }
+
+    // Mean absolute error of the simulated loads, or None if nothing matched.
+    pub fn mae(&self) -> Option<f64> {
+        self.mean(|row| (row.simulated_load - row.observation.observed_load).abs())
+    }
+
+    pub fn mean_geh(&self) -> Option<f64> {
+        self.mean(|row| row.geh)
+    }
+
+    // Proportion of matched observations with a GEH below 5.
+    pub fn geh_below_5(&self) -> Option<f64> {
+        self.mean(|row| if row.geh < 5. { 1. } else { 0. })
+    }
+
+    fn mean(&self, value: impl Fn(&ValidationRow) -> f64) -> Option<f64> {
+        if self.rows.is_empty() {
+            None
+        } else {
+            Some(self.rows.iter().map(value).sum::<f64>() / self.rows.len() as f64)
+        }
+    }
+
+    pub fn log(&self) {
+        let optional = |value: Option<f64>| value.map_or("-".to_owned(), |value| format!("{value:.2}"));
+        log::info!("Validated against {} observed loads: RMSE {}, MAE {}, mean GEH {}, {} with GEH < 5.",
+                   self.num_matched(),
+                   optional(self.rmse()),
+                   optional(self.mae()),
+                   optional(self.mean_geh()),
+                   self.geh_below_5().map_or("-".to_owned(), |proportion| format!("{:.1}%", 100. * proportion)));
+        if !self.unmatched.is_empty() {
+            let num_not_running = self.unmatched.iter().filter(|(_, reason)| *reason == UnmatchedReason::TripNotRunning).count();
+            log::warn!("{} observed loads couldn't be compared ({num_not_running} on trips not running on the modelled date).", self.unmatched.len());
+        }
+    }
+
+    // Writes one row per compared observation (which doubles as scatter plot data) to <path>.csv,
+    // and the observations that couldn't be compared to <path>_unmatched.csv.
+    pub fn export(&self, path: &Path) -> Result<(), DataExportError> {
+        let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+        csv_writer.write_record(&["trip_id", "from_stop_id", "to_stop_id", "observed_load", "simulated_load", "difference", "geh"])?;
+        for row in self.rows.iter() {
+            csv_writer.write_record(&[
+                row.observation.trip_id.as_str(),
+                &row.observation.from_stop_id,
+                &row.observation.to_stop_id,
+                &row.observation.observed_load.to_string(),
+                &row.simulated_load.to_string(),
+                &(row.simulated_load - row.observation.observed_load).to_string(),
+                &format!("{:.3}", row.geh),
+            ])?;
+        }
+        csv_writer.flush()?;
+
+        let file_name = format!("{}_unmatched.csv", path.file_stem().unwrap_or_default().to_string_lossy());
+        let mut csv_writer = csv::Writer::from_path(path.with_file_name(file_name))?;
+        csv_writer.write_record(&["trip_id", "from_stop_id", "to_stop_id", "observed_load", "reason"])?;
+        for (observation, reason) in self.unmatched.iter() {
+            csv_writer.write_record(&[
+                observation.trip_id.as_str(),
+                &observation.from_stop_id,
+                &observation.to_stop_id,
+                &observation.observed_load.to_string(),
+                reason.get_name(),
+            ])?;
+        }
+        csv_writer.flush()?;
+
+        Ok(())
+    }
+}
+
+// Compares observed loads with the simulated load departing each observation's from stop.
+pub fn validate_loads(network: &Network, simulation_result: &SimulationResult, observations: &[ObservedLoad]) -> ValidationReport {
+    let trip_idx_map: HashMap<&str, (usize, usize)> = network.routes.iter().enumerate().flat_map(|(route_idx, route)| {
+        route.trip_ids.iter().enumerate().map(move |(trip, trip_id)| {
+            let trip_id: &str = trip_id.as_ref();
+            (trip_id, (route_idx, trip))
+        })
+    }).collect();
+
+    let mut report = ValidationReport::default();
+    for observation in observations.iter() {
+        let Some(&(route_idx, trip)) = trip_idx_map.get(observation.trip_id.as_str()) else {
+            report.unmatched.push((observation.clone(), UnmatchedReason::TripNotRunning));
+            continue;
+        };
+        let route = &network.routes[route_idx];
+        let stops = route.get_stops(&network.route_stops);
+        let stop_id = |stop_order: usize| -> &str { network.stops[stops[stop_order] as usize].id.as_ref() };
+        let from_stop_order = (0..stops.len()).find(|&stop_order| stop_id(stop_order) == observation.from_stop_id);
+        let to_stop_order = from_stop_order.and_then(|from_stop_order| (from_stop_order + 1..stops.len()).find(|&stop_order| stop_id(stop_order) == observation.to_stop_id));
+        let (Some(from_stop_order), Some(_)) = (from_stop_order, to_stop_order) else {
+            report.unmatched.push((observation.clone(), UnmatchedReason::StopsNotOnTrip));
+            continue;
+        };
+
+        let simulated_load = simulation_result.population_count[route.get_trip_range(trip)][from_stop_order] as f64;
+        report.rows.push(ValidationRow {
+            observation: observation.clone(),
+            simulated_load,
+            geh: geh(simulated_load, observation.observed_load),
+        });
+    }
+    report
+}
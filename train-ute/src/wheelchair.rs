@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use gtfs_structures::{Availability, Gtfs};
+use itertools::Itertools;
+use rand::prelude::*;
+use raptor::network::{GlobalTripIndex, PathfindingCost, StopIndex, Timestamp};
+use raptor::{Leg, Network};
+use rayon::prelude::*;
+
+use crate::data_export::DataExportError;
+use crate::simulation::{self, SimulationParams, SimulationResult, SimulationStep};
+
+// Which agents need wheelchair-accessible journeys.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct WheelchairModel {
+    // Share of the agents (chosen using the seed) that need wheelchair access, on top of those marked in the OD matrix.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub share: f64,
+    // Count trips and stops without wheelchair information in the GTFS (including trips not in it, like frequency-expanded
+    // and supplementary trips) as accessible.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub assume_unknown_accessible: bool,
+}
+
+impl WheelchairModel {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0. ..=1.).contains(&self.share) {
+            return Err(format!("share ({}) must be between 0 and 1", self.share));
+        }
+        Ok(())
+    }
+}
+
+// Marks whole parties in each simulation step as needing wheelchair access at random, with probability `share`. Agents in a
+// simulation step share a journey query, so those needing access get their own simulation step.
+pub fn assign_wheelchair_users(simulation_steps: Vec<SimulationStep>, share: f64, seed: u64) -> Vec<SimulationStep> {
+    if share <= 0. {
+        return simulation_steps;
+    }
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let mut assigned_steps = Vec::with_capacity(simulation_steps.len() * 2);
+    for simulation_step in simulation_steps {
+        if simulation_step.requires_accessible {
+            assigned_steps.push(simulation_step);
+            continue;
+        }
+        let [mut other_step, mut accessible_step] = [false, true].map(|requires_accessible| SimulationStep {
+            segment: simulation_step.segment,
+            requires_accessible,
+            ..SimulationStep::new(simulation_step.departure_time, simulation_step.origin_stop)
+        });
+        for ((dest_stop, count), &party_size) in simulation_step.destinations().zip(simulation_step.party_sizes()) {
+            let num_parties = count / party_size;
+            let num_accessible = (0..num_parties).filter(|_| rng.gen_bool(share)).count() as u32;
+            if num_accessible > 0 {
+                accessible_step.push_parties(dest_stop, party_size, num_accessible);
+            }
+            if num_accessible < num_parties {
+                other_step.push_parties(dest_stop, party_size, num_parties - num_accessible);
+            }
+        }
+        assigned_steps.extend([other_step, accessible_step].into_iter().filter(|step| step.len() > 0));
+    }
+    assigned_steps
+}
+
+// Wheelchair access to the network's stops and trips, from the GTFS wheelchair_boarding and wheelchair_accessible fields.
+pub struct WheelchairAccess {
+    // Indexed by stop.
+    accessible_stops: Vec<bool>,
+    // Indexed by each trip's position in network order.
+    accessible_trips: Vec<bool>,
+    route_trip_offsets: Vec<usize>,
+}
+
+impl WheelchairAccess {
+    pub fn new(network: &Network, gtfs: &Gtfs, assume_unknown_accessible: bool) -> Self {
+        let is_accessible = |availability: Option<&Availability>| match availability {
+            Some(Availability::Available) => true,
+            Some(Availability::NotAvailable) => false,
+            _ => assume_unknown_accessible,
+        };
+
+        // Stops without information inherit it from their parent station.
+        let stop_availability = |stop_id: &str| {
+            let stop = gtfs.stops.get(stop_id)?;
+            match (&stop.wheelchair_boarding, &stop.parent_station) {
+                (Availability::InformationNotAvailable, Some(parent_id)) => gtfs.stops.get(parent_id).map(|parent| &parent.wheelchair_boarding),
+                (availability, _) => Some(availability),
+            }
+        };
+        let accessible_stops = network.stops.iter().map(|stop| is_accessible(stop_availability(&stop.id[..]))).collect();
+
+        let mut route_trip_offsets = Vec::with_capacity(network.routes.len());
+        let mut accessible_trips = Vec::new();
+        for route in network.routes.iter() {
+            route_trip_offsets.push(accessible_trips.len());
+            accessible_trips.extend(route.trip_ids.iter().take(route.num_trips as usize).map(|trip_id| {
+                let trip_id: &str = trip_id.as_ref();
+                is_accessible(gtfs.trips.get(trip_id).map(|trip| &trip.wheelchair_accessible))
+            }));
+        }
+
+        Self { accessible_stops, accessible_trips, route_trip_offsets }
+    }
+
+    pub fn is_stop_accessible(&self, stop: StopIndex) -> bool {
+        self.accessible_stops[stop as usize]
+    }
+
+    pub fn is_trip_accessible(&self, trip: GlobalTripIndex) -> bool {
+        self.accessible_trips[self.route_trip_offsets[trip.route_idx as usize] + trip.trip_order as usize]
+    }
+
+    // Number of (stops, trips) that aren't accessible.
+    pub fn num_inaccessible(&self) -> (usize, usize) {
+        (self.accessible_stops.iter().filter(|&&accessible| !accessible).count(), self.accessible_trips.iter().filter(|&&accessible| !accessible).count())
+    }
+
+    pub fn log(&self) {
+        let (num_stops, num_trips) = self.num_inaccessible();
+        log::info!("{num_stops} of {} stops and {num_trips} of {} trips aren't wheelchair accessible.", self.accessible_stops.len(), self.accessible_trips.len());
+    }
+
+    // The planner costs with every segment of the inaccessible trips given `blocked_cost`, so journeys avoid them.
+    pub fn block_inaccessible_trips(&self, network: &Network, costs: &[PathfindingCost], blocked_cost: PathfindingCost) -> Vec<PathfindingCost> {
+        let mut costs = costs.to_vec();
+        for (route_idx, route) in network.routes.iter().enumerate() {
+            for trip in 0..route.num_trips as usize {
+                if !self.accessible_trips[self.route_trip_offsets[route_idx] + trip] {
+                    costs[route.get_trip_range(trip)].fill(blocked_cost);
+                }
+            }
+        }
+        costs
+    }
+
+    // The stop time whose cost to block so a journey avoids its first boarding or alighting at an inaccessible stop: the
+    // segment departing the boarding stop, or arriving at the alighting stop.
+    pub fn inaccessible_stop_time(&self, network: &Network, legs: &[Leg]) -> Option<usize> {
+        legs.iter().find_map(|leg| {
+            let trip_start = network.routes[leg.trip.route_idx as usize].get_trip_range(leg.trip.trip_order as usize).start;
+            if !self.is_stop_accessible(leg.boarded_stop) {
+                Some(trip_start + leg.boarded_stop_order as usize + 1)
+            } else if !self.is_stop_accessible(leg.arrival_stop) {
+                Some(trip_start + leg.arrival_stop_order as usize)
+            } else {
+                None
+            }
+        })
+    }
+
+    // True if every leg is on an accessible trip, boarded and left at accessible stops.
+    pub fn is_journey_accessible(&self, legs: &[Leg]) -> bool {
+        legs.iter().all(|leg| self.is_trip_accessible(leg.trip) && self.is_stop_accessible(leg.boarded_stop) && self.is_stop_accessible(leg.arrival_stop))
+    }
+}
+
+// The journeys of the agents needing wheelchair access between an OD pair, compared with the journeys they'd take without.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WheelchairComparison {
+    pub num_agents: u64,
+    // Agents with no accessible journey, though there's one without needing access.
+    pub num_unservable: u64,
+    // Agents with both journeys, which the total times are over (in seconds).
+    pub num_compared: u64,
+    pub accessible_duration: u64,
+    pub unconstrained_duration: u64,
+}
+
+// Compares the final round's journeys of the agents needing wheelchair access with the journeys they'd plan under the final
+// loads if they didn't, to see how much longer access makes them. Keyed by (origin stop, destination stop).
+pub fn compare_journey_times(network: &Network, params: &impl SimulationParams, simulation_steps: &[SimulationStep], simulation_result: &SimulationResult) -> HashMap<(StopIndex, StopIndex), WheelchairComparison> {
+    let Some(agent_journeys) = simulation_result.round_agent_journeys.last() else {
+        return HashMap::new();
+    };
+    let accessible_journeys = agent_journeys.iter().filter(|agent_journey| agent_journey.count > 0 && simulation_steps[agent_journey.sim_step_idx as usize].requires_accessible).collect_vec();
+
+    // Agents in the same step to the same destination plan the same journey, so each is only planned once.
+    let queries = accessible_journeys.iter().map(|agent_journey| (agent_journey.origin_stop, agent_journey.start_time, agent_journey.dest_stop)).unique().collect_vec();
+    let unconstrained_durations: HashMap<(StopIndex, Timestamp, StopIndex), Option<Timestamp>> = queries.into_par_iter().map(|query @ (origin_stop, departure_time, dest_stop)| {
+        let journey = simulation::plan_journey(network, params, &simulation_result.population_count, origin_stop, departure_time, dest_stop);
+        (query, journey.ok().map(|journey| journey.duration))
+    }).collect();
+
+    let mut comparisons: HashMap<(StopIndex, StopIndex), WheelchairComparison> = HashMap::new();
+    for agent_journey in accessible_journeys {
+        let count = agent_journey.count as u64;
+        let comparison = comparisons.entry((agent_journey.origin_stop, agent_journey.dest_stop)).or_default();
+        comparison.num_agents += count;
+        let unconstrained_duration = unconstrained_durations[&(agent_journey.origin_stop, agent_journey.start_time, agent_journey.dest_stop)];
+        match (&agent_journey.result, unconstrained_duration) {
+            (Ok(journey), Some(unconstrained_duration)) => {
+                comparison.num_compared += count;
+                comparison.accessible_duration += journey.duration as u64 * count;
+                comparison.unconstrained_duration += unconstrained_duration as u64 * count;
+            }
+            (Err(_), Some(_)) => comparison.num_unservable += count,
+            _ => {}
+        }
+    }
+    comparisons
+}
+
+// Writes the comparison of each OD pair's journey times with and without wheelchair access to <path>.csv, in minutes.
+pub fn export_wheelchair_comparison(path: &Path, network: &Network, comparisons: &HashMap<(StopIndex, StopIndex), WheelchairComparison>) -> Result<(), DataExportError> {
+    if comparisons.is_empty() {
+        return Err(DataExportError::NoData);
+    }
+    let mean_minutes = |total: u64, count: u64| if count > 0 { format!("{:.2}", total as f64 / count as f64 / 60.) } else { String::new() };
+
+    let mut csv_writer = csv::Writer::from_path(path.with_extension("csv"))?;
+    csv_writer.write_record(&["origin_stop_id", "destination_stop_id", "num_agents", "num_unservable", "accessible_minutes", "unconstrained_minutes", "extra_minutes"])?;
+    for (&(origin_stop, dest_stop), comparison) in comparisons.iter().sorted_unstable_by_key(|(od, _)| **od) {
+        csv_writer.write_record(&[
+            network.stops[origin_stop as usize].id.as_ref(),
+            network.stops[dest_stop as usize].id.as_ref(),
+            &comparison.num_agents.to_string(),
+            &comparison.num_unservable.to_string(),
+            &mean_minutes(comparison.accessible_duration, comparison.num_compared),
+            &mean_minutes(comparison.unconstrained_duration, comparison.num_compared),
+            &mean_minutes(comparison.accessible_duration.saturating_sub(comparison.unconstrained_duration), comparison.num_compared),
+        ])?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+// Logs the agents needing wheelchair access that have no accessible journey.
+pub fn log_unservable(comparisons: &HashMap<(StopIndex, StopIndex), WheelchairComparison>) {
+    let num_agents = comparisons.values().map(|comparison| comparison.num_agents).sum::<u64>();
+    let num_unservable = comparisons.values().map(|comparison| comparison.num_unservable).sum::<u64>();
+    if num_unservable > 0 {
+        log::warn!("{num_unservable} of {num_agents} agents needing wheelchair access have no accessible journey.");
+    }
+    let (accessible_duration, unconstrained_duration) = comparisons.values().fold((0, 0), |(accessible, unconstrained), comparison| {
+        (accessible + comparison.accessible_duration, unconstrained + comparison.unconstrained_duration)
+    });
+    let num_compared = comparisons.values().map(|comparison| comparison.num_compared).sum::<u64>();
+    if num_compared > 0 {
+        log::info!("Agents needing wheelchair access take {:.1} minutes longer on average.", accessible_duration.saturating_sub(unconstrained_duration) as f64 / num_compared as f64 / 60.);
+    }
+}
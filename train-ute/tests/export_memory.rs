@@ -0,0 +1,65 @@
+// Checks that the streaming exports don't take much more memory than the simulation result they're exporting.
+#![cfg(all(target_os = "linux", feature = "config"))]
+
+use chrono::NaiveDate;
+use gtfs_structures::GtfsReader;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use raptor::network::{StopIndex, Timestamp};
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use train_ute::data_export::{export_agent_journeys, export_network_trips, segment_crowding_costs};
+use train_ute::{run_simulation, RunConfig, SimulationStep};
+
+// A status field of the process, in kB.
+fn status_kb(field: &str) -> u64 {
+    let status = fs::read_to_string("/proc/self/status").unwrap();
+    let line = status.lines().find(|line| line.starts_with(field)).unwrap_or_else(|| panic!("No {field} in /proc/self/status"));
+    line[field.len()..].trim().trim_end_matches("kB").trim().parse().unwrap()
+}
+
+#[test]
+fn exports_stay_within_the_simulation_working_set() -> Result<(), Box<dyn std::error::Error>> {
+    const NUM_STEPS: usize = 200_000;
+
+    let gtfs_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/two_lines");
+    let gtfs = GtfsReader::default().read_from_path(&gtfs_path)?;
+    let mut config = RunConfig::new(gtfs_path, NaiveDate::from_ymd_opt(2024, 6, 3).unwrap());
+    config.num_rounds = 1;
+    let mut network = config.build_network(&gtfs);
+    let params = config.simulation_params();
+
+    // The fixture feed has no shapes, so draw each route through its stops for the trips export.
+    for route_idx in 0..network.num_routes() {
+        let shape = (0..network.num_stops_in_route(route_idx)).map(|stop_order| network.stop_points[network.get_stop_in_route(route_idx, stop_order) as usize]).collect();
+        network.routes[route_idx].shape = shape;
+    }
+
+    // One agent per step between random stops over the morning peak, so the result is large next to the fixed overheads.
+    let mut rng = SmallRng::seed_from_u64(0);
+    let num_stops = network.stops.len() as StopIndex;
+    let simulation_steps = (0..NUM_STEPS).map(|_| {
+        let origin = rng.gen_range(0..num_stops);
+        let dest = (origin + rng.gen_range(1..num_stops)) % num_stops;
+        let mut simulation_step = SimulationStep::new(rng.gen_range(7 * 3600..9 * 3600) as Timestamp, origin);
+        simulation_step.push(dest, 1);
+        simulation_step
+    }).collect::<Vec<_>>();
+    let result = run_simulation(&network, &simulation_steps, &params);
+    let segment_costs = segment_crowding_costs(&network, &params, &result.population_count);
+
+    // Everything allocated so far is the simulation's working set. Clearing the page flags resets the peak RSS to the
+    // current RSS, so the peak afterwards is the exports' peak.
+    fs::write("/proc/self/clear_refs", "5")?;
+    let working_set = status_kb("VmRSS:");
+
+    let dir = tempfile::tempdir()?;
+    export_agent_journeys(File::create(dir.path().join("journeys.parquet"))?, &network, &result, false)?;
+    export_agent_journeys(File::create(dir.path().join("legs.parquet"))?, &network, &result, true)?;
+    export_network_trips(&network, &result, &segment_costs, &mut BufWriter::new(File::create(dir.path().join("trips.bin"))?))?;
+
+    let peak = status_kb("VmHWM:");
+    assert!(peak as f64 <= 1.2 * working_set as f64, "Exports peaked at {peak} kB, over 1.2 times the {working_set} kB working set");
+    Ok(())
+}
@@ -0,0 +1,48 @@
+// Runs a simulation through the library API, as a service using train-ute would.
+#![cfg(feature = "config")]
+
+use chrono::NaiveDate;
+use gtfs_structures::GtfsReader;
+use raptor::network::{StopIndex, Timestamp};
+use std::path::PathBuf;
+use train_ute::{run_simulation, RunConfig, SimulationStep};
+
+#[test]
+fn simulates_fixture_feed() -> Result<(), Box<dyn std::error::Error>> {
+    let gtfs_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/two_lines");
+    let gtfs = GtfsReader::default().read_from_path(&gtfs_path)?;
+    let mut config = RunConfig::new(gtfs_path, NaiveDate::from_ymd_opt(2024, 6, 3).unwrap());
+    config.num_rounds = 3;
+    let network = config.build_network(&gtfs);
+    let params = config.simulation_params();
+
+    // 100 agents from Alpha, a quarter to each stop down the line (which needs a change at Charlie for Delta and Echo).
+    let stop_idx = |stop_id: &str| network.stops.iter().position(|stop| {
+        let id: &str = stop.id.as_ref();
+        id == stop_id
+    }).unwrap() as StopIndex;
+    let simulation_steps = ["BRA", "CHA", "DEL", "ECH"].iter().enumerate().map(|(i, dest)| {
+        let mut simulation_step = SimulationStep::new(7 * 3600 + 50 * 60 + i as Timestamp * 60, stop_idx("ALP"));
+        simulation_step.push(stop_idx(dest), 25);
+        simulation_step
+    }).collect::<Vec<_>>();
+
+    let result = run_simulation(&network, &simulation_steps, &params);
+    assert!(!result.cancelled);
+    assert_eq!(result.round_agent_journeys.len(), 3);
+    assert_eq!(result.iteration_history.len(), 3);
+    assert_eq!(result.population_count.len(), network.stop_times.len());
+    for agent_journeys in &result.round_agent_journeys {
+        assert_eq!(agent_journeys.len(), simulation_steps.len());
+        assert_eq!(agent_journeys.iter().map(|agent_journey| agent_journey.count).sum::<u32>(), 100);
+        for agent_journey in agent_journeys {
+            let Ok(journey) = agent_journey.result else {
+                panic!("No journey to {}", network.stops[agent_journey.dest_stop as usize].name);
+            };
+            let expected_transfers = if agent_journey.dest_stop == stop_idx("DEL") || agent_journey.dest_stop == stop_idx("ECH") { 1 } else { 0 };
+            assert_eq!(journey.num_transfers, expected_transfers);
+            assert_eq!(journey.legs.len(), journey.num_transfers as usize + 1);
+        }
+    }
+    Ok(())
+}